@@ -0,0 +1,122 @@
+//! Connection churn: 1000 sequential connect/request/close cycles against a local server, reporting
+//! the elapsed time and the heap allocations per connection, counted by a wrapping allocator. The
+//! cycles are run with a small request, which fits in a single read, with a large one carrying 4KB
+//! of cookies, which is accumulated over multiple reads, and with a typical browser GET carrying 12
+//! header fields.
+//!
+//! Run with `cargo bench --bench conn_churn`.
+
+extern crate rusty_express;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusty_express::prelude::*;
+
+const PORT: u16 = 18765;
+const WARM_UP: usize = 100;
+const CYCLES: usize = 1000;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn pong(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("pong");
+}
+
+fn churn(request: &[u8], cycles: usize) {
+    for _ in 0..cycles {
+        let mut stream = TcpStream::connect(("127.0.0.1", PORT)).expect("server not reachable");
+
+        stream.write_all(request).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap_or_default();
+
+        let mut wire = Vec::new();
+        stream.read_to_end(&mut wire).unwrap_or_default();
+        assert!(wire.starts_with(b"HTTP/1.1 200 OK"));
+    }
+}
+
+fn main() {
+    thread::spawn(|| {
+        let mut server = HttpServer::new();
+        server.get(RequestPath::Explicit("/ping"), pong);
+        server.listen(PORT);
+    });
+
+    // wait for the server to come up
+    while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let small = b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n".to_vec();
+    let large = format!(
+        "GET /ping HTTP/1.1\r\nHost: localhost\r\nCookie: session={}\r\nConnection: close\r\n\r\n",
+        "a".repeat(4096)
+    )
+    .into_bytes();
+
+    let browser = b"GET /ping HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:68.0) Gecko/20100101 Firefox/68.0\r\n\
+        Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+        Accept-Language: en-US,en;q=0.5\r\n\
+        Accept-Encoding: gzip, deflate, br\r\n\
+        Referer: http://localhost/\r\n\
+        DNT: 1\r\n\
+        Upgrade-Insecure-Requests: 1\r\n\
+        Cache-Control: max-age=0\r\n\
+        If-None-Match: \"abc\"\r\n\
+        Pragma: no-cache\r\n\
+        Connection: close\r\n\r\n"
+        .to_vec();
+
+    for (name, request) in [("small", small), ("large", large), ("browser", browser)].iter() {
+        churn(request, WARM_UP);
+
+        let allocs = ALLOCS.load(Ordering::SeqCst);
+        let start = Instant::now();
+
+        churn(request, CYCLES);
+
+        let elapsed = start.elapsed();
+        let allocs = ALLOCS.load(Ordering::SeqCst) - allocs;
+
+        println!(
+            "conn_churn ({}): {} cycles in {:?} ({:?} per connection), {} allocations per connection",
+            name,
+            CYCLES,
+            elapsed,
+            elapsed / CYCLES as u32,
+            allocs / CYCLES
+        );
+    }
+
+    // the server runs until the process quits
+    process::exit(0);
+}
@@ -0,0 +1,104 @@
+//! Hello world: 1000 sequential requests over a single keep-alive connection, reporting the elapsed
+//! time per request. Small responses are written to the connection in one go, see
+//! `ServerConfig::set_coalesce_threshold`; run once more with the coalescing turned off to compare
+//! against the header and the body written apart, which also lets the Nagle's algorithm hold the
+//! body back until the header is acknowledged.
+//!
+//! Run with `cargo bench --bench hello_world`, and `COALESCE=0 cargo bench --bench hello_world`.
+
+extern crate rusty_express;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusty_express::prelude::*;
+
+const PORT: u16 = 18766;
+const WARM_UP: usize = 10;
+const REQUESTS: usize = 1000;
+
+fn hello(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("Hello world!");
+}
+
+/// Read a response off the stream, where the body is delimited by the `Content-Length`.
+fn read_response(stream: &mut TcpStream, buf: &mut Vec<u8>) {
+    let mut chunk = [0u8; 1024];
+    buf.clear();
+
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = str::from_utf8(&buf[..pos]).unwrap().to_lowercase();
+            let length = head
+                .lines()
+                .find(|line| line.starts_with("content-length:"))
+                .and_then(|line| line[15..].trim().parse::<usize>().ok())
+                .unwrap_or(0);
+
+            if buf.len() >= pos + 4 + length {
+                assert!(buf.starts_with(b"HTTP/1.1 200 OK"));
+                return;
+            }
+        }
+
+        let size = stream
+            .read(&mut chunk)
+            .expect("connection closed by the server");
+        assert!(size > 0, "connection closed by the server");
+        buf.extend_from_slice(&chunk[..size]);
+    }
+}
+
+fn run(stream: &mut TcpStream, requests: usize) {
+    let request = b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n";
+    let mut buf = Vec::with_capacity(1024);
+
+    for _ in 0..requests {
+        stream.write_all(request).unwrap();
+        read_response(stream, &mut buf);
+    }
+}
+
+fn main() {
+    let coalesce = env::var("COALESCE")
+        .ok()
+        .and_then(|c| c.parse::<usize>().ok());
+
+    thread::spawn(move || {
+        let mut server = HttpServer::new();
+        if let Some(bytes) = coalesce {
+            server.config().set_coalesce_threshold(bytes);
+        }
+
+        server.get(RequestPath::Explicit("/hello"), hello);
+        server.listen(PORT);
+    });
+
+    // wait for the server to come up
+    while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut stream = TcpStream::connect(("127.0.0.1", PORT)).expect("server not reachable");
+    run(&mut stream, WARM_UP);
+
+    let start = Instant::now();
+    run(&mut stream, REQUESTS);
+    let elapsed = start.elapsed();
+
+    println!(
+        "hello_world (coalesce {}): {} requests in {:?} ({:?} per request)",
+        coalesce.map_or_else(|| String::from("default"), |c| c.to_string()),
+        REQUESTS,
+        elapsed,
+        elapsed / REQUESTS as u32
+    );
+
+    // the server runs until the process quits
+    process::exit(0);
+}
@@ -0,0 +1,126 @@
+//! Route cache: 1000 sequential requests over a single keep-alive connection to a router with 200
+//! param routes and 50 wildcard routes, where the requested routes are registered last, reporting
+//! the elapsed time per request. The route cache is disabled by default, see
+//! `ServerConfig::set_route_cache_size`; run once more with the cache turned on to compare the
+//! cache hits against the walk over the trie and the wildcard patterns.
+//!
+//! Run with `cargo bench --bench route_cache`, and
+//! `ROUTE_CACHE=256 cargo bench --bench route_cache`.
+
+extern crate rusty_express;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusty_express::prelude::*;
+
+const PORT: u16 = 18767;
+const WARM_UP: usize = 10;
+const REQUESTS: usize = 1000;
+const PARAM_ROUTES: usize = 200;
+const WILDCARD_ROUTES: usize = 50;
+
+fn found(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("found");
+}
+
+/// Read a response off the stream, where the body is delimited by the `Content-Length`.
+fn read_response(stream: &mut TcpStream, buf: &mut Vec<u8>) {
+    let mut chunk = [0u8; 1024];
+    buf.clear();
+
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = str::from_utf8(&buf[..pos]).unwrap().to_lowercase();
+            let length = head
+                .lines()
+                .find(|line| line.starts_with("content-length:"))
+                .and_then(|line| line[15..].trim().parse::<usize>().ok())
+                .unwrap_or(0);
+
+            if buf.len() >= pos + 4 + length {
+                assert!(buf.starts_with(b"HTTP/1.1 200 OK"));
+                return;
+            }
+        }
+
+        let size = stream
+            .read(&mut chunk)
+            .expect("connection closed by the server");
+        assert!(size > 0, "connection closed by the server");
+        buf.extend_from_slice(&chunk[..size]);
+    }
+}
+
+fn run(stream: &mut TcpStream, requests: usize) {
+    let uris = [
+        format!("/api/r{}/7/items/3", PARAM_ROUTES - 1),
+        format!("/w{}/v2/report", WILDCARD_ROUTES - 1),
+    ];
+
+    let mut buf = Vec::with_capacity(1024);
+
+    for i in 0..requests {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            uris[i % uris.len()]
+        );
+
+        stream.write_all(request.as_bytes()).unwrap();
+        read_response(stream, &mut buf);
+    }
+}
+
+fn main() {
+    let capacity = env::var("ROUTE_CACHE")
+        .ok()
+        .and_then(|c| c.parse::<usize>().ok());
+
+    thread::spawn(move || {
+        let mut server = HttpServer::new();
+        if let Some(size) = capacity {
+            ServerConfig::set_route_cache_size(size);
+        }
+
+        for i in 0..PARAM_ROUTES {
+            server.get(
+                RequestPath::ExplicitWithParams(&format!("/api/r{}/:id/items/:item", i)),
+                found,
+            );
+        }
+
+        for i in 0..WILDCARD_ROUTES {
+            server.get(RequestPath::WildCard(&format!(r"^/w{}/v\d+/.*$", i)), found);
+        }
+
+        server.listen(PORT);
+    });
+
+    // wait for the server to come up
+    while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut stream = TcpStream::connect(("127.0.0.1", PORT)).expect("server not reachable");
+    run(&mut stream, WARM_UP);
+
+    let start = Instant::now();
+    run(&mut stream, REQUESTS);
+    let elapsed = start.elapsed();
+
+    println!(
+        "route_cache (capacity {}): {} requests in {:?} ({:?} per request)",
+        capacity.unwrap_or(0),
+        REQUESTS,
+        elapsed,
+        elapsed / REQUESTS as u32
+    );
+
+    // the server runs until the process quits
+    process::exit(0);
+}
@@ -0,0 +1,97 @@
+//! A small app wiring the features together: the static site, the params routes, a JSON API, a
+//! session-backed counter, the custom 404 page, and the graceful shutdown. The scenarios in
+//! `tests/full_app.rs` run against the same setup, so a feature touched here shall get a scenario
+//! there as well.
+
+extern crate rusty_express;
+
+use rusty_express::prelude::*;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+
+/// The visits of the client, kept in the session.
+pub struct Visits(u32);
+
+impl SessionData for Visits {
+    fn serialize(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn deserialize(raw: &str) -> Option<Self> {
+        raw.parse().ok().map(Visits)
+    }
+}
+
+fn main() {
+    let mut server = HttpServer::new();
+    setup(&mut server);
+
+    println!("Serving on http://localhost:8080, press Enter to stop the server");
+    server.listen_and_serve(8080, Some(stop_on_enter));
+}
+
+/// Define the routes and the settings of the app.
+pub fn setup(server: &mut HttpServer) {
+    ServerConfig::set_default_header(
+        String::from("X-Powered-By"),
+        String::from("Rusty Express"),
+        true,
+    );
+    ServerConfig::set_status_page_generator(404, not_found_page);
+
+    // the static site, where the files are served from the root path except the secrets
+    server.use_static_filtered(
+        PathBuf::from("examples").join("static"),
+        &[],
+        &["secrets.json", "*.map"],
+    );
+
+    server.scope("/api", |api| {
+        api.get(RequestPath::ExplicitWithParams("/users/:id"), user)
+            .post(RequestPath::Explicit("/echo"), echo);
+    });
+
+    server.get(RequestPath::Explicit("/counter"), counter);
+}
+
+fn stop_on_enter(controller: AsyncController) {
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line).unwrap_or_default();
+
+    controller
+        .send(ControlMessage::Terminate)
+        .unwrap_or_else(|_| eprintln!("Failed to stop the server"));
+}
+
+fn user(req: &Box<Request>, resp: &mut Box<Response>) {
+    let id = req.param("id").unwrap_or_default();
+
+    resp.set_content_type("application/json");
+    resp.send(&format!("{{\"id\":\"{}\"}}", id));
+}
+
+fn echo(req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.set_content_type("application/json");
+    resp.send(&req.json());
+}
+
+fn counter(req: &Box<Request>, resp: &mut Box<Response>) {
+    let mut session = match Session::from_request(req).or_else(Session::create_new) {
+        Some(session) => session,
+        None => {
+            resp.status(500);
+            return;
+        }
+    };
+
+    let visits: Option<Visits> = session.get_data();
+    let count = visits.map_or(0, |v| v.0) + 1;
+
+    session.set_data(Visits(count));
+    session.attach_to(resp);
+    resp.send(&format!("visits: {}", count));
+}
+
+fn not_found_page() -> String {
+    String::from("<html><body><h1>Nothing here</h1></body></html>")
+}
@@ -0,0 +1,62 @@
+extern crate rusty_express;
+
+use rusty_express::prelude::*;
+
+fn main() {
+    // define http server now
+    let mut server = HttpServer::new();
+    server.set_pool_size(8);
+
+    server.get(RequestPath::Explicit("/"), simple_response);
+
+    // all routes defined in the scope are served under the `/api/v1` prefix
+    server.scope("/api/v1", |api| {
+        api.get(RequestPath::Explicit("/"), simple_response)
+            .get(RequestPath::Explicit("/status"), simple_response)
+            .get(RequestPath::Explicit("/users"), simple_response)
+            .post(RequestPath::Explicit("/users"), simple_response)
+            .get(
+                RequestPath::ExplicitWithParams("/users/:id"),
+                param_response,
+            )
+            .put(
+                RequestPath::ExplicitWithParams("/users/:id"),
+                param_response,
+            )
+            .delete(
+                RequestPath::ExplicitWithParams("/users/:id"),
+                param_response,
+            )
+            .get(
+                RequestPath::ExplicitWithParams("/blogs/:id"),
+                param_response,
+            );
+
+        // groups can be nested, e.g. the routes below are served under `/api/v1/admin`
+        api.scope("/admin", |admin| {
+            admin
+                .get(RequestPath::Explicit("/stats"), simple_response)
+                .get(RequestPath::WildCard(r"^/logs/\d+$"), simple_response);
+        });
+    });
+
+    server.listen(8080);
+}
+
+pub fn simple_response(req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send(&format!(
+        "Hello world from rusty server from {}!\n",
+        req.uri
+    ));
+    resp.status(200);
+}
+
+pub fn param_response(req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send(&format!("Hello world from rusty server from {}\n", req.uri));
+
+    for param in req.param_iter() {
+        resp.send(&format!("Param: [{}] --- Set as: [{}]\n", param.0, param.1));
+    }
+
+    resp.status(200);
+}
@@ -3,14 +3,28 @@
 use std::cmp;
 use std::fs::File;
 use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
 use std::ops::Div;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::core::cookie::CookiePolicy;
+use crate::core::cors::CorsPolicy;
+use crate::core::http::Request;
+use crate::core::ipfilter::IpFilter;
+use crate::core::router::Route;
+#[cfg(feature = "metrics")]
+use crate::core::router::{RequestPath, RouteHandler, REST};
+use crate::core::stats::{self, ConnError};
 use crate::hashbrown::HashMap;
 use crate::num_cpus;
-use crate::parking_lot::RwLock;
+use crate::parking_lot::{Mutex, RwLock};
 use crate::support::common::*;
+use crate::support::debug::{self, InfoLevel as DebugLevel};
+#[cfg(feature = "metrics")]
+use crate::support::metrics;
+use crate::support::{shared_pool, PoolEvent};
 use native_tls::{Identity, TlsAcceptor};
 use std::mem::MaybeUninit;
 
@@ -27,14 +41,55 @@ static mut VIEW_ENGINES: MaybeUninit<RwLock<HashMap<String, Box<ViewEngine>>>> =
     MaybeUninit::uninit();
 static mut METADATA_STORE: MaybeUninit<RwLock<ConnMetadata>> = MaybeUninit::uninit();
 
+/// The largest error page in bytes a `PageGenerator` may produce, beyond which the page is cut.
+const MAX_STATUS_PAGE_BYTES: usize = 1024 * 1024;
+
+/// The smallest stack size in bytes the worker threads can be set to.
+const MIN_STACK_SIZE: usize = 64 * 1024;
+
+/// The size under which a response is written to the connection in one go by default.
+pub(crate) const DEFAULT_COALESCE_BYTES: usize = 8 * 1024;
+
+/// The largest buffer of the pooled objects kept for the next use by default.
+pub(crate) const DEFAULT_SHRINK_BYTES: usize = 64 * 1024;
+
+/// The largest request head in bytes when no limit is set, such that a head that never ends can't
+/// take up the memory without bounds.
+pub(crate) const HEAD_BYTES_CAP: usize = 64 * 1024;
+
+#[cfg(test)]
+thread_local! {
+    static PAGE_TRUNCATIONS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
 pub struct ServerConfig {
+    bind_address: IpAddr,
     pool_size: usize,
+    pool_expansion_step: usize,
+    pool_idle_limit: usize,
+    reserved_workers: usize,
+    worker_stack_size: Option<usize>,
     read_timeout: u16,
     write_timeout: u16,
     read_limit: usize,
+    max_header_bytes: usize,
+    max_header_count: usize,
+    max_cookie_count: usize,
+    max_query_params: usize,
+    max_body_bytes: usize,
+    max_body_ceiling: usize,
+    line_endings: LineEndings,
+    coalesce_bytes: usize,
     tls_path: &'static str,
+    tls_source: Option<TlsSource>,
     use_session_autoclean: bool,
     session_auto_clean_period: Option<Duration>,
+    session_store_path: Option<PathBuf>,
+    log_folder_path: Option<PathBuf>,
+    cors: Option<Arc<CorsPolicy>>,
+    ip_filter: Option<Arc<IpFilter>>,
+    metrics_path: Option<String>,
+    https_redirect: Option<(u16, String)>,
 }
 
 impl ServerConfig {
@@ -43,6 +98,18 @@ impl ServerConfig {
         Default::default()
     }
 
+    /// The address that the server will be listening on with `listen(port)`, default to
+    /// `127.0.0.1`. Use `0.0.0.0` to accept connections from other machines.
+    #[inline]
+    pub fn set_bind_address(&mut self, addr: IpAddr) {
+        self.bind_address = addr;
+    }
+
+    #[inline]
+    pub fn get_bind_address(&self) -> IpAddr {
+        self.bind_address
+    }
+
     #[inline]
     pub fn get_pool_size(&self) -> usize {
         self.pool_size
@@ -53,6 +120,56 @@ impl ServerConfig {
         self.pool_size = size;
     }
 
+    /// The stack size in bytes of the worker threads, including the ones added when a pool
+    /// expands, and of the other long-lived threads of the server, e.g. for the handlers that
+    /// recurse deeply. Default to the platform's stack size. Sizes below 64KB are rejected.
+    pub fn set_worker_stack_size(&mut self, bytes: usize) -> Result<(), String> {
+        self.worker_stack_size = Some(check_stack_size(bytes)?);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get_worker_stack_size(&self) -> Option<usize> {
+        self.worker_stack_size
+    }
+
+    /// The number of workers to add to a busy pool at a time, default to 4.
+    #[inline]
+    pub fn set_pool_expansion_step(&mut self, step: usize) {
+        self.pool_expansion_step = step;
+    }
+
+    #[inline]
+    pub fn get_pool_expansion_step(&self) -> usize {
+        self.pool_expansion_step
+    }
+
+    /// The number of idle ticks, each is around 128ms, before an expanded worker retires, default
+    /// to 10.
+    #[inline]
+    pub fn set_pool_idle_limit(&mut self, limit: usize) {
+        self.pool_idle_limit = limit;
+    }
+
+    #[inline]
+    pub fn get_pool_idle_limit(&self) -> usize {
+        self.pool_idle_limit
+    }
+
+    /// The number of workers reserved for the connections from the high priority listeners, see
+    /// `HttpServer::listen_on_prioritized`, which never take the connections from the other
+    /// listeners; as many are reserved in each of the shared pools for the work of those
+    /// connections. Default to 2, and the workers are only launched if there's such a listener.
+    #[inline]
+    pub fn set_reserved_workers(&mut self, count: usize) {
+        self.reserved_workers = count;
+    }
+
+    #[inline]
+    pub fn get_reserved_workers(&self) -> usize {
+        self.reserved_workers
+    }
+
     #[inline]
     pub fn get_read_timeout(&self) -> u16 {
         self.read_timeout
@@ -74,7 +191,7 @@ impl ServerConfig {
     }
 
     /// The size of each request in bytes. If a request arrives with a larger size, we will drop the
-    /// request with an "Access Denied" message. If setting to 0, we will not enforce the size limit
+    /// request with a "413 Payload Too Large" message. If setting to 0, we will not enforce the size limit
     /// check and we will keep reading the request until read-timeout, which is default to 512ms,
     /// but can be changed with teh `set_read_timeout` function.
     #[inline]
@@ -88,6 +205,109 @@ impl ServerConfig {
         self.read_limit
     }
 
+    /// The largest size in bytes of the request head, i.e. the request line and the header fields.
+    /// Larger requests are rejected with "431 Request Header Fields Too Large" and the connection is
+    /// closed. Setting to 0 means the built-in limit of 64KB, which also holds the heads that never
+    /// end. Default to 0.
+    #[inline]
+    pub fn set_max_header_bytes(&mut self, limit: usize) {
+        self.max_header_bytes = limit;
+    }
+
+    #[inline]
+    pub fn get_max_header_bytes(&self) -> usize {
+        self.max_header_bytes
+    }
+
+    /// The largest number of header fields in a request. Requests with more header fields are
+    /// rejected with "431 Request Header Fields Too Large" before any of the fields is parsed, and
+    /// the connection is closed. Setting to 0 means no limit. Default to 100.
+    #[inline]
+    pub fn set_max_header_count(&mut self, limit: usize) {
+        self.max_header_count = limit;
+    }
+
+    #[inline]
+    pub fn get_max_header_count(&self) -> usize {
+        self.max_header_count
+    }
+
+    /// The largest number of cookies in a request, of all its `Cookie` header fields together.
+    /// Requests with more cookies are rejected with "413 Payload Too Large" before any of them is
+    /// parsed, and the connection is closed. Setting to 0 means no limit. Default to 100.
+    #[inline]
+    pub fn set_max_cookie_count(&mut self, limit: usize) {
+        self.max_cookie_count = limit;
+    }
+
+    #[inline]
+    pub fn get_max_cookie_count(&self) -> usize {
+        self.max_cookie_count
+    }
+
+    /// The largest number of parameters in the query string of a request. Requests with more
+    /// parameters are rejected with "400 Bad Request" before any of them is parsed, and the
+    /// connection is closed. Setting to 0 means no limit. Default to 256.
+    #[inline]
+    pub fn set_max_query_params(&mut self, limit: usize) {
+        self.max_query_params = limit;
+    }
+
+    #[inline]
+    pub fn get_max_query_params(&self) -> usize {
+        self.max_query_params
+    }
+
+    /// The largest size in bytes of the request body, unless the route sets its own limit. Requests
+    /// declaring a larger body are rejected with "413 Payload Too Large", and the body is drained
+    /// if it's small enough, or the connection is closed otherwise. Default to 0, i.e. no limit.
+    #[inline]
+    pub fn set_max_body_bytes(&mut self, limit: usize) {
+        self.max_body_bytes = limit;
+    }
+
+    #[inline]
+    pub fn get_max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// The absolute cap in bytes of the request body, which also applies to the routes overriding
+    /// the body size limit with their `RouteOptions`. Default to 0, i.e. no cap.
+    #[inline]
+    pub fn set_max_body_ceiling(&mut self, limit: usize) {
+        self.max_body_ceiling = limit;
+    }
+
+    #[inline]
+    pub fn get_max_body_ceiling(&self) -> usize {
+        self.max_body_ceiling
+    }
+
+    /// Whether the request lines may end with a bare LF instead of CRLF. Default to
+    /// `LineEndings::Lenient`, i.e. they may.
+    #[inline]
+    pub fn set_line_endings(&mut self, line_endings: LineEndings) {
+        self.line_endings = line_endings;
+    }
+
+    #[inline]
+    pub fn get_line_endings(&self) -> LineEndings {
+        self.line_endings
+    }
+
+    /// The responses smaller than this, header and body together, are written to the connection in
+    /// a single write, instead of the header and the body each in its own. Setting it to 0 turns
+    /// this off. Default to 8KB.
+    #[inline]
+    pub fn set_coalesce_threshold(&mut self, bytes: usize) {
+        self.coalesce_bytes = bytes;
+    }
+
+    #[inline]
+    pub fn get_coalesce_threshold(&self) -> usize {
+        self.coalesce_bytes
+    }
+
     #[inline]
     pub fn set_session_auto_clean(&mut self, auto_clean: bool) {
         self.use_session_autoclean = auto_clean;
@@ -108,23 +328,61 @@ impl ServerConfig {
         self.tls_path
     }
 
-    #[inline]
-    pub(crate) fn build_tls_acceptor(&mut self) -> Option<Arc<TlsAcceptor>> {
-        if self.tls_path.is_empty() {
-            return None;
-        }
+    /// Serve over TLS with the certificate chain and the PKCS #8 private key, both PEM encoded.
+    /// The files are read when the server launches, and again on `ControlMessage::ReloadTls`, such
+    /// that the renewed certificates can be picked up without a restart.
+    pub fn set_tls_from_pem<P: Into<PathBuf>>(&mut self, cert_path: P, key_path: P) {
+        self.tls_source = Some(TlsSource::Pem {
+            cert: cert_path.into(),
+            key: key_path.into(),
+        });
+    }
 
-        // read the identity from the file
-        let mut file = File::open(self.tls_path).unwrap();
-        let mut content = vec![];
-        file.read_to_end(&mut content).unwrap();
+    /// Serve over TLS with the identity from the PKCS #12 archive, see `set_tls_from_pem` for when
+    /// the file is read.
+    pub fn set_tls_from_pkcs12<P: Into<PathBuf>>(&mut self, path: P, password: &str) {
+        self.tls_source = Some(TlsSource::Pkcs12 {
+            path: path.into(),
+            password: String::from(password),
+        });
+    }
 
-        // create the acceptor using the provided identity
-        let identity = Identity::from_pkcs12(&content, "hunter2").unwrap();
-        let acceptor = Arc::new(TlsAcceptor::new(identity).unwrap());
+    pub fn clear_tls(&mut self) {
+        self.tls_source = None;
         self.tls_path = "";
+    }
+
+    /// Also listen on the `http_port` of the bind address, and answer every request there with a
+    /// `301` to the same path and query at `https://{https_authority}`, e.g. `example.com` or
+    /// `example.com:8443`. The redirect listener is stopped along with the server.
+    pub fn set_https_redirect(&mut self, http_port: u16, https_authority: &str) {
+        self.https_redirect = Some((http_port, String::from(https_authority)));
+    }
 
-        Some(acceptor)
+    #[inline]
+    pub fn get_https_redirect(&self) -> Option<(u16, &str)> {
+        self.https_redirect
+            .as_ref()
+            .map(|(port, authority)| (*port, authority.as_str()))
+    }
+
+    #[inline]
+    pub fn clear_https_redirect(&mut self) {
+        self.https_redirect = None;
+    }
+
+    /// Build the acceptor from the TLS identity set, or `None` if the server shall not use TLS.
+    pub(crate) fn build_tls_acceptor(&self) -> Result<Option<Arc<TlsAcceptor>>, String> {
+        match self.tls_source.as_ref() {
+            Some(source) => source.build().map(Some),
+            None if !self.tls_path.is_empty() => TlsSource::Pkcs12 {
+                path: PathBuf::from(self.tls_path),
+                password: String::from("hunter2"),
+            }
+            .build()
+            .map(Some),
+            None => Ok(None),
+        }
     }
 
     #[inline]
@@ -141,6 +399,32 @@ impl ServerConfig {
         self.session_auto_clean_period = Some(auto_clean_sec);
     }
 
+    /// Set the file that the session store will be persisted to when the server shuts down, which
+    /// can be loaded back with `PersistHandler::init_from_file` on the next launch. Default to none,
+    /// i.e. the sessions are gone with the server.
+    #[inline]
+    pub fn set_session_store_path(&mut self, path: Option<PathBuf>) {
+        self.session_store_path = path;
+    }
+
+    #[inline]
+    pub fn get_session_store_path(&self) -> Option<&PathBuf> {
+        self.session_store_path.as_ref()
+    }
+
+    /// Set the folder for the logging service to dump the log messages into, where the service is
+    /// only launched along with the server if the folder is set. The log messages still queued are
+    /// dumped when the server shuts down.
+    #[inline]
+    pub fn set_log_folder_path(&mut self, path: Option<PathBuf>) {
+        self.log_folder_path = path;
+    }
+
+    #[inline]
+    pub fn get_log_folder_path(&self) -> Option<&PathBuf> {
+        self.log_folder_path.as_ref()
+    }
+
     pub fn use_default_header(header: HashMap<String, String>) {
         let mut store = Self::metadata().write();
         (*store).header = header;
@@ -151,19 +435,352 @@ impl ServerConfig {
         (*store).header.add(&field[..], value, replace, false);
     }
 
+    /// The folder that the relative paths given to `send_file`, `send_file_async` and
+    /// `send_template` are resolved against, instead of the working directory of the process. The
+    /// files resolved outside of the folder, e.g. via `..` or a symlink, are not found. The absolute
+    /// paths are used as they are.
+    pub fn set_doc_root(root: PathBuf) {
+        // the resolved files are compared against the canonical form of the root
+        let root = root.canonicalize().unwrap_or_else(|err| {
+            rex_warn!(
+                "Unable to resolve the document root {}: {}",
+                root.display(),
+                err
+            );
+            root
+        });
+
+        let mut store = Self::metadata().write();
+        (*store).doc_root = Some(root);
+    }
+
+    pub fn clear_doc_root() {
+        let mut store = Self::metadata().write();
+        (*store).doc_root = None;
+    }
+
+    /// The folder that the relative paths given to `send_template` are resolved against, in place
+    /// of the document root set with `set_doc_root`. The view engines can also find the partials
+    /// and the layouts there with `get_views_root`. The templates resolved outside of the folder are
+    /// not found.
+    pub fn set_views_root(root: PathBuf) {
+        let root = root.canonicalize().unwrap_or_else(|err| {
+            rex_warn!(
+                "Unable to resolve the views root {}: {}",
+                root.display(),
+                err
+            );
+            root
+        });
+
+        let mut store = Self::metadata().write();
+        (*store).views_root = Some(root);
+    }
+
+    pub fn get_views_root() -> Option<PathBuf> {
+        ServerConfig::metadata().read().views_root.clone()
+    }
+
+    pub fn clear_views_root() {
+        let mut store = Self::metadata().write();
+        (*store).views_root = None;
+    }
+
+    /// Map the file extensions to the mime types used for the `Content-Type` of the files sent, e.g.
+    /// `"avif" => "image/avif"`. The map is checked before the built-in types, so it can also
+    /// correct a built-in one. The extensions are case-insensitive, and the leading '.' is optional.
+    pub fn set_mime_overrides(overrides: HashMap<String, String>) {
+        let mut store = Self::metadata().write();
+        (*store).mime_overrides = overrides
+            .into_iter()
+            .map(|(ext, mime)| (ext.trim_start_matches('.').to_lowercase(), mime))
+            .collect();
+    }
+
+    /// Generate the body of the error responses with the status, in place of the default page.
+    /// The generated page is reused by the error responses within the TTL set with
+    /// `set_status_page_ttl`, such that a burst of errors won't run the generator for each of them.
+    /// Pages larger than 1MB are cut at 1MB, and they're sent as `text/html`.
     pub fn set_status_page_generator(status: u16, generator: PageGenerator) {
+        Self::register_status_page(status, PageSource::Plain(generator), false);
+    }
+
+    /// Same as `set_status_page_generator`, but the generator is dynamic, i.e. its page may differ
+    /// each time, so the page is generated for every error response instead of being reused.
+    pub fn set_dynamic_status_page_generator(status: u16, generator: PageGenerator) {
+        Self::register_status_page(status, PageSource::Plain(generator), true);
+    }
+
+    /// How long a generated error page is reused for. Default to 1 second, and 0 to generate the
+    /// page for every error response.
+    pub fn set_status_page_ttl(ttl: Duration) {
+        let mut store = Self::metadata().write();
+        (*store).page_ttl = ttl;
+    }
+
+    /// Generate the body of the error responses with the status, along with its content type, which
+    /// is `text/html` if the generator returns `None`. Any status can have its page, e.g. 403, 429 or
+    /// a 503 maintenance page, and it applies to the errors from both the routes and the connection,
+    /// e.g. the 400 to a malformed request. The page is reused within the TTL like the pages from
+    /// `set_status_page_generator`.
+    pub fn set_status_page(status: u16, generator: StatusPageGenerator) {
+        Self::register_status_page(status, PageSource::Typed(generator), false);
+    }
+
+    /// Same as `set_status_page`, but the page is generated from the request being answered as well,
+    /// e.g. to name the missing path or to match the language of the client. Such a page is never
+    /// reused, and the errors without a request to go with it, e.g. the 400 to a request that
+    /// can't be parsed, get the `ErrorPageTemplate` or the built-in page instead.
+    pub fn set_request_status_page(status: u16, generator: RequestPageGenerator) {
+        Self::register_status_page(status, PageSource::Request(generator), true);
+    }
+
+    /// Generate the body of the error responses with the statuses that don't have their own page
+    /// set, from the status, along with its content type, which is `text/html` if the template
+    /// returns `None`. Without the template, the built-in pages are sent instead. The page is
+    /// reused within the TTL for the same status.
+    pub fn set_error_page_template(template: ErrorPageTemplate) {
+        let mut store = Self::metadata().write();
+        (*store).error_page_template = Some(template);
+        (*store).page_cache.lock().clear();
+    }
+
+    fn register_status_page(status: u16, generator: PageSource, dynamic: bool) {
         if status > 0 {
             let mut store = Self::metadata().write();
-            (*store).status_page_generators.insert(status, generator);
+            (*store)
+                .status_page_generators
+                .insert(status, (generator, dynamic));
+            (*store).page_cache.lock().remove(&status);
         }
     }
 
-    pub(crate) fn load_server_params(&self) -> (u64, u64, usize) {
-        (
-            u64::from(self.get_read_timeout()),
-            u64::from(self.get_write_timeout()),
-            self.get_read_limit().div(512),
-        )
+    /// The largest body in bytes that we will skip over when a request is rejected before being
+    /// served, e.g. when it fails the authorization, such that the requests pipelined after it can
+    /// still be served. If the rejected request declares a larger body, or its body hasn't fully
+    /// arrived yet, the connection is closed instead. Default to 16KB.
+    pub fn set_reject_drain_limit(limit: usize) {
+        let mut store = Self::metadata().write();
+        (*store).drain_limit = limit;
+    }
+
+    /// The largest message in bytes a client may send over a long connection, see
+    /// `ResponseStates::get_channels`. The connection is closed once a message grows beyond it.
+    /// Default to 64KB.
+    pub fn set_long_conn_message_limit(limit: usize) {
+        let mut store = Self::metadata().write();
+        (*store).message_limit = limit;
+    }
+
+    /// Cap the size in bytes of each part of the `multipart/form-data` bodies, and of all the parts
+    /// together, see `Request::multipart`. Default to 0 for either, i.e. no cap other than the
+    /// body size limit.
+    pub fn set_multipart_limits(part_bytes: usize, total_bytes: usize) {
+        let mut store = Self::metadata().write();
+        (*store).multipart_limits = (part_bytes, total_bytes);
+    }
+
+    /// The largest buffer in bytes, e.g. the body or the header fields, that the pooled requests
+    /// and responses keep for the next use when they're recycled. The larger ones are dropped, such
+    /// that a single large download doesn't pin its body in the pool for the life of the server.
+    /// It takes effect when the server is launched, and 0 keeps the buffers of any size. Default
+    /// to 64KB.
+    pub fn set_pool_shrink_threshold(bytes: usize) {
+        let mut store = Self::metadata().write();
+        (*store).pool_shrink_threshold = bytes;
+    }
+
+    /// Compress the response bodies with gzip or deflate, if the client accepts either encoding and
+    /// the response meets the requirements of the policy.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(policy: CompressionPolicy) {
+        let mut store = Self::metadata().write();
+        (*store).compression = Some(Arc::new(policy));
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn clear_compression() {
+        let mut store = Self::metadata().write();
+        (*store).compression = None;
+    }
+
+    /// Apply the defaults and the checks of the policy to every cookie the server sends, see
+    /// `CookiePolicy`.
+    pub fn cookie_policy(policy: CookiePolicy) {
+        let mut store = Self::metadata().write();
+        (*store).cookie_policy = Some(Arc::new(policy));
+    }
+
+    pub fn clear_cookie_policy() {
+        let mut store = Self::metadata().write();
+        (*store).cookie_policy = None;
+    }
+
+    /// Send the `Strict-Transport-Security` header with every response over TLS, unless the
+    /// handler has set one, such that the browsers will only reach the host over https afterwards.
+    pub fn set_hsts(max_age: Duration, include_subdomains: bool, preload: bool) {
+        let mut value = format!("max-age={}", max_age.as_secs());
+
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+
+        if preload {
+            value.push_str("; preload");
+        }
+
+        let mut store = Self::metadata().write();
+        (*store).hsts = Some(value);
+    }
+
+    pub fn clear_hsts() {
+        let mut store = Self::metadata().write();
+        (*store).hsts = None;
+    }
+
+    /// Set the max number of the route lookups to cache, such that the requests to the same uri
+    /// skip the walk over the params and wildcard routes. The cache is keyed on the request uri, so
+    /// it pays off when the same uris are requested over and over. The cache is cleared whenever
+    /// the routes change. Default to 0, i.e. the cache is disabled.
+    pub fn set_route_cache_size(size: usize) {
+        Route::set_cache_capacity(size);
+    }
+
+    /// Set the level of the debug messages to print, which can also be set with the `DEBUG_LEVEL`
+    /// environment variable when the server starts. Default to `DebugLevel::Silent`.
+    pub fn set_debug_level(level: DebugLevel) {
+        debug::set_debug_level(level);
+    }
+
+    /// Set the hook to observe the events from the thread pools, e.g. when a pool expands or when an
+    /// expanded worker retires after being idle.
+    pub fn on_pool_event(hook: fn(PoolEvent)) {
+        shared_pool::set_event_hook(Some(hook));
+    }
+
+    /// Set the hook to observe the connections that have failed before any request could be served,
+    /// e.g. to ban the peers that keep failing the TLS handshakes.
+    pub fn on_conn_error(hook: fn(ConnError)) {
+        stats::set_conn_error_hook(Some(hook));
+    }
+
+    /// Allow the cross-origin requests per the policy: the preflights are answered by the server
+    /// on behalf of the routes, and the responses to the allowed origins carry the CORS headers, see
+    /// the `cors` module. The policy can be changed while the server is running by hot loading the
+    /// config with `ControlMessage::HotLoadConfig`. The credentials are only allowed for the exact
+    /// origins, and they're dropped from a policy allowing any origin.
+    pub fn set_cors(&mut self, policy: CorsPolicy) {
+        if policy.allow_credentials && !policy.shares_credentials() {
+            rex_warn!("The credentials can't be allowed for any origin, list the origins instead");
+        }
+
+        self.cors = Some(Arc::new(policy));
+    }
+
+    pub fn clear_cors(&mut self) {
+        self.cors = None;
+    }
+
+    pub fn get_cors(&self) -> Option<&CorsPolicy> {
+        self.cors.as_ref().map(|policy| &**policy)
+    }
+
+    /// Screen the connections by the address of the peer as soon as they're accepted, see the
+    /// `ipfilter` module. The filter can be changed while the server is running by hot loading the
+    /// config with `ControlMessage::HotLoadConfig`.
+    pub fn set_ip_filter(&mut self, filter: IpFilter) {
+        self.ip_filter = Some(Arc::new(filter));
+    }
+
+    pub fn clear_ip_filter(&mut self) {
+        self.ip_filter = None;
+    }
+
+    pub fn get_ip_filter(&self) -> Option<&IpFilter> {
+        self.ip_filter.as_ref().map(|filter| &**filter)
+    }
+
+    pub(crate) fn load_ip_filter(&self) -> Option<Arc<IpFilter>> {
+        self.ip_filter.clone()
+    }
+
+    /// Serve the counters and the server stats in the Prometheus text format at the `path`, e.g.
+    /// `/metrics`, see the `metrics` module. It must be set before the server is launched.
+    #[cfg(feature = "metrics")]
+    pub fn enable_metrics(&mut self, path: &str) {
+        self.metrics_path = Some(String::from(path));
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn get_metrics_path(&self) -> Option<&str> {
+        self.metrics_path.as_ref().map(|path| &path[..])
+    }
+
+    /// Register the metrics endpoint with the router, if it's enabled.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn load_metrics(&self) {
+        if let Some(path) = self.metrics_path.as_ref() {
+            Route::add_route(
+                REST::GET,
+                RequestPath::Explicit(path),
+                RouteHandler::new(Some(metrics::serve), None),
+            );
+        }
+    }
+
+    /// Make the CORS policy of this config the one the connections are served with.
+    pub(crate) fn load_cors(&self) {
+        let mut store = Self::metadata().write();
+        (*store).cors = self.cors.clone();
+    }
+
+    pub(crate) fn load_conn_params(&self) -> ConnParams {
+        ConnParams {
+            read_timeout: u64::from(self.get_read_timeout()),
+            write_timeout: u64::from(self.get_write_timeout()),
+            req_limit: self.get_read_limit().div(512),
+            limits: self.load_conn_limits(),
+        }
+    }
+
+    /// Take a snapshot of the tunables, e.g. to show the config the server is running with, see
+    /// `QueryKind::EffectiveConfig`.
+    pub fn snapshot(&self) -> ConfigSnapshot {
+        ConfigSnapshot {
+            bind_address: self.bind_address,
+            pool_size: self.pool_size,
+            pool_expansion_step: self.pool_expansion_step,
+            pool_idle_limit: self.pool_idle_limit,
+            reserved_workers: self.reserved_workers,
+            worker_stack_size: self.worker_stack_size,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            read_limit: self.read_limit,
+            max_header_bytes: self.max_header_bytes,
+            max_header_count: self.max_header_count,
+            max_cookie_count: self.max_cookie_count,
+            max_query_params: self.max_query_params,
+            max_body_bytes: self.max_body_bytes,
+            max_body_ceiling: self.max_body_ceiling,
+            coalesce_bytes: self.coalesce_bytes,
+            tls: self.tls_source.is_some() || !self.tls_path.is_empty(),
+            https_redirect_port: self.https_redirect.as_ref().map(|(port, _)| *port),
+            session_auto_clean_period: self.session_auto_clean_period,
+            metrics_path: self.metrics_path.clone(),
+        }
+    }
+
+    pub(crate) fn load_conn_limits(&self) -> ConnLimits {
+        ConnLimits {
+            header_bytes: self.max_header_bytes,
+            header_count: self.max_header_count,
+            cookie_count: self.max_cookie_count,
+            query_params: self.max_query_params,
+            body_bytes: self.max_body_bytes,
+            body_ceiling: self.max_body_ceiling,
+            line_endings: self.line_endings,
+            coalesce_bytes: self.coalesce_bytes,
+        }
     }
 
     #[inline]
@@ -176,6 +793,62 @@ impl ServerConfig {
     }
 }
 
+/// Where the TLS identity is loaded from.
+enum TlsSource {
+    Pem { cert: PathBuf, key: PathBuf },
+    Pkcs12 { path: PathBuf, password: String },
+}
+
+impl TlsSource {
+    fn build(&self) -> Result<Arc<TlsAcceptor>, String> {
+        let identity = match self {
+            TlsSource::Pem { cert, key } => {
+                let cert_pem = read_tls_file(cert, "certificate")?;
+                let key_pem = read_tls_file(key, "private key")?;
+
+                Identity::from_pkcs8(&cert_pem, &key_pem).map_err(|err| {
+                    format!(
+                        "Unable to load the TLS identity from {:?} and {:?}: {}",
+                        cert, key, err
+                    )
+                })?
+            }
+            TlsSource::Pkcs12 { path, password } => {
+                let archive = read_tls_file(path, "PKCS #12 archive")?;
+
+                Identity::from_pkcs12(&archive, password).map_err(|err| {
+                    format!("Unable to load the TLS identity from {:?}: {}", path, err)
+                })?
+            }
+        };
+
+        TlsAcceptor::new(identity)
+            .map(Arc::new)
+            .map_err(|err| format!("Unable to build the TLS acceptor: {}", err))
+    }
+}
+
+fn check_stack_size(bytes: usize) -> Result<usize, String> {
+    if bytes < MIN_STACK_SIZE {
+        return Err(format!(
+            "The stack size of {} bytes is too small, it shall be at least {} bytes",
+            bytes, MIN_STACK_SIZE
+        ));
+    }
+
+    Ok(bytes)
+}
+
+fn read_tls_file(path: &PathBuf, kind: &str) -> Result<Vec<u8>, String> {
+    let mut content = Vec::new();
+
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut content))
+        .map_err(|err| format!("Unable to read the TLS {} from {:?}: {}", kind, path, err))?;
+
+    Ok(content)
+}
+
 impl Default for ServerConfig {
     fn default() -> Self {
         unsafe {
@@ -188,13 +861,33 @@ impl Default for ServerConfig {
         let path = option_env!("TLS_PATH").unwrap_or("");
 
         ServerConfig {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
             pool_size: cmp::max(4 * num_cpus::get(), 8),
+            pool_expansion_step: 4,
+            pool_idle_limit: 10,
+            reserved_workers: 2,
+            worker_stack_size: None,
             read_timeout: 512,
             write_timeout: 0,
             read_limit: 0,
+            max_header_bytes: 0,
+            max_header_count: 100,
+            max_cookie_count: 100,
+            max_query_params: 256,
+            max_body_bytes: 0,
+            max_body_ceiling: 0,
+            line_endings: LineEndings::Lenient,
+            coalesce_bytes: DEFAULT_COALESCE_BYTES,
             tls_path: path,
+            tls_source: None,
             use_session_autoclean: false,
             session_auto_clean_period: Some(Duration::from_secs(3600)),
+            session_store_path: None,
+            log_folder_path: None,
+            cors: None,
+            ip_filter: None,
+            metrics_path: None,
+            https_redirect: None,
         }
     }
 }
@@ -202,9 +895,11 @@ impl Default for ServerConfig {
 /// Function type alias `ViewEngine` represents the function signature required for the external
 /// view engine framework to be used in the Rusty_Express. Each engine shall be specific to handle one
 /// type of html-template. The 1st parameter represents the raw template content in string format,
-/// while the 2nd parameter represents the rendering context -- the information required to render
-/// the template into customisable webpage.
-pub type ViewEngine = fn(&mut String, Box<dyn EngineContext + Send + Sync>) -> u16;
+/// the 2nd parameter is the resolved path of the template, such that the engine can load the
+/// partials or the layouts next to it (or under `ServerConfig::get_views_root`), while the 3rd
+/// parameter represents the rendering context -- the information required to render the template
+/// into customisable webpage.
+pub type ViewEngine = fn(&mut String, &Path, Box<dyn EngineContext + Send + Sync>) -> u16;
 
 /// In order to streamline the way to supply rendering context information to the underlying template
 /// engines, the `EngineContext` trait is required to be implemented by the `ViewEngine` framework's
@@ -239,45 +934,146 @@ pub trait EngineContext {
     /// and the `context.display("msg")` returns `Ok(String::from("A Secret Message!"))`, then the
     /// rendered content could be `<p>A Secret Message!</p>`
     fn display(&self, field: &str) -> Result<String, String>;
+
+    /// `get_value` provides the structured value at the dotted path, e.g. `user.name` or
+    /// `items.3.title`, such that the engines can iterate the lists or walk into the nested
+    /// objects. The default implementation adapts the `display` function, i.e. every path is taken
+    /// as a flat field holding a string.
+    fn get_value(&self, path: &str) -> Option<ContextValue> {
+        self.display(path).ok().map(ContextValue::Str)
+    }
+
+    /// `layout` names the template that the rendered page shall be placed into, e.g. `main` or
+    /// `layout.hbs`, or `None` if the page stands on its own. It's up to the engine to resolve the
+    /// name, e.g. the built-in `template::render` looks it up next to the page template. The
+    /// `ResponseWriter::send_template_with_layout` function provides the name on the context's
+    /// behalf.
+    fn layout(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The structured value in the rendering context, see `EngineContext::get_value`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ContextValue {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    List(Vec<ContextValue>),
+    Map(HashMap<String, ContextValue>),
+}
+
+impl ContextValue {
+    /// Find the value at the dotted path, where the segments are the keys of the maps, or the
+    /// indices of the lists. The empty path is the value itself.
+    pub fn lookup(&self, path: &str) -> Option<&ContextValue> {
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        path.split('.')
+            .try_fold(self, |value, segment| match value {
+                ContextValue::Map(map) => map.get(segment),
+                ContextValue::List(list) => segment.parse::<usize>().ok().and_then(|i| list.get(i)),
+                _ => None,
+            })
+    }
+
+    /// The text of the scalar values, or `None` for the lists and the maps. The whole numbers are
+    /// written without the fraction, e.g. `3` rather than `3.0`.
+    pub fn as_text(&self) -> Option<String> {
+        match self {
+            ContextValue::Str(s) => Some(s.clone()),
+            ContextValue::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => {
+                Some(format!("{}", *n as i64))
+            }
+            ContextValue::Num(n) => Some(n.to_string()),
+            ContextValue::Bool(b) => Some(b.to_string()),
+            ContextValue::List(_) | ContextValue::Map(_) => None,
+        }
+    }
+}
+
+/// A `ContextValue`, usually a `ContextValue::Map`, can be the rendering context by itself.
+impl EngineContext for ContextValue {
+    fn display(&self, field: &str) -> Result<String, String> {
+        self.lookup(field)
+            .and_then(ContextValue::as_text)
+            .ok_or_else(|| format!("Unable to provide information for the key: {}", field))
+    }
+
+    fn get_value(&self, path: &str) -> Option<ContextValue> {
+        self.lookup(path).cloned()
+    }
 }
 
 pub trait ViewEngineDefinition {
     fn view_engine(extension: &str, engine: ViewEngine);
+
+    /// Render the templates with any of the extensions, e.g. `&["hbs", "handlebars"]`, with the
+    /// same engine.
+    fn view_engine_multi(extensions: &[&str], engine: ViewEngine) {
+        for extension in extensions {
+            Self::view_engine(extension, engine);
+        }
+    }
 }
 
 impl ViewEngineDefinition for ServerConfig {
+    #[inline]
     fn view_engine(extension: &str, engine: ViewEngine) {
-        if extension.is_empty() {
-            return;
-        }
+        Self::view_engine_multi(&[extension], engine);
+    }
 
+    fn view_engine_multi(extensions: &[&str], engine: ViewEngine) {
         let mut engines = ServerConfig::view_engines().write();
-        (*engines).insert(extension.to_owned(), Box::new(engine));
+
+        for extension in extensions.iter().map(|ext| ext.trim_start_matches('.')) {
+            if !extension.is_empty() {
+                (*engines).insert(extension.to_owned(), Box::new(engine));
+            }
+        }
     }
 }
 
 pub trait ViewEngineParser {
     fn template_parser<T: EngineContext + Send + Sync + 'static>(
-        extension: &str,
+        path: &Path,
         source: Vec<u8>,
         context: Box<T>,
+        fallback: Option<&str>,
     ) -> (u16, Vec<u8>);
 }
 
 impl ViewEngineParser for ServerConfig {
+    /// Render the template at the resolved path with the engine registered for its extension, or
+    /// with the one registered for the `fallback` extension if its own extension has none.
     fn template_parser<T: EngineContext + Send + Sync + 'static>(
-        extension: &str,
+        path: &Path,
         source: Vec<u8>,
         context: Box<T>,
+        fallback: Option<&str>,
     ) -> (u16, Vec<u8>) {
-        if extension.is_empty() {
-            return (0, Vec::new());
-        }
+        let extension = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if !ext.is_empty() => ext,
+            _ => return (0, Vec::new()),
+        };
 
         match String::from_utf8(source) {
             Ok(mut s) => {
-                if let Some(engine) = ServerConfig::view_engines().read().get(extension) {
-                    let code = engine(&mut s, context);
+                // the engine may take a while, and it may also read the config, so don't hold the lock
+                let engine = {
+                    let engines = ServerConfig::view_engines().read();
+                    engines
+                        .get(extension)
+                        .or_else(|| {
+                            fallback.and_then(|ext| engines.get(ext.trim_start_matches('.')))
+                        })
+                        .map(|engine| **engine)
+                };
+
+                if let Some(engine) = engine {
+                    let code = engine(&mut s, path, context);
                     return (code, Vec::from(s.as_bytes()));
                 }
 
@@ -290,19 +1086,315 @@ impl ViewEngineParser for ServerConfig {
 
 pub type PageGenerator = fn() -> String;
 
+/// Generate an error page, along with its content type, or `None` for `text/html`.
+pub type StatusPageGenerator = fn() -> (String, Option<String>);
+
+/// Generate the error page of the status, along with its content type, or `None` for `text/html`.
+pub type ErrorPageTemplate = fn(u16) -> (String, Option<String>);
+
+/// Generate the error page of the status for the request, along with its content type, or `None`
+/// for `text/html`.
+pub type RequestPageGenerator = fn(u16, &Box<Request>) -> (String, Option<String>);
+
+/// An error page and its content type, where `None` stands for `text/html`.
+pub(crate) type StatusPage = (Vec<u8>, Option<String>);
+
+#[derive(Clone, Copy)]
+enum PageSource {
+    Plain(PageGenerator),
+    Typed(StatusPageGenerator),
+    Template(ErrorPageTemplate),
+    Request(RequestPageGenerator),
+}
+
+impl PageSource {
+    fn generate(self, status: u16, request: Option<&Box<Request>>) -> StatusPage {
+        let (page, content_type) = match (self, request) {
+            (PageSource::Plain(generator), _) => (generator(), None),
+            (PageSource::Typed(generator), _) => generator(),
+            (PageSource::Template(template), _) => template(status),
+            (PageSource::Request(generator), Some(request)) => generator(status, request),
+            (PageSource::Request(_), None) => (String::new(), None),
+        };
+
+        (cap_status_page(status, page), content_type)
+    }
+}
+
+/// The rules to decide if a response body shall be compressed: the body must be at least
+/// `min_size` bytes long, and its content type must start with one of the `content_types`
+/// prefixes, e.g. `text/` or `application/json`.
+#[cfg(feature = "compression")]
+#[derive(Clone, Debug)]
+pub struct CompressionPolicy {
+    pub min_size: usize,
+    pub content_types: Vec<String>,
+}
+
+#[cfg(feature = "compression")]
+impl CompressionPolicy {
+    pub fn new(min_size: usize, content_types: Vec<String>) -> Self {
+        CompressionPolicy {
+            min_size,
+            content_types,
+        }
+    }
+
+    pub(crate) fn allows(&self, content_type: &str, size: usize) -> bool {
+        if size < self.min_size || content_type.is_empty() {
+            return false;
+        }
+
+        let content_type = content_type.to_lowercase();
+        self.content_types
+            .iter()
+            .any(|prefix| content_type.starts_with(&prefix.to_lowercase()))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            min_size: 1024,
+            content_types: vec![
+                String::from("text/"),
+                String::from("application/json"),
+                String::from("application/javascript"),
+                String::from("application/xml"),
+            ],
+        }
+    }
+}
+
+/// How the request parser treats the lines ending with a bare LF, i.e. `\n`, instead of CRLF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEndings {
+    /// Take a bare LF as a line end wherever CRLF is expected, including the empty line ending the
+    /// request head, since some embedded clients send them.
+    Lenient,
+    /// Only take CRLF as a line end, and reject the requests with a bare LF in the head with
+    /// "400 Bad Request", closing the connection as well.
+    Strict,
+}
+
+impl Default for LineEndings {
+    fn default() -> Self {
+        LineEndings::Lenient
+    }
+}
+
+/// The tunables of a `ServerConfig` at the time of `ServerConfig::snapshot`, which can be written as
+/// JSON with `to_json`, e.g. for an admin tool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigSnapshot {
+    pub bind_address: IpAddr,
+    pub pool_size: usize,
+    pub pool_expansion_step: usize,
+    pub pool_idle_limit: usize,
+    pub reserved_workers: usize,
+    pub worker_stack_size: Option<usize>,
+    pub read_timeout: u16,
+    pub write_timeout: u16,
+    pub read_limit: usize,
+    pub max_header_bytes: usize,
+    pub max_header_count: usize,
+    pub max_cookie_count: usize,
+    pub max_query_params: usize,
+    pub max_body_bytes: usize,
+    pub max_body_ceiling: usize,
+    pub coalesce_bytes: usize,
+    pub tls: bool,
+    pub https_redirect_port: Option<u16>,
+    pub session_auto_clean_period: Option<Duration>,
+    pub metrics_path: Option<String>,
+}
+
+impl ConfigSnapshot {
+    /// Write the snapshot as a JSON object, where the durations are in seconds, and the fields not
+    /// set are `null`.
+    pub fn to_json(&self) -> String {
+        fn text(value: &str) -> String {
+            format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+
+        fn or_null<T: ToString>(value: Option<T>) -> String {
+            value.map_or_else(|| String::from("null"), |v| v.to_string())
+        }
+
+        let fields = [
+            ("bind_address", text(&self.bind_address.to_string())),
+            ("pool_size", self.pool_size.to_string()),
+            ("pool_expansion_step", self.pool_expansion_step.to_string()),
+            ("pool_idle_limit", self.pool_idle_limit.to_string()),
+            ("reserved_workers", self.reserved_workers.to_string()),
+            ("worker_stack_size", or_null(self.worker_stack_size)),
+            ("read_timeout", self.read_timeout.to_string()),
+            ("write_timeout", self.write_timeout.to_string()),
+            ("read_limit", self.read_limit.to_string()),
+            ("max_header_bytes", self.max_header_bytes.to_string()),
+            ("max_header_count", self.max_header_count.to_string()),
+            ("max_cookie_count", self.max_cookie_count.to_string()),
+            ("max_query_params", self.max_query_params.to_string()),
+            ("max_body_bytes", self.max_body_bytes.to_string()),
+            ("max_body_ceiling", self.max_body_ceiling.to_string()),
+            ("coalesce_bytes", self.coalesce_bytes.to_string()),
+            ("tls", self.tls.to_string()),
+            ("https_redirect_port", or_null(self.https_redirect_port)),
+            (
+                "session_auto_clean_period",
+                or_null(self.session_auto_clean_period.map(|p| p.as_secs())),
+            ),
+            (
+                "metrics_path",
+                or_null(self.metrics_path.as_deref().map(text)),
+            ),
+        ];
+
+        let body: Vec<String> = fields
+            .iter()
+            .map(|(field, value)| format!("\"{}\":{}", field, value))
+            .collect();
+
+        format!("{{{}}}", body.join(","))
+    }
+}
+
+/// What each connection is served with: the socket timeouts in milliseconds, where 0 means no
+/// timeout, the cap on the requests read, and the limits of each request.
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct ConnParams {
+    pub(crate) read_timeout: u64,
+    pub(crate) write_timeout: u64,
+    pub(crate) req_limit: usize,
+    pub(crate) limits: ConnLimits,
+}
+
+/// The limits on the size of each request served by a connection, where 0 means no limit except
+/// for the head, see `head_limit`, the line ends the requests may use, and the size under which a
+/// response is written in one go.
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct ConnLimits {
+    pub(crate) header_bytes: usize,
+    pub(crate) header_count: usize,
+    pub(crate) cookie_count: usize,
+    pub(crate) query_params: usize,
+    pub(crate) body_bytes: usize,
+    pub(crate) body_ceiling: usize,
+    pub(crate) line_endings: LineEndings,
+    pub(crate) coalesce_bytes: usize,
+}
+
+impl ConnLimits {
+    /// The size limit of a request head: the configured one, or the built-in cap if none is set.
+    pub(crate) fn head_limit(&self) -> usize {
+        match self.header_bytes {
+            0 => HEAD_BYTES_CAP,
+            limit => limit,
+        }
+    }
+
+    /// The body size limit of a request: the route's own limit if it has one, or the global one
+    /// otherwise, and neither can go beyond the ceiling.
+    pub(crate) fn body_limit(&self, route_limit: Option<usize>) -> usize {
+        let limit = route_limit.unwrap_or(self.body_bytes);
+
+        match (limit, self.body_ceiling) {
+            (_, 0) => limit,
+            (0, ceiling) => ceiling,
+            (limit, ceiling) => limit.min(ceiling),
+        }
+    }
+}
+
 pub struct ConnMetadata {
     header: HashMap<String, String>,
-    status_page_generators: HashMap<u16, PageGenerator>,
+    mime_overrides: HashMap<String, String>,
+    doc_root: Option<PathBuf>,
+    views_root: Option<PathBuf>,
+    status_page_generators: HashMap<u16, (PageSource, bool)>,
+    error_page_template: Option<ErrorPageTemplate>,
+    page_ttl: Duration,
+    page_cache: Arc<Mutex<HashMap<u16, (Instant, StatusPage)>>>,
+    drain_limit: usize,
+    message_limit: usize,
+    multipart_limits: (usize, usize),
+    pool_shrink_threshold: usize,
+    cors: Option<Arc<CorsPolicy>>,
+    hsts: Option<String>,
+    cookie_policy: Option<Arc<CookiePolicy>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Arc<CompressionPolicy>>,
 }
 
 impl ConnMetadata {
     pub fn new() -> Self {
         ConnMetadata {
             header: HashMap::new(),
+            mime_overrides: HashMap::new(),
+            doc_root: None,
+            views_root: None,
             status_page_generators: HashMap::new(),
+            error_page_template: None,
+            page_ttl: Duration::from_secs(1),
+            page_cache: Arc::new(Mutex::new(HashMap::new())),
+            drain_limit: 16 * 1024,
+            message_limit: 64 * 1024,
+            multipart_limits: (0, 0),
+            pool_shrink_threshold: DEFAULT_SHRINK_BYTES,
+            cors: None,
+            hsts: None,
+            cookie_policy: None,
+            #[cfg(feature = "compression")]
+            compression: None,
         }
     }
 
+    #[inline]
+    pub(crate) fn get_drain_limit() -> usize {
+        ServerConfig::metadata().read().drain_limit
+    }
+
+    #[inline]
+    pub(crate) fn get_doc_root() -> Option<PathBuf> {
+        ServerConfig::metadata().read().doc_root.clone()
+    }
+
+    #[inline]
+    pub(crate) fn get_message_limit() -> usize {
+        ServerConfig::metadata().read().message_limit
+    }
+
+    #[inline]
+    pub(crate) fn get_multipart_limits() -> (usize, usize) {
+        ServerConfig::metadata().read().multipart_limits
+    }
+
+    #[inline]
+    pub(crate) fn get_pool_shrink_threshold() -> usize {
+        ServerConfig::metadata().read().pool_shrink_threshold
+    }
+
+    #[inline]
+    pub(crate) fn get_cors() -> Option<Arc<CorsPolicy>> {
+        ServerConfig::metadata().read().cors.clone()
+    }
+
+    #[inline]
+    pub(crate) fn get_cookie_policy() -> Option<Arc<CookiePolicy>> {
+        ServerConfig::metadata().read().cookie_policy.clone()
+    }
+
+    #[inline]
+    pub(crate) fn get_hsts() -> Option<String> {
+        ServerConfig::metadata().read().hsts.clone()
+    }
+
+    #[cfg(feature = "compression")]
+    pub(crate) fn get_compression() -> Option<Arc<CompressionPolicy>> {
+        ServerConfig::metadata().read().compression.clone()
+    }
+
     #[inline]
     pub fn get_default_header() -> Option<HashMap<String, String>> {
         let store = ServerConfig::metadata().read();
@@ -313,13 +1405,272 @@ impl ConnMetadata {
         None
     }
 
+    /// The mime type set for the file extension with `ServerConfig::set_mime_overrides`, where the
+    /// extension shall be in lower case.
     #[inline]
-    pub(crate) fn get_status_pages(status: u16) -> Option<PageGenerator> {
+    pub(crate) fn get_mime_override(ext: &str) -> Option<String> {
         let store = ServerConfig::metadata().read();
-        if store.status_page_generators.is_empty() {
+        if store.mime_overrides.is_empty() {
             return None;
         }
 
-        store.status_page_generators.get(&status).cloned()
+        store.mime_overrides.get(ext).cloned()
+    }
+
+    /// The error page for the status and its content type, from the generator of the status if one
+    /// is set, or else from the `ErrorPageTemplate` if one is set. The page is taken from the cache
+    /// if it has been generated within the TTL, unless the generator is dynamic. The generators
+    /// taking the request are skipped if there's no request to answer.
+    pub(crate) fn get_status_page(
+        status: u16,
+        request: Option<&Box<Request>>,
+    ) -> Option<StatusPage> {
+        // the generator runs out of the lock, as it may read the metadata as well
+        let (source, dynamic, ttl, cache) = {
+            let store = ServerConfig::metadata().read();
+            if store.status_page_generators.is_empty() && store.error_page_template.is_none() {
+                return None;
+            }
+
+            let (source, dynamic) = match store.status_page_generators.get(&status) {
+                Some((PageSource::Request(_), _)) if request.is_none() => {
+                    (PageSource::Template(store.error_page_template?), false)
+                }
+                Some(generator) => *generator,
+                None => (PageSource::Template(store.error_page_template?), false),
+            };
+
+            (
+                source,
+                dynamic,
+                store.page_ttl,
+                Arc::clone(&store.page_cache),
+            )
+        };
+
+        if dynamic || ttl == Duration::from_secs(0) {
+            return Some(source.generate(status, request));
+        }
+
+        // the burst of errors waits for the page being generated, instead of generating their own
+        let mut cache = cache.lock();
+        if let Some((generated, page)) = cache.get(&status) {
+            if generated.elapsed() < ttl {
+                return Some(page.clone());
+            }
+        }
+
+        let page = source.generate(status, request);
+        cache.insert(status, (Instant::now(), page.clone()));
+
+        Some(page)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn page_truncations() -> usize {
+        PAGE_TRUNCATIONS.with(|count| count.get())
+    }
+}
+
+fn cap_status_page(status: u16, mut page: String) -> Vec<u8> {
+    if page.len() > MAX_STATUS_PAGE_BYTES {
+        rex_warn!(
+            "The page generated for the status {} is {} bytes, and will be cut to {} bytes",
+            status,
+            page.len(),
+            MAX_STATUS_PAGE_BYTES
+        );
+
+        #[cfg(test)]
+        PAGE_TRUNCATIONS.with(|count| count.set(count.get() + 1));
+
+        // cut at the last whole character
+        let mut end = MAX_STATUS_PAGE_BYTES;
+        while !page.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        page.truncate(end);
+    }
+
+    page.into_bytes()
+}
+
+/// Initialize the global stores for the tests, which share them across the threads; the stores
+/// must be initialized only once, or a test may lose the settings made by another.
+#[cfg(test)]
+pub(crate) fn init_test_config() {
+    use std::sync::Once;
+
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        ServerConfig::new();
+    });
+}
+
+/// Set the CORS policy for the tests, without replacing the global stores like `ServerConfig::new`
+/// would. The policy lasts as long as the returned guard, which puts back the previous one.
+#[cfg(test)]
+pub(crate) fn set_test_cors(policy: Option<CorsPolicy>) -> TestCors {
+    init_test_config();
+
+    let mut store = ServerConfig::metadata().write();
+    TestCors(std::mem::replace(&mut store.cors, policy.map(Arc::new)))
+}
+
+#[cfg(test)]
+pub(crate) struct TestCors(Option<Arc<CorsPolicy>>);
+
+#[cfg(test)]
+impl Drop for TestCors {
+    fn drop(&mut self) {
+        let mut store = ServerConfig::metadata().write();
+        store.cors = self.0.take();
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::{
+        check_stack_size, init_test_config, ConfigSnapshot, ConnMetadata, ServerConfig, TlsSource,
+        MAX_STATUS_PAGE_BYTES,
+    };
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::str;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    static CACHED_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static DYNAMIC_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn cached_page() -> String {
+        CACHED_CALLS.fetch_add(1, Ordering::SeqCst);
+        String::from("busy")
+    }
+
+    fn dynamic_page() -> String {
+        DYNAMIC_CALLS.fetch_add(1, Ordering::SeqCst);
+        String::from("fresh")
+    }
+
+    fn huge_page() -> String {
+        "\u{e9}".repeat(MAX_STATUS_PAGE_BYTES)
+    }
+
+    fn typed_page() -> (String, Option<String>) {
+        (
+            String::from("{\"retry\":true}"),
+            Some(String::from("application/json")),
+        )
+    }
+
+    #[test]
+    fn worker_stack_size() {
+        assert!(check_stack_size(0).is_err());
+        assert!(check_stack_size(16 * 1024).is_err());
+        assert_eq!(check_stack_size(64 * 1024), Ok(64 * 1024));
+        assert_eq!(check_stack_size(8 * 1024 * 1024), Ok(8 * 1024 * 1024));
+    }
+
+    #[test]
+    fn snapshot_json() {
+        let snapshot = ConfigSnapshot {
+            bind_address: [127, 0, 0, 1].into(),
+            pool_size: 8,
+            pool_expansion_step: 2,
+            pool_idle_limit: 8,
+            reserved_workers: 2,
+            worker_stack_size: None,
+            read_timeout: 512,
+            write_timeout: 0,
+            read_limit: 1024,
+            max_header_bytes: 0,
+            max_header_count: 100,
+            max_cookie_count: 100,
+            max_query_params: 256,
+            max_body_bytes: 0,
+            max_body_ceiling: 0,
+            coalesce_bytes: 8192,
+            tls: false,
+            https_redirect_port: Some(80),
+            session_auto_clean_period: Some(Duration::from_secs(3600)),
+            metrics_path: Some(String::from("/a\"b")),
+        };
+
+        let json = snapshot.to_json();
+        assert!(json.starts_with("{\"bind_address\":\"127.0.0.1\",\"pool_size\":8,"));
+        assert!(json.contains(",\"reserved_workers\":2,\"worker_stack_size\":null,"));
+        assert!(json.contains(",\"tls\":false,\"https_redirect_port\":80,"));
+        assert!(json.ends_with(",\"session_auto_clean_period\":3600,\"metrics_path\":\"/a\\\"b\"}"));
+    }
+
+    #[test]
+    fn tls_sources() {
+        let pem = TlsSource::Pem {
+            cert: PathBuf::from("/no/such/cert.pem"),
+            key: PathBuf::from("/no/such/key.pem"),
+        };
+
+        let err = pem.build().err().unwrap();
+        assert!(err.contains("certificate"), "{}", err);
+        assert!(err.contains("/no/such/cert.pem"), "{}", err);
+
+        // an archive that's not PKCS #12 at all
+        let path = env::temp_dir().join("rex-tls-sources.p12");
+        fs::write(&path, b"not an archive").unwrap();
+
+        let pkcs12 = TlsSource::Pkcs12 {
+            path: path.clone(),
+            password: String::from("secret"),
+        };
+
+        let err = pkcs12.build().err().unwrap();
+        assert!(
+            err.starts_with("Unable to load the TLS identity"),
+            "{}",
+            err
+        );
+        fs::remove_file(&path).unwrap_or_default();
+    }
+
+    #[test]
+    fn status_pages() {
+        init_test_config();
+
+        // the statuses no other tests would generate pages for
+        ServerConfig::set_status_page_generator(597, cached_page);
+        ServerConfig::set_dynamic_status_page_generator(598, dynamic_page);
+        ServerConfig::set_status_page_generator(599, huge_page);
+        ServerConfig::set_status_page(596, typed_page);
+
+        for _ in 0..100 {
+            assert_eq!(
+                ConnMetadata::get_status_page(597, None).unwrap(),
+                (b"busy".to_vec(), None)
+            );
+            assert_eq!(
+                ConnMetadata::get_status_page(598, None).unwrap().0,
+                b"fresh"
+            );
+        }
+
+        assert!(CACHED_CALLS.load(Ordering::SeqCst) < 10);
+        assert_eq!(DYNAMIC_CALLS.load(Ordering::SeqCst), 100);
+        assert!(ConnMetadata::get_status_page(595, None).is_none());
+        assert_eq!(
+            ConnMetadata::get_status_page(596, None).unwrap(),
+            (
+                b"{\"retry\":true}".to_vec(),
+                Some(String::from("application/json"))
+            )
+        );
+
+        let truncations = ConnMetadata::page_truncations();
+        let (page, _) = ConnMetadata::get_status_page(599, None).unwrap();
+        assert_eq!(ConnMetadata::page_truncations(), truncations + 1);
+        assert_eq!(page.len(), MAX_STATUS_PAGE_BYTES);
+        assert!(str::from_utf8(&page).is_ok());
     }
 }
@@ -1,33 +1,64 @@
 #![allow(clippy::borrowed_box)]
 #![allow(dead_code)]
 
-use std::collections::BTreeMap;
+use std::cmp;
 use std::io::{prelude::*, BufWriter, ErrorKind};
-use std::net::{Shutdown, SocketAddr};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::str;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::core::config::ConnMetadata;
+use crate::core::config::{ConnLimits, ConnMetadata, LineEndings, DEFAULT_COALESCE_BYTES};
 use crate::core::http::{
     Request, RequestWriter, Response, ResponseManager, ResponseStates, ResponseWriter,
 };
-use crate::core::router::{Route, RouteHandler, RouteSeeker, REST};
+use crate::core::router::{Route, RouteHandler, RouteSeeker, RouterView, SeekResult, REST};
 use crate::core::stream::Stream;
-use crate::core::syncstore::Reusable;
+#[cfg(test)]
+use crate::core::syncstore::MissStats;
+use crate::core::syncstore::{Reusable, SyncPool};
+use crate::core::{context, cors, stats};
+use crate::parking_lot::Mutex;
+#[cfg(feature = "logger")]
+use crate::support::logger;
+#[cfg(feature = "metrics")]
+use crate::support::metrics;
 use crate::support::{
-    common::{MapUpdates, VecExt},
-    debug,
-    debug::InfoLevel,
-    shared_pool, TaskType,
+    common::{flush_buffer, percent_decode, HeaderMap, MapUpdates},
+    shared_pool,
+    span::{self, TraceIds},
+    TaskType,
 };
 
 use crate::channel::{self, Receiver, Sender};
 use crate::hashbrown::HashMap;
 
+#[cfg(feature = "websocket")]
+use crate::core::websocket::{WsReader, WsUpgrade};
+
 const BUFFER_SIZE: usize = 512;
+const RAW_BUF_CAP: usize = 64 * BUFFER_SIZE;
+const REORDER_CAP: usize = 32;
+const REDIRECT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_BUF_SIZE: usize = 8 * 1024;
+const SEEK_TIMEOUT: Duration = Duration::from_millis(128);
+const ABANDONED_CAP: usize = 64;
+
+const HANDLER_RUNNING: u8 = 0;
+const HANDLER_DONE: u8 = 1;
+const HANDLER_ABANDONED: u8 = 2;
+
+/// The number of the timed handlers that have overrun their timeouts, but are still running.
+static ABANDONED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref CONN_POOL: Mutex<Option<SyncPool<Arc<ConnContext>>>> = Mutex::new(None);
+}
 
 type ExecCode = u8;
-type BaseLine = Option<Receiver<(RouteHandler, HashMap<String, String>)>>;
+type BaseLine = Option<Receiver<SeekResult>>;
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 enum StreamException {
@@ -36,16 +67,110 @@ enum StreamException {
     ReadStreamFailure,
     AccessDenied,
     ServiceUnavailable,
+    HeaderTooLarge,
+    PayloadTooLarge,
+    ExpectationFailed,
+    MalformedRequest,
 }
 
 struct RespSeqBundle(usize, Box<Response>);
 
+/// The per-connection scratch space, checked out from the pool when serving a stream and handed
+/// back once the connection is done. The reader holds the `raw_buf` to accumulate the requests,
+/// and hands it over to the parser as a whole, carrying on with the `spare` buffer which the parser
+/// hands back once it's done with the last one. The writer holds the `reorder` store for the
+/// responses finished out of order. The channels
+/// between the pipeline stages can't be pooled here: their endpoints are moved into the reader and
+/// the parser tasks, and a channel is disconnected for good once either side is dropped.
+#[derive(Default)]
+pub(crate) struct ConnContext {
+    raw_buf: Mutex<Vec<u8>>,
+    spare: Mutex<Vec<u8>>,
+    reorder: Mutex<Vec<RespSeqBundle>>,
+    /// Set by the writer while a long connection is in service, then the reader passes the bytes
+    /// from the client over to it, instead of parsing them as requests.
+    feed: Mutex<Option<Sender<Vec<u8>>>>,
+}
+
+impl ConnContext {
+    /// The reader and the writer share the context, and the last one leaving hands it back.
+    fn leave(mut ctx: Arc<ConnContext>) {
+        if Arc::get_mut(&mut ctx).is_some() {
+            Box::new(ctx).release();
+        }
+    }
+}
+
+impl Reusable for Arc<ConnContext> {
+    fn obtain() -> Box<Self> {
+        match CONN_POOL.lock().as_mut() {
+            Some(pool) => pool.get(),
+            None => Default::default(),
+        }
+    }
+
+    fn release(mut self: Box<Self>) {
+        // the other end of the pipeline is still using it, it will be handed back from there
+        if Arc::get_mut(&mut self).is_none() {
+            return;
+        }
+
+        self.reset(false);
+
+        if let Some(pool) = CONN_POOL.lock().as_mut() {
+            pool.put(self);
+        }
+    }
+
+    fn reset(&mut self, hard: bool) {
+        let ctx = match Arc::get_mut(self) {
+            Some(ctx) => ctx,
+            None => return,
+        };
+
+        // clear the content, but keep the capacity unless it has grown over the cap
+        for buf in [ctx.raw_buf.get_mut(), ctx.spare.get_mut()] {
+            if hard || buf.capacity() > RAW_BUF_CAP {
+                *buf = Vec::new();
+            } else {
+                buf.clear();
+            }
+        }
+
+        let reorder = ctx.reorder.get_mut();
+        reorder.drain(..).for_each(|bundle| bundle.1.release());
+        ctx.feed.get_mut().take();
+
+        if hard || reorder.capacity() > REORDER_CAP {
+            *reorder = Vec::new();
+        }
+    }
+}
+
+pub(crate) fn init_pool() {
+    *CONN_POOL.lock() = Some(SyncPool::new());
+}
+
+pub(crate) fn drop_pool() {
+    let conn_pool = CONN_POOL.lock().take();
+    drop(conn_pool);
+}
+
+/// The contexts ready to be checked out, along with the counters of the pool.
+#[cfg(test)]
+fn pool_stats() -> (usize, MissStats) {
+    match CONN_POOL.lock().as_ref() {
+        Some(pool) => (pool.len(), pool.miss_stats()),
+        None => (0, MissStats::default()),
+    }
+}
+
 pub(crate) trait StreamHandler {
-    fn process(self, is_tls: bool, req_limit: usize);
+    fn process(self, is_tls: bool, req_limit: usize, limits: ConnLimits);
 }
 
 impl StreamHandler for Stream {
-    fn process(mut self, is_tls: bool, req_limit: usize) {
+    fn process(mut self, is_tls: bool, req_limit: usize, limits: ConnLimits) {
         // split the stream such that we can read while writing latest responses
         let mut reader_stream = match self.try_clone() {
             Ok(stream) => {
@@ -54,63 +179,271 @@ impl StreamHandler for Stream {
             }
             Err(_) => {
                 // failed to clone(?) and now try the old-fashion way to serve
-                async_handler::handle_connection(self);
+                async_handler::handle_connection(self, limits);
                 return;
             }
         };
 
+        // the scratch space shared by the reader and the writer of this connection
+        let ctx = <Arc<ConnContext>>::obtain();
+        let reader_ctx = Arc::clone(&ctx);
+        let parser_ctx = Arc::clone(&ctx);
+
+        // the connection-level work of all pipeline stages is traced with the connection's id
+        let conn_id = span::next_conn_id();
+        let conn_span = TraceIds::new(conn_id, 0);
+        let _span = span::enter(conn_span);
+
         // pipeline-1: keep listening to the reader stream
         let (sender, receiver) = channel::bounded(6);
-        shared_pool::run(
-            move || reader_stream.recv_requests(sender, req_limit),
+        shared_pool::run_traced(
+            move || {
+                reader_stream.recv_requests(
+                    sender,
+                    req_limit,
+                    &mut reader_ctx.raw_buf.lock(),
+                    &reader_ctx.spare,
+                    &reader_ctx.feed,
+                );
+                ConnContext::leave(reader_ctx);
+            },
             TaskType::StreamLoader,
+            conn_span,
         );
 
         // pipeline-2: once receiving a request, parse and serve, then send the response back to be written back
+        let coalesce = limits.coalesce_bytes;
         let (resp_tx, resp_rx) = channel::bounded(8);
         let addr = self.peer_addr();
-        shared_pool::run(
-            move || handle_requests(receiver, resp_tx, addr.ok(), is_tls),
+        shared_pool::run_traced(
+            move || {
+                handle_requests(
+                    receiver,
+                    resp_tx,
+                    &parser_ctx.spare,
+                    conn_id,
+                    addr.ok(),
+                    is_tls,
+                    limits,
+                );
+                ConnContext::leave(parser_ctx);
+            },
             TaskType::Parser,
+            conn_span,
         );
 
         // pipeline-end: receive the response, write them back
-        self.send_responses(resp_rx);
+        self.send_responses(resp_rx, &mut ctx.reorder.lock(), &ctx.feed, coalesce);
 
         // shut down the stream after we're done
         if let Err(err) = self.shutdown(Shutdown::Both) {
-            debug::print(
-                &format!(
-                    "Encountered errors while shutting down the trunked body stream: {}",
-                    err
-                ),
-                InfoLevel::Warning,
+            rex_warn!(
+                "Encountered errors while shutting down the trunked body stream: {}",
+                err
             );
         }
+
+        ctx.release();
+    }
+}
+
+/// Where the requests accumulated from the full reads end. A request ending right at the end of a
+/// full read isn't followed by a short read, which would otherwise send it for processing, so the
+/// reader checks if the requests buffered so far are complete, resuming from where the last check
+/// left off such that a large request isn't scanned all over again on each read.
+#[derive(Default)]
+struct Framing {
+    /// Where the request being framed starts.
+    start: usize,
+    /// How far the head of the request has been searched for its end.
+    scanned: usize,
+    /// Where the body of the request ends, once its head is complete.
+    body_end: Option<usize>,
+    /// If the head of the request has been framed since the buffer was last handed over, i.e. the
+    /// request is yet to be authorized.
+    fresh: bool,
+}
+
+impl Framing {
+    fn is_complete(&mut self, buf: &[u8]) -> bool {
+        loop {
+            if let Some(end) = self.body_end {
+                if buf.len() < end {
+                    return false;
+                }
+
+                self.start = end;
+                self.scanned = end;
+                self.body_end = None;
+                self.fresh = false;
+            }
+
+            // skip the line breaks and paddings between the requests
+            self.start += buf[self.start..]
+                .iter()
+                .position(|b| *b != b'\r' && *b != b'\n' && *b != 0)
+                .unwrap_or_else(|| buf.len() - self.start);
+
+            if self.start == buf.len() {
+                return true;
+            }
+
+            // the head terminator may straddle the reads; the bare LF line ends are framed in the
+            // strict mode too, such that the request can be rejected right away
+            let from = self.scanned.saturating_sub(3).max(self.start);
+            match find_head_end(&buf[from..]) {
+                Some((_, body_start)) => {
+                    let head_end = from + body_start;
+                    let declared = declared_length(&buf[self.start..head_end]);
+                    self.body_end = Some(head_end.saturating_add(declared));
+                    self.fresh = true;
+                }
+                None => {
+                    self.scanned = buf.len();
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// If the buffer ends with the head of a request whose body is yet to come, after `is_complete`
+    /// returned false.
+    fn holds_body(&self, buf: &[u8]) -> bool {
+        match self.body_end {
+            Some(end) => self.fresh && end > buf.len(),
+            None => false,
+        }
+    }
+
+    /// The buffer is handed over, carry on with the rest of the body of the last request, if any.
+    fn rebase(&mut self, len: usize) {
+        match self.body_end {
+            Some(end) if end > len => {
+                *self = Framing {
+                    body_end: Some(end - len),
+                    ..Default::default()
+                }
+            }
+            _ => self.reset(),
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+/// Find the empty line ending the request head, i.e. `\r\n\r\n`, or `\n\n` and the mixes of the
+/// two with the bare LF line ends. Return where the head ends, which excludes the line break of its
+/// last line but possibly the CR, and where the body starts.
+fn find_head_end(buf: &[u8]) -> Option<(usize, usize)> {
+    let mut from = 0;
+
+    while let Some(pos) = buf[from..].iter().position(|b| *b == b'\n') {
+        let lf = from + pos;
+
+        match buf.get(lf + 1) {
+            Some(b'\n') => return Some((lf, lf + 2)),
+            Some(b'\r') if buf.get(lf + 2) == Some(&b'\n') => return Some((lf, lf + 3)),
+            _ => from = lf + 1,
+        }
+    }
+
+    None
+}
+
+/// Check if any line of the request head ends with a bare LF rather than CRLF.
+fn has_bare_lf(head: &[u8]) -> bool {
+    head.iter()
+        .enumerate()
+        .any(|(i, b)| *b == b'\n' && (i == 0 || head[i - 1] != b'\r'))
+}
+
+/// The body size declared by the `Content-Length` field of the raw request head, or 0 if none.
+fn declared_length(head: &[u8]) -> usize {
+    head.split(|b| *b == b'\n')
+        .find_map(|line| {
+            let line = str::from_utf8(line).ok()?;
+            let mut field = line.splitn(2, ':');
+            if !field.next()?.trim().eq_ignore_ascii_case("content-length") {
+                return None;
+            }
+
+            field.next()?.trim().parse::<usize>().ok()
+        })
+        .unwrap_or(0)
+}
+
+/// A trunk of the bytes read from the connection. If it ends with the head of a request whose body
+/// is yet to come, the reader holds off reading the body until the parser has authorized the
+/// request and sends `true` over the `verdict`, or drops it to stop the reader.
+struct Trunk {
+    bytes: Vec<u8>,
+    verdict: Option<Sender<bool>>,
+}
+
+impl Trunk {
+    fn new(bytes: Vec<u8>) -> Self {
+        Trunk {
+            bytes,
+            verdict: None,
+        }
     }
 }
 
 trait PipelineWorker {
-    fn recv_requests(&mut self, chan: Sender<Result<Vec<u8>, StreamException>>, req_limit: usize);
-    fn send_responses(&mut self, chan: Receiver<RespSeqBundle>);
+    fn recv_requests(
+        &mut self,
+        chan: Sender<Result<Trunk, StreamException>>,
+        req_limit: usize,
+        raw_req: &mut Vec<u8>,
+        spare: &Mutex<Vec<u8>>,
+        feed: &Mutex<Option<Sender<Vec<u8>>>>,
+    );
+    fn send_responses(
+        &mut self,
+        chan: Receiver<RespSeqBundle>,
+        reorder: &mut Vec<RespSeqBundle>,
+        feed: &Mutex<Option<Sender<Vec<u8>>>>,
+        coalesce: usize,
+    );
     fn sink(&mut self, response: Box<Response>) -> u8;
 }
 
 impl PipelineWorker for Stream {
-    /// req_limit is the number of 512B that we can receive before timeout for the request
-    fn recv_requests(&mut self, chan: Sender<Result<Vec<u8>, StreamException>>, req_limit: usize) {
+    /// req_limit is the number of 512B that we can receive before timeout for the request; raw_req
+    /// is the pooled buffer to accumulate the requests longer than a single read, which is handed
+    /// over as it is, and the spare one handed back by the parser takes its place. A request head is
+    /// sent over as soon as it's complete, and its body is only read after the request is accepted.
+    /// While a long connection is in service, the bytes read are passed over to its `feed`.
+    fn recv_requests(
+        &mut self,
+        chan: Sender<Result<Trunk, StreamException>>,
+        req_limit: usize,
+        raw_req: &mut Vec<u8>,
+        spare: &Mutex<Vec<u8>>,
+        feed: &Mutex<Option<Sender<Vec<u8>>>>,
+    ) {
         let mut buffer = [0u8; BUFFER_SIZE];
+        let mut framing = Framing::default();
         let mut total = 0usize;
-        let mut raw_req = Vec::new();
 
         loop {
             // read will block until there're data to read; if not, then we're good to quit
-            match self.read(&mut buffer) {
+            let read = self.read(&mut buffer);
+            if let Ok(len) = read {
+                if len > 0 && divert(feed, &buffer[..len]) {
+                    continue;
+                }
+            }
+
+            match read {
                 Ok(empty) if empty == 0 => {
                     // if no more request data left to read
                     if !raw_req.is_empty() {
                         // if we have no more incoming stream, sending it to parser and wrap up
-                        chan.send(Ok(raw_req)).unwrap_or_default();
+                        chan.send(Ok(Trunk::new(hand_off(raw_req, spare))))
+                            .unwrap_or_default();
                     } else {
                         // send a heart-beat
                         chan.send(Err(StreamException::HeartBeat))
@@ -133,18 +466,33 @@ impl PipelineWorker for Stream {
 
                     if req_limit > 0 && total > req_limit {
                         // send the content for processing and break
-                        chan.send(Err(StreamException::AccessDenied))
+                        chan.send(Err(StreamException::PayloadTooLarge))
                             .unwrap_or_default();
 
                         break;
                     }
+
+                    // the requests may end right here, then no short read will follow to send
+                    // them, so send them now; so is a head whose body is yet to be read
+                    let complete = framing.is_complete(raw_req);
+                    if complete || framing.holds_body(raw_req) {
+                        let request = hand_off(raw_req, spare);
+                        framing.rebase(request.len());
+                        total = 0;
+
+                        if !send_trunk(&chan, request, !complete) {
+                            break;
+                        }
+                    }
                 }
                 Ok(len) => {
                     // if there are request data to read, convert bytes to string
                     let request = if raw_req.is_empty() {
                         // if we don't have any previous request contents to append to, just
                         // send the whole package now.
-                        Vec::from(&mut buffer[..len])
+                        let mut request = mem::take(&mut *spare.lock());
+                        request.extend_from_slice(&buffer[..len]);
+                        request
                     } else {
                         // expand the capacity always, since we're almost certainly run out of space
                         // if entering here, we're at the end of this request loop, prepare to swap
@@ -152,23 +500,36 @@ impl PipelineWorker for Stream {
                         raw_req.reserve(len);
                         raw_req.extend_from_slice(&buffer[..len]);
 
-                        // done with this request, sending it to parser; if the channel is closed,
-                        // meaning the stream is closed, we quit as well.
-                        raw_req.swap_reset()
+                        // done with this request, hand the buffer over to the parser and carry on
+                        // with the spare one; if the channel is closed, meaning the stream is
+                        // closed, we quit as well.
+                        hand_off(raw_req, spare)
                     };
 
-                    if chan.send(Ok(request)).is_err() {
+                    let held = !framing.is_complete(&request) && framing.holds_body(&request);
+                    framing.rebase(request.len());
+                    total = 0;
+
+                    if !send_trunk(&chan, request, held) {
                         break;
                     }
                 }
                 Err(e) => {
+                    // the read timeout is lifted once the connection is upgraded to a websocket,
+                    // which may stay idle for long, keep reading then; so is a long connection.
+                    if (e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock)
+                        && (self
+                            .read_timeout()
+                            .map_or(false, |timeout| timeout.is_none())
+                            || feed.lock().is_some())
+                    {
+                        continue;
+                    }
+
                     // handle read errors. If timeout, meaning we've waited long enough for more requests
                     // but none are received, close the stream now.
                     if e.kind() != ErrorKind::TimedOut {
-                        debug::print(
-                            &format!("Reading stream disconnected -- {}", e),
-                            InfoLevel::Warning,
-                        );
+                        rex_warn!("Reading stream disconnected -- {}", e);
 
                         chan.send(Err(StreamException::ReadStreamFailure))
                             .unwrap_or_default();
@@ -179,110 +540,333 @@ impl PipelineWorker for Stream {
             };
         }
 
+        // the long connection in service won't hear from the client anymore
+        feed.lock().take();
+
         // shutdown the read stream regardless of the reason
         self.shutdown(Shutdown::Read).unwrap_or_default();
     }
 
-    fn send_responses(&mut self, chan: Receiver<RespSeqBundle>) {
-        // pipeline-end: receive the response, write them back
-        let mut curr_id = 1;
-        let mut temp_store: BTreeMap<usize, Box<Response>> = BTreeMap::new();
-
-        // Get the response set in correct order
-        while let Ok(store) = chan.recv_timeout(Duration::from_secs(8)) {
-            if store.0 == 0 || store.0 == curr_id {
-                // send the response and increment the id count
-                if self.sink(store.1) != 0 {
-                    return;
-                }
+    fn send_responses(
+        &mut self,
+        chan: Receiver<RespSeqBundle>,
+        reorder: &mut Vec<RespSeqBundle>,
+        feed: &Mutex<Option<Sender<Vec<u8>>>>,
+        coalesce: usize,
+    ) {
+        // pipeline-end: one writer for the lifetime of the connection, all responses go through it,
+        // and it must be able to hold a whole coalesced response
+        let mut writer = BufWriter::with_capacity(cmp::max(coalesce, WRITE_BUF_SIZE), self);
+
+        if !pipe_responses(&mut writer, chan, reorder, feed, coalesce) {
+            // a partial response is on the wire, so nothing else shall follow it: discard whatever
+            // is still buffered instead of letting the drop flush it out.
+            let _ = writer.into_parts();
+            return;
+        }
+
+        flush_buffer(&mut writer);
+    }
 
-                if store.0 == curr_id {
-                    curr_id += 1;
+    fn sink(&mut self, response: Box<Response>) -> u8 {
+        let mut writer = BufWriter::with_capacity(WRITE_BUF_SIZE, self);
 
-                    // now pop the delayed and stored responses
-                    while let Some(resp) = temp_store.remove(&curr_id) {
-                        if self.sink(resp) != 0 {
-                            return;
-                        }
+        if write_back(&mut writer, response, None, DEFAULT_COALESCE_BYTES) != 0 {
+            let _ = writer.into_parts();
+            return 1;
+        }
+
+        flush_buffer(&mut writer)
+    }
+}
+
+/// Pass the bytes read over to the long connection in service, if any, and return true if it has
+/// taken them. The long connection that's over has left, and the bytes are for the parser then.
+fn divert(feed: &Mutex<Option<Sender<Vec<u8>>>>, bytes: &[u8]) -> bool {
+    // the slot isn't held while waiting on the long connection, the writer clears it once done
+    let tx = match feed.lock().as_ref() {
+        Some(tx) => tx.clone(),
+        None => return false,
+    };
+
+    tx.send(bytes.to_vec()).is_ok()
+}
+
+/// Hand the accumulated bytes over as a whole, rather than copying them, and carry on with the spare
+/// buffer, if the parser has handed one back.
+fn hand_off(raw_req: &mut Vec<u8>, spare: &Mutex<Vec<u8>>) -> Vec<u8> {
+    mem::replace(raw_req, mem::take(&mut *spare.lock()))
+}
+
+/// Hand the bytes the parser is done with back to the reader as the spare buffer, unless it has a
+/// larger one already, or the bytes are over the cap to keep.
+fn recycle(spare: &Mutex<Vec<u8>>, mut bytes: Vec<u8>) {
+    if bytes.capacity() > RAW_BUF_CAP {
+        return;
+    }
+
+    let mut spare = spare.lock();
+    if bytes.capacity() > spare.capacity() {
+        bytes.clear();
+        *spare = bytes;
+    }
+}
+
+/// Send the bytes over to the parser. If the trunk is held, wait for the verdict on the request it
+/// ends with before reading its body. Return false if the reader shall stop.
+fn send_trunk(chan: &Sender<Result<Trunk, StreamException>>, bytes: Vec<u8>, held: bool) -> bool {
+    if !held {
+        return chan.send(Ok(Trunk::new(bytes))).is_ok();
+    }
+
+    let (tx, rx) = channel::bounded(1);
+    let trunk = Trunk {
+        bytes,
+        verdict: Some(tx),
+    };
+
+    chan.send(Ok(trunk)).is_ok() && rx.recv().unwrap_or(false)
+}
+
+/// Receive the responses and write them back in the order of the requests. Returns `false` if a
+/// response failed to go out in full, in which case the connection shall not send anything more.
+fn pipe_responses(
+    writer: &mut BufWriter<&mut Stream>,
+    chan: Receiver<RespSeqBundle>,
+    reorder: &mut Vec<RespSeqBundle>,
+    feed: &Mutex<Option<Sender<Vec<u8>>>>,
+    coalesce: usize,
+) -> bool {
+    let mut curr_id = 1;
+
+    // Get the response set in correct order
+    while let Ok(store) = chan.recv_timeout(Duration::from_secs(8)) {
+        let id = store.0;
+
+        if id == 0 || id == curr_id {
+            // send the response and increment the id count, unless it's an interim response
+            // and the final one is yet to come.
+            let is_final = !store.1.is_interim();
+            if write_back(writer, store.1, Some(feed), coalesce) != 0 {
+                return false;
+            }
+
+            if id == curr_id && is_final {
+                curr_id += 1;
+
+                // now pop the delayed and stored responses, which are sorted by their ids
+                while reorder.first().map_or(false, |bundle| bundle.0 == curr_id) {
+                    let bundle = reorder.remove(0);
+                    let is_final = !bundle.1.is_interim();
+
+                    if write_back(writer, bundle.1, Some(feed), coalesce) != 0 {
+                        return false;
+                    }
 
+                    if is_final {
                         curr_id += 1;
                     }
                 }
-            } else {
-                temp_store.insert(store.0, store.1);
             }
-        }
+        } else {
+            let pos = reorder
+                .iter()
+                .position(|bundle| bundle.0 > id)
+                .unwrap_or_else(|| reorder.len());
 
-        // if there're remainder requests to be sent, send them now.
-        if !temp_store.is_empty() {
-            for (id, resp) in temp_store.into_iter() {
-                while id > curr_id {
-                    if self.sink(build_err_response(map_err_code(
-                        StreamException::EmptyRequest,
-                    ))) != 0
-                    {
-                        return;
-                    }
+            reorder.insert(pos, store);
+        }
+    }
 
-                    curr_id += 1;
+    // if there're remainder requests to be sent, send them now.
+    if !reorder.is_empty() {
+        for RespSeqBundle(id, resp) in reorder.drain(..) {
+            while id > curr_id {
+                if write_back(
+                    writer,
+                    build_err_response(map_err_code(StreamException::EmptyRequest), None),
+                    None,
+                    coalesce,
+                ) != 0
+                {
+                    return false;
                 }
 
-                if self.sink(resp) != 0 {
-                    return;
-                }
+                curr_id += 1;
+            }
+
+            let is_final = !resp.is_interim();
+            if write_back(writer, resp, Some(feed), coalesce) != 0 {
+                return false;
+            }
 
+            if is_final {
                 curr_id += 1;
             }
         }
     }
 
-    fn sink(&mut self, mut response: Box<Response>) -> u8 {
-        let mut writer = BufWriter::new(self);
+    true
+}
 
-        // Serialize the header to the stream
-        if !response.write_header(&mut writer) {
+/// Write the response to the connection's writer, every part of it is flushed explicitly. A
+/// response smaller than `coalesce` bytes goes out in a single write, the others have the header
+/// flushed ahead of the body. A long connection hears from the client through the `feed` if the
+/// connection has a reader. Returns 0 if the connection can carry on with the next response.
+fn write_back(
+    writer: &mut BufWriter<&mut Stream>,
+    mut response: Box<Response>,
+    feed: Option<&Mutex<Option<Sender<Vec<u8>>>>>,
+    coalesce: usize,
+) -> u8 {
+    if !response.is_interim() {
+        stats::record_response(response.status_sent());
+    }
+
+    if response.is_coalescible() {
+        // hold the header in the buffer, and only flush it on its own if the body can't join it
+        response.put_header(writer);
+
+        let size = writer.buffer().len() + response.get_body_size().unwrap_or(0);
+        if size >= coalesce && flush_buffer(writer) != 0 {
             return 1;
         }
 
-        // If header only, we're done
-        if response.is_header_only() {
-            return 0;
+        if !response.write_body(writer) {
+            return 1;
         }
 
-        // write the body to the stream
-        if !response.write_body(&mut writer) {
+        response.release();
+        return 0;
+    }
+
+    // Serialize the header to the stream
+    if !response.write_header(writer) {
+        return 1;
+    }
+
+    // the handshake is done, and we're done with http on this connection
+    #[cfg(feature = "websocket")]
+    {
+        if let Some(upgrade) = response.take_websocket() {
+            response.release();
+            serve_websocket(writer.get_ref().try_clone(), upgrade);
             return 1;
         }
+    }
 
+    // The interim response has no body, and it's not for keeping
+    if response.is_interim() {
         response.release();
+        return 0;
+    }
 
-        0
+    // If header only, we're done
+    if response.is_header_only() {
+        return 0;
+    }
+
+    if response.is_long_conn() {
+        // the reader shall be the only one reading the stream, so it passes the client's bytes on
+        let inbox = feed.map(|slot| {
+            let (tx, rx) = channel::bounded(16);
+            slot.lock().replace(tx);
+            rx
+        });
+
+        // keep sending the body in chunks until the route is done with it
+        let clone = writer.get_ref().try_clone().ok();
+        response.keep_long_conn(clone, inbox, writer);
+
+        if let Some(slot) = feed {
+            slot.lock().take();
+        }
+    } else if !response.write_body(writer) {
+        // write the body to the stream
+        return 1;
     }
+
+    response.release();
+
+    0
 }
 
 fn handle_requests(
-    inbox: Receiver<Result<Vec<u8>, StreamException>>,
+    inbox: Receiver<Result<Trunk, StreamException>>,
     outbox: Sender<RespSeqBundle>,
+    spare: &Mutex<Vec<u8>>,
+    conn_id: u64,
     peer_addr: Option<SocketAddr>,
     is_tls: bool,
+    limits: ConnLimits,
 ) {
     let mut req_id = 1;
+    let mut leftover = Leftover::default();
 
-    for req in inbox {
+    while let Ok(req) = inbox.recv() {
         match req {
-            Ok(source) => {
-                let content = str::from_utf8(source.as_slice()).unwrap_or_default();
-
-                if !content.is_empty() {
-                    let clone_box = outbox.clone();
-                    match serve_connection(content, req_id, clone_box, peer_addr, is_tls) {
+            Ok(trunk) => {
+                if !trunk.bytes.is_empty() {
+                    match serve_connection(
+                        &trunk.bytes,
+                        req_id,
+                        outbox.clone(),
+                        conn_id,
+                        peer_addr,
+                        is_tls,
+                        limits,
+                        &mut leftover,
+                    ) {
                         Ok(id) => req_id = id,
                         Err(_) => return,
                     };
+
+                    // the request is accepted, or its body is to be skipped: go ahead reading
+                    if let Some(verdict) = trunk.verdict {
+                        verdict.send(true).unwrap_or_default();
+                    }
+
+                    recycle(spare, trunk.bytes);
+
+                    #[cfg(feature = "websocket")]
+                    {
+                        if let Some((mut request, callback)) = leftover.upgrade.take() {
+                            let ids = TraceIds::new(conn_id, req_id);
+                            request.set_trace_ids(ids);
+
+                            let _span = span::enter(ids);
+                            let mut response = build_response(request, callback, is_tls);
+
+                            if response.is_websocket() {
+                                // the connection is no longer for http: the reader will feed the
+                                // websocket from now on, starting with what's left in the buffer.
+                                let buffered = mem::take(&mut leftover.head);
+                                if let Some(upgrade) = response.websocket_mut() {
+                                    upgrade.set_reader(ws_reader(inbox), buffered);
+                                }
+
+                                outbox
+                                    .send(RespSeqBundle(req_id, response))
+                                    .unwrap_or_default();
+
+                                return;
+                            }
+
+                            // the upgrade is turned down, keep serving http requests
+                            if outbox.send(RespSeqBundle(req_id, response)).is_err() {
+                                return;
+                            }
+
+                            req_id += 1;
+                        }
+                    }
                 } else {
                     let err = StreamException::ReadStreamFailure;
                     outbox
-                        .send(RespSeqBundle(0, build_err_response(map_err_code(err))))
+                        .send(RespSeqBundle(
+                            0,
+                            build_err_response(map_err_code(err), None),
+                        ))
                         .unwrap_or_default();
                 }
             }
@@ -291,111 +875,459 @@ fn handle_requests(
                     // if only a read stream heart-beat, meaning we're still waiting for new requests
                     // to come, just continue with the listener.
                     outbox
-                        .send(RespSeqBundle(0, build_err_response(map_err_code(err))))
+                        .send(RespSeqBundle(
+                            0,
+                            build_err_response(map_err_code(err), None),
+                        ))
                         .unwrap_or_default();
+
+                    return;
                 }
 
-                return;
+                break;
             }
         }
     }
+
+    // the client has closed the connection, serve what's left from the last reads
+    serve_leftover(req_id, outbox, conn_id, peer_addr, is_tls, limits, leftover);
+}
+
+/// Feed the websocket with the trunks from the reader, and the connection is gone once the reader
+/// has stopped.
+#[cfg(feature = "websocket")]
+fn ws_reader(inbox: Receiver<Result<Trunk, StreamException>>) -> WsReader {
+    Box::new(move || match inbox.recv() {
+        Ok(Ok(trunk)) => Ok(trunk.bytes),
+        _ => Ok(Vec::new()),
+    })
+}
+
+/// Hand the connection over to the websocket, which blocks until the websocket handler is done.
+#[cfg(feature = "websocket")]
+fn serve_websocket(stream: std::io::Result<Stream>, upgrade: WsUpgrade) {
+    match stream {
+        Ok(stream) => {
+            // the websocket may stay idle for long, so lift the read timeout of the connection
+            stream.set_read_timeout(None).unwrap_or_default();
+            upgrade.serve(stream);
+        }
+        Err(e) => rex_warn!("Failed to hand the connection over to the websocket: {}", e),
+    }
+}
+
+/// What's left from the previous reads of the connection, which will be completed by the following
+/// reads.
+#[derive(Default)]
+struct Leftover {
+    /// The request waiting for the rest of its body.
+    body: Option<PendingBody>,
+    /// The beginning of the next request, whose head is yet to be terminated.
+    head: Vec<u8>,
+    /// How far the head has been searched for its end, such that the search resumes from there.
+    scanned: usize,
+    /// The request asking to upgrade the connection to a websocket.
+    #[cfg(feature = "websocket")]
+    upgrade: Option<(Box<Request>, RouteHandler)>,
+}
+
+/// The request waiting for the rest of its body, which will arrive with the following reads.
+struct PendingBody {
+    /// The request and its handler, or `None` if the request is rejected and the body is skipped.
+    request: Option<(Box<Request>, RouteHandler)>,
+    body: Vec<u8>,
+    remainder: usize,
+    to_close: bool,
 }
 
 fn serve_connection(
-    source: &str,
+    source: &[u8],
     base_id: usize,
     outbox: Sender<RespSeqBundle>,
+    conn_id: u64,
     peer_addr: Option<SocketAddr>,
     is_tls: bool,
+    limits: ConnLimits,
+    leftover: &mut Leftover,
 ) -> Result<usize, ErrorKind> {
     // prepare the request source string to be parsed
     let mut next_id = base_id;
     if source.is_empty() {
-        return send_err(next_id, outbox, StreamException::EmptyRequest);
+        return send_err(next_id, outbox, StreamException::EmptyRequest, None);
     }
 
-    // now parse the request and find the proper request handler
-    let mut last: Option<(Box<Request>, RouteHandler)> = None;
-    let mut to_close = false;
-    let mut body_size: usize = 0;
+    // continue with the unfinished request head from the last trunk, where its search left off
+    let joined;
+    let mut resume = 0;
+    let mut rest = if leftover.head.is_empty() {
+        source
+    } else {
+        resume = mem::replace(&mut leftover.scanned, 0);
+        leftover.head.extend_from_slice(source);
+        joined = mem::take(&mut leftover.head);
+        &joined[..]
+    };
+
+    let pending = &mut leftover.body;
+
+    loop {
+        // if the last request is waiting for its body, append the body from this trunk and then
+        // start processing the request, or skip the body if the request has been rejected.
+        if let Some(mut waiting) = pending.take() {
+            let len = waiting.remainder.min(rest.len());
+            if waiting.request.is_some() {
+                waiting.body.extend_from_slice(&rest[..len]);
+            }
+
+            waiting.remainder -= len;
+            rest = &rest[len..];
 
-    // header-body or header-header separation is built with an empty line, or "\r\n\r\n".
-    for raw_req in source.split("\r\n\r\n") {
-        // if parsing the body from the next trunk, append the body then start processing the request
-        // otherwise, just continue with parsing the new request.
-        let next: &str = (if body_size > 0 && last.is_some() {
-            // append the body and finishing off handing over the last request.
-            let (body, remainder): (&str, &str) = raw_req.split_at(body_size);
+            if waiting.remainder > 0 {
+                // the body is yet to be completed, wait for the next trunk
+                pending.replace(waiting);
+                return Ok(next_id);
+            }
 
-            if let Some((mut request, callback)) = last.take() {
-                request.set_body(body.to_owned());
+            if let Some((mut request, callback)) = waiting.request.take() {
+                request.set_body_bytes(mem::take(&mut waiting.body));
 
                 // generate the request
-                process_request(next_id, request, callback, outbox.clone(), is_tls);
+                process_request(
+                    TraceIds::new(conn_id, next_id),
+                    request,
+                    callback,
+                    outbox.clone(),
+                    is_tls,
+                );
+                next_id += 1;
             }
 
             // to we shall close the connection, we're done
-            if to_close {
+            if waiting.to_close {
                 return Err(ErrorKind::ConnectionAborted);
             }
+        }
 
-            // send the remainder work to finish parsing and processing
-            next_id += 1;
-            remainder
-        } else {
-            // pick the new request to work with.
-            raw_req
-        })
-        .trim_matches(|c| c == '\r' || c == '\n' || c == '\u{0}');
+        // skip the line breaks and paddings between the requests
+        let start = rest
+            .iter()
+            .position(|b| *b != b'\r' && *b != b'\n' && *b != 0)
+            .unwrap_or_else(|| rest.len());
 
-        if next.is_empty() {
-            // reset and continue
-            body_size = 0;
-            continue;
+        rest = &rest[start..];
+        if rest.is_empty() {
+            break;
         }
 
-        // Get callback from the next request
-        let (mut request, callback) = parse_request_sync(next);
-        to_close = !request.keep_alive();
+        // header-body or header-header separation is built with an empty line, or "\r\n\r\n",
+        // which may straddle the trunks
+        let from = resume.saturating_sub(start + 3);
+        resume = 0;
 
-        // not matching any given router, return null
-        if callback.is_none() || request.uri.is_empty() {
-            return send_err(next_id, outbox, StreamException::ServiceUnavailable);
-        }
+        let (head_end, body_start) = match find_head_end(&rest[from..]) {
+            Some((head_end, body_start)) => (from + head_end, from + body_start),
+            None if rest.len() > limits.head_limit() => {
+                send_err(next_id, outbox, StreamException::HeaderTooLarge, None)?;
+                return Err(ErrorKind::ConnectionAborted);
+            }
+            None => {
+                // the head is yet to be completed, wait for the next trunk
+                leftover.head.extend_from_slice(rest);
+                leftover.scanned = rest.len();
+                return Ok(next_id);
+            }
+        };
+
+        let next = match str::from_utf8(&rest[..head_end]) {
+            Ok(head) => head.trim_end_matches(|c| c == '\r' || c == '\n' || c == '\u{0}'),
+            Err(_) => {
+                send_err(next_id, outbox, StreamException::EmptyRequest, None)?;
+                return Err(ErrorKind::ConnectionAborted);
+            }
+        };
 
-        // check server authorization on certain path
-        if !Route::authorize(&request, &request.uri) {
-            return send_err(next_id, outbox, StreamException::AccessDenied);
+        // and we can't tell where the next request starts after the bare LF in the strict mode
+        if limits.line_endings == LineEndings::Strict && has_bare_lf(&rest[..body_start]) {
+            send_err(next_id, outbox, StreamException::MalformedRequest, None)?;
+            return Err(ErrorKind::ConnectionAborted);
         }
 
-        // setup peer address
-        if let Some(client) = peer_addr {
-            request.set_client(client);
+        rest = &rest[body_start.min(rest.len())..];
+
+        // reject the oversized request head before parsing it, and since we can't tell where the
+        // next request starts, the connection will be closed as well
+        if let Some(err) = check_head(next, &limits) {
+            send_err(next_id, outbox, err, None)?;
+            return Err(ErrorKind::ConnectionAborted);
         }
 
-        // get the body size and parse the amount from the next trunk.
-        body_size = match request.header("content-length") {
-            Some(val) => val.parse::<usize>().unwrap_or(0),
-            None => 0,
-        };
+        // Get callback from the next request, along with the view of the router it's found in
+        let (mut request, mut callback, view) = parse_request_sync(next);
+        stats::record_request();
+
+        // the CORS preflights are answered on behalf of the routes, which may not even exist
+        let preflight = cors::is_preflight(&request);
+        if preflight {
+            callback = RouteHandler::new(Some(cors::answer_preflight), None);
+        }
+        let to_close = !request.keep_alive();
+        let body_size = content_length(&request);
+
+        // the client is holding back the body until we agree to take it: refuse the unknown
+        // expectations or the requests we won't serve, and the client won't send the body at all.
+        let expect = request.header("expect");
+        if let Some(expectation) = expect.as_ref() {
+            if !expectation.eq_ignore_ascii_case("100-continue")
+                || callback.is_none()
+                || request.uri.is_empty()
+            {
+                send_err(
+                    next_id,
+                    outbox,
+                    StreamException::ExpectationFailed,
+                    Some(&request),
+                )?;
 
-        // if no body's attached with this request, we're done parsing and send the request for
-        // processing now.
-        if body_size == 0 {
-            process_request(next_id, request, callback, outbox.clone(), is_tls);
-            if to_close {
                 return Err(ErrorKind::ConnectionAborted);
             }
-
-            next_id += 1;
-        } else {
-            // otherwise, we need to save the request/callback to the placeholder
-            last.replace((request, callback));
         }
 
-        //TODO: handle the trunked body stream, aka split the part before the final `boundary` in
-        //      the next trunk
-    }
+        // not matching any given router, return null
+        if callback.is_none() || request.uri.is_empty() {
+            next_id = reject(
+                next_id,
+                &outbox,
+                StreamException::ServiceUnavailable,
+                &request,
+                body_size,
+                pending,
+            )?;
+
+            continue;
+        }
+
+        // the route may accept a larger, or a smaller body than the server-wide limit
+        let body_limit = limits.body_limit(callback.max_body());
+        if body_limit > 0 && body_size > body_limit {
+            next_id = reject(
+                next_id,
+                &outbox,
+                StreamException::PayloadTooLarge,
+                &request,
+                body_size,
+                pending,
+            )?;
+
+            continue;
+        }
+
+        // check server authorization on certain path, with the same router that routed the request
+        if !preflight && !view.authorize(&mut request) {
+            next_id = reject(
+                next_id,
+                &outbox,
+                StreamException::AccessDenied,
+                &request,
+                body_size,
+                pending,
+            )?;
+
+            continue;
+        }
+
+        // setup peer address
+        if let Some(client) = peer_addr {
+            request.set_client(client);
+        }
+
+        // if no body's attached with this request, we're done parsing and send the request for
+        // processing now.
+        if body_size == 0 {
+            // the client shall wait for the handshake response before sending anything else, and
+            // the upgrade will be served when we're done with this trunk.
+            #[cfg(feature = "websocket")]
+            {
+                if request.is_websocket_upgrade() {
+                    leftover.head.extend_from_slice(rest);
+                    leftover.upgrade = Some((request, callback));
+                    return Ok(next_id);
+                }
+            }
+
+            process_request(
+                TraceIds::new(conn_id, next_id),
+                request,
+                callback,
+                outbox.clone(),
+                is_tls,
+            );
+            if to_close {
+                return Err(ErrorKind::ConnectionAborted);
+            }
+
+            next_id += 1;
+            continue;
+        }
+
+        // the body is yet to be sent, let the client know it can go ahead now. The interim
+        // response takes the request's id, such that it's sent before the final response.
+        if expect.is_some()
+            && rest.is_empty()
+            && outbox
+                .send(RespSeqBundle(next_id, Response::interim(100)))
+                .is_err()
+        {
+            return Err(ErrorKind::ConnectionAborted);
+        }
+
+        // otherwise, we need to save the request/callback to the placeholder, and the body will
+        // be appended from the remainder of this trunk and the following ones.
+        pending.replace(PendingBody {
+            request: Some((request, callback)),
+            body: Vec::with_capacity(body_size.min(RAW_BUF_CAP)),
+            remainder: body_size,
+            to_close,
+        });
+
+        //TODO: handle the trunked body stream, aka split the part before the final `boundary` in
+        //      the next trunk
+    }
+
+    Ok(next_id)
+}
+
+/// Serve the leftover after the client has closed the connection. The head without the terminating
+/// empty line is still taken as a complete one, but a request that's missing any part of its body
+/// is incomplete, and will be answered with 400.
+fn serve_leftover(
+    base_id: usize,
+    outbox: Sender<RespSeqBundle>,
+    conn_id: u64,
+    peer_addr: Option<SocketAddr>,
+    is_tls: bool,
+    limits: ConnLimits,
+    mut leftover: Leftover,
+) {
+    let mut next_id = base_id;
+
+    if !leftover.head.is_empty() {
+        match serve_connection(
+            b"\r\n\r\n",
+            next_id,
+            outbox.clone(),
+            conn_id,
+            peer_addr,
+            is_tls,
+            limits,
+            &mut leftover,
+        ) {
+            Ok(id) => next_id = id,
+            Err(_) => return,
+        }
+    }
+
+    if let Some(waiting) = leftover.body.take() {
+        if let Some((request, _)) = waiting.request {
+            rex_debug!(
+                "Connection closed with {} bytes of the request body missing",
+                waiting.remainder
+            );
+
+            send_err(
+                next_id,
+                outbox,
+                StreamException::EmptyRequest,
+                Some(&request),
+            )
+            .unwrap_or_default();
+        }
+    }
+}
+
+fn content_length(request: &Box<Request>) -> usize {
+    match request.header("content-length") {
+        Some(val) => val.parse::<usize>().unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// Check the request head, i.e. the request line and the header fields, against the limits. The
+/// fields, the cookies and the query parameters are counted on the raw head, such that none of
+/// them is parsed into the request if there're too many.
+fn check_head(head: &str, limits: &ConnLimits) -> Option<StreamException> {
+    if head.len() > limits.head_limit() {
+        return Some(StreamException::HeaderTooLarge);
+    }
+
+    if limits.header_count > 0 && head.lines().skip(1).count() > limits.header_count {
+        return Some(StreamException::HeaderTooLarge);
+    }
+
+    if limits.cookie_count > 0 && count_cookies(head) > limits.cookie_count {
+        return Some(StreamException::PayloadTooLarge);
+    }
+
+    if limits.query_params > 0 && count_query_params(head) > limits.query_params {
+        return Some(StreamException::MalformedRequest);
+    }
+
+    None
+}
+
+/// The number of cookies in all the `Cookie` header fields of the request head.
+fn count_cookies(head: &str) -> usize {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(field, _)| field.trim().eq_ignore_ascii_case("cookie"))
+        .map(|(_, value)| value.split(';').filter(|c| !c.trim().is_empty()).count())
+        .sum()
+}
+
+/// The number of parameters in the query string of the request line.
+fn count_query_params(head: &str) -> usize {
+    let target = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default();
+
+    let query = match target.split_once('?') {
+        Some((_, query)) => query.split('#').next().unwrap_or_default(),
+        None => return 0,
+    };
+
+    query.split('&').filter(|p| !p.trim().is_empty()).count()
+}
+
+/// Reject the request, then skip its body if it's small enough to be drained, such that the
+/// pipelined requests are still aligned; otherwise, or if the client wants the connection closed,
+/// stop serving the connection after sending the rejection.
+fn reject(
+    base_id: usize,
+    outbox: &Sender<RespSeqBundle>,
+    err: StreamException,
+    request: &Box<Request>,
+    body_size: usize,
+    pending: &mut Option<PendingBody>,
+) -> Result<usize, ErrorKind> {
+    let next_id = send_err(base_id, outbox.clone(), err, Some(request))?;
+
+    if !request.keep_alive()
+        || request.header("expect").is_some()
+        || body_size > ConnMetadata::get_drain_limit()
+    {
+        return Err(ErrorKind::ConnectionAborted);
+    }
+
+    if body_size > 0 {
+        pending.replace(PendingBody {
+            request: None,
+            body: Vec::new(),
+            remainder: body_size,
+            to_close: false,
+        });
+    }
 
     Ok(next_id)
 }
@@ -404,11 +1336,12 @@ fn send_err(
     base_id: usize,
     outbox: Sender<RespSeqBundle>,
     err: StreamException,
+    request: Option<&Box<Request>>,
 ) -> Result<usize, ErrorKind> {
     if outbox
         .send(RespSeqBundle(
             base_id,
-            build_err_response(map_err_code(err)),
+            build_rejection(map_err_code(err), request),
         ))
         .is_err()
     {
@@ -419,59 +1352,198 @@ fn send_err(
 }
 
 fn process_request(
-    next_id: usize,
-    request: Box<Request>,
+    ids: TraceIds,
+    mut request: Box<Request>,
     callback: RouteHandler,
     outbox: Sender<RespSeqBundle>,
     is_tls: bool,
 ) {
-    shared_pool::run(
+    request.set_trace_ids(ids);
+
+    shared_pool::run_traced(
         move || {
             outbox
                 .send(RespSeqBundle(
-                    next_id,
+                    ids.req,
                     build_response(request, callback, is_tls),
                 ))
                 .unwrap_or_default();
         },
         TaskType::Request,
+        ids,
     );
 }
 
-fn build_response(
-    request: Box<Request>,
-    mut callback: RouteHandler,
-    is_tls: bool,
-) -> Box<Response> {
+fn build_response(request: Box<Request>, callback: RouteHandler, is_tls: bool) -> Box<Response> {
     // generating the response and setup stuff
     let mut response = initialize_response(is_tls);
 
-    match request.header("connection") {
-        Some(ref val) if val.eq(&String::from("close")) => response.can_keep_alive(false),
-        _ => response.can_keep_alive(true),
-    };
+    if !request.keep_alive() {
+        response.forbid_keep_alive();
+    }
 
-    if request.method.eq(&REST::OTHER(String::from("HEAD"))) {
+    response.set_cross_site(request.is_cross_site());
+    response.set_views(callback.views());
+
+    // HEAD requests are served as GET requests, only that the body won't be sent, and the files
+    // won't be read, only their sizes are needed
+    if request.method == REST::HEAD {
         response.header_only(true);
+        response.stat_only(true);
     }
 
     // callback function will decide what to be written into the response
-    callback.execute(&request, &mut response);
-    request.release();
+    let start = Instant::now();
+
+    let (request, mut response) = match run_callback(request, response, callback) {
+        Ok(done) => done,
+        Err((request, overrun)) => {
+            log_overrun(&request, &overrun, start.elapsed());
+            return overrun;
+        }
+    };
+
+    #[cfg(feature = "metrics")]
+    let handled = start.elapsed();
+
+    cors::decorate(&request, &mut response);
+
+    #[cfg(feature = "websocket")]
+    response.upgrade_websocket(&request);
+
+    response.validate_conditional(&request);
+    response.negotiate_err_format(&request);
+    response.negotiate_encoding(&request);
 
     // update the response based on critical conditions
     response.redirect_handling();
-    response.validate_and_update();
+    response.validate_and_update_for(Some(&request));
+    context::end_request();
+
+    for reason in response.audit() {
+        rex_debug!("Response to {} is overridden: {}", request.uri, reason);
+    }
+
+    #[cfg(feature = "metrics")]
+    metrics::observe(&request.method, response.get_status(), handled);
+
+    #[cfg(feature = "logger")]
+    logger::log_access(&request, &response, start.elapsed());
+    request.release();
 
     // done, send response back
     response
 }
 
-fn parse_request_sync(source: &str) -> (Box<Request>, RouteHandler) {
+/// The request and its response, once the handler is done with them.
+type Handled = (Box<Request>, Box<Response>);
+
+/// Run the route's callback, within the route's handler timeout if it has one: the callback is then
+/// run on a thread of its own, timed from the moment it starts, and if it doesn't finish in time,
+/// it's left running and the "503 Service Unavailable" response is returned as the error instead,
+/// along with a copy of the request head. The overrunning callbacks don't hold up the request pool,
+/// but once `ABANDONED_CAP` of them are still running, the timed routes are refused right away.
+fn run_callback(
+    request: Box<Request>,
+    mut response: Box<Response>,
+    mut callback: RouteHandler,
+) -> Result<Handled, Handled> {
+    let timeout = match callback.handler_timeout() {
+        Some(timeout) => timeout,
+        None => {
+            context::begin_request();
+            callback.execute(&request, &mut response);
+            return Ok((request, response));
+        }
+    };
+
+    if ABANDONED.load(Ordering::Acquire) >= ABANDONED_CAP {
+        rex_warn!(
+            "Too many handlers are still running past their timeouts, refusing {}",
+            request.uri
+        );
+        return Err((request, build_err_response(503, None)));
+    }
+
+    let head = request.head_copy();
+    let ids = request.trace_ids();
+    let (started_tx, started_rx) = channel::bounded(1);
+    let (tx, rx) = channel::bounded(1);
+    let state = Arc::new(AtomicU8::new(HANDLER_RUNNING));
+    let handler_state = Arc::clone(&state);
+
+    let spawned = shared_pool::thread_builder(String::from("rex-handler")).spawn(move || {
+        let _span = span::enter(ids);
+        started_tx.send(()).unwrap_or_default();
+
+        context::begin_request();
+        callback.execute(&request, &mut response);
+        context::end_request();
+
+        if handler_state.swap(HANDLER_DONE, Ordering::AcqRel) == HANDLER_ABANDONED {
+            ABANDONED.fetch_sub(1, Ordering::AcqRel);
+        }
+
+        // the receiver is gone if we're late, then the response is dropped here
+        tx.send((request, response)).unwrap_or_default();
+    });
+
+    if let Err(e) = spawned {
+        rex_error!("Unable to launch the handler of {}: {}", head.uri, e);
+        return Err((head, build_err_response(503, None)));
+    }
+
+    // the clock starts once the handler does
+    if started_rx.recv().is_err() {
+        return Err((head, build_err_response(503, None)));
+    }
+
+    if let Ok(done) = rx.recv_timeout(timeout) {
+        return Ok(done);
+    }
+
+    // count the handler in before marking it, since it could be done and count itself out anytime
+    ABANDONED.fetch_add(1, Ordering::AcqRel);
+    let marked = state.compare_exchange(
+        HANDLER_RUNNING,
+        HANDLER_ABANDONED,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+
+    if marked.is_err() {
+        // it's done just now, and the response is on its way
+        ABANDONED.fetch_sub(1, Ordering::AcqRel);
+        if let Ok(done) = rx.recv() {
+            return Ok(done);
+        }
+    }
+
+    rex_warn!(
+        "The handler of {} has overrun its timeout of {:?}",
+        head.uri,
+        timeout
+    );
+
+    Err((head, build_err_response(503, None)))
+}
+
+/// Account for the response that replaces the one of an overrunning handler, as the responses
+/// from the handlers are.
+fn log_overrun(request: &Request, response: &Response, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::observe(&request.method, response.get_status(), elapsed);
+
+    #[cfg(feature = "logger")]
+    logger::log_access(request, response, elapsed);
+}
+
+fn parse_request_sync(source: &str) -> (Box<Request>, RouteHandler, RouterView) {
     let mut handler = RouteHandler::default();
+    let mut view = RouterView::default();
     let mut request = Request::obtain();
 
-    for (index, info) in source.trim().splitn(2, "\r\n").enumerate() {
+    for (index, info) in source.trim().splitn(2, '\n').enumerate() {
         match index {
             0 => {
                 let res = parse_start_line_sync(&info, &mut request);
@@ -480,40 +1552,37 @@ fn parse_request_sync(source: &str) -> (Box<Request>, RouteHandler) {
                     request.create_param(res.1);
                 }
 
+                if let Some(pattern) = res.0.pattern() {
+                    request.set_route_pattern(pattern);
+                }
+
                 handler = res.0;
+                view = res.2;
             }
             1 => parse_remainder_sync(info, &mut request),
             _ => break,
         }
     }
 
-    (request, handler)
+    (request, handler, view)
 }
 
-fn parse_start_line_sync(
-    source: &str,
-    req: &mut Box<Request>,
-) -> (RouteHandler, HashMap<String, String>) {
+fn parse_start_line_sync(source: &str, req: &mut Box<Request>) -> SeekResult {
     let mut raw_query = String::new();
     let mut raw_fragment = String::new();
 
     for (index, info) in source.split_whitespace().enumerate() {
         if index < 2 && info.is_empty() {
-            return (RouteHandler::default(), HashMap::new());
+            return (
+                RouteHandler::default(),
+                HashMap::new(),
+                RouterView::default(),
+            );
         }
 
         match index {
             0 => {
-                let base_method = match &info.to_uppercase()[..] {
-                    "GET" => REST::GET,
-                    "PUT" => REST::PUT,
-                    "POST" => REST::POST,
-                    "DELETE" => REST::DELETE,
-                    "OPTIONS" => REST::OPTIONS,
-                    _ => REST::OTHER(info.to_uppercase()),
-                };
-
-                req.method = base_method;
+                req.method = REST::parse(info);
             }
             1 => {
                 // path is at most the length of the source string
@@ -541,13 +1610,17 @@ fn parse_start_line_sync(
         }
 
         if !raw_query.is_empty() {
-            req.create_query(parse_query(raw_query));
+            req.set_query(parse_query(raw_query));
         }
 
         return res;
     }
 
-    (RouteHandler::default(), HashMap::new())
+    (
+        RouteHandler::default(),
+        HashMap::new(),
+        RouterView::default(),
+    )
 }
 
 fn parse_remainder_sync(info: &str, req: &mut Box<Request>) {
@@ -556,7 +1629,7 @@ fn parse_remainder_sync(info: &str, req: &mut Box<Request>) {
         return;
     }
 
-    let mut header: HashMap<String, String> = HashMap::new();
+    let mut header = HeaderMap::new();
     let mut cookie: HashMap<String, String> = HashMap::new();
     let mut body: String = String::with_capacity(1024);
     let mut is_body = false;
@@ -584,7 +1657,7 @@ fn initialize_response(is_tls: bool) -> Box<Response> {
     }
 
     if is_tls {
-        resp.can_keep_alive(false);
+        resp.set_tls_conn();
     }
 
     resp
@@ -593,7 +1666,7 @@ fn initialize_response(is_tls: bool) -> Box<Response> {
 fn parse_headers(
     line: &str,
     is_body: bool,
-    header: &mut HashMap<String, String>,
+    header: &mut HeaderMap,
     cookie: &mut HashMap<String, String>,
     body: &mut String,
 ) {
@@ -605,12 +1678,13 @@ fn parse_headers(
             match idx {
                 0 => {
                     header_key = &info.trim()[..];
-                    is_cookie = header_key.eq("cookie");
+                    is_cookie = header_key.eq_ignore_ascii_case("cookie");
                 }
                 1 => {
                     if is_cookie {
                         parse_cookie(info.trim(), cookie);
                     } else if !header_key.is_empty() {
+                        // the name is stored in lowercase, such that the lookup is case-insensitive
                         header.add(header_key, info.trim().to_owned(), true, false);
                     }
                 }
@@ -623,7 +1697,8 @@ fn parse_headers(
 }
 
 fn parse_path(source: &str, path: &mut String, query: &mut String, frag: &mut String) {
-    let uri = source.trim().trim_end_matches('/');
+    // the trailing slash is kept, and it's up to the router's normalization how it's matched
+    let uri = source.trim();
     if uri.is_empty() {
         path.push('/');
         return;
@@ -661,7 +1736,7 @@ fn parse_path(source: &str, path: &mut String, query: &mut String, frag: &mut St
         path.push('/');
 
         // now push the remainder of the split string, could be empty, e.g. path: "/?say=hi&to=mom"
-        if uri_parts[0].is_empty() {
+        if !uri_parts[0].is_empty() {
             path.push_str(uri_parts[0]);
         }
 
@@ -671,12 +1746,19 @@ fn parse_path(source: &str, path: &mut String, query: &mut String, frag: &mut St
             path.push('/');
         }
 
-        let uri_len = uri.len();
-        if uri_len > 1 && uri.ends_with('/') {
-            path.push_str(&uri[..uri_len - 1]);
-        } else {
-            path.push_str(uri)
-        };
+        // the fragment has been cut from the last part already
+        if let Some(lead) = uri_parts.get(1) {
+            path.push_str(lead);
+            path.push('/');
+        }
+
+        path.push_str(uri_parts[0]);
+    }
+
+    // decode the path before the route matching, note that the encoded slash `%2F` becomes a
+    // separator as well, and malformed escapes are kept as they are
+    if path.contains('%') {
+        *path = percent_decode(path, false);
     }
 }
 
@@ -699,33 +1781,46 @@ fn parse_cookie(raw: &str, cookie: &mut HashMap<String, String>) {
     }
 }
 
-fn parse_query(query: String) -> HashMap<String, Vec<String>> {
-    let mut query_result: HashMap<String, Vec<String>> = HashMap::new();
-    for (_, kv_pair) in query.trim().split('&').enumerate() {
+/// Decode the query into the field-value pairs, in the order they appear in the query.
+fn parse_query(query: String) -> Vec<(String, String)> {
+    let mut query_result = Vec::new();
+    for kv_pair in query.trim().trim_start_matches('?').split('&') {
         let store: Vec<&str> = kv_pair.trim().splitn(2, '=').collect();
 
-        if !store.is_empty() {
-            let key = store[0].trim();
+        // skip the pairs without a key, e.g. from `?=x` or `a=1&&b=2`
+        if !store[0].trim().is_empty() {
+            let key = percent_decode(store[0].trim(), true);
             let val = if store.len() == 2 {
-                store[1].trim().to_owned()
+                percent_decode(store[1].trim(), true)
             } else {
                 String::new()
             };
 
-            if query_result.contains_key(key) {
-                if let Some(val_vec) = query_result.get_mut(key) {
-                    val_vec.push(val);
-                }
-            } else {
-                query_result.insert(key.to_owned(), vec![val]);
-            }
+            query_result.push((key, val));
         }
     }
 
     query_result
 }
 
-fn build_err_response(err_status: u16) -> Box<Response> {
+/// Build the error response to the request refused before it reaches the handler, and log it to the
+/// access log like the handled ones.
+fn build_rejection(status: u16, request: Option<&Box<Request>>) -> Box<Response> {
+    #[cfg(feature = "logger")]
+    let start = Instant::now();
+    let response = build_err_response(status, request);
+
+    #[cfg(feature = "logger")]
+    {
+        if let Some(request) = request {
+            logger::log_access(request, &response, start.elapsed());
+        }
+    }
+
+    response
+}
+
+fn build_err_response(err_status: u16, request: Option<&Box<Request>>) -> Box<Response> {
     let mut resp = Response::obtain(); //Box::new(Response::new());
 
     resp.status(err_status);
@@ -733,7 +1828,16 @@ fn build_err_response(err_status: u16) -> Box<Response> {
         return resp;
     }
 
-    resp.validate_and_update();
+    if let Some(req) = request {
+        resp.negotiate_err_format(req);
+
+        // the error page won't be sent to a HEAD request, so don't build it either
+        if req.method == REST::HEAD {
+            resp.header_only(true);
+        }
+    }
+
+    resp.validate_and_update_for(request);
     resp.keep_alive(false);
 
     if resp.get_content_type().is_empty() {
@@ -747,16 +1851,99 @@ fn map_err_code(err: StreamException) -> u16 {
     //TODO: need more error code, e.g. illegal request, etc.
 
     match err {
-        StreamException::EmptyRequest => 400,
+        StreamException::EmptyRequest | StreamException::MalformedRequest => 400,
         StreamException::AccessDenied => 401,
         StreamException::ServiceUnavailable => 404,
+        StreamException::PayloadTooLarge => 413,
+        StreamException::ExpectationFailed => 417,
+        StreamException::HeaderTooLarge => 431,
         StreamException::ReadStreamFailure | StreamException::HeartBeat => 0,
     }
 }
 
 #[inline]
 pub(crate) fn send_err_resp(mut stream: Stream, err_code: u16) {
-    stream.sink(build_err_response(err_code));
+    stream.sink(build_err_response(err_code, None));
+}
+
+/// Answer the request to the plain http listener with a redirect to the same path and query over
+/// https, see `ServerConfig::set_https_redirect`.
+pub(crate) fn send_https_redirect(mut stream: TcpStream, authority: &str) {
+    let head = read_redirect_head(&mut stream, Instant::now() + REDIRECT_READ_TIMEOUT);
+    if head.is_empty() {
+        return;
+    }
+
+    let resp = format!(
+        "HTTP/1.1 301 Moved Permanently\r\nLocation: https://{}{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        authority,
+        redirect_target(&head)
+    );
+
+    if let Err(e) = stream.write_all(resp.as_bytes()) {
+        rex_debug!("Failed to redirect the request to https: {}", e);
+    }
+}
+
+/// Read the request head off the stream, or as much of it as arrives before the deadline, such that
+/// a client trickling the bytes in can't hold the worker for longer than the one timeout.
+fn read_redirect_head(stream: &mut TcpStream, deadline: Instant) -> Vec<u8> {
+    // only the request line is needed, but read through the head such that the client won't see
+    // the connection reset for the unread data before it gets the response
+    let mut head = Vec::with_capacity(BUFFER_SIZE);
+    let mut buf = [0u8; BUFFER_SIZE];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) || stream.set_read_timeout(Some(remaining)).is_err()
+        {
+            break;
+        }
+
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                head.extend_from_slice(&buf[..n]);
+                if head.len() >= RAW_BUF_CAP || head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    break;
+                }
+            }
+        }
+    }
+
+    head
+}
+
+/// The path and query from the request line, which also takes the absolute form of the target,
+/// or `/` if the target isn't a usable one.
+fn redirect_target(head: &[u8]) -> String {
+    let line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let target = match str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.split_whitespace().nth(1))
+    {
+        Some(t) if !t.chars().any(char::is_control) => t,
+        _ => return String::from("/"),
+    };
+
+    if target.starts_with('/') {
+        return String::from(target);
+    }
+
+    let lower = target.to_ascii_lowercase();
+    let rest = if lower.starts_with("http://") {
+        &target[7..]
+    } else if lower.starts_with("https://") {
+        &target[8..]
+    } else {
+        return String::from("/");
+    };
+
+    match rest.find(&['/', '?'][..]) {
+        Some(pos) if rest[pos..].starts_with('/') => String::from(&rest[pos..]),
+        Some(pos) => format!("/{}", &rest[pos..]),
+        None => String::from("/"),
+    }
 }
 
 mod async_handler {
@@ -767,19 +1954,20 @@ mod async_handler {
     use std::time::Duration;
 
     use crate::core::{
-        http::{Request, RequestWriter, Response, ResponseManager, ResponseStates, ResponseWriter},
-        router::{Route, RouteHandler, RouteSeeker, REST},
+        context,
+        http::{Request, RequestWriter, Response, ResponseManager, ResponseStates},
+        router::{Route, RouteHandler, RouteSeeker, RouterView, REST},
         stream::Stream,
     };
 
-    use crate::support::{debug, debug::InfoLevel, shared_pool, TaskType};
+    use crate::support::{shared_pool, TaskType};
 
     use crate::channel;
     use crate::hashbrown::HashMap;
 
-    pub(crate) fn handle_connection(mut stream: Stream) -> ExecCode {
-        let (callback, request) = match recv_requests(&mut stream) {
-            Err(err) => {
+    pub(crate) fn handle_connection(mut stream: Stream, limits: ConnLimits) -> ExecCode {
+        let (callback, mut request) = match recv_requests(&mut stream, &limits) {
+            Err((err, request)) => {
                 let status = map_err_code(err);
                 if status == 0 {
                     // connection is sour, shutdown now
@@ -790,16 +1978,18 @@ mod async_handler {
                     return 0;
                 }
 
-                debug::print(
-                    &format!("Error on parsing request: {}", status),
-                    InfoLevel::Error,
-                );
+                rex_error!("Error on parsing request: {}", status);
 
-                return write_to_stream(stream, build_err_response(status));
+                return write_to_stream(stream, build_rejection(status, request.as_ref()));
             }
             Ok(cb) => cb,
         };
 
+        // a single request is served over the connection
+        let ids = TraceIds::new(span::next_conn_id(), 1);
+        request.set_trace_ids(ids);
+
+        let _span = span::enter(ids);
         let is_tls = stream.is_tls();
         send_response(stream, request, callback, is_tls)
     }
@@ -807,51 +1997,92 @@ mod async_handler {
     fn send_response(
         stream: Stream,
         request: Box<Request>,
-        mut callback: RouteHandler,
+        callback: RouteHandler,
         is_tls: bool,
     ) -> ExecCode {
         let mut response = initialize_response(is_tls);
-        match request.header("connection") {
-            Some(ref val) if val.eq(&String::from("close")) => response.can_keep_alive(false),
-            _ => response.can_keep_alive(true),
-        };
+        if !request.keep_alive() {
+            response.forbid_keep_alive();
+        }
+
+        response.set_cross_site(request.is_cross_site());
+        response.set_views(callback.views());
 
-        if request.method.eq(&REST::OTHER(String::from("HEAD"))) {
+        // HEAD requests are served as GET requests, only that the body won't be sent
+        if request.method == REST::HEAD {
             response.header_only(true);
+            response.stat_only(true);
         }
 
         // callback function will decide what to be written into the response
-        callback.execute(&request, &mut response);
+        let start = Instant::now();
+
+        let (request, mut response) = match run_callback(request, response, callback) {
+            Ok(done) => done,
+            Err((request, overrun)) => {
+                log_overrun(&request, &overrun, start.elapsed());
+                return write_to_stream(stream, overrun);
+            }
+        };
+
+        #[cfg(feature = "metrics")]
+        let handled = start.elapsed();
+
+        #[cfg(feature = "websocket")]
+        response.upgrade_websocket(&request);
+
+        response.validate_conditional(&request);
+        response.negotiate_err_format(&request);
+        response.negotiate_encoding(&request);
 
         response.redirect_handling();
-        response.validate_and_update();
+        response.validate_and_update_for(Some(&request));
+        context::end_request();
+
+        for reason in response.audit() {
+            rex_debug!("Response to {} is overridden: {}", request.uri, reason);
+        }
+
+        #[cfg(feature = "metrics")]
+        metrics::observe(&request.method, response.get_status(), handled);
+
+        #[cfg(feature = "logger")]
+        logger::log_access(&request, &response, start.elapsed());
 
         write_to_stream(stream, response)
     }
 
     fn write_to_stream(mut stream: Stream, mut response: Box<Response>) -> ExecCode {
-        let s_clone = if response.to_keep_alive() {
-            match stream.try_clone() {
-                Ok(s) => Some(s),
-                _ => None,
-            }
+        let s_clone = if response.is_long_conn() {
+            stream.try_clone().ok()
         } else {
             None
         };
 
+        stats::record_response(response.status_sent());
         let mut writer = BufWriter::new(&mut stream);
 
         // Serialize the header to the stream
         response.write_header(&mut writer);
 
+        // the handshake is done, the websocket takes over the connection
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(upgrade) = response.take_websocket() {
+                drop(writer);
+                serve_websocket(Ok(stream), upgrade);
+                return 0;
+            }
+        }
+
         // If header only, we're done
         if response.is_header_only() {
             return 0;
         }
 
-        if let Some(s) = s_clone {
-            // serialize_trunked_body will block until all the keep-alive i/o are done
-            response.keep_long_conn(s, &mut writer);
+        if response.is_long_conn() {
+            // keep_long_conn will block until all the keep-alive i/o are done
+            response.keep_long_conn(s_clone, None, &mut writer);
         } else {
             // else, write the body to the stream
             response.write_body(&mut writer);
@@ -861,69 +2092,149 @@ mod async_handler {
         stream_shutdown(writer.get_mut())
     }
 
-    fn recv_requests(stream: &mut Stream) -> Result<(RouteHandler, Box<Request>), StreamException> {
-        let raw = read_content(stream)?;
-        let trimmed = raw.trim_matches(|c| c == '\r' || c == '\n' || c == '\u{0}');
+    /// The reason to refuse the request, and the request if it's parsed by then.
+    type Rejection = (StreamException, Option<Box<Request>>);
+
+    fn recv_requests(
+        stream: &mut Stream,
+        limits: &ConnLimits,
+    ) -> Result<(RouteHandler, Box<Request>), Rejection> {
+        let (raw, arrived) = read_head(stream, limits).map_err(|err| (err, None))?;
+        let (head, body_start) = match find_head_end(&raw) {
+            Some((head_end, body_start)) => (&raw[..head_end], body_start),
+            None => (&raw[..], raw.len()),
+        };
+
+        if limits.line_endings == LineEndings::Strict && has_bare_lf(&raw[..body_start]) {
+            return Err((StreamException::MalformedRequest, None));
+        }
+
+        let head = match str::from_utf8(head) {
+            Ok(head) => head.trim_end_matches(|c| c == '\r' || c == '\n' || c == '\u{0}'),
+            Err(_) => {
+                rex_warn!("Failed to parse the request stream");
+                return Err((StreamException::ReadStreamFailure, None));
+            }
+        };
+
+        if head.is_empty() {
+            return Err((StreamException::EmptyRequest, None));
+        }
 
-        if trimmed.is_empty() {
-            return Err(StreamException::EmptyRequest);
+        if let Some(err) = check_head(head, limits) {
+            return Err((err, None));
         }
 
         let mut request = Box::new(Request::new());
-        let result = parse_request(trimmed, &mut request);
+        let (mut result, view) = parse_request(head, &mut request);
+        stats::record_request();
+
+        // the CORS preflights are answered on behalf of the routes, which may not even exist
+        let preflight = cors::is_preflight(&request);
+        if preflight {
+            result = RouteHandler::new(Some(cors::answer_preflight), None);
+        }
+
+        let body_limit = limits.body_limit(result.max_body());
+        if body_limit > 0 && content_length(&request) > body_limit {
+            return Err((StreamException::PayloadTooLarge, Some(request)));
+        }
+
+        let expect = request.header("expect");
+        if let Some(expectation) = expect.as_ref() {
+            if !expectation.eq_ignore_ascii_case("100-continue") || result.is_none() {
+                return Err((StreamException::ExpectationFailed, Some(request)));
+            }
+        }
 
         if result.is_none() {
-            return Err(StreamException::ServiceUnavailable);
+            return Err((StreamException::ServiceUnavailable, Some(request)));
         }
 
         if let Ok(client) = stream.peer_addr() {
             request.set_client(client);
         }
 
-        if let Some(auth) = Route::get_auth_func() {
-            if !auth(&request, &request.uri) {
-                return Err(StreamException::AccessDenied);
+        if !preflight && !view.authorize(&mut request) {
+            return Err((StreamException::AccessDenied, Some(request)));
+        }
+
+        // only now that the request is accepted, read its body; the client may be holding it back
+        // until we let it go ahead
+        let declared = content_length(&request);
+        let mut body = arrived;
+
+        if declared > body.len() {
+            if expect.is_some()
+                && body.is_empty()
+                && (stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").is_err()
+                    || stream.flush().is_err())
+            {
+                return Err((StreamException::ReadStreamFailure, None));
+            }
+
+            let rest = (declared - body.len()) as u64;
+            body.reserve(declared.min(RAW_BUF_CAP));
+
+            if Read::by_ref(stream)
+                .take(rest)
+                .read_to_end(&mut body)
+                .is_err()
+            {
+                return Err((StreamException::ReadStreamFailure, None));
             }
+        } else if declared > 0 {
+            body.truncate(declared);
+        }
+
+        if !body.is_empty() {
+            request.set_body_bytes(body);
         }
 
         Ok((result, request))
     }
 
-    fn read_content(stream: &mut Stream) -> Result<String, StreamException> {
+    /// Read until the request head is complete, or the stream ends, without the line breaks and
+    /// paddings before it. Return the head, and the beginning of the body that has arrived with it;
+    /// the rest of the body is left unread. The head is refused once it outgrows the limit, without
+    /// waiting for its end.
+    fn read_head(
+        stream: &mut Stream,
+        limits: &ConnLimits,
+    ) -> Result<(Vec<u8>, Vec<u8>), StreamException> {
         let mut buffer = [0u8; 512];
-        let mut raw_req = String::with_capacity(512);
+        let mut raw_req = Vec::with_capacity(512);
 
         loop {
             match stream.read(&mut buffer) {
-                Ok(len) => {
-                    if len == 0 && raw_req.is_empty() {
+                Ok(0) => {
+                    if raw_req.is_empty() {
                         // if the request is a mere handshake with no request data, we return
                         return Err(StreamException::HeartBeat);
                     }
 
-                    if let Ok(req_slice) = str::from_utf8(&buffer[..len]) {
-                        if len < 512 {
-                            // trim end if we're at the end of the request stream
-                            raw_req.push_str(
-                                req_slice
-                                    .trim_end_matches(|c| c == '\r' || c == '\n' || c == '\u{0}'),
-                            );
-
-                            return Ok(raw_req);
-                        } else {
-                            // if there are more to read, don't trim and continue
-                            raw_req.push_str(req_slice);
-                        }
-                    } else {
-                        debug::print("Failed to parse the request stream", InfoLevel::Warning);
-                        return Err(StreamException::ReadStreamFailure);
+                    return Ok((raw_req, Vec::new()));
+                }
+                Ok(len) => {
+                    raw_req.extend_from_slice(&buffer[..len]);
+
+                    let lead = raw_req
+                        .iter()
+                        .position(|b| *b != b'\r' && *b != b'\n' && *b != 0)
+                        .unwrap_or_else(|| raw_req.len());
+                    raw_req.drain(..lead);
+
+                    if let Some((_, body_start)) = find_head_end(&raw_req) {
+                        let arrived = raw_req.split_off(body_start);
+                        return Ok((raw_req, arrived));
+                    }
+
+                    if raw_req.len() > limits.head_limit() {
+                        return Err(StreamException::HeaderTooLarge);
                     }
                 }
                 Err(e) => {
-                    debug::print(
-                        &format!("Reading stream disconnected -- {}", e),
-                        InfoLevel::Warning,
-                    );
+                    rex_warn!("Reading stream disconnected -- {}", e);
 
                     return Err(StreamException::ReadStreamFailure);
                 }
@@ -931,16 +2242,17 @@ mod async_handler {
         }
     }
 
-    fn parse_request(source: &str, store: &mut Box<Request>) -> RouteHandler {
+    fn parse_request(source: &str, store: &mut Box<Request>) -> (RouteHandler, RouterView) {
         if source.is_empty() {
-            return RouteHandler::default();
+            return (RouteHandler::default(), RouterView::default());
         }
 
         let mut res = RouteHandler::default();
+        let mut view = RouterView::default();
         let mut baseline_chan = None;
         let mut remainder_chan = None;
 
-        for (index, info) in source.trim().splitn(2, "\r\n").enumerate() {
+        for (index, info) in source.trim().splitn(2, '\n').enumerate() {
             match index {
                 0 => baseline_chan = parse_start_line(&info, store),
                 1 => {
@@ -950,7 +2262,7 @@ mod async_handler {
                     }
 
                     let (tx_remainder, rx_remainder) = channel::bounded(1);
-                    let mut header: HashMap<String, String> = HashMap::new();
+                    let mut header = HeaderMap::new();
                     let mut cookie: HashMap<String, String> = HashMap::new();
                     let mut body: String = String::with_capacity(1024);
 
@@ -968,10 +2280,7 @@ mod async_handler {
                             }
 
                             if tx_remainder.send((header, cookie, body)).is_err() {
-                                debug::print(
-                                    "Unable to construct the remainder of the request.",
-                                    InfoLevel::Error,
-                                );
+                                rex_error!("Unable to construct the remainder of the request.");
                             }
                         },
                         TaskType::Request,
@@ -984,11 +2293,15 @@ mod async_handler {
         }
 
         if let Some(rx) = baseline_chan {
-            if let Ok(result) = rx.recv_timeout(Duration::from_millis(128)) {
-                res = result.0;
-                if res.is_some() {
-                    store.create_param(result.1);
-                }
+            let result = await_seek(&rx, SEEK_TIMEOUT);
+            res = result.0;
+            view = result.2;
+            if res.is_some() {
+                store.create_param(result.1);
+            }
+
+            if let Some(pattern) = res.pattern() {
+                store.set_route_pattern(pattern);
             }
 
             if let Some(chan) = remainder_chan {
@@ -1000,7 +2313,20 @@ mod async_handler {
             }
         }
 
-        res
+        (res, view)
+    }
+
+    /// Wait for the route lookup. If it fails or times out, the router view is unknown, and the
+    /// request is denied rather than let through without the auth function of the router.
+    pub(super) fn await_seek(rx: &Receiver<SeekResult>, timeout: Duration) -> SeekResult {
+        rx.recv_timeout(timeout).unwrap_or_else(|_| {
+            rex_warn!("The route lookup has failed or timed out, denying the request");
+            (
+                RouteHandler::default(),
+                HashMap::new(),
+                RouterView::denied(),
+            )
+        })
     }
 
     fn parse_start_line(source: &str, req: &mut Box<Request>) -> BaseLine {
@@ -1014,16 +2340,7 @@ mod async_handler {
 
             match index {
                 0 => {
-                    let base_method = match &info.to_uppercase()[..] {
-                        "GET" => REST::GET,
-                        "PUT" => REST::PUT,
-                        "POST" => REST::POST,
-                        "DELETE" => REST::DELETE,
-                        "OPTIONS" => REST::OPTIONS,
-                        _ => REST::OTHER(info.to_uppercase()),
-                    };
-
-                    req.method = base_method;
+                    req.method = REST::parse(info);
                 }
                 1 => {
                     // path is at most the length of the source string
@@ -1058,7 +2375,7 @@ mod async_handler {
             }
 
             if !raw_query.is_empty() {
-                req.create_query(parse_query(raw_query));
+                req.set_query(parse_query(raw_query));
             }
 
             return Some(rx);
@@ -1069,12 +2386,9 @@ mod async_handler {
 
     fn stream_shutdown(stream: &mut Stream) -> u8 {
         if let Err(err) = stream.shutdown(Shutdown::Both) {
-            debug::print(
-                &format!(
-                    "Encountered errors while shutting down the trunked body stream: {}",
-                    err
-                ),
-                InfoLevel::Warning,
+            rex_warn!(
+                "Encountered errors while shutting down the trunked body stream: {}",
+                err
             );
             return 1;
         }
@@ -1082,3 +2396,1957 @@ mod async_handler {
         0
     }
 }
+
+#[cfg(test)]
+mod conn_test {
+    use super::{
+        async_handler, build_response, hand_off, init_pool, parse_path, parse_query,
+        parse_request_sync, pool_stats, read_redirect_head, recycle, redirect_target,
+        send_https_redirect, serve_connection, ConnContext, Leftover, PipelineWorker,
+        RespSeqBundle, StreamHandler, ABANDONED, ABANDONED_CAP, BUFFER_SIZE, RAW_BUF_CAP,
+    };
+    use crate::channel;
+    use crate::core::config::{
+        init_test_config, set_test_cors, ConnLimits, EngineContext, LineEndings, ServerConfig,
+        ViewEngineDefinition, HEAD_BYTES_CAP,
+    };
+    use crate::core::context;
+    use crate::core::cors::{AllowedOrigins, CorsPolicy};
+    use crate::core::http::{Request, Response, ResponseStates, ResponseWriter};
+    use crate::core::router::{
+        Callback, RequestPath, Route, RouteHandler, RouteOptions, ViewsScope, REST,
+    };
+    use crate::core::stream::{MockStream, Stream};
+    use crate::core::syncstore::Reusable;
+    #[cfg(feature = "websocket")]
+    use crate::core::websocket::{WsConnection, WsMessage};
+    use crate::parking_lot::{Mutex, RwLock};
+    use crate::support::debug::{self, InfoLevel as DebugLevel};
+    use std::env;
+    use std::fs;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::{atomic::Ordering, Arc, Once};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    static ROUTES: Once = Once::new();
+
+    lazy_static! {
+        /// The tests serving the connections share the pool of the contexts, while the one
+        /// counting the contexts in the pool has it to itself.
+        static ref CONN_POOL_USERS: RwLock<()> = RwLock::new(());
+
+        /// Likewise, the tests with the timed handlers share the count of the abandoned ones.
+        static ref TIMED_HANDLERS: RwLock<()> = RwLock::new(());
+    }
+
+    fn pong(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.send("pong");
+    }
+
+    fn echo(req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.send(&req.json());
+    }
+
+    /// Describe the parts of the upload, with the contents in hex.
+    fn list_parts(req: &Box<Request>, resp: &mut Box<Response>) {
+        match req.multipart() {
+            Ok(form) => {
+                for part in form.iter() {
+                    let hex: String = part.bytes().iter().map(|b| format!("{:02x}", b)).collect();
+                    resp.send(&format!(
+                        "[{}|{}|{}]",
+                        part.name(),
+                        part.filename().unwrap_or_default(),
+                        hex
+                    ));
+                }
+            }
+            Err(e) => {
+                resp.status(400);
+                resp.send(&e.to_string());
+            }
+        }
+    }
+
+    fn mirror(req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.send(&String::from_utf8_lossy(req.body_bytes()));
+    }
+
+    fn resubmit(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.redirect_preserve_method("/upload");
+    }
+
+    fn deny_private(_req: &Box<Request>, uri: &str) -> bool {
+        uri != "/private"
+    }
+
+    fn setup_routes() {
+        ROUTES.call_once(|| {
+            init_test_config();
+            Route::init();
+            init_pool();
+            Route::set_auth_func(Some(deny_private));
+            Route::add_route(
+                REST::GET,
+                RequestPath::Explicit("/ping"),
+                RouteHandler::new(Some(pong), None),
+            );
+            Route::add_route(
+                REST::POST,
+                RequestPath::Explicit("/private"),
+                RouteHandler::new(Some(pong), None),
+            );
+            Route::add_route(
+                REST::GET,
+                RequestPath::ExplicitWithParams("/files/:name"),
+                RouteHandler::new(Some(pong), None),
+            );
+            Route::add_route(
+                REST::POST,
+                RequestPath::Explicit("/upload"),
+                RouteHandler::new(Some(echo), None),
+            );
+            Route::add_route(
+                REST::POST,
+                RequestPath::Explicit("/form"),
+                RouteHandler::new(Some(resubmit), None),
+            );
+            Route::add_route(
+                REST::POST,
+                RequestPath::Explicit("/mirror"),
+                RouteHandler::new(Some(mirror), None),
+            );
+            Route::add_route(
+                REST::POST,
+                RequestPath::Explicit("/multipart"),
+                RouteHandler::new(Some(list_parts), None),
+            );
+
+            let mut images = RouteHandler::new(Some(pong), None);
+            images.set_options(RouteOptions::new().max_body(50 * 1024 * 1024));
+            Route::add_route(REST::POST, RequestPath::Explicit("/images"), images);
+
+            #[cfg(feature = "websocket")]
+            Route::add_route(
+                REST::GET,
+                RequestPath::Explicit("/ws"),
+                RouteHandler::new(Some(ws_accept), None),
+            );
+        });
+    }
+
+    #[cfg(feature = "websocket")]
+    fn ws_accept(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.accept_websocket(ws_echo);
+    }
+
+    #[cfg(feature = "websocket")]
+    fn ws_echo(mut conn: WsConnection) {
+        while let Ok(message) = conn.recv() {
+            match message {
+                WsMessage::Text(_) | WsMessage::Binary(_) => conn.send(message).unwrap(),
+                WsMessage::Close(_) => break,
+                _ => {}
+            }
+        }
+    }
+
+    fn serve_pipeline(raw_requests: &[u8]) -> String {
+        serve_limited(raw_requests, ConnLimits::default())
+    }
+
+    fn serve_limited(raw_requests: &[u8], limits: ConnLimits) -> String {
+        let _pool = CONN_POOL_USERS.read();
+        serve_exclusive(raw_requests, limits)
+    }
+
+    /// Serve the connection without sharing the pool of the contexts, i.e. the caller has it to
+    /// itself already.
+    fn serve_exclusive(raw_requests: &[u8], limits: ConnLimits) -> String {
+        setup_routes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        let handler = thread::spawn(move || server.process(false, 0, limits));
+
+        // the server may close the connection before taking all the requests, e.g. after rejecting
+        // a large body, so write from aside and don't wait for it: the write can be stuck until
+        // the closed socket is timed out.
+        let mut writer = client.try_clone().unwrap();
+        let raw_requests = raw_requests.to_vec();
+        thread::spawn(move || {
+            writer.write_all(&raw_requests).unwrap_or_default();
+            writer.shutdown(Shutdown::Write).unwrap_or_default();
+        });
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        let mut wire = Vec::new();
+        client.read_to_end(&mut wire).unwrap_or_default();
+        handler.join().unwrap();
+
+        String::from_utf8_lossy(&wire).into_owned()
+    }
+
+    fn round_trip(raw_request: &str) -> String {
+        setup_routes();
+
+        let (request, handler, _) = parse_request_sync(raw_request);
+        let response = build_response(request, handler, false);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = Stream::Tcp(listener.accept().unwrap().0);
+
+        assert_eq!(server.sink(response), 0);
+        server.shutdown(Shutdown::Both).unwrap_or_default();
+
+        let mut wire = String::new();
+        client.read_to_string(&mut wire).unwrap();
+        wire
+    }
+
+    #[test]
+    fn coalesced_small_response() {
+        setup_routes();
+
+        // returns the bytes on the wire without the date, and the number of writes to the stream
+        let send = |content: &str, coalesce: usize| {
+            let mut resp = Box::new(Response::new());
+            resp.status(200);
+            resp.send(content);
+
+            let mock = MockStream::new(0);
+            let (wire, writes) = (mock.wire.clone(), mock.writes.clone());
+            let mut stream = Stream::Mock(mock);
+
+            let (tx, rx) = channel::unbounded();
+            tx.send(RespSeqBundle(1, resp)).unwrap();
+            drop(tx);
+
+            stream.send_responses(rx, &mut Vec::new(), &Mutex::new(None), coalesce);
+
+            let wire = String::from_utf8(wire.lock().unwrap().clone()).unwrap();
+            let wire: Vec<String> = wire
+                .split("\r\n")
+                .filter(|line| !line.starts_with("Date:"))
+                .map(String::from)
+                .collect();
+
+            (wire, writes.load(Ordering::Relaxed))
+        };
+
+        let (split, split_writes) = send("hello world", 0);
+        let (joined, joined_writes) = send("hello world", 8 * 1024);
+        assert_eq!(split_writes, 2);
+        assert_eq!(joined_writes, 1);
+        assert_eq!(split, joined);
+        assert_eq!(joined.last().map(String::as_str), Some("hello world"));
+
+        // the ones larger than the threshold keep the header and the body apart
+        let (_, large_writes) = send(&"a".repeat(4096), 1024);
+        assert_eq!(large_writes, 2);
+    }
+
+    #[test]
+    fn hsts_over_tls() {
+        init_test_config();
+        ServerConfig::set_hsts(Duration::from_secs(31_536_000), true, false);
+
+        let send = |tls: bool, header: Option<&str>| {
+            let mut resp = Box::new(Response::new());
+            if tls {
+                resp.set_tls_conn();
+            }
+
+            if let Some(value) = header {
+                resp.set_header("Strict-Transport-Security", value);
+            }
+
+            resp.send("secure");
+
+            let mock = MockStream::new(0);
+            let wire = mock.wire.clone();
+            let mut stream = Stream::Mock(mock);
+
+            let (tx, rx) = channel::unbounded();
+            tx.send(RespSeqBundle(1, resp)).unwrap();
+            drop(tx);
+
+            stream.send_responses(rx, &mut Vec::new(), &Mutex::new(None), 0);
+            let wire = String::from_utf8(wire.lock().unwrap().clone()).unwrap();
+            wire.to_ascii_lowercase()
+        };
+
+        let wire = send(true, None);
+        assert!(
+            wire.contains("strict-transport-security: max-age=31536000; includesubdomains\r\n"),
+            "{}",
+            wire
+        );
+
+        // the plain connections never carry it, and the handler's own value is kept
+        assert!(!send(false, None).contains("strict-transport-security"));
+
+        let wire = send(true, Some("max-age=0"));
+        assert_eq!(
+            wire.matches("strict-transport-security").count(),
+            1,
+            "{}",
+            wire
+        );
+        assert!(wire.contains("max-age=0\r\n"), "{}", wire);
+    }
+
+    #[test]
+    fn flush_failure_stops_pipeline() {
+        setup_routes();
+
+        let reply = |content: &str| {
+            let mut resp = Box::new(Response::new());
+            resp.status(200);
+            resp.send(content);
+            resp
+        };
+
+        // every response flushes its header and then its body, so the 3rd flush is the header of
+        // the 2nd response
+        let mock = MockStream::new(3);
+        let wire = mock.wire.clone();
+        let mut stream = Stream::Mock(mock);
+
+        let (tx, rx) = channel::unbounded();
+        for (id, content) in ["first-body", "second-body", "third-body"]
+            .iter()
+            .enumerate()
+        {
+            tx.send(RespSeqBundle(id + 1, reply(content))).unwrap();
+        }
+        drop(tx);
+
+        stream.send_responses(rx, &mut Vec::new(), &Mutex::new(None), 0);
+
+        let wire = String::from_utf8(wire.lock().unwrap().clone()).unwrap();
+        assert!(wire.contains("first-body"));
+        assert_eq!(wire.matches("HTTP/1.1 200").count(), 2);
+        assert!(!wire.contains("second-body"));
+        assert!(!wire.contains("third-body"));
+    }
+
+    #[test]
+    fn percent_decoded_path() {
+        let cases = [
+            ("/my%20file.txt", "/my file.txt", ""),
+            ("/%E4%B8%AD%E6%96%87", "/中文", ""),
+            ("/a%2Fb", "/a/b", ""),
+            ("/bad%ZZ/trunc%2", "/bad%ZZ/trunc%2", ""),
+            ("/search%20all?q=a%20b", "/search all", "?q=a%20b"),
+            ("/?q=a%20b", "/", "?q=a%20b"),
+            ("/a+b", "/a+b", ""),
+        ];
+
+        for (raw, expected_path, expected_query) in cases.iter() {
+            let (mut path, mut query, mut frag) = (String::new(), String::new(), String::new());
+            parse_path(raw, &mut path, &mut query, &mut frag);
+
+            assert_eq!(&path, expected_path, "Failed at case: {}", raw);
+            assert_eq!(&query, expected_query, "Failed at case: {}", raw);
+        }
+    }
+
+    #[test]
+    fn percent_decoded_query() {
+        let cases = [
+            ("name=my%20file", "name", "my file"),
+            ("name=a+b", "name", "a b"),
+            ("path=%2Fhome%2Fuser", "path", "/home/user"),
+            ("lang=%E4%B8%AD%E6%96%87", "lang", "中文"),
+            ("first%20name=Jo", "first name", "Jo"),
+            ("bad=%ZZ%2", "bad", "%ZZ%2"),
+            ("latin=%E9", "latin", "%E9"),
+            ("?lead=1", "lead", "1"),
+        ];
+
+        for (raw, key, expected) in cases.iter() {
+            let query = parse_query(String::from(*raw));
+            assert_eq!(
+                query,
+                vec![(key.to_string(), expected.to_string())],
+                "Failed at case: {}",
+                raw
+            );
+        }
+
+        // the pairs without a key are skipped
+        let query = parse_query(String::from("?=x&&a=1&=&b&"));
+        assert_eq!(
+            query,
+            vec![
+                (String::from("a"), String::from("1")),
+                (String::from("b"), String::new())
+            ]
+        );
+        assert!(parse_query(String::from("&&")).is_empty());
+    }
+
+    #[test]
+    fn percent_decoded_params() {
+        setup_routes();
+
+        let (request, handler, _) = parse_request_sync(
+            "GET /files/my%20file%E2%84%A2.txt?tag=a+b%26c HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+
+        assert!(handler.is_some());
+        assert_eq!(request.param("name"), Some(String::from("my file™.txt")));
+        assert_eq!(request.query("tag"), Some(vec![String::from("a b&c")]));
+    }
+
+    #[test]
+    fn multipart_upload() {
+        // the file is not valid UTF-8, and carries the blank line
+        let file = [
+            0x89, b'P', b'N', b'G', b'\r', b'\n', b'\r', b'\n', 0xff, 0x00,
+        ];
+
+        let mut body = Vec::new();
+        body.extend_from_slice(b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\n");
+        body.extend_from_slice(b"hi there\r\n--XyZ\r\n");
+        body.extend_from_slice(
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+              Content-Type: image/png\r\n\r\n",
+        );
+        body.extend_from_slice(&file);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+        let mut raw = format!(
+            "POST /multipart HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Type: multipart/form-data; boundary=XyZ\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&body);
+
+        let wire = serve_pipeline(&raw);
+        assert!(wire.starts_with("HTTP/1.1 200 OK"), "{}", wire);
+        assert!(
+            wire.ends_with("[title||6869207468657265][file|a.png|89504e470d0a0d0aff00]"),
+            "{}",
+            wire
+        );
+    }
+
+    #[test]
+    fn method_preserving_redirect() {
+        let wire = round_trip(
+            "POST /form HTTP/1.1\r\nHost: localhost\r\nContent-Length: 7\r\n\r\nname=jo",
+        );
+
+        let (head, body) = wire.split_at(wire.find("\r\n\r\n").unwrap() + 4);
+        assert!(
+            head.starts_with("HTTP/1.1 307 Temporary Redirect\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("location: /upload\r\n"), "{}", head);
+        assert!(head.contains("Content-Length: 0\r\n"), "{}", head);
+        assert!(body.is_empty(), "{}", body);
+    }
+
+    #[test]
+    fn conn_context_reset() {
+        let mut ctx = <Arc<ConnContext>>::obtain();
+        ctx.raw_buf
+            .lock()
+            .extend_from_slice(b"GET /secret HTTP/1.1\r\n\r\n");
+        ctx.spare.lock().extend_from_slice(b"token=secret");
+        ctx.reorder
+            .lock()
+            .push(RespSeqBundle(2, Response::obtain()));
+
+        // the other end of the pipeline is still around, so the context must be left alone
+        let reader = Arc::clone(&ctx);
+        ctx.reset(false);
+        assert_eq!(reader.raw_buf.lock().len(), 24);
+
+        drop(reader);
+        ctx.reset(false);
+
+        assert!(ctx.raw_buf.lock().is_empty());
+        assert!(ctx.raw_buf.lock().capacity() >= 24);
+        assert!(ctx.spare.lock().is_empty());
+        assert!(ctx.spare.lock().capacity() >= 12);
+        assert!(ctx.reorder.lock().is_empty());
+
+        // the buffer grown over the cap is not kept
+        ctx.raw_buf.lock().resize(RAW_BUF_CAP + 1, b'a');
+        ctx.reset(false);
+        assert_eq!(ctx.raw_buf.lock().capacity(), 0);
+    }
+
+    #[test]
+    fn raw_buffer_hand_off() {
+        let spare = Mutex::new(Vec::with_capacity(64));
+        let mut raw_req = b"GET /ping HTTP/1.1\r\n\r\n".to_vec();
+        let bytes = raw_req.as_ptr();
+
+        // the bytes are handed over as they are, and the spare buffer takes their place
+        let trunk = hand_off(&mut raw_req, &spare);
+        assert_eq!(trunk.as_ptr(), bytes);
+        assert!(raw_req.is_empty() && raw_req.capacity() >= 64);
+        assert_eq!(spare.lock().capacity(), 0);
+
+        // the parser hands them back, cleared
+        recycle(&spare, trunk);
+        assert_eq!(spare.lock().as_ptr(), bytes);
+        assert!(spare.lock().is_empty());
+
+        // neither the smaller buffer nor the one over the cap is kept instead
+        recycle(&spare, Vec::with_capacity(1));
+        recycle(&spare, Vec::with_capacity(RAW_BUF_CAP + 1));
+        assert_eq!(spare.lock().as_ptr(), bytes);
+    }
+
+    #[test]
+    fn conn_context_not_shared_between_connections() {
+        let _pool = CONN_POOL_USERS.write();
+
+        let serve = || {
+            let wire = serve_exclusive(
+                b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                ConnLimits::default(),
+            );
+
+            assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+            assert_eq!(wire.matches("pong").count(), 2);
+        };
+
+        // the last stage leaving the connection hands the context back, a moment after the
+        // response is out
+        let returned = |available: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while pool_stats().0 < available && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            pool_stats()
+        };
+
+        serve();
+        let (mut available, mut counters) = returned(1);
+        assert!(available > 0);
+
+        for _ in 0..4 {
+            serve();
+            let (now_available, now) = returned(available);
+
+            // the context is checked out from the pool, then handed back to it
+            assert_eq!(now.gets, counters.gets + 1);
+            assert_eq!(now.get_misses, counters.get_misses);
+            assert_eq!(now.put_drops, counters.put_drops);
+            assert!(
+                now_available >= available,
+                "{} < {}",
+                now_available,
+                available
+            );
+
+            available = now_available;
+            counters = now;
+        }
+    }
+
+    #[test]
+    fn request_size_limits() {
+        let limits = ConnLimits {
+            header_bytes: 128,
+            header_count: 3,
+            body_bytes: 16,
+            ..Default::default()
+        };
+
+        let wire = serve_limited(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            limits,
+        );
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+
+        let cookie = format!(
+            "GET /ping HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\n\r\n",
+            "a".repeat(128)
+        );
+        let wire = serve_limited(cookie.as_bytes(), limits);
+        assert!(wire.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let wire = serve_limited(
+            b"GET /ping HTTP/1.1\r\nHost: a\r\nX-A: 1\r\nX-B: 2\r\nX-C: 3\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            limits,
+        );
+        assert!(wire.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+
+        let wire = serve_limited(
+            b"POST /private HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n\
+              aaaaaaaaaaaaaaaaa",
+            limits,
+        );
+        assert!(wire.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+
+        // the async path stops reading the head as soon as it's over the limit
+        let mock = MockStream::new(0);
+        mock.feed(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nX-Pad: ");
+        mock.feed(&[b'a'; 65536]);
+
+        let wire = Arc::clone(&mock.wire);
+        let read = Arc::clone(&mock.read_bytes);
+        async_handler::handle_connection(Stream::Mock(mock), limits);
+
+        let wire = String::from_utf8_lossy(&wire.lock().unwrap()).into_owned();
+        assert!(wire.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(read.load(Ordering::SeqCst) < 1024);
+
+        let limits = ConnLimits {
+            cookie_count: 2,
+            query_params: 2,
+            ..Default::default()
+        };
+
+        let wire = serve_limited(
+            b"GET /ping?a=1&b=2#c&d HTTP/1.1\r\nHost: localhost\r\nCookie: a=1; b=2;\r\n\r\n",
+            limits,
+        );
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let wire = serve_limited(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\nCookie: a=1; b=2\r\nCookie: c=3\r\n\r\n",
+            limits,
+        );
+        assert!(wire.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        let wire = serve_limited(
+            b"GET /ping?a=1&b=2&c HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            limits,
+        );
+        assert!(wire.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    /// Write the request in fragments, then close the write side right after the last one.
+    fn serve_fragments(fragments: &[&[u8]]) -> String {
+        let _pool = CONN_POOL_USERS.read();
+        setup_routes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        let handler = thread::spawn(move || server.process(false, 0, ConnLimits::default()));
+
+        client.set_nodelay(true).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        for (i, fragment) in fragments.iter().enumerate() {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(30));
+            }
+
+            client.write_all(fragment).unwrap();
+        }
+
+        client.shutdown(Shutdown::Write).unwrap_or_default();
+
+        let mut wire = Vec::new();
+        client.read_to_end(&mut wire).unwrap_or_default();
+        handler.join().unwrap();
+
+        String::from_utf8_lossy(&wire).into_owned()
+    }
+
+    /// Read a single response off the connection, and return its body.
+    fn read_response(client: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+
+        let len = head
+            .lines()
+            .find_map(|line| {
+                let mut field = line.splitn(2, ':');
+                match field.next() {
+                    Some(key) if key.eq_ignore_ascii_case("content-length") => field
+                        .next()
+                        .and_then(|val| val.trim().parse::<usize>().ok()),
+                    _ => None,
+                }
+            })
+            .unwrap();
+
+        let mut body = vec![0u8; len];
+        client.read_exact(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    /// Echo the client's messages over the long connection, and end it after the third one.
+    fn chat(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.status(200);
+        resp.keep_alive(true);
+
+        let (notifier, subscriber) = resp.get_channels().unwrap();
+        thread::spawn(move || {
+            for message in subscriber.iter().take(3) {
+                notifier.send(format!("<{}>", message)).unwrap_or_default();
+            }
+
+            notifier.send(String::new()).unwrap_or_default();
+        });
+    }
+
+    #[test]
+    fn long_conn_through_reader() {
+        let _pool = CONN_POOL_USERS.read();
+        setup_routes();
+        Route::add_route(
+            REST::GET,
+            RequestPath::Explicit("/chat"),
+            RouteHandler::new(Some(chat), None),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        let handler = thread::spawn(move || server.process(false, 0, ConnLimits::default()));
+
+        client.set_nodelay(true).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+            .write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        // the messages look nothing like requests, and they must all reach the route
+        for message in ["hello\n", "GET / HTTP/1.1\n", "bye\n"].iter() {
+            thread::sleep(Duration::from_millis(30));
+            client.write_all(message.as_bytes()).unwrap();
+        }
+
+        let mut wire = Vec::new();
+        let mut byte = [0u8; 1];
+        while !wire.ends_with(b"\r\n0\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            wire.push(byte[0]);
+        }
+
+        let wire = String::from_utf8(wire).unwrap();
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+        assert!(wire.contains("<hello>"), "{}", wire);
+        assert!(wire.contains("<GET / HTTP/1.1>"), "{}", wire);
+        assert!(wire.contains("<bye>"), "{}", wire);
+
+        // the long connection is over, and the reader is back to the requests
+        client
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        assert_eq!(read_response(&mut client), "pong");
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn bodies_across_reads() {
+        let _pool = CONN_POOL_USERS.read();
+        setup_routes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        let handler = thread::spawn(move || server.process(false, 0, ConnLimits::default()));
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        for size in [511, 512, 513, 1024, 1500].iter() {
+            let body: String = (0..*size)
+                .map(|i| (b'a' + (i % 26) as u8) as char)
+                .collect();
+
+            // send each body twice: as is, and padded such that the request ends right at the
+            // end of a read, which is not followed by a short read
+            for pad in [false, true].iter() {
+                let mut head = format!(
+                    "POST /mirror HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n",
+                    size
+                );
+
+                if *pad {
+                    let used = head.len() + "X-Pad: \r\n\r\n".len() + size;
+                    let fill = (BUFFER_SIZE - used % BUFFER_SIZE) % BUFFER_SIZE;
+                    head.push_str(&format!("X-Pad: {}\r\n", "p".repeat(fill)));
+                }
+
+                let raw = format!("{}\r\n{}", head, body);
+                assert!(!*pad || raw.len() % BUFFER_SIZE == 0);
+
+                client.write_all(raw.as_bytes()).unwrap();
+                assert_eq!(read_response(&mut client), body, "size: {}", size);
+            }
+        }
+
+        client.shutdown(Shutdown::Both).unwrap_or_default();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn body_at_close() {
+        let raw: &[u8] =
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let head = raw.len() - 5;
+
+        let splits: Vec<Vec<&[u8]>> = vec![
+            vec![raw],
+            vec![&raw[..head], &raw[head..]],
+            vec![&raw[..head - 1], &raw[head - 1..]],
+            vec![&raw[..20], &raw[20..head + 2], &raw[head + 2..]],
+            vec![
+                &raw[..10],
+                &raw[10..30],
+                &raw[30..head],
+                &raw[head..head + 1],
+                &raw[head + 1..],
+            ],
+        ];
+
+        for fragments in splits.iter() {
+            let wire = serve_fragments(fragments);
+            assert!(
+                wire.starts_with("HTTP/1.1 200 OK\r\n"),
+                "{:?}: {}",
+                fragments,
+                wire
+            );
+            assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+        }
+
+        // the head without the ending empty line is still served
+        let wire = serve_fragments(&[b"GET /ping HTTP/1.1\r\nHost: localhost"]);
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // but the body is really missing
+        let wire = serve_fragments(&[
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n",
+            b"hello",
+        ]);
+        assert!(wire.starts_with("HTTP/1.1 400 "));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn websocket_upgrade() {
+        let _pool = CONN_POOL_USERS.read();
+        setup_routes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        let handler = thread::spawn(move || server.process(false, 0, ConnLimits::default()));
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        // a pipelined request is served before the upgrade
+        client
+            .write_all(
+                b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: keep-alive, Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\n\r\npongHTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Upgrade: websocket\r\nConnection: Upgrade\r\n"));
+
+        // the text frame "hello", masked, is echoed unmasked
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![0x81, 0x85];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        client.write_all(&frame).unwrap();
+
+        let mut echo = [0u8; 7];
+        client.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"\x81\x05hello");
+
+        // the close handshake, then the connection is closed
+        client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap_or_default();
+        assert_eq!(rest, vec![0x88, 0x00]);
+        handler.join().unwrap();
+
+        // the upgrade can't be accepted without the handshake headers
+        let wire = serve_pipeline(b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(wire.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+
+        // and a route not accepting the upgrade keeps serving http
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+    }
+
+    #[test]
+    fn head_across_trunks() {
+        setup_routes();
+
+        let raw: &[u8] = b"GET /ping HTTP/1.1\r\nHost: localhost\r\nX-Pad: abc\r\n\r\n";
+        let (tx, rx) = channel::unbounded();
+        let serve = |trunk: &[u8], leftover: &mut Leftover, limits: ConnLimits| {
+            serve_connection(trunk, 1, tx.clone(), 0, None, false, limits, leftover)
+        };
+
+        // the head arrives a byte at a time, and each byte is only searched once
+        let mut leftover = Leftover::default();
+        for (i, byte) in raw.iter().enumerate() {
+            let next_id = serve(&[*byte], &mut leftover, ConnLimits::default()).unwrap();
+
+            if i + 1 < raw.len() {
+                assert_eq!(next_id, 1);
+                assert_eq!(leftover.scanned, i + 1);
+            } else {
+                assert_eq!(next_id, 2);
+                assert_eq!(leftover.scanned, 0);
+            }
+        }
+
+        // the end of the head straddles the trunks
+        for split in raw.len() - 4..raw.len() {
+            let mut leftover = Leftover::default();
+            assert_eq!(
+                serve(&raw[..split], &mut leftover, ConnLimits::default()),
+                Ok(1)
+            );
+            assert_eq!(
+                serve(&raw[split..], &mut leftover, ConnLimits::default()),
+                Ok(2)
+            );
+        }
+
+        // the handlers are run aside, and each request is the first of its connection
+        let served: Vec<usize> = (0..5)
+            .filter_map(|_| rx.recv_timeout(Duration::from_secs(5)).ok())
+            .map(|bundle: RespSeqBundle| bundle.0)
+            .collect();
+        assert_eq!(served, vec![1; 5]);
+
+        // the head that never ends is cut off even if no limit is set
+        let mut leftover = Leftover::default();
+        let line = format!("X-Pad: {}\r\n", "a".repeat(1024));
+        let mut trunks = 1;
+        let mut result = serve(
+            b"GET /ping HTTP/1.1\r\n",
+            &mut leftover,
+            ConnLimits::default(),
+        );
+        while result == Ok(1) && trunks < 1024 {
+            result = serve(line.as_bytes(), &mut leftover, ConnLimits::default());
+            trunks += 1;
+        }
+
+        assert_eq!(result, Err(ErrorKind::ConnectionAborted));
+        assert!(trunks * line.len() > HEAD_BYTES_CAP);
+        assert!(trunks * line.len() < HEAD_BYTES_CAP + 2 * line.len());
+        assert_eq!(rx.try_recv().map(|bundle| bundle.1.get_status()), Ok(431));
+    }
+
+    #[test]
+    fn expect_continue() {
+        let _pool = CONN_POOL_USERS.read();
+        setup_routes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        let handler = thread::spawn(move || server.process(false, 0, ConnLimits::default()));
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+                  Content-Length: 11\r\n\r\n",
+            )
+            .unwrap();
+
+        // the body is held back until the server agrees to take it
+        let mut interim = [0u8; 25];
+        client.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        // the body may be split over the reads
+        client.write_all(b"hello").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b" world").unwrap();
+        client.shutdown(Shutdown::Write).unwrap_or_default();
+
+        let mut wire = Vec::new();
+        client.read_to_end(&mut wire).unwrap_or_default();
+        handler.join().unwrap();
+
+        let wire = String::from_utf8_lossy(&wire);
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(wire.contains("hello world"));
+
+        // the body has been sent along already, so no interim response is needed
+        let wire = serve_pipeline(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+              Content-Length: 5\r\n\r\nhello",
+        );
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let wire = serve_pipeline(
+            b"POST /missing HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+              Content-Length: 5\r\n\r\n",
+        );
+        assert!(wire.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+
+        let wire = serve_pipeline(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 200-ok\r\n\
+              Content-Length: 5\r\n\r\n",
+        );
+        assert!(wire.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+
+        let limits = ConnLimits {
+            body_bytes: 4,
+            ..Default::default()
+        };
+        let wire = serve_limited(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+              Content-Length: 5\r\n\r\n",
+            limits,
+        );
+        assert!(wire.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(!wire.contains("100 Continue"));
+    }
+
+    #[test]
+    fn hot_route_removal() {
+        setup_routes();
+
+        let flag = || {
+            serve_pipeline(b"GET /flag HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        };
+
+        Route::add_route(
+            REST::GET,
+            RequestPath::Explicit("/flag"),
+            RouteHandler::new(Some(pong), None),
+        );
+        assert!(flag().starts_with("HTTP/1.1 200 OK\r\n"));
+
+        assert!(Route::replace(
+            REST::GET,
+            RequestPath::Explicit("/flag"),
+            echo
+        ));
+        assert!(flag().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!flag().ends_with("pong"));
+
+        assert!(Route::remove(REST::GET, RequestPath::Explicit("/flag")));
+        assert!(flag().starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        assert!(!Route::remove(REST::GET, RequestPath::Explicit("/flag")));
+        assert!(!Route::replace(
+            REST::GET,
+            RequestPath::Explicit("/flag"),
+            pong
+        ));
+    }
+
+    #[test]
+    fn route_body_limits() {
+        let mut limits = ConnLimits {
+            body_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+
+        let upload = |uri: &str, limits: ConnLimits| {
+            let mut raw = format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                uri,
+                5 * 1024 * 1024
+            )
+            .into_bytes();
+
+            raw.resize(raw.len() + 5 * 1024 * 1024, b'x');
+            raw.extend_from_slice(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            serve_limited(&raw, limits)
+        };
+
+        let wire = upload("/images", limits);
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+
+        let wire = upload("/upload", limits);
+        assert!(wire.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+
+        // the route can't go beyond the ceiling
+        limits.body_ceiling = 2 * 1024 * 1024;
+        let wire = upload("/images", limits);
+        assert!(wire.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        // a small body over the limit is drained, and the connection is kept
+        let wire = serve_limited(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\n\
+              12345678GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            ConnLimits {
+                body_bytes: 4,
+                ..Default::default()
+            },
+        );
+        assert!(wire.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(wire.ends_with("\r\n\r\npong"));
+    }
+
+    #[test]
+    fn router_view_snapshot() {
+        setup_routes();
+
+        // keep changing the router while the requests are routed
+        let writer = thread::spawn(|| {
+            for i in 0..200 {
+                Route::add_route(
+                    REST::GET,
+                    RequestPath::ExplicitWithParams(&format!("/generation/{}/:id", i)),
+                    RouteHandler::new(Some(pong), None),
+                );
+            }
+        });
+
+        let mut last = 0;
+        for _ in 0..500 {
+            let checkouts = Route::read_checkouts();
+            let (mut request, handler, view) =
+                parse_request_sync("POST /private HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+            // the lookup and the auth function come out of a single read lock, or none at all on
+            // a cache hit, and authorizing takes no lock
+            assert!(handler.is_some());
+            assert!(Route::read_checkouts() - checkouts <= 1);
+            assert!(!view.authorize(&mut request));
+            assert!(Route::read_checkouts() - checkouts <= 1);
+
+            // the views only move forward, and never ahead of the router
+            assert!(view.generation() >= last);
+            assert!(view.generation() <= Route::generation());
+            last = view.generation();
+        }
+
+        writer.join().unwrap();
+
+        // the lookups cached before a change are outdated by it
+        let generation = Route::generation();
+        for _ in 0..2 {
+            let (_, handler, view) =
+                parse_request_sync("GET /generation/199/7 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            assert!(handler.is_some());
+            assert!(view.generation() >= generation);
+        }
+    }
+
+    #[test]
+    fn seek_timeout_denied() {
+        let mut request = Box::new(Request::new());
+        request.uri.push_str("/ping");
+
+        // the lookup never comes back
+        let (tx, rx) = channel::bounded(1);
+        let (handler, params, view) = async_handler::await_seek(&rx, Duration::from_millis(16));
+        assert!(handler.is_none());
+        assert!(params.is_empty());
+        assert!(!view.authorize(&mut request));
+
+        // or the lookup has failed
+        drop(tx);
+        let (handler, _, view) = async_handler::await_seek(&rx, Duration::from_secs(1));
+        assert!(handler.is_none());
+        assert!(!view.authorize(&mut request));
+    }
+
+    #[test]
+    fn cors_requests() {
+        setup_routes();
+
+        let mut policy = CorsPolicy::new(AllowedOrigins::exact(&["https://app.example.com"]));
+        policy.methods.push(REST::DELETE);
+        policy.allow_headers = vec![String::from("X-Token")];
+        policy.expose_headers = vec![String::from("X-Total")];
+        policy.allow_credentials = true;
+        policy.max_age = Some(600);
+        let _cors = set_test_cors(Some(policy));
+
+        // the preflight is answered without an OPTIONS route, or the authorization
+        for uri in ["/ping", "/private"].iter() {
+            let wire = serve_pipeline(
+                format!(
+                    "OPTIONS {} HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\
+                     Access-Control-Request-Method: DELETE\r\n\
+                     Access-Control-Request-Headers: x-token\r\n\r\n",
+                    uri
+                )
+                .as_bytes(),
+            );
+
+            assert!(wire.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", wire);
+            assert!(wire.contains("access-control-allow-origin: https://app.example.com\r\n"));
+            assert!(wire.contains("access-control-allow-methods: DELETE\r\n"));
+            assert!(wire.contains("access-control-allow-headers: x-token\r\n"));
+            assert!(wire.contains("access-control-allow-credentials: true\r\n"));
+            assert!(wire.contains("access-control-max-age: 600\r\n"));
+            assert!(wire.contains("vary: Origin\r\n"));
+        }
+
+        // the disallowed origins, methods and headers get no CORS headers
+        for (origin, method, headers) in [
+            ("https://evil.example.com", "GET", ""),
+            ("https://app.example.com", "PUT", ""),
+            ("https://app.example.com", "GET", "x-token, x-other"),
+        ]
+        .iter()
+        {
+            let wire = serve_pipeline(
+                format!(
+                    "OPTIONS /ping HTTP/1.1\r\nHost: localhost\r\nOrigin: {}\r\n\
+                     Access-Control-Request-Method: {}\r\n\
+                     Access-Control-Request-Headers: {}\r\n\r\n",
+                    origin, method, headers
+                )
+                .as_bytes(),
+            );
+
+            assert!(wire.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", wire);
+            assert!(!wire.contains("access-control-"), "{}", wire);
+        }
+
+        // the actual requests from the allowed origins get the CORS headers
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\nOrigin: https://evil.example.com\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+        assert_eq!(
+            wire.matches("access-control-allow-origin: https://app.example.com\r\n")
+                .count(),
+            1
+        );
+        assert_eq!(
+            wire.matches("access-control-expose-headers: X-Total\r\n")
+                .count(),
+            1
+        );
+        assert_eq!(wire.matches("vary: Origin\r\n").count(), 1);
+
+        // the policy can be lifted
+        let _lifted = set_test_cors(None);
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\r\n",
+        );
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!wire.contains("access-control-"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        setup_routes();
+
+        let (request, handler, _) =
+            parse_request_sync("HEAD /ping HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(request.method == REST::HEAD);
+        assert!(handler.is_some());
+
+        let get = round_trip("GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let head = round_trip("HEAD /ping HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        assert!(get.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get.ends_with("\r\n\r\npong"));
+
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Length: 4\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn head_to_missing_route() {
+        let wire = serve_pipeline(
+            b"HEAD /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(wire.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", wire);
+        assert!(wire.ends_with("\r\n\r\n"), "{}", wire);
+        assert!(!wire.contains("<html"), "{}", wire);
+
+        // the page isn't built, so no length is declared for it
+        assert!(!wire.contains("Content-Length"), "{}", wire);
+    }
+
+    #[test]
+    fn https_redirect() {
+        let targets: Vec<String> = [
+            &b"GET /docs/a?b=1&c=2 HTTP/1.1\r\nHost: x\r\n\r\n"[..],
+            b"GET http://example.com/docs?q HTTP/1.1\r\n\r\n",
+            b"GET HTTP://example.com?q HTTP/1.1\r\n\r\n",
+            b"GET http://example.com HTTP/1.1\r\n\r\n",
+            b"OPTIONS * HTTP/1.1\r\n\r\n",
+            b"GET\r\n\r\n",
+        ]
+        .iter()
+        .map(|head| redirect_target(head))
+        .collect();
+
+        assert_eq!(
+            targets,
+            vec!["/docs/a?b=1&c=2", "/docs?q", "/?q", "/", "/", "/"]
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = listener.accept().unwrap().0;
+
+        client
+            .write_all(b"POST /login?next=%2Fhome HTTP/1.1\r\nHost: localhost:8080\r\n\r\n")
+            .unwrap();
+        send_https_redirect(server, "example.com:8443");
+
+        let mut wire = String::new();
+        client.read_to_string(&mut wire).unwrap();
+
+        assert!(
+            wire.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+            "{}",
+            wire
+        );
+        assert!(
+            wire.contains("Location: https://example.com:8443/login?next=%2Fhome\r\n"),
+            "{}",
+            wire
+        );
+
+        // a client trickling the head in is cut off at the deadline, not a timeout per read
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = listener.accept().unwrap().0;
+
+        let drip = thread::spawn(move || {
+            for byte in b"GET /slow HTTP/1.1\r\n".iter() {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let start = Instant::now();
+        let head = read_redirect_head(&mut server, start + Duration::from_millis(300));
+
+        assert!(start.elapsed() < Duration::from_millis(600));
+        assert!(!head.is_empty() && head.len() < 10, "{:?}", head);
+
+        drop(server);
+        drip.join().unwrap();
+    }
+
+    fn asset_path() -> PathBuf {
+        env::temp_dir().join(format!("rex-head-{}.txt", std::process::id()))
+    }
+
+    fn asset(_req: &Box<Request>, resp: &mut Box<Response>) {
+        let status = resp.send_file_from_path(asset_path());
+        resp.status(status);
+    }
+
+    fn asset_async(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.send_file_from_path_async(asset_path());
+    }
+
+    fn no_content(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.status(204);
+    }
+
+    /// Tell if the handler knows that the body won't be sent.
+    fn body_wanted(_req: &Box<Request>, resp: &mut Box<Response>) {
+        let wanted = if resp.is_header_only() { "no" } else { "yes" };
+        resp.set_header("X-Body-Wanted", wanted);
+        resp.send("body");
+    }
+
+    #[test]
+    fn head_declares_get_length() {
+        setup_routes();
+        fs::write(asset_path(), "x".repeat(3000)).unwrap();
+
+        for (uri, handler) in [
+            ("/head/asset", asset as Callback),
+            ("/head/asset-async", asset_async),
+            ("/head/none", no_content),
+        ]
+        .iter()
+        {
+            Route::add_route(
+                REST::GET,
+                RequestPath::Explicit(uri),
+                RouteHandler::new(Some(*handler), None),
+            );
+        }
+
+        let length = |wire: &str| {
+            wire.split("\r\n")
+                .find(|line| line.starts_with("Content-Length: "))
+                .map(|line| line["Content-Length: ".len()..].to_owned())
+        };
+
+        for uri in ["/ping", "/head/asset", "/head/asset-async"].iter() {
+            let get = round_trip(&format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", uri));
+            let head = round_trip(&format!("HEAD {} HTTP/1.1\r\nHost: localhost\r\n\r\n", uri));
+
+            assert!(get.starts_with("HTTP/1.1 200 OK\r\n"), "{}", get);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+            assert!(length(&get).is_some(), "{}", get);
+            assert_eq!(length(&get), length(&head), "{}", uri);
+            assert!(head.ends_with("\r\n\r\n"), "{}", head);
+        }
+
+        assert_eq!(
+            length(&round_trip(
+                "GET /head/asset HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            ))
+            .as_deref(),
+            Some("3000")
+        );
+
+        // no body, and no length to declare
+        let head = round_trip("HEAD /head/none HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", head);
+        assert!(length(&head).is_none(), "{}", head);
+
+        // the handler knows it's serving a HEAD request, over either path
+        Route::add_route(
+            REST::GET,
+            RequestPath::Explicit("/head/wanted"),
+            RouteHandler::new(Some(body_wanted), None),
+        );
+
+        let get = round_trip("GET /head/wanted HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(get.contains("x-body-wanted: yes\r\n"), "{}", get);
+
+        let head = round_trip("HEAD /head/wanted HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(head.contains("x-body-wanted: no\r\n"), "{}", head);
+        assert_eq!(length(&head).as_deref(), Some("4"), "{}", head);
+
+        let mock = MockStream::new(0);
+        mock.feed(b"HEAD /head/wanted HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let wire = Arc::clone(&mock.wire);
+        async_handler::handle_connection(Stream::Mock(mock), ConnLimits::default());
+
+        let head = String::from_utf8_lossy(&wire.lock().unwrap()).into_owned();
+        assert!(head.contains("x-body-wanted: no\r\n"), "{}", head);
+        assert_eq!(length(&head).as_deref(), Some("4"), "{}", head);
+        assert!(head.ends_with("\r\n\r\n"), "{}", head);
+
+        fs::remove_file(asset_path()).unwrap_or_default();
+    }
+
+    struct Visitor(String);
+
+    /// Report the scoped entry left over by an earlier request, if any, then leave one behind.
+    fn scoped(req: &Box<Request>, resp: &mut Box<Response>) {
+        match context::take_scoped::<Visitor>() {
+            Some(Visitor(name)) => resp.send(&format!("leaked:{}", name)),
+            None => resp.send("clean"),
+        }
+
+        context::set_scoped(Visitor(req.param("name").unwrap_or_default()));
+    }
+
+    #[test]
+    fn scoped_context_isolation() {
+        setup_routes();
+        Route::add_route(
+            REST::GET,
+            RequestPath::ExplicitWithParams("/scoped/:name"),
+            RouteHandler::new(Some(scoped), None),
+        );
+
+        // both requests are served by this thread, one after the other
+        let first = round_trip("GET /scoped/alice HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let second = round_trip("GET /scoped/bob HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        assert!(first.ends_with("\r\n\r\nclean"), "{}", first);
+        assert!(second.ends_with("\r\n\r\nclean"), "{}", second);
+
+        // and nothing outlives the request
+        assert!(context::take_scoped::<Visitor>().is_none());
+
+        // while the entries are there within the same scope
+        context::set_scoped(Visitor(String::from("carol")));
+        context::set_scoped(Visitor(String::from("dave")));
+        assert_eq!(
+            context::take_scoped::<Visitor>().map(|v| v.0).as_deref(),
+            Some("dave")
+        );
+        assert!(context::take_scoped::<Visitor>().is_none());
+    }
+
+    #[test]
+    fn bare_lf_line_ends() {
+        let lenient = ConnLimits::default();
+        let strict = ConnLimits {
+            line_endings: LineEndings::Strict,
+            ..Default::default()
+        };
+
+        let lf_only: &[u8] = b"GET /ping HTTP/1.1\nHost: localhost\nConnection: Keep-Alive\n\n";
+        let wire = serve_limited(lf_only, lenient);
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+        assert!(wire.ends_with("\r\n\r\npong"), "{}", wire);
+
+        let wire = serve_limited(lf_only, strict);
+        assert!(wire.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", wire);
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1, "{}", wire);
+
+        // the body starts right after the empty line, whichever the line ends are
+        let mixed: &[u8] = b"POST /mirror HTTP/1.1\r\nHost: localhost\nConnection: Keep-Alive\r\n\
+              Content-Length: 5\n\r\nhello\
+              GET /ping HTTP/1.1\nHost: localhost\r\n\r\n";
+        let wire = serve_limited(mixed, lenient);
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2, "{}", wire);
+        assert!(wire.contains("Content-Length: 5\r\n"), "{}", wire);
+        assert!(
+            wire.contains("\r\n\r\nhelloHTTP/1.1 200 OK\r\n"),
+            "{}",
+            wire
+        );
+        assert!(wire.ends_with("\r\n\r\npong"), "{}", wire);
+
+        let wire = serve_limited(mixed, strict);
+        assert!(wire.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", wire);
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1, "{}", wire);
+    }
+
+    fn nap(req: &Box<Request>, resp: &mut Box<Response>) {
+        let millis = req.param("millis").unwrap_or_default().parse().unwrap_or(0);
+        thread::sleep(Duration::from_millis(millis));
+        resp.send("awake");
+    }
+
+    #[test]
+    fn handler_timeout() {
+        let _timed = TIMED_HANDLERS.read();
+        setup_routes();
+
+        let mut handler = RouteHandler::new(Some(nap), None);
+        handler.set_options(RouteOptions::new().handler_timeout(Duration::from_millis(200)));
+        Route::add_route(
+            REST::GET,
+            RequestPath::ExplicitWithParams("/nap/:millis"),
+            handler,
+        );
+
+        let wire = round_trip("GET /nap/0 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+        assert!(wire.ends_with("\r\n\r\nawake"), "{}", wire);
+
+        // the client won't wait for the handler to wake up
+        let start = Instant::now();
+        let wire = round_trip("GET /nap/2000 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(start.elapsed() < Duration::from_millis(1500));
+        assert!(
+            wire.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            wire
+        );
+        assert!(wire.contains("Connection: close\r\n"), "{}", wire);
+        assert!(!wire.contains("awake"), "{}", wire);
+    }
+
+    #[test]
+    fn abandoned_handlers() {
+        let _timed = TIMED_HANDLERS.write();
+        setup_routes();
+
+        let mut handler = RouteHandler::new(Some(nap), None);
+        handler.set_options(RouteOptions::new().handler_timeout(Duration::from_millis(50)));
+        Route::add_route(
+            REST::GET,
+            RequestPath::ExplicitWithParams("/abandoned/:millis"),
+            handler,
+        );
+
+        // the overrunning handler is counted until it's done
+        let wire = round_trip("GET /abandoned/300 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(wire.starts_with("HTTP/1.1 503"), "{}", wire);
+        assert_eq!(ABANDONED.load(Ordering::SeqCst), 1);
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while ABANDONED.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(ABANDONED.load(Ordering::SeqCst), 0);
+
+        // once too many of them are left running, the timed routes are refused up front
+        ABANDONED.store(ABANDONED_CAP, Ordering::SeqCst);
+        let wire = round_trip("GET /abandoned/0 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        ABANDONED.store(0, Ordering::SeqCst);
+        assert!(wire.starts_with("HTTP/1.1 503"), "{}", wire);
+
+        let wire = round_trip("GET /abandoned/0 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(wire.ends_with("\r\n\r\nawake"), "{}", wire);
+    }
+
+    #[test]
+    #[cfg(feature = "logger")]
+    fn access_logged_on_every_exit() {
+        use crate::support::logger::ACCESS_TAP;
+
+        let _timed = TIMED_HANDLERS.read();
+        setup_routes();
+
+        let mut handler = RouteHandler::new(Some(nap), None);
+        handler.set_options(RouteOptions::new().handler_timeout(Duration::from_millis(100)));
+        Route::add_route(
+            REST::GET,
+            RequestPath::ExplicitWithParams("/logged/nap/:millis"),
+            handler,
+        );
+
+        let logged = |uri: &str| {
+            ACCESS_TAP
+                .read()
+                .iter()
+                .filter(|(logged, _)| logged == uri)
+                .map(|(_, status)| *status)
+                .collect::<Vec<u16>>()
+        };
+
+        // the overrun handlers and the refused requests are logged over both paths
+        let wire = round_trip("GET /logged/nap/1000 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(wire.starts_with("HTTP/1.1 503"), "{}", wire);
+        assert_eq!(logged("/logged/nap/1000"), [503]);
+
+        let wire = serve_pipeline(
+            b"GET /logged/missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert!(wire.starts_with("HTTP/1.1 404"), "{}", wire);
+        assert_eq!(logged("/logged/missing"), [404]);
+
+        for raw in [
+            &b"GET /logged/nap/1000 HTTP/1.1\r\nHost: localhost\r\n\r\n"[..],
+            b"GET /logged/missing HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ]
+        .iter()
+        {
+            let mock = MockStream::new(0);
+            mock.feed(raw);
+            async_handler::handle_connection(Stream::Mock(mock), ConnLimits::default());
+        }
+
+        assert_eq!(logged("/logged/nap/1000"), [503, 503]);
+        assert_eq!(logged("/logged/missing"), [404, 404]);
+    }
+
+    fn no_content_body(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.status(204);
+        resp.send("dropped");
+    }
+
+    #[test]
+    fn audit_logged_on_both_paths() {
+        setup_routes();
+        Route::add_route(
+            REST::GET,
+            RequestPath::Explicit("/audited"),
+            RouteHandler::new(Some(no_content_body), None),
+        );
+
+        let overridden = |printed: &[String]| {
+            printed
+                .iter()
+                .filter(|line| line.starts_with("Response to /audited is overridden: "))
+                .count()
+        };
+
+        // the body sent along with a 204 is dropped
+        let printed = debug::capture(DebugLevel::Debug, || {
+            let wire = round_trip("GET /audited HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            assert!(wire.starts_with("HTTP/1.1 204"), "{}", wire);
+            assert!(!wire.contains("dropped"), "{}", wire);
+        });
+        assert_eq!(overridden(&printed), 1, "{:?}", printed);
+
+        let printed = debug::capture(DebugLevel::Debug, || {
+            let mock = MockStream::new(0);
+            mock.feed(b"GET /audited HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            async_handler::handle_connection(Stream::Mock(mock), ConnLimits::default());
+        });
+        assert_eq!(overridden(&printed), 1, "{:?}", printed);
+
+        // the messages under the debug level are dropped
+        let printed = debug::capture(DebugLevel::Warning, || {
+            round_trip("GET /audited HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        });
+        assert_eq!(overridden(&printed), 0, "{:?}", printed);
+    }
+
+    #[test]
+    fn denied_small_body_is_skipped() {
+        let wire = serve_pipeline(
+            b"POST /private HTTP/1.1\r\nHost: localhost\r\nConnection: Keep-Alive\r\n\
+              Content-Length: 5\r\n\r\nhello\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+
+        assert!(wire.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 2);
+        assert!(wire.contains("HTTP/1.1 200 OK\r\n"));
+        assert!(wire.ends_with("\r\n\r\npong"));
+    }
+
+    #[test]
+    fn denied_large_body_closes() {
+        let mut raw = Vec::from(
+            &b"POST /private HTTP/1.1\r\nHost: localhost\r\nConnection: Keep-Alive\r\n\
+              Content-Length: 1048576\r\n\r\n"[..],
+        );
+        raw.extend_from_slice(&[b'x'; 1024]);
+        raw.extend_from_slice(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        let wire = serve_pipeline(&raw);
+
+        assert!(wire.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert_eq!(wire.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[test]
+    fn denied_before_body_read() {
+        let _pool = CONN_POOL_USERS.read();
+        setup_routes();
+
+        let head = b"POST /private HTTP/1.1\r\nHost: localhost\r\nConnection: Keep-Alive\r\n\
+                     Content-Length: 1048576\r\n\r\n";
+        let body = vec![b'x'; 1_048_576];
+        let mut joined = head.to_vec();
+        joined.extend_from_slice(&body);
+
+        // serve the request arriving in the trunks over either path, and return the wire and the
+        // number of the bytes read from the stream
+        let serve = |trunks: &[&[u8]], pipelined: bool| {
+            let mock = MockStream::new(0);
+            for trunk in trunks {
+                mock.feed(trunk);
+            }
+
+            let wire = Arc::clone(&mock.wire);
+            let read = Arc::clone(&mock.read_bytes);
+
+            if pipelined {
+                Stream::Mock(mock).process(false, 0, ConnLimits::default());
+            } else {
+                async_handler::handle_connection(Stream::Mock(mock), ConnLimits::default());
+            }
+
+            let wire = String::from_utf8_lossy(&wire.lock().unwrap()).into_owned();
+            (wire, read.load(Ordering::SeqCst))
+        };
+
+        for pipelined in [true, false].iter() {
+            // the body following the head is never read
+            let (wire, read) = serve(&[&head[..], &body], *pipelined);
+            assert!(
+                wire.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+                "{}",
+                wire
+            );
+            assert_eq!(read, head.len());
+
+            // nor beyond the read completing the head, if the body arrives along
+            let (wire, read) = serve(&[&joined], *pipelined);
+            assert!(
+                wire.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+                "{}",
+                wire
+            );
+            assert_eq!(read, BUFFER_SIZE);
+        }
+    }
+
+    /// Render the template of the scope with a marker engine, such that the wire tells whether the
+    /// scoped root and engine were used.
+    fn scoped_page(_req: &Box<Request>, resp: &mut Box<Response>) {
+        let status = resp.send_template("page.html", Box::new(Title));
+        resp.status(status);
+    }
+
+    struct Title;
+
+    impl EngineContext for Title {
+        fn display(&self, _field: &str) -> Result<String, String> {
+            Ok(String::from("scoped"))
+        }
+    }
+
+    fn marker(
+        content: &mut String,
+        _path: &Path,
+        context: Box<dyn EngineContext + Send + Sync>,
+    ) -> u16 {
+        *content = content.replace("%title%", &context.display("title").unwrap());
+        200
+    }
+
+    #[test]
+    fn views_scoped_on_both_paths() {
+        setup_routes();
+
+        let root = env::temp_dir().join(format!("rex-conn-views-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("page.html"), "<h1>%title%</h1>").unwrap();
+
+        ServerConfig::view_engine("convw", marker);
+        Route::set_views(
+            "/scoped",
+            ViewsScope {
+                root: root.clone(),
+                default_engine_ext: Some(String::from("convw")),
+            },
+        );
+        Route::add_route(
+            REST::GET,
+            RequestPath::Explicit("/scoped/page"),
+            RouteHandler::new(Some(scoped_page), None),
+        );
+
+        // the async path serves every TLS connection
+        let mock = MockStream::new(0);
+        mock.feed(b"GET /scoped/page HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let wire = Arc::clone(&mock.wire);
+        async_handler::handle_connection(Stream::Mock(mock), ConnLimits::default());
+        let tls = String::from_utf8_lossy(&wire.lock().unwrap()).into_owned();
+
+        let pipelined = serve_pipeline(
+            b"GET /scoped/page HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+
+        for wire in [tls, pipelined].iter() {
+            assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+            assert!(wire.ends_with("\r\n\r\n<h1>scoped</h1>"), "{}", wire);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn conflict(_req: &Box<Request>, resp: &mut Box<Response>) {
+        resp.send("taken");
+        resp.status(409);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_observed_on_both_paths() {
+        use crate::support::metrics;
+
+        setup_routes();
+        Route::add_route(
+            REST::PUT,
+            RequestPath::Explicit("/conflict"),
+            RouteHandler::new(Some(conflict), None),
+        );
+
+        let served = || {
+            let line = "http_requests_total{method=\"PUT\",status=\"409\"} ";
+            metrics::render()
+                .lines()
+                .find(|l| l.starts_with(line))
+                .map_or(0, |l| l[line.len()..].parse::<usize>().unwrap())
+        };
+
+        let before = served();
+
+        let mock = MockStream::new(0);
+        mock.feed(b"PUT /conflict HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+        async_handler::handle_connection(Stream::Mock(mock), ConnLimits::default());
+        assert_eq!(served(), before + 1);
+
+        serve_pipeline(
+            b"PUT /conflict HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\
+              Connection: close\r\n\r\n",
+        );
+        assert_eq!(served(), before + 2);
+    }
+
+    #[test]
+    fn pipelined_http_1_1() {
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n\
+              GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+    }
+
+    #[test]
+    fn pipelined_http_1_0() {
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n\
+              GET /ping HTTP/1.0\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+
+        let wire = serve_pipeline(
+            b"GET /ping HTTP/1.0\r\nHost: localhost\r\n\r\n\
+              GET /ping HTTP/1.0\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(wire.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+    }
+}
@@ -1,7 +1,20 @@
+//! The context holds the state the handlers can share, in two kinds:
+//!
+//! - The server context, set with `set_context`, is the long-lived `ContextProvider` object shared
+//!   by all requests, and lives as long as the server does.
+//! - The scoped entries, set with `set_scoped` and taken with `take_scoped`, belong to the request
+//!   being handled on the current thread. They are cleared before the route handler is invoked and
+//!   again after the response has been generated, so no entry is visible to another request, even
+//!   if the same worker thread serves it next.
+
 #![allow(dead_code)]
 #![allow(clippy::borrowed_box)]
 
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+
 use crate::core::http::{Request, Response};
+use crate::hashbrown::HashMap;
 use crate::parking_lot::RwLock;
 
 const ERR_STR: &str = "The context has not been initialized...";
@@ -9,6 +22,10 @@ static mut CONTEXT: Option<RwLock<Box<ServerContextProvider>>> = None;
 
 pub type ServerContextProvider = dyn ContextProvider + Sync + Send;
 
+thread_local! {
+    static SCOPED: RefCell<HashMap<TypeId, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
 pub trait ContextProvider {
     fn update(&mut self, req: &Box<Request>, resp: &mut Box<Response>) -> Result<(), &'static str>;
     fn process(&self, req: &Box<Request>, resp: &mut Box<Response>) -> Result<(), &'static str>;
@@ -60,3 +77,49 @@ pub fn process_with_context(
 
     Err(ERR_STR)
 }
+
+/// Set the scoped entry of type `T` for the request being handled, which replaces the entry of the
+/// same type set earlier in the request. Each type holds one entry, so wrap the plain types, e.g.
+/// `struct Locale(String)`, to avoid the clashes. The entry is dropped once the response to the
+/// request has been generated, unless it has been taken by then.
+pub fn set_scoped<T: Any>(val: T) {
+    SCOPED.with(|scoped| {
+        scoped.borrow_mut().insert(TypeId::of::<T>(), Box::new(val));
+    });
+}
+
+/// Take the scoped entry of type `T` out of the request being handled, or `None` if there's no
+/// such entry.
+pub fn take_scoped<T: Any>() -> Option<T> {
+    SCOPED.with(|scoped| {
+        scoped
+            .borrow_mut()
+            .remove(&TypeId::of::<T>())
+            .and_then(|entry| entry.downcast::<T>().ok())
+            .map(|entry| *entry)
+    })
+}
+
+/// Start the scope of a new request on the current thread, dropping whatever is left behind.
+pub(crate) fn begin_request() {
+    clear_scoped();
+}
+
+/// End the scope of the request on the current thread.
+pub(crate) fn end_request() {
+    clear_scoped();
+}
+
+fn clear_scoped() {
+    // the entries are dropped out of the borrow, in case their destructors touch the scope
+    let entries = SCOPED.with(|scoped| {
+        let mut scoped = scoped.borrow_mut();
+        if scoped.is_empty() {
+            None
+        } else {
+            Some(std::mem::replace(&mut *scoped, HashMap::new()))
+        }
+    });
+
+    drop(entries);
+}
@@ -8,6 +8,66 @@ pub enum KeyPrefix {
     Host,
 }
 
+/// The `SameSite` attribute of the cookie, which tells the browser if the cookie shall be sent
+/// along with the cross-site requests. Note that `SameSite::None` requires the cookie to be
+/// `Secure`, and the attribute will be added to such cookies automatically.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn parse(value: &str) -> Option<SameSite> {
+        match &value.trim().to_lowercase()[..] {
+            "strict" => Some(SameSite::Strict),
+            "lax" => Some(SameSite::Lax),
+            "none" => Some(SameSite::None),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// The defaults and the checks applied to every cookie the server sends, see
+/// `ServerConfig::cookie_policy`.
+#[derive(Clone, Default, Debug)]
+pub struct CookiePolicy {
+    /// The `SameSite` attribute given to the cookies that don't set one.
+    pub default_same_site: Option<SameSite>,
+    /// Mark all cookies sent over TLS as `Secure`.
+    pub force_secure_on_tls: bool,
+    /// Log a warning for the cookies set on a cross-site request without `SameSite=None; Secure`,
+    /// which the browsers won't store.
+    pub warn_on_cross_site_without_none: bool,
+}
+
+impl CookiePolicy {
+    /// Apply the defaults to the cookie, and return `true` if the cookie deserves the cross-site
+    /// warning.
+    pub(crate) fn apply(&self, cookie: &mut Cookie, is_tls: bool, cross_site: bool) -> bool {
+        if cookie.same_site.is_none() && self.default_same_site.is_some() {
+            cookie.set_same_site(self.default_same_site);
+        }
+
+        if is_tls && self.force_secure_on_tls {
+            cookie.set_secure_attr(true);
+        }
+
+        self.warn_on_cross_site_without_none
+            && cross_site
+            && !(cookie.secure && cookie.same_site == Some(SameSite::None))
+    }
+}
+
 pub struct Cookie {
     key: String,
     value: String,
@@ -18,6 +78,7 @@ pub struct Cookie {
     path: String,
     secure: bool,
     http_only: bool,
+    same_site: Option<SameSite>,
 }
 
 impl Cookie {
@@ -32,9 +93,81 @@ impl Cookie {
             path: String::new(),
             secure: false,
             http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Start building a cookie with the fluent builder, e.g.
+    ///
+    /// ```
+    /// use rusty_express::prelude::*;
+    ///
+    /// let cookie = Cookie::build("sid", "abc")
+    ///     .path("/")
+    ///     .http_only(true)
+    ///     .same_site(SameSite::Lax)
+    ///     .max_age(3600)
+    ///     .finish();
+    ///
+    /// assert_eq!(
+    ///     cookie.to_string(),
+    ///     "sid=abc; Max-Age=3600; Path=/; HttpOnly; SameSite=Lax;"
+    /// );
+    /// ```
+    pub fn build(key: &str, value: &str) -> CookieBuilder {
+        CookieBuilder {
+            cookie: Cookie::new(key, value),
+            key_prefix: None,
         }
     }
 
+    /// Parse the cookie from the value of a `Set-Cookie` header, where the unknown attributes are
+    /// ignored. Returns `None` if the cookie doesn't have a name or a value.
+    pub fn parse(source: &str) -> Option<Cookie> {
+        let mut attrs = source.split(';');
+        let mut pair = attrs.next()?.splitn(2, '=');
+
+        let (key, value) = (pair.next()?.trim(), pair.next()?.trim());
+        if key.is_empty() || value.is_empty() {
+            return None;
+        }
+
+        let mut cookie = if let Some(name) = key.strip_prefix("__Secure-") {
+            let mut cookie = Cookie::new(name, value);
+            cookie.set_key_prefix(Some(KeyPrefix::Secure));
+            cookie
+        } else if let Some(name) = key.strip_prefix("__Host-") {
+            let mut cookie = Cookie::new(name, value);
+            cookie.set_key_prefix(Some(KeyPrefix::Host));
+            cookie
+        } else {
+            Cookie::new(key, value)
+        };
+
+        for attr in attrs {
+            let mut field = attr.splitn(2, '=');
+            let name = field.next().unwrap_or_default().trim().to_lowercase();
+            let val = field.next().unwrap_or_default().trim();
+
+            match &name[..] {
+                "expires" => {
+                    if let Ok(time) = Utc.datetime_from_str(val, "%a, %e %b %Y %T GMT") {
+                        cookie.set_expires(Some(SystemTime::from(time)));
+                    }
+                }
+                "max-age" => cookie.set_max_age(val.parse().ok()),
+                "domain" => cookie.set_domain(val),
+                "path" if val.starts_with('/') => cookie.set_path(val),
+                "secure" => cookie.set_secure_attr(true),
+                "httponly" => cookie.set_http_only_attr(true),
+                "samesite" => cookie.set_same_site(SameSite::parse(val)),
+                _ => { /* Unknown attributes */ }
+            }
+        }
+
+        Some(cookie)
+    }
+
     pub fn set_key_prefix(&mut self, prefix: Option<KeyPrefix>) {
         self.key_prefix = match prefix {
             Some(KeyPrefix::Secure) => {
@@ -66,7 +199,7 @@ impl Cookie {
 
     pub fn set_path(&mut self, path: &str) {
         self.path = match self.key_prefix {
-            Some(KeyPrefix::Host) => String::new(),
+            Some(KeyPrefix::Host) => String::from("/"),
             _ if path.is_empty() => String::new(),
             _ => {
                 if path.starts_with('/') {
@@ -88,6 +221,7 @@ impl Cookie {
     pub fn set_secure_attr(&mut self, is_secure: bool) {
         self.secure = match self.key_prefix {
             Some(KeyPrefix::Host) | Some(KeyPrefix::Secure) => true,
+            _ if self.same_site == Some(SameSite::None) => true,
             _ => is_secure,
         };
     }
@@ -96,6 +230,16 @@ impl Cookie {
         self.http_only = http_only;
     }
 
+    /// Set the `SameSite` attribute, where `SameSite::None` will also mark the cookie as `Secure`,
+    /// since browsers reject such cookies otherwise.
+    pub fn set_same_site(&mut self, same_site: Option<SameSite>) {
+        if same_site == Some(SameSite::None) {
+            self.secure = true;
+        }
+
+        self.same_site = same_site;
+    }
+
     pub fn update_session_key(&mut self, key: &str) {
         if key.is_empty() {
             panic!("Session key must have a value!");
@@ -111,7 +255,9 @@ impl Cookie {
     }
 
     pub fn is_valid(&self) -> bool {
-        (!self.key.is_empty()) && (!self.value.is_empty())
+        (!self.key.is_empty())
+            && (!self.value.is_empty())
+            && (self.secure || self.same_site != Some(SameSite::None))
     }
 
     pub fn get_cookie_key(&self) -> String {
@@ -121,6 +267,79 @@ impl Cookie {
     pub fn get_cookie_value(&self) -> String {
         self.value.to_owned()
     }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn get_same_site(&self) -> Option<SameSite> {
+        self.same_site
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+}
+
+/// The fluent builder of the `Cookie`, created by `Cookie::build`. The key prefix is applied at
+/// last, such that its restrictions on the other attributes always hold.
+pub struct CookieBuilder {
+    cookie: Cookie,
+    key_prefix: Option<KeyPrefix>,
+}
+
+impl CookieBuilder {
+    pub fn key_prefix(mut self, prefix: KeyPrefix) -> Self {
+        self.key_prefix = Some(prefix);
+        self
+    }
+
+    pub fn expires(mut self, expires_at: SystemTime) -> Self {
+        self.cookie.set_expires(Some(expires_at));
+        self
+    }
+
+    pub fn max_age(mut self, max_age: u32) -> Self {
+        self.cookie.set_max_age(Some(max_age));
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.cookie.set_domain(domain);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.cookie.set_path(path);
+        self
+    }
+
+    pub fn secure(mut self, is_secure: bool) -> Self {
+        self.cookie.set_secure_attr(is_secure);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.cookie.set_http_only_attr(http_only);
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.cookie.set_same_site(Some(same_site));
+        self
+    }
+
+    pub fn finish(mut self) -> Cookie {
+        if self.key_prefix.is_some() {
+            self.cookie.set_key_prefix(self.key_prefix.take());
+        }
+
+        self.cookie
+    }
 }
 
 impl ToString for Cookie {
@@ -184,6 +403,12 @@ impl ToString for Cookie {
             cookie.push_str(" HttpOnly;");
         }
 
+        if let Some(same_site) = self.same_site {
+            cookie.push_str(" SameSite=");
+            cookie.push_str(same_site.as_str());
+            cookie.push(';');
+        }
+
         cookie
     }
 }
@@ -200,6 +425,7 @@ impl Clone for Cookie {
             path: self.path.clone(),
             secure: self.secure,
             http_only: self.http_only,
+            same_site: self.same_site,
         }
     }
 }
@@ -220,3 +446,146 @@ fn system_to_utc(t: SystemTime) -> DateTime<Utc> {
 
     Utc.timestamp(sec, n_sec)
 }
+
+#[cfg(test)]
+mod cookie_test {
+    use super::{Cookie, CookiePolicy, KeyPrefix, SameSite};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn round_trip(cookie: &Cookie) -> Cookie {
+        let header = cookie.to_string();
+        Cookie::parse(&header).unwrap_or_else(|| panic!("Failed to parse: {}", header))
+    }
+
+    #[test]
+    fn builder_round_trip() {
+        // the header only carries the time in seconds
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let expires = UNIX_EPOCH + Duration::from_secs(now.as_secs() + 5 * 86400);
+
+        let cookie = Cookie::build("sid", "abc")
+            .path("/app")
+            .domain("example.com")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(3600)
+            .expires(expires)
+            .finish();
+
+        assert!(cookie.is_valid());
+        let parsed = round_trip(&cookie);
+
+        assert_eq!(parsed.key, "sid");
+        assert_eq!(parsed.value, "abc");
+        assert_eq!(parsed.path, "/app");
+        assert_eq!(parsed.domain, "example.com");
+        assert_eq!(parsed.max_age, Some(3600));
+        assert_eq!(parsed.expires, Some(expires));
+        assert!(parsed.http_only);
+        assert!(!parsed.secure);
+        assert_eq!(parsed.get_same_site(), Some(SameSite::Lax));
+        assert_eq!(parsed.to_string(), cookie.to_string());
+
+        for same_site in [SameSite::Strict, SameSite::Lax, SameSite::None].iter() {
+            let cookie = Cookie::build("k", "v").same_site(*same_site).finish();
+            assert_eq!(round_trip(&cookie).get_same_site(), Some(*same_site));
+        }
+    }
+
+    #[test]
+    fn same_site_none_requires_secure() {
+        let cookie = Cookie::build("sid", "abc")
+            .same_site(SameSite::None)
+            .secure(false)
+            .finish();
+
+        assert!(cookie.is_valid());
+        assert_eq!(cookie.to_string(), "sid=abc; Secure; SameSite=None;");
+
+        let mut cookie = Cookie::new("sid", "abc");
+        cookie.same_site = Some(SameSite::None);
+        assert!(!cookie.is_valid());
+
+        let parsed = Cookie::parse("sid=abc; samesite=none").unwrap();
+        assert!(parsed.secure);
+        assert_eq!(parsed.get_same_site(), Some(SameSite::None));
+    }
+
+    #[test]
+    fn prefixed_round_trip() {
+        let cookie = Cookie::build("sid", "abc")
+            .domain("example.com")
+            .path("/app")
+            .key_prefix(KeyPrefix::Host)
+            .finish();
+
+        assert_eq!(cookie.to_string(), "__Host-sid=abc; Path=/; Secure;");
+
+        let parsed = round_trip(&cookie);
+        assert_eq!(parsed.key, "sid");
+        assert!(parsed.key_prefix == Some(KeyPrefix::Host));
+        assert_eq!(parsed.to_string(), cookie.to_string());
+
+        assert!(Cookie::parse("=abc; Path=/").is_none());
+        assert!(Cookie::parse("sid").is_none());
+        assert!(Cookie::parse("sid=abc; Priority=High").is_some());
+    }
+
+    #[test]
+    fn policy_defaults() {
+        let policy = CookiePolicy {
+            default_same_site: Some(SameSite::Lax),
+            force_secure_on_tls: true,
+            warn_on_cross_site_without_none: false,
+        };
+
+        // only the cookies without the attribute get the default
+        let mut plain = Cookie::new("sid", "abc");
+        let mut strict = Cookie::build("pref", "dark")
+            .same_site(SameSite::Strict)
+            .finish();
+
+        assert!(!policy.apply(&mut plain, false, false));
+        assert!(!policy.apply(&mut strict, false, false));
+        assert_eq!(plain.to_string(), "sid=abc; SameSite=Lax;");
+        assert_eq!(strict.get_same_site(), Some(SameSite::Strict));
+        assert!(!strict.is_secure());
+
+        // and the ones sent over TLS are all secure
+        let mut plain = Cookie::new("sid", "abc");
+        policy.apply(&mut plain, true, false);
+        assert_eq!(plain.to_string(), "sid=abc; Secure; SameSite=Lax;");
+
+        // the empty policy leaves the cookie as is
+        let mut plain = Cookie::new("sid", "abc");
+        assert!(!CookiePolicy::default().apply(&mut plain, true, true));
+        assert_eq!(plain.to_string(), "sid=abc;");
+    }
+
+    #[test]
+    fn policy_cross_site_warning() {
+        let mut policy = CookiePolicy {
+            warn_on_cross_site_without_none: true,
+            ..Default::default()
+        };
+
+        let none = || {
+            Cookie::build("sid", "abc")
+                .same_site(SameSite::None)
+                .finish()
+        };
+
+        assert!(policy.apply(&mut Cookie::new("sid", "abc"), true, true));
+        assert!(!policy.apply(&mut Cookie::new("sid", "abc"), true, false));
+        assert!(!policy.apply(&mut none(), false, true));
+
+        // the default fixes the cookies that don't set the attribute themselves
+        policy.default_same_site = Some(SameSite::None);
+        assert!(!policy.apply(&mut Cookie::new("sid", "abc"), true, true));
+
+        let mut lax = Cookie::build("sid", "abc")
+            .same_site(SameSite::Lax)
+            .finish();
+        assert!(policy.apply(&mut lax, true, true));
+    }
+}
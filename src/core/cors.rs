@@ -0,0 +1,257 @@
+//! The `cors` module lets the browsers call the server from other origins, following the policy
+//! set with `ServerConfig::set_cors`. Once set, the preflight requests, i.e. the `OPTIONS` requests
+//! asking if a cross-origin request may be sent, are answered by the server on behalf of the
+//! routes, and the responses to the allowed origins carry the `Access-Control-Allow-*` headers.
+//!
+//! ```no_run
+//! use rusty_express::prelude::*;
+//!
+//! let mut server = HttpServer::new();
+//!
+//! let mut policy = CorsPolicy::new(AllowedOrigins::exact(&["https://app.example.com"]));
+//! policy.methods = vec![REST::GET, REST::POST, REST::DELETE];
+//! policy.allow_headers = vec![String::from("Content-Type"), String::from("X-Token")];
+//! policy.allow_credentials = true;
+//! policy.max_age = Some(600);
+//!
+//! server.config().set_cors(policy);
+//! server.listen(8080);
+//! ```
+
+#![allow(clippy::borrowed_box)]
+
+use crate::core::config::ConnMetadata;
+use crate::core::http::{Request, Response, ResponseStates, ResponseWriter};
+use crate::core::router::REST;
+
+/// The origins allowed to make the cross-origin requests.
+#[derive(Clone, Debug)]
+pub enum AllowedOrigins {
+    /// Any origin. The responses then allow `*`, which never goes with the credentials: to allow
+    /// the credentials, list the origins trusted with them.
+    Any,
+    /// Only the listed origins, e.g. `https://app.example.com`, which are compared without the
+    /// case.
+    Exact(Vec<String>),
+}
+
+impl AllowedOrigins {
+    pub fn exact(origins: &[&str]) -> Self {
+        AllowedOrigins::Exact(origins.iter().map(|origin| (*origin).to_owned()).collect())
+    }
+}
+
+/// The rules to decide which cross-origin requests are allowed, and what the browsers may do with
+/// the responses to them. The `allow_headers` are the request headers the client may send, where
+/// `*` allows any header, and the `expose_headers` are the response headers the client may read.
+/// The `max_age` is the number of seconds the browsers may cache the answer to a preflight.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    pub origins: AllowedOrigins,
+    pub methods: Vec<REST>,
+    pub allow_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<u32>,
+}
+
+impl CorsPolicy {
+    /// The policy allowing the origins to send the `GET`, `HEAD` and `POST` requests without any
+    /// extra headers or the credentials.
+    pub fn new(origins: AllowedOrigins) -> Self {
+        CorsPolicy {
+            origins,
+            methods: vec![REST::GET, REST::HEAD, REST::POST],
+            allow_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// The value of the `Access-Control-Allow-Origin` header for the origin, or `None` if the
+    /// origin is not allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        match self.origins {
+            AllowedOrigins::Any => Some(String::from("*")),
+            AllowedOrigins::Exact(ref origins) => origins
+                .iter()
+                .find(|allowed| allowed.eq_ignore_ascii_case(origin))
+                .map(|_| origin.to_owned()),
+        }
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        let method = REST::parse(method.trim());
+        self.methods.contains(&method)
+    }
+
+    /// If the credentials may go with the requests, which is never the case for any origin, since
+    /// echoing back whichever origin asks would share the credentials with all of them.
+    pub(crate) fn shares_credentials(&self) -> bool {
+        match self.origins {
+            AllowedOrigins::Any => false,
+            AllowedOrigins::Exact(_) => self.allow_credentials,
+        }
+    }
+
+    /// Check the comma separated header names from the `Access-Control-Request-Headers` header.
+    fn allows_headers(&self, requested: &str) -> bool {
+        if self.allow_headers.iter().any(|allowed| allowed == "*") {
+            return true;
+        }
+
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.allow_headers
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy::new(AllowedOrigins::Any)
+    }
+}
+
+/// Check if the request is a preflight to be answered by the server, i.e. an `OPTIONS` request
+/// with the `Origin` and the `Access-Control-Request-Method` headers while the policy is set.
+pub(crate) fn is_preflight(request: &Box<Request>) -> bool {
+    request.method == REST::OPTIONS
+        && request.header("origin").is_some()
+        && request.header("access-control-request-method").is_some()
+        && ConnMetadata::get_cors().is_some()
+}
+
+/// Answer the preflight with `204 No Content`, which allows the requested method and headers if
+/// the policy does, or carries no CORS headers otherwise, such that the browser won't send the
+/// actual request.
+pub(crate) fn answer_preflight(request: &Box<Request>, response: &mut Box<Response>) {
+    response.status(204);
+
+    let policy = match ConnMetadata::get_cors() {
+        Some(policy) => policy,
+        None => return,
+    };
+
+    let origin = request.header("origin").unwrap_or_default();
+    let method = request
+        .header("access-control-request-method")
+        .unwrap_or_default();
+    let headers = request
+        .header("access-control-request-headers")
+        .unwrap_or_default();
+
+    let allow_origin = match policy.allow_origin(&origin) {
+        Some(value) if policy.allows_method(&method) && policy.allows_headers(&headers) => value,
+        _ => return,
+    };
+
+    response.header("Access-Control-Allow-Methods", method.trim(), true);
+    response.header("Access-Control-Allow-Headers", headers.trim(), true);
+    if let Some(age) = policy.max_age {
+        response.header("Access-Control-Max-Age", &age.to_string(), true);
+    }
+
+    allow(response, &policy, allow_origin);
+}
+
+/// Add the CORS headers to the response if its request comes from an origin allowed by the
+/// policy, unless the handler has set them itself.
+pub(crate) fn decorate(request: &Box<Request>, response: &mut Box<Response>) {
+    let policy = match ConnMetadata::get_cors() {
+        Some(policy) => policy,
+        None => return,
+    };
+
+    // the handler has its own say, or the preflight has been answered, either allowed or not
+    if response.get_header("access-control-allow-origin").is_some()
+        || (request.method == REST::OPTIONS
+            && request.header("access-control-request-method").is_some())
+    {
+        return;
+    }
+
+    let allow_origin = match request.header("origin") {
+        Some(origin) => match policy.allow_origin(&origin) {
+            Some(value) => value,
+            None => return,
+        },
+        None => return,
+    };
+
+    if !policy.expose_headers.is_empty() {
+        response.header(
+            "Access-Control-Expose-Headers",
+            &policy.expose_headers.join(", "),
+            true,
+        );
+    }
+
+    allow(response, &policy, allow_origin);
+}
+
+fn allow(response: &mut Box<Response>, policy: &CorsPolicy, allow_origin: String) {
+    if policy.shares_credentials() {
+        response.header("Access-Control-Allow-Credentials", "true", true);
+    }
+
+    // the response now varies with the origin of the request, unless any origin gets the same one
+    if allow_origin != "*" {
+        let vary = match response.get_header("vary") {
+            Some(vary) if vary.to_lowercase().contains("origin") => None,
+            Some(vary) => Some(format!("{}, Origin", vary)),
+            None => Some(String::from("Origin")),
+        };
+
+        if let Some(vary) = vary {
+            response.header("Vary", &vary, true);
+        }
+    }
+
+    response.header("Access-Control-Allow-Origin", &allow_origin, true);
+}
+
+#[cfg(test)]
+mod cors_test {
+    use super::{AllowedOrigins, CorsPolicy};
+
+    #[test]
+    fn policy_rules() {
+        let mut policy = CorsPolicy::new(AllowedOrigins::exact(&["https://app.example.com"]));
+        policy.allow_headers = vec![String::from("Content-Type")];
+
+        assert_eq!(
+            policy.allow_origin("https://APP.example.com").as_deref(),
+            Some("https://APP.example.com")
+        );
+        assert!(policy.allow_origin("https://evil.example.com").is_none());
+
+        assert!(policy.allows_method("POST"));
+        assert!(!policy.allows_method("DELETE"));
+
+        assert!(policy.allows_headers(""));
+        assert!(policy.allows_headers("content-type"));
+        assert!(!policy.allows_headers("content-type, x-token"));
+
+        policy.allow_headers.push(String::from("*"));
+        assert!(policy.allows_headers("content-type, x-token"));
+
+        assert!(!policy.shares_credentials());
+        policy.allow_credentials = true;
+        assert!(policy.shares_credentials());
+
+        // any origin gets `*`, and never the credentials, which would be shared with every origin
+        let policy = CorsPolicy {
+            allow_credentials: true,
+            ..CorsPolicy::default()
+        };
+        assert_eq!(policy.allow_origin("https://a.com").as_deref(), Some("*"));
+        assert!(!policy.shares_credentials());
+    }
+}
@@ -1,51 +1,150 @@
 #![allow(dead_code)]
 
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
 use std::collections;
-use std::fs::File;
-use std::io::{prelude::*, BufReader, BufWriter};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Shutdown, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::ptr;
 use std::str;
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::channel::{self, Receiver, Sender, TryRecvError};
+use crate::channel::{self, Receiver, RecvTimeoutError, Sender};
 use crate::chrono::prelude::*;
 use crate::core::syncstore::{Reusable, StaticStore, SyncPool, TOTAL_ELEM_COUNT};
 use crate::core::{
-    config::{ConnMetadata, EngineContext, ServerConfig, ViewEngineParser},
+    config::{
+        ConnMetadata, ContextValue, EngineContext, ServerConfig, ViewEngineParser,
+        DEFAULT_SHRINK_BYTES,
+    },
     cookie::*,
-    router::REST,
+    multipart::{Multipart, MultipartError},
+    router::{ViewsScope, REST},
+    states::ServerStates,
+    stats::StoreStats,
     stream::Stream,
 };
 use crate::hashbrown::{hash_map::Iter, HashMap};
-use crate::support::{common::*, debug, debug::InfoLevel, shared_pool, TaskType};
+use crate::support::{common::*, shared_pool, TaskType, TraceIds};
 
-const FOUR_OH_FOUR: &str = include_str!("../default/404.html");
-const FOUR_OH_ONE: &str = include_str!("../default/401.html");
-const FIVE_HUNDRED: &str = include_str!("../default/500.html");
+const FOUR_OH_FOUR: &[u8] = include_bytes!("../default/404.html");
+const FOUR_OH_ONE: &[u8] = include_bytes!("../default/401.html");
+const FIVE_HUNDRED: &[u8] = include_bytes!("../default/500.html");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const RESP_TIMEOUT: Duration = Duration::from_millis(64);
 const LONG_CONN_TIMEOUT: Duration = Duration::from_secs(8);
 const HEADER_END: [u8; 2] = [13, 10];
+const CHUNK_SIZE: usize = 8192;
+const STREAM_FLUSH_BYTES: usize = 32 * 1024;
+const STREAM_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
 
 type BodyChan = (
     Option<Sender<(Vec<u8>, u16)>>,
     Option<Receiver<(Vec<u8>, u16)>>,
 );
 type NotifyChan = Option<(Sender<String>, Receiver<String>)>;
+type BodyStream = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+#[cfg(feature = "compression")]
+use crate::core::config::CompressionPolicy;
+
+#[cfg(feature = "websocket")]
+use crate::core::websocket::{self, WsConnection, WsUpgrade};
+
+#[cfg(feature = "compression")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+#[cfg(feature = "compression")]
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
 
 static mut REQ_POOL: StaticStore<SyncPool<Request>> = StaticStore::init();
 static mut RESP_POOL: StaticStore<SyncPool<Response>> = StaticStore::init();
 static mut POOL_CHAN: StaticStore<(channel::Sender<()>, channel::Receiver<()>)> =
     StaticStore::init();
 
+/// The largest buffers in bytes the pooled objects keep for the next use, see
+/// `ServerConfig::set_pool_shrink_threshold`.
+static SHRINK_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_SHRINK_BYTES);
+
 //TODO: pub http version?
 
-#[derive(PartialOrd, PartialEq)]
+/// A language range from the `Accept-Language` header, e.g. `fr-ch` with the quality of `0.9`. The
+/// tag is lowercased, and `*` stands for any language.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LanguageTag {
+    pub tag: String,
+    pub quality: f32,
+}
+
+/// The reasons the framework has overridden what the handler set on the response, in the order
+/// they happened, see `ResponseStates::audit`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverrideReason {
+    /// The status set by the handler is not recognized, so it's dropped, and the status is then
+    /// guessed from the response.
+    UnrecognizedStatus(u16),
+    /// No status is set, and the response goes out as `200 OK` since it has contents.
+    InferredStatus(u16),
+    /// The status doesn't allow a body, e.g. `1xx`, `204` or `304`, so the contents are dropped and
+    /// only the header is sent.
+    HeaderOnly(u16),
+    /// One of the async body segments has failed with the status, so the body is dropped and the
+    /// response is turned into a `500 Internal Server Error`.
+    AsyncBodyFailed(u16),
+    /// The response has no contents, so the error page of the status is sent as the body.
+    ErrorPage(u16),
+}
+
+impl fmt::Display for OverrideReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OverrideReason::UnrecognizedStatus(status) => {
+                write!(f, "unrecognized status {} is dropped", status)
+            }
+            OverrideReason::InferredStatus(status) => {
+                write!(f, "no status is set, inferred as {}", status)
+            }
+            OverrideReason::HeaderOnly(status) => {
+                write!(
+                    f,
+                    "status {} allows no body, the contents are dropped",
+                    status
+                )
+            }
+            OverrideReason::AsyncBodyFailed(status) => write!(
+                f,
+                "async body segment failed with status {}, responding with 500",
+                status
+            ),
+            OverrideReason::ErrorPage(status) => {
+                write!(f, "no contents, the error page of {} is sent", status)
+            }
+        }
+    }
+}
+
+/// The keep-alive state of the response. `TlsConn` and `Forbidden` are set by the framework, for
+/// connections over TLS and requests that ask to close the connection, and they take precedence
+/// over whatever the handler asks for. Otherwise the handler can toggle between `NotSet`, i.e.
+/// close the connection after the response, and `KeepAlive` with `ResponseWriter::keep_alive`.
+#[derive(Debug, PartialOrd, PartialEq)]
 enum KeepAliveStatus {
     NotSet,
     TlsConn,
@@ -53,6 +152,13 @@ enum KeepAliveStatus {
     KeepAlive,
 }
 
+impl KeepAliveStatus {
+    #[inline]
+    fn is_reserved(&self) -> bool {
+        *self == KeepAliveStatus::TlsConn || *self == KeepAliveStatus::Forbidden
+    }
+}
+
 impl Default for KeepAliveStatus {
     fn default() -> Self {
         KeepAliveStatus::NotSet
@@ -65,12 +171,17 @@ pub struct Request {
     pub uri: String,
     params: HashMap<String, String>,
     query: HashMap<String, Vec<String>>,
-    header: HashMap<String, String>,
+    query_pairs: Vec<(String, String)>,
+    header: HeaderMap,
     cookie: HashMap<String, String>,
     fragment: String,
     host: String,
     body: String,
+    raw_body: Option<Vec<u8>>,
     client_info: Option<SocketAddr>,
+    route_pattern: String,
+    trace_ids: TraceIds,
+    extensions: HashMap<TypeId, Box<dyn Any + Send>>,
 }
 
 impl Request {
@@ -79,6 +190,55 @@ impl Request {
         Default::default()
     }
 
+    /// If the request carries nothing from a previous one, i.e. it's as good as a new one.
+    fn is_blank(&self) -> bool {
+        self.method == REST::GET
+            && self.uri.is_empty()
+            && self.params.is_empty()
+            && self.query.is_empty()
+            && self.query_pairs.is_empty()
+            && self.header.is_empty()
+            && self.cookie.is_empty()
+            && self.fragment.is_empty()
+            && self.host.is_empty()
+            && self.body.is_empty()
+            && self.raw_body.is_none()
+            && self.client_info.is_none()
+            && self.route_pattern.is_empty()
+            && self.trace_ids == TraceIds::default()
+            && self.extensions.is_empty()
+    }
+
+    /// A copy of the request line and the head, without the body and the extensions, which stands
+    /// in for the request handed over to an overrunning handler, e.g. in the access log.
+    pub(crate) fn head_copy(&self) -> Box<Request> {
+        Box::new(Request {
+            method: self.method.clone(),
+            uri: self.uri.clone(),
+            params: self.params.clone(),
+            query: self.query.clone(),
+            query_pairs: self.query_pairs.clone(),
+            header: self.header.clone(),
+            cookie: self.cookie.clone(),
+            fragment: self.fragment.clone(),
+            host: self.host.clone(),
+            client_info: self.client_info,
+            route_pattern: self.route_pattern.clone(),
+            trace_ids: self.trace_ids,
+            ..Default::default()
+        })
+    }
+
+    /// Get the value of type `T` attached to the request with `RequestWriter::set_ext`, e.g. the
+    /// user id found by the auth function, or `None` if no such value is attached.
+    pub fn get_ext<T: Any + Send>(&self) -> Option<&T> {
+        self.extensions
+            .get(&TypeId::of::<T>())
+            .and_then(|ext| ext.downcast_ref::<T>())
+    }
+
+    /// The value of the header field. The field names are case-insensitive, e.g. `Content-Length`,
+    /// `content-length` and `CONTENT-LENGTH` are the same field.
     pub fn header(&self, field: &str) -> Option<String> {
         if field.is_empty() {
             return None;
@@ -88,17 +248,157 @@ impl Request {
             return None;
         }
 
-        match self.header.get(&field[..]) {
-            Some(value) => Some(value.to_owned()),
-            None => None,
-        }
+        // the names are stored in lowercase, and the well-known ones are looked up without allocation
+        self.header.get(header_name(field).as_ref()).cloned()
+    }
+
+    /// Iterate over the header fields of the request, with the names in lowercase as they're
+    /// stored, e.g. `("content-length", "42")`, in no particular order.
+    pub fn header_iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.header
+            .iter()
+            .filter(|(field, _)| field.as_ref() != "http_version")
+            .map(|(field, value)| (field.as_ref(), value.as_str()))
     }
 
+    /// If the connection shall be kept open after serving this request. HTTP/1.1 connections are
+    /// persistent unless the client sends `Connection: close`, while HTTP/1.0 connections are
+    /// closed unless the client sends `Connection: keep-alive`.
     pub fn keep_alive(&self) -> bool {
-        match self.header("connection") {
+        let has_option = |option: &str| match self.header("connection") {
+            Some(val) => val
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(option)),
+            None => false,
+        };
+
+        match self.header("http_version") {
+            Some(ref version) if !version.eq_ignore_ascii_case("HTTP/1.0") => !has_option("close"),
+            _ => has_option("keep-alive"),
+        }
+    }
+
+    /// If the request asks to upgrade the connection to a websocket, i.e. a `GET` request with the
+    /// `Upgrade: websocket` and `Connection: Upgrade` headers, a `Sec-WebSocket-Key`, and the
+    /// `Sec-WebSocket-Version` of 13.
+    #[cfg(feature = "websocket")]
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let has_token = |field: &str, token: &str| match self.header(field) {
+            Some(val) => val
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token)),
             None => false,
-            Some(val) => &val != "keep-alive",
+        };
+
+        self.method == REST::GET
+            && has_token("upgrade", "websocket")
+            && has_token("connection", "upgrade")
+            && self
+                .header("sec-websocket-key")
+                .map_or(false, |key| !key.trim().is_empty())
+            && self
+                .header("sec-websocket-version")
+                .map_or(false, |version| version.trim() == "13")
+    }
+
+    /// The language ranges from the `Accept-Language` header, ordered by their quality values, and
+    /// the ranges with the same quality keep the order from the header. Malformed entries and the
+    /// ones with a quality of 0 are skipped.
+    pub fn accepted_languages(&self) -> Vec<LanguageTag> {
+        language_ranges(&self.header("accept-language").unwrap_or_default())
+            .into_iter()
+            .filter(|lang| lang.quality > 0.0)
+            .collect()
+    }
+
+    /// Pick the best language from the `supported` ones, following the lookup scheme of RFC 4647:
+    /// each language range, ordered by quality, is matched to the supported languages, then its
+    /// subtags are dropped one by one until there's a match, such that `en-GB` matches `en`. The
+    /// wildcard `*` matches the first supported language that is not explicitly refused with a
+    /// quality of 0. If the request has no `Accept-Language` header, i.e. no preference, the first
+    /// supported language is returned.
+    pub fn negotiate_language(&self, supported: &[&str]) -> Option<String> {
+        let ranges = language_ranges(&self.header("accept-language").unwrap_or_default());
+        if ranges.is_empty() {
+            return supported.first().map(|lang| (*lang).to_owned());
+        }
+
+        let find = |tag: &str| {
+            supported
+                .iter()
+                .find(|lang| lang.eq_ignore_ascii_case(tag))
+                .map(|lang| (*lang).to_owned())
+        };
+
+        for range in ranges.iter().filter(|range| range.quality > 0.0) {
+            if range.tag == "*" {
+                return supported
+                    .iter()
+                    .find(|lang| {
+                        !ranges.iter().any(|refused| {
+                            refused.quality <= 0.0 && lang.eq_ignore_ascii_case(&refused.tag)
+                        })
+                    })
+                    .map(|lang| (*lang).to_owned());
+            }
+
+            let mut tag = &range.tag[..];
+            loop {
+                if let Some(lang) = find(tag) {
+                    return Some(lang);
+                }
+
+                match tag.rfind('-') {
+                    Some(pos) => tag = &tag[..pos],
+                    None => break,
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The charsets from the `Accept-Charset` header with their quality values, ordered by quality,
+    /// and the charsets with the same quality keep the order from the header. The names are
+    /// lowercased, and `*` stands for any charset. Malformed entries and the ones with a quality of
+    /// 0 are skipped.
+    pub fn accepted_charsets(&self) -> Vec<(String, f32)> {
+        charset_ranges(&self.header("accept-charset").unwrap_or_default())
+            .into_iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect()
+    }
+
+    /// Pick the best charset from the `supported` ones for the `Accept-Charset` header: the accepted
+    /// charsets are matched in the order of quality, where the aliases of the same charset match
+    /// each other, e.g. `latin1` and `ISO-8859-1`. The wildcard `*` matches the first supported
+    /// charset that is not explicitly refused with a quality of 0. If the request has no
+    /// `Accept-Charset` header, i.e. no preference, the first supported charset is returned.
+    pub fn negotiate_charset(&self, supported: &[&str]) -> Option<String> {
+        let ranges = charset_ranges(&self.header("accept-charset").unwrap_or_default());
+        if ranges.is_empty() {
+            return supported.first().map(|charset| (*charset).to_owned());
+        }
+
+        let refused = |charset: &str| {
+            ranges
+                .iter()
+                .any(|(name, quality)| *quality <= 0.0 && same_charset(name, charset))
+        };
+
+        for (name, _) in ranges.iter().filter(|(_, quality)| *quality > 0.0) {
+            let found = if name == "*" {
+                supported.iter().find(|charset| !refused(charset))
+            } else {
+                supported.iter().find(|charset| same_charset(name, charset))
+            };
+
+            if let Some(charset) = found {
+                return Some((*charset).to_owned());
+            }
         }
+
+        None
     }
 
     pub fn cookie(&self, key: &str) -> Option<String> {
@@ -121,6 +421,8 @@ impl Request {
         self.cookie.iter()
     }
 
+    /// The values of the query field, percent-decoded and with `+` decoded into spaces. Malformed
+    /// escapes, e.g. `%ZZ`, are kept as they are.
     pub fn query(&self, field: &str) -> Option<Vec<String>> {
         if field.is_empty() {
             return None;
@@ -136,10 +438,61 @@ impl Request {
         }
     }
 
+    /// The first value of the query field, e.g. `1` for the field `a` in `?a=1&a=2`, decoded like
+    /// the ones from `query`.
+    pub fn query_first(&self, field: &str) -> Option<String> {
+        self.query
+            .get(field)
+            .and_then(|values| values.first())
+            .cloned()
+    }
+
+    /// Iterate over the decoded query pairs in the order they appear in the request, where a field
+    /// repeated in the query is visited once for each value.
+    pub fn query_iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.query_pairs
+            .iter()
+            .map(|(field, value)| (field.as_str(), value.as_str()))
+    }
+
+    /// The number of the query pairs, i.e. the number of the items from `query_iter`.
+    #[inline]
+    pub fn query_count(&self) -> usize {
+        self.query_pairs.len()
+    }
+
+    /// The query re-encoded into a string with the leading `?`, e.g. when redirecting the request,
+    /// or empty if there's no query. The pairs are kept in the order they appear in the request.
+    pub(crate) fn query_string(&self) -> String {
+        let mut res = String::new();
+
+        for (field, value) in self.query_pairs.iter() {
+            res.push(if res.is_empty() { '?' } else { '&' });
+            res.push_str(&percent_encode(field));
+            res.push('=');
+            res.push_str(&percent_encode(value));
+        }
+
+        res
+    }
+
     pub fn uri_fragment(&self) -> String {
         self.fragment.clone()
     }
 
+    /// The route pattern that the request has matched to, as it was registered to the router, e.g.
+    /// `/users/:id/orders/:oid` for parameterized routes, or the regex source for wildcard routes.
+    /// Explicit routes will report their literal path. Return `None` if the request is served by
+    /// the static folder.
+    pub fn route_pattern(&self) -> Option<String> {
+        if self.route_pattern.is_empty() {
+            return None;
+        }
+
+        Some(self.route_pattern.clone())
+    }
+
+    /// The value of the route parameter, which is matched against the percent-decoded path.
     pub fn param(&self, key: &str) -> Option<String> {
         match self.params.get(key) {
             Some(val) => Some(val.to_owned()),
@@ -157,11 +510,79 @@ impl Request {
         self.client_info
     }
 
+    /// The IP address of the client, without the port.
+    #[inline]
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_info.map(|addr| addr.ip())
+    }
+
+    /// The ids of the connection and of the request within the connection, i.e. the same ones
+    /// prefixed to the log lines of the server when serving this request, formatted as
+    /// `conn.req`.
+    #[inline]
+    pub fn trace_ids(&self) -> TraceIds {
+        self.trace_ids
+    }
+
+    #[inline]
+    pub(crate) fn set_trace_ids(&mut self, ids: TraceIds) {
+        self.trace_ids = ids;
+    }
+
+    /// The host from the `Host` header of the request, without the port. IPv6 literals are also
+    /// stored without the square brackets, e.g. `example.com:8080` becomes `example.com`, and
+    /// `[::1]:8080` becomes `::1`.
     #[inline]
     pub fn host_info(&self) -> String {
         self.host.clone()
     }
 
+    /// Check if the request is made to the given virtual host, where the port (if any) and the
+    /// square brackets around IPv6 literals are ignored, and the names are compared in a case
+    /// insensitive manner. For example, a request to `[::1]:8080` matches the host `::1`.
+    pub fn host_matches(&self, vhost: &str) -> bool {
+        if self.host.is_empty() {
+            return false;
+        }
+
+        self.host == normalize_host(vhost)
+    }
+
+    /// If the request is sent from another site, i.e. its `Origin` is not the host it's made to.
+    pub(crate) fn is_cross_site(&self) -> bool {
+        let origin = match self.header("origin") {
+            Some(origin) => origin,
+            None => return false,
+        };
+
+        let authority = match origin.find("://") {
+            Some(pos) => &origin[pos + 3..],
+            None => return true,
+        };
+
+        !self.host_matches(authority.split('/').next().unwrap_or_default())
+    }
+
+    /// The body as it's received. Unlike the text body, e.g. used by `form_data`, where the
+    /// invalid UTF-8 sequences are replaced, the binary contents are kept intact.
+    pub fn body_bytes(&self) -> &[u8] {
+        match self.raw_body.as_ref() {
+            Some(raw) => raw,
+            None => self.body.as_bytes(),
+        }
+    }
+
+    /// Parse the `multipart/form-data` body, e.g. the form with the file uploads, into the parts.
+    /// The size of the parts can be capped with `ServerConfig::set_multipart_limits`.
+    pub fn multipart(&self) -> Result<Multipart, MultipartError> {
+        let content_type = self
+            .header("content-type")
+            .ok_or(MultipartError::NotMultipart)?;
+
+        let (part_limit, total_limit) = ConnMetadata::get_multipart_limits();
+        Multipart::parse(&content_type, self.body_bytes(), part_limit, total_limit)
+    }
+
     #[must_use]
     pub fn form_data(&self) -> collections::HashMap<String, String> {
         let mut data = collections::HashMap::new();
@@ -209,40 +630,82 @@ impl Request {
             source.insert(String::from("host"), self.host.to_owned());
         }
 
+        if !self.route_pattern.is_empty() {
+            source.insert(String::from("route_pattern"), self.route_pattern.to_owned());
+        }
+
         if let Some(addr) = self.client_info {
             source.insert(String::from("socket_address"), addr.to_string());
+            source.insert(String::from("client_ip"), addr.ip().to_string());
+            source.insert(String::from("client_port"), addr.port().to_string());
         }
 
         json_stringify(&source)
     }
 
-    pub(crate) fn set_headers(&mut self, header: HashMap<String, String>) {
+    pub(crate) fn set_headers(&mut self, mut header: HeaderMap) {
+        // keep the http version parsed from the request line
+        if let Some(version) = self.header.remove("http_version") {
+            header.insert(Cow::Borrowed("http_version"), version);
+        }
+
         self.header = header;
 
-        if let Some(host_name) = self.header.get(&String::from("host")) {
-            self.host = host_name.to_owned();
-        }
+        // a request without the `Host` header has no host
+        self.host = match self.header.get("host") {
+            Some(host_name) => normalize_host(host_name),
+            None => String::new(),
+        };
     }
 
     pub(crate) fn set_cookies(&mut self, cookie: HashMap<String, String>) {
         self.cookie = cookie;
     }
 
+    /// Set the query from the pairs in the order they appear in the request.
+    pub(crate) fn set_query(&mut self, pairs: Vec<(String, String)>) {
+        self.query.clear();
+        for (field, value) in pairs.iter() {
+            self.query
+                .entry(field.to_owned())
+                .or_insert_with(Vec::new)
+                .push(value.to_owned());
+        }
+
+        self.query_pairs = pairs;
+    }
+
     pub(crate) fn set_body(&mut self, body: String) {
         self.body = body;
+        self.raw_body = None;
+    }
+
+    /// Set the body from the received bytes, which are kept as they are if not valid UTF-8.
+    pub(crate) fn set_body_bytes(&mut self, bytes: Vec<u8>) {
+        match String::from_utf8(bytes) {
+            Ok(body) => self.set_body(body),
+            Err(e) => {
+                self.body = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                self.raw_body = Some(e.into_bytes());
+            }
+        }
     }
 }
 
 impl Reusable for Request {
     fn obtain() -> Box<Self> {
-        match unsafe { REQ_POOL.as_mut() } {
+        let req: Box<Self> = match unsafe { REQ_POOL.as_mut() } {
             Ok(pool) => pool.get(),
             Err(_) => Default::default(),
-        }
+        };
+
+        debug_assert!(req.is_blank(), "the pooled request is not reset");
+        req
     }
 
     fn release(mut self: Box<Self>) {
         self.reset(false);
+        debug_assert!(self.is_blank(), "the request is not fully reset");
 
         if let Ok(pool) = unsafe { REQ_POOL.as_mut() } {
             pool.put(self);
@@ -252,28 +715,35 @@ impl Reusable for Request {
     fn reset(&mut self, hard: bool) {
         self.method = REST::GET;
 
-        if !hard {
-            unsafe {
-                self.uri.as_mut_vec().set_len(0);
-                self.fragment.as_mut_vec().set_len(0);
-                self.host.as_mut_vec().set_len(0);
-                self.body.as_mut_vec().set_len(0);
-            }
+        // the parser may skip any of the fields, e.g. the host of a request without the `Host`
+        // header, so all of them are cleared, which keeps their capacity for the next request
+        self.uri.clear();
+        self.fragment.clear();
+        self.host.clear();
+        self.route_pattern.clear();
+
+        // unless it's an upload too large to keep around
+        if oversized(self.body.capacity(), hard) {
+            self.body = String::new();
         } else {
-            self.uri.clear();
-            self.fragment.clear();
-            self.host.clear();
             self.body.clear();
         }
 
         self.params.clear();
         self.query.clear();
+        self.query_pairs.clear();
         self.header.clear();
         self.cookie.clear();
+        self.raw_body = None;
 
         if self.client_info.is_some() {
             self.client_info.take();
         }
+
+        self.trace_ids = TraceIds::default();
+
+        // the pooled request must not carry the values of the previous one
+        self.extensions.clear();
     }
 }
 
@@ -281,6 +751,7 @@ pub trait RequestWriter {
     fn write_header(&mut self, key: &str, val: &str, allow_override: bool);
     fn write_query(&mut self, key: &str, val: Vec<String>, allow_override: bool);
     fn create_query(&mut self, query: HashMap<String, Vec<String>>);
+
     fn set_cookie(&mut self, key: &str, val: &str, allow_override: bool);
     fn create_cookie(&mut self, cookie: HashMap<String, String>);
     fn set_param(&mut self, key: &str, val: &str);
@@ -288,7 +759,15 @@ pub trait RequestWriter {
     fn set_fragment(&mut self, fragment: String);
     fn set_host(&mut self, host: String);
     fn set_client(&mut self, addr: SocketAddr);
+    fn set_route_pattern(&mut self, pattern: &str);
     fn extend_body(&mut self, content: &str);
+
+    /// Attach a value to the request, which replaces the value of the same type attached before.
+    /// Each type holds one value, so wrap the plain types, e.g. `struct UserId(u64)`, to avoid
+    /// the clashes.
+    fn set_ext<T: Any + Send>(&mut self, val: T)
+    where
+        Self: Sized;
 }
 
 impl RequestWriter for Request {
@@ -296,11 +775,30 @@ impl RequestWriter for Request {
         self.header.add(key, val.to_owned(), allow_override, false);
     }
 
+    /// Set the values of the query field, which are appended to the query pairs, see
+    /// `Request::query_iter`. If the field exists, its values are replaced only if
+    /// `allow_override` is true.
     fn write_query(&mut self, key: &str, val: Vec<String>, allow_override: bool) {
-        self.query.add(key, val, allow_override, false);
+        let field = key.to_lowercase();
+        if field.is_empty() || (!allow_override && self.query.contains_key(&field)) {
+            return;
+        }
+
+        self.query_pairs.retain(|(f, _)| f != &field);
+        self.query_pairs
+            .extend(val.iter().map(|v| (field.to_owned(), v.to_owned())));
+        self.query.insert(field, val);
     }
 
+    /// Replace the query with the fields, where the order of the query pairs follows the
+    /// iteration order of the map.
     fn create_query(&mut self, query: HashMap<String, Vec<String>>) {
+        self.query_pairs = query
+            .iter()
+            .flat_map(|(field, values)| {
+                values.iter().map(move |v| (field.to_owned(), v.to_owned()))
+            })
+            .collect();
         self.query = query;
     }
 
@@ -328,7 +826,7 @@ impl RequestWriter for Request {
     }
 
     fn set_host(&mut self, host: String) {
-        self.host = host;
+        self.host = normalize_host(&host);
     }
 
     #[inline]
@@ -336,9 +834,63 @@ impl RequestWriter for Request {
         self.client_info = Some(addr)
     }
 
+    fn set_route_pattern(&mut self, pattern: &str) {
+        self.route_pattern.clear();
+        self.route_pattern.push_str(pattern);
+    }
+
     fn extend_body(&mut self, content: &str) {
         self.body.push_str(content);
     }
+
+    fn set_ext<T: Any + Send>(&mut self, val: T) {
+        self.extensions.insert(TypeId::of::<T>(), Box::new(val));
+    }
+}
+
+/// How the streamed body is flushed to the client, see `ResponseWriter::set_stream_options`. Each
+/// send, i.e. a write into the sink of `ResponseWriter::stream`, or a message sent over the long
+/// connection, is buffered until one of the options asks for a flush, or the body ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamOptions {
+    /// Flush once this many bytes have been buffered since the last flush, or never if 0.
+    pub flush_every_bytes: usize,
+
+    /// Flush once the buffered content has waited this long. It's checked on each send, and while
+    /// the long connection waits for the next message, so no timer thread is involved.
+    pub flush_every: Option<Duration>,
+
+    /// Flush after every send.
+    pub flush_on_each_send: bool,
+}
+
+impl StreamOptions {
+    /// The options for the bulk contents, e.g. a file download or a CSV export, which are the
+    /// default of `ResponseWriter::stream`: flush every 32KB, or once the buffered content has
+    /// waited for 500ms, such that a slow export still shows its progress.
+    pub fn bulk() -> Self {
+        StreamOptions {
+            flush_every_bytes: STREAM_FLUSH_BYTES,
+            flush_every: Some(STREAM_FLUSH_INTERVAL),
+            flush_on_each_send: false,
+        }
+    }
+
+    /// The options for the events, e.g. the server-sent events or the progress updates, which are
+    /// the default of the long connections: every message is flushed as soon as it's sent.
+    pub fn events() -> Self {
+        StreamOptions {
+            flush_every_bytes: 0,
+            flush_every: None,
+            flush_on_each_send: true,
+        }
+    }
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions::bulk()
+    }
 }
 
 #[derive(Default)]
@@ -350,11 +902,24 @@ pub struct Response {
     header: HashMap<String, String>,
     cookie: HashMap<String, Cookie>,
     header_only: bool,
+    stat_only: bool,
+    size_hint: Option<u64>,
     redirect: String,
     body: Vec<u8>,
     body_chan: BodyChan,
+    body_stream: Option<BodyStream>,
+    stream_options: Option<StreamOptions>,
+    json_err: Option<(String, TraceIds)>,
+    #[cfg(feature = "compression")]
+    encoding: Option<Encoding>,
     notifier: NotifyChan,
     subscriber: NotifyChan,
+    trailers: Vec<(String, String)>,
+    audit: Vec<OverrideReason>,
+    cross_site: bool,
+    views: Option<Arc<ViewsScope>>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<WsUpgrade>,
 }
 
 impl Response {
@@ -362,6 +927,162 @@ impl Response {
         Default::default()
     }
 
+    /// If the response carries nothing from a previous one, i.e. it's as good as a new one.
+    fn is_blank(&self) -> bool {
+        #[cfg(feature = "compression")]
+        {
+            if self.encoding.is_some() {
+                return false;
+            }
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            if self.websocket.is_some() {
+                return false;
+            }
+        }
+
+        self.status == 0
+            && self.keep_alive == KeepAliveStatus::NotSet
+            && self.content_type.is_empty()
+            && self.content_length.is_none()
+            && self.header.is_empty()
+            && self.cookie.is_empty()
+            && !self.header_only
+            && !self.stat_only
+            && self.size_hint.is_none()
+            && self.redirect.is_empty()
+            && self.body.is_empty()
+            && self.body_chan.0.is_none()
+            && self.body_chan.1.is_none()
+            && self.body_stream.is_none()
+            && self.stream_options.is_none()
+            && self.json_err.is_none()
+            && self.notifier.is_none()
+            && self.subscriber.is_none()
+            && self.trailers.is_empty()
+            && self.audit.is_empty()
+            && !self.cross_site
+            && self.views.is_none()
+    }
+
+    /// The bytes held by the buffers that a reset may keep, i.e. the body, the content type and the
+    /// header.
+    #[cfg(test)]
+    fn retained_bytes(&self) -> usize {
+        self.body.capacity() + self.content_type.capacity() + header_bytes(&self.header)
+    }
+
+    /// Create an interim response, e.g. the `100 Continue`, which only has the status line and
+    /// will be followed by the final response to the same request.
+    pub(crate) fn interim(status: u16) -> Box<Self> {
+        let mut resp = Response::obtain();
+        resp.status(status);
+        resp
+    }
+
+    #[inline]
+    pub(crate) fn is_interim(&self) -> bool {
+        self.status == 100
+    }
+
+    /// Close the connection after the response, which the handler can't turn back on. A TLS
+    /// connection stays marked as such.
+    #[inline]
+    pub(crate) fn forbid_keep_alive(&mut self) {
+        if self.keep_alive != KeepAliveStatus::TlsConn {
+            self.keep_alive = KeepAliveStatus::Forbidden;
+        }
+    }
+
+    /// Mark the response to be sent over a TLS connection, which is closed after the response.
+    #[inline]
+    pub(crate) fn set_tls_conn(&mut self) {
+        self.keep_alive = KeepAliveStatus::TlsConn;
+    }
+
+    /// Mark the response to a cross-site request, which the cookie policy checks the cookies for.
+    #[inline]
+    pub(crate) fn set_cross_site(&mut self, cross_site: bool) {
+        self.cross_site = cross_site;
+    }
+
+    /// Render the templates with the views scope of the matched route, see `ViewsScope`.
+    #[inline]
+    pub(crate) fn set_views(&mut self, views: Option<Arc<ViewsScope>>) {
+        self.views = views;
+    }
+
+    /// Apply the `Connection` header value set by the handler. The framework-reserved states can't
+    /// be reached from the header values.
+    fn keep_alive_from_header(&mut self, value: &str) {
+        match &value.trim().to_lowercase()[..] {
+            "keep-alive" => self.keep_alive(true),
+            "close" => self.keep_alive(false),
+            "tls" | "forbidden" => {
+                rex_warn!("Connection header value '{}' is reserved, ignored", value)
+            }
+            _ => { /* Otherwise, don't update the keep_alive field */ }
+        }
+    }
+
+    /// Accept the websocket upgrade of the request, and the connection will be handed to the
+    /// handler once the handshake response, i.e. the `101 Switching Protocols`, is sent. The server
+    /// stops serving http requests on this connection afterwards, and the connection is closed when
+    /// the handler returns. If the request doesn't ask for the upgrade, see
+    /// `Request::is_websocket_upgrade`, the response will be a `426 Upgrade Required` instead.
+    #[cfg(feature = "websocket")]
+    pub fn accept_websocket(&mut self, handler: fn(WsConnection)) {
+        self.status(101);
+        self.websocket = Some(WsUpgrade::new(handler));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[inline]
+    pub(crate) fn is_websocket(&self) -> bool {
+        self.websocket.is_some()
+    }
+
+    /// Complete the handshake response with the key of the request, or turn it down if the request
+    /// isn't a valid upgrade request.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn upgrade_websocket(&mut self, request: &Request) {
+        let upgrade = match self.websocket.as_mut() {
+            Some(upgrade) => upgrade,
+            None => return,
+        };
+
+        if request.is_websocket_upgrade() {
+            let key = request.header("sec-websocket-key").unwrap_or_default();
+            upgrade.accept = websocket::accept_key(&key);
+            return;
+        }
+
+        self.websocket = None;
+        self.status(426);
+        self.header("Upgrade", "websocket", true);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[inline]
+    pub(crate) fn websocket_mut(&mut self) -> Option<&mut WsUpgrade> {
+        self.websocket.as_mut()
+    }
+
+    #[cfg(feature = "websocket")]
+    #[inline]
+    pub(crate) fn take_websocket(&mut self) -> Option<WsUpgrade> {
+        self.websocket.take()
+    }
+
+    /// If the response is served over a long connection, i.e. the handler has obtained the channels
+    /// to keep sending contents, which will be framed as chunks of the body.
+    #[inline]
+    pub(crate) fn is_long_conn(&self) -> bool {
+        self.notifier.is_some()
+    }
+
     pub(crate) fn default_header(&mut self, header: HashMap<String, String>) {
         self.header = header;
     }
@@ -376,7 +1097,13 @@ impl Response {
             }
 
             self.header("Location", &redirect, true);
-            self.status(301);
+
+            // the handler may have picked the redirect status, e.g. the method-preserving ones,
+            // otherwise default to the permanent one
+            match self.status {
+                301 | 302 | 303 | 307 | 308 => {}
+                _ => self.status(301),
+            }
         }
     }
 
@@ -387,10 +1114,11 @@ impl Response {
         } else {
             let (tx, rx) = channel::bounded(1);
             let cookie = mem::replace(&mut self.cookie, HashMap::new());
+            let conn = (self.keep_alive == KeepAliveStatus::TlsConn, self.cross_site);
 
             shared_pool::run(
                 move || {
-                    write_header_cookie(cookie, tx);
+                    write_header_cookie(cookie, conn, tx);
                 },
                 TaskType::Response,
             );
@@ -399,31 +1127,103 @@ impl Response {
         };
 
         // get the initial header line
-        let mut header = write_header_status(self.status, self.has_contents());
+        let mut header = get_status(self.status_sent());
+
+        // the 1xx, 204 and 304 responses never have a body, so they don't frame one either
+        let bodiless = is_bodiless(self.status);
 
         // other header field-value pairs
-        write_headers(&self.header, &mut header, self.to_keep_alive());
+        write_headers(
+            &self.header,
+            &mut header,
+            self.to_keep_alive() || self.is_streaming(),
+            bodiless,
+        );
+
+        let chunked = !bodiless && (self.is_streaming() || self.is_long_conn());
 
         // write to the buffer first
         buffer.write(&header.swap_reset()).unwrap_or_default();
 
-        // write the remainder headers
-        if !self.content_type.is_empty() {
+        // the handshake response has no body, and the connection is upgraded afterwards
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(upgrade) = self.websocket.as_ref() {
+                header.extend_from_slice(b"Upgrade: websocket\r\nConnection: Upgrade\r\n");
+                header.extend_from_slice(b"Sec-WebSocket-Accept: ");
+                header.extend_from_slice(upgrade.accept.as_bytes());
+                header.append_line_break();
+
+                buffer.write(&header).unwrap_or_default();
+                write_cookie_header(buffer, receiver);
+                return;
+            }
+        }
+
+        // pin the browsers to https once they have reached us over TLS
+        if self.keep_alive == KeepAliveStatus::TlsConn
+            && !self.header.contains_key("strict-transport-security")
+        {
+            if let Some(hsts) = ConnMetadata::get_hsts() {
+                header.reserve(29 + hsts.len());
+                header.extend_from_slice(b"Strict-Transport-Security: ");
+                header.extend_from_slice(hsts.as_bytes());
+                header.append_line_break();
+            }
+        }
+
+        // write the remainder headers, where the content type describes the body, if any
+        if !self.content_type.is_empty() && !bodiless {
             header.reserve(16 + self.content_type.len());
             header.extend_from_slice(b"Content-Type: ");
             header.extend_from_slice(self.content_type.as_bytes());
             header.append_line_break();
         }
 
-        if let Some(length) = self.content_length.as_ref() {
+        if bodiless {
+            // only a 304 may declare the length, which is the one of the representation it
+            // revalidates, if that's known, see `validate_conditional`
+            if let (304, Some(length)) = (self.status, self.content_length.as_ref()) {
+                header.reserve(18 + length.len());
+                header.extend_from_slice(b"Content-Length: ");
+                header.extend_from_slice(length.as_bytes());
+                header.append_line_break();
+            }
+        } else if chunked {
+            // the body will be sent in chunks, and the last chunk marks the end of the message
+            if !self.header.contains_key("transfer-encoding") {
+                header.reserve(28);
+                header.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+            }
+
+            // declare the trailer fields that will follow the last chunk
+            if !self.trailers.is_empty() && !self.header.contains_key("trailer") {
+                let fields: Vec<&str> = self.trailers.iter().map(|(f, _)| f.as_str()).collect();
+
+                header.extend_from_slice(b"Trailer: ");
+                header.extend_from_slice(fields.join(", ").as_bytes());
+                header.append_line_break();
+            }
+        } else if let Some(length) = self.content_length.as_ref() {
             // explicit content length is set, use it here
             header.reserve(18 + length.len());
             header.extend_from_slice(b"Content-Length: ");
             header.extend_from_slice(length.as_bytes());
             header.append_line_break();
+        } else if let Some(size) = self.size_hint {
+            // the file to a HEAD request is not read, but its size is what a GET would be sent
+            let size = size.to_string();
+
+            header.reserve(18 + size.len());
+            header.extend_from_slice(b"Content-Length: ");
+            header.extend_from_slice(size.as_bytes());
+            header.append_line_break();
+        } else if self.body.is_empty() && self.header_only && self.status >= 400 {
+            // the error page is not generated for a header-only response, so its length is unknown
         } else {
-            // Only generate content length header attribute if not using async and no content-length set explicitly
-            if self.is_header_only() || self.body.is_empty() {
+            // Only generate content length header attribute if not using async and no content-length
+            // set explicitly. Header-only responses to HEAD requests still report the body size.
+            if self.body.is_empty() {
                 header.reserve(19);
                 header.extend_from_slice(b"Content-Length: 0\r\n");
             } else {
@@ -451,21 +1251,152 @@ impl Response {
 
         // we're pretty much done, write the content to the underlying buffer.
         buffer.write(&header).unwrap_or_default();
-
-        if let Some(rx) = receiver {
-            if let Ok(content) = rx.recv_timeout(RESP_TIMEOUT) {
-                if !content.is_empty() && buffer.write(&content).is_err() {
-                    debug::print("Failed to send cookie headers", InfoLevel::Warning);
-                }
-            }
-        }
+        write_cookie_header(buffer, receiver);
     }
 
-    fn set_ext_mime_header(&mut self, path: &PathBuf) {
-        let mime_type = if let Some(ext) = path.extension() {
-            let file_extension = ext.to_string_lossy();
-            default_mime_type_with_ext(&file_extension)
-        } else {
+    /// Set the `ETag` and `Last-Modified` headers from the metadata of the file, such that clients can
+    /// make conditional requests for the file later. Validators that have been set explicitly will
+    /// not be overridden.
+    fn set_file_validators(&mut self, path: &PathBuf) {
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(_) => return,
+        };
+
+        let modified = match meta.modified() {
+            Ok(time) => time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            Err(_) => return,
+        };
+
+        if !self.header.contains_key("etag") {
+            // weak validator, as it's derived from the file metadata rather than the content
+            let etag = format!("W/\"{:x}-{:x}\"", meta.len(), modified);
+            self.header("ETag", &etag, true);
+        }
+
+        if !self.header.contains_key("last-modified") {
+            let date = Utc
+                .timestamp(modified as i64, 0)
+                .format("%a, %d %b %Y %T GMT")
+                .to_string();
+
+            self.header("Last-Modified", &date, true);
+        }
+    }
+
+    /// Whether the body is not wanted at all: the response is header-only, other than to a HEAD
+    /// request, whose body is still measured as for a GET, see `stat_only`.
+    fn skips_body(&self) -> bool {
+        self.header_only && !self.stat_only
+    }
+
+    /// For a HEAD request, take the size of the file from its metadata instead of reading it, and
+    /// return `true` if done. The file is still read if it can't be found, such that the error is
+    /// reported as for a GET.
+    fn stat_file(&mut self, path: &PathBuf) -> bool {
+        if !self.stat_only {
+            return false;
+        }
+
+        match fs::metadata(path) {
+            Ok(ref meta) if meta.is_file() => {
+                self.size_hint = Some(meta.len());
+                if self.content_type.is_empty() {
+                    self.set_ext_mime_header(path);
+                }
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Compress the body with the encoding negotiated from the request, if the response qualifies
+    /// under the compression policy.
+    #[cfg(feature = "compression")]
+    fn compress_body(&mut self) {
+        let policy = ConnMetadata::get_compression();
+        self.compress_body_under(policy.as_deref());
+    }
+
+    /// Compress the body under the policy. The ranges are left alone, since their `Content-Range`
+    /// counts the bytes of the identity body, and so are the HEAD responses, whose headers describe
+    /// the identity body as well.
+    #[cfg(feature = "compression")]
+    fn compress_body_under(&mut self, policy: Option<&CompressionPolicy>) {
+        let encoding = match self.encoding.take() {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        if self.body.is_empty()
+            || self.is_streaming()
+            || self.header_only
+            || self.header.contains_key("content-encoding")
+        {
+            // nothing to compress, or the body has been encoded already
+            return;
+        }
+
+        if self.status == 206 || self.status == 416 || self.header.contains_key("content-range") {
+            // a slice of the body, or the refusal of one
+            return;
+        }
+
+        match policy {
+            Some(policy) if policy.allows(&self.content_type, self.body.len()) => {}
+            _ => return,
+        };
+
+        let compressed = match compress(&self.body, encoding) {
+            Ok(content) => content,
+            Err(e) => {
+                rex_warn!("Failed to compress the response body: {}", e);
+                return;
+            }
+        };
+
+        self.body = compressed;
+        self.header("Content-Encoding", encoding.as_str(), true);
+
+        let vary = match self.header.get("vary") {
+            Some(vary) if vary.to_lowercase().contains("accept-encoding") => None,
+            Some(vary) => Some(format!("{}, Accept-Encoding", vary)),
+            None => Some(String::from("Accept-Encoding")),
+        };
+
+        if let Some(vary) = vary {
+            self.header("Vary", &vary, true);
+        }
+
+        if self.content_length.is_some() {
+            // the explicit content length is no longer valid, fix it up
+            self.content_length = Some(self.body.len().to_string());
+        }
+    }
+
+    fn insert_cookie(&mut self, cookie: Cookie) {
+        let key = cookie.get_cookie_key();
+
+        if self.cookie.contains_key(&key) {
+            rex_debug!(
+                "The response cookie `{}` is overwritten by a later write",
+                key
+            );
+        }
+
+        self.cookie.insert(key, cookie);
+    }
+
+    fn set_ext_mime_header(&mut self, path: &PathBuf) {
+        let mime_type = if let Some(ext) = path.extension() {
+            let file_extension = ext.to_string_lossy().to_lowercase();
+            ConnMetadata::get_mime_override(&file_extension)
+                .unwrap_or_else(|| default_mime_type_with_ext(&file_extension))
+        } else {
             String::from("text/plain")
         };
 
@@ -475,14 +1406,18 @@ impl Response {
 
 impl Reusable for Response {
     fn obtain() -> Box<Self> {
-        match unsafe { RESP_POOL.as_mut() } {
+        let resp: Box<Self> = match unsafe { RESP_POOL.as_mut() } {
             Ok(pool) => pool.get(),
             Err(_) => Default::default(),
-        }
+        };
+
+        debug_assert!(resp.is_blank(), "the pooled response is not reset");
+        resp
     }
 
     fn release(mut self: Box<Self>) {
         self.reset(false);
+        debug_assert!(self.is_blank(), "the response is not fully reset");
 
         if let Ok(pool) = unsafe { RESP_POOL.as_mut() } {
             pool.put(self);
@@ -493,25 +1428,65 @@ impl Reusable for Response {
         self.status = 0;
         self.keep_alive = KeepAliveStatus::NotSet;
 
-        if !hard {
-            unsafe {
-                self.content_type.as_mut_vec().set_len(0);
-                self.redirect.as_mut_vec().set_len(0);
-                self.body.set_len(0);
-            }
+        // cleared with their capacity kept for the next response, unless they've grown too large
+        // to be pinned in the pool, e.g. by a file download
+        self.redirect.clear();
+
+        if oversized(self.content_type.capacity(), hard) {
+            self.content_type = String::new();
         } else {
             self.content_type.clear();
-            self.redirect.clear();
+        }
+
+        if oversized(self.body.capacity(), hard) {
+            self.body = Vec::new();
+        } else {
             self.body.clear();
         }
 
+        if oversized(header_bytes(&self.header), hard) {
+            self.header = HashMap::new();
+        } else {
+            self.header.clear();
+        }
+
         if self.content_length.is_some() {
             self.content_length.take();
         }
 
+        if self.body_stream.is_some() {
+            self.body_stream.take();
+        }
+
+        if self.json_err.is_some() {
+            self.json_err.take();
+        }
+
+        self.stream_options = None;
+
+        #[cfg(feature = "compression")]
+        {
+            self.encoding = None;
+        }
+
         self.header_only = false;
-        self.header.clear();
+        self.stat_only = false;
+        self.size_hint = None;
         self.cookie.clear();
+        self.trailers.clear();
+        self.audit.clear();
+        self.cross_site = false;
+        self.views = None;
+
+        // the channels belong to the async body or the long connection of the previous response
+        self.body_chan = (None, None);
+        self.notifier = None;
+        self.subscriber = None;
+
+        #[cfg(feature = "websocket")]
+        {
+            self.websocket = None;
+        }
     }
 }
 
@@ -521,10 +1496,14 @@ pub trait ResponseStates {
     fn get_header(&self, key: &str) -> Option<&String>;
     fn get_cookie(&self, key: &str) -> Option<&Cookie>;
     fn get_content_type(&self) -> String;
+    fn get_status(&self) -> u16;
+    fn get_body_size(&self) -> Option<usize>;
     fn status_is_set(&self) -> bool;
     fn has_contents(&self) -> bool;
     fn is_header_only(&self) -> bool;
+    fn is_streaming(&self) -> bool;
     fn get_channels(&mut self) -> Result<(Sender<String>, Receiver<String>), &'static str>;
+    fn audit(&self) -> &[OverrideReason];
 }
 
 impl ResponseStates for Response {
@@ -553,6 +1532,32 @@ impl ResponseStates for Response {
         self.content_type.to_owned()
     }
 
+    #[inline]
+    fn get_status(&self) -> u16 {
+        self.status
+    }
+
+    /// The size in bytes of the body to be sent, before any compression, or `None` if it's not known
+    /// ahead, e.g. the body is streamed.
+    fn get_body_size(&self) -> Option<usize> {
+        if self.is_header_only() {
+            return Some(0);
+        }
+
+        if self.is_streaming() || self.is_long_conn() {
+            return None;
+        }
+
+        if let Some(length) = self.content_length.as_ref() {
+            return length.parse().ok();
+        }
+
+        match self.size_hint {
+            Some(size) => Some(size as usize),
+            None => Some(self.body.len()),
+        }
+    }
+
     fn status_is_set(&self) -> bool {
         match self.status {
             0 => false,
@@ -562,7 +1567,11 @@ impl ResponseStates for Response {
 
     #[inline]
     fn has_contents(&self) -> bool {
-        (self.is_header_only() || !self.body.is_empty() || self.body_chan.0.is_some())
+        (self.is_header_only()
+            || !self.body.is_empty()
+            || self.size_hint.is_some()
+            || self.body_chan.0.is_some()
+            || self.body_stream.is_some())
     }
 
     #[inline]
@@ -570,9 +1579,19 @@ impl ResponseStates for Response {
         self.header_only
     }
 
+    #[inline]
+    fn is_streaming(&self) -> bool {
+        self.body_stream.is_some()
+    }
+
     /// get_channels will create the channels for communicating between the chunk generator threads and
     /// the main stream. Listen to the receiver for any client communications, and use the sender to
     /// send any ensuing responses.
+    ///
+    /// The client sends its messages as lines, i.e. each ends with `\n` (or `\r\n`), and the receiver
+    /// gets each line as one message without the line break, however the bytes are split across
+    /// the reads. The blank lines are skipped, the invalid UTF-8 sequences are replaced, and the
+    /// connection is closed once a message exceeds `ServerConfig::set_long_conn_message_limit`.
     fn get_channels(&mut self) -> Result<(Sender<String>, Receiver<String>), &'static str> {
         if self.notifier.is_none() {
             self.notifier = Some(channel::bounded(64));
@@ -588,9 +1607,16 @@ impl ResponseStates for Response {
             }
         }
 
-        debug::print("Unable to create channels", InfoLevel::Warning);
+        rex_warn!("Unable to create channels");
         Err("Unable to create channels")
     }
+
+    /// What the framework has overridden on the response so far, and why, e.g. the error page
+    /// injected in place of an empty body. It's empty unless something has been overridden.
+    #[inline]
+    fn audit(&self) -> &[OverrideReason] {
+        &self.audit
+    }
 }
 
 pub trait ResponseWriter {
@@ -599,9 +1625,15 @@ pub trait ResponseWriter {
     fn set_header(&mut self, field: &str, value: &str);
     fn with_headers(&mut self, header: HashMap<String, String>);
     fn send(&mut self, content: &str);
+    fn send_with_charset(&mut self, content: &str, charset: &str);
     fn send_async(&mut self, f: fn() -> (Option<u16>, String));
+    fn stream<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static;
+    fn set_stream_options(&mut self, options: StreamOptions);
     fn send_file(&mut self, file_path: &str) -> u16;
     fn send_file_from_path(&mut self, path: PathBuf) -> u16;
+    fn send_file_range(&mut self, path: PathBuf, range: &str) -> u16;
     fn send_file_async(&mut self, file_loc: &str);
     fn send_file_from_path_async(&mut self, path: PathBuf);
     fn send_template<T: EngineContext + Send + Sync + 'static>(
@@ -609,13 +1641,25 @@ pub trait ResponseWriter {
         file_path: &str,
         context: Box<T>,
     ) -> u16;
+    fn send_template_with_layout<T: EngineContext + Send + Sync + 'static>(
+        &mut self,
+        file_path: &str,
+        layout: &str,
+        context: Box<T>,
+    ) -> u16;
     fn set_cookie(&mut self, cookie: Cookie);
     fn set_cookies(&mut self, cookie: &[Cookie]);
     fn clear_cookies(&mut self);
+    fn remove_cookie(&mut self, name: &str, path: &str, domain: &str) -> bool;
+    fn add_trailer(&mut self, field: &str, value: &str);
+    #[deprecated(since = "0.4.7", note = "use `keep_alive` instead")]
     fn can_keep_alive(&mut self, can_keep_alive: bool);
     fn keep_alive(&mut self, to_keep: bool);
     fn set_content_type(&mut self, content_type: &str);
     fn redirect(&mut self, path: &str);
+    fn redirect_with(&mut self, path: &str, status: u16);
+    fn redirect_preserve_method(&mut self, path: &str);
+    fn redirect_preserve_method_permanent(&mut self, path: &str);
 }
 
 impl ResponseWriter for Response {
@@ -627,11 +1671,14 @@ impl ResponseWriter for Response {
         self.status = match status {
             100..=101 => status,
             200..=206 => status,
-            300..=308 if status != 307 && status != 308 => status,
+            300..=308 => status,
             400..=417 if status != 402 => status,
             426 | 428 | 429 | 431 | 451 => status,
             500..=505 | 511 => status,
-            _ => 0,
+            _ => {
+                self.audit.push(OverrideReason::UnrecognizedStatus(status));
+                0
+            }
         };
     }
 
@@ -686,14 +1733,7 @@ impl ResponseWriter for Response {
                     return;
                 }
 
-                match &value.to_lowercase()[..] {
-                    "keep-alive" if self.keep_alive == KeepAliveStatus::NotSet => {
-                        self.keep_alive = KeepAliveStatus::KeepAlive
-                    }
-                    "tls" => self.keep_alive = KeepAliveStatus::TlsConn,
-                    "forbidden" => self.keep_alive = KeepAliveStatus::Forbidden,
-                    _ => { /* Otherwise, don't update the keep_alive field */ }
-                }
+                self.keep_alive_from_header(value);
             }
             _ => {
                 self.header
@@ -753,14 +1793,7 @@ impl ResponseWriter for Response {
         }
 
         if let Some(val) = header.remove("connection") {
-            match &val.to_lowercase()[..] {
-                "keep-alive" if self.keep_alive == KeepAliveStatus::NotSet => {
-                    self.keep_alive = KeepAliveStatus::KeepAlive
-                }
-                "tls" => self.keep_alive = KeepAliveStatus::TlsConn,
-                "forbidden" => self.keep_alive = KeepAliveStatus::Forbidden,
-                _ => { /* Otherwise, don't update the keep_alive field */ }
-            }
+            self.keep_alive_from_header(&val);
         }
 
         self.header = header;
@@ -786,7 +1819,7 @@ impl ResponseWriter for Response {
     /// }
     /// ```
     fn send(&mut self, content: &str) {
-        if self.is_header_only() {
+        if self.skips_body() {
             return;
         }
 
@@ -796,6 +1829,53 @@ impl ResponseWriter for Response {
         }
     }
 
+    /// Same as `send`, but the content is transcoded from UTF-8 to the charset, which is one of
+    /// `utf-8`, `iso-8859-1` (or `latin1`) and `windows-1252` (or `cp1252`), e.g. the one picked with
+    /// `Request::negotiate_charset`; the other charsets fall back to UTF-8. The charset is set as the
+    /// `charset` parameter of the content type, which is `text/plain` unless it's set beforehand,
+    /// and the body length is counted in the transcoded bytes.
+    ///
+    /// The characters the charset can't represent are written as the numeric character references,
+    /// e.g. `&#9749;`, if the content type is HTML, or `?` otherwise. All the contents sent to the
+    /// response shall be in the same charset.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rusty_express::prelude::*;
+    ///
+    /// pub fn legacy_handler(req: &Box<Request>, resp: &mut Box<Response>) {
+    ///     let charset = req
+    ///         .negotiate_charset(&["utf-8", "iso-8859-1"])
+    ///         .unwrap_or_else(|| String::from("utf-8"));
+    ///
+    ///     resp.set_content_type("text/html");
+    ///     resp.send_with_charset("<p>Caf\u{e9}</p>", &charset);
+    /// }
+    /// ```
+    fn send_with_charset(&mut self, content: &str, charset: &str) {
+        if self.skips_body() {
+            return;
+        }
+
+        let charset = Charset::lookup(charset).unwrap_or(Charset::Utf8);
+        let media_type = match self.content_type.split(';').next().map(str::trim) {
+            Some(media_type) if !media_type.is_empty() => media_type.to_owned(),
+            _ => String::from("text/plain"),
+        };
+
+        let html = media_type.eq_ignore_ascii_case("text/html")
+            || media_type.eq_ignore_ascii_case("application/xhtml+xml");
+
+        charset.encode_into(content, html, &mut self.body);
+        self.content_type = format!("{}; charset={}", media_type, charset.name());
+
+        if self.content_length.is_some() {
+            // the explicit content length is no longer valid, fix it up
+            self.content_length = Some(self.body.len().to_string());
+        }
+    }
+
     /// Send the response body in async mode. This means the closure or function supplied as the 1st
     /// parameter will be executed in parallel.
     ///
@@ -826,7 +1906,7 @@ impl ResponseWriter for Response {
     /// ```
     fn send_async(&mut self, f: fn() -> (Option<u16>, String)) {
         // if header only, quit
-        if self.is_header_only() {
+        if self.skips_body() {
             return;
         }
 
@@ -851,6 +1931,79 @@ impl ResponseWriter for Response {
         }
     }
 
+    /// Stream the response body to the client. The closure supplied as the 1st parameter will be
+    /// invoked when the response is written to the connection, and whatever it writes into the sink
+    /// will be sent to the client in chunks with `Transfer-Encoding: chunked`, such that the entire
+    /// body won't need to be held in memory. Any existing body content will be discarded.
+    ///
+    /// If the closure returns an error, the body will not be properly terminated, and the connection
+    /// will be closed after the write.
+    ///
+    /// # Examples
+    ///
+    /// Streaming a large file to the client:
+    ///
+    /// ```rust
+    /// use rusty_express::prelude::*;
+    /// use std::fs::File;
+    /// use std::io;
+    ///
+    /// pub fn simple_handler(req: &Box<Request>, resp: &mut Box<Response>) {
+    ///     resp.set_content_type("video/mp4");
+    ///     resp.stream(|sink| {
+    ///         let mut file = File::open("./static/movie.mp4")?;
+    ///         io::copy(&mut file, sink)?;
+    ///         Ok(())
+    ///     });
+    /// }
+    /// ```
+    fn stream<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        // if header only, quit
+        if self.skips_body() {
+            return;
+        }
+
+        if !self.body.is_empty() {
+            self.body.clear();
+        }
+
+        self.body_stream = Some(Box::new(f));
+    }
+
+    /// Set how the streamed body is flushed to the client, for both the body of `stream` and the
+    /// messages sent over the long connection, see `get_channels`. If not set, the former uses
+    /// `StreamOptions::bulk`, and the latter uses `StreamOptions::events`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rusty_express::prelude::*;
+    /// use std::io::Write;
+    /// use std::time::Duration;
+    ///
+    /// pub fn simple_handler(req: &Box<Request>, resp: &mut Box<Response>) {
+    ///     resp.set_content_type("text/csv");
+    ///     resp.set_stream_options(StreamOptions {
+    ///         flush_every: Some(Duration::from_millis(100)),
+    ///         ..StreamOptions::bulk()
+    ///     });
+    ///
+    ///     resp.stream(|sink| {
+    ///         for row in 0..1000 {
+    ///             writeln!(sink, "{},{}", row, row * row)?;
+    ///         }
+    ///
+    ///         Ok(())
+    ///     });
+    /// }
+    /// ```
+    fn set_stream_options(&mut self, options: StreamOptions) {
+        self.stream_options = Some(options);
+    }
+
     /// Send a static file as part of the response to the client. Return the http
     /// header status that can be set directly to the response object using:
     ///
@@ -880,7 +2033,7 @@ impl ResponseWriter for Response {
     /// content type based on file extension. You can always reset the value for
     /// this auto-generated content type response attribute.
     fn send_file(&mut self, file_loc: &str) -> u16 {
-        if self.is_header_only() {
+        if self.skips_body() {
             return 200;
         }
 
@@ -893,7 +2046,9 @@ impl ResponseWriter for Response {
     }
 
     fn send_file_from_path(&mut self, path: PathBuf) -> u16 {
-        if self.is_header_only() {
+        self.set_file_validators(&path);
+
+        if self.skips_body() || self.stat_file(&path) {
             return 200;
         }
 
@@ -912,6 +2067,72 @@ impl ResponseWriter for Response {
         status
     }
 
+    /// Send a window of a static file as part of the response to the client, where the window is
+    /// specified by the value of a `Range` request header, e.g. `bytes=0-1023`. Only the requested
+    /// bytes will be read from the file. Return the http status code that shall be set to the
+    /// response: 206 if the range is served, 416 if the range is malformed or can't be satisfied,
+    /// and 404 or 500 if the file can't be read.
+    ///
+    /// If multiple ranges are requested, only the first one will be served. The `Accept-Ranges`,
+    /// and if applicable, the `Content-Range` headers will be set to the response.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rusty_express::prelude::*;
+    /// use std::path::PathBuf;
+    ///
+    /// pub fn simple_handler(req: &Box<Request>, resp: &mut Box<Response>) {
+    ///     let path = PathBuf::from("./static/movie.mp4");
+    ///
+    ///     let status = match req.header("range") {
+    ///         Some(range) => resp.send_file_range(path, &range),
+    ///         None => resp.send_file_from_path(path),
+    ///     };
+    ///
+    ///     resp.status(status);
+    /// }
+    /// ```
+    fn send_file_range(&mut self, path: PathBuf, range: &str) -> u16 {
+        self.header("Accept-Ranges", "bytes", true);
+
+        if self.skips_body() {
+            return 200;
+        }
+
+        let total = match fs::metadata(&path) {
+            Ok(ref meta) if meta.is_file() => meta.len(),
+            _ => return 404,
+        };
+
+        let (start, end) = match parse_range(range, total) {
+            Some(window) => window,
+            None => {
+                self.header("Content-Range", &format!("bytes */{}", total), true);
+                return 416;
+            }
+        };
+
+        let status = open_file_range(&path, start, end, &mut self.body);
+
+        if status != 206 {
+            // if not opening the file correctly, reset the body for error page
+            self.body.clear();
+        } else {
+            self.header(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", start, end, total),
+                true,
+            );
+
+            if self.content_type.is_empty() {
+                self.set_ext_mime_header(&path);
+            }
+        }
+
+        status
+    }
+
     fn send_file_async(&mut self, file_loc: &str) {
         if let Some(path) = get_file_path(file_loc) {
             self.send_file_from_path_async(path);
@@ -919,8 +2140,10 @@ impl ResponseWriter for Response {
     }
 
     fn send_file_from_path_async(&mut self, path: PathBuf) {
+        self.set_file_validators(&path);
+
         // if header only, quit
-        if self.is_header_only() {
+        if self.skips_body() || self.stat_file(&path) {
             return;
         }
 
@@ -930,8 +2153,10 @@ impl ResponseWriter for Response {
             self.body_chan = (Some(tx), Some(rx));
         }
 
-        // set header's mime extension field
-        self.set_ext_mime_header(&path);
+        // set header's mime extension field, unless the handler has set one
+        if self.content_type.is_empty() {
+            self.set_ext_mime_header(&path);
+        }
 
         // actually load the file to the response body
         if let Some(chan) = self.body_chan.0.as_ref() {
@@ -944,34 +2169,34 @@ impl ResponseWriter for Response {
         file_path: &str,
         context: Box<T>,
     ) -> u16 {
-        if self.is_header_only() {
+        if self.skips_body() {
             return 200;
         }
         if file_path.is_empty() {
             return 404;
         }
 
-        if let Some(path) = get_file_path(file_path) {
-            if !path.is_file() {
-                return 404;
-            }
-
-            let mut ext = String::new();
-            if let Some(os_ext) = path.extension() {
-                if let Some(str_ext) = os_ext.to_str() {
-                    ext = str_ext.to_owned();
-                }
-            }
+        // the templates are resolved against the first of: the root of the route's views scope,
+        // the views root, and the document root
+        let scope = self.views.clone();
+        let root = match scope.as_ref() {
+            Some(scope) => Some(scope.root.clone()),
+            None => ServerConfig::get_views_root().or_else(ConnMetadata::get_doc_root),
+        };
 
-            if ext.is_empty() {
+        if let Some(path) = resolve_file_path(file_path, root) {
+            if path.extension().map_or(true, |ext| ext.is_empty()) {
                 return 404;
             }
 
             let mut content = Vec::new();
             open_file(&path, &mut content);
 
-            // Now render the conent with the engine
-            let (status, final_content) = ServerConfig::template_parser(&ext[..], content, context);
+            // Now render the conent with the engine of the file's extension, or the default engine
+            // of the route's views scope if the extension has none
+            let fallback = scope.as_ref().and_then(|s| s.default_engine_ext.as_deref());
+            let (status, final_content) =
+                ServerConfig::template_parser(&path, content, context, fallback);
 
             if status == 0 || status == 200 {
                 self.body = final_content;
@@ -987,43 +2212,112 @@ impl ResponseWriter for Response {
         }
     }
 
+    /// Render the template like `send_template`, then place the page into the layout of the name,
+    /// which the engine obtains from `EngineContext::layout`. The name overrides the one that the
+    /// context may provide by itself.
+    fn send_template_with_layout<T: EngineContext + Send + Sync + 'static>(
+        &mut self,
+        file_path: &str,
+        layout: &str,
+        context: Box<T>,
+    ) -> u16 {
+        let context = Box::new(WithLayout {
+            layout: layout.to_owned(),
+            inner: context,
+        });
+
+        self.send_template(file_path, context)
+    }
+
+    /// Set the cookie to the response. The cookies are kept by their names, and the later write
+    /// always wins: setting a cookie with the same name as an existing one replaces it, regardless
+    /// of the path or the domain. Invalid cookies are ignored.
     fn set_cookie(&mut self, cookie: Cookie) {
         if !cookie.is_valid() {
             return;
         }
 
-        let key = cookie.get_cookie_key();
-        self.cookie.insert(key, cookie);
+        self.insert_cookie(cookie);
     }
 
+    /// Set the cookies to the response in the order of the slice, such that if the slice contains
+    /// cookies with the same name, the last one wins, the same as calling `set_cookie` on each of
+    /// them in turn.
     fn set_cookies(&mut self, cookies: &[Cookie]) {
         for cookie in cookies.iter() {
             if !cookie.is_valid() {
                 continue;
             }
 
-            let key = cookie.get_cookie_key();
-            self.cookie.insert(key, cookie.clone());
+            self.insert_cookie(cookie.clone());
         }
     }
 
+    /// Clear the cookies set to the response so far. The cookies sent with the request are not
+    /// affected.
     fn clear_cookies(&mut self) {
         self.cookie.clear();
     }
 
+    /// Remove the cookie set to the response so far, if its name, path and domain all match the
+    /// given ones, where an empty path or domain matches a cookie without the attribute. Returns
+    /// if a cookie has been removed.
+    fn remove_cookie(&mut self, name: &str, path: &str, domain: &str) -> bool {
+        let matched = self.cookie.get(name).map_or(false, |cookie| {
+            cookie.get_path() == path && cookie.get_domain() == domain
+        });
+
+        if matched {
+            self.cookie.remove(name);
+        }
+
+        matched
+    }
+
+    /// Add a trailer field, which will be sent after the last chunk of the body when the response
+    /// is streamed or served over a long connection, and declared in the `Trailer` header. Adding
+    /// the same field again will override the existing value. Fields that control the framing of
+    /// the message, e.g. `Content-Length` or `Transfer-Encoding`, can't be sent as trailers and will
+    /// be ignored.
+    fn add_trailer(&mut self, field: &str, value: &str) {
+        let field = field.trim();
+        if field.is_empty() {
+            return;
+        }
+
+        match &field.to_lowercase()[..] {
+            "content-length" | "transfer-encoding" | "trailer" | "host" | "content-type"
+            | "set-cookie" => {
+                rex_warn!("Field '{}' is not allowed as a trailer", field);
+                return;
+            }
+            _ => {}
+        }
+
+        let value = value.trim().to_owned();
+        match self
+            .trailers
+            .iter_mut()
+            .find(|(f, _)| f.eq_ignore_ascii_case(field))
+        {
+            Some(existing) => existing.1 = value,
+            None => self.trailers.push((field.to_owned(), value)),
+        }
+    }
+
+    /// Deprecated alias of `keep_alive`. Unlike before, it can't turn a connection the framework
+    /// has decided to close, e.g. a TLS connection, back on.
     #[inline]
     fn can_keep_alive(&mut self, can_keep_alive: bool) {
-        self.keep_alive = if !can_keep_alive {
-            KeepAliveStatus::Forbidden
-        } else {
-            KeepAliveStatus::NotSet
-        }
+        self.keep_alive(can_keep_alive);
     }
 
+    /// Ask to keep the connection alive after the response, or to close it. Setting the
+    /// `Connection` header to `keep-alive` or `close` does the same. The request is ignored if the
+    /// framework has decided to close the connection, i.e. the connection is over TLS, or the
+    /// request asks to close the connection, which the handler can't override.
     fn keep_alive(&mut self, to_keep: bool) {
-        if self.keep_alive == KeepAliveStatus::TlsConn
-            || self.keep_alive == KeepAliveStatus::Forbidden
-        {
+        if self.keep_alive.is_reserved() {
             return;
         }
 
@@ -1045,14 +2339,52 @@ impl ResponseWriter for Response {
     fn redirect(&mut self, path: &str) {
         self.redirect = path.to_owned();
     }
-}
+
+    /// Redirect to the path with the given status, which shall be one of the redirect statuses, i.e.
+    /// `301`, `302`, `303`, `307` or `308`, otherwise the default `301` is used.
+    fn redirect_with(&mut self, path: &str, status: u16) {
+        self.redirect(path);
+
+        match status {
+            301 | 302 | 303 | 307 | 308 => self.status(status),
+            _ => self.status(301),
+        }
+    }
+
+    /// Temporarily redirect to the path with `307 Temporary Redirect`, such that the client shall
+    /// repeat the request with the same method and body, e.g. a `POST` is not turned into a `GET`.
+    #[inline]
+    fn redirect_preserve_method(&mut self, path: &str) {
+        self.redirect_with(path, 307);
+    }
+
+    /// Same as `redirect_preserve_method`, but the redirect is permanent, i.e. with the status
+    /// `308 Permanent Redirect`.
+    #[inline]
+    fn redirect_preserve_method_permanent(&mut self, path: &str) {
+        self.redirect_with(path, 308);
+    }
+}
 
 pub(crate) trait ResponseManager {
     fn header_only(&mut self, header_only: bool);
+    fn stat_only(&mut self, stat_only: bool);
+    fn status_sent(&self) -> u16;
+    fn validate_conditional(&mut self, request: &Box<Request>);
+    fn negotiate_err_format(&mut self, request: &Box<Request>);
+    fn negotiate_encoding(&mut self, request: &Box<Request>);
     fn validate_and_update(&mut self);
+    fn validate_and_update_for(&mut self, request: Option<&Box<Request>>);
+    fn put_header(&mut self, buffer: &mut BufWriter<&mut Stream>);
     fn write_header(&mut self, buffer: &mut BufWriter<&mut Stream>) -> bool;
-    fn write_body(&self, buffer: &mut BufWriter<&mut Stream>) -> bool;
-    fn keep_long_conn(&mut self, clone: Stream, buffer: &mut BufWriter<&mut Stream>);
+    fn is_coalescible(&self) -> bool;
+    fn write_body(&mut self, buffer: &mut BufWriter<&mut Stream>) -> bool;
+    fn keep_long_conn(
+        &mut self,
+        clone: Option<Stream>,
+        feed: Option<Receiver<Vec<u8>>>,
+        buffer: &mut BufWriter<&mut Stream>,
+    );
 }
 
 impl ResponseManager for Response {
@@ -1061,12 +2393,119 @@ impl ResponseManager for Response {
         self.header_only = header_only;
     }
 
+    /// The response is to a HEAD request, so the files sent by the handler only need their sizes.
+    #[inline]
+    fn stat_only(&mut self, stat_only: bool) {
+        self.stat_only = stat_only;
+    }
+
+    /// The status in the status line of the response: the one set explicitly, or else 200 if the
+    /// response has contents, and 404 otherwise.
+    fn status_sent(&self) -> u16 {
+        match self.status {
+            0 if self.has_contents() => 200,
+            0 => 404,
+            status => status,
+        }
+    }
+
+    /// Check the validators of the response against the conditional headers of the request, i.e.
+    /// `If-None-Match` and `If-Modified-Since`, and if the client's copy is still fresh, turn the
+    /// response into a `304 Not Modified` with an empty body, which keeps the validators set on the
+    /// response, and the length of the body it stands for, if that's known.
+    fn validate_conditional(&mut self, request: &Box<Request>) {
+        if self.status != 0 && self.status != 200 {
+            return;
+        }
+
+        match request.method {
+            REST::GET | REST::HEAD => {}
+            _ => return,
+        }
+
+        let fresh = if let Some(tags) = request.header("if-none-match") {
+            // if-none-match takes precedence, and if-modified-since shall be ignored
+            match self.header.get("etag") {
+                Some(etag) => etag_matches(&tags, etag),
+                None => false,
+            }
+        } else if let Some(since) = request.header("if-modified-since") {
+            match self.header.get("last-modified") {
+                Some(modified) => not_modified_since(&since, modified),
+                None => false,
+            }
+        } else {
+            false
+        };
+
+        if fresh {
+            // keep the length of the representation for the caches, unless it's only known once
+            // the body is streamed, collected or compressed
+            #[cfg(feature = "compression")]
+            let compressible = ConnMetadata::get_compression().is_some();
+            #[cfg(not(feature = "compression"))]
+            let compressible = false;
+
+            if self.content_length.is_none() && !compressible {
+                self.content_length = self
+                    .size_hint
+                    .or_else(|| Some(self.body.len() as u64).filter(|len| *len > 0))
+                    .map(|len| len.to_string());
+            }
+
+            self.status(304);
+            self.header_only(true);
+            self.body.clear();
+            self.size_hint = None;
+            self.body_chan = (None, None);
+            self.body_stream.take();
+        }
+    }
+
+    /// If the client prefers JSON over HTML per the `Accept` header of the request, the error page
+    /// generated by the framework will be a JSON object instead of the default HTML page. The object
+    /// carries the `request_id`, i.e. the trace ids of the request, to match it with the log lines.
+    fn negotiate_err_format(&mut self, request: &Box<Request>) {
+        if let Some(accept) = request.header("accept") {
+            if prefers_json(&accept) {
+                self.json_err = Some((request.uri.to_owned(), request.trace_ids()));
+            }
+        }
+    }
+
+    /// Pick the encoding to compress the response body with, from the `Accept-Encoding` header of
+    /// the request. This is a no-op unless the compression policy has been set.
+    fn negotiate_encoding(&mut self, request: &Box<Request>) {
+        #[cfg(feature = "compression")]
+        {
+            if ConnMetadata::get_compression().is_none() {
+                return;
+            }
+
+            if let Some(accept) = request.header("accept-encoding") {
+                self.encoding = accepted_encoding(&accept);
+            }
+        }
+    }
+
     fn validate_and_update(&mut self) {
+        self.validate_and_update_for(None);
+    }
+
+    /// Same as `validate_and_update`, and the error page is generated for the request, if there's
+    /// one, see `ServerConfig::set_request_status_page`.
+    fn validate_and_update_for(&mut self, request: Option<&Box<Request>>) {
         if self.status != 0 && (self.status < 200 || self.status == 204 || self.status == 304) {
+            if !self.header_only && self.has_contents() {
+                self.audit.push(OverrideReason::HeaderOnly(self.status));
+            }
+
             self.header_only(true);
         }
 
-        if !self.is_header_only() && self.body_chan.1.is_some() {
+        // header-only responses still collect the async bodies, since HEAD requests shall get the
+        // same content length as the GET requests
+        if self.body_chan.1.is_some() {
             // manual drop the transmission channel so we won't hang forever. this only drops the origin
             // channel, all clones (which must have been created before reaching this point) can still
             // be valid at this point, and the rx loop will either enter or break after the last one
@@ -1084,6 +2523,7 @@ impl ResponseManager for Response {
                         }
                     } else {
                         // faulty, clear the content
+                        self.audit.push(OverrideReason::AsyncBodyFailed(received.1));
                         self.status = 500;
                         self.body.clear();
                         break;
@@ -1092,38 +2532,78 @@ impl ResponseManager for Response {
             }
         }
 
-        // if contents have been provided, we're all good.
+        // redirects only need the `Location` header, don't inject the error page as the body
+        if (300..400).contains(&self.status)
+            && self.header.contains_key("location")
+            && self.body.is_empty()
+            && self.body_stream.is_none()
+        {
+            self.header_only(true);
+        }
+
+        // if contents have been provided, we're all good; and a header-only response keeps its
+        // status without the error page, which wouldn't be sent anyway.
         if self.has_contents() {
+            if self.status == 0 {
+                self.audit.push(OverrideReason::InferredStatus(200));
+            }
+
             return;
         }
 
         // if not setting the header only and not having a body, it's a failure
-        match self.status {
-            0 | 404 => {
-                if let Some(page_generator) = ConnMetadata::get_status_pages(404) {
-                    self.body = page_generator().into_bytes();
-                } else {
-                    self.body = Vec::from(FOUR_OH_FOUR.as_bytes());
-                }
-            }
-            401 => {
-                if let Some(page_generator) = ConnMetadata::get_status_pages(401) {
-                    self.body = page_generator().into_bytes();
-                } else {
-                    self.body = Vec::from(FOUR_OH_ONE.as_bytes());
-                }
-            }
-            _ => {
-                if let Some(page_generator) = ConnMetadata::get_status_pages(500) {
-                    self.body = page_generator().into_bytes();
-                } else {
-                    self.body = Vec::from(FIVE_HUNDRED.as_bytes());
-                }
-            }
+        let status = match self.status {
+            0 => 404,
+            status => status,
+        };
+
+        self.audit.push(OverrideReason::ErrorPage(status));
+
+        // custom pages always take precedence, though they're only for the errors, not for the
+        // other responses left without a body
+        let page = match status {
+            400..=599 => ConnMetadata::get_status_page(status, request),
+            _ => None,
+        };
+
+        if let Some((page, content_type)) = page {
+            self.body = page;
+            self.set_content_type(content_type.as_deref().unwrap_or("text/html"));
+            return;
+        }
+
+        if let Some((path, ids)) = self.json_err.take() {
+            let status = if self.status == 0 { 404 } else { self.status };
+            let reason = status_reason(status);
+
+            self.body = format!(
+                "{{\"status\":{},\"error\":\"{}\",\"path\":\"{}\",\"request_id\":\"{}\"}}",
+                status,
+                json_escape(reason),
+                json_escape(&path),
+                ids
+            )
+            .into_bytes();
+
+            self.set_content_type("application/json");
+            return;
         }
+
+        self.body = Vec::from(default_page(status));
     }
 
-    fn write_header(&mut self, buffer: &mut BufWriter<&mut Stream>) -> bool {
+    /// Serialize the header into the buffer, without flushing it to the stream yet.
+    fn put_header(&mut self, buffer: &mut BufWriter<&mut Stream>) {
+        // the interim response is the status line alone
+        if self.is_interim() {
+            write_to_buff(buffer, &get_status(self.status));
+            write_to_buff(buffer, &HEADER_END);
+            return;
+        }
+
+        #[cfg(feature = "compression")]
+        self.compress_body();
+
         // write the headers
         self.write_resp_header(buffer);
 
@@ -1131,12 +2611,44 @@ impl ResponseManager for Response {
 
         // Blank line to indicate the end of the response header
         write_to_buff(buffer, &HEADER_END);
+    }
+
+    fn write_header(&mut self, buffer: &mut BufWriter<&mut Stream>) -> bool {
+        self.put_header(buffer);
 
         // flush what we got so far
-        buffer.flush().is_ok()
+        flush_with_retry(buffer).is_ok()
+    }
+
+    /// If the header and the body can be written out together: the body is all in memory, i.e. it's
+    /// neither streamed nor kept sending over a long connection, and the response isn't a bare
+    /// header, e.g. an interim one or the websocket handshake.
+    fn is_coalescible(&self) -> bool {
+        #[cfg(feature = "websocket")]
+        {
+            if self.is_websocket() {
+                return false;
+            }
+        }
+
+        !self.is_interim() && !self.is_header_only() && !self.is_streaming() && !self.is_long_conn()
     }
 
-    fn write_body(&self, buffer: &mut BufWriter<&mut Stream>) -> bool {
+    fn write_body(&mut self, buffer: &mut BufWriter<&mut Stream>) -> bool {
+        if let Some(f) = self.body_stream.take() {
+            // stream the body in chunks, the sink will be terminated with the last chunk
+            let mut sink = ChunkedWriter::new(buffer, self.stream_options.unwrap_or_default());
+
+            return match f(&mut sink) {
+                Ok(()) => sink.finish(&self.trailers).is_ok(),
+                Err(e) => {
+                    rex_warn!("Failed to stream the response body: {}", e);
+
+                    false
+                }
+            };
+        }
+
         if self.has_contents() {
             // the content length should have been set in the header, see function resp_header
             write_to_buff(buffer, &self.body);
@@ -1145,63 +2657,92 @@ impl ResponseManager for Response {
             stream_default_body(self.status, buffer);
         }
 
-        buffer.flush().is_ok()
+        flush_with_retry(buffer).is_ok()
     }
 
-    fn keep_long_conn(&mut self, stream_clone: Stream, buffer: &mut BufWriter<&mut Stream>) {
-        if self.has_contents() {
-            // the content length should have been set in the header, see function resp_header
-            stream_trunk(&self.body, buffer);
-        }
+    /// Serve the body over the long connection: every message from the notifier channel is sent as
+    /// a chunk, and the subscriber channel will receive whatever the client sends in the meantime.
+    /// The client's bytes come from the `feed` if the connection has a reader of its own, which is
+    /// then the only one reading the stream, or else from the stream clone. The body is terminated
+    /// when an empty message is received from the notifier, or when the notifier has been quiet
+    /// for `LONG_CONN_TIMEOUT`.
+    fn keep_long_conn(
+        &mut self,
+        stream_clone: Option<Stream>,
+        feed: Option<Receiver<Vec<u8>>>,
+        buffer: &mut BufWriter<&mut Stream>,
+    ) {
+        let done = Arc::new(AtomicBool::new(false));
+
+        // registered for the server to close the connection on shutdown, until the body is over;
+        // the TLS streams can't be cloned, and are ended by the notifier alone
+        let _token = ServerStates::register_long_conn(
+            stream_clone
+                .as_ref()
+                .and_then(|stream| stream.try_clone().ok()),
+            self.notifier.as_ref().map(|(tx, _)| tx.clone()),
+        );
 
-        // set read time-out to 16 seconds
-        if let Err(e) = stream_clone.set_read_timeout(Some(LONG_CONN_TIMEOUT)) {
-            debug::print(
-                &format!(
+        if let (Some(stream_clone), Some(sub)) = (stream_clone, self.subscriber.as_ref()) {
+            // set read time-out, such that the reader can check if the body has ended; the stream
+            // is left alone if it's read by the connection's reader, which shares the time-out.
+            let ready = match feed {
+                Some(_) => Ok(()),
+                None => stream_clone.set_read_timeout(Some(LONG_CONN_TIMEOUT)),
+            };
+
+            if let Err(e) = ready {
+                rex_warn!(
                     "Failed to establish a reading channel on a keep-alive stream: {}",
                     e
-                ),
-                InfoLevel::Warning,
-            );
-            return;
+                );
+            } else {
+                // spawn a new thread to listen to the read stream for any new communications
+                broadcast_new_communications(sub.0.clone(), stream_clone, feed, Arc::clone(&done));
+            }
         }
 
-        if let Some(sub) = self.subscriber.as_ref() {
-            // spawn a new thread to listen to the read stream for any new communications
-            broadcast_new_communications(sub.0.clone(), stream_clone);
+        // the initial content goes out first, then the replies from the server routes
+        let options = self.stream_options.unwrap_or_else(StreamOptions::events);
+        let mut sink = ChunkedWriter::new(buffer, options);
+        let notifier = self.notifier.as_ref().map(|(_, rx)| rx);
+
+        if let Err(e) = relay_messages(&mut sink, &self.body, notifier) {
+            rex_warn!("Failed to send the message over the long connection: {}", e);
         }
 
-        if let Some(ref notifier) = self.notifier {
-            // listen to any replies from the server routes
-            while let Ok(mut message) = notifier.1.recv_timeout(LONG_CONN_TIMEOUT) {
-                stream_trunk(unsafe { message.as_mut_vec() }, buffer);
-                if message.is_empty() {
-                    // if a 0-length reply, then we're done after the reply and shall break out
-                    return;
-                }
-            }
+        // the last chunk, so the client knows the message is complete
+        if let Err(e) = sink.finish(&self.trailers) {
+            rex_warn!("Failed to terminate the long connection: {}", e);
         }
+
+        // stop the reader once the message is over
+        done.store(true, atomic::Ordering::Release);
     }
 }
 
 pub(crate) fn init_pools() {
+    SHRINK_THRESHOLD.store(
+        ConnMetadata::get_pool_shrink_threshold(),
+        atomic::Ordering::Relaxed,
+    );
+
     unsafe {
         REQ_POOL.set(SyncPool::new());
         RESP_POOL.set(SyncPool::new());
         POOL_CHAN.set(channel::bounded(0))
     }
 
-    thread::spawn(|| {
+    let refill = shared_pool::thread_builder(String::from("rex-pool-refill"));
+    let result = refill.spawn(|| {
         let cap = TOTAL_ELEM_COUNT / 5;
         let mut count = 0;
 
         loop {
-            thread::sleep(Duration::from_secs(1));
-            count += 1;
-
+            // wake up every second, or quit as soon as the statics are to be dropped
             if let Ok(chan) = unsafe { POOL_CHAN.as_ref() } {
-                match chan.1.try_recv() {
-                    Ok(_) | Err(TryRecvError::Disconnected) => return,
+                match chan.1.recv_timeout(Duration::from_secs(1)) {
+                    Ok(_) | Err(RecvTimeoutError::Disconnected) => return,
                     _ => {}
                 }
             } else {
@@ -1209,139 +2750,495 @@ pub(crate) fn init_pools() {
                 return;
             }
 
-            if count % 30 == 0 {
-                if let Ok(pool) = unsafe { REQ_POOL.as_mut() } {
-                    if pool.len() < cap {
-                        pool.refill(cap);
-                    }
+            count += 1;
+            let refill = count % 30 == 0;
+
+            // grow the pools right away if they keep missing under load, and top them up every
+            // 30 seconds
+            if let Ok(pool) = unsafe { REQ_POOL.as_mut() } {
+                if pool.adapt() {
+                    rex_debug!("Request pool expanded to {} slots", pool.capacity());
                 }
 
-                if let Ok(pool) = unsafe { RESP_POOL.as_mut() } {
-                    if pool.len() < cap {
-                        pool.refill(cap);
-                    }
+                if refill && pool.len() < cap {
+                    pool.refill(cap);
                 }
+            }
+
+            if let Ok(pool) = unsafe { RESP_POOL.as_mut() } {
+                if pool.adapt() {
+                    rex_debug!("Response pool expanded to {} slots", pool.capacity());
+                }
+
+                if refill && pool.len() < cap {
+                    pool.refill(cap);
+                }
+            }
 
+            if refill {
                 count = 0;
             }
         }
     });
+
+    if let Err(e) = result {
+        rex_error!("Unable to launch the pool refill thread: {}", e);
+    }
+}
+
+/// The occupancy and the efficiency of the `Request` and the `Response` pools, or empty ones if
+/// the pools are not initialized.
+pub(crate) fn store_stats() -> (StoreStats, StoreStats) {
+    let req = unsafe { REQ_POOL.as_ref() }.map_or_else(|_| StoreStats::default(), pool_stats);
+    let resp = unsafe { RESP_POOL.as_ref() }.map_or_else(|_| StoreStats::default(), pool_stats);
+
+    (req, resp)
+}
+
+fn pool_stats<T: Default>(pool: &SyncPool<T>) -> StoreStats {
+    let misses = pool.miss_stats();
+
+    StoreStats {
+        available: pool.len(),
+        capacity: pool.capacity(),
+        gets: misses.gets,
+        get_misses: misses.get_misses,
+        put_drops: misses.put_drops,
+    }
 }
 
 pub(crate) fn drop_statics() {
     unsafe {
         if let Ok(chan) = POOL_CHAN.as_ref() {
-            // zero-sized channel will block until the message is read, which shall happen evey second.
+            // zero-sized channel will block until the message is read, which the refill thread is
+            // waiting for.
             chan.0.send(()).unwrap_or_default();
         }
 
-        // take the pools out
-        let _ = POOL_CHAN.take();
-        let mut req_pool = REQ_POOL.take();
-        let mut resp_pool = RESP_POOL.take();
+        // take the pools out and drop them, only once: the statics are left empty
+        drop(POOL_CHAN.take());
+        drop(REQ_POOL.take());
+        drop(RESP_POOL.take());
+    }
+}
+
+/// The sink of the streamed body, which frames the content written into it as chunks of the
+/// `Transfer-Encoding: chunked` body, and flushes them to the client as the options say.
+/// The rendering context with the layout name provided by `send_template_with_layout`.
+struct WithLayout<T: EngineContext> {
+    layout: String,
+    inner: Box<T>,
+}
+
+impl<T: EngineContext> EngineContext for WithLayout<T> {
+    fn display(&self, field: &str) -> Result<String, String> {
+        self.inner.display(field)
+    }
+
+    fn get_value(&self, path: &str) -> Option<ContextValue> {
+        self.inner.get_value(path)
+    }
+
+    fn layout(&self) -> Option<String> {
+        Some(self.layout.clone())
+    }
+}
+
+struct ChunkedWriter<'a, W: Write> {
+    buffer: &'a mut W,
+    chunk: Vec<u8>,
+    options: StreamOptions,
+    unflushed: usize,
+    pending_since: Option<Instant>,
+}
+
+impl<'a, W: Write> ChunkedWriter<'a, W> {
+    fn new(buffer: &'a mut W, options: StreamOptions) -> Self {
+        ChunkedWriter {
+            buffer,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            options,
+            unflushed: 0,
+            pending_since: None,
+        }
+    }
+
+    /// Buffer the content of one send, and flush what's buffered if any of the options asks for it.
+    fn send(&mut self, content: &[u8]) -> io::Result<()> {
+        // an empty chunk would end the body, the last chunk is written by `finish`
+        if content.is_empty() {
+            return Ok(());
+        }
+
+        self.chunk.extend_from_slice(content);
+        self.unflushed += content.len();
+        self.pending_since.get_or_insert_with(Instant::now);
+
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+
+        let bytes = self.options.flush_every_bytes;
+        if self.options.flush_on_each_send || (bytes > 0 && self.unflushed >= bytes) {
+            return self.flush();
+        }
+
+        self.tick()
+    }
+
+    /// Flush what's buffered if it has waited for `StreamOptions::flush_every`.
+    fn tick(&mut self) -> io::Result<()> {
+        match self.until_flush() {
+            Some(left) if left == Duration::from_secs(0) => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// How long until what's buffered is due for a flush, if anything is buffered and the flush
+    /// interval is set.
+    fn until_flush(&self) -> Option<Duration> {
+        let since = self.pending_since?;
+        let every = self.options.flush_every?;
+
+        Some(every.checked_sub(since.elapsed()).unwrap_or_default())
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        // chunk size in hex, then the chunk data, each ends with a line break
+        write!(self.buffer, "{:x}\r\n", self.chunk.len())?;
+        self.buffer.write_all(&self.chunk)?;
+        self.buffer.write_all(&HEADER_END)?;
+
+        self.chunk.clear();
+        Ok(())
+    }
+
+    fn finish(mut self, trailers: &[(String, String)]) -> io::Result<()> {
+        self.write_chunk()?;
+        write_last_chunk(self.buffer, trailers)
+    }
+}
+
+impl<'a, W: Write> Write for ChunkedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.unflushed = 0;
+        self.pending_since = None;
 
-        // drop them in place
-        ptr::drop_in_place(&mut req_pool as *mut Option<SyncPool<Request>>);
-        ptr::drop_in_place(&mut resp_pool as *mut Option<SyncPool<Response>>);
+        flush_with_retry(self.buffer)
     }
 }
 
-fn broadcast_new_communications(sender: Sender<String>, mut stream_clone: Stream) {
-    thread::spawn(move || {
+/// Relay the client's messages to the subscriber, which are read from the stream clone, or passed
+/// on over the `feed` by the connection's reader. The stream clone is shut down if a message
+/// exceeds the limit.
+fn broadcast_new_communications(
+    sender: Sender<String>,
+    mut stream_clone: Stream,
+    feed: Option<Receiver<Vec<u8>>>,
+    done: Arc<AtomicBool>,
+) {
+    let limit = ConnMetadata::get_message_limit();
+    let reader = shared_pool::thread_builder(String::from("rex-keep-alive-reader"));
+    let result = reader.spawn(move || {
         let mut buffer = [0u8; 512];
+        let mut framer = LineFramer::new(limit);
 
         loop {
+            if done.load(atomic::Ordering::Acquire) {
+                // the body has been terminated, nothing more to listen to
+                break;
+            }
+
             if let Err(e) = stream_clone.take_error() {
-                debug::print(
-                    &format!("Keep-alive stream can't continue: {}", e),
-                    InfoLevel::Warning,
-                );
+                rex_warn!("Keep-alive stream can't continue: {}", e);
                 break;
             }
 
-            if let Err(e) = stream_clone.read(&mut buffer) {
-                debug::print(
-                    &format!("Unable to continue reading from a keep-alive stream: {}", e),
-                    InfoLevel::Warning,
-                );
+            let received;
+            let bytes: &[u8] = match feed.as_ref() {
+                Some(feed) => match feed.recv_timeout(LONG_CONN_TIMEOUT) {
+                    Ok(bytes) => {
+                        received = bytes;
+                        &received
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match stream_clone.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(size) => &buffer[..size],
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        continue;
+                    }
+                    Err(e) => {
+                        rex_warn!("Unable to continue reading from a keep-alive stream: {}", e);
+                        break;
+                    }
+                },
+            };
+
+            if done.load(atomic::Ordering::Acquire) {
                 break;
             }
 
-            if let Ok(result) = str::from_utf8(&buffer) {
-                if result.is_empty() {
-                    continue;
+            let messages = match framer.feed(bytes) {
+                Some(messages) => messages,
+                None => {
+                    rex_warn!(
+                        "The message on a keep-alive stream exceeds the limit of {} bytes, closing the connection",
+                        limit
+                    );
+
+                    stream_clone.shutdown(Shutdown::Both).unwrap_or_default();
+                    break;
                 }
+            };
 
-                if let Err(err) = sender.send(result.to_owned()) {
+            for message in messages {
+                if let Err(err) = sender.send(message) {
                     // this could be caused by shutting down the stream from the main thread, so more of
                     // the informative level of the message.
-                    debug::print(
-                        &format!("Unable to broadcast the communications: {}", err),
-                        InfoLevel::Error,
-                    );
-                    break;
+                    rex_error!("Unable to broadcast the communications: {}", err);
+                    return;
                 }
             }
         }
     });
+
+    if let Err(e) = result {
+        rex_error!("Unable to launch the keep-alive reader thread: {}", e);
+    }
+}
+
+/// Assemble the lines sent over the long connection into messages, see `get_channels`.
+struct LineFramer {
+    pending: Vec<u8>,
+    limit: usize,
+}
+
+impl LineFramer {
+    fn new(limit: usize) -> Self {
+        LineFramer {
+            pending: Vec::new(),
+            limit,
+        }
+    }
+
+    /// Take the bytes read, and return the messages they have completed, or `None` if a message
+    /// exceeds the limit.
+    fn feed(&mut self, bytes: &[u8]) -> Option<Vec<String>> {
+        let mut messages = Vec::new();
+        let mut start = 0;
+
+        for (pos, byte) in bytes.iter().enumerate() {
+            if *byte != b'\n' {
+                continue;
+            }
+
+            self.pending.extend_from_slice(&bytes[start..pos]);
+            start = pos + 1;
+
+            if self.pending.last() == Some(&b'\r') {
+                self.pending.pop();
+            }
+
+            if self.pending.len() > self.limit {
+                return None;
+            }
+
+            if !self.pending.is_empty() {
+                messages.push(String::from_utf8_lossy(&self.pending).into_owned());
+                self.pending.clear();
+            }
+        }
+
+        self.pending.extend_from_slice(&bytes[start..]);
+
+        // the line break may be yet to come for the `\r` that's one byte over
+        let partial = match self.pending.last() {
+            Some(b'\r') => self.pending.len() - 1,
+            _ => self.pending.len(),
+        };
+
+        if partial > self.limit {
+            return None;
+        }
+
+        Some(messages)
+    }
+}
+
+/// Send the initial content, then the messages from the notifier over the long connection, until an
+/// empty message marks the end of the body, or the notifier has been quiet for `LONG_CONN_TIMEOUT`.
+/// While waiting for the next message, what's buffered is flushed once it's due.
+fn relay_messages<W: Write>(
+    sink: &mut ChunkedWriter<W>,
+    initial: &[u8],
+    notifier: Option<&Receiver<String>>,
+) -> io::Result<()> {
+    sink.send(initial)?;
+
+    let notifier = match notifier {
+        Some(rx) => rx,
+        None => return Ok(()),
+    };
+
+    let mut quiet_since = Instant::now();
+
+    loop {
+        let quiet_left = LONG_CONN_TIMEOUT
+            .checked_sub(quiet_since.elapsed())
+            .unwrap_or_default();
+        let wait = sink
+            .until_flush()
+            .map_or(quiet_left, |due| due.min(quiet_left));
+
+        match notifier.recv_timeout(wait) {
+            Ok(message) if message.is_empty() => return Ok(()),
+            Ok(message) => {
+                sink.send(message.as_bytes())?;
+                quiet_since = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) if quiet_since.elapsed() < LONG_CONN_TIMEOUT => {
+                sink.tick()?;
+            }
+            Err(_) => return Ok(()),
+        }
+    }
 }
 
-fn stream_trunk(content: &[u8], buffer: &mut BufWriter<&mut Stream>) {
-    // the content length should have been set in the header, see function resp_header
-    write_to_buff(buffer, content.len().to_string().as_bytes());
-    write_line_break(buffer);
-    write_to_buff(buffer, content);
-    write_line_break(buffer);
-    flush_buffer(buffer);
+/// Write the last chunk of a chunked body, followed by the trailer fields, if any, and the blank line
+/// which ends the message.
+fn write_last_chunk<W: Write>(buffer: &mut W, trailers: &[(String, String)]) -> io::Result<()> {
+    buffer.write_all(b"0\r\n")?;
+
+    for (field, value) in trailers {
+        write!(buffer, "{}: {}\r\n", field, value)?;
+    }
+
+    buffer.write_all(&HEADER_END)?;
+    flush_with_retry(buffer)
 }
 
 fn stream_default_body(status: u16, buffer: &mut BufWriter<&mut Stream>) {
     match status {
         //explicit error status
-        0 | 404 => write_default_page(400, buffer),
-        500 => write_default_page(500, buffer),
+        0 | 401 | 404 | 500 => write_to_buff(buffer, default_page(status)),
         _ => { /* Nothing */ }
     };
 }
 
-fn write_default_page(status: u16, buffer: &mut BufWriter<&mut Stream>) {
+/// The built-in error page for the status, where the statuses without a page of their own get the
+/// `500` one, and no status at all means nothing is found.
+fn default_page(status: u16) -> &'static [u8] {
     match status {
-        500 => {
-            /* return default 500 page */
-            write_to_buff(buffer, FIVE_HUNDRED.as_bytes());
-        }
-        _ => {
-            /* return default/override 404 page */
-            write_to_buff(buffer, FOUR_OH_FOUR.as_bytes());
-        }
+        0 | 404 => FOUR_OH_FOUR,
+        401 => FOUR_OH_ONE,
+        _ => FIVE_HUNDRED,
     }
 }
 
-fn get_file_path(path: &str) -> Option<PathBuf> {
-    if path.is_empty() {
-        debug::print(
-            "Undefined file path to retrieve data from...",
-            InfoLevel::Warning,
-        );
-        return None;
-    }
+/// Normalize the host for storage and comparison: all hosts lose the port, IPv6 literals also lose
+/// the square brackets, and all hosts are lower-cased.
+fn normalize_host(raw: &str) -> String {
+    let host = raw.trim();
 
-    let file_path = Path::new(path);
-    if !file_path.is_file() {
-        debug::print("Can't locate requested file", InfoLevel::Warning);
-        return None;
+    if host.starts_with('[') {
+        if let Some(end) = host.find(']') {
+            return host[1..end].to_lowercase();
+        }
     }
 
-    Some(file_path.to_path_buf())
+    host_name(host).to_lowercase()
 }
 
-fn open_file(file_path: &PathBuf, buf: &mut Vec<u8>) -> u16 {
+/// The host name without the port. The IPv6 literals without the square brackets contain multiple
+/// colons and never have a port attached, so they're kept as they are.
+fn host_name(host: &str) -> &str {
+    match host.find(':') {
+        Some(pos) if host.rfind(':') == Some(pos) => &host[..pos],
+        _ => host,
+    }
+}
+
+fn get_file_path(path: &str) -> Option<PathBuf> {
+    resolve_file_path(path, ConnMetadata::get_doc_root())
+}
+
+/// If a buffer of the capacity in bytes is to be dropped when the pooled object is reset, rather
+/// than kept for the next use: always on a hard reset, or when it's beyond the threshold set with
+/// `ServerConfig::set_pool_shrink_threshold`, where a threshold of 0 keeps the buffers of any size.
+fn oversized(capacity: usize, hard: bool) -> bool {
+    let threshold = SHRINK_THRESHOLD.load(atomic::Ordering::Relaxed);
+    hard || (threshold > 0 && capacity > threshold)
+}
+
+/// The bytes held by the header map, i.e. its slots and the buffers of the fields and the values.
+fn header_bytes(header: &HashMap<String, String>) -> usize {
+    header.capacity() * mem::size_of::<(String, String)>()
+        + header
+            .iter()
+            .map(|(field, value)| field.capacity() + value.capacity())
+            .sum::<usize>()
+}
+
+/// Locate the file, where the relative path is resolved against the root if there's one, and the
+/// file resolved outside of the root is not found.
+fn resolve_file_path(path: &str, root: Option<PathBuf>) -> Option<PathBuf> {
+    if path.is_empty() {
+        rex_warn!("Undefined file path to retrieve data from...");
+        return None;
+    }
+
+    let file_path = match root {
+        Some(root) if Path::new(path).is_relative() => {
+            // resolve the `..` and the symlinks before checking if we're still within the root
+            let resolved = match root.join(path).canonicalize() {
+                Ok(resolved) => resolved,
+                Err(_) => {
+                    rex_warn!("Can't locate requested file");
+                    return None;
+                }
+            };
+
+            if !resolved.starts_with(&root) {
+                rex_warn!("The requested file is outside of the document root");
+                return None;
+            }
+
+            resolved
+        }
+        _ => PathBuf::from(path),
+    };
+
+    if !file_path.is_file() {
+        rex_warn!("Can't locate requested file");
+        return None;
+    }
+
+    Some(file_path)
+}
+
+fn open_file(file_path: &PathBuf, buf: &mut Vec<u8>) -> u16 {
     // try open the file
     if let Ok(file) = File::open(file_path) {
         let mut buf_reader = BufReader::new(file);
         match buf_reader.read_to_end(buf) {
             Err(e) => {
-                debug::print(&format!("Unable to read file: {}", e), InfoLevel::Warning);
+                rex_warn!("Unable to read file: {}", e);
                 500
             }
             Ok(_) => {
@@ -1350,11 +3247,326 @@ fn open_file(file_path: &PathBuf, buf: &mut Vec<u8>) -> u16 {
             }
         }
     } else {
-        debug::print("Unable to open requested file for path", InfoLevel::Warning);
+        rex_warn!("Unable to open requested file for path");
         404
     }
 }
 
+fn open_file_range(file_path: &PathBuf, start: u64, end: u64, buf: &mut Vec<u8>) -> u16 {
+    let mut file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(_) => {
+            rex_warn!("Unable to open requested file for path");
+            return 404;
+        }
+    };
+
+    if let Err(e) = file.seek(SeekFrom::Start(start)) {
+        rex_warn!("Unable to seek file: {}", e);
+        return 500;
+    }
+
+    // only read the requested window from the file
+    let len = end - start + 1;
+    buf.reserve(len as usize);
+
+    match file.take(len).read_to_end(buf) {
+        Ok(size) if size as u64 == len => 206,
+        Ok(_) => {
+            rex_warn!("File changed while reading the range");
+            500
+        }
+        Err(e) => {
+            rex_warn!("Unable to read file: {}", e);
+            500
+        }
+    }
+}
+
+/// Parse the `Range` header value against the total size of the resource, and return the inclusive
+/// window of bytes to be served. Only the first range will be honored if multiple ranges are
+/// requested; `None` is returned if the range is malformed or can't be satisfied.
+fn parse_range(range: &str, total: u64) -> Option<(u64, u64)> {
+    // the value may hold any characters, so it's only sliced past the unit once that's matched
+    let range = range.trim();
+    match range.get(..6) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=") => {}
+        _ => return None,
+    }
+
+    let spec = range[6..].split(',').next()?.trim();
+    let mut bounds = spec.splitn(2, '-');
+    let (first, last) = (bounds.next()?.trim(), bounds.next()?.trim());
+
+    if total == 0 {
+        return None;
+    }
+
+    if first.is_empty() {
+        // suffix range, e.g. `bytes=-500` for the last 500 bytes
+        let suffix = last.parse::<u64>().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+
+        return Some((total.saturating_sub(suffix), total - 1));
+    }
+
+    let start = first.parse::<u64>().ok()?;
+    if start >= total {
+        return None;
+    }
+
+    if last.is_empty() {
+        return Some((start, total - 1));
+    }
+
+    let end = last.parse::<u64>().ok()?;
+    if end < start {
+        return None;
+    }
+
+    Some((start, end.min(total - 1)))
+}
+
+/// Split the value of the `Accept`-alike headers into the lowercased items and their quality values,
+/// which are 1.0 if not given. The items with a malformed quality value are skipped.
+fn quality_values(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+
+            let q = match parts.map(str::trim).find(|param| param.starts_with("q=")) {
+                Some(param) => match param[2..].parse::<f32>() {
+                    Ok(q) if q >= 0.0 && q <= 1.0 => q,
+                    _ => return None,
+                },
+                None => 1.0,
+            };
+
+            Some((name, q))
+        })
+        .collect()
+}
+
+/// Parse the `Accept-Language` header value into the language ranges sorted by their quality
+/// values, including the refused ones with a quality of 0. Malformed ranges are skipped.
+fn language_ranges(accept: &str) -> Vec<LanguageTag> {
+    let mut ranges: Vec<LanguageTag> = quality_values(accept)
+        .into_iter()
+        .filter(|(tag, _)| tag == "*" || is_language_tag(tag))
+        .map(|(tag, quality)| LanguageTag { tag, quality })
+        .collect();
+
+    // stable sort, so the ranges with the same quality stay in the order of the header
+    ranges.sort_by(|a, b| {
+        b.quality
+            .partial_cmp(&a.quality)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    ranges
+}
+
+/// The language tag is made of a primary subtag of 1 to 8 letters, then optional subtags of 1 to 8
+/// letters or digits, joined by `-`.
+fn is_language_tag(tag: &str) -> bool {
+    tag.split('-').enumerate().all(|(idx, subtag)| {
+        !subtag.is_empty()
+            && subtag.len() <= 8
+            && subtag.chars().all(|c| {
+                if idx == 0 {
+                    c.is_ascii_alphabetic()
+                } else {
+                    c.is_ascii_alphanumeric()
+                }
+            })
+    })
+}
+
+/// Parse the `Accept-Charset` header value into the charsets sorted by their quality values,
+/// including the refused ones with a quality of 0.
+fn charset_ranges(accept: &str) -> Vec<(String, f32)> {
+    let mut ranges = quality_values(accept);
+
+    // stable sort, so the charsets with the same quality stay in the order of the header
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranges
+}
+
+/// If the two charset names are the same, or the aliases of the same known charset.
+fn same_charset(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+        || match Charset::lookup(a) {
+            Some(charset) => Charset::lookup(b) == Some(charset),
+            None => false,
+        }
+}
+
+/// The characters of windows-1252 from `0x80` to `0x9F`, where the bytes left undefined by the
+/// charset stand for the C1 controls of the same code points, as the WHATWG encoding standard does.
+const WINDOWS_1252_C1: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+/// The charsets the response body can be transcoded to, see `ResponseWriter::send_with_charset`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Charset {
+    Utf8,
+    Latin1,
+    Windows1252,
+}
+
+impl Charset {
+    fn lookup(label: &str) -> Option<Charset> {
+        match &label.trim().to_lowercase()[..] {
+            "utf-8" | "utf8" => Some(Charset::Utf8),
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "latin-1" | "l1" => {
+                Some(Charset::Latin1)
+            }
+            "windows-1252" | "cp1252" => Some(Charset::Windows1252),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Charset::Utf8 => "utf-8",
+            Charset::Latin1 => "iso-8859-1",
+            Charset::Windows1252 => "windows-1252",
+        }
+    }
+
+    /// The byte of the character in the charset, if it's a single-byte charset that has it.
+    fn byte_of(self, c: char) -> Option<u8> {
+        let code = c as u32;
+
+        match self {
+            Charset::Utf8 => None,
+            _ if code < 0x80 || (0xA0..=0xFF).contains(&code) => Some(code as u8),
+            Charset::Latin1 if code <= 0xFF => Some(code as u8),
+            Charset::Latin1 => None,
+            Charset::Windows1252 => WINDOWS_1252_C1
+                .iter()
+                .position(|mapped| *mapped == c)
+                .map(|pos| 0x80 + pos as u8),
+        }
+    }
+
+    /// Append the content in the charset to the buffer, with the characters out of the charset
+    /// written as the numeric character references in `html`, or `?` otherwise.
+    fn encode_into(self, content: &str, html: bool, buf: &mut Vec<u8>) {
+        if self == Charset::Utf8 {
+            buf.extend_from_slice(content.as_bytes());
+            return;
+        }
+
+        buf.reserve(content.len());
+        for c in content.chars() {
+            match self.byte_of(c) {
+                Some(byte) => buf.push(byte),
+                None if html => buf.extend_from_slice(format!("&#{};", c as u32).as_bytes()),
+                None => buf.push(b'?'),
+            }
+        }
+    }
+}
+
+/// Check if the `Accept` header value ranks `application/json` higher than `text/html`. Ties, e.g.
+/// `*/*` or a missing header, will fallback to html.
+fn prefers_json(accept: &str) -> bool {
+    let mut json_q: f32 = 0.0;
+    let mut html_q: f32 = 0.0;
+
+    for (kind, q) in quality_values(accept) {
+        match &kind[..] {
+            "application/json" | "application/*" => json_q = json_q.max(q),
+            "text/html" | "text/*" => html_q = html_q.max(q),
+            _ => {}
+        }
+    }
+
+    json_q > html_q
+}
+
+/// Pick the preferred encoding from the `Accept-Encoding` header value, gzip wins the ties.
+#[cfg(feature = "compression")]
+fn accepted_encoding(accept: &str) -> Option<Encoding> {
+    let mut gzip_q: f32 = 0.0;
+    let mut deflate_q: f32 = 0.0;
+    let mut any_q: f32 = 0.0;
+
+    for (name, q) in quality_values(accept) {
+        match &name[..] {
+            "gzip" | "x-gzip" => gzip_q = gzip_q.max(q),
+            "deflate" => deflate_q = deflate_q.max(q),
+            "*" => any_q = any_q.max(q),
+            _ => {}
+        }
+    }
+
+    if gzip_q > 0.0 && gzip_q >= deflate_q {
+        Some(Encoding::Gzip)
+    } else if deflate_q > 0.0 {
+        Some(Encoding::Deflate)
+    } else if any_q > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(content: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+
+    let buf = Vec::with_capacity(content.len() / 2);
+
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(buf, Compression::default());
+            encoder.write_all(content)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(buf, Compression::default());
+            encoder.write_all(content)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    let tags = tags.trim();
+    if tags == "*" {
+        return true;
+    }
+
+    // weak comparison, the `W/` prefix is ignored on both sides
+    let etag = etag.trim_start_matches("W/");
+    tags.split(',')
+        .any(|tag| tag.trim().trim_start_matches("W/") == etag)
+}
+
+fn not_modified_since(since: &str, modified: &str) -> bool {
+    match (
+        DateTime::parse_from_rfc2822(since.trim()),
+        DateTime::parse_from_rfc2822(modified.trim()),
+    ) {
+        (Ok(since), Ok(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
 fn open_file_async(file_path: PathBuf, tx: Sender<(Vec<u8>, u16)>) {
     assert!(file_path.is_file());
 
@@ -1368,18 +3580,15 @@ fn open_file_async(file_path: PathBuf, tx: Sender<(Vec<u8>, u16)>) {
                 match buf_reader.read_to_end(&mut buf) {
                     Ok(len) => {
                         if tx.send((buf, 200)).is_err() {
-                            debug::print(
-                                "Unable to write the file to the stream",
-                                InfoLevel::Warning,
-                            );
+                            rex_warn!("Unable to write the file to the stream");
                         }
                     }
                     Err(e) => {
-                        debug::print(&format!("Unable to read file: {}", e), InfoLevel::Warning);
+                        rex_warn!("Unable to read file: {}", e);
                     }
                 }
             } else {
-                debug::print("Unable to open requested file for path", InfoLevel::Warning);
+                rex_warn!("Unable to open requested file for path");
             }
         },
         TaskType::Response,
@@ -1387,63 +3596,91 @@ fn open_file_async(file_path: PathBuf, tx: Sender<(Vec<u8>, u16)>) {
 }
 
 fn get_status(status: u16) -> Vec<u8> {
-    let status = match status {
-        100 => "100 Continue",
-        101 => "101 Switching Protocols",
-        200 => "200 OK",
-        201 => "201 Created",
-        202 => "202 Accepted",
-        203 => "203 Non-Authoritative Information",
-        204 => "204 No Content",
-        205 => "205 Reset Content",
-        206 => "206 Partial Content",
-        300 => "300 Multiple Choices",
-        301 => "301 Moved Permanently",
-        302 => "302 Found",
-        303 => "303 See Other",
-        304 => "304 Not Modified",
-        307 => "307 Temporary Redirect",
-        308 => "308 Permanent Redirect",
-        400 => "400 Bad Request",
-        401 => "401 Unauthorized",
-        403 => "403 Forbidden",
-        404 => "404 Not Found",
-        405 => "405 Method Not Allowed",
-        406 => "406 Not Acceptable",
-        407 => "407 Proxy Authentication Required",
-        408 => "408 Request Timeout",
-        409 => "409 Conflict",
-        410 => "410 Gone",
-        411 => "411 Length Required",
-        412 => "412 Precondition Failed",
-        413 => "413 Payload Too Large",
-        414 => "414 URI Too Long",
-        415 => "415 Unsupported Media Type",
-        416 => "416 Range Not Satisfiable",
-        417 => "417 Expectation Failed",
-        426 => "426 Upgrade Required",
-        428 => "428 Precondition Required",
-        429 => "429 Too Many Requests",
-        431 => "431 Request Header Fields Too Large",
-        451 => "451 Unavailable For Legal Reasons",
-        500 => "500 Internal Server Error",
-        501 => "501 Not Implemented",
-        502 => "502 Bad Gateway",
-        503 => "503 Service Unavailable",
-        504 => "504 Gateway Timeout",
-        505 => "505 HTTP Version Not Supported",
-        511 => "511 Network Authentication Required",
-        _ => "403 Forbidden",
-    };
+    let reason = status_reason(status);
 
-    let mut result = Vec::with_capacity(11 + status.len());
+    let mut result = Vec::with_capacity(15 + reason.len());
     result.extend_from_slice(b"HTTP/1.1 ");
-    result.extend_from_slice(status.as_bytes());
+    result.extend_from_slice(status.to_string().as_bytes());
+
+    // the reason phrase is optional, but the space before it is not
+    result.push(b' ');
+    result.extend_from_slice(reason.as_bytes());
     result.append_line_break();
 
     result
 }
 
+/// The reason phrase of the status code registered with IANA, or an empty phrase if the code is
+/// unknown.
+fn status_reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        102 => "Processing",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        208 => "Already Reported",
+        226 => "IM Used",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        306 => "Unused",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        418 => "Unused",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        510 => "Not Extended",
+        511 => "Network Authentication Required",
+        _ => "",
+    }
+}
+
 fn default_mime_type_with_ext(ext: &str) -> String {
     match ext {
         "abw" => String::from("application/x-abiword"),
@@ -1490,30 +3727,22 @@ fn default_mime_type_with_ext(ext: &str) -> String {
         "midi" | "mp3" | "aac" | "mid" | "oga" => ["audio/", ext].join(""),
         "webm" | "mp4" | "ogg" | "mpeg" | "ogv" => ["video/", ext].join(""),
         "xml" | "pdf" | "json" | "ogx" | "rtf" | "zip" => ["application/", ext].join(""),
-        _ if !ext.is_empty() => ["application/", ext].join(""),
+        _ if !ext.is_empty() => String::from("application/octet-stream"),
         _ => String::from("text/plain"),
     }
 }
 
-fn write_header_status(status: u16, has_contents: bool) -> Vec<u8> {
-    match status {
-        404 | 500 => get_status(status),
-        0 => {
-            /* No status has been explicitly set, be smart here */
-            if has_contents {
-                get_status(200)
-            } else {
-                get_status(404)
-            }
-        }
-        _ => {
-            /* A status has been set explicitly, respect that here. */
-            get_status(status)
-        }
-    }
+/// If the responses with the status never have a body, i.e. the 1xx, 204 and 304 ones.
+fn is_bodiless(status: u16) -> bool {
+    (100..200).contains(&status) || status == 204 || status == 304
 }
 
-fn write_headers(source: &HashMap<String, String>, header: &mut Vec<u8>, keep_alive: bool) {
+fn write_headers(
+    source: &HashMap<String, String>,
+    header: &mut Vec<u8>,
+    chunked: bool,
+    bodiless: bool,
+) {
     header.reserve_exact(24);
     header.extend_from_slice(b"Server: Rusty-Express/");
     header.extend_from_slice(VERSION.as_bytes());
@@ -1530,12 +3759,17 @@ fn write_headers(source: &HashMap<String, String>, header: &mut Vec<u8>, keep_al
 
     let transfer = String::from("transfer-encoding");
     for (field, value) in source.iter() {
+        if bodiless && field.eq_ignore_ascii_case(&transfer) {
+            // there's no body to transfer
+            continue;
+        }
+
         header.reserve_exact(field.len() + value.len() + 4);
         header.extend_from_slice(field.as_bytes());
         header.extend_from_slice(b": ");
         header.extend_from_slice(value.as_bytes());
 
-        if keep_alive && field.eq(&transfer) && !value.contains("chunked") {
+        if chunked && field.eq(&transfer) && !value.contains("chunked") {
             header.reserve_exact(9);
             header.extend_from_slice(b", chunked\r\n");
         } else {
@@ -1544,10 +3778,36 @@ fn write_headers(source: &HashMap<String, String>, header: &mut Vec<u8>, keep_al
     }
 }
 
-fn write_header_cookie(cookie: HashMap<String, Cookie>, tx: Sender<Vec<u8>>) {
+fn write_cookie_header(buffer: &mut BufWriter<&mut Stream>, receiver: Option<Receiver<Vec<u8>>>) {
+    if let Some(rx) = receiver {
+        if let Ok(content) = rx.recv_timeout(RESP_TIMEOUT) {
+            if !content.is_empty() && buffer.write(&content).is_err() {
+                rex_warn!("Failed to send cookie headers");
+            }
+        }
+    }
+}
+
+/// Serialize the cookies, where `conn` tells if the response is sent over TLS, and if it's to a
+/// cross-site request, for applying the cookie policy.
+fn write_header_cookie(
+    mut cookie: HashMap<String, Cookie>,
+    conn: (bool, bool),
+    tx: Sender<Vec<u8>>,
+) {
     let mut output = Vec::new();
+    let policy = ConnMetadata::get_cookie_policy();
+
+    for (key, cookie) in cookie.iter_mut() {
+        if let Some(policy) = policy.as_ref() {
+            if policy.apply(cookie, conn.0, conn.1) {
+                rex_warn!(
+                    "The cookie `{}` is set on a cross-site request without `SameSite=None; Secure`, and the browsers won't store it",
+                    key
+                );
+            }
+        }
 
-    for (_, cookie) in cookie.iter() {
         if cookie.is_valid() {
             let c = cookie.to_string();
 
@@ -1559,9 +3819,1612 @@ fn write_header_cookie(cookie: HashMap<String, Cookie>, tx: Sender<Vec<u8>>) {
     }
 
     tx.send(output).unwrap_or_else(|e| {
-        debug::print(
-            &format!("Unable to write response cookies: {}", e),
-            InfoLevel::Warning,
-        );
+        rex_warn!("Unable to write response cookies: {}", e);
     });
 }
+
+#[cfg(test)]
+mod http_test {
+    use super::{
+        get_status, parse_range, relay_messages, status_reason, ChunkedWriter, Cookie,
+        KeepAliveStatus, LanguageTag, LineFramer, OverrideReason, Request, RequestWriter, Response,
+        ResponseManager, ResponseStates, ResponseWriter, StreamOptions,
+    };
+    use crate::channel;
+    use crate::core::config::{
+        init_test_config, EngineContext, ServerConfig, ViewEngineDefinition, DEFAULT_SHRINK_BYTES,
+    };
+    use crate::core::router::{ViewsScope, REST};
+    use crate::core::stream::{MockStream, Stream};
+    use crate::core::syncstore::{Reusable, SyncPool};
+    use crate::core::template;
+    use crate::hashbrown::HashMap;
+    use crate::support::common::{HeaderMap, MapUpdates};
+    use crate::support::TraceIds;
+    use std::borrow::Cow;
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::str;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[cfg(feature = "compression")]
+    use super::Encoding;
+
+    fn conditional_response(path: &PathBuf, header: Option<(&str, &str)>) -> (u16, Response) {
+        let mut resp = Response::new();
+        let status = resp.send_file_from_path(path.clone());
+        resp.status(status);
+
+        let mut req = Box::new(Request::new());
+        if let Some((field, value)) = header {
+            req.write_header(field, value, true);
+        }
+
+        resp.validate_conditional(&req);
+        (status, resp)
+    }
+
+    /// Write the header of the response, and return its lines, with the values of `Date` dropped.
+    fn header_lines(resp: &mut Response) -> Vec<String> {
+        let mock = MockStream::new(0);
+        let wire = mock.wire.clone();
+        let mut stream = Stream::Mock(mock);
+        {
+            let mut writer = BufWriter::new(&mut stream);
+            assert!(resp.write_header(&mut writer));
+        }
+
+        let wire = String::from_utf8(wire.lock().unwrap().clone()).unwrap();
+        assert!(wire.ends_with("\r\n\r\n"), "{}", wire);
+
+        let mut lines: Vec<String> = wire
+            .trim_end()
+            .split("\r\n")
+            .map(|line| {
+                if line.starts_with("Date: ") {
+                    String::from("Date")
+                } else {
+                    line.to_owned()
+                }
+            })
+            .collect();
+
+        lines[1..].sort();
+        lines
+    }
+
+    #[test]
+    fn bodiless_framing() {
+        init_test_config();
+
+        let fields = |status: &str, fields: &[&str]| {
+            let mut lines = vec![String::from(status)];
+            let mut fields: Vec<String> = fields.iter().map(|f| (*f).to_owned()).collect();
+            fields.push(String::from("Date"));
+            fields.push(format!("Server: Rusty-Express/{}", super::VERSION));
+            fields.sort();
+
+            lines.extend(fields);
+            lines
+        };
+
+        let page = |resp: &mut Response| {
+            resp.status(200);
+            resp.set_content_type("text/plain");
+            resp.set_header("ETag", "\"v1\"");
+            resp.set_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT");
+            resp.set_header("Cache-Control", "max-age=60");
+            resp.set_header("Vary", "Accept-Language");
+            resp.set_header("Transfer-Encoding", "gzip");
+        };
+
+        let mut req = Box::new(Request::new());
+        req.write_header("If-None-Match", "\"v1\"", true);
+
+        // revalidated: the validators are kept, along with the length of the body of the 200
+        let mut resp = Response::new();
+        page(&mut resp);
+        resp.send("hello");
+        resp.validate_conditional(&req);
+        resp.validate_and_update();
+        assert_eq!(
+            header_lines(&mut resp),
+            fields(
+                "HTTP/1.1 304 Not Modified",
+                &[
+                    "etag: \"v1\"",
+                    "last-modified: Wed, 21 Oct 2015 07:28:00 GMT",
+                    "cache-control: max-age=60",
+                    "vary: Accept-Language",
+                    "Content-Length: 5",
+                    "Connection: close",
+                ]
+            )
+        );
+
+        // and without the length if it's not known, e.g. the body is streamed
+        let mut resp = Response::new();
+        page(&mut resp);
+        resp.stream(|sink| sink.write_all(b"hello"));
+        resp.validate_conditional(&req);
+        resp.validate_and_update();
+        assert_eq!(
+            header_lines(&mut resp),
+            fields(
+                "HTTP/1.1 304 Not Modified",
+                &[
+                    "etag: \"v1\"",
+                    "last-modified: Wed, 21 Oct 2015 07:28:00 GMT",
+                    "cache-control: max-age=60",
+                    "vary: Accept-Language",
+                    "Connection: close",
+                ]
+            )
+        );
+
+        // no content, no framing, even if the handler asks for it
+        let mut resp = Response::new();
+        resp.status(204);
+        resp.set_content_type("application/json");
+        resp.set_header("Content-Length", "0");
+        resp.set_header("Transfer-Encoding", "chunked");
+        resp.set_header("X-Request-Id", "42");
+        resp.validate_and_update();
+        assert_eq!(
+            header_lines(&mut resp),
+            fields(
+                "HTTP/1.1 204 No Content",
+                &["x-request-id: 42", "Connection: close"]
+            )
+        );
+
+        // and the same for the 1xx ones
+        let mut resp = Response::new();
+        resp.status(101);
+        resp.send("ignored");
+        resp.validate_and_update();
+        assert_eq!(
+            header_lines(&mut resp),
+            fields("HTTP/1.1 101 Switching Protocols", &["Connection: close"])
+        );
+    }
+
+    fn with_languages(accept: Option<&str>) -> Request {
+        let mut req = Request::new();
+        if let Some(value) = accept {
+            req.write_header("Accept-Language", value, true);
+        }
+
+        req
+    }
+
+    fn with_charsets(accept: Option<&str>) -> Request {
+        let mut req = Request::new();
+        if let Some(value) = accept {
+            req.write_header("Accept-Charset", value, true);
+        }
+
+        req
+    }
+
+    #[test]
+    fn accept_charset_negotiation() {
+        let req = with_charsets(Some(
+            "utf-8;q=0.7, ISO-8859-1, *;q=0.2, koi8-r;q=0, x;q=abc",
+        ));
+        assert_eq!(
+            req.accepted_charsets(),
+            vec![
+                (String::from("iso-8859-1"), 1.0),
+                (String::from("utf-8"), 0.7),
+                (String::from("*"), 0.2),
+            ]
+        );
+
+        let cases: [(&[&str], Option<&str>); 5] = [
+            (&["utf-8", "latin1"], Some("latin1")),
+            (&["UTF8", "windows-1252"], Some("UTF8")),
+            (&["windows-1252"], Some("windows-1252")),
+            (&["koi8-r", "cp1252"], Some("cp1252")),
+            (&["koi8-r"], None),
+        ];
+
+        for (supported, expected) in cases.iter() {
+            assert_eq!(
+                req.negotiate_charset(supported).as_deref(),
+                *expected,
+                "Failed at case: {:?}",
+                supported
+            );
+        }
+
+        // no preference takes the first supported, and no match without the wildcard
+        let req = with_charsets(None);
+        assert!(req.accepted_charsets().is_empty());
+        assert_eq!(
+            req.negotiate_charset(&["windows-1252", "utf-8"]),
+            Some(String::from("windows-1252"))
+        );
+        assert_eq!(
+            with_charsets(Some("utf-8")).negotiate_charset(&["latin1"]),
+            None
+        );
+    }
+
+    /// Send the content in the charset, and return the content type and the wire of the response.
+    fn charset_wire(content_type: &str, content: &str, charset: &str) -> (String, Vec<u8>) {
+        init_test_config();
+
+        let mut resp = Response::new();
+        resp.status(200);
+        if !content_type.is_empty() {
+            resp.set_content_type(content_type);
+        }
+        resp.send_with_charset(content, charset);
+
+        let mock = MockStream::new(0);
+        let wire = mock.wire.clone();
+        let mut stream = Stream::Mock(mock);
+        {
+            let mut writer = BufWriter::new(&mut stream);
+            assert!(resp.write_header(&mut writer));
+            assert!(resp.write_body(&mut writer));
+        }
+
+        let wire = wire.lock().unwrap().clone();
+        (resp.get_content_type(), wire)
+    }
+
+    #[test]
+    fn send_with_charset() {
+        let body_of = |wire: &[u8]| {
+            let pos = wire.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            (
+                String::from_utf8_lossy(&wire[..pos]).into_owned(),
+                wire[pos + 4..].to_vec(),
+            )
+        };
+
+        // é is 0xE9 in latin-1, and the cup of coffee has no place in it
+        let (content_type, wire) = charset_wire("", "café ☕", "latin1");
+        let (head, body) = body_of(&wire);
+        assert_eq!(content_type, "text/plain; charset=iso-8859-1");
+        assert_eq!(body, b"caf\xe9 ?".to_vec());
+        assert!(
+            head.contains("Content-Type: text/plain; charset=iso-8859-1\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Content-Length: 6\r\n"), "{}", head);
+
+        // HTML gets the numeric character references instead
+        let (content_type, wire) =
+            charset_wire("text/html; charset=utf-8", "café ☕", "ISO-8859-1");
+        let (head, body) = body_of(&wire);
+        assert_eq!(content_type, "text/html; charset=iso-8859-1");
+        assert_eq!(body, b"caf\xe9 &#9749;".to_vec());
+        assert!(head.contains("Content-Length: 12\r\n"), "{}", head);
+
+        // windows-1252 has the euro sign in the C1 range, which latin-1 doesn't
+        let (content_type, wire) = charset_wire("text/plain", "€5 é", "cp1252");
+        assert_eq!(content_type, "text/plain; charset=windows-1252");
+        assert_eq!(body_of(&wire).1, b"\x805 \xe9".to_vec());
+        assert!(charset_wire("", "€", "latin1").1.ends_with(b"\r\n\r\n?"));
+
+        // unsupported charsets fall back to UTF-8, and say so
+        let (content_type, wire) = charset_wire("text/plain", "café ☕", "koi8-r");
+        let (head, body) = body_of(&wire);
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(body, "café ☕".as_bytes().to_vec());
+        assert!(head.contains("Content-Length: 9\r\n"), "{}", head);
+    }
+
+    #[test]
+    fn accept_language_negotiation() {
+        let req = with_languages(Some("fr-CH, fr;q=0.9, en;q=0.8, *;q=0.5"));
+
+        let accepted = req.accepted_languages();
+        let tags: Vec<&str> = accepted.iter().map(|lang| lang.tag.as_str()).collect();
+        assert_eq!(tags, vec!["fr-ch", "fr", "en", "*"]);
+
+        let cases: [(&[&str], Option<&str>); 5] = [
+            (&["en", "fr-CH"], Some("fr-CH")),
+            (&["en", "fr"], Some("fr")),
+            (&["de", "en"], Some("en")),
+            (&["de", "ja"], Some("de")),
+            (&[], None),
+        ];
+
+        for (supported, expected) in cases.iter() {
+            assert_eq!(
+                req.negotiate_language(supported)
+                    .as_ref()
+                    .map(String::as_str),
+                *expected,
+                "Failed at case: {:?}",
+                supported
+            );
+        }
+
+        // region fallback, and no match without the wildcard
+        let req = with_languages(Some("en-GB;q=0.8, de-AT-1996"));
+        assert_eq!(
+            req.negotiate_language(&["fr", "en"]),
+            Some(String::from("en"))
+        );
+        assert_eq!(req.negotiate_language(&["de"]), Some(String::from("de")));
+        assert_eq!(req.negotiate_language(&["fr", "ja"]), None);
+
+        // refused languages are not picked by the wildcard, and malformed entries are skipped
+        let req = with_languages(Some("en;q=0, *;q=0.3, 12, fr;q=2, ja;q=abc"));
+        assert_eq!(
+            req.accepted_languages(),
+            vec![LanguageTag {
+                tag: String::from("*"),
+                quality: 0.3
+            }]
+        );
+        assert_eq!(
+            req.negotiate_language(&["en", "fr"]),
+            Some(String::from("fr"))
+        );
+
+        // no header means no preference
+        let req = with_languages(None);
+        assert!(req.accepted_languages().is_empty());
+        assert_eq!(
+            req.negotiate_language(&["ja", "en"]),
+            Some(String::from("ja"))
+        );
+    }
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(get_status(200), b"HTTP/1.1 200 OK\r\n".to_vec());
+        assert_eq!(get_status(305), b"HTTP/1.1 305 Use Proxy\r\n".to_vec());
+        assert_eq!(
+            get_status(422),
+            b"HTTP/1.1 422 Unprocessable Content\r\n".to_vec()
+        );
+
+        // an unknown code keeps its own number, only without a reason phrase
+        assert_eq!(status_reason(299), "");
+        assert_eq!(get_status(299), b"HTTP/1.1 299 \r\n".to_vec());
+
+        // every status the response takes has its reason phrase
+        for code in 100..600 {
+            let mut resp = Response::new();
+            resp.status(code);
+
+            if resp.get_status() != 0 {
+                assert!(!status_reason(code).is_empty(), "{}", code);
+            }
+        }
+    }
+
+    #[test]
+    fn request_writer_in_sync() {
+        let mut query = HashMap::new();
+        query.insert(String::from("page"), vec![String::from("2")]);
+
+        let mut params = HashMap::new();
+        params.insert(String::from("id"), String::from("7"));
+
+        // the writers the connection fills the request with are all on the trait
+        let mut req = Request::new();
+        {
+            let writer: &mut dyn RequestWriter = &mut req;
+            writer.create_query(query);
+            writer.write_query("tag", vec![String::from("rust")], true);
+            writer.create_param(params);
+            writer.set_client("127.0.0.1:8080".parse().unwrap());
+            writer.set_route_pattern("/items/:id");
+        }
+
+        assert_eq!(req.query("page"), Some(vec![String::from("2")]));
+        assert_eq!(req.query_first("tag"), Some(String::from("rust")));
+        assert_eq!(req.param("id"), Some(String::from("7")));
+        assert_eq!(req.client_info(), "127.0.0.1:8080".parse().ok());
+        assert_eq!(req.route_pattern(), Some(String::from("/items/:id")));
+    }
+
+    #[test]
+    fn ordered_query_pairs() {
+        let pairs = [("a", "1"), ("b", "2"), ("a", "3")];
+
+        let mut req = Request::new();
+        req.set_query(
+            pairs
+                .iter()
+                .map(|(f, v)| (f.to_string(), v.to_string()))
+                .collect(),
+        );
+
+        // grouped by the field
+        assert_eq!(
+            req.query("a"),
+            Some(vec![String::from("1"), String::from("3")])
+        );
+        assert_eq!(req.query_first("a"), Some(String::from("1")));
+        assert_eq!(req.query_first("b"), Some(String::from("2")));
+        assert_eq!(req.query_first("c"), None);
+
+        // and iterated in the order of the request
+        assert_eq!(req.query_iter().collect::<Vec<_>>(), pairs);
+        assert_eq!(req.query_count(), 3);
+        assert_eq!(req.query_string(), "?a=1&b=2&a=3");
+
+        // the written field moves to the end
+        req.write_query("a", vec![String::from("4")], false);
+        assert_eq!(req.query_count(), 3);
+        req.write_query("A", vec![String::from("4")], true);
+        assert_eq!(
+            req.query_iter().collect::<Vec<_>>(),
+            [("b", "2"), ("a", "4")]
+        );
+        assert_eq!(req.query("a"), Some(vec![String::from("4")]));
+
+        let mut query = HashMap::new();
+        query.insert(String::from("page"), vec![String::from("2")]);
+        req.create_query(query);
+        assert_eq!(req.query_iter().collect::<Vec<_>>(), [("page", "2")]);
+        assert_eq!(req.query("b"), None);
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some((0, 499)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=-200", 1000), Some((800, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=0-9, 20-29", 1000), Some((0, 9)));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=abc", 1000), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+        assert_eq!(parse_range("bytés=0-9", 1000), None);
+        assert_eq!(parse_range("bytes=0-é", 1000), None);
+        assert_eq!(parse_range("bytesé0-9", 1000), None);
+        assert_eq!(parse_range("ééé", 1000), None);
+    }
+
+    #[test]
+    fn conditional_get() {
+        let path = env::temp_dir().join("rusty_express_conditional_get.txt");
+        fs::write(&path, b"cached content").unwrap();
+
+        let (status, resp) = conditional_response(&path, None);
+        assert_eq!(status, 200);
+
+        let etag = resp.get_header("etag").cloned().unwrap();
+        assert!(etag.starts_with("W/\""));
+        assert!(resp.get_header("last-modified").is_some());
+
+        // matching etag
+        let (_, resp) = conditional_response(&path, Some(("if-none-match", &etag)));
+        assert_eq!(resp.status, 304);
+        assert!(resp.is_header_only());
+        assert!(resp.body.is_empty());
+
+        // stale etag
+        let (_, resp) = conditional_response(&path, Some(("if-none-match", "W/\"0-0\"")));
+        assert_eq!(resp.status, 200);
+        assert!(!resp.is_header_only());
+        assert_eq!(resp.body, b"cached content".to_vec());
+
+        fs::remove_file(&path).unwrap();
+
+        // missing file
+        let (status, resp) = conditional_response(&path, Some(("if-none-match", &etag)));
+        assert_eq!(status, 404);
+        assert_eq!(resp.status, 404);
+        assert!(resp.get_header("etag").is_none());
+    }
+
+    #[test]
+    fn cookie_overwrites() {
+        let cookie =
+            |name: &str, value: &str, path: &str| Cookie::build(name, value).path(path).finish();
+
+        let value =
+            |resp: &Response, name: &str| resp.get_cookie(name).map(|c| c.get_cookie_value());
+
+        // the last one wins in the same call
+        let mut resp = Response::new();
+        resp.set_cookies(&[cookie("sid", "first", "/"), cookie("sid", "second", "/app")]);
+        assert_eq!(value(&resp, "sid"), Some(String::from("second")));
+        assert_eq!(resp.cookie.len(), 1);
+
+        // the later call wins
+        resp.set_cookie(cookie("theme", "dark", "/"));
+        resp.set_cookies(&[cookie("theme", "light", "/")]);
+        assert_eq!(value(&resp, "theme"), Some(String::from("light")));
+
+        resp.set_cookies(&[cookie("sid", "third", "/")]);
+        resp.set_cookie(cookie("sid", "fourth", "/"));
+        assert_eq!(value(&resp, "sid"), Some(String::from("fourth")));
+
+        // the targeted removal only matches the exact path and domain
+        assert!(!resp.remove_cookie("sid", "/app", ""));
+        assert!(!resp.remove_cookie("sid", "/", "example.com"));
+        assert!(resp.remove_cookie("sid", "/", ""));
+        assert!(!resp.remove_cookie("sid", "/", ""));
+        assert_eq!(value(&resp, "sid"), None);
+        assert_eq!(value(&resp, "theme"), Some(String::from("light")));
+
+        resp.set_cookie(cookie("sid", "fifth", "/"));
+        assert_eq!(value(&resp, "sid"), Some(String::from("fifth")));
+
+        // clearing the response cookies leaves the request ones alone
+        let mut req = Request::new();
+        req.set_cookie("sid", "from-client", true);
+        resp.clear_cookies();
+
+        assert!(resp.cookie.is_empty());
+        assert_eq!(req.cookie("sid"), Some(String::from("from-client")));
+    }
+
+    #[test]
+    fn file_mime_types() {
+        init_test_config();
+
+        let mut overrides = HashMap::new();
+        overrides.insert(String::from("AVIF"), String::from("image/avif"));
+        overrides.insert(String::from(".mjs"), String::from("text/javascript"));
+        ServerConfig::set_mime_overrides(overrides);
+
+        let mime = |name: &str, preset: Option<&str>| {
+            let path = env::temp_dir().join(format!("rusty_express_mime_{}", name));
+            fs::write(&path, b"content").unwrap();
+
+            let mut resp = Response::new();
+            if let Some(content_type) = preset {
+                resp.set_content_type(content_type);
+            }
+
+            assert_eq!(resp.send_file_from_path(path.clone()), 200);
+            fs::remove_file(&path).unwrap();
+
+            resp.get_content_type()
+        };
+
+        assert_eq!(mime("pic.avif", None), "image/avif");
+        assert_eq!(mime("app.MJS", None), "text/javascript");
+        assert_eq!(mime("data.xyz", None), "application/octet-stream");
+        assert_eq!(mime("logo.PNG", None), "image/png");
+        assert_eq!(mime("page.Html", None), "text/html");
+        assert_eq!(mime("notes", None), "text/plain");
+        assert_eq!(mime("logo.png", Some("image/x-custom")), "image/x-custom");
+    }
+
+    #[test]
+    fn error_format_negotiation() {
+        init_test_config();
+
+        let error_page = |accept: &str| {
+            let mut req = Box::new(Request::new());
+            req.uri = String::from("/missing");
+            req.set_trace_ids(TraceIds::new(7, 2));
+            req.write_header("accept", accept, true);
+
+            let mut resp = Response::new();
+            resp.negotiate_err_format(&req);
+            resp.validate_and_update();
+
+            (
+                resp.get_content_type(),
+                String::from_utf8(resp.body).unwrap(),
+            )
+        };
+
+        let (content_type, body) = error_page("application/json");
+        assert_eq!(content_type, "application/json");
+        assert_eq!(
+            body,
+            r#"{"status":404,"error":"Not Found","path":"/missing","request_id":"7.2"}"#
+        );
+
+        let (content_type, body) = error_page("text/html, application/json;q=0.9");
+        assert!(content_type.is_empty());
+        assert!(body.contains("<html"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn encoding_negotiation() {
+        use super::{accepted_encoding, Encoding};
+
+        assert_eq!(accepted_encoding("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(
+            accepted_encoding("gzip;q=0.5, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(accepted_encoding("br, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(accepted_encoding("gzip;q=0, identity"), None);
+    }
+
+    /// The text response of the status, with the setup applied, compressed with gzip under a
+    /// policy that takes any text body of 16 bytes or more.
+    #[cfg(feature = "compression")]
+    fn compressed(status: u16, setup: fn(&mut Response)) -> Response {
+        use crate::core::config::CompressionPolicy;
+
+        let policy = CompressionPolicy::new(16, vec![String::from("text/")]);
+
+        let mut resp = Response::new();
+        resp.status(status);
+        resp.set_content_type("text/plain");
+        resp.send(&"compress me ".repeat(64));
+        setup(&mut resp);
+
+        resp.encoding = Some(Encoding::Gzip);
+        resp.compress_body_under(Some(&policy));
+        resp
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compress_full_body() {
+        use flate2::read::GzDecoder;
+
+        let resp = compressed(200, |_| {});
+        assert_eq!(
+            resp.header.get("content-encoding").map(String::as_str),
+            Some("gzip")
+        );
+        assert_eq!(
+            resp.header.get("vary").map(String::as_str),
+            Some("Accept-Encoding")
+        );
+
+        let mut body = String::new();
+        GzDecoder::new(&resp.body[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "compress me ".repeat(64));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compress_skips_identity_bodies() {
+        type Setup = fn(&mut Response);
+
+        let cases: [(&str, u16, Setup); 5] = [
+            ("range", 206, |resp| {
+                resp.header("Content-Range", "bytes 0-767/2048", true)
+            }),
+            ("range status", 206, |_| {}),
+            ("unsatisfiable range", 416, |resp| {
+                resp.header("Content-Range", "bytes */2048", true)
+            }),
+            ("head", 200, |resp| resp.header_only(true)),
+            ("encoded", 200, |resp| {
+                resp.header("Content-Encoding", "br", true)
+            }),
+        ];
+
+        for (case, status, setup) in cases.iter() {
+            let resp = compressed(*status, *setup);
+
+            assert_eq!(
+                resp.body,
+                "compress me ".repeat(64).into_bytes(),
+                "{}",
+                case
+            );
+            assert!(!resp.header.contains_key("vary"), "{}", case);
+            assert_ne!(
+                resp.header.get("content-encoding").map(String::as_str),
+                Some("gzip"),
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn interned_header_names() {
+        let mut header = HeaderMap::new();
+        header.add("User-Agent", String::from("curl/7.64.0"), true, false);
+        header.add("X-Trace-Id", String::from("abc"), true, false);
+        header.add("ACCEPT", String::from("*/*"), true, false);
+
+        // the well-known names are borrowed, the unknown ones are allocated
+        let mut borrowed: Vec<(&str, bool)> = header
+            .keys()
+            .map(|key| {
+                (
+                    key.as_ref(),
+                    match key {
+                        Cow::Borrowed(_) => true,
+                        Cow::Owned(_) => false,
+                    },
+                )
+            })
+            .collect();
+
+        borrowed.sort();
+        assert_eq!(
+            borrowed,
+            vec![
+                ("accept", true),
+                ("user-agent", true),
+                ("x-trace-id", false)
+            ]
+        );
+
+        let mut req = Box::new(Request::new());
+        req.write_header("HTTP_VERSION", "HTTP/1.1", true);
+        req.set_headers(header);
+        req.write_header("X-Forwarded-For", "10.0.0.1", true);
+
+        assert_eq!(req.header("user-agent"), Some(String::from("curl/7.64.0")));
+        assert_eq!(req.header("accept"), Some(String::from("*/*")));
+        assert_eq!(req.header("x-trace-id"), Some(String::from("abc")));
+        assert_eq!(
+            req.header("x-forwarded-for"),
+            Some(String::from("10.0.0.1"))
+        );
+        assert_eq!(req.header("http_version"), Some(String::from("HTTP/1.1")));
+        assert_eq!(req.header("x-unknown"), None);
+
+        let json = req.json();
+        assert!(json.contains("user-agent:curl/7.64.0"));
+        assert!(json.contains("x-trace-id:abc"));
+        assert!(json.contains("x-forwarded-for:10.0.0.1"));
+    }
+
+    #[test]
+    fn case_insensitive_header() {
+        let mut header = HeaderMap::new();
+        header.add("Content-length", String::from("42"), true, false);
+        header.add(
+            "x-Requested-WITH",
+            String::from("XMLHttpRequest"),
+            true,
+            false,
+        );
+        header.add("X-Api-Key", String::from("secret"), true, false);
+
+        let mut req = Box::new(Request::new());
+        req.set_headers(header);
+
+        for field in &["content-length", "Content-Length", "CONTENT-LENGTH"] {
+            assert_eq!(req.header(field), Some(String::from("42")), "{}", field);
+        }
+
+        assert_eq!(
+            req.header("X-Requested-With"),
+            Some(String::from("XMLHttpRequest"))
+        );
+        assert_eq!(req.header("x-api-key"), Some(String::from("secret")));
+        assert_eq!(req.header("X-API-KEY"), Some(String::from("secret")));
+
+        let mut pairs: Vec<(&str, &str)> = req.header_iter().collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                ("content-length", "42"),
+                ("x-api-key", "secret"),
+                ("x-requested-with", "XMLHttpRequest"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ipv6_host_and_client() {
+        let mut req = Box::new(Request::new());
+        let mut header = crate::hashbrown::HashMap::new();
+        header.insert("host".into(), String::from("[::1]:8080"));
+
+        req.set_headers(header);
+        req.set_client("[::1]:52100".parse().unwrap());
+
+        assert_eq!(req.host_info(), "::1");
+        assert!(req.host_matches("::1"));
+        assert!(req.host_matches("[::1]"));
+        assert!(!req.host_matches("::2"));
+
+        assert_eq!(req.client_ip(), Some("::1".parse().unwrap()));
+        assert_eq!(req.client_info().map(|addr| addr.port()), Some(52100));
+
+        let json = req.json();
+        assert!(json.contains("client_ip:::1"));
+        assert!(json.contains("client_port:52100"));
+        assert!(json.contains("socket_address:[::1]:52100"));
+
+        // the port is stripped from the other hosts too
+        req.set_host(String::from("Example.com:8080"));
+        assert_eq!(req.host_info(), "example.com");
+        assert!(req.host_matches("example.com"));
+        assert!(req.host_matches("EXAMPLE.com:443"));
+
+        req.set_host(String::from("127.0.0.1:8080"));
+        assert_eq!(req.host_info(), "127.0.0.1");
+        assert!(req.host_matches("127.0.0.1"));
+    }
+
+    #[test]
+    fn cross_site_origin() {
+        let cross_site = |origin: Option<&str>| {
+            let mut req = Box::new(Request::new());
+            let mut header = crate::hashbrown::HashMap::new();
+            header.insert("host".into(), String::from("api.example.com:8443"));
+            if let Some(origin) = origin {
+                header.insert("origin".into(), String::from(origin));
+            }
+
+            req.set_headers(header);
+            req.is_cross_site()
+        };
+
+        assert!(!cross_site(None));
+        assert!(!cross_site(Some("https://api.example.com:8443")));
+        assert!(!cross_site(Some("https://API.example.com")));
+        assert!(cross_site(Some("https://app.example.com")));
+        assert!(cross_site(Some("null")));
+    }
+
+    /// Read a chunked message off the wire until its end, without waiting for the connection to
+    /// close: returns the head, the decoded body and the trailer lines.
+    fn read_chunked<R: Read>(client: R) -> (String, String, Vec<String>) {
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+
+        let mut head = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+
+            head.push_str(&line);
+        }
+
+        let mut body = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+
+            let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            assert!(chunk.ends_with(b"\r\n"));
+            body.push_str(str::from_utf8(&chunk[..size]).unwrap());
+        }
+
+        let mut trailers = Vec::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+
+            trailers.push(line.trim_end().to_owned());
+        }
+
+        (head, body, trailers)
+    }
+
+    #[test]
+    fn long_conn_termination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = Stream::Tcp(listener.accept().unwrap().0);
+
+        let mut resp = Box::new(Response::new());
+        resp.status(200);
+        resp.keep_alive(true);
+        resp.send("hello, ");
+        resp.add_trailer("X-Checksum", "abc");
+        resp.add_trailer("x-checksum", "def");
+        resp.add_trailer("Content-Length", "12");
+
+        let (notifier, _subscriber) = resp.get_channels().unwrap();
+        thread::spawn(move || {
+            notifier.send(String::from("long ")).unwrap();
+            notifier.send(String::from("connection")).unwrap();
+            notifier.send(String::new()).unwrap();
+        });
+
+        // hold on to the connection until the client has read the whole message
+        let (done_tx, done_rx) = crate::channel::bounded::<()>(1);
+        let handler = thread::spawn(move || {
+            let clone = server.try_clone().ok();
+            let mut writer = BufWriter::new(&mut server);
+
+            assert!(resp.write_header(&mut writer));
+            resp.keep_long_conn(clone, None, &mut writer);
+            done_rx.recv().unwrap_or_default();
+        });
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let (head, body, trailers) = read_chunked(&mut client);
+        done_tx.send(()).unwrap();
+        handler.join().unwrap();
+
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(head.contains("Trailer: X-Checksum\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, "hello, long connection");
+        assert_eq!(trailers, vec!["X-Checksum: def"]);
+    }
+
+    /// Write the streamed response onto a mock stream, and return the wire, along with if the body
+    /// has been written out in full.
+    fn streamed(resp: &mut Response) -> (String, bool) {
+        let mock = MockStream::new(0);
+        let wire = mock.wire.clone();
+        let mut stream = Stream::Mock(mock);
+
+        let done = {
+            let mut writer = BufWriter::new(&mut stream);
+            assert!(resp.write_header(&mut writer));
+            resp.write_body(&mut writer)
+        };
+
+        let wire = String::from_utf8(wire.lock().unwrap().clone()).unwrap();
+        (wire, done)
+    }
+
+    #[test]
+    fn stream_chunked_body() {
+        let mut resp = Response::new();
+        resp.status(200);
+        resp.send("stale");
+        resp.add_trailer("X-Checksum", "abc");
+        resp.stream(|sink| {
+            sink.write_all(b"id,name\n")?;
+            sink.write_all(b"")?;
+            sink.write_all(b"1,tea\n")?;
+            Ok(())
+        });
+
+        // the streamed body replaces the one sent before, and the empty write won't end it
+        assert!(resp.is_streaming());
+        let (wire, done) = streamed(&mut resp);
+        assert!(done);
+
+        let (head, body, trailers) = read_chunked(wire.as_bytes());
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{}", head);
+        assert!(head.contains("Trailer: X-Checksum\r\n"), "{}", head);
+        assert!(!head.contains("Content-Length"), "{}", head);
+        assert_eq!(body, "id,name\n1,tea\n");
+        assert_eq!(trailers, vec!["X-Checksum: abc"]);
+
+        // a failed stream is cut short, and the body is never terminated
+        let mut resp = Response::new();
+        resp.status(200);
+        resp.set_stream_options(StreamOptions::events());
+        resp.stream(|sink| {
+            sink.write_all(b"partial")?;
+            Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "gone"))
+        });
+
+        let (wire, done) = streamed(&mut resp);
+        assert!(!done);
+        assert!(wire.ends_with("7\r\npartial\r\n"), "{}", wire);
+
+        // and the body of a HEAD request is never streamed
+        let mut resp = Response::new();
+        resp.header_only(true);
+        resp.stream(|sink| sink.write_all(b"skipped"));
+        assert!(!resp.is_streaming());
+    }
+
+    #[test]
+    fn long_conn_messages() {
+        init_test_config();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = Stream::Tcp(listener.accept().unwrap().0);
+
+        let mut resp = Box::new(Response::new());
+        resp.status(200);
+        resp.keep_alive(true);
+
+        let (notifier, subscriber) = resp.get_channels().unwrap();
+        let route = thread::spawn(move || {
+            let messages: Vec<String> = (0..3)
+                .filter_map(|_| subscriber.recv_timeout(Duration::from_secs(5)).ok())
+                .collect();
+
+            notifier.send(String::new()).unwrap();
+            messages
+        });
+
+        let handler = thread::spawn(move || {
+            let clone = server.try_clone().ok();
+            let mut writer = BufWriter::new(&mut server);
+
+            assert!(resp.write_header(&mut writer));
+            resp.keep_long_conn(clone, None, &mut writer);
+        });
+
+        for piece in ["he", "llo\nwor", "ld\r", "\n\nbye", "\n"].iter() {
+            client.write_all(piece.as_bytes()).unwrap();
+            client.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(route.join().unwrap(), vec!["hello", "world", "bye"]);
+        handler.join().unwrap();
+    }
+
+    /// The client end of a stream, which counts the flushes.
+    #[derive(Default)]
+    struct Flushes {
+        wire: Vec<u8>,
+        count: usize,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.wire.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.count += 1;
+            Ok(())
+        }
+    }
+
+    fn flush_counts(options: StreamOptions, sends: &[&str], pause: Duration) -> Vec<usize> {
+        let mut client = Flushes::default();
+        let mut counts = Vec::new();
+
+        {
+            let mut sink = ChunkedWriter::new(&mut client, options);
+            for content in sends {
+                sink.write_all(content.as_bytes()).unwrap();
+                counts.push(sink.buffer.count);
+                thread::sleep(pause);
+            }
+
+            sink.finish(&[]).unwrap();
+        }
+
+        counts.push(client.count);
+        counts
+    }
+
+    #[test]
+    fn stream_flush_policies() {
+        let sends = ["id,name\n", "1,tea\n", "2,cake\n", "3,pie\n"];
+
+        // every send goes out as soon as it's written
+        assert_eq!(
+            flush_counts(StreamOptions::events(), &sends, Duration::from_millis(0)),
+            vec![1, 2, 3, 4, 5]
+        );
+
+        // the small sends are held until the byte threshold, or the end of the body
+        let bytes = StreamOptions {
+            flush_every_bytes: 14,
+            flush_every: None,
+            flush_on_each_send: false,
+        };
+        assert_eq!(
+            flush_counts(bytes, &sends, Duration::from_millis(0)),
+            vec![0, 1, 1, 1, 2]
+        );
+
+        // the bulk defaults don't flush a quick series of small sends before the end
+        assert_eq!(
+            flush_counts(StreamOptions::bulk(), &sends, Duration::from_millis(0)),
+            vec![0, 0, 0, 0, 1]
+        );
+
+        // but a slow one is flushed on the send after the buffered content has waited long enough
+        let timed = StreamOptions {
+            flush_every_bytes: 0,
+            flush_every: Some(Duration::from_millis(30)),
+            flush_on_each_send: false,
+        };
+        assert_eq!(
+            flush_counts(timed, &sends, Duration::from_millis(50)),
+            vec![0, 1, 1, 2, 3]
+        );
+
+        // the chunks are framed the same whatever the flushes are
+        let mut client = Flushes::default();
+        let mut sink = ChunkedWriter::new(&mut client, StreamOptions::bulk());
+        sink.write_all(b"hello, ").unwrap();
+        sink.flush().unwrap();
+        sink.write_all(b"world").unwrap();
+        sink.finish(&[]).unwrap();
+        assert_eq!(
+            client.wire,
+            b"7\r\nhello, \r\n5\r\nworld\r\n0\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn long_conn_flush_policies() {
+        // the messages over the long connection are flushed as they're sent by default
+        let (tx, rx) = channel::unbounded();
+        for message in &["a", "b", "c", ""] {
+            tx.send(message.to_string()).unwrap();
+        }
+
+        let mut client = Flushes::default();
+        let mut sink = ChunkedWriter::new(&mut client, StreamOptions::events());
+        relay_messages(&mut sink, b"initial", Some(&rx)).unwrap();
+        assert_eq!(sink.buffer.count, 4);
+
+        // with a flush interval, what's buffered is flushed while waiting for the next message
+        let timed = StreamOptions {
+            flush_every_bytes: 0,
+            flush_every: Some(Duration::from_millis(20)),
+            flush_on_each_send: false,
+        };
+
+        let (tx, rx) = channel::unbounded();
+        let route = thread::spawn(move || {
+            tx.send(String::from("a")).unwrap();
+            tx.send(String::from("b")).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            tx.send(String::new()).unwrap();
+        });
+
+        let mut client = Flushes::default();
+        let mut sink = ChunkedWriter::new(&mut client, timed);
+        relay_messages(&mut sink, b"", Some(&rx)).unwrap();
+        assert_eq!(sink.buffer.count, 1);
+        // the messages buffered in between go out as one chunk
+        assert_eq!(sink.buffer.wire, b"2\r\nab\r\n".to_vec());
+
+        route.join().unwrap();
+    }
+
+    #[test]
+    fn line_framer_limit() {
+        let mut framer = LineFramer::new(4);
+        assert_eq!(framer.feed(b"abcd\r").unwrap(), Vec::<String>::new());
+        assert_eq!(framer.feed(b"\nab").unwrap(), vec!["abcd"]);
+        assert_eq!(framer.feed(b"\xffc\n").unwrap(), vec!["ab\u{fffd}c"]);
+        assert!(framer.feed(b"abcde").is_none());
+
+        let mut framer = LineFramer::new(4);
+        assert!(framer.feed(b"ab\nabcdef\n").is_none());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn keep_alive_transitions() {
+        let mut resp = Response::new();
+        assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+
+        // the handler toggles between the open states
+        resp.keep_alive(true);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::KeepAlive);
+        resp.keep_alive(false);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+
+        resp.set_header("Connection", "Keep-Alive");
+        assert_eq!(resp.keep_alive, KeepAliveStatus::KeepAlive);
+        resp.set_header("connection", "close");
+        assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+
+        // without replacing, the header can't turn the keep-alive off
+        resp.keep_alive(true);
+        resp.header("connection", "close", false);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::KeepAlive);
+
+        // the deprecated alias behaves the same
+        resp.can_keep_alive(false);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+        resp.can_keep_alive(true);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::KeepAlive);
+
+        // the reserved states can't be reached from the header values
+        for value in ["tls", "forbidden", "TLS"].iter() {
+            let mut resp = Response::new();
+            resp.set_header("connection", value);
+            assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+
+            let mut header = HashMap::new();
+            header.insert(String::from("connection"), value.to_string());
+            resp.with_headers(header);
+            assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+        }
+
+        // once the framework forbids the keep-alive, the handler can't flip it back on
+        let mut resp = Response::new();
+        resp.keep_alive(true);
+        resp.forbid_keep_alive();
+        assert_eq!(resp.keep_alive, KeepAliveStatus::Forbidden);
+
+        resp.keep_alive(true);
+        resp.can_keep_alive(true);
+        resp.set_header("connection", "keep-alive");
+        let mut header = HashMap::new();
+        header.insert(String::from("connection"), String::from("keep-alive"));
+        resp.with_headers(header);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::Forbidden);
+        assert!(!resp.to_keep_alive());
+
+        // the TLS connection stays marked as such, even if forbidden afterwards
+        let mut resp = Response::new();
+        resp.set_tls_conn();
+        resp.keep_alive(true);
+        resp.set_header("connection", "forbidden");
+        resp.forbid_keep_alive();
+        assert_eq!(resp.keep_alive, KeepAliveStatus::TlsConn);
+        assert!(!resp.to_keep_alive());
+
+        // and the framework resets the state for the next response
+        resp.reset(false);
+        assert_eq!(resp.keep_alive, KeepAliveStatus::NotSet);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct UserId(u64);
+
+    #[test]
+    fn extensions_recycled() {
+        let mut pool: SyncPool<Request> = SyncPool::with_size(1);
+
+        let mut req = pool.get();
+        req.set_ext(UserId(7));
+        req.set_ext(String::from("en-US"));
+        req.set_ext(UserId(42));
+
+        assert_eq!(req.get_ext::<UserId>(), Some(&UserId(42)));
+        assert_eq!(req.get_ext::<String>().map(String::as_str), Some("en-US"));
+        assert!(req.get_ext::<u64>().is_none());
+
+        // the generic setter doesn't keep the writer from being used as a trait object
+        let writer: &mut dyn RequestWriter = &mut *req;
+        writer.set_fragment(String::from("#top"));
+        assert_eq!(req.uri_fragment(), "#top");
+
+        // back to the pool as `Reusable::release` does, then check out everything in the pool
+        let recycled = &*req as *const Request;
+        req.reset(false);
+        pool.put(req);
+
+        let mut drained = Vec::new();
+        while pool.len() > 0 {
+            drained.push(pool.get());
+        }
+
+        assert!(drained
+            .iter()
+            .any(|req| &**req as *const Request == recycled));
+        for req in drained.iter() {
+            assert!(req.get_ext::<UserId>().is_none());
+            assert!(req.get_ext::<String>().is_none());
+        }
+    }
+
+    /// A request with every field filled, as if it had been served. The struct literal makes sure
+    /// the new fields are poisoned as well.
+    fn poisoned_request() -> Box<Request> {
+        let stale = |s: &str| String::from(s);
+        let mut req = Box::new(Request {
+            method: REST::POST,
+            uri: stale("/stale"),
+            params: vec![(stale("id"), stale("7"))].into_iter().collect(),
+            query: vec![(stale("q"), vec![stale("1")])].into_iter().collect(),
+            query_pairs: vec![(stale("q"), stale("1"))],
+            header: HeaderMap::new(),
+            cookie: vec![(stale("sid"), stale("abc"))].into_iter().collect(),
+            fragment: stale("section"),
+            host: stale("stale.example"),
+            body: stale("payload"),
+            raw_body: Some(b"payload".to_vec()),
+            client_info: "127.0.0.1:8080".parse().ok(),
+            route_pattern: stale("/:id"),
+            trace_ids: TraceIds::new(3, 4),
+            extensions: HashMap::new(),
+        });
+
+        req.write_header("Host", "stale.example", true);
+        req.set_ext(UserId(7));
+        req
+    }
+
+    /// A response with every field filled, as if it had been served.
+    fn poisoned_response() -> Box<Response> {
+        let (body_tx, body_rx) = channel::unbounded();
+
+        Box::new(Response {
+            status: 503,
+            keep_alive: KeepAliveStatus::KeepAlive,
+            content_type: String::from("text/plain"),
+            content_length: Some(String::from("5")),
+            header: vec![(String::from("x-stale"), String::from("1"))]
+                .into_iter()
+                .collect(),
+            cookie: vec![(String::from("sid"), Cookie::new("sid", "abc"))]
+                .into_iter()
+                .collect(),
+            header_only: true,
+            stat_only: true,
+            size_hint: Some(5),
+            redirect: String::from("/elsewhere"),
+            body: b"stale".to_vec(),
+            body_chan: (Some(body_tx), Some(body_rx)),
+            body_stream: Some(Box::new(|_: &mut dyn Write| Ok(()))),
+            stream_options: Some(StreamOptions::events()),
+            json_err: Some((String::from("/stale"), TraceIds::new(3, 4))),
+            #[cfg(feature = "compression")]
+            encoding: Some(Encoding::Gzip),
+            notifier: Some(channel::bounded(1)),
+            subscriber: Some(channel::bounded(1)),
+            trailers: vec![(String::from("X-Checksum"), String::from("abc"))],
+            audit: vec![OverrideReason::ErrorPage(503)],
+            cross_site: true,
+            views: Some(Arc::new(ViewsScope::default())),
+            #[cfg(feature = "websocket")]
+            websocket: None,
+        })
+    }
+
+    #[test]
+    fn pooled_objects_blank() {
+        let mut requests: SyncPool<Request> = SyncPool::with_size(1);
+        let mut responses: SyncPool<Response> = SyncPool::with_size(1);
+
+        let mut req = poisoned_request();
+        let mut resp = poisoned_response();
+        assert!(!req.is_blank());
+        assert!(!resp.is_blank());
+
+        // back to the pools as `Reusable::release` does, and checked out again
+        req.reset(false);
+        resp.reset(false);
+        requests.put(req);
+        responses.put(resp);
+
+        let mut drained = Vec::new();
+        while requests.len() > 0 {
+            drained.push(requests.get());
+        }
+        assert!(drained.iter().all(|req| req.is_blank()));
+
+        let mut drained = Vec::new();
+        while responses.len() > 0 {
+            drained.push(responses.get());
+        }
+        assert!(drained.iter().all(|resp| resp.is_blank()));
+
+        // and the host of a request without the `Host` header is not made up
+        let mut req = poisoned_request();
+        req.set_headers(HeaderMap::new());
+        assert!(req.host_info().is_empty());
+    }
+
+    #[test]
+    fn pooled_buffers_shrink() {
+        let mut responses: SyncPool<Response> = SyncPool::with_size(1);
+
+        // a 10MB download with plenty of header fields, and a small page
+        let mut large = responses.get();
+        large.set_content_type(&"application/octet-stream; ".repeat(4096));
+        large.send(&"x".repeat(10 * 1024 * 1024));
+        (0..2048).for_each(|i| large.header(&format!("x-field-{}", i), &"v".repeat(64), true));
+
+        let mut small = responses.get();
+        small.send(&"x".repeat(1024));
+
+        assert!(large.retained_bytes() > 10 * 1024 * 1024);
+        let kept = small.retained_bytes();
+
+        // back to the pool as `Reusable::release` does
+        large.reset(false);
+        small.reset(false);
+        responses.put(large);
+        responses.put(small);
+
+        let mut drained = Vec::new();
+        while responses.len() > 0 {
+            drained.push(responses.get());
+        }
+
+        let retained: Vec<usize> = drained.iter().map(|resp| resp.retained_bytes()).collect();
+        assert!(
+            retained.iter().all(|bytes| *bytes <= DEFAULT_SHRINK_BYTES),
+            "{:?}",
+            retained
+        );
+
+        // the small one keeps its buffers for the next response, unless it's a hard reset
+        assert!(retained.contains(&kept), "{:?}", retained);
+
+        let mut small = drained.pop().unwrap();
+        small.send(&"x".repeat(1024));
+        small.reset(true);
+        assert_eq!(small.retained_bytes(), 0);
+    }
+
+    #[test]
+    fn doc_root_paths() {
+        init_test_config();
+
+        let base = env::temp_dir().join(format!("rex-doc-root-{}", std::process::id()));
+        let public = base.join("public");
+        fs::create_dir_all(&public).unwrap();
+        fs::write(public.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(base.join("secret.txt"), "hunter2").unwrap();
+
+        ServerConfig::set_doc_root(public);
+
+        let send = |path: &str| {
+            let mut resp = Response::new();
+            let status = resp.send_file(path);
+            (status, String::from_utf8_lossy(&resp.body).into_owned())
+        };
+
+        let hit = send("index.html");
+        let escape = send("../secret.txt");
+        let absolute = send(base.join("secret.txt").to_str().unwrap());
+
+        ServerConfig::clear_doc_root();
+        fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(hit, (200, String::from("<h1>home</h1>")));
+        assert_eq!(escape.0, 404);
+        assert_eq!(absolute, (200, String::from("hunter2")));
+    }
+
+    struct Page(&'static str);
+
+    impl EngineContext for Page {
+        fn display(&self, field: &str) -> Result<String, String> {
+            match field {
+                "title" => Ok(self.0.to_owned()),
+                _ => Err(format!("Unknown field: {}", field)),
+            }
+        }
+    }
+
+    /// Replace `{{> name}}` with the sibling template of the name, and `{{title}}` with the title.
+    fn with_partials(
+        content: &mut String,
+        path: &Path,
+        context: Box<dyn EngineContext + Send + Sync>,
+    ) -> u16 {
+        while let Some(start) = content.find("{{> ") {
+            let end = match content[start..].find("}}") {
+                Some(end) => start + end,
+                None => return 500,
+            };
+
+            let mut partial = path.with_file_name(&content[start + 4..end]);
+            partial.set_extension(path.extension().unwrap());
+
+            match fs::read_to_string(partial) {
+                Ok(text) => content.replace_range(start..end + 2, &text),
+                Err(_) => return 500,
+            }
+        }
+
+        *content = content.replace("{{title}}", &context.display("title").unwrap());
+        200
+    }
+
+    #[test]
+    fn template_partials() {
+        init_test_config();
+
+        let views = env::temp_dir().join(format!("rex-views-{}", std::process::id()));
+        fs::create_dir_all(&views).unwrap();
+        fs::write(views.join("page.hbs"), "<main>{{> header}}</main>").unwrap();
+        fs::write(views.join("header.hbs"), "<h1>{{title}}</h1>").unwrap();
+        fs::write(views.join("page.handlebars"), "{{> header}}!").unwrap();
+        fs::write(views.join("header.handlebars"), "{{title}}").unwrap();
+        fs::write(views.join("article.mst"), "<p>{{title}}</p>").unwrap();
+        fs::write(views.join("main.mst"), "<title>{{title}}</title>{{> body}}").unwrap();
+
+        ServerConfig::view_engine_multi(&["hbs", ".handlebars"], with_partials);
+        ServerConfig::view_engine("mst", template::render);
+        ServerConfig::set_views_root(views.clone());
+
+        let render = |path: &str| {
+            let mut resp = Response::new();
+            let status = resp.send_template(path, Box::new(Page("Hello")));
+            (status, String::from_utf8_lossy(&resp.body).into_owned())
+        };
+
+        let page = render("page.hbs");
+        let alias = render("page.handlebars");
+        let escape = render("../page.hbs");
+
+        // the layout name reaches the built-in engine through the context
+        let mut resp = Response::new();
+        let status = resp.send_template_with_layout("article.mst", "main", Box::new(Page("Hi")));
+        let layout = (status, String::from_utf8_lossy(&resp.body).into_owned());
+
+        let mut resp = Response::new();
+        let missing = resp.send_template_with_layout("article.mst", "none", Box::new(Page("Hi")));
+
+        ServerConfig::clear_views_root();
+        fs::remove_dir_all(&views).unwrap();
+
+        assert_eq!(page, (200, String::from("<main><h1>Hello</h1></main>")));
+        assert_eq!(alias, (200, String::from("Hello!")));
+        assert_eq!(escape.0, 404);
+        assert_eq!(layout, (200, String::from("<title>Hi</title><p>Hi</p>")));
+        assert_eq!(missing, 500);
+    }
+
+    #[test]
+    fn response_audit() {
+        init_test_config();
+
+        // nothing is overridden
+        let mut resp = Response::new();
+        resp.status(201);
+        resp.send("created");
+        resp.validate_and_update();
+        assert!(resp.audit().is_empty());
+        assert_eq!(resp.audit.capacity(), 0);
+
+        // the empty body is replaced by the error page
+        let mut resp = Response::new();
+        resp.validate_and_update();
+        assert_eq!(resp.audit(), &[OverrideReason::ErrorPage(404)]);
+
+        // the unknown status is dropped, then guessed from the body
+        let mut resp = Response::new();
+        resp.status(299);
+        resp.send("ok");
+        resp.validate_and_update();
+        assert_eq!(
+            resp.audit(),
+            &[
+                OverrideReason::UnrecognizedStatus(299),
+                OverrideReason::InferredStatus(200)
+            ]
+        );
+
+        // the body of a 204 is dropped
+        let mut resp = Response::new();
+        resp.status(204);
+        resp.send("ignored");
+        resp.validate_and_update();
+        assert!(resp.is_header_only());
+        assert_eq!(resp.audit(), &[OverrideReason::HeaderOnly(204)]);
+
+        // a failed async segment wipes the body
+        let mut resp = Response::new();
+        let (tx, rx) = channel::unbounded();
+        tx.send((Vec::from("part"), 200)).unwrap();
+        tx.send((Vec::new(), 503)).unwrap();
+        resp.body_chan = (Some(tx), Some(rx));
+        resp.validate_and_update();
+        assert_eq!(resp.get_status(), 500);
+        assert_eq!(
+            resp.audit(),
+            &[
+                OverrideReason::AsyncBodyFailed(503),
+                OverrideReason::ErrorPage(500)
+            ]
+        );
+
+        // the audit doesn't outlive the response
+        resp.reset(false);
+        assert!(resp.audit().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! The `ipfilter` module screens the connections by the address of the peer right after they're
+//! accepted, before any byte of the request is read, following the filter set with
+//! `ServerConfig::set_ip_filter`. A peer in any of the `deny` blocks is rejected, and when the
+//! `allow` list is not empty, so is any peer outside of it.
+//!
+//! ```no_run
+//! use rusty_express::prelude::*;
+//!
+//! let mut server = HttpServer::new();
+//!
+//! server.config().set_ip_filter(IpFilter {
+//!     allow: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+//!     deny: vec!["10.6.6.0/24".parse().unwrap()],
+//!     ..Default::default()
+//! });
+//!
+//! server.listen(8080);
+//! ```
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::str::FromStr;
+
+use crate::core::conn;
+use crate::core::stream::Stream;
+
+/// A block of addresses, e.g. `192.168.0.0/16` or `2001:db8::/32`. A bare address is a block of
+/// its own, i.e. `/32` for IPv4 and `/128` for IPv6.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CidrBlock {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl CidrBlock {
+    /// Create the block from the address and the length of the prefix, where the bits of the
+    /// address beyond the prefix are ignored.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let network = match addr {
+            IpAddr::V4(v4) if prefix <= 32 => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & v4_mask(prefix)))
+            }
+            IpAddr::V6(v6) if prefix <= 128 => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & v6_mask(prefix)))
+            }
+            _ => return Err(format!("Invalid prefix length /{} for {}", prefix, addr)),
+        };
+
+        Ok(CidrBlock { network, prefix })
+    }
+
+    /// If the address is within the block. An IPv4-mapped IPv6 address, e.g. `::ffff:10.0.0.1`,
+    /// is matched as the IPv4 address it carries.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.network, unmap(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                u32::from(addr) & v4_mask(self.prefix) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                u128::from(addr) & v6_mask(self.prefix) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for CidrBlock {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parts = source.trim().splitn(2, '/');
+
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| format!("Invalid address in the block: {}", source))?;
+
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| format!("Invalid prefix length in the block: {}", source))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        CidrBlock::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for CidrBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// The filter on the address of the peers. The `deny` blocks are checked first, then the `allow`
+/// blocks if there are any. A rejected connection is closed without a response, unless
+/// `send_forbidden` is set, then it's answered with a `403 Forbidden` first; either way, the
+/// `on_reject` hook is called with the address of the peer.
+#[derive(Clone, Default)]
+pub struct IpFilter {
+    pub allow: Vec<CidrBlock>,
+    pub deny: Vec<CidrBlock>,
+    pub send_forbidden: bool,
+    pub on_reject: Option<fn(SocketAddr)>,
+}
+
+impl IpFilter {
+    /// If the connections from the address may be served.
+    pub fn permits(&self, addr: &IpAddr) -> bool {
+        if self.deny.iter().any(|block| block.contains(addr)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|block| block.contains(addr))
+    }
+
+    /// Hand back the stream if the peer may be served, or reject it otherwise. A peer whose address
+    /// can't be told is rejected, unless the filter is empty.
+    pub(crate) fn admit(&self, stream: TcpStream) -> Option<TcpStream> {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Some(stream);
+        }
+
+        let peer = match stream.peer_addr() {
+            Ok(peer) if self.permits(&peer.ip()) => return Some(stream),
+            Ok(peer) => Some(peer),
+            Err(_) => None,
+        };
+
+        if let (Some(hook), Some(peer)) = (self.on_reject, peer) {
+            hook(peer);
+        }
+
+        if self.send_forbidden {
+            conn::send_err_resp(Stream::Tcp(stream), 403);
+        } else {
+            stream.shutdown(Shutdown::Both).unwrap_or_default();
+        }
+
+        None
+    }
+}
+
+#[inline]
+fn v4_mask(prefix: u8) -> u32 {
+    match prefix {
+        0 => 0,
+        _ => u32::MAX << (32 - u32::from(prefix)),
+    }
+}
+
+#[inline]
+fn v6_mask(prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => u128::MAX << (128 - u32::from(prefix)),
+    }
+}
+
+fn unmap(addr: &IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*addr, IpAddr::V4),
+        IpAddr::V4(_) => *addr,
+    }
+}
+
+#[cfg(test)]
+mod ipfilter_test {
+    use super::{CidrBlock, IpFilter};
+    use std::net::IpAddr;
+
+    fn ip(source: &str) -> IpAddr {
+        source.parse().unwrap()
+    }
+
+    fn block(source: &str) -> CidrBlock {
+        source.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_blocks() {
+        // the whole address space of its family
+        assert!(block("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(!block("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(block("::/0").contains(&ip("2001:db8::1")));
+
+        // a single address
+        assert!(block("10.1.2.3/32").contains(&ip("10.1.2.3")));
+        assert!(!block("10.1.2.3/32").contains(&ip("10.1.2.4")));
+        assert!(block("2001:db8::1/128").contains(&ip("2001:db8::1")));
+        assert!(!block("2001:db8::1/128").contains(&ip("2001:db8::2")));
+        assert_eq!(block("10.1.2.3"), block("10.1.2.3/32"));
+        assert_eq!(block("2001:db8::1"), block("2001:db8::1/128"));
+
+        // the host bits are ignored, and the mapped addresses match the IPv4 blocks
+        assert_eq!(block("192.168.7.9/16").to_string(), "192.168.0.0/16");
+        assert!(block("192.168.0.0/16").contains(&ip("192.168.255.1")));
+        assert!(!block("192.168.0.0/16").contains(&ip("192.169.0.1")));
+        assert!(block("2001:db8::/32").contains(&ip("2001:db8:ffff::1")));
+        assert!(block("127.0.0.0/8").contains(&ip("::ffff:127.0.0.1")));
+
+        for bad in &[
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "/8",
+            "10.0.0.0/-1",
+        ] {
+            assert!(bad.parse::<CidrBlock>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn filter_lists() {
+        let mut filter = IpFilter::default();
+        assert!(filter.permits(&ip("198.51.100.1")));
+
+        filter.allow = vec![block("10.0.0.0/8")];
+        assert!(filter.permits(&ip("10.9.9.9")));
+        assert!(!filter.permits(&ip("198.51.100.1")));
+
+        // deny wins over allow
+        filter.deny = vec![block("10.6.6.0/24")];
+        assert!(!filter.permits(&ip("10.6.6.6")));
+        assert!(filter.permits(&ip("10.6.7.6")));
+    }
+}
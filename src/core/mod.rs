@@ -2,9 +2,17 @@ pub mod config;
 pub(crate) mod conn;
 pub mod context;
 pub mod cookie;
+pub mod cors;
 pub mod http;
+pub mod ipfilter;
+pub mod multipart;
 pub mod router;
 pub mod server;
 pub mod states;
+pub mod stats;
 pub(crate) mod stream;
 pub(crate) mod syncstore;
+pub mod template;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
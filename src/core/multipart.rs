@@ -0,0 +1,490 @@
+//! The `multipart` module parses the `multipart/form-data` request bodies, i.e. the forms with
+//! file uploads. The parts are found with `Request::multipart`:
+//!
+//! ```
+//! use rusty_express::prelude::*;
+//! use std::io::Read;
+//!
+//! pub fn upload(req: &Box<Request>, resp: &mut Box<Response>) {
+//!     let form = match req.multipart() {
+//!         Ok(form) => form,
+//!         Err(e) => {
+//!             resp.status(400);
+//!             resp.send(&e.to_string());
+//!             return;
+//!         }
+//!     };
+//!
+//!     for part in form.iter() {
+//!         let mut content = Vec::new();
+//!         part.reader().read_to_end(&mut content).unwrap();
+//!
+//!         resp.send(&format!(
+//!             "{} ({}): {} bytes\n",
+//!             part.name(),
+//!             part.filename().unwrap_or("field"),
+//!             content.len()
+//!         ));
+//!     }
+//! }
+//! ```
+//!
+//! The size of each part, and of all the parts together, can be capped with
+//! `ServerConfig::set_multipart_limits`.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Cursor;
+use std::slice::Iter;
+
+const CRLF: &[u8] = b"\r\n";
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+/// The reasons a request body can't be parsed as `multipart/form-data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultipartError {
+    /// The `Content-Type` of the request is not `multipart/form-data`.
+    NotMultipart,
+
+    /// The `Content-Type` of the request has no boundary, or the boundary is invalid.
+    MissingBoundary,
+
+    /// The body doesn't follow the multipart format, e.g. a part without the headers, or the
+    /// closing boundary is missing.
+    Malformed(&'static str),
+
+    /// The content of the part with the name is larger than the cap in bytes.
+    PartTooLarge(String, usize),
+
+    /// The contents of all the parts are larger than the cap in bytes.
+    TooLarge(usize),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "The request is not multipart/form-data"),
+            MultipartError::MissingBoundary => write!(f, "The multipart boundary is missing"),
+            MultipartError::Malformed(reason) => write!(f, "Malformed multipart body: {}", reason),
+            MultipartError::PartTooLarge(name, limit) => {
+                write!(f, "The part '{}' is larger than {} bytes", name, limit)
+            }
+            MultipartError::TooLarge(limit) => {
+                write!(f, "The multipart body is larger than {} bytes", limit)
+            }
+        }
+    }
+}
+
+impl Error for MultipartError {}
+
+/// One part of the multipart body, e.g. a form field or an uploaded file.
+#[derive(Debug, Clone, Default)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+    data: Vec<u8>,
+}
+
+impl Part {
+    /// The name of the form field, from the `Content-Disposition` header of the part.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the uploaded file, if the part is a file.
+    #[inline]
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_ref().map(String::as_str)
+    }
+
+    /// The `Content-Type` of the part, if it's given, e.g. `image/png` for an uploaded image.
+    #[inline]
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_ref().map(String::as_str)
+    }
+
+    /// The value of the header of the part, where the field name is case-insensitive.
+    pub fn header(&self, field: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(f, _)| f.eq_ignore_ascii_case(field))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The content of the part as it's received.
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The content of the part as text, or `None` if it's not valid UTF-8.
+    #[inline]
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.data).ok()
+    }
+
+    /// Read the content of the part, e.g. to copy the uploaded file to the disk with `io::copy`.
+    /// The request body has been received in full, so the reader never blocks.
+    #[inline]
+    pub fn reader(&self) -> Cursor<&[u8]> {
+        Cursor::new(&self.data)
+    }
+
+    /// Take the content of the part without copying it.
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// The parts of the multipart body, in the order they appear in the body.
+#[derive(Debug, Clone, Default)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    /// Parse the body with the `Content-Type` of the request. Each part may take at most
+    /// `part_limit` bytes, and all the parts together at most `total_limit` bytes, where 0 stands
+    /// for no cap.
+    pub(crate) fn parse(
+        content_type: &str,
+        body: &[u8],
+        part_limit: usize,
+        total_limit: usize,
+    ) -> Result<Multipart, MultipartError> {
+        let boundary = boundary(content_type)?;
+        let delimiter = [b"--", boundary.as_bytes()].concat();
+
+        // skip the preamble before the first boundary
+        let mut pos = match find(body, &delimiter, 0) {
+            Some(0) => delimiter.len(),
+            _ => match find(body, &[CRLF, &delimiter[..]].concat(), 0) {
+                Some(start) => start + CRLF.len() + delimiter.len(),
+                None => return Err(MultipartError::Malformed("the boundary is not found")),
+            },
+        };
+
+        let next_delimiter = [CRLF, &delimiter[..]].concat();
+        let mut parts = Vec::new();
+        let mut total = 0;
+
+        loop {
+            if body[pos..].starts_with(b"--") {
+                // the closing boundary, and the epilogue is ignored
+                break;
+            }
+
+            // the transport padding after the boundary
+            while pos < body.len() && (body[pos] == b' ' || body[pos] == b'\t') {
+                pos += 1;
+            }
+
+            if !body[pos..].starts_with(CRLF) {
+                return Err(MultipartError::Malformed("the boundary is not terminated"));
+            }
+
+            pos += CRLF.len();
+
+            // the part headers can be empty, then the blank line follows the boundary right away
+            let (head, data_start) = if body[pos..].starts_with(CRLF) {
+                (&body[pos..pos], pos + CRLF.len())
+            } else {
+                match find(body, HEAD_END, pos) {
+                    Some(end) => (&body[pos..end], end + HEAD_END.len()),
+                    None => {
+                        return Err(MultipartError::Malformed("the part headers are not closed"))
+                    }
+                }
+            };
+
+            let data_end = match find(body, &next_delimiter, data_start) {
+                Some(end) => end,
+                None => return Err(MultipartError::Malformed("the closing boundary is missing")),
+            };
+
+            let mut part = parse_part_head(head)?;
+            let size = data_end - data_start;
+
+            if part_limit > 0 && size > part_limit {
+                return Err(MultipartError::PartTooLarge(part.name, part_limit));
+            }
+
+            total += size;
+            if total_limit > 0 && total > total_limit {
+                return Err(MultipartError::TooLarge(total_limit));
+            }
+
+            part.data = body[data_start..data_end].to_vec();
+            parts.push(part);
+
+            pos = data_end + next_delimiter.len();
+        }
+
+        Ok(Multipart { parts })
+    }
+
+    /// The first part with the name.
+    pub fn part(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|p| p.name == name)
+    }
+
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, Part> {
+        self.parts.iter()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Take the parts, e.g. to keep the uploaded files without copying them.
+    #[inline]
+    pub fn into_parts(self) -> Vec<Part> {
+        self.parts
+    }
+}
+
+impl IntoIterator for Multipart {
+    type Item = Part;
+    type IntoIter = std::vec::IntoIter<Part>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.parts.into_iter()
+    }
+}
+
+/// The boundary from the `Content-Type`, e.g. `multipart/form-data; boundary="abc"`.
+fn boundary(content_type: &str) -> Result<String, MultipartError> {
+    let mut params = content_type.split(';');
+
+    let mime = params.next().unwrap_or_default().trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(MultipartError::NotMultipart);
+    }
+
+    let boundary = params
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            Some((kv.next()?.trim(), kv.next()?.trim()))
+        })
+        .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"'))
+        .unwrap_or_default();
+
+    // RFC 2046 limits the boundary to 70 characters
+    if boundary.is_empty() || boundary.len() > 70 {
+        return Err(MultipartError::MissingBoundary);
+    }
+
+    Ok(boundary.to_owned())
+}
+
+fn parse_part_head(head: &[u8]) -> Result<Part, MultipartError> {
+    let head = String::from_utf8_lossy(head);
+    let mut part = Part::default();
+    let mut has_disposition = false;
+
+    for line in head.split("\r\n") {
+        let mut kv = line.splitn(2, ':');
+        let (field, value) = match (kv.next(), kv.next()) {
+            (Some(field), Some(value)) => (field.trim(), value.trim()),
+            _ => continue,
+        };
+
+        if field.eq_ignore_ascii_case("content-disposition") {
+            has_disposition = true;
+
+            for (key, val) in disposition_params(value) {
+                if key.eq_ignore_ascii_case("name") {
+                    part.name = val;
+                } else if key.eq_ignore_ascii_case("filename") {
+                    part.filename = Some(val);
+                }
+            }
+        } else if field.eq_ignore_ascii_case("content-type") {
+            part.content_type = Some(value.to_owned());
+        }
+
+        part.headers.push((field.to_owned(), value.to_owned()));
+    }
+
+    if !has_disposition {
+        return Err(MultipartError::Malformed(
+            "the part has no Content-Disposition header",
+        ));
+    }
+
+    Ok(part)
+}
+
+/// The params of the `Content-Disposition`, e.g. `form-data; name="file"; filename="a;b.txt"`,
+/// where the quoted values may contain the `;` and the escaped quotes.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    // skip the disposition type
+    while let Some(c) = chars.next() {
+        if c == ';' {
+            break;
+        }
+    }
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .skip_while(|c| c.is_whitespace() || *c == ';')
+            .take_while(|c| *c != '=')
+            .collect();
+
+        if key.is_empty() {
+            break;
+        }
+
+        let mut val = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => val.extend(chars.next()),
+                    '"' => break,
+                    _ => val.push(c),
+                }
+            }
+
+            // drop the rest up to the next param
+            while let Some(c) = chars.next() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            val = chars.by_ref().take_while(|c| *c != ';').collect();
+        }
+
+        params.push((key.trim().to_owned(), val.trim().to_owned()));
+    }
+
+    params
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() || needle.is_empty() {
+        return None;
+    }
+
+    haystack[from..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|pos| from + pos)
+}
+
+#[cfg(test)]
+mod multipart_test {
+    use super::{disposition_params, Multipart, MultipartError};
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=----RexBoundary7MA4YWxk";
+
+    fn upload_body(file: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(b"preamble to be ignored\r\n");
+        body.extend_from_slice(b"------RexBoundary7MA4YWxk\r\n");
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"title\"\r\n\r\n");
+        body.extend_from_slice(b"My upload\r\n\r\nwith a blank line");
+        body.extend_from_slice(b"\r\n------RexBoundary7MA4YWxk\r\n");
+        body.extend_from_slice(
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"pixel;1.png\"\r\n",
+        );
+        body.extend_from_slice(b"Content-Type: image/png\r\n\r\n");
+        body.extend_from_slice(file);
+        body.extend_from_slice(b"\r\n------RexBoundary7MA4YWxk--\r\n");
+        body
+    }
+
+    #[test]
+    fn text_field_and_binary_file() {
+        // not valid UTF-8, and carries the line breaks
+        let file = [
+            0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, 0x00, 0xff, b'\r', b'\n',
+        ];
+        let form = Multipart::parse(CONTENT_TYPE, &upload_body(&file), 0, 0).unwrap();
+
+        assert_eq!(form.len(), 2);
+
+        let title = form.part("title").unwrap();
+        assert_eq!(title.filename(), None);
+        assert_eq!(title.content_type(), None);
+        assert_eq!(title.text(), Some("My upload\r\n\r\nwith a blank line"));
+
+        let upload = form.part("file").unwrap();
+        assert_eq!(upload.filename(), Some("pixel;1.png"));
+        assert_eq!(upload.content_type(), Some("image/png"));
+        assert_eq!(upload.header("content-type"), Some("image/png"));
+        assert_eq!(upload.bytes(), &file[..]);
+        assert!(upload.text().is_none());
+
+        // the caps
+        assert_eq!(
+            Multipart::parse(CONTENT_TYPE, &upload_body(&file), 16, 0).unwrap_err(),
+            MultipartError::PartTooLarge(String::from("title"), 16)
+        );
+        assert_eq!(
+            Multipart::parse(CONTENT_TYPE, &upload_body(&file), 0, 32).unwrap_err(),
+            MultipartError::TooLarge(32)
+        );
+    }
+
+    #[test]
+    fn rejected_bodies() {
+        let body = upload_body(b"data");
+
+        assert_eq!(
+            Multipart::parse("application/json", &body, 0, 0).unwrap_err(),
+            MultipartError::NotMultipart
+        );
+        assert_eq!(
+            Multipart::parse("multipart/form-data", &body, 0, 0).unwrap_err(),
+            MultipartError::MissingBoundary
+        );
+
+        // truncated before the closing boundary
+        let truncated = &body[..body.len() - 30];
+        assert!(match Multipart::parse(CONTENT_TYPE, truncated, 0, 0) {
+            Err(MultipartError::Malformed(_)) => true,
+            _ => false,
+        });
+
+        // the quoted boundary, and an empty form
+        let form = Multipart::parse(
+            "Multipart/Form-Data; boundary=\"empty form\"",
+            b"--empty form--\r\n",
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(form.is_empty());
+    }
+
+    #[test]
+    fn disposition_quoting() {
+        assert_eq!(
+            disposition_params(r#"form-data; name="a\"b"; filename=plain.txt"#),
+            vec![
+                (String::from("name"), String::from("a\"b")),
+                (String::from("filename"), String::from("plain.txt")),
+            ]
+        );
+    }
+}
@@ -1,31 +1,34 @@
 #![allow(unused)]
 #![allow(clippy::borrowed_box)]
 
+use std::borrow::Cow;
+use std::cmp::Reverse;
 use std::fmt;
 use std::fs;
 use std::path::PathBuf;
-use std::ptr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 
 use crate::channel;
 use crate::core::http::{Request, Response, ResponseWriter};
 use crate::core::syncstore::StaticStore;
 use crate::hashbrown::{HashMap, HashSet};
-use crate::regex::Regex;
+use crate::parking_lot::RwLock;
+use crate::regex::{self, Regex};
 use crate::support::common::cpu_relax;
-use crate::support::{
-    common::MapUpdates,
-    debug::{self, InfoLevel},
-    Field, RouteTrie,
-};
+use crate::support::{common::MapUpdates, Field, RouteCache, RouteTrie};
 use std::sync::Arc;
 
-//TODO: impl route caching: 1) only explicit and wildcard will get cached ... especially the wildcard
-//      one. 2) store uri in the "method:path" format.
-
 static mut ROUTER: StaticStore<(Route, AtomicUsize)> = StaticStore::init();
-static mut ROUTE_CACHE: StaticStore<HashMap<(REST, String), RouteHandler>> = StaticStore::init();
+static mut ROUTE_CACHE: StaticStore<RwLock<RouteCache>> = StaticStore::init();
+static CACHE_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+thread_local! {
+    static READ_CHECKOUTS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub enum REST {
@@ -35,9 +38,31 @@ pub enum REST {
     PUT,
     DELETE,
     OPTIONS,
+    HEAD,
+    TRACE,
+    CONNECT,
     OTHER(String),
 }
 
+impl REST {
+    /// Map the method token from the request line to its variant. Custom verbs are kept as
+    /// `REST::OTHER` in upper case.
+    pub(crate) fn parse(method: &str) -> REST {
+        match &method.to_uppercase()[..] {
+            "GET" => REST::GET,
+            "PATCH" => REST::PATCH,
+            "POST" => REST::POST,
+            "PUT" => REST::PUT,
+            "DELETE" => REST::DELETE,
+            "OPTIONS" => REST::OPTIONS,
+            "HEAD" => REST::HEAD,
+            "TRACE" => REST::TRACE,
+            "CONNECT" => REST::CONNECT,
+            other => REST::OTHER(other.to_owned()),
+        }
+    }
+}
+
 impl fmt::Display for REST {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -47,6 +72,9 @@ impl fmt::Display for REST {
             REST::PUT => write!(fmt, "PUT"),
             REST::DELETE => write!(fmt, "DELETE"),
             REST::OPTIONS => write!(fmt, "OPTIONS"),
+            REST::HEAD => write!(fmt, "HEAD"),
+            REST::TRACE => write!(fmt, "TRACE"),
+            REST::CONNECT => write!(fmt, "CONNECT"),
             REST::OTHER(s) => write!(fmt, "{}", s),
         }
     }
@@ -58,23 +86,151 @@ impl Default for REST {
     }
 }
 
+/// `RequestPath` defines how the URI of a route shall be matched. Routes are searched in tiers:
+/// explicit routes first, then the static folder, then routes with parameters, and lastly the
+/// wildcard routes.
+///
+/// A wildcard route registered with `WildCardWithPriority` and a priority larger than 0 will be
+/// evaluated ahead of the parameter routes, where routes with higher priority are checked first.
+/// A priority of 0 is the same as a plain `WildCard` route.
+///
+/// The wildcard pattern is a regex searched in the whole uri, i.e. it's not anchored unless the
+/// pattern says so: `foo` matches `/anything/foo/bar`, while `^/foo$` only matches `/foo`. An invalid
+/// pattern panics when the route is registered.
+///
+/// The path is only borrowed for the registration, and the router keeps its own copy, such that
+/// the routes can be built at runtime, e.g. from a config file, without leaking the strings:
+///
+/// ```
+/// use rusty_express::prelude::*;
+///
+/// fn handler(_req: &Box<Request>, resp: &mut Box<Response>) {
+///     resp.send("ok");
+/// }
+///
+/// let pages: Vec<String> = vec![String::from("about"), String::from("contact")];
+///
+/// let mut server = HttpServer::new();
+/// for page in pages.iter() {
+///     server.get(RequestPath::Explicit(&format!("/{}", page)), handler);
+/// }
+/// ```
 #[derive(PartialEq, Eq, Hash)]
 pub enum RequestPath<'a> {
     Explicit(&'a str),
     ExplicitWithParams(&'a str),
     WildCard(&'a str),
+    WildCardWithPriority(&'a str, u8),
 }
 
 /// `Callback` is a type alias to the REST request handler functions, which will be invoked when a
 /// client request has been received on the associated URI or pattern.
 pub type Callback = fn(&Box<Request>, &mut Box<Response>);
 
+/// `RefCallback` is the same request handler as `Callback`, but takes the plain references to the
+/// request and the response, which saves the handler from the boxed-reference noise. Register such
+/// handlers via the `*_ref` methods of the `Router`, e.g. `Router::get_ref`.
+pub type RefCallback = fn(&Request, &mut Response);
+
+/// The handler function stored along with the route, in either of the signature styles, or the
+/// redirect to the canonical form of the requested path.
+#[derive(Clone)]
+pub(crate) enum Handler {
+    Boxed(Callback),
+    Plain(RefCallback),
+    Redirect(Arc<String>),
+}
+
+/// `RouteOptions` holds the per-route settings overriding the server-wide ones, registered along
+/// with the route's callback via `Router::with_options`.
+///
+/// `max_body` is the largest request body in bytes the route accepts, in place of the server's
+/// `max_body_bytes`, where `Some(0)` means no limit. The route limit can't go beyond the server's
+/// `max_body_ceiling` though, if one is set.
+///
+/// `handler_timeout` is how long the route's callback may run, from the moment it starts. The
+/// callback is then run on a thread of its own, and if it overruns, the client gets "503 Service
+/// Unavailable" right away. Since a thread can't be killed, the callback keeps running to its end,
+/// and its response is dropped; while too many of them are still running, the routes with a
+/// timeout are refused with 503 as well.
+///
+/// # Examples
+///
+/// ```rust
+/// use rusty_express::prelude::*;
+/// use std::time::Duration;
+///
+/// let options = RouteOptions::new()
+///     .max_body(1024)
+///     .handler_timeout(Duration::from_secs(2));
+///
+/// assert_eq!(options.max_body, Some(1024));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RouteOptions {
+    pub max_body: Option<usize>,
+    pub handler_timeout: Option<Duration>,
+}
+
+impl RouteOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn max_body(mut self, limit: usize) -> Self {
+        self.max_body = Some(limit);
+        self
+    }
+
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+}
+
+/// `ViewsScope` holds the template settings for the routes under a path prefix, registered via
+/// `Router::with_views`, which are consulted by `send_template` when the handler of such a route
+/// renders a template.
+///
+/// `root` takes the place of the server's views root (and the document root), such that the
+/// relative template paths of the routes are resolved against it instead.
+///
+/// `default_engine_ext` names the registered engine, by its extension, to render the templates
+/// whose own extension has no engine registered, e.g. `hbs` to render the `.html` templates with
+/// the handlebars engine. The engine registered for a template's own extension always wins.
+///
+/// # Examples
+///
+/// ```rust
+/// use rusty_express::prelude::*;
+/// use std::path::PathBuf;
+///
+/// let mut server = HttpServer::new();
+/// server.with_views(
+///     "/admin",
+///     ViewsScope {
+///         root: PathBuf::from("./admin/views"),
+///         default_engine_ext: Some(String::from("hbs")),
+///     },
+/// );
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ViewsScope {
+    pub root: PathBuf,
+    pub default_engine_ext: Option<String>,
+}
+
 /// `AuthFunc` is a type alias to the authentication functions, which is optional, but if set, it
 /// will be invoked right after we parse the client request to determine if the requested URI is
-/// allowed to be visited by the client: if denied, we will generate the 403 error message as the
+/// allowed to be visited by the client: if denied, we will generate the 401 error message as the
 /// response. This function is generally to be used as the gate-keeper, e.g. if a use is logged in
 /// to see the dashboard routes.
 ///
+/// The function is invoked once the request line and the header fields are parsed, and before any
+/// of the body is read from the connection, such that a denied upload is never received: the method,
+/// the URI, the headers and the cookies of the request are all set, but its body is not, except
+/// for the bytes that happened to arrive along with the head.
+///
 /// The function takes 2 input parameters: 1) request: &Box<Request>, which contains all information
 /// from the client request; 2) the URI from the request: String, which is the URI being requested,
 /// this information is also available from the `request` parameter, but we extracted out to make it
@@ -88,6 +244,59 @@ pub type Callback = fn(&Box<Request>, &mut Box<Response>);
 /// update persistent information regarding the client requestor.
 pub type AuthFunc = fn(&Box<Request>, &str) -> bool;
 
+/// `AuthFuncMut` is the variant of `AuthFunc` that can modify the request, mostly to attach the
+/// findings of the check to the request with `RequestWriter::set_ext`, e.g. the id of the logged-in
+/// user, such that the route handler can read them with `Request::get_ext`. Only one of the two
+/// kinds of auth functions can be set at a time.
+pub type AuthFuncMut = fn(&mut Box<Request>, &str) -> bool;
+
+/// The route lookup result: the route handler, the params captured from the uri, and the view of
+/// the router the lookup was made against.
+pub(crate) type SeekResult = (RouteHandler, HashMap<String, String>, RouterView);
+
+/// The snapshot of the router state a request is served with, taken under the same read lock as
+/// the route lookup: the auth function, and the generation of the router, which is bumped on every
+/// change to the router. Authorizing with the view instead of `Route::authorize` saves the second
+/// lock, and guarantees the request is authorized by the same router that routed it.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RouterView {
+    auth_func: Option<AuthFunc>,
+    auth_func_mut: Option<AuthFuncMut>,
+    generation: usize,
+    denied: bool,
+}
+
+impl RouterView {
+    /// The view for a request whose route lookup has failed or timed out: since the auth function
+    /// of the router is unknown, the request is never authorized.
+    pub(crate) fn denied() -> Self {
+        RouterView {
+            denied: true,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn authorize(&self, request: &mut Box<Request>) -> bool {
+        if self.denied {
+            return false;
+        }
+
+        if let Some(auth_fn) = self.auth_func_mut {
+            let uri = request.uri.clone();
+            return auth_fn(request, &uri);
+        }
+
+        match self.auth_func {
+            Some(auth_fn) => auth_fn(request, &request.uri),
+            None => true,
+        }
+    }
+
+    pub(crate) fn generation(&self) -> usize {
+        self.generation
+    }
+}
+
 struct RegexRoute {
     regex: Regex,
     handler: RouteHandler,
@@ -103,71 +312,153 @@ impl RegexRoute {
 enum StaticListData {
     Ext(Arc<String>),
     Loc(Arc<String>),
+    File(Arc<String>),
+}
+
+impl StaticListData {
+    /// Entries formatted as `*.<extension>` match the file extension, entries containing a path
+    /// separator match everything under the folder, and anything else matches the exact file name.
+    fn parse(entry: &str) -> Self {
+        let raw = Arc::new(entry.to_lowercase());
+
+        if entry.starts_with("*.") {
+            StaticListData::Ext(raw)
+        } else if entry.contains('/') || entry.contains('\\') {
+            StaticListData::Loc(raw)
+        } else {
+            StaticListData::File(raw)
+        }
+    }
+
+    fn matches(&self, loc: &PathBuf, ext: &str, file: &str) -> bool {
+        match self {
+            StaticListData::Ext(e) => e.as_str() == ext,
+            StaticListData::Loc(l) => loc.starts_with(l.as_ref()),
+            StaticListData::File(f) => f.as_str() == file,
+        }
+    }
+}
+
+/// How the static routes treat the path components beginning with a dot, e.g. `/.env` or
+/// `/.well-known/acme-challenge/<token>`. The `.` and `..` components are always denied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DotfilePolicy {
+    /// Any path component beginning with a dot gets a 404, this is the default.
+    Deny,
+    /// Dotfiles and hidden folders are served like any other static file.
+    Allow,
+    /// Only the listed components, e.g. `.well-known`, are served, and the rest gets a 404.
+    AllowList(Vec<String>),
+}
+
+impl Default for DotfilePolicy {
+    fn default() -> Self {
+        DotfilePolicy::Deny
+    }
+}
+
+impl DotfilePolicy {
+    fn permits(&self, raw_uri: &str) -> bool {
+        raw_uri.split('/').all(|part| {
+            if part == "." || part == ".." {
+                return false;
+            }
+
+            if !part.starts_with('.') {
+                return true;
+            }
+
+            match self {
+                DotfilePolicy::Deny => false,
+                DotfilePolicy::Allow => true,
+                DotfilePolicy::AllowList(list) => list.iter().any(|allowed| allowed == part),
+            }
+        })
+    }
+}
+
+/// How the trailing slash of the request path is treated when it's matched against the explicit
+/// routes. The routes with parameters always match with or without the trailing slash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// `/foo` and `/foo/` are distinct routes.
+    Strict,
+    /// A request to `/foo/` where only `/foo` is registered, or vice versa, is redirected to the
+    /// registered form with a 301.
+    Redirect,
+    /// `/foo` and `/foo/` match the same route, this is the default. If both are registered, the
+    /// exact one wins.
+    Ignore,
+}
+
+/// `RouteNormalization` controls how the request path is matched against the routes of a method,
+/// set with `Router::normalization`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RouteNormalization {
+    pub trailing_slash: TrailingSlash,
+    pub case_sensitive: bool,
+}
+
+impl Default for RouteNormalization {
+    fn default() -> Self {
+        RouteNormalization {
+            trailing_slash: TrailingSlash::Ignore,
+            case_sensitive: false,
+        }
+    }
 }
 
 struct StaticLocRoute {
     location: PathBuf,
+    root: PathBuf,
     black_list: Vec<StaticListData>,
     white_list: Vec<StaticListData>,
+    dotfiles: DotfilePolicy,
+    follow_symlinks: bool,
 }
 
 impl StaticLocRoute {
+    fn within_root(&self, loc: &PathBuf) -> bool {
+        // the relative jumps are denied already, so symlinks are the only way out of the root
+        if self.follow_symlinks {
+            return loc.starts_with(&self.location);
+        }
+
+        match fs::canonicalize(loc) {
+            Ok(real) => real.starts_with(&self.root),
+            Err(_) => false,
+        }
+    }
+
     fn check_access(&self, loc: &PathBuf) -> bool {
-        // the extension prefix
-        let mut ext = String::from("*.");
-
-        // the requested file exists, now check white-list and black-list, in this order
-        match loc.extension() {
-            Some(e) => {
-                if let Some(e_str) = e.to_str() {
-                    ext.push_str(e_str);
-                } else {
-                    return false;
-                }
-            }
-            _ => return false,
+        // the extension prefix, files without an extension, e.g. `.env`, can only match the
+        // locations or the file names in the lists
+        let ext = match loc.extension() {
+            Some(e) => match e.to_str() {
+                Some(e_str) => format!("*.{}", e_str.to_lowercase()),
+                None => return false,
+            },
+            None => String::new(),
         };
 
-        if !self.white_list.is_empty() {
-            for wl in self.white_list.iter() {
-                match wl {
-                    StaticListData::Ext(e) => {
-                        if e.as_str() == ext {
-                            return true;
-                        }
-                    }
-                    StaticListData::Loc(l) => {
-                        if loc.starts_with(l.as_ref()) {
-                            return true;
-                        }
-                    }
-                }
-            }
+        let file = match loc.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_lowercase(),
+            None => return false,
+        };
 
+        if !self.white_list.is_empty() {
             // we can't match to a white-listed position, quit
-            return false;
-        }
-
-        if !self.black_list.is_empty() {
-            for wl in self.black_list.iter() {
-                match wl {
-                    StaticListData::Ext(e) => {
-                        if e.as_str() == ext {
-                            return false;
-                        }
-                    }
-                    StaticListData::Loc(l) => {
-                        if loc.starts_with(l.as_ref()) {
-                            return false;
-                        }
-                    }
-                }
-            }
-
-            // we can't match to a black-listed position, we're good
+            return self
+                .white_list
+                .iter()
+                .any(|wl| wl.matches(loc, &ext, &file));
         }
 
-        true
+        // we're good if we can't match to a black-listed position
+        !self
+            .black_list
+            .iter()
+            .any(|bl| bl.matches(loc, &ext, &file))
     }
 }
 
@@ -175,8 +466,11 @@ impl Clone for StaticLocRoute {
     fn clone(&self) -> Self {
         StaticLocRoute {
             location: self.location.clone(),
+            root: self.root.clone(),
             black_list: self.black_list.clone(),
             white_list: self.white_list.clone(),
+            dotfiles: self.dotfiles.clone(),
+            follow_symlinks: self.follow_symlinks,
         }
     }
 }
@@ -185,8 +479,9 @@ pub(crate) struct RouteMap {
     explicit: HashMap<String, RouteHandler>,
     explicit_with_params: RouteTrie,
     wildcard: HashMap<String, RegexRoute>,
+    priority_wildcard: Vec<(u8, RegexRoute)>,
     static_path: Option<StaticLocRoute>,
-    case_sensitive: bool,
+    normalization: RouteNormalization,
 }
 
 impl RouteMap {
@@ -195,20 +490,23 @@ impl RouteMap {
             explicit: HashMap::new(),
             explicit_with_params: RouteTrie::initialize(),
             wildcard: HashMap::new(),
+            priority_wildcard: Vec::new(),
             static_path: None,
-            case_sensitive: false,
+            normalization: RouteNormalization::default(),
         }
     }
 
-    pub fn insert(&mut self, uri: RequestPath<'_>, handler: RouteHandler) {
+    pub fn insert(&mut self, uri: RequestPath<'_>, mut handler: RouteHandler) {
         match uri {
             RequestPath::Explicit(req_uri) => {
                 if req_uri.is_empty() || !req_uri.starts_with('/') {
                     panic!("Request path must have valid contents and start with '/'.");
                 }
 
+                handler.set_pattern(req_uri);
+
                 self.explicit
-                    .add(req_uri, handler, false, self.case_sensitive);
+                    .add(req_uri, handler, false, self.is_case_sensitive());
             }
             RequestPath::WildCard(req_uri) => {
                 if req_uri.is_empty() {
@@ -219,36 +517,195 @@ impl RouteMap {
                     return;
                 }
 
-                if let Ok(re) = Regex::new(req_uri) {
-                    self.wildcard.add(
-                        req_uri,
-                        RegexRoute::new(re, handler),
-                        false,
-                        self.case_sensitive,
-                    );
+                handler.set_pattern(req_uri);
+
+                let re = compile_pattern(req_uri);
+                self.wildcard.add(
+                    req_uri,
+                    RegexRoute::new(re, handler),
+                    false,
+                    self.is_case_sensitive(),
+                );
+            }
+            RequestPath::WildCardWithPriority(req_uri, 0) => {
+                self.insert(RequestPath::WildCard(req_uri), handler);
+            }
+            RequestPath::WildCardWithPriority(req_uri, priority) => {
+                if req_uri.is_empty() {
+                    panic!("Request path must have valid contents.");
+                }
+
+                if self
+                    .priority_wildcard
+                    .iter()
+                    .any(|(_, route)| route.regex.as_str() == req_uri)
+                {
+                    return;
                 }
+
+                handler.set_pattern(req_uri);
+
+                // keep the list sorted by priority, and routes with the same priority are checked
+                // in the order they're registered.
+                let re = compile_pattern(req_uri);
+                let pos = self
+                    .priority_wildcard
+                    .iter()
+                    .position(|(p, _)| *p < priority)
+                    .unwrap_or_else(|| self.priority_wildcard.len());
+
+                self.priority_wildcard
+                    .insert(pos, (priority, RegexRoute::new(re, handler)));
             }
             RequestPath::ExplicitWithParams(req_uri) => {
-                if !req_uri.contains("/:") && !req_uri.contains(":\\") {
+                handler.set_pattern(req_uri);
+
+                if !RouteMap::has_params(req_uri) {
                     self.explicit
-                        .add(req_uri, handler, false, self.case_sensitive);
+                        .add(req_uri, handler, false, self.is_case_sensitive());
                     return;
                 }
 
                 self.explicit_with_params.add(
-                    RouteMap::params_parser(req_uri, self.case_sensitive),
+                    RouteMap::params_parser(req_uri, self.is_case_sensitive()),
                     handler,
                 );
             }
         }
     }
 
+    /// Remove the route registered with the uri, and return its handler if found.
+    pub(crate) fn remove(&mut self, uri: RequestPath<'_>) -> Option<RouteHandler> {
+        match uri {
+            RequestPath::Explicit(req_uri) => self.explicit.remove(&self.map_key(req_uri)),
+            RequestPath::ExplicitWithParams(req_uri) if !RouteMap::has_params(req_uri) => {
+                self.explicit.remove(&self.map_key(req_uri))
+            }
+            RequestPath::ExplicitWithParams(req_uri) => {
+                let segments = RouteMap::params_parser(req_uri, self.is_case_sensitive());
+                self.explicit_with_params.remove(&segments)
+            }
+            RequestPath::WildCard(req_uri) | RequestPath::WildCardWithPriority(req_uri, 0) => self
+                .wildcard
+                .remove(&self.map_key(req_uri))
+                .map(|route| route.handler),
+            RequestPath::WildCardWithPriority(req_uri, _) => self
+                .priority_wildcard
+                .iter()
+                .position(|(_, route)| route.regex.as_str() == req_uri)
+                .map(|pos| self.priority_wildcard.remove(pos).1.handler),
+        }
+    }
+
+    /// Update the handler of the route registered with the uri in place, and return `false` if
+    /// the route doesn't exist.
+    pub(crate) fn update<F>(&mut self, uri: RequestPath<'_>, mut f: F) -> bool
+    where
+        F: FnMut(&mut RouteHandler),
+    {
+        let handler = match uri {
+            RequestPath::Explicit(req_uri) => {
+                let key = self.map_key(req_uri);
+                self.explicit.get_mut(&key)
+            }
+            RequestPath::ExplicitWithParams(req_uri) if !RouteMap::has_params(req_uri) => {
+                let key = self.map_key(req_uri);
+                self.explicit.get_mut(&key)
+            }
+            RequestPath::ExplicitWithParams(req_uri) => {
+                let segments = RouteMap::params_parser(req_uri, self.is_case_sensitive());
+                return self.explicit_with_params.update(&segments, f);
+            }
+            RequestPath::WildCard(req_uri) | RequestPath::WildCardWithPriority(req_uri, 0) => {
+                let key = self.map_key(req_uri);
+                self.wildcard.get_mut(&key).map(|route| &mut route.handler)
+            }
+            RequestPath::WildCardWithPriority(req_uri, _) => self
+                .priority_wildcard
+                .iter_mut()
+                .find(|(_, route)| route.regex.as_str() == req_uri)
+                .map(|(_, route)| &mut route.handler),
+        };
+
+        match handler {
+            Some(h) => {
+                f(h);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Find the custom static route registered with the uri, or with its closest ancestor, e.g.
+    /// `/assets` for `/assets/css/site.css`, and resolve the rest of the path, i.e. `css/site.css`,
+    /// under the folder of the route. The uri is the request path without the file name, unless
+    /// the file is at the top level.
+    fn search_custom_static(&self, uri: &str, file_name: &str) -> Option<RouteHandler> {
+        let lookup = |prefix: &str| {
+            let key = if self.is_case_sensitive() || !prefix.chars().any(char::is_uppercase) {
+                Cow::Borrowed(prefix)
+            } else {
+                Cow::Owned(prefix.to_lowercase())
+            };
+
+            self.explicit
+                .get(key.as_ref())
+                .filter(|handler| handler.0.is_none() && handler.1.is_some())
+        };
+
+        // a custom static route registered with the exact uri, e.g. `/index.html`
+        if let Some(handler) = lookup(uri) {
+            return Some(resolve_custom_static(handler, file_name));
+        }
+
+        // otherwise the top level file is served by the route of the root only
+        let dir = if uri.len() == file_name.len() + 1 && uri.ends_with(file_name) {
+            "/"
+        } else {
+            uri
+        };
+
+        let mut prefix = dir;
+        loop {
+            if let Some(handler) = lookup(prefix) {
+                let rest = format!("{}/{}", &dir[prefix.len()..], file_name);
+                return Some(resolve_custom_static(handler, rest.trim_start_matches('/')));
+            }
+
+            prefix = match prefix.rfind('/') {
+                Some(0) if prefix.len() > 1 => "/",
+                Some(pos) if pos > 0 => &prefix[..pos],
+                _ => return None,
+            };
+        }
+    }
+
+    /// The key of the uri in the explicit and the wildcard maps, which are folded if the routes
+    /// are case-insensitive.
+    fn map_key(&self, uri: &str) -> String {
+        if self.is_case_sensitive() {
+            uri.to_owned()
+        } else {
+            uri.to_lowercase()
+        }
+    }
+
+    /// If the route has parameters, otherwise it's stored as an explicit one.
+    #[inline]
+    fn has_params(uri: &str) -> bool {
+        uri.contains("/:") || uri.contains(":\\")
+    }
+
     pub fn case_sensitive(&mut self, allow_case: bool) {
-        self.case_sensitive = allow_case;
+        self.normalization.case_sensitive = allow_case;
     }
 
     pub fn is_case_sensitive(&self) -> bool {
-        self.case_sensitive
+        self.normalization.case_sensitive
+    }
+
+    pub fn normalization(&mut self, policy: RouteNormalization) {
+        self.normalization = policy;
     }
 
     fn params_parser(source_uri: &str, allow_case: bool) -> Vec<Field> {
@@ -338,7 +795,7 @@ impl RouteMap {
 
                                 match pos {
                                     0 => actual_name = seg,
-                                    1 => validation = Regex::new(seg).ok(),
+                                    1 => validation = Some(compile_pattern(seg)),
                                     _ => return,
                                 };
 
@@ -382,6 +839,23 @@ impl RouteMap {
             return route;
         }
 
+        /*
+         * Try the path in the other form, i.e. with or without the trailing slash.
+         */
+        if self.normalization.trailing_slash != TrailingSlash::Strict {
+            if let Some(other) = toggle_trailing_slash(raw_uri) {
+                params.clear();
+
+                let route = self.search(&other, "", "", params);
+                if route.is_some() {
+                    return match self.normalization.trailing_slash {
+                        TrailingSlash::Redirect => RouteHandler::redirect(other),
+                        _ => route,
+                    };
+                }
+            }
+        }
+
         /*
          * Exact uri match failed, now try parsing the file name if it contains one.
          */
@@ -391,6 +865,7 @@ impl RouteMap {
 
         let mut actual_uri = String::with_capacity(raw_uri.len());
         let mut file_name = "";
+        let mut hidden = false;
 
         for part in raw_uri.split('/') {
             if part.is_empty() || part == ".." || part == "." {
@@ -402,6 +877,9 @@ impl RouteMap {
                 continue;
             }
 
+            // hidden components, e.g. `/.well-known/acme-challenge/<token>`, can only be served
+            // from the static folder, subject to its dotfile policy
+            hidden = hidden || part.starts_with('.');
             file_name = "";
 
             actual_uri.push('/');
@@ -410,6 +888,12 @@ impl RouteMap {
 
         // uri doesn't contain a file name, actual_uri === raw_uri, done (and route not found).
         if file_name.is_empty() {
+            if hidden {
+                if let Some(static_path) = self.static_path.as_ref() {
+                    return search_static_router(static_path, raw_uri).unwrap_or_default();
+                }
+            }
+
             return RouteHandler::default();
         }
 
@@ -429,11 +913,22 @@ impl RouteMap {
     ) -> RouteHandler {
         let for_file = !file_name.is_empty();
 
-        if let Some(callback) = self.explicit.get(uri) {
-            // only exact match can return: callback and no file name, or path with file name (custom
-            if (!for_file && callback.0.is_some()) || (for_file && callback.1.is_some()) {
-                return RouteHandler::update_handler(callback.clone(), file_name);
+        // the explicit routes have been folded on registration if they're case-insensitive
+        let key = if self.is_case_sensitive() || !uri.chars().any(char::is_uppercase) {
+            Cow::Borrowed(uri)
+        } else {
+            Cow::Owned(uri.to_lowercase())
+        };
+
+        if !for_file {
+            if let Some(callback) = self.explicit.get(key.as_ref()) {
+                // only exact match can return
+                if callback.0.is_some() {
+                    return callback.clone();
+                }
             }
+        } else if let Some(found) = self.search_custom_static(uri, file_name) {
+            return found;
         }
 
         if for_file {
@@ -455,8 +950,21 @@ impl RouteMap {
             }
         }
 
+        if !self.priority_wildcard.is_empty() {
+            let result = search_priority_router(&self.priority_wildcard, uri);
+
+            if (!for_file && result.0.is_some()) || (for_file && result.1.is_some()) {
+                return RouteHandler::update_handler(result, file_name);
+            }
+        }
+
         if !self.explicit_with_params.is_empty() {
-            let result = search_params_router(&self.explicit_with_params, uri, params);
+            let result = search_params_router(
+                &self.explicit_with_params,
+                uri,
+                params,
+                self.is_case_sensitive(),
+            );
 
             if (!for_file && result.0.is_some()) || (for_file && result.1.is_some()) {
                 return RouteHandler::update_handler(result, file_name);
@@ -471,21 +979,24 @@ impl RouteMap {
             }
         }
 
-        RouteHandler(None, None)
+        RouteHandler(None, None, None, None, None)
     }
 }
 
 #[derive(Default)]
 pub struct Route {
     store: HashMap<REST, RouteMap>,
+    fallbacks: HashMap<Option<REST>, Callback>,
     auth_func: Option<AuthFunc>,
+    auth_func_mut: Option<AuthFuncMut>,
+    views: Vec<(String, Arc<ViewsScope>)>,
 }
 
 impl Route {
     pub(crate) fn init() {
         unsafe {
             ROUTER.set((Route::new(), AtomicUsize::new(1)));
-            ROUTE_CACHE.set(HashMap::new());
+            ROUTE_CACHE.set(RwLock::new(RouteCache::new()));
         }
     }
 
@@ -498,14 +1009,38 @@ impl Route {
     }
 
     pub fn set_auth_func(auth_func: Option<AuthFunc>) {
-        Route::write().with(|r| r.auth_func = auth_func);
+        Route::write().with(|r| {
+            r.auth_func = auth_func;
+            r.auth_func_mut = None;
+        });
+    }
+
+    /// Set the auth function that can attach values to the request, which replaces the one set
+    /// with `set_auth_func`, if any.
+    pub fn set_auth_func_mut(auth_func: Option<AuthFuncMut>) {
+        Route::write().with(|r| {
+            r.auth_func_mut = auth_func;
+            r.auth_func = None;
+        });
     }
 
+    /// Check the request against the auth function of the router. The auth function set with
+    /// `set_auth_func_mut` takes a mutable request, which can't be lent here, so the request is
+    /// denied if that's the one in use.
+    #[deprecated(
+        since = "0.4.7",
+        note = "the requests are authorized by the server with the router that routed them"
+    )]
     pub fn authorize(request: &Box<Request>, uri: &str) -> bool {
-        Route::read().with(|r| match r.auth_func {
-            Some(auth_fn) => auth_fn(request, uri),
-            None => true,
-        })
+        Route::read().with(|r| r.authorizes(request, uri))
+    }
+
+    fn authorizes(&self, request: &Box<Request>, uri: &str) -> bool {
+        match (self.auth_func, self.auth_func_mut) {
+            (_, Some(_)) => false,
+            (Some(auth_fn), None) => auth_fn(request, uri),
+            (None, None) => true,
+        }
     }
 
     pub fn use_router(another: Route) {
@@ -518,6 +1053,77 @@ impl Route {
         });
     }
 
+    /// Remove the route registered with the method and the uri while the server is running, and
+    /// return `false` if no such route exists. The uri shall be the same one used for registering
+    /// the route, e.g. `RequestPath::ExplicitWithParams("/users/:id")`.
+    pub fn remove(method: REST, uri: RequestPath) -> bool {
+        Route::write().with(|r| {
+            r.store
+                .get_mut(&method)
+                .and_then(|maps| maps.remove(uri))
+                .is_some()
+        })
+    }
+
+    /// Replace the handler of the route registered with the method and the uri while the server
+    /// is running, and return `false` if no such route exists, see `Route::remove`. The options of
+    /// the route, e.g. the body size limit, are kept.
+    pub fn replace(method: REST, uri: RequestPath, callback: Callback) -> bool {
+        Route::write().with(|r| {
+            r.store.get_mut(&method).map_or(false, |maps| {
+                maps.update(uri, |handler| {
+                    handler.0 = Some(Handler::Boxed(callback));
+                    handler.1 = None;
+                })
+            })
+        })
+    }
+
+    /// Set the max number of the route lookups to cache. When the cache is full, the clock hand
+    /// evicts the first entry not hit since its last sweep. A lookup is cached only if it finds a
+    /// callback, such that the static files and the missing routes are always looked up afresh.
+    /// Default to 0, i.e. the cache is disabled.
+    pub(crate) fn set_cache_capacity(capacity: usize) {
+        CACHE_CAPACITY.store(capacity, Ordering::Release);
+        Route::invalidate_cache();
+    }
+
+    /// The generation of the router, which is bumped whenever the router is changed, see
+    /// `RouteGuard`.
+    pub(crate) fn generation() -> usize {
+        GENERATION.load(Ordering::Acquire)
+    }
+
+    /// Drop the cached routes. The entries cached before a change to the router are already
+    /// outdated by the generation, so this is only needed to apply a new capacity.
+    fn invalidate_cache() {
+        if let Ok(cache) = unsafe { ROUTE_CACHE.as_ref() } {
+            cache.write().clear();
+        }
+    }
+
+    fn cached(method: &REST, uri: &str) -> Option<SeekResult> {
+        unsafe { ROUTE_CACHE.as_ref() }
+            .ok()
+            .and_then(|cache| cache.read().get(method, uri, Route::generation()))
+    }
+
+    fn cache(method: &REST, uri: &str, found: &SeekResult) {
+        let capacity = CACHE_CAPACITY.load(Ordering::Acquire);
+        if capacity == 0 || found.0 .0.is_none() {
+            return;
+        }
+
+        // a miss doesn't wait for the write lock: if another request holds it, the lookup is
+        // simply cached by a later miss
+        if let Some(mut cache) = unsafe { ROUTE_CACHE.as_ref() }
+            .ok()
+            .and_then(|cache| cache.try_write())
+        {
+            cache.insert(method, uri, found.clone(), capacity);
+        }
+    }
+
     pub fn all_case_sensitive(allow_case: bool) {
         Route::write().with(|r| {
             for maps in r.store.values_mut() {
@@ -529,7 +1135,7 @@ impl Route {
     pub fn case_sensitive(method: &REST, allow_case: bool) {
         Route::write().with(|r| {
             if let Some(maps) = r.store.get_mut(method) {
-                maps.case_sensitive = allow_case;
+                maps.case_sensitive(allow_case);
             }
         });
     }
@@ -538,22 +1144,46 @@ impl Route {
         Route::read().with(|r| {
             r.store
                 .get(method)
-                .filter(|maps| maps.case_sensitive)
+                .filter(|maps| maps.is_case_sensitive())
                 .is_some()
         })
     }
 
+    pub(crate) fn set_normalization(policy: RouteNormalization, method: Option<REST>) {
+        Route::write().with(|r| r.normalization(policy, method));
+    }
+
     pub(crate) fn add_route(method: REST, uri: RequestPath, callback: RouteHandler) {
         Route::write().with(|r| r.add(method, uri, callback));
     }
 
+    pub(crate) fn set_fallback(method: Option<REST>, callback: Callback) {
+        Route::write().with(|r| {
+            r.fallbacks.insert(method, callback);
+        });
+    }
+
+    pub(crate) fn set_views(prefix: &str, scope: ViewsScope) {
+        Route::write().with(|r| {
+            r.with_views(prefix, scope);
+        });
+    }
+
     pub(crate) fn add_static(method: REST, uri: Option<RequestPath>, path: PathBuf) {
         Route::write().with(|r| match uri {
-            Some(u) => r.add(method, u, RouteHandler(None, Some(path))),
+            Some(u) => r.add(method, u, RouteHandler(None, Some(path), None, None, None)),
             None => r.set_static(method, path),
         });
     }
 
+    pub(crate) fn dotfile_policy(policy: DotfilePolicy, for_path: Option<PathBuf>) {
+        Route::write().with(|r| r.static_dotfiles(policy, for_path))
+    }
+
+    pub(crate) fn follow_symlinks(follow: bool, for_path: Option<PathBuf>) {
+        Route::write().with(|r| r.static_symlinks(follow, for_path))
+    }
+
     pub(crate) fn static_lists(loc_or_ext: String, is_white_list: bool, for_path: Option<PathBuf>) {
         Route::write().with(|r| {
             if is_white_list {
@@ -576,15 +1206,35 @@ impl Route {
         self.store.insert(method, map);
     }
 
-    fn set_static(&mut self, method: REST, path: PathBuf) {
-        if !path.exists() || !path.is_dir() {
+    /// Replace the white list and the black list of the static folder served for the method. This
+    /// can be called at runtime, and the lists take effect from the next request.
+    pub fn set_static_filter(method: REST, allow: &[&str], deny: &[&str]) {
+        Route::write().with(|r| r.static_filter(&method, allow, deny));
+    }
+
+    fn static_filter(&mut self, method: &REST, allow: &[&str], deny: &[&str]) {
+        if let Some(s_route) = self
+            .store
+            .get_mut(method)
+            .and_then(|m| m.static_path.as_mut())
+        {
+            s_route.white_list = allow.iter().map(|e| StaticListData::parse(e)).collect();
+            s_route.black_list = deny.iter().map(|e| StaticListData::parse(e)).collect();
+        }
+    }
+
+    fn set_static(&mut self, method: REST, path: PathBuf) {
+        if !path.exists() || !path.is_dir() {
             panic!("The static path must point to a folder");
         }
 
         let static_route = StaticLocRoute {
+            root: fs::canonicalize(&path).unwrap_or_else(|_| path.clone()),
             location: path,
             black_list: Vec::new(),
             white_list: Vec::new(),
+            dotfiles: DotfilePolicy::default(),
+            follow_symlinks: false,
         };
 
         if let Some(r) = self.store.get_mut(&method) {
@@ -598,21 +1248,107 @@ impl Route {
         self.store.insert(method, map);
     }
 
+    /// Find the handler for the uri: the routes of the method first, then the `GET` routes for a
+    /// `HEAD` request, and the all-match routes last.
+    fn lookup(
+        &self,
+        method: &REST,
+        uri: &str,
+        params: &mut HashMap<String, String>,
+    ) -> RouteHandler {
+        let mut result = RouteHandler(None, None, None, None, None);
+
+        // get from the method
+        if let Some(routes) = self.store.get(method) {
+            result = routes.seek_path(uri, params);
+        }
+
+        // if a header only request, fallback to search with REST::GET
+        if result.is_none() && method == &REST::HEAD {
+            if let Some(routes) = self.store.get(&REST::GET) {
+                result = routes.seek_path(uri, params);
+            }
+        }
+
+        // otherwise, try the all-match routes
+        if result.is_none() {
+            if let Some(all_routes) = self.store.get(&REST::OTHER(String::from("*"))) {
+                result = all_routes.seek_path(uri, params);
+            }
+        }
+
+        result
+    }
+
+    /// The fallback handler for the method when no route matched the uri: the one registered for
+    /// the method, then the `GET` one for a `HEAD` request, and the one for any method last.
+    fn fallback_for(&self, method: &REST) -> Option<RouteHandler> {
+        self.fallbacks
+            .get(&Some(method.clone()))
+            .or_else(|| {
+                if method == &REST::HEAD {
+                    self.fallbacks.get(&Some(REST::GET))
+                } else {
+                    None
+                }
+            })
+            .or_else(|| self.fallbacks.get(&None))
+            .map(|cb| RouteHandler::new(Some(*cb), None))
+    }
+
+    /// The views scope of the route pattern: the one with the longest prefix that the pattern
+    /// begins with, where the prefix shall end at a segment boundary, i.e. `/admin` covers
+    /// `/admin` and `/admin/users/:id`, but not `/administrators`. The wildcard routes are matched
+    /// by their regex source, less the leading `^`.
+    fn views_for(&self, pattern: Option<&str>) -> Option<Arc<ViewsScope>> {
+        let pattern = pattern?.trim_start_matches('^');
+
+        self.views
+            .iter()
+            .find(|(prefix, _)| {
+                pattern.starts_with(prefix.as_str())
+                    && !pattern[prefix.len()..]
+                        .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .map(|(_, scope)| Arc::clone(scope))
+    }
+
     fn replace_with(&mut self, mut another: Route) {
         self.store = another.store;
+        self.fallbacks = another.fallbacks;
+        self.views = another.views;
         self.auth_func = another.auth_func.take();
+        self.auth_func_mut = another.auth_func_mut.take();
     }
 
     fn read() -> RouteGuard<'static> {
         RouteGuard::checkout(true)
     }
 
+    /// The number of the read guards checked out by the current thread so far.
+    #[cfg(test)]
+    pub(crate) fn read_checkouts() -> usize {
+        READ_CHECKOUTS.with(|count| count.get())
+    }
+
     fn write() -> RouteGuard<'static> {
         RouteGuard::checkout(false)
     }
 }
 
-pub trait Router {
+/// Upcast the router into the `Router` trait object, such that the provided methods of `Router`
+/// can return the router for chaining. It's implemented for all the routers.
+pub trait AsRouter {
+    fn as_router(&mut self) -> &mut dyn Router;
+}
+
+impl<T: Router> AsRouter for T {
+    fn as_router(&mut self) -> &mut dyn Router {
+        self
+    }
+}
+
+pub trait Router: AsRouter {
     fn get(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router;
     fn patch(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router;
     fn post(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router;
@@ -621,41 +1357,151 @@ pub trait Router {
     fn options(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router;
     fn other(&mut self, method: &str, uri: RequestPath, callback: Callback) -> &mut dyn Router;
     fn all(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router;
+    fn other_ref(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: RefCallback,
+    ) -> &mut dyn Router;
+    fn with_options(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: Callback,
+        options: RouteOptions,
+    ) -> &mut dyn Router;
     fn use_static(&mut self, path: PathBuf) -> &mut dyn Router;
     fn use_custom_static(&mut self, uri: RequestPath, path: PathBuf) -> &mut dyn Router;
+    fn use_static_filtered(
+        &mut self,
+        path: PathBuf,
+        allow: &[&str],
+        deny: &[&str],
+    ) -> &mut dyn Router;
     fn static_white_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>);
     fn static_black_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>);
+    fn static_dotfiles(&mut self, policy: DotfilePolicy, for_path: Option<PathBuf>);
+    fn static_symlinks(&mut self, follow: bool, for_path: Option<PathBuf>);
     fn case_sensitive(&mut self, allow_case: bool, method: Option<REST>);
+    fn normalization(&mut self, policy: RouteNormalization, method: Option<REST>);
+
+    /// Register the handler to invoke when no route matches the request's uri, for the `method`,
+    /// or for any method if `None`. It's consulted only after the explicit, parameterized,
+    /// wildcard, static and all-match routes all missed, and it takes over the response in place
+    /// of the default 404 page. The fallback for the method wins over the one for any method.
+    ///
+    /// The default implementation ignores the fallback, i.e. the routers that don't support it keep
+    /// serving the 404 page.
+    fn fallback(&mut self, method: Option<REST>, callback: Callback) -> &mut dyn Router {
+        rex_warn!("The router doesn't support the fallbacks, the fallback is ignored");
+        self.as_router()
+    }
+
+    /// Render the templates of the routes under the `prefix` with the views scope, see
+    /// `ViewsScope`. Registering the same prefix again replaces its scope. When the prefixes
+    /// overlap, the longest one the route falls under wins, e.g. `/admin/reports` over `/admin`,
+    /// and the routes outside of any scope keep using the server's views root and engines.
+    ///
+    /// The default implementation ignores the scope, i.e. the routers that don't support it keep
+    /// using the server's views root and engines for all routes.
+    fn with_views(&mut self, prefix: &str, scope: ViewsScope) -> &mut dyn Router {
+        rex_warn!(
+            "The router doesn't support the views scopes, the scope of {} is ignored",
+            prefix
+        );
+        self.as_router()
+    }
+
+    fn get_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("GET", uri, callback)
+    }
+
+    fn patch_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("PATCH", uri, callback)
+    }
+
+    fn post_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("POST", uri, callback)
+    }
+
+    fn put_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("PUT", uri, callback)
+    }
+
+    fn delete_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("DELETE", uri, callback)
+    }
+
+    fn options_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("OPTIONS", uri, callback)
+    }
+
+    fn all_ref(&mut self, uri: RequestPath, callback: RefCallback) -> &mut dyn Router {
+        self.other_ref("*", uri, callback)
+    }
+
+    /// Register the routes under the shared `prefix`, i.e. the routes defined with the
+    /// `RouteGroup` given to `define` will have the prefix prepended to their paths. Groups can be
+    /// nested, and the prefixes are joined in order.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rusty_express::prelude::*;
+    ///
+    /// fn handler(_req: &Box<Request>, resp: &mut Box<Response>) {
+    ///     resp.send("ok");
+    /// }
+    ///
+    /// let mut server = HttpServer::new();
+    /// server.scope("/api/v1", |api| {
+    ///     // served at `/api/v1/users/:id`
+    ///     api.get(RequestPath::ExplicitWithParams("/users/:id"), handler);
+    ///
+    ///     // served at `/api/v1/admin/stats`
+    ///     api.scope("/admin", |admin| {
+    ///         admin.get(RequestPath::Explicit("/stats"), handler);
+    ///     });
+    /// });
+    /// ```
+    fn scope<F>(&mut self, prefix: &str, define: F) -> &mut dyn Router
+    where
+        Self: Sized,
+        F: FnOnce(&mut RouteGroup),
+    {
+        define(&mut RouteGroup::new(self, prefix));
+        self
+    }
 }
 
 impl Router for Route {
     fn get(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
-        self.add(REST::GET, uri, RouteHandler(Some(callback), None));
+        self.add(REST::GET, uri, RouteHandler::new(Some(callback), None));
         self
     }
 
     fn patch(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
-        self.add(REST::PATCH, uri, RouteHandler(Some(callback), None));
+        self.add(REST::PATCH, uri, RouteHandler::new(Some(callback), None));
         self
     }
 
     fn post(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
-        self.add(REST::POST, uri, RouteHandler(Some(callback), None));
+        self.add(REST::POST, uri, RouteHandler::new(Some(callback), None));
         self
     }
 
     fn put(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
-        self.add(REST::PUT, uri, RouteHandler(Some(callback), None));
+        self.add(REST::PUT, uri, RouteHandler::new(Some(callback), None));
         self
     }
 
     fn delete(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
-        self.add(REST::DELETE, uri, RouteHandler(Some(callback), None));
+        self.add(REST::DELETE, uri, RouteHandler::new(Some(callback), None));
         self
     }
 
     fn options(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
-        self.add(REST::OPTIONS, uri, RouteHandler(Some(callback), None));
+        self.add(REST::OPTIONS, uri, RouteHandler::new(Some(callback), None));
         self
     }
 
@@ -664,8 +1510,8 @@ impl Router for Route {
             panic!("Must provide a valid method!");
         }
 
-        let request_method = REST::OTHER(method.to_uppercase());
-        self.add(request_method, uri, RouteHandler(Some(callback), None));
+        let request_method = REST::parse(method);
+        self.add(request_method, uri, RouteHandler::new(Some(callback), None));
 
         self
     }
@@ -678,6 +1524,43 @@ impl Router for Route {
         self.other("*", uri, callback)
     }
 
+    /// Same as `other`, but takes the handler with the plain reference signature, i.e.
+    /// `fn(&Request, &mut Response)`.
+    fn other_ref(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: RefCallback,
+    ) -> &mut dyn Router {
+        if method.is_empty() {
+            panic!("Must provide a valid method!");
+        }
+
+        self.add(REST::parse(method), uri, RouteHandler::new_ref(callback));
+
+        self
+    }
+
+    /// Same as `other`, but with the route's own options overriding the server-wide settings, e.g.
+    /// a larger body size limit for the upload route.
+    fn with_options(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: Callback,
+        options: RouteOptions,
+    ) -> &mut dyn Router {
+        if method.is_empty() {
+            panic!("Must provide a valid method!");
+        }
+
+        let mut handler = RouteHandler::new(Some(callback), None);
+        handler.set_options(options);
+        self.add(REST::parse(method), uri, handler);
+
+        self
+    }
+
     /// Define a static folder location, where the request will be forwarded to and read the desired
     /// file as the response body.
     ///
@@ -697,7 +1580,10 @@ impl Router for Route {
     }
 
     /// Define a customized static folder location, where the requested file will be served only if
-    /// it matches the URI rules defined by the API.
+    /// it matches the URI rules defined by the API. An explicit route also serves the files nested
+    /// under it, e.g. `/assets/css/site.css` is served from `css/site.css` in the folder of the
+    /// `/assets` route, guarded against the relative jumps, the hidden files and the symlinks out
+    /// of the folder.
     ///
     /// # Example
     ///
@@ -709,9 +1595,41 @@ impl Router for Route {
     ///
     /// // only the `index.html` file in the static folder of the project location will be served.
     /// server.use_custom_static(RequestPath::Explicit("/index.html"), PathBuf::from(r".\static"));
+    ///
+    /// // and all the files in the `dist` folder, including the nested ones, under `/assets`.
+    /// server.use_custom_static(RequestPath::Explicit("/assets"), PathBuf::from(r"./dist"));
     /// ```
     fn use_custom_static(&mut self, uri: RequestPath, path: PathBuf) -> &mut dyn Router {
-        self.add(REST::GET, uri, RouteHandler(None, Some(path)));
+        self.add(
+            REST::GET,
+            uri,
+            RouteHandler(None, Some(path), None, None, None),
+        );
+        self
+    }
+
+    /// Define the static folder location like `use_static`, and only serve the files that pass the
+    /// `allow` and `deny` lists. Each entry can be an extension formatted as `*.<extension>`, e.g.
+    /// `*.map`, an absolute path to a folder, or an exact file name, e.g. `secrets.json`. If the
+    /// `allow` list is not empty, only files matching it will be served.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rusty_express::prelude::*;
+    /// use std::path::PathBuf;
+    ///
+    /// let mut server = HttpServer::new();
+    /// server.use_static_filtered(PathBuf::from(r".\static"), &[], &["*.map", "secrets.json"]);
+    /// ```
+    fn use_static_filtered(
+        &mut self,
+        path: PathBuf,
+        allow: &[&str],
+        deny: &[&str],
+    ) -> &mut dyn Router {
+        self.set_static(REST::GET, path);
+        self.static_filter(&REST::GET, allow, deny);
         self
     }
 
@@ -724,15 +1642,7 @@ impl Router for Route {
     /// Note that if the `for_path` params are provided, the white list will only be applied to the
     /// given path (i.e. defined prior with the path to the static folder location).
     fn static_white_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>) {
-        let data = unsafe {
-            let raw = Arc::new(loc_or_ext.to_lowercase());
-
-            if loc_or_ext.starts_with("*.") {
-                StaticListData::Ext(raw)
-            } else {
-                StaticListData::Loc(raw)
-            }
-        };
+        let data = StaticListData::parse(&loc_or_ext);
 
         self.store.values_mut().for_each(|mut m| {
             if let Some(s_route) = m.static_path.as_mut() {
@@ -756,15 +1666,7 @@ impl Router for Route {
     /// Note that if the `for_path` params are provided, the white list will only be applied to the
     /// given path (i.e. defined prior with the path to the static folder location).
     fn static_black_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>) {
-        let data = unsafe {
-            let raw = Arc::new(loc_or_ext.to_lowercase());
-
-            if loc_or_ext.starts_with("*.") {
-                StaticListData::Ext(raw)
-            } else {
-                StaticListData::Loc(raw)
-            }
-        };
+        let data = StaticListData::parse(&loc_or_ext);
 
         self.store.values_mut().for_each(|mut route| {
             if let Some(s_route) = route.static_path.as_mut() {
@@ -779,12 +1681,50 @@ impl Router for Route {
         });
     }
 
+    /// Set how the static routes treat the path components beginning with a dot, e.g. `/.env` or
+    /// `/.well-known/acme-challenge/<token>`. By default, any such component gets a 404.
+    ///
+    /// Note that if the `for_path` params are provided, the policy will only be applied to the
+    /// given path (i.e. defined prior with the path to the static folder location).
+    fn static_dotfiles(&mut self, policy: DotfilePolicy, for_path: Option<PathBuf>) {
+        self.store.values_mut().for_each(|route| {
+            if let Some(s_route) = route.static_path.as_mut() {
+                if let Some(p) = for_path.as_ref() {
+                    if &s_route.location != p {
+                        return;
+                    }
+                }
+
+                s_route.dotfiles = policy.clone();
+            }
+        });
+    }
+
+    /// Set if the static routes shall follow the symlinks pointing outside of the static folder.
+    /// By default, a file is only served if its canonical path is still under the static folder.
+    ///
+    /// Note that if the `for_path` params are provided, the setting will only be applied to the
+    /// given path (i.e. defined prior with the path to the static folder location).
+    fn static_symlinks(&mut self, follow: bool, for_path: Option<PathBuf>) {
+        self.store.values_mut().for_each(|route| {
+            if let Some(s_route) = route.static_path.as_mut() {
+                if let Some(p) = for_path.as_ref() {
+                    if &s_route.location != p {
+                        return;
+                    }
+                }
+
+                s_route.follow_symlinks = follow;
+            }
+        });
+    }
+
     /// Note: this API only affect routes moving forward, and it will not be applied to routes
     /// already in the `Router`.
     fn case_sensitive(&mut self, allow_case: bool, method: Option<REST>) {
         if method.is_none() {
             for maps in self.store.values_mut() {
-                maps.case_sensitive = allow_case;
+                maps.case_sensitive(allow_case);
             }
 
             return;
@@ -792,161 +1732,493 @@ impl Router for Route {
 
         if let Some(m) = method {
             if let Some(maps) = self.store.get_mut(&m) {
-                maps.case_sensitive = allow_case
+                maps.case_sensitive(allow_case)
             }
         }
     }
-}
-
-pub(crate) trait RouteSeeker {
-    fn seek(method: &REST, uri: &str, tx: channel::Sender<(RouteHandler, HashMap<String, String>)>);
-    fn seek_sync(method: &REST, uri: &str) -> (RouteHandler, HashMap<String, String>);
-}
 
-impl RouteSeeker for Route {
-    fn seek(
-        method: &REST,
-        uri: &str,
-        tx: channel::Sender<(RouteHandler, HashMap<String, String>)>,
-    ) {
-        if let Err(e) = tx.send(Self::seek_sync(method, uri)) {
-            debug::print("Unable to find the route handler", InfoLevel::Error);
+    /// Set how the request paths are matched against the routes of the method, or of all methods
+    /// if `None`. Same as `case_sensitive`, this only applies to the methods with routes already
+    /// in the `Router`, though it also covers the routes added to them later.
+    fn normalization(&mut self, policy: RouteNormalization, method: Option<REST>) {
+        match method {
+            Some(m) => {
+                if let Some(maps) = self.store.get_mut(&m) {
+                    maps.normalization(policy);
+                }
+            }
+            None => {
+                for maps in self.store.values_mut() {
+                    maps.normalization(policy);
+                }
+            }
         }
     }
 
-    fn seek_sync(method: &REST, uri: &str) -> (RouteHandler, HashMap<String, String>) {
-        //TODO: check cache first
-
-        // keep the route_store in limited scope so we can release the read lock ASAP
-        Route::read().with(|r| {
-            let mut result = RouteHandler(None, None);
-            let mut params = HashMap::new();
-
-            // get from the method
-            if let Some(routes) = r.store.get(method) {
-                result = routes.seek_path(uri, &mut params);
-            }
+    fn fallback(&mut self, method: Option<REST>, callback: Callback) -> &mut dyn Router {
+        self.fallbacks.insert(method, callback);
+        self
+    }
 
-            // if a header only request, fallback to search with REST::GET
-            if result.is_none() && method == &REST::OTHER(String::from("HEADER")) {
-                if let Some(routes) = r.store.get(&REST::GET) {
-                    result = routes.seek_path(uri, &mut params);
-                }
-            }
+    fn with_views(&mut self, prefix: &str, mut scope: ViewsScope) -> &mut dyn Router {
+        let mut prefix = prefix.trim_end_matches('/').to_owned();
+        if !prefix.starts_with('/') {
+            prefix.insert(0, '/');
+        }
 
-            // otherwise, try the all-match routes
-            if result.is_none() {
-                if let Some(all_routes) = r.store.get(&REST::OTHER(String::from("*"))) {
-                    result = all_routes.seek_path(uri, &mut params);
-                }
-            }
+        // the same as the views root, the templates resolved outside of the folder are not found
+        if let Ok(root) = scope.root.canonicalize() {
+            scope.root = root;
+        } else {
+            rex_warn!("Unable to resolve the views root {}", scope.root.display());
+        }
 
-            (result, params)
-        })
+        self.views.retain(|(p, _)| p != &prefix);
+        self.views.push((prefix, Arc::new(scope)));
 
-        //TODO: Caching the request, also maintain the hash-map if it gets too large
+        // the longest prefix is tried first
+        self.views.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        self
     }
 }
 
-//TODO: add the 3rd field -- Option<Route>
-pub(crate) struct RouteHandler(Option<Callback>, Option<PathBuf>);
+/// `RouteGroup` registers the routes to the underlying router with the shared path prefix, see
+/// `Router::scope`. Explicit routes get the prefix prepended, and the wildcard routes get the prefix
+/// anchored at the beginning of the regex. The static folders are not affected by the prefix, and
+/// they are always served from the root.
+pub struct RouteGroup<'a> {
+    router: &'a mut dyn Router,
+    prefix: String,
+}
 
-impl RouteHandler {
-    pub(crate) fn new(cb: Option<Callback>, path: Option<PathBuf>) -> Self {
-        RouteHandler(cb, path)
-    }
+impl<'a> RouteGroup<'a> {
+    fn new(router: &'a mut dyn Router, prefix: &str) -> Self {
+        let mut normalized = String::with_capacity(prefix.len() + 1);
 
-    pub(crate) fn is_some(&self) -> bool {
-        self.0.is_some() || self.1.is_some()
-    }
+        if !prefix.starts_with('/') {
+            normalized.push('/');
+        }
 
-    pub(crate) fn is_none(&self) -> bool {
-        self.0.is_none() && self.1.is_none()
+        normalized.push_str(prefix.trim_end_matches('/'));
+
+        RouteGroup {
+            router,
+            prefix: normalized,
+        }
     }
 
-    pub(crate) fn execute(&mut self, req: &Box<Request>, resp: &mut Box<Response>) {
-        assert!(self.is_some());
+    /// The prefix of the group, not including the ones of the outer groups.
+    #[inline]
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
 
-        if let Some(cb) = self.0.take() {
-            cb(req, resp);
-            return;
+    fn join(&self, uri: &str) -> String {
+        if uri == "/" || uri.is_empty() {
+            return self.prefix.clone();
         }
 
-        if let Some(path) = self.1.take() {
-            resp.send_file_from_path_async(path);
+        let mut path = String::with_capacity(self.prefix.len() + uri.len() + 1);
+        path.push_str(&self.prefix);
+
+        if !uri.starts_with('/') {
+            path.push('/');
         }
+
+        path.push_str(uri);
+        path
     }
 
-    fn update_handler(mut handler: RouteHandler, file_name: &str) -> RouteHandler {
-        if file_name.is_empty() {
-            return handler;
+    fn anchor(&self, pattern: &str) -> String {
+        let prefix = regex::escape(&self.prefix);
+
+        // the rest is grouped, such that every branch of a top-level alternation gets the prefix
+        if pattern.starts_with('^') {
+            format!("^{}(?:{})", prefix, &pattern[1..])
+        } else {
+            format!("^{}.*(?:{})", prefix, pattern)
         }
+    }
 
-        if let Some(mut p) = handler.1.take() {
-            assert!(
-                handler.0.is_none(),
-                "Router error: callback and static files are found for this route ..."
-            );
+    /// Register the route to the underlying router, with the prefix applied to the path.
+    fn register<F>(&mut self, uri: RequestPath, add: F)
+    where
+        F: FnOnce(&mut dyn Router, RequestPath),
+    {
+        let (path, priority) = match uri {
+            RequestPath::Explicit(path) | RequestPath::ExplicitWithParams(path) => {
+                (self.join(path), 0)
+            }
+            RequestPath::WildCard(pattern) => (self.anchor(pattern), 0),
+            RequestPath::WildCardWithPriority(pattern, priority) => {
+                (self.anchor(pattern), priority)
+            }
+        };
 
-            p.push(file_name);
-            handler.1.replace(p);
+        let prefixed = match uri {
+            RequestPath::Explicit(_) => RequestPath::Explicit(&path),
+            RequestPath::ExplicitWithParams(_) => RequestPath::ExplicitWithParams(&path),
+            RequestPath::WildCard(_) => RequestPath::WildCard(&path),
+            RequestPath::WildCardWithPriority(..) => {
+                RequestPath::WildCardWithPriority(&path, priority)
+            }
         };
 
-        handler
+        add(self.router, prefixed);
     }
 }
 
-impl Default for RouteHandler {
-    fn default() -> Self {
-        RouteHandler(None, None)
+impl<'a> Router for RouteGroup<'a> {
+    fn get(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("GET", uri, callback)
     }
-}
 
-impl Clone for RouteHandler {
-    fn clone(&self) -> Self {
-        RouteHandler(self.0, self.1.clone())
+    fn patch(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("PATCH", uri, callback)
     }
-}
 
-/// The router guard struct, holding: 1) (mutable) reference to the underlying route; 2) The reader
-/// counter reference; 3) if guarding a read access, or not.
-#[doc(hidden)]
-struct RouteGuard<'a>(&'a mut Route, &'a AtomicUsize, bool);
+    fn post(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("POST", uri, callback)
+    }
 
-impl<'a> RouteGuard<'a> {
-    fn checkout(is_reader: bool) -> Self {
-        // prepare the base reference
-        let r = unsafe { ROUTER.as_mut().unwrap() };
+    fn put(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("PUT", uri, callback)
+    }
 
-        if is_reader {
-            // initial guess, doesn't really matter if we have to compete for the lock
-            let mut curr = r.1.load(Ordering::Relaxed);
+    fn delete(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("DELETE", uri, callback)
+    }
 
-            // waiting for the write lock to release
-            while let Err(old) =
-                r.1.compare_exchange(curr, curr + 1, Ordering::Acquire, Ordering::Relaxed)
-            {
-                let count = if old < 1 {
-                    // a writer holds the lock, wait till it releases it
-                    curr = 1;
-                    16
-                } else {
-                    // otherwise we're in readers mode, try to grab the lock before it goes away to
-                    // a contentious writer.
-                    curr = old;
-                    4
-                };
+    fn options(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("OPTIONS", uri, callback)
+    }
 
-                // take a break: if a writer has the lock, wait longer to check again.
-                cpu_relax(count);
-            }
-        } else {
-            // keep checking until all readers have released and no other writer is holding the lock
-            while r
-                .1
-                .compare_exchange(1, 0, Ordering::SeqCst, Ordering::Relaxed)
-                .is_err()
-            {
+    fn other(&mut self, method: &str, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.register(uri, |router, path| {
+            router.other(method, path, callback);
+        });
+
+        self
+    }
+
+    fn all(&mut self, uri: RequestPath, callback: Callback) -> &mut dyn Router {
+        self.other("*", uri, callback)
+    }
+
+    fn other_ref(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: RefCallback,
+    ) -> &mut dyn Router {
+        self.register(uri, |router, path| {
+            router.other_ref(method, path, callback);
+        });
+
+        self
+    }
+
+    fn with_options(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: Callback,
+        options: RouteOptions,
+    ) -> &mut dyn Router {
+        self.register(uri, |router, path| {
+            router.with_options(method, path, callback, options);
+        });
+
+        self
+    }
+
+    fn use_static(&mut self, path: PathBuf) -> &mut dyn Router {
+        self.router.use_static(path);
+        self
+    }
+
+    fn use_custom_static(&mut self, uri: RequestPath, path: PathBuf) -> &mut dyn Router {
+        self.register(uri, |router, uri| {
+            router.use_custom_static(uri, path);
+        });
+
+        self
+    }
+
+    fn use_static_filtered(
+        &mut self,
+        path: PathBuf,
+        allow: &[&str],
+        deny: &[&str],
+    ) -> &mut dyn Router {
+        self.router.use_static_filtered(path, allow, deny);
+        self
+    }
+
+    fn static_white_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>) {
+        self.router.static_white_list(loc_or_ext, for_path);
+    }
+
+    fn static_black_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>) {
+        self.router.static_black_list(loc_or_ext, for_path);
+    }
+
+    fn static_dotfiles(&mut self, policy: DotfilePolicy, for_path: Option<PathBuf>) {
+        self.router.static_dotfiles(policy, for_path);
+    }
+
+    fn static_symlinks(&mut self, follow: bool, for_path: Option<PathBuf>) {
+        self.router.static_symlinks(follow, for_path);
+    }
+
+    fn case_sensitive(&mut self, allow_case: bool, method: Option<REST>) {
+        self.router.case_sensitive(allow_case, method);
+    }
+
+    fn normalization(&mut self, policy: RouteNormalization, method: Option<REST>) {
+        self.router.normalization(policy, method);
+    }
+
+    /// The fallback is not scoped by the group's prefix, it applies to the whole router.
+    fn fallback(&mut self, method: Option<REST>, callback: Callback) -> &mut dyn Router {
+        self.router.fallback(method, callback);
+        self
+    }
+
+    /// The views scope is under the group's prefix.
+    fn with_views(&mut self, prefix: &str, scope: ViewsScope) -> &mut dyn Router {
+        let prefix = self.join(prefix);
+        self.router.with_views(&prefix, scope);
+        self
+    }
+}
+
+pub(crate) trait RouteSeeker {
+    fn seek(method: &REST, uri: &str, tx: channel::Sender<SeekResult>);
+    fn seek_sync(method: &REST, uri: &str) -> SeekResult;
+}
+
+impl RouteSeeker for Route {
+    fn seek(method: &REST, uri: &str, tx: channel::Sender<SeekResult>) {
+        if let Err(e) = tx.send(Self::seek_sync(method, uri)) {
+            rex_error!("Unable to find the route handler");
+        }
+    }
+
+    fn seek_sync(method: &REST, uri: &str) -> SeekResult {
+        if let Some(found) = Route::cached(method, uri) {
+            return found;
+        }
+
+        // keep the route_store in limited scope so we can release the read lock ASAP, and take
+        // the view of the router under the same lock, no writer can change it meanwhile
+        Route::read().with(|r| {
+            let view = RouterView {
+                auth_func: r.auth_func,
+                auth_func_mut: r.auth_func_mut,
+                generation: Route::generation(),
+                denied: false,
+            };
+
+            let mut params = HashMap::new();
+            let mut result = r.lookup(method, uri, &mut params);
+            result.4 = r.views_for(result.pattern());
+
+            // cache while holding the read lock, such that a change to the routes can't slip in
+            // between the lookup and the caching
+            let mut found = (result, params, view);
+            Route::cache(method, uri, &found);
+
+            // no route matched, so the method's fallback takes over the response if there's one;
+            // this is not cached, or the unmatched uris would crowd out the real routes
+            if found.0.is_none() {
+                if let Some(fallback) = r.fallback_for(method) {
+                    found.0 = fallback;
+                }
+            }
+
+            found
+        })
+    }
+}
+
+/// The route handler, holding: 1) the callback function; 2) the static file location; 3) the route
+/// pattern as it was registered, e.g. `/users/:id`, or the regex source for wildcard routes; 4) the
+/// route options; 5) the views scope the route falls under, which is attached at the lookup.
+pub(crate) struct RouteHandler(
+    Option<Handler>,
+    Option<PathBuf>,
+    Option<Arc<String>>,
+    Option<Arc<RouteOptions>>,
+    Option<Arc<ViewsScope>>,
+);
+
+impl RouteHandler {
+    pub(crate) fn new(cb: Option<Callback>, path: Option<PathBuf>) -> Self {
+        RouteHandler(cb.map(Handler::Boxed), path, None, None, None)
+    }
+
+    pub(crate) fn new_ref(cb: RefCallback) -> Self {
+        RouteHandler(Some(Handler::Plain(cb)), None, None, None, None)
+    }
+
+    fn redirect(location: String) -> Self {
+        RouteHandler(
+            Some(Handler::Redirect(Arc::new(location))),
+            None,
+            None,
+            None,
+            None,
+        )
+    }
+
+    #[inline]
+    pub(crate) fn pattern(&self) -> Option<&str> {
+        self.2.as_ref().map(|p| p.as_str())
+    }
+
+    #[inline]
+    pub(crate) fn set_pattern(&mut self, pattern: &str) {
+        self.2.replace(Arc::new(pattern.to_owned()));
+    }
+
+    #[inline]
+    pub(crate) fn set_options(&mut self, options: RouteOptions) {
+        self.3.replace(Arc::new(options));
+    }
+
+    /// The route's own body size limit, if it overrides the server-wide one.
+    #[inline]
+    pub(crate) fn max_body(&self) -> Option<usize> {
+        self.3.as_ref().and_then(|o| o.max_body)
+    }
+
+    /// How long the route's callback may run, if it's limited.
+    #[inline]
+    pub(crate) fn handler_timeout(&self) -> Option<Duration> {
+        self.3.as_ref().and_then(|o| o.handler_timeout)
+    }
+
+    /// The views scope of the matched route, if it falls under one.
+    #[inline]
+    pub(crate) fn views(&self) -> Option<Arc<ViewsScope>> {
+        self.4.clone()
+    }
+
+    pub(crate) fn is_some(&self) -> bool {
+        self.0.is_some() || self.1.is_some()
+    }
+
+    pub(crate) fn is_none(&self) -> bool {
+        self.0.is_none() && self.1.is_none()
+    }
+
+    pub(crate) fn execute(&mut self, req: &Box<Request>, resp: &mut Box<Response>) {
+        assert!(self.is_some());
+
+        if let Some(cb) = self.0.take() {
+            match cb {
+                Handler::Boxed(cb) => cb(req, resp),
+                Handler::Plain(cb) => cb(req, resp),
+                Handler::Redirect(location) => {
+                    resp.redirect(&[location.as_str(), &req.query_string()].concat())
+                }
+            }
+
+            return;
+        }
+
+        if let Some(path) = self.1.take() {
+            if let Some(range) = req.header("range") {
+                let status = resp.send_file_range(path, &range);
+                resp.status(status);
+                return;
+            }
+
+            resp.header("Accept-Ranges", "bytes", true);
+            resp.send_file_from_path_async(path);
+        }
+    }
+
+    fn update_handler(mut handler: RouteHandler, file_name: &str) -> RouteHandler {
+        if file_name.is_empty() {
+            return handler;
+        }
+
+        if let Some(mut p) = handler.1.take() {
+            assert!(
+                handler.0.is_none(),
+                "Router error: callback and static files are found for this route ..."
+            );
+
+            p.push(file_name);
+            handler.1.replace(p);
+        };
+
+        handler
+    }
+}
+
+impl Default for RouteHandler {
+    fn default() -> Self {
+        RouteHandler(None, None, None, None, None)
+    }
+}
+
+impl Clone for RouteHandler {
+    fn clone(&self) -> Self {
+        RouteHandler(
+            self.0.clone(),
+            self.1.clone(),
+            self.2.clone(),
+            self.3.clone(),
+            self.4.clone(),
+        )
+    }
+}
+
+/// The router guard struct, holding: 1) (mutable) reference to the underlying route; 2) The reader
+/// counter reference; 3) if guarding a read access, or not.
+#[doc(hidden)]
+struct RouteGuard<'a>(&'a mut Route, &'a AtomicUsize, bool);
+
+impl<'a> RouteGuard<'a> {
+    fn checkout(is_reader: bool) -> Self {
+        // prepare the base reference
+        let r = unsafe { ROUTER.as_mut().unwrap() };
+
+        if is_reader {
+            #[cfg(test)]
+            READ_CHECKOUTS.with(|count| count.set(count.get() + 1));
+
+            // initial guess, doesn't really matter if we have to compete for the lock
+            let mut curr = r.1.load(Ordering::Relaxed);
+
+            // waiting for the write lock to release
+            while let Err(old) =
+                r.1.compare_exchange(curr, curr + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                let count = if old < 1 {
+                    // a writer holds the lock, wait till it releases it
+                    curr = 1;
+                    16
+                } else {
+                    // otherwise we're in readers mode, try to grab the lock before it goes away to
+                    // a contentious writer.
+                    curr = old;
+                    4
+                };
+
+                // take a break: if a writer has the lock, wait longer to check again.
+                cpu_relax(count);
+            }
+        } else {
+            // keep checking until all readers have released and no other writer is holding the lock
+            while r
+                .1
+                .compare_exchange(1, 0, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+            {
                 cpu_relax(8);
             }
         }
@@ -965,7 +2237,9 @@ impl<'a> Drop for RouteGuard<'a> {
             // this is a reader guard
             self.1.fetch_sub(1, Ordering::Release);
         } else {
-            // this is a writer guard
+            // this is a writer guard, the router may have changed so move on to the next
+            // generation before releasing the lock, which outdates the cached lookups as well
+            GENERATION.fetch_add(1, Ordering::AcqRel);
             self.1.store(1, Ordering::SeqCst);
         }
     }
@@ -973,15 +2247,46 @@ impl<'a> Drop for RouteGuard<'a> {
 
 pub(crate) fn drop_statics() {
     unsafe {
-        ptr::drop_in_place(&mut ROUTER as *mut StaticStore<(Route, AtomicUsize)>);
-        ptr::drop_in_place(
-            &mut ROUTE_CACHE as *mut StaticStore<HashMap<(REST, String), RouteHandler>>,
-        );
+        // take the contents out such that they can't be dropped twice, e.g. when set again
+        drop(ROUTER.take());
+        drop(ROUTE_CACHE.take());
+    }
+}
+
+/// The path with the trailing slash removed, or appended if it has none. The root path has no other
+/// form.
+fn toggle_trailing_slash(uri: &str) -> Option<String> {
+    if uri.ends_with('/') {
+        let trimmed = uri.trim_end_matches('/');
+        if trimmed.is_empty() {
+            return None;
+        }
+
+        return Some(trimmed.to_owned());
+    }
+
+    let mut other = String::with_capacity(uri.len() + 1);
+    other.push_str(uri);
+    other.push('/');
+
+    Some(other)
+}
+
+/// Compile the regex of a wildcard route or a parameter's validation, where an invalid one is a
+/// mistake in the routes and shall fail the registration right away, rather than leaving the route
+/// silently missing.
+fn compile_pattern(pattern: &str) -> Regex {
+    match Regex::new(pattern) {
+        Ok(re) => re,
+        Err(e) => panic!(
+            "Route pattern is not a valid regex: {}, error: {}",
+            pattern, e
+        ),
     }
 }
 
 fn search_wildcard_router(routes: &HashMap<String, RegexRoute>, uri: &str) -> RouteHandler {
-    let mut result = RouteHandler(None, None);
+    let mut result = RouteHandler(None, None, None, None, None);
     for (_, route) in routes.iter() {
         if route.regex.is_match(&uri) {
             result = route.handler.clone();
@@ -992,24 +2297,73 @@ fn search_wildcard_router(routes: &HashMap<String, RegexRoute>, uri: &str) -> Ro
     result
 }
 
+fn search_priority_router(routes: &[(u8, RegexRoute)], uri: &str) -> RouteHandler {
+    match routes.iter().find(|(_, route)| route.regex.is_match(uri)) {
+        Some((_, route)) => route.handler.clone(),
+        None => RouteHandler(None, None, None, None, None),
+    }
+}
+
 fn search_params_router(
     head: &RouteTrie,
     uri: &str,
     params: &mut HashMap<String, String>,
+    allow_case: bool,
 ) -> RouteHandler {
     let raw_segments: Vec<String> = uri.trim_matches('/').split('/').map(String::from).collect();
 
     params.reserve(raw_segments.len());
-    let result = RouteTrie::find(head, raw_segments.as_slice(), params);
+    let result = RouteTrie::find(head, raw_segments.as_slice(), params, allow_case);
 
     params.shrink_to_fit();
     result
 }
 
+/// Resolve the relative path under the folder of the custom static route, which is guarded like
+/// the static root: the relative jumps have been dropped from the uri already, the hidden
+/// components are denied, and the path can't escape the folder through a symlink. A denied path
+/// resolves to no route at all.
+fn resolve_custom_static(handler: &RouteHandler, rest: &str) -> RouteHandler {
+    let folder = match handler.1.as_ref() {
+        Some(folder) => folder,
+        None => return RouteHandler::default(),
+    };
+
+    if rest.contains('\\') || rest.contains('\0') || !DotfilePolicy::Deny.permits(rest) {
+        return RouteHandler::default();
+    }
+
+    let mut loc = folder.clone();
+    loc.push(rest);
+
+    // a missing file can't escape, and it will get a 404 when served
+    if let (Ok(real), Ok(root)) = (fs::canonicalize(&loc), fs::canonicalize(folder)) {
+        if !real.starts_with(&root) {
+            return RouteHandler::default();
+        }
+    }
+
+    let mut found = handler.clone();
+    found.1 = Some(loc);
+    found
+}
+
 fn search_static_router(path: &StaticLocRoute, raw_uri: &str) -> Result<RouteHandler, ()> {
+    // the uri has been percent-decoded when parsing the request, so the escaped `%2e%2e%2f` is
+    // already a relative jump by now; backslashes are separators on Windows, and they're never
+    // part of a valid static uri
+    if raw_uri.contains('\\') || raw_uri.contains('\0') {
+        return Err(());
+    }
+
+    // hidden components and relative jumps are checked against the dotfile policy
+    if !path.dotfiles.permits(raw_uri) {
+        return Err(());
+    }
+
     // check if static path can be met
     let mut normalized_uri = path.location.clone();
-    normalized_uri.push(raw_uri.trim_start_matches(|x| x == '.' || x == '/'));
+    normalized_uri.push(raw_uri.trim_start_matches('/'));
 
     let meta = match fs::metadata(&normalized_uri) {
         Ok(m) => m,
@@ -1018,7 +2372,7 @@ fn search_static_router(path: &StaticLocRoute, raw_uri: &str) -> Result<RouteHan
 
     // only if the file exists
     if meta.is_file() {
-        if !path.check_access(&normalized_uri) {
+        if !path.within_root(&normalized_uri) || !path.check_access(&normalized_uri) {
             return Err(());
         }
 
@@ -1036,7 +2390,7 @@ fn search_static_router(path: &StaticLocRoute, raw_uri: &str) -> Result<RouteHan
         //            return Err(());
         //        }
 
-        return Ok(RouteHandler(None, Some(normalized_uri)));
+        return Ok(RouteHandler(None, Some(normalized_uri), None, None, None));
     }
 
     Ok(RouteHandler::default())
@@ -1044,8 +2398,33 @@ fn search_static_router(path: &StaticLocRoute, raw_uri: &str) -> Result<RouteHan
 
 #[cfg(test)]
 mod route_test {
-    use super::{Field, RouteMap};
+    use super::{
+        search_static_router, DotfilePolicy, Field, RequestPath, Route, RouteHandler, RouteMap,
+        RouteNormalization, Router, RouterView, TrailingSlash, ViewsScope, REST,
+    };
+    use crate::core::http::{Request, RequestWriter, Response, ResponseStates, ResponseWriter};
+    use crate::hashbrown::HashMap;
+    use crate::support::common::percent_decode;
+    use crate::support::RouteCache;
     use regex::*;
+    use std::{env, fs};
+
+    fn dummy(_req: &Box<Request>, _resp: &mut Box<Response>) {}
+
+    fn handler() -> RouteHandler {
+        RouteHandler::new(Some(dummy), None)
+    }
+
+    fn sitemap_routes(priority: u8) -> RouteMap {
+        let mut map = RouteMap::new();
+        map.insert(RequestPath::ExplicitWithParams("/:page"), handler());
+        map.insert(
+            RequestPath::WildCardWithPriority(r"^/sitemap.*\.xml$", priority),
+            handler(),
+        );
+
+        map
+    }
 
     #[test]
     fn params_parser_test_one() {
@@ -1068,4 +2447,635 @@ mod route_test {
             num += 1;
         }
     }
+
+    #[test]
+    fn route_pattern_explicit() {
+        let mut map = RouteMap::new();
+        map.insert(RequestPath::Explicit("/users/all"), handler());
+
+        let mut params = HashMap::new();
+        let result = map.seek_path("/users/all", &mut params);
+
+        assert!(result.is_some());
+        assert_eq!(result.pattern(), Some("/users/all"));
+    }
+
+    #[test]
+    fn route_pattern_params() {
+        let mut map = RouteMap::new();
+        map.insert(
+            RequestPath::ExplicitWithParams("/users/:id/orders/:oid"),
+            handler(),
+        );
+
+        let mut params = HashMap::new();
+        let result = map.seek_path("/users/12/orders/7", &mut params);
+
+        assert!(result.is_some());
+        assert_eq!(result.pattern(), Some("/users/:id/orders/:oid"));
+        assert_eq!(params.get("id"), Some(&String::from("12")));
+        assert_eq!(params.get("oid"), Some(&String::from("7")));
+    }
+
+    #[test]
+    fn route_pattern_wildcard() {
+        let mut map = RouteMap::new();
+        map.insert(RequestPath::WildCard(r"^/files/\d+$"), handler());
+
+        let mut params = HashMap::new();
+        let result = map.seek_path("/files/42", &mut params);
+
+        assert!(result.is_some());
+        assert_eq!(result.pattern(), Some(r"^/files/\d+$"));
+    }
+
+    #[test]
+    fn route_priority_default_order() {
+        let map = sitemap_routes(0);
+
+        let mut params = HashMap::new();
+        let result = map.seek_path("/sitemap-posts.xml", &mut params);
+
+        assert_eq!(result.pattern(), Some("/:page"));
+        assert_eq!(params.get("page"), Some(&String::from("sitemap-posts.xml")));
+    }
+
+    #[test]
+    fn route_priority_wildcard_wins() {
+        let map = sitemap_routes(1);
+
+        let mut params = HashMap::new();
+        let result = map.seek_path("/sitemap-posts.xml", &mut params);
+
+        assert_eq!(result.pattern(), Some(r"^/sitemap.*\.xml$"));
+        assert!(params.is_empty());
+
+        let result = map.seek_path("/about", &mut params);
+        assert_eq!(result.pattern(), Some("/:page"));
+    }
+
+    #[test]
+    fn static_filtered_lists() {
+        let root = env::temp_dir().join(format!("rex-static-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        for file in &["app.css", "app.js.map", "secrets.json", ".env"] {
+            fs::write(root.join(file), file).unwrap();
+        }
+
+        // the content of the file served for the uri, or `None` if it's refused
+        let served = |route: &Route, uri: &str| {
+            let map = route.store.get(&REST::GET).unwrap();
+            let handler = map.seek_path(uri, &mut HashMap::new());
+            assert!(handler.0.is_none(), "{} is not a static file", uri);
+            handler.1.map(|path| fs::read_to_string(path).unwrap())
+        };
+
+        let mut route = Route::new();
+        route.use_static_filtered(root.clone(), &[], &["*.map", "secrets.json", ".env"]);
+
+        assert_eq!(served(&route, "/app.css").as_deref(), Some("app.css"));
+        assert_eq!(served(&route, "/app.js.map"), None);
+        assert_eq!(served(&route, "/secrets.json"), None);
+        assert_eq!(served(&route, "/.env"), None);
+
+        // the white list wins over the black list
+        route.static_filter(&REST::GET, &["*.CSS"], &[]);
+
+        assert_eq!(served(&route, "/app.css").as_deref(), Some("app.css"));
+        assert_eq!(served(&route, "/app.js.map"), None);
+        assert_eq!(served(&route, "/secrets.json"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn handler_signature_styles() {
+        fn boxed(req: &Box<Request>, resp: &mut Box<Response>) {
+            resp.header("X-Handler", &format!("boxed {}", req.uri), true);
+        }
+
+        fn plain(req: &Request, resp: &mut Response) {
+            resp.header("X-Handler", &format!("plain {}", req.uri), true);
+        }
+
+        let mut route = Route::new();
+        route
+            .get(RequestPath::Explicit("/boxed"), boxed)
+            .get_ref(RequestPath::Explicit("/plain"), plain);
+
+        let map = route.store.get(&REST::GET).unwrap();
+        for (uri, expected) in &[("/boxed", "boxed /boxed"), ("/plain", "plain /plain")] {
+            let mut req = Box::new(Request::new());
+            req.uri = uri.to_string();
+
+            let mut resp = Box::new(Response::new());
+            map.seek_path(uri, &mut HashMap::new())
+                .execute(&req, &mut resp);
+
+            assert_eq!(
+                resp.get_header("x-handler").map(String::as_str),
+                Some(*expected)
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_slash_and_case_normalization() {
+        let seek = |trailing_slash: TrailingSlash, case_sensitive: bool, uri: &str| {
+            let mut route = Route::new();
+            route.get(RequestPath::Explicit("/foo"), dummy);
+            route.normalization(
+                RouteNormalization {
+                    trailing_slash,
+                    case_sensitive,
+                },
+                None,
+            );
+
+            let map = route.store.get(&REST::GET).unwrap();
+            let mut resp = Box::new(Response::new());
+            let mut handler = map.seek_path(uri, &mut HashMap::new());
+            let found = handler.is_some();
+
+            if found {
+                handler.execute(&Box::new(Request::new()), &mut resp);
+            }
+
+            (found, resp.get_redirect_path())
+        };
+
+        assert_eq!(
+            seek(TrailingSlash::Strict, false, "/foo/"),
+            (false, String::new())
+        );
+        assert_eq!(
+            seek(TrailingSlash::Ignore, false, "/foo/"),
+            (true, String::new())
+        );
+        assert_eq!(
+            seek(TrailingSlash::Redirect, false, "/foo/"),
+            (true, String::from("/foo"))
+        );
+
+        assert_eq!(
+            seek(TrailingSlash::Strict, false, "/FOO"),
+            (true, String::new())
+        );
+        assert_eq!(
+            seek(TrailingSlash::Strict, true, "/FOO"),
+            (false, String::new())
+        );
+    }
+
+    #[test]
+    fn runtime_built_paths() {
+        // e.g. loaded from a config file, and gone after the registration
+        let paths: Vec<String> = vec!["/about", "/users/:id", "/files/:name/raw"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let mut route = Route::new();
+        for path in paths.iter() {
+            if path.contains(':') {
+                route.get(RequestPath::ExplicitWithParams(path), dummy);
+            } else {
+                route.get(RequestPath::Explicit(path), dummy);
+            }
+        }
+
+        drop(paths);
+
+        let map = route.store.get(&REST::GET).unwrap();
+        let mut params = HashMap::new();
+
+        assert!(map.seek_path("/about", &mut params).is_some());
+        assert!(map.seek_path("/files/report/raw", &mut params).is_some());
+        assert_eq!(params.get("name").map(String::as_str), Some("report"));
+
+        params.clear();
+        assert!(map.seek_path("/users/42", &mut params).is_some());
+        assert_eq!(params.get("id").map(String::as_str), Some("42"));
+    }
+
+    #[test]
+    fn route_cache_hit_path() {
+        let mut route = Route::new();
+        for i in 0..150 {
+            route.get(
+                RequestPath::ExplicitWithParams(&format!("/api/r{}/:id/items/:item", i)),
+                dummy,
+            );
+        }
+
+        for i in 0..50 {
+            route.get(RequestPath::WildCard(&format!(r"^/w{}/v\d+/.*$", i)), dummy);
+        }
+
+        let map = route.store.get(&REST::GET).unwrap();
+        let uris = ["/api/r149/7/items/3", "/w49/v2/report"];
+
+        let mut cache = RouteCache::new();
+        for uri in uris.iter() {
+            assert!(cache.get(&REST::GET, uri, 0).is_none());
+
+            let mut params = HashMap::new();
+            let found = map.seek_path(uri, &mut params);
+            cache.insert(&REST::GET, uri, (found, params, RouterView::default()), 16);
+        }
+
+        for _ in 0..10 {
+            for uri in uris.iter() {
+                assert!(cache.get(&REST::GET, uri, 0).unwrap().0.is_some());
+            }
+        }
+
+        // only the first lookups walk the routes, the rest are served from the cache
+        assert_eq!(cache.stats(), (20, 2));
+        assert_eq!(
+            cache.get(&REST::GET, uris[0], 0).unwrap().1.get("item"),
+            Some(&String::from("3"))
+        );
+
+        // an outdated entry is a miss until it's refreshed
+        assert!(cache.get(&REST::GET, uris[1], 1).is_none());
+        assert_eq!(cache.stats(), (21, 3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Route pattern is not a valid regex: ^/files/(\\d+$")]
+    fn wildcard_invalid_pattern() {
+        RouteMap::new().insert(RequestPath::WildCard(r"^/files/(\d+$"), handler());
+    }
+
+    #[test]
+    fn wildcard_anchoring() {
+        let mut map = RouteMap::new();
+        map.insert(RequestPath::WildCard("foo"), handler());
+        map.insert(RequestPath::WildCard(r"^/bar$"), handler());
+
+        let mut params = HashMap::new();
+        assert!(map.seek_path("/anything/foo/bar", &mut params).is_some());
+        assert!(map.seek_path("/bar", &mut params).is_some());
+        assert!(map.seek_path("/bar/baz", &mut params).is_none());
+        assert!(map.seek_path("/x/bar", &mut params).is_none());
+    }
+
+    #[test]
+    fn fallback_precedence() {
+        fn page(_req: &Box<Request>, resp: &mut Box<Response>) {
+            resp.status(200);
+        }
+
+        fn anything(_req: &Box<Request>, resp: &mut Box<Response>) {
+            resp.status(202);
+        }
+
+        fn post_missing(_req: &Box<Request>, resp: &mut Box<Response>) {
+            resp.status(404);
+        }
+
+        fn any_missing(_req: &Box<Request>, resp: &mut Box<Response>) {
+            resp.status(410);
+        }
+
+        let status_of = |route: &Route, method: REST, uri: &str| {
+            let mut params = HashMap::new();
+            let mut handler = route.lookup(&method, uri, &mut params);
+            if handler.is_none() {
+                handler = match route.fallback_for(&method) {
+                    Some(fallback) => fallback,
+                    None => return None,
+                };
+            }
+
+            let mut resp = Box::new(Response::new());
+            handler.execute(&Box::new(Request::new()), &mut resp);
+            Some(resp.get_status())
+        };
+
+        let mut route = Route::new();
+        route.get(RequestPath::Explicit("/page"), page);
+        route.all(RequestPath::Explicit("/everything"), anything);
+
+        // nothing registered, the framework's 404 it is
+        assert_eq!(status_of(&route, REST::POST, "/nowhere"), None);
+
+        route.fallback(Some(REST::POST), post_missing);
+        assert_eq!(status_of(&route, REST::POST, "/nowhere"), Some(404));
+        assert_eq!(status_of(&route, REST::GET, "/nowhere"), None);
+
+        route.fallback(None, any_missing);
+        assert_eq!(status_of(&route, REST::POST, "/nowhere"), Some(404));
+        assert_eq!(status_of(&route, REST::GET, "/nowhere"), Some(410));
+        assert_eq!(status_of(&route, REST::HEAD, "/nowhere"), Some(410));
+
+        // the routes, including the all-match ones, come before any fallback
+        assert_eq!(status_of(&route, REST::GET, "/page"), Some(200));
+        assert_eq!(status_of(&route, REST::HEAD, "/page"), Some(200));
+        assert_eq!(status_of(&route, REST::POST, "/everything"), Some(202));
+    }
+
+    #[test]
+    fn auth_attaches_extensions() {
+        struct UserId(u64);
+
+        fn login(req: &mut Box<Request>, uri: &str) -> bool {
+            if uri.starts_with("/admin") {
+                return false;
+            }
+
+            req.set_ext(UserId(7));
+            true
+        }
+
+        let view = RouterView {
+            auth_func: None,
+            auth_func_mut: Some(login),
+            generation: 0,
+            denied: false,
+        };
+
+        let mut req = Box::new(Request::new());
+        req.uri.push_str("/dashboard");
+        assert!(view.authorize(&mut req));
+        assert_eq!(req.get_ext::<UserId>().map(|id| id.0), Some(7));
+
+        let mut req = Box::new(Request::new());
+        req.uri.push_str("/admin");
+        assert!(!view.authorize(&mut req));
+        assert!(req.get_ext::<UserId>().is_none());
+
+        // no auth function, no check
+        assert!(RouterView::default().authorize(&mut req));
+
+        // unless the lookup has failed, then nothing is let through
+        assert!(!RouterView::denied().authorize(&mut req));
+
+        // the router can't lend the mutable request to the auth function, so it won't let it pass
+        let mut route = Route::new();
+        route.auth_func_mut = Some(login);
+        assert!(!route.authorizes(&req, "/dashboard"));
+
+        route.auth_func_mut = None;
+        assert!(route.authorizes(&req, "/admin"));
+    }
+
+    #[test]
+    fn remove_and_replace_routes() {
+        fn tagged(_req: &Box<Request>, resp: &mut Box<Response>) {
+            resp.header("X-Handler", "tagged", true);
+        }
+
+        let mut route = Route::new();
+        route
+            .get(RequestPath::ExplicitWithParams("/users/:id"), dummy)
+            .get(RequestPath::ExplicitWithParams("/users/:id/posts"), dummy)
+            .get(RequestPath::WildCardWithPriority(r"^/v\d+/.*$", 2), dummy);
+
+        let map = route.store.get_mut(&REST::GET).unwrap();
+        let found = |map: &RouteMap, uri: &str| map.seek_path(uri, &mut HashMap::new());
+
+        // the sibling route sharing the branch is kept
+        assert!(map
+            .remove(RequestPath::ExplicitWithParams("/users/:id"))
+            .is_some());
+        assert!(found(map, "/users/7").is_none());
+        assert!(found(map, "/users/7/posts").is_some());
+        assert!(map
+            .remove(RequestPath::ExplicitWithParams("/users/:id"))
+            .is_none());
+
+        assert!(
+            map.update(RequestPath::ExplicitWithParams("/users/:id/posts"), |h| {
+                *h = RouteHandler::new(Some(tagged), None);
+            })
+        );
+
+        let mut resp = Box::new(Response::new());
+        found(map, "/users/7/posts").execute(&Box::new(Request::new()), &mut resp);
+        assert_eq!(
+            resp.get_header("x-handler").map(String::as_str),
+            Some("tagged")
+        );
+
+        assert!(found(map, "/v2/users").is_some());
+        assert!(map
+            .remove(RequestPath::WildCardWithPriority(r"^/v\d+/.*$", 2))
+            .is_some());
+        assert!(found(map, "/v2/users").is_none());
+    }
+
+    #[test]
+    fn nested_route_groups() {
+        let mut route = Route::new();
+        route.scope("/api/v1/", |api| {
+            api.get(RequestPath::Explicit("/"), dummy)
+                .get(RequestPath::ExplicitWithParams("/users/:id"), dummy);
+
+            api.scope("admin", |admin| {
+                admin
+                    .get(RequestPath::Explicit("/stats"), dummy)
+                    .get(RequestPath::WildCard(r"^/logs/\d+$"), dummy)
+                    .get(RequestPath::WildCard(r"^/audit/\d+$|/report$"), dummy);
+            });
+        });
+
+        let map = route.store.get(&REST::GET).unwrap();
+        let mut params = HashMap::new();
+
+        assert!(map.seek_path("/api/v1", &mut params).is_some());
+        assert!(map.seek_path("/api/v1/admin/stats", &mut params).is_some());
+        assert!(map
+            .seek_path("/api/v1/admin/logs/42", &mut params)
+            .is_some());
+        assert!(map.seek_path("/admin/stats", &mut params).is_none());
+        assert!(map.seek_path("/logs/42", &mut params).is_none());
+
+        // every branch of the alternation is anchored under the prefix
+        assert!(map
+            .seek_path("/api/v1/admin/audit/42", &mut params)
+            .is_some());
+        assert!(map.seek_path("/api/v1/admin/report", &mut params).is_some());
+        assert!(map.seek_path("/report", &mut params).is_none());
+        assert!(map.seek_path("/files/report", &mut params).is_none());
+
+        params.clear();
+        assert!(map.seek_path("/api/v1/users/7", &mut params).is_some());
+        assert_eq!(params.get("id").map(String::as_str), Some("7"));
+    }
+
+    #[test]
+    fn static_dotfile_policy() {
+        let root = env::temp_dir().join(format!("rex-dotfiles-{}", std::process::id()));
+        fs::create_dir_all(root.join(".well-known/acme-challenge")).unwrap();
+        fs::write(root.join(".env"), "SECRET=1").unwrap();
+        fs::write(root.join(".well-known/acme-challenge/token"), "proof").unwrap();
+
+        let mut route = Route::new();
+        route.use_static(root.clone());
+
+        let found = |route: &Route, uri: &str| {
+            let mut params = HashMap::new();
+            let map = route.store.get(&REST::GET).unwrap();
+            map.seek_path(uri, &mut params).1.is_some()
+        };
+
+        // denied by default
+        assert!(!found(&route, "/.env"));
+        assert!(!found(&route, "/.well-known/acme-challenge/token"));
+
+        route.static_dotfiles(
+            DotfilePolicy::AllowList(vec![String::from(".well-known")]),
+            None,
+        );
+        assert!(found(&route, "/.well-known/acme-challenge/token"));
+        assert!(!found(&route, "/.env"));
+
+        route.static_dotfiles(DotfilePolicy::Allow, None);
+        assert!(found(&route, "/.env"));
+        assert!(!found(&route, "/.well-known/../.env"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn nested_custom_static() {
+        let base = env::temp_dir().join(format!("rex-custom-static-{}", std::process::id()));
+        let dist = base.join("dist");
+        fs::create_dir_all(dist.join("css")).unwrap();
+        fs::create_dir_all(dist.join("js")).unwrap();
+        fs::create_dir_all(dist.join(".git")).unwrap();
+        fs::write(dist.join("css").join("site.css"), "body {}").unwrap();
+        fs::write(dist.join("js").join("app.js"), "app()").unwrap();
+        fs::write(dist.join("index.html"), "index").unwrap();
+        fs::write(dist.join(".git").join("config.txt"), "config").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        let mut route = Route::new();
+        route.use_custom_static(RequestPath::Explicit("/assets"), dist.clone());
+
+        let resolve = |uri: &str| {
+            let map = route.store.get(&REST::GET).unwrap();
+            let mut params = HashMap::new();
+            map.seek_path(&percent_decode(uri, false), &mut params).1
+        };
+
+        assert_eq!(
+            resolve("/assets/css/site.css"),
+            Some(dist.join("css").join("site.css"))
+        );
+        assert_eq!(
+            resolve("/assets/js/app.js"),
+            Some(dist.join("js").join("app.js"))
+        );
+        assert_eq!(resolve("/assets/index.html"), Some(dist.join("index.html")));
+        assert_eq!(resolve("/other/css/site.css"), None);
+        assert_eq!(resolve("/assets/.git/config.txt"), None);
+
+        // the relative jumps are dropped, so they can't get out of the folder
+        for uri in [
+            "/assets/../secret.txt",
+            "/assets/css/%2e%2e/%2e%2e/secret.txt",
+        ]
+        .iter()
+        {
+            let loc = resolve(uri).unwrap();
+            assert!(loc.starts_with(&dist), "{:?}", loc);
+            assert!(!loc.exists());
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret.txt"), dist.join("css").join("link.css"))
+                .unwrap();
+            assert_eq!(resolve("/assets/css/link.css"), None);
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn static_traversal_rejected() {
+        let base = env::temp_dir().join(format!("rex-traversal-{}", std::process::id()));
+        let root = base.join("public");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "index").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        let mut route = Route::new();
+        route.use_static(root.clone());
+
+        // the uri arrives percent-decoded from the request parser
+        let check = |route: &Route, uri: &str| {
+            let map = route.store.get(&REST::GET).unwrap();
+            let uri = percent_decode(uri, false);
+            search_static_router(map.static_path.as_ref().unwrap(), &uri).map(|res| res.is_some())
+        };
+
+        assert_eq!(check(&route, "/index.html"), Ok(true));
+        assert_eq!(check(&route, "/%69ndex.html"), Ok(true));
+        assert_eq!(check(&route, "/../secret.txt"), Err(()));
+        assert_eq!(check(&route, "/../../etc/passwd"), Err(()));
+        assert_eq!(check(&route, "/%2e%2e%2fsecret.txt"), Err(()));
+        assert_eq!(check(&route, "/%2E%2E/%2e%2e/etc/passwd"), Err(()));
+        assert_eq!(check(&route, "/..%5csecret.txt"), Err(()));
+        assert_eq!(check(&route, "/%zz.html"), Ok(false));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+
+            assert_eq!(check(&route, "/link.txt"), Err(()));
+
+            route.static_symlinks(true, None);
+            assert_eq!(check(&route, "/link.txt"), Ok(true));
+            assert_eq!(check(&route, "/../secret.txt"), Err(()));
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn views_scope_lookup() {
+        let scope = |ext: &str| ViewsScope {
+            root: env::temp_dir(),
+            default_engine_ext: Some(ext.to_owned()),
+        };
+
+        let mut route = Route::new();
+        route.with_views("/admin", scope("tpl"));
+        route.with_views("admin/reports/", scope("tpl"));
+        route.scope("/shop", |shop| {
+            shop.with_views("/", scope("shop"));
+        });
+
+        // registering the prefix again replaces its scope
+        route.with_views("/admin/", scope("hbs"));
+
+        let engine_of = |pattern: Option<&str>| {
+            route
+                .views_for(pattern)
+                .and_then(|views| views.default_engine_ext.clone())
+        };
+
+        assert_eq!(route.views.len(), 3);
+        assert_eq!(engine_of(Some("/admin")), Some(String::from("hbs")));
+        assert_eq!(
+            engine_of(Some("/admin/users/:id")),
+            Some(String::from("hbs"))
+        );
+        assert_eq!(
+            engine_of(Some("^/admin.*(?:/x$)")),
+            Some(String::from("hbs"))
+        );
+        assert_eq!(
+            engine_of(Some("/admin/reports/daily")),
+            Some(String::from("tpl"))
+        );
+        assert_eq!(engine_of(Some("/shop/cart")), Some(String::from("shop")));
+        assert_eq!(engine_of(Some("/administrators")), None);
+        assert_eq!(engine_of(Some("/")), None);
+        assert_eq!(engine_of(None), None);
+    }
 }
@@ -1,28 +1,92 @@
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use crate::channel;
 use crate::core::{
-    config::{ServerConfig, ViewEngine, ViewEngineDefinition},
+    config::{ConnParams, ServerConfig, ViewEngine, ViewEngineDefinition},
     conn::{self, StreamHandler},
     http,
-    router::{self, Callback, RequestPath, Route, RouteHandler, Router, REST},
-    states::{AsyncController, ControlMessage, ServerStates},
+    ipfilter::IpFilter,
+    router::{
+        self, Callback, DotfilePolicy, RefCallback, RequestPath, Route, RouteHandler,
+        RouteNormalization, RouteOptions, Router, ViewsScope, REST,
+    },
+    states::{AsyncController, ControlMessage, QueryKind, QueryReply, ServerStates},
+    stats::{self, ConnError, ServerStats, TlsFailure},
     stream::Stream,
 };
 use crate::hashbrown::HashMap;
-use crate::native_tls::TlsAcceptor;
+use crate::native_tls::{HandshakeError, TlsAcceptor};
+use crate::parking_lot::RwLock;
 use crate::support::{
-    debug::{self, InfoLevel},
-    session::*,
-    shared_pool, ThreadPool, TimeoutPolicy,
+    debug, session::*, shared_pool, Priority, TaskType, ThreadPool, TimeoutPolicy,
 };
 
+#[cfg(feature = "logger")]
+use crate::support::logger::{self, DefaultLogWriter};
+#[cfg(feature = "logger")]
+use std::fs;
+
+const SHUTDOWN_STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What wakes up the accept loop.
+enum Wakeup {
+    Stream(io::Result<TcpStream>, Priority),
+    Control(ControlMessage),
+    /// The listeners of the priority are all closed, or the courier is if `None`.
+    Closed(Option<Priority>),
+}
+
 //TODO: Impl middlewear
 
+/// The reasons the server can't be launched, see `HttpServer::try_listen`.
+#[derive(Debug)]
+pub enum ServerError {
+    /// Unable to bind to the address, e.g. the port is already in use, or the privileges are
+    /// insufficient for the port.
+    BindFailed(io::Error),
+
+    /// Unable to build the TLS acceptor from the identity set in the config.
+    TlsSetupFailed(String),
+
+    /// A server has already been launched in this process, where only 1 instance is allowed.
+    AlreadyRunning,
+
+    /// Unable to launch the workers of the thread pools.
+    PoolInitFailed(String),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::BindFailed(err) => write!(f, "Unable to bind to the address: {}", err),
+            ServerError::TlsSetupFailed(err) => write!(f, "Unable to set up TLS: {}", err),
+            ServerError::AlreadyRunning => {
+                write!(f, "Only 1 instance of the server is allowed per process")
+            }
+            ServerError::PoolInitFailed(err) => {
+                write!(f, "Unable to launch the thread pools: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::BindFailed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 /// The server instance that represents and controls the underlying http-service.
 pub struct HttpServer {
     config: ServerConfig,
@@ -48,7 +112,7 @@ impl HttpServer {
     ///
     /// # Examples
     ///
-    /// ```rust
+    /// ```no_run
     /// extern crate rusty_express as express;
     /// use express::prelude::{HttpServer, ServerDef, Router, Route};
     ///
@@ -93,52 +157,110 @@ impl HttpServer {
     /// }));
     /// ```
     pub fn listen_and_serve(&mut self, port: u16, callback: Option<fn(AsyncController)>) {
-        // initialize the debug service, which setup the debug level based on the environment variable
-        debug::initialize();
-
-        // update the server state for the socket-host address
-        self.state.set_port(port);
-
-        // create the listener
-        let server_address = SocketAddr::from(([127, 0, 0, 1], port));
-        let listener = TcpListener::bind(server_address).unwrap_or_else(|err| {
-            panic!("Unable to start the http server: {}...", err);
-        });
-
-        // obtain the control message courier service and start the callback
-        let (control_handler, controller_tx) = if let Some(cb) = callback {
-            let sender = self.state.get_courier_sender();
-            let (tx, rx) = channel::bounded(1);
+        self.try_listen_and_serve(port, callback)
+            .unwrap_or_else(|err| panic!("Unable to start the http server: {}...", err));
+    }
 
-            let handler = thread::spawn(move || {
-                // wait for server to launch before it's ready to take control messages.
-                let _ = rx.recv();
-                cb(sender);
-            });
+    /// Same as `listen`, but return the error instead of panicking if the server can't be launched,
+    /// e.g. the port is already in use, such that the caller can retry with another port.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use rusty_express::prelude::*;
+    ///
+    /// let mut server = HttpServer::new();
+    ///
+    /// if let Err(ServerError::BindFailed(_)) = server.try_listen(8080) {
+    ///     server.try_listen(8081).unwrap();
+    /// }
+    /// ```
+    pub fn try_listen(&mut self, port: u16) -> Result<(), ServerError> {
+        self.try_listen_and_serve(port, None)
+    }
 
-            (Some(handler), Some(tx))
-        } else {
-            (None, None)
-        };
+    /// Same as `listen_and_serve`, but return the error instead of panicking if the server can't be
+    /// launched. The callback is only run if the server is launched.
+    pub fn try_listen_and_serve(
+        &mut self,
+        port: u16,
+        callback: Option<fn(AsyncController)>,
+    ) -> Result<(), ServerError> {
+        let addr = SocketAddr::new(self.config.get_bind_address(), port);
+        self.try_serve(vec![(addr, Priority::Normal)], callback)
+    }
 
-        // launch the service, now this will block until the server is shutdown
-        println!("Listening for connections on port {}", port);
+    /// `listen_on` will take 1 parameter for the socket address that the server will be monitoring
+    /// at, e.g. `0.0.0.0:8080` to accept connections from other machines. This function will block
+    /// until the server is shut down.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use rusty_express::prelude::*;
+    ///
+    /// let mut server = HttpServer::new();
+    ///
+    /// // ... code to add router handlers to ...
+    ///
+    /// server.listen_on(([0, 0, 0, 0], 8080));
+    /// ```
+    pub fn listen_on<A: Into<SocketAddr>>(&mut self, addr: A) {
+        self.serve(vec![(addr.into(), Priority::Normal)], None);
+    }
 
-        // actually mounting the server
-        self.launch_with(&listener, controller_tx);
+    /// `listen_on_all` will bind to all the given socket addresses, e.g. an IPv4 and an IPv6 one,
+    /// and serve the connections from all of them with the same worker pool. This function will
+    /// block until the server is shut down.
+    pub fn listen_on_all(&mut self, addrs: Vec<SocketAddr>) {
+        self.serve(
+            addrs
+                .into_iter()
+                .map(|addr| (addr, Priority::Normal))
+                .collect(),
+            None,
+        );
+    }
 
-        // start to shut down the TcpListener
-        println!("Shutting down...");
+    /// `listen_on_prioritized` will bind to all the given socket addresses like `listen_on_all`,
+    /// and the connections from the `Priority::High` listeners, e.g. the admin or health-check
+    /// ones, are served by the workers reserved for them, see `ServerConfig::set_reserved_workers`,
+    /// such that they stay responsive while the other listeners are overwhelmed. The high priority
+    /// connections are also accepted ahead of the others. This function will block until the
+    /// server is shut down.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use rusty_express::prelude::*;
+    ///
+    /// let mut server = HttpServer::new();
+    /// server.config().set_reserved_workers(2);
+    ///
+    /// server.listen_on_prioritized(
+    ///     vec![
+    ///         (([0, 0, 0, 0], 8080).into(), Priority::Normal),
+    ///         (([127, 0, 0, 1], 9090).into(), Priority::High),
+    ///     ],
+    ///     None,
+    /// );
+    /// ```
+    pub fn listen_on_prioritized(
+        &mut self,
+        addrs: Vec<(SocketAddr, Priority)>,
+        callback: Option<fn(AsyncController)>,
+    ) {
+        self.serve(addrs, callback);
+    }
 
-        // now terminate the callback function as well.
-        if let Some(handler) = control_handler {
-            handler.join().unwrap_or_else(|err| {
-                debug::print(
-                    "Failed to shut down the callback handler, the service is teared down correctly",
-                    InfoLevel::Warning
-                );
-            });
-        }
+    /// Same as `listen_on_prioritized`, but return the error instead of panicking if the server
+    /// can't be launched.
+    pub fn try_listen_on_prioritized(
+        &mut self,
+        addrs: Vec<(SocketAddr, Priority)>,
+        callback: Option<fn(AsyncController)>,
+    ) -> Result<(), ServerError> {
+        self.try_serve(addrs, callback)
     }
 
     /// Obtain an `AsyncController`, which can be run in a parallel thread and control or update
@@ -149,6 +271,13 @@ impl HttpServer {
         self.state.get_courier_sender()
     }
 
+    /// Take a snapshot of the server stats, which can be called from anywhere, e.g. in a route
+    /// handler. The pool of the connection workers is only reported via an `AsyncController`, see
+    /// `AsyncController::query_stats`.
+    pub fn stats() -> ServerStats {
+        stats::snapshot(None)
+    }
+
     #[inline]
     /// Stop and clear the session auto-cleaning schedules. This API can be useful when no more new
     /// sessions shall be built and stored in the server, to save server resources.
@@ -176,141 +305,478 @@ impl HttpServer {
             .courier_deliver(ControlMessage::HotReloadConfig)
             .is_err()
         {
-            debug::print("Failed to hot reload the configuration", InfoLevel::Error);
+            rex_error!("Failed to hot reload the configuration");
+        }
+    }
+
+    fn serve(&mut self, addrs: Vec<(SocketAddr, Priority)>, callback: Option<fn(AsyncController)>) {
+        self.try_serve(addrs, callback)
+            .unwrap_or_else(|err| panic!("Unable to start the http server: {}...", err));
+    }
+
+    fn try_serve(
+        &mut self,
+        addrs: Vec<(SocketAddr, Priority)>,
+        callback: Option<fn(AsyncController)>,
+    ) -> Result<(), ServerError> {
+        if addrs.is_empty() {
+            return Err(ServerError::BindFailed(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            )));
+        }
+
+        if self.state.is_running() || shared_pool::is_initialized() {
+            return Err(ServerError::AlreadyRunning);
+        }
+
+        // initialize the debug service, which setup the debug level based on the environment variable
+        debug::initialize();
+
+        let acceptor = self
+            .config
+            .build_tls_acceptor()
+            .map_err(ServerError::TlsSetupFailed)?;
+
+        // create the listeners
+        let listeners = addrs
+            .iter()
+            .map(|(addr, priority)| TcpListener::bind(addr).map(|l| (l, *priority)))
+            .collect::<io::Result<Vec<(TcpListener, Priority)>>>()
+            .map_err(ServerError::BindFailed)?;
+
+        // the plain http listener that only redirects to the https one
+        let redirect = match self.config.get_https_redirect() {
+            Some((port, authority)) => {
+                let listener = TcpListener::bind(SocketAddr::new(addrs[0].0.ip(), port))
+                    .map_err(ServerError::BindFailed)?;
+
+                Some((listener, String::from(authority)))
+            }
+            None => None,
+        };
+
+        // the threads spawned from now on take the stack size, if one is set
+        shared_pool::set_stack_size(self.config.get_worker_stack_size().unwrap_or_default());
+        let prioritized = listeners.iter().any(|(_, p)| *p == Priority::High);
+        let workers_pool = self.setup_worker_pools(prioritized)?;
+
+        // obtain the control message courier service and start the callback
+        let (control_handler, controller_tx) = if let Some(cb) = callback {
+            let sender = self.state.get_courier_sender();
+            let (tx, rx) = channel::bounded(1);
+
+            let handler = thread::Builder::new()
+                .name(String::from("rex-control"))
+                .spawn(move || {
+                    // wait for server to launch before it's ready to take control messages.
+                    let _ = rx.recv();
+                    cb(sender);
+                })
+                .ok();
+
+            (handler, Some(tx))
+        } else {
+            (None, None)
+        };
+
+        // launch the service, now this will block until the server is shutdown
+        for (listener, priority) in listeners.iter() {
+            match (listener.local_addr(), priority) {
+                (Ok(addr), Priority::High) => {
+                    println!("Listening for connections on {} (high priority)", addr)
+                }
+                (Ok(addr), Priority::Normal) => println!("Listening for connections on {}", addr),
+                _ => {}
+            }
+        }
+
+        if let Some(Ok(addr)) = redirect.as_ref().map(|(listener, _)| listener.local_addr()) {
+            println!("Redirecting to https from {}", addr);
         }
+
+        // actually mounting the server
+        self.launch_with(&listeners, redirect, acceptor, workers_pool, controller_tx);
+
+        // start to shut down the TcpListener
+        println!("Shutting down...");
+
+        // now terminate the callback function as well.
+        if let Some(handler) = control_handler {
+            handler.join().unwrap_or_else(|err| {
+                rex_warn!("Failed to shut down the callback handler, the service is teared down correctly");
+            });
+        }
+
+        Ok(())
     }
 
-    fn launch_with(&mut self, listener: &TcpListener, mut cb_sig: Option<channel::Sender<()>>) {
+    fn launch_with(
+        &mut self,
+        listeners: &[(TcpListener, Priority)],
+        redirect: Option<(TcpListener, String)>,
+        mut acceptor: Option<Arc<TlsAcceptor>>,
+        mut workers_pool: ThreadPool,
+        mut cb_sig: Option<channel::Sender<()>>,
+    ) {
         // if using the session module and allow auto clean up, launch the service now.
         if cfg!(feature = "session") {
             self.session_cleanup_config();
         }
 
+        // launch the logging service if the log folder is set
+        #[cfg(feature = "logger")]
+        self.logger_config();
+
         // toggle states
         self.state.toggle_running_state(true);
+        let launched = Instant::now();
+        let (mut served, mut rejected, mut dropped) = (0usize, 0usize, 0usize);
 
         // initialize the shared object pools
         http::init_pools();
+        conn::init_pool();
+        stats::reset();
+
+        let mut params = self.config.load_conn_params();
+        let mut ip_filter = self.config.load_ip_filter();
+        self.config.load_cors();
 
-        let acceptor: Option<Arc<TlsAcceptor>> = self.config.build_tls_acceptor();
-        let (mut read_timeout, mut write_timeout, mut req_limit) = self.config.load_server_params();
+        #[cfg(feature = "metrics")]
+        self.config.load_metrics();
 
-        let mut workers_pool = self.setup_worker_pools();
         workers_pool.toggle_auto_expansion(true, None);
         workers_pool.set_timeout_policy(TimeoutPolicy::Run);
 
         // notify the server launcher that we're ready to serve incoming streams
         if let Some(sender) = cb_sig.take() {
             sender.send(()).unwrap_or_else(|err| {
-                debug::print(
-                    &format!(
-                        "Failed to notify the server launching callback function: {}",
-                        err
-                    )[..],
-                    InfoLevel::Warning,
+                rex_warn!(
+                    "Failed to notify the server launching callback function: {}",
+                    err
                 );
             });
         }
 
-        for stream in listener.incoming() {
-            if let Some(message) = self.state.fetch_update() {
-                match message {
-                    ControlMessage::Terminate => {
-                        if let Ok(s) = stream {
-                            conn::send_err_resp(Stream::Tcp(s), 503);
-                        }
+        // accept streams from all listeners, and serve them in this loop; the streams from the high
+        // priority listeners come in their own lane, which is always checked first
+        let stop = Arc::new(AtomicBool::new(false));
+        let (stream_tx, mut stream_rx) = channel::unbounded();
+        let (priority_tx, mut priority_rx) = channel::unbounded();
+        let mut acceptors: Vec<JoinHandle<()>> = listeners
+            .iter()
+            .filter_map(|(listener, priority)| {
+                let tx = match priority {
+                    Priority::High => priority_tx.clone(),
+                    Priority::Normal => stream_tx.clone(),
+                };
 
-                        break;
-                    }
-                    ControlMessage::HotReloadConfig => {
-                        if cfg!(feature = "session") {
-                            self.session_cleanup_config();
+                spawn_acceptor(listener, tx, stop.clone())
+            })
+            .collect();
+
+        // the redirect listener screens the peers with the same filter, which follows the reloads
+        let redirect_filter = Arc::new(RwLock::new(ip_filter.clone()));
+        if let Some((listener, authority)) = redirect.as_ref() {
+            acceptors.extend(spawn_redirector(
+                listener,
+                authority.clone(),
+                redirect_filter.clone(),
+                stop.clone(),
+            ));
+        }
+
+        drop(stream_tx);
+        drop(priority_tx);
+
+        // where the listeners are bound to, as answered to `QueryKind::LocalAddrs`
+        let local_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|(listener, _)| listener)
+            .chain(redirect.as_ref().map(|(listener, _)| listener))
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect();
+
+        // a lane is closed once all its acceptors have quit, or if it has none to begin with
+        let mut open_lanes = 2;
+
+        let mut drain_deadline: Option<Duration> = None;
+        let courier = self.state.get_courier_receiver();
+
+        loop {
+            // wake up on either a new connection or a control message, such that the messages are
+            // taken care of even if the server is idle
+            let wakeup = match priority_rx.try_recv() {
+                Ok(stream) => Wakeup::Stream(stream, Priority::High),
+                Err(_) => channel::select! {
+                    recv(priority_rx) -> stream => stream.map_or(
+                        Wakeup::Closed(Some(Priority::High)),
+                        |s| Wakeup::Stream(s, Priority::High),
+                    ),
+                    recv(stream_rx) -> stream => stream.map_or(
+                        Wakeup::Closed(Some(Priority::Normal)),
+                        |s| Wakeup::Stream(s, Priority::Normal),
+                    ),
+                    recv(courier) -> message => message.map_or(Wakeup::Closed(None), Wakeup::Control),
+                },
+            };
+
+            let (stream, priority) = match wakeup {
+                Wakeup::Stream(stream, priority) => (stream, priority),
+                Wakeup::Control(message) => {
+                    match message {
+                        ControlMessage::Terminate => break,
+                        ControlMessage::TerminateGracefully(deadline) => {
+                            drain_deadline = Some(deadline);
+                            break;
                         }
-                    }
-                    ControlMessage::HotLoadRouter(r) => {
-                        Route::use_router_async(r);
-                    }
-                    ControlMessage::HotLoadConfig(c) => {
-                        // check pool size param
-                        if c.get_pool_size() != self.config.get_pool_size() {
-                            debug::print(
-                                "Change size of the thread pool is not supported while the server is running",
-                                InfoLevel::Warning
-                            );
+                        ControlMessage::HotReloadConfig => {
+                            if cfg!(feature = "session") {
+                                self.session_cleanup_config();
+                            }
                         }
-
-                        // load the bulk params and decompose
-                        let params = c.load_server_params();
-                        read_timeout = params.0;
-                        write_timeout = params.1;
-                        req_limit = params.2;
-
-                        // update the config and reset the session clean effort
-                        self.config = c;
-
-                        if cfg!(feature = "session") {
-                            self.session_cleanup_config();
+                        ControlMessage::ReloadTls if acceptor.is_none() => {
+                            rex_warn!("Unable to reload TLS: the server is not serving over TLS");
+                        }
+                        ControlMessage::ReloadTls => match self.config.build_tls_acceptor() {
+                            // the connections in service keep the acceptor they're accepted with
+                            Ok(Some(a)) => acceptor = Some(a),
+                            Ok(None) => {
+                                rex_error!("Unable to reload TLS: no TLS identity is set, keep serving with the current one");
+                            }
+                            Err(e) => {
+                                rex_error!(
+                                    "Unable to reload TLS, keep serving with the current identity: {}",
+                                    e
+                                );
+                            }
+                        },
+                        ControlMessage::HotLoadRouter(r) => {
+                            Route::use_router_async(r);
+                        }
+                        ControlMessage::RemoveRoute(method, uri) => {
+                            if !Route::remove(method, uri) {
+                                rex_warn!("Unable to remove the route: it's not registered");
+                            }
+                        }
+                        ControlMessage::ReplaceRoute(method, uri, callback) => {
+                            if !Route::replace(method, uri, callback) {
+                                rex_warn!("Unable to replace the route: it's not registered");
+                            }
+                        }
+                        ControlMessage::HotLoadConfig(c) => {
+                            // check pool size param
+                            if c.get_pool_size() != self.config.get_pool_size() {
+                                rex_warn!("Change size of the thread pool is not supported while the server is running");
+                            }
+
+                            params = c.load_conn_params();
+                            ip_filter = c.load_ip_filter();
+                            *redirect_filter.write() = ip_filter.clone();
+                            c.load_cors();
+
+                            // update the config and reset the session clean effort
+                            self.config = c;
+
+                            if cfg!(feature = "session") {
+                                self.session_cleanup_config();
+                            }
+                        }
+                        ControlMessage::QueryStats(tx) => {
+                            tx.send(stats::snapshot(Some(workers_pool.stats())))
+                                .unwrap_or_default();
+                        }
+                        ControlMessage::Query(kind, tx) => {
+                            tx.send(self.answer(kind, launched, &local_addrs))
+                                .unwrap_or_default();
+                        }
+                        ControlMessage::Custom(content) => {
+                            println!("The message: {} is not yet supported.", content)
                         }
                     }
-                    ControlMessage::Custom(content) => {
-                        println!("The message: {} is not yet supported.", content)
+
+                    continue;
+                }
+                Wakeup::Closed(Some(priority)) if open_lanes > 1 => {
+                    // stop listening to the closed lane, and carry on with the other
+                    open_lanes -= 1;
+                    match priority {
+                        Priority::High => priority_rx = channel::never(),
+                        Priority::Normal => stream_rx = channel::never(),
                     }
+
+                    continue;
                 }
-            }
+                Wakeup::Closed(_) => break,
+            };
+
+            // screen the peer before anything is read from the connection
+            let stream = match (stream, ip_filter.as_ref()) {
+                (Ok(s), Some(filter)) => match filter.admit(s) {
+                    Some(s) => Ok(s),
+                    None => {
+                        rejected += 1;
+                        continue;
+                    }
+                },
+                (stream, _) => stream,
+            };
 
             match stream {
                 Ok(s) => {
-                    // set the timeout for this connection
-                    if read_timeout > 0 || write_timeout > 0 {
-                        s.set_timeout(read_timeout, write_timeout);
-                    }
-
                     // process the connection
-                    self.handle_stream(
-                        s,
-                        &mut workers_pool,
-                        acceptor.clone(),
-                        read_timeout,
-                        write_timeout,
-                        req_limit,
-                    );
+                    served += 1;
+                    stats::record_accept();
+                    self.handle_stream(s, priority, &mut workers_pool, acceptor.clone(), params);
                 }
-                Err(e) => debug::print(
-                    &format!("Failed to receive the upcoming stream: {}", e)[..],
-                    InfoLevel::Warning,
-                ),
+                Err(e) => rex_warn!("Failed to receive the upcoming stream: {}", e),
+            }
+        }
+
+        // stop accepting new streams from all listeners
+        stop.store(true, Ordering::Release);
+        for listener in listeners
+            .iter()
+            .map(|(listener, _)| listener)
+            .chain(redirect.as_ref().map(|(listener, _)| listener))
+        {
+            if let Ok(addr) = listener.local_addr() {
+                // wake up the acceptor such that it can quit
+                let _ = TcpStream::connect(reachable_addr(addr));
             }
         }
 
+        for acceptor in acceptors {
+            acceptor.join().unwrap_or_else(|err| {
+                rex_warn!("Failed to shut down the listener");
+            });
+        }
+
+        // the streams that have been accepted but not yet served
+        for stream in priority_rx.try_iter().chain(stream_rx.try_iter()) {
+            if let Ok(s) = stream {
+                conn::send_err_resp(Stream::Tcp(s), 503);
+                dropped += 1;
+            }
+        }
+
+        // the long connections won't end on their own, and their readers would block until the
+        // clients leave, so close them before waiting for the workers
+        let closed = ServerStates::close_long_conns();
+        if closed > 0 {
+            rex_debug!("Closed {} long connections for the shutdown", closed);
+        }
+
+        if let Some(deadline) = drain_deadline {
+            dropped += self.drain(listeners, &workers_pool, deadline);
+        }
+
         self.state.toggle_running_state(false);
-        self.cleanup();
+        self.cleanup(workers_pool);
+
+        // the logging service may be gone by now, then the summary goes to the console instead
+        rex_info!(
+            "Server shut down after {:?}: {} connections served, {} rejected, {} responses dropped",
+            launched.elapsed(),
+            served,
+            rejected,
+            dropped
+        );
+    }
+
+    /// Answer the query about the running server, which has been serving since `launched`.
+    fn answer(&self, kind: QueryKind, launched: Instant, local_addrs: &[SocketAddr]) -> QueryReply {
+        match kind {
+            QueryKind::RunningState => QueryReply::RunningState(self.state.is_running()),
+            QueryKind::EffectiveConfig => QueryReply::EffectiveConfig(self.config.snapshot()),
+            #[cfg(feature = "session")]
+            QueryKind::SessionCount => {
+                QueryReply::SessionCount(ExchangeConfig::store_size().unwrap_or_default())
+            }
+            QueryKind::Uptime => QueryReply::Uptime(launched.elapsed()),
+            QueryKind::LocalAddrs => QueryReply::LocalAddrs(local_addrs.to_vec()),
+        }
+    }
+
+    /// Reject the connections left in the listeners' backlog, and wait for the ones in service to
+    /// finish until the deadline. Return the number of the connections rejected or left unfinished.
+    fn drain(
+        &self,
+        listeners: &[(TcpListener, Priority)],
+        workers_pool: &ThreadPool,
+        deadline: Duration,
+    ) -> usize {
+        let start = Instant::now();
+        let mut dropped = 0;
+
+        // reject the connections that are still queued in the listeners' backlog
+        for (listener, _) in listeners.iter() {
+            if listener.set_nonblocking(true).is_err() {
+                continue;
+            }
+
+            while let Ok((s, _)) = listener.accept() {
+                if s.set_nonblocking(false).is_ok() {
+                    conn::send_err_resp(Stream::Tcp(s), 503);
+                    dropped += 1;
+                }
+            }
+        }
+
+        // wait for the connections in service, then the requests they've handed over
+        let drained = workers_pool.wait_idle(deadline)
+            && shared_pool::wait_idle(deadline.checked_sub(start.elapsed()).unwrap_or_default());
+
+        if !drained {
+            rex_warn!("Graceful shutdown deadline has passed, dropping the remaining requests");
+            dropped += workers_pool.pending_count();
+        }
+
+        dropped
     }
 
     fn handle_stream(
         &self,
         stream: TcpStream,
+        priority: Priority,
         workers_pool: &mut ThreadPool,
         acceptor: Option<Arc<TlsAcceptor>>,
-        read_timeout: u64,
-        write_timeout: u64,
-        req_limit: usize,
+        params: ConnParams,
     ) {
-        workers_pool.execute(move || {
+        // set the timeout for this connection
+        if params.read_timeout > 0 || params.write_timeout > 0 {
+            stream.set_timeout(params.read_timeout, params.write_timeout);
+        }
+
+        // the accept loop won't wait for the reserved workers to catch up, the connection is turned
+        // away instead, though a TLS client can't be told why before the handshake
+        if priority == Priority::High && workers_pool.is_backed_up(priority) {
+            rex_warn!("The workers reserved for the high priority listeners are all backed up");
+            match acceptor {
+                Some(_) => stream.shutdown(Shutdown::Both).unwrap_or_default(),
+                None => conn::send_err_resp(Stream::Tcp(stream), 503),
+            }
+
+            return;
+        }
+
+        let job = move || {
             if let Some(a) = acceptor {
+                let peer = stream.peer_addr().ok();
+
                 // handshake and encrypt
                 match a.accept(stream) {
                     Ok(s) => {
-                        Stream::Tls(Box::new(s)).process(true, req_limit);
+                        Stream::Tls(Box::new(s)).process(true, params.req_limit, params.limits);
                     }
-                    Err(e) => debug::print(
-                        &format!("Failed to receive the upcoming stream: {:?}", e)[..],
-                        InfoLevel::Error,
-                    ),
+                    Err(e) => handshake_failed(peer, e),
                 };
             } else {
-                Stream::Tcp(stream).process(false, req_limit);
+                Stream::Tcp(stream).process(false, params.req_limit, params.limits);
             }
-        });
+        };
+
+        workers_pool.execute_prioritized(job, priority);
     }
 
     #[cfg(feature = "session")]
@@ -325,25 +791,275 @@ impl HttpServer {
         }
     }
 
-    fn setup_worker_pools(&self) -> ThreadPool {
+    /// Launch the worker pools, with the workers reserved for the high priority listeners if the
+    /// server is `prioritized`.
+    fn setup_worker_pools(&self, prioritized: bool) -> Result<ThreadPool, ServerError> {
         let size = self.config.get_pool_size();
-        shared_pool::initialize_with(vec![size]);
-        ThreadPool::new(size)
+        let step = self.config.get_pool_expansion_step();
+        let idle_limit = self.config.get_pool_idle_limit();
+
+        shared_pool::initialize_with(vec![size]).map_err(ServerError::PoolInitFailed)?;
+        shared_pool::set_expansion_policy(step, idle_limit);
+
+        let mut pool = ThreadPool::new(size, "connection");
+        if pool.worker_count() == 0 {
+            shared_pool::close();
+            return Err(ServerError::PoolInitFailed(String::from(
+                "no worker of the connection pool is launched",
+            )));
+        }
+
+        pool.set_expansion_policy(step, idle_limit);
+
+        if prioritized {
+            pool.reserve(self.config.get_reserved_workers(), "priority");
+            shared_pool::reserve(self.config.get_reserved_workers());
+        }
+
+        Ok(pool)
     }
 
-    fn cleanup(&self) {
+    #[cfg(feature = "logger")]
+    fn logger_config(&self) {
+        let folder = match self.config.get_log_folder_path() {
+            Some(f) => f,
+            None => return,
+        };
+
+        if let Err(e) = fs::create_dir_all(folder) {
+            rex_error!("Unable to create the log folder {:?}: {}", folder, e);
+            return;
+        }
+
+        logger::start(DefaultLogWriter, None, folder.to_str(), None);
+    }
+
+    /// Shut down the services in order once the accept loop has quit: flush the queued log messages,
+    /// persist the sessions if a store file is set, then close the pools and drop the statics. Each
+    /// step has a bounded wait, such that a stuck component won't hang the shutdown.
+    fn cleanup(&self, workers_pool: ThreadPool) {
+        #[cfg(feature = "logger")]
+        {
+            if self.config.get_log_folder_path().is_some() {
+                bounded_step("logger", logger::shutdown);
+            }
+        }
+
+        if cfg!(feature = "session") {
+            if let Some(path) = self.config.get_session_store_path().cloned() {
+                bounded_step("session", move || Session::save_to_file(&path));
+            }
+        }
+
         // Must close the shared pool, since it's a static and won't drop with the end of the server,
         // which could cause response executions still on-the-fly to crash.
-        shared_pool::close();
+        let closed = bounded_step("pools", move || {
+            drop(workers_pool);
+            shared_pool::close()
+        });
 
-        // Clean up with static stores. Invoking the statics after this cleanup step will lead to
-        // undefined behaviors (not always panic), since we drop contents in-place and before the
-        // actual destructors are called on the statics
+        if closed != Some(true) {
+            // the workers still running may visit the statics, leak them instead.
+            return;
+        }
+
+        // Clean up with static stores. Invoking the statics after this cleanup step will panic, since
+        // their contents are taken out and dropped here, before the server can be launched again
         http::drop_statics();
+        conn::drop_pool();
         router::drop_statics();
     }
 }
 
+/// Report the failed TLS handshake: the noise from the scanners is only logged as info, while
+/// the clients that can't agree with us on the TLS parameters deserve a warning.
+fn handshake_failed(peer: Option<SocketAddr>, err: HandshakeError<TcpStream>) {
+    let (reason, detail) = match err {
+        HandshakeError::WouldBlock(_) => (TlsFailure::Timeout, String::from("timed out")),
+        HandshakeError::Failure(e) => {
+            let detail = e.to_string();
+            (classify_handshake(&detail), detail)
+        }
+    };
+
+    let peer_label = peer.map_or_else(|| String::from("unknown peer"), |addr| addr.to_string());
+    if reason.is_noise() {
+        rex_info!(
+            "TLS handshake with {} failed ({}): {}",
+            peer_label,
+            reason.label(),
+            detail
+        );
+    } else {
+        rex_warn!(
+            "TLS handshake with {} failed ({}): {}",
+            peer_label,
+            reason.label(),
+            detail
+        );
+    }
+
+    stats::record_conn_error(ConnError::TlsHandshake { peer, reason });
+}
+
+/// Tell the reason of the handshake failure from the error message, since the TLS backends don't
+/// expose the reasons otherwise.
+fn classify_handshake(detail: &str) -> TlsFailure {
+    let detail = detail.to_lowercase();
+    let has = |patterns: &[&str]| patterns.iter().any(|p| detail.contains(p));
+
+    if has(&[
+        "eof",
+        "connection reset",
+        "broken pipe",
+        "connection aborted",
+    ]) {
+        TlsFailure::Closed
+    } else if has(&[
+        "wrong version number",
+        "http request",
+        "unknown protocol",
+        "packet length too long",
+        "record layer failure",
+        "record_layer_failure",
+    ]) {
+        TlsFailure::NotTls
+    } else if has(&["timed out", "would block", "temporarily unavailable"]) {
+        TlsFailure::Timeout
+    } else {
+        TlsFailure::Negotiation
+    }
+}
+
+/// Run the shutdown step on its own thread, and stop waiting for it after `SHUTDOWN_STEP_TIMEOUT`.
+/// Return `None` if the step has failed or is still running.
+fn bounded_step<F, T>(name: &str, step: F) -> Option<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = channel::bounded(1);
+    let spawned = thread::Builder::new()
+        .name(format!("rex-shutdown-{}", name))
+        .spawn(move || {
+            tx.send(step()).unwrap_or_default();
+        });
+
+    if let Err(e) = spawned {
+        rex_warn!("Failed to run the shutdown step '{}': {}", name, e);
+        return None;
+    }
+
+    match rx.recv_timeout(SHUTDOWN_STEP_TIMEOUT) {
+        Ok(result) => Some(result),
+        Err(_) => {
+            rex_warn!(
+                "The shutdown step '{}' has failed or timed out, moving on to the next one",
+                name
+            );
+
+            None
+        }
+    }
+}
+
+fn spawn_acceptor(
+    listener: &TcpListener,
+    tx: channel::Sender<io::Result<TcpStream>>,
+    stop: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let listener = match listener.try_clone() {
+        Ok(l) => l,
+        Err(e) => {
+            rex_error!("Failed to start the listener: {}", e);
+            return None;
+        }
+    };
+
+    let name = match listener.local_addr() {
+        Ok(addr) => format!("rex-acceptor-{}", addr.port()),
+        Err(_) => String::from("rex-acceptor"),
+    };
+
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::Acquire) || tx.send(stream).is_err() {
+                    break;
+                }
+            }
+        })
+        .ok()
+}
+
+/// Answer every connection to the listener with a redirect to https, until the server stops. The
+/// peers are screened by the `ip_filter` first, as the ones to the server listeners are.
+fn spawn_redirector(
+    listener: &TcpListener,
+    authority: String,
+    ip_filter: Arc<RwLock<Option<Arc<IpFilter>>>>,
+    stop: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let listener = match listener.try_clone() {
+        Ok(l) => l,
+        Err(e) => {
+            rex_error!("Failed to start the https redirect: {}", e);
+            return None;
+        }
+    };
+
+    let name = match listener.local_addr() {
+        Ok(addr) => format!("rex-redirect-{}", addr.port()),
+        Err(_) => String::from("rex-redirect"),
+    };
+
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::Acquire) {
+                    break;
+                }
+
+                let filter = ip_filter.read().clone();
+                let stream = match (stream, filter) {
+                    (Ok(s), Some(filter)) => match filter.admit(s) {
+                        Some(s) => Ok(s),
+                        None => continue,
+                    },
+                    (stream, _) => stream,
+                };
+
+                match stream {
+                    Ok(s) => {
+                        let authority = authority.clone();
+                        shared_pool::run(
+                            move || conn::send_https_redirect(s, &authority),
+                            TaskType::Request,
+                        );
+                    }
+                    Err(e) => rex_warn!("Failed to receive the stream to redirect: {}", e),
+                }
+            }
+        })
+        .ok()
+}
+
+/// The address that can be connected to for reaching the listener, i.e. the loopback address if the
+/// listener is bound to all interfaces.
+fn reachable_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
+
 impl Default for HttpServer {
     fn default() -> Self {
         // reset the router with the new server instance
@@ -365,20 +1081,14 @@ impl TimeoutConfig for TcpStream {
         if read_timeout > 0 {
             self.set_read_timeout(Some(Duration::from_millis(read_timeout)))
                 .unwrap_or_else(|err| {
-                    debug::print(
-                        &format!("Unable to set read timeout: {}", err)[..],
-                        InfoLevel::Warning,
-                    );
+                    rex_warn!("Unable to set read timeout: {}", err);
                 });
         }
 
         if write_timeout > 0 {
             self.set_write_timeout(Some(Duration::from_millis(write_timeout)))
                 .unwrap_or_else(|err| {
-                    debug::print(
-                        &format!("Unable to set write timeout: {}", err)[..],
-                        InfoLevel::Warning,
-                    );
+                    rex_warn!("Unable to set write timeout: {}", err);
                 });
         }
     }
@@ -503,7 +1213,7 @@ impl Router for HttpServer {
 
     fn other(&mut self, method: &str, uri: RequestPath, callback: Callback) -> &mut dyn Router {
         Route::add_route(
-            REST::OTHER(method.to_uppercase()),
+            REST::parse(method),
             uri,
             RouteHandler::new(Some(callback), None),
         );
@@ -516,6 +1226,31 @@ impl Router for HttpServer {
         self
     }
 
+    fn other_ref(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: RefCallback,
+    ) -> &mut dyn Router {
+        Route::add_route(REST::parse(method), uri, RouteHandler::new_ref(callback));
+
+        self
+    }
+
+    fn with_options(
+        &mut self,
+        method: &str,
+        uri: RequestPath,
+        callback: Callback,
+        options: RouteOptions,
+    ) -> &mut dyn Router {
+        let mut handler = RouteHandler::new(Some(callback), None);
+        handler.set_options(options);
+        Route::add_route(REST::parse(method), uri, handler);
+
+        self
+    }
+
     /// Define a static folder location, where the request will be forwarded to and read the desired
     /// file as the response body.
     ///
@@ -535,7 +1270,8 @@ impl Router for HttpServer {
     }
 
     /// Define a customized static folder location, where the requested file will be served only if
-    /// it matches the URI rules defined by the API.
+    /// it matches the URI rules defined by the API. An explicit route also serves the files nested
+    /// under it, see `Router::use_custom_static`.
     ///
     /// # Example
     ///
@@ -553,6 +1289,32 @@ impl Router for HttpServer {
         self
     }
 
+    /// Define the static folder location like `use_static`, and only serve the files that pass the
+    /// `allow` and `deny` lists. Each entry can be an extension formatted as `*.<extension>`, e.g.
+    /// `*.map`, an absolute path to a folder, or an exact file name, e.g. `secrets.json`. If the
+    /// `allow` list is not empty, only files matching it will be served. The lists can be changed
+    /// later with `Route::set_static_filter`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rusty_express::prelude::*;
+    /// use std::path::PathBuf;
+    ///
+    /// let mut server = HttpServer::new();
+    /// server.use_static_filtered(PathBuf::from(r".\static"), &[], &["*.map", "secrets.json"]);
+    /// ```
+    fn use_static_filtered(
+        &mut self,
+        path: PathBuf,
+        allow: &[&str],
+        deny: &[&str],
+    ) -> &mut dyn Router {
+        Route::add_static(REST::GET, None, path);
+        Route::set_static_filter(REST::GET, allow, deny);
+        self
+    }
+
     /// This API will add the location or the extension that are allowed to be served to all the static
     /// routes. If a location is white-listed, you must provide a normalized and absolute path to the
     /// folder, and all files or sub-folders under the given path will be deemed as white-listed;
@@ -574,7 +1336,26 @@ impl Router for HttpServer {
     /// Note that if the `for_path` params are provided, the white list will only be applied to the
     /// given path (i.e. defined prior with the path to the static folder location).
     fn static_black_list(&mut self, loc_or_ext: String, for_path: Option<PathBuf>) {
-        Route::static_lists(loc_or_ext, true, for_path);
+        Route::static_lists(loc_or_ext, false, for_path);
+    }
+
+    /// Set how the static routes treat the path components beginning with a dot, e.g. `/.env` or
+    /// `/.well-known/acme-challenge/<token>`. By default, any such component gets a 404, and
+    /// `DotfilePolicy::AllowList` can open specific ones, like `.well-known` for ACME challenges.
+    ///
+    /// Note that if the `for_path` params are provided, the policy will only be applied to the
+    /// given path (i.e. defined prior with the path to the static folder location).
+    fn static_dotfiles(&mut self, policy: DotfilePolicy, for_path: Option<PathBuf>) {
+        Route::dotfile_policy(policy, for_path);
+    }
+
+    /// Set if the static routes shall follow the symlinks pointing outside of the static folder.
+    /// By default, a file is only served if its canonical path is still under the static folder.
+    ///
+    /// Note that if the `for_path` params are provided, the setting will only be applied to the
+    /// given path (i.e. defined prior with the path to the static folder location).
+    fn static_symlinks(&mut self, follow: bool, for_path: Option<PathBuf>) {
+        Route::follow_symlinks(follow, for_path);
     }
 
     /// Note: this API only affect routes moving forward, and it will not be applied to routes
@@ -589,6 +1370,22 @@ impl Router for HttpServer {
             Route::case_sensitive(&m, allow_case);
         }
     }
+
+    /// Set how the trailing slash and the letter case of the request path shall be treated when
+    /// matching routes. If `method` is `None`, the policy is applied to all methods.
+    fn normalization(&mut self, policy: RouteNormalization, method: Option<REST>) {
+        Route::set_normalization(policy, method);
+    }
+
+    fn fallback(&mut self, method: Option<REST>, callback: Callback) -> &mut dyn Router {
+        Route::set_fallback(method, callback);
+        self
+    }
+
+    fn with_views(&mut self, prefix: &str, scope: ViewsScope) -> &mut dyn Router {
+        Route::set_views(prefix, scope);
+        self
+    }
 }
 
 impl ViewEngineDefinition for HttpServer {
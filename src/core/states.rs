@@ -1,52 +1,160 @@
 #![allow(dead_code)]
 
-use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
-use crate::channel::{self, SendError, TryRecvError};
-use crate::core::{config::ServerConfig, router::Route};
-use crate::support::{
-    debug::{self, InfoLevel},
-    session::*,
+use crate::channel::{self, SendError, Sender};
+use crate::core::{
+    config::{ConfigSnapshot, ServerConfig},
+    router::{Callback, RequestPath, Route, REST},
+    stats::ServerStats,
+    stream::Stream,
 };
+use crate::hashbrown::HashMap;
+use crate::parking_lot::Mutex;
+use crate::support::session::*;
+
+static NEXT_LONG_CONN: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref LONG_CONNS: Mutex<HashMap<usize, LongConn>> = Mutex::new(HashMap::new());
+}
 
 pub enum ControlMessage {
     Terminate,
+    /// Stop accepting new connections, and wait for the in-flight requests to be served before
+    /// shutting down the server, or until the deadline has passed.
+    TerminateGracefully(Duration),
     HotReloadConfig,
     HotLoadRouter(Route),
     HotLoadConfig(ServerConfig),
+    /// Rebuild the TLS acceptor from the identity files set in the config, e.g. after the
+    /// certificates are renewed. The new connections are accepted with the new identity, while the
+    /// ones in service carry on with the old one. If the rebuild fails, the server keeps serving
+    /// with the old identity.
+    ReloadTls,
+    /// Remove a single route, see `Route::remove`.
+    RemoveRoute(REST, RequestPath<'static>),
+    /// Replace the handler of a single route, see `Route::replace`.
+    ReplaceRoute(REST, RequestPath<'static>, Callback),
+    /// Ask for a snapshot of the server stats, including the pool of the connection workers, which
+    /// is sent back through the channel. See `AsyncController::query_stats`.
+    QueryStats(mpsc::Sender<ServerStats>),
+    /// Ask the server about its state, and the reply is sent back through the channel. See
+    /// `AsyncController::query`.
+    Query(QueryKind, mpsc::Sender<QueryReply>),
     Custom(String),
 }
 
-pub struct AsyncController(channel::Sender<ControlMessage>, SocketAddr);
+/// What can be asked with `ControlMessage::Query`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    /// If the server is serving the connections.
+    RunningState,
+    /// The tunables of the config the server is running with, including the hot-loaded ones.
+    EffectiveConfig,
+    /// The number of the live sessions in the store.
+    #[cfg(feature = "session")]
+    SessionCount,
+    /// How long the server has been serving.
+    Uptime,
+    /// The addresses the listeners are bound to, in the order they're given to the server and
+    /// followed by the https redirect listener if any, e.g. to find out the port picked by the OS
+    /// when listening on port 0.
+    LocalAddrs,
+}
+
+/// The answer to the `QueryKind` of the same name.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryReply {
+    RunningState(bool),
+    EffectiveConfig(ConfigSnapshot),
+    #[cfg(feature = "session")]
+    SessionCount(usize),
+    Uptime(Duration),
+    LocalAddrs(Vec<SocketAddr>),
+}
+
+pub struct AsyncController(channel::Sender<ControlMessage>);
 
 impl AsyncController {
-    fn new(messenger: channel::Sender<ControlMessage>, addr: SocketAddr) -> Self {
-        AsyncController(messenger, addr)
+    fn new(messenger: channel::Sender<ControlMessage>) -> Self {
+        AsyncController(messenger)
     }
 
+    /// Send the message to the server, which picks it up right away, even if it's idle.
     pub fn send(&self, message: ControlMessage) -> Result<(), SendError<ControlMessage>> {
-        match message {
-            ControlMessage::Terminate => {
-                self.0.send(ControlMessage::Terminate)?;
-
-                // initiate a connection such that the terminal signal can be immediately picked up
-                if let Ok(client) = TcpStream::connect(self.1) {
-                    let _ = client.shutdown(Shutdown::Both);
-                }
-            }
-            other_msg => {
-                self.0.send(other_msg)?;
-            }
-        };
-
-        Ok(())
+        self.0.send(message)
+    }
+
+    /// Ask the server about its state, or `None` if the server doesn't answer within the timeout,
+    /// e.g. it has been shut down.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use rusty_express::prelude::*;
+    /// use std::time::Duration;
+    ///
+    /// let mut server = HttpServer::new();
+    /// server.listen_and_serve(8080, Some(|controller| {
+    ///     match controller.query(QueryKind::Uptime, Duration::from_secs(1)) {
+    ///         Some(QueryReply::Uptime(uptime)) => println!("Serving for {:?}", uptime),
+    ///         _ => println!("The server doesn't answer"),
+    ///     }
+    /// }));
+    /// ```
+    pub fn query(&self, kind: QueryKind, timeout: Duration) -> Option<QueryReply> {
+        let (tx, rx) = mpsc::channel();
+        self.send(ControlMessage::Query(kind, tx)).ok()?;
+        rx.recv_timeout(timeout).ok()
+    }
+
+    /// Take a snapshot of the server stats, or `None` if the server doesn't answer within the
+    /// timeout, e.g. it has been shut down.
+    pub fn query_stats(&self, timeout: Duration) -> Option<ServerStats> {
+        let (tx, rx) = mpsc::channel();
+        self.send(ControlMessage::QueryStats(tx)).ok()?;
+        rx.recv_timeout(timeout).ok()
     }
 }
 
 impl Clone for AsyncController {
     fn clone(&self) -> Self {
-        AsyncController(self.0.clone(), self.1)
+        AsyncController(self.0.clone())
+    }
+}
+
+/// A long connection in service: a clone of its stream, if the stream can be cloned, which isn't
+/// the case over TLS, and the notifier that ends its body.
+struct LongConn {
+    stream: Option<Stream>,
+    notifier: Option<Sender<String>>,
+}
+
+impl LongConn {
+    fn close(self) {
+        if let Some(mut stream) = self.stream {
+            stream.shutdown(Shutdown::Both).unwrap_or_default();
+        }
+
+        if let Some(notifier) = self.notifier {
+            // a full channel means the writer is busy, and it will fail on the closed stream
+            notifier.try_send(String::new()).unwrap_or_default();
+        }
+    }
+}
+
+/// The registration of a long connection, which is withdrawn once dropped, i.e. when the long
+/// connection ends on its own.
+pub(crate) struct LongConnToken(usize);
+
+impl Drop for LongConnToken {
+    fn drop(&mut self) {
+        LONG_CONNS.lock().remove(&self.0);
     }
 }
 
@@ -56,7 +164,6 @@ pub struct ServerStates {
         channel::Sender<ControlMessage>,
         channel::Receiver<ControlMessage>,
     ),
-    socket_addr: SocketAddr,
     session_auto_clean_handler: Option<JoinHandle<()>>,
 }
 
@@ -65,7 +172,6 @@ impl ServerStates {
         ServerStates {
             running: false,
             courier_channel: channel::bounded(1),
-            socket_addr: SocketAddr::from(([127, 0, 0, 1], 8080)),
             session_auto_clean_handler: None,
         }
     }
@@ -80,13 +186,16 @@ impl ServerStates {
         }
     }
 
-    pub(crate) fn set_port(&mut self, port: u16) {
-        self.socket_addr.set_port(port);
+    #[inline]
+    pub(crate) fn get_courier_sender(&self) -> AsyncController {
+        AsyncController::new(self.courier_channel.0.clone())
     }
 
+    /// The receiver of the control messages, which the accept loop listens to along with the
+    /// incoming connections.
     #[inline]
-    pub(crate) fn get_courier_sender(&self) -> AsyncController {
-        AsyncController::new(self.courier_channel.0.clone(), self.socket_addr)
+    pub(crate) fn get_courier_receiver(&self) -> channel::Receiver<ControlMessage> {
+        self.courier_channel.1.clone()
     }
 
     pub(crate) fn courier_deliver(
@@ -96,29 +205,81 @@ impl ServerStates {
         self.courier_channel.0.send(msg)
     }
 
-    #[inline]
-    pub(crate) fn fetch_update(&self) -> Option<ControlMessage> {
-        match self.courier_channel.1.try_recv() {
-            Ok(msg) => Some(msg),
-            Err(TryRecvError::Empty) => None,
-            Err(e) => {
-                debug::print(
-                    &format!("Hot load channel disconnected: {:?}", e),
-                    InfoLevel::Warning,
-                );
-
-                None
-            }
-        }
-    }
-
     #[inline]
     pub(crate) fn toggle_running_state(&mut self, running: bool) {
-        self.running = true;
+        self.running = running;
     }
 
     #[inline]
     pub(crate) fn is_running(&self) -> bool {
         self.running
     }
+
+    /// Register the long connection with the stream clone and the notifier of its body, such that
+    /// it can be closed when the server shuts down. Without a clone, the connection is ended by
+    /// the notifier alone. The registration lasts as long as the token.
+    pub(crate) fn register_long_conn(
+        stream: Option<Stream>,
+        notifier: Option<Sender<String>>,
+    ) -> LongConnToken {
+        let id = NEXT_LONG_CONN.fetch_add(1, Ordering::Relaxed);
+        LONG_CONNS.lock().insert(id, LongConn { stream, notifier });
+
+        LongConnToken(id)
+    }
+
+    /// Close all the long connections in service: the streams are shut down, such that the readers
+    /// blocked on them return right away, and the bodies are ended, such that the writers don't
+    /// wait for the next message. Return the number of the connections closed.
+    pub(crate) fn close_long_conns() -> usize {
+        let conns: Vec<LongConn> = LONG_CONNS.lock().drain().map(|(_, conn)| conn).collect();
+        let count = conns.len();
+
+        conns.into_iter().for_each(LongConn::close);
+        count
+    }
+}
+
+#[cfg(test)]
+mod states_test {
+    use super::{ServerStates, LONG_CONNS};
+    use crate::channel;
+    use crate::core::stream::{MockStream, Stream};
+
+    #[test]
+    fn long_conn_registry() {
+        // the other tests may be serving their own long connections, so only look at this one
+        let (tx, rx) = channel::bounded(1);
+        let token =
+            ServerStates::register_long_conn(Some(Stream::Mock(MockStream::new(0))), Some(tx));
+        let id = token.0;
+        assert!(LONG_CONNS.lock().contains_key(&id));
+
+        // the connection ended on its own
+        drop(token);
+        assert!(!LONG_CONNS.lock().contains_key(&id));
+        assert!(rx.try_recv().is_err());
+
+        // the one in service is ended by the shutdown, while closing all of them would break the
+        // other tests, so this one is closed alone
+        let (tx, rx) = channel::bounded(1);
+        let token =
+            ServerStates::register_long_conn(Some(Stream::Mock(MockStream::new(0))), Some(tx));
+        let conn = LONG_CONNS.lock().remove(&token.0).unwrap();
+
+        conn.close();
+        assert_eq!(rx.try_recv(), Ok(String::new()));
+
+        // and the token of the closed one is harmless
+        drop(token);
+
+        // a TLS stream can't be cloned, and the connection is ended by its notifier alone
+        let (tx, rx) = channel::bounded(1);
+        let token = ServerStates::register_long_conn(None, Some(tx));
+        let conn = LONG_CONNS.lock().remove(&token.0).unwrap();
+
+        conn.close();
+        assert_eq!(rx.try_recv(), Ok(String::new()));
+        drop(token);
+    }
 }
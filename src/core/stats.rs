@@ -0,0 +1,314 @@
+//! The `stats` module keeps the server-wide counters, which are bumped on the hot paths of the
+//! connections and only aggregated when a snapshot is taken, either with `HttpServer::stats` from
+//! anywhere, e.g. a route handler, or with `ControlMessage::QueryStats` from an `AsyncController`,
+//! which also reports the pool of the connection workers. The connection errors can also be
+//! observed as they happen with the hook set via `ServerConfig::on_conn_error`.
+//!
+//! ```no_run
+//! use rusty_express::prelude::*;
+//!
+//! let mut server = HttpServer::new();
+//! server.get(RequestPath::Explicit("/metrics"), metrics);
+//! server.listen(8080);
+//!
+//! pub fn metrics(_req: &Box<Request>, resp: &mut Box<Response>) {
+//!     let stats = HttpServer::stats();
+//!
+//!     resp.set_content_type("text/plain");
+//!     resp.send(&format!(
+//!         "connections_accepted {}\nrequests_parsed {}\nresponses_5xx {}\nunder_pressure {}\n",
+//!         stats.accepted,
+//!         stats.requests,
+//!         stats.responses_5xx,
+//!         stats.is_under_pressure(),
+//!     ));
+//! }
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::core::http;
+use crate::parking_lot::RwLock;
+use crate::support::{shared_pool, PoolStats, TaskType};
+
+static ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static RESPONSES: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static TLS_FAILURES: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+lazy_static! {
+    static ref CONN_ERROR_HOOK: RwLock<Option<fn(ConnError)>> = RwLock::new(None);
+}
+
+/// Why a TLS handshake has failed, as far as it can be told from the error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsFailure {
+    /// The peer closed the connection without a handshake, e.g. a port scanner.
+    Closed,
+    /// The peer didn't speak TLS, e.g. a plain http request to the https port.
+    NotTls,
+    /// The handshake didn't complete within the read timeout.
+    Timeout,
+    /// The peer spoke TLS, but the two sides couldn't agree, e.g. on the protocol version.
+    Negotiation,
+}
+
+impl TlsFailure {
+    /// The label of the reason, e.g. in the metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            TlsFailure::Closed => "closed",
+            TlsFailure::NotTls => "not_tls",
+            TlsFailure::Timeout => "timeout",
+            TlsFailure::Negotiation => "negotiation",
+        }
+    }
+
+    /// If the failure is likely the noise from the scanners rather than a client that's unable to
+    /// connect.
+    pub fn is_noise(self) -> bool {
+        self != TlsFailure::Negotiation
+    }
+
+    const ALL: [TlsFailure; 4] = [
+        TlsFailure::Closed,
+        TlsFailure::NotTls,
+        TlsFailure::Timeout,
+        TlsFailure::Negotiation,
+    ];
+}
+
+/// The connections that have failed before any request could be served from them. More kinds of
+/// the failures may be reported in the future, so the matches shall have the catch-all arm.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum ConnError {
+    TlsHandshake {
+        peer: Option<SocketAddr>,
+        reason: TlsFailure,
+    },
+}
+
+/// The occupancy of a pool of the reusable objects: how many are ready to be handed out, out of
+/// the number it can hold, along with how well it has kept up with the demand.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct StoreStats {
+    pub available: usize,
+    pub capacity: usize,
+    /// The objects requested from the pool.
+    pub gets: usize,
+    /// The requested objects allocated afresh, since the pool had none to hand out.
+    pub get_misses: usize,
+    /// The returned objects dropped, since the pool had no room to take them.
+    pub put_drops: usize,
+}
+
+impl StoreStats {
+    /// The share of the requested objects served from the pool, or 1 if none has been requested.
+    pub fn hit_rate(&self) -> f64 {
+        if self.gets == 0 {
+            return 1.0;
+        }
+
+        (self.gets - self.get_misses.min(self.gets)) as f64 / self.gets as f64
+    }
+}
+
+/// The snapshot of the server counters, which are counted since the server is launched. A pool is
+/// `None` if it's not running at the moment the snapshot is taken.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ServerStats {
+    /// The connections handed to the workers, i.e. not counting the ones rejected by the IP filter.
+    pub accepted: usize,
+    /// The requests parsed from the connections, including the malformed ones.
+    pub requests: usize,
+    pub responses_2xx: usize,
+    pub responses_3xx: usize,
+    pub responses_4xx: usize,
+    pub responses_5xx: usize,
+    /// The connections failed in the TLS handshake, of any reason.
+    pub tls_handshake_failures: usize,
+    /// The pool serving the connections, only reported via `ControlMessage::QueryStats`.
+    pub connection_pool: Option<PoolStats>,
+    pub request_pool: Option<PoolStats>,
+    pub response_pool: Option<PoolStats>,
+    pub parser_pool: Option<PoolStats>,
+    pub stream_pool: Option<PoolStats>,
+    /// The pooled `Request` objects.
+    pub request_store: StoreStats,
+    /// The pooled `Response` objects.
+    pub response_store: StoreStats,
+}
+
+impl ServerStats {
+    /// If any of the worker pools is under pressure.
+    pub fn is_under_pressure(&self) -> bool {
+        [
+            self.connection_pool,
+            self.request_pool,
+            self.response_pool,
+            self.parser_pool,
+            self.stream_pool,
+        ]
+        .iter()
+        .any(|pool| pool.map_or(false, |p| p.under_pressure))
+    }
+}
+
+pub(crate) fn snapshot(connection_pool: Option<PoolStats>) -> ServerStats {
+    let (request_store, response_store) = http::store_stats();
+
+    ServerStats {
+        accepted: ACCEPTED.load(Ordering::Relaxed),
+        requests: REQUESTS.load(Ordering::Relaxed),
+        responses_2xx: RESPONSES[0].load(Ordering::Relaxed),
+        responses_3xx: RESPONSES[1].load(Ordering::Relaxed),
+        responses_4xx: RESPONSES[2].load(Ordering::Relaxed),
+        responses_5xx: RESPONSES[3].load(Ordering::Relaxed),
+        tls_handshake_failures: tls_failures().iter().map(|(_, count)| count).sum(),
+        connection_pool,
+        request_pool: shared_pool::stats(TaskType::Request),
+        response_pool: shared_pool::stats(TaskType::Response),
+        parser_pool: shared_pool::stats(TaskType::Parser),
+        stream_pool: shared_pool::stats(TaskType::StreamLoader),
+        request_store,
+        response_store,
+    }
+}
+
+/// Start over the counters, for a newly launched server.
+pub(crate) fn reset() {
+    ACCEPTED.store(0, Ordering::Relaxed);
+    REQUESTS.store(0, Ordering::Relaxed);
+    RESPONSES
+        .iter()
+        .chain(TLS_FAILURES.iter())
+        .for_each(|count| count.store(0, Ordering::Relaxed));
+}
+
+#[inline]
+pub(crate) fn record_accept() {
+    ACCEPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub(crate) fn record_request() {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count the final response by the class of its status, the interim ones are not counted.
+#[inline]
+pub(crate) fn record_response(status: u16) {
+    if let Some(class) = status_class(status) {
+        RESPONSES[class].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The TLS handshake failures counted for each reason.
+pub(crate) fn tls_failures() -> [(TlsFailure, usize); 4] {
+    let mut counts = [(TlsFailure::Closed, 0); 4];
+
+    for (slot, reason) in counts.iter_mut().zip(TlsFailure::ALL.iter()) {
+        *slot = (
+            *reason,
+            TLS_FAILURES[*reason as usize].load(Ordering::Relaxed),
+        );
+    }
+
+    counts
+}
+
+pub(crate) fn set_conn_error_hook(hook: Option<fn(ConnError)>) {
+    *CONN_ERROR_HOOK.write() = hook;
+}
+
+/// Count the connection error, and pass it on to the hook if one is set.
+pub(crate) fn record_conn_error(err: ConnError) {
+    match err {
+        ConnError::TlsHandshake { reason, .. } => {
+            TLS_FAILURES[reason as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if let Some(hook) = *CONN_ERROR_HOOK.read() {
+        hook(err);
+    }
+}
+
+/// The index of the counter for the status, i.e. 0 for `2xx` through 3 for `5xx`.
+fn status_class(status: u16) -> Option<usize> {
+    match status {
+        200..=599 => Some((status / 100 - 2) as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod stats_test {
+    use super::{status_class, ServerStats, StoreStats};
+    use crate::support::PoolStats;
+
+    #[test]
+    fn status_classes() {
+        let classes: Vec<Option<usize>> = [100, 101, 200, 204, 301, 404, 418, 500, 599, 600]
+            .iter()
+            .map(|status| status_class(*status))
+            .collect();
+
+        assert_eq!(
+            classes,
+            vec![
+                None,
+                None,
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(2),
+                Some(3),
+                Some(3),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn store_hit_rate() {
+        assert_eq!(StoreStats::default().hit_rate(), 1.0);
+
+        let store = StoreStats {
+            gets: 8,
+            get_misses: 2,
+            ..Default::default()
+        };
+        assert_eq!(store.hit_rate(), 0.75);
+    }
+
+    #[test]
+    fn pools_under_pressure() {
+        let mut stats = ServerStats::default();
+        assert!(!stats.is_under_pressure());
+
+        stats.request_pool = Some(PoolStats::default());
+        stats.connection_pool = Some(PoolStats::default());
+        assert!(!stats.is_under_pressure());
+
+        // any pool under pressure will do, including the one of the connections
+        stats.connection_pool = Some(PoolStats {
+            under_pressure: true,
+            ..Default::default()
+        });
+        assert!(stats.is_under_pressure());
+    }
+}
@@ -9,6 +9,71 @@ use crate::native_tls::TlsStream;
 pub(crate) enum Stream {
     Tcp(TcpStream),
     Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(test)]
+    Mock(MockStream),
+}
+
+/// An in-memory stream for the tests: everything written lands in the shared `wire`, and `writes`
+/// counts the write calls. Reads take from the `inbound` trunks, each read at most one trunk, and
+/// see an end of stream once they're exhausted, while `read_bytes` counts the bytes read. The flush
+/// numbered `fail_flush_at` (counting from 1) fails once. The clones share all but the flushes.
+#[cfg(test)]
+pub(crate) struct MockStream {
+    pub(crate) wire: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    pub(crate) writes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    pub(crate) inbound: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<Vec<u8>>>>,
+    pub(crate) read_bytes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    pub(crate) fail_flush_at: usize,
+    flushes: usize,
+}
+
+#[cfg(test)]
+impl MockStream {
+    pub(crate) fn new(fail_flush_at: usize) -> Self {
+        MockStream {
+            wire: Default::default(),
+            writes: Default::default(),
+            inbound: Default::default(),
+            read_bytes: Default::default(),
+            fail_flush_at,
+            flushes: 0,
+        }
+    }
+
+    /// Queue a trunk of bytes to be read.
+    pub(crate) fn feed(&self, trunk: &[u8]) {
+        self.inbound.lock().unwrap().push_back(trunk.to_vec());
+    }
+
+    fn try_clone(&self) -> Self {
+        MockStream {
+            wire: std::sync::Arc::clone(&self.wire),
+            writes: std::sync::Arc::clone(&self.writes),
+            inbound: std::sync::Arc::clone(&self.inbound),
+            read_bytes: std::sync::Arc::clone(&self.read_bytes),
+            fail_flush_at: self.fail_flush_at,
+            flushes: 0,
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inbound = self.inbound.lock().unwrap();
+        let mut trunk = match inbound.pop_front() {
+            Some(trunk) => trunk,
+            None => return Ok(0),
+        };
+
+        let len = trunk.len().min(buf.len());
+        buf[..len].copy_from_slice(&trunk[..len]);
+
+        if len < trunk.len() {
+            inbound.push_front(trunk.split_off(len));
+        }
+
+        self.read_bytes
+            .fetch_add(len, std::sync::atomic::Ordering::SeqCst);
+        Ok(len)
+    }
 }
 
 impl Stream {
@@ -16,6 +81,8 @@ impl Stream {
         match self {
             Stream::Tcp(tcp) => tcp.shutdown(how),
             Stream::Tls(ref mut tls) => tls.shutdown(),
+            #[cfg(test)]
+            Stream::Mock(_) => Ok(()),
         }
     }
 
@@ -23,6 +90,17 @@ impl Stream {
         match self {
             Stream::Tcp(tcp) => tcp.set_read_timeout(dur),
             Stream::Tls(tls) => tls.get_ref().set_read_timeout(dur),
+            #[cfg(test)]
+            Stream::Mock(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read_timeout(),
+            Stream::Tls(tls) => tls.get_ref().read_timeout(),
+            #[cfg(test)]
+            Stream::Mock(_) => Ok(None),
         }
     }
 
@@ -30,6 +108,8 @@ impl Stream {
         match self {
             Stream::Tcp(tcp) => tcp.set_write_timeout(dur),
             Stream::Tls(tls) => tls.get_ref().set_write_timeout(dur),
+            #[cfg(test)]
+            Stream::Mock(_) => Ok(()),
         }
     }
 
@@ -37,6 +117,8 @@ impl Stream {
         match self {
             Stream::Tcp(tcp) => tcp.take_error(),
             Stream::Tls(tls) => tls.get_ref().take_error(),
+            #[cfg(test)]
+            Stream::Mock(_) => Ok(None),
         }
     }
 
@@ -44,6 +126,8 @@ impl Stream {
         match self {
             Stream::Tcp(tcp) => tcp.peer_addr(),
             Stream::Tls(tls) => tls.get_ref().peer_addr(),
+            #[cfg(test)]
+            Stream::Mock(_) => Err(Error::new(ErrorKind::NotConnected, "mock stream")),
         }
     }
 
@@ -51,6 +135,8 @@ impl Stream {
     pub(crate) fn try_clone(&self) -> io::Result<Stream> {
         match self {
             Stream::Tcp(tcp) => tcp.try_clone().map(Stream::Tcp),
+            #[cfg(test)]
+            Stream::Mock(mock) => Ok(Stream::Mock(mock.try_clone())),
             _ => Err(Error::new(
                 ErrorKind::InvalidInput,
                 "TLS connection shouldn't be kept long-live",
@@ -71,6 +157,8 @@ impl Read for Stream {
         match self {
             Stream::Tcp(tcp) => tcp.read(buf),
             Stream::Tls(tls) => tls.read(buf),
+            #[cfg(test)]
+            Stream::Mock(mock) => mock.read(buf),
         }
     }
 }
@@ -80,6 +168,13 @@ impl Write for Stream {
         match self {
             Stream::Tcp(tcp) => tcp.write(buf),
             Stream::Tls(tls) => tls.write(buf),
+            #[cfg(test)]
+            Stream::Mock(mock) => {
+                mock.wire.lock().unwrap().extend_from_slice(buf);
+                mock.writes
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Ok(buf.len())
+            }
         }
     }
 
@@ -87,6 +182,16 @@ impl Write for Stream {
         match self {
             Stream::Tcp(tcp) => tcp.flush(),
             Stream::Tls(tls) => tls.flush(),
+            #[cfg(test)]
+            Stream::Mock(mock) => {
+                mock.flushes += 1;
+
+                if mock.flushes == mock.fail_flush_at {
+                    return Err(Error::new(ErrorKind::BrokenPipe, "injected flush failure"));
+                }
+
+                Ok(())
+            }
         }
     }
 }
@@ -6,6 +6,7 @@ use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::support::common::cpu_relax;
 
@@ -16,6 +17,17 @@ const PUT_MASK: u16 = 0b1111_1111_1111_1111;
 
 pub(crate) const TOTAL_ELEM_COUNT: usize = POOL_SIZE * SLOT_CAP;
 
+/// The misses in one adaptation window that make the pool grow, i.e. a bucket worth of values
+/// made on the fly, or dropped.
+const MISS_THRESHOLD: usize = SLOT_CAP;
+
+/// How long an adaptation window lasts, after which the misses too sparse to make the pool grow
+/// are forgotten, rather than adding up over the lifetime of the pool.
+const MISS_WINDOW: Duration = Duration::from_secs(10);
+
+/// The most buckets a pool can grow to by adapting to the misses.
+const MAX_POOL_SIZE: usize = 8 * POOL_SIZE;
+
 struct Slot<T> {
     /// the actual data store
     slot: [Option<T>; SLOT_CAP],
@@ -146,7 +158,7 @@ impl<T: Default> Bucket<T> {
         // done
         Bucket {
             slot: slice,
-            len: AtomicUsize::new(SLOT_CAP),
+            len: AtomicUsize::new(if fill { SLOT_CAP } else { 0 }),
             bitmap: AtomicU16::new(bitmap),
         }
     }
@@ -287,10 +299,11 @@ impl<T: Default> Bucket<T> {
 impl<T> Drop for Bucket<T> {
     fn drop(&mut self) {
         for item in self.slot.iter_mut() {
-            unsafe {
-                ptr::drop_in_place(*item);
+            // the slot is empty if the value has been checked out and not yet returned
+            let val = mem::replace(item, ptr::null_mut());
+            if !val.is_null() {
+                drop(unsafe { Box::from_raw(val) });
             }
-            *item = ptr::null_mut();
         }
     }
 }
@@ -340,8 +353,30 @@ pub(crate) struct SyncPool<T> {
     ///   true  -> write barrier has been raised
     ///   false -> no write barrier
     visitor_counter: (AtomicUsize, AtomicBool),
+
+    /// The values handed out, the ones made on the fly since the pool can't provide them, and the
+    /// returned ones dropped since the pool can't take them back.
+    counters: (AtomicUsize, AtomicUsize, AtomicUsize),
+
+    /// The misses, and the drops, already accounted for by the last adaptation.
+    window: (AtomicUsize, AtomicUsize),
+
+    /// When the current adaptation window is opened.
+    window_opened: Instant,
+}
+
+/// The counters of a pool since it's created.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) struct MissStats {
+    pub(crate) gets: usize,
+    pub(crate) get_misses: usize,
+    pub(crate) put_drops: usize,
 }
 
+// The pool owns the values behind the pointers in its slots, so it can be moved to wherever the
+// values can, e.g. into a static behind a lock.
+unsafe impl<T: Send> Send for SyncPool<T> {}
+
 impl<T: Default> SyncPool<T> {
     pub fn new() -> Self {
         Self::make_pool(POOL_SIZE)
@@ -357,11 +392,14 @@ impl<T: Default> SyncPool<T> {
     }
 
     pub fn get(&mut self) -> Box<T> {
+        self.counters.0.fetch_add(1, Ordering::Relaxed);
+
         // update user count
         let guard = VisitorGuard::register(&self.visitor_counter, true);
 
         // if the pool itself is being operated on, no need to wait, just create the object on the fly.
         if guard.is_none() {
+            self.counters.1.fetch_add(1, Ordering::Relaxed);
             return Default::default();
         }
 
@@ -412,6 +450,7 @@ impl<T: Default> SyncPool<T> {
         // make sure our guard has been returned if we want the correct visitor count
         drop(guard);
 
+        self.counters.1.fetch_add(1, Ordering::Relaxed);
         Default::default()
     }
 
@@ -462,6 +501,9 @@ impl<T: Default> SyncPool<T> {
                 break;
             }
         }
+
+        // the value is dropped here
+        self.counters.2.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn len(&self) -> usize {
@@ -470,7 +512,54 @@ impl<T: Default> SyncPool<T> {
             .fold(0, |sum, item| sum + item.size_hint())
     }
 
+    /// The number of values the pool can hold, including the ones checked out.
+    pub fn capacity(&self) -> usize {
+        self.slots.len() * SLOT_CAP
+    }
+
+    /// The counters of the values handed out, made on the fly, and dropped since the pool is created.
+    pub fn miss_stats(&self) -> MissStats {
+        MissStats {
+            gets: self.counters.0.load(Ordering::Relaxed),
+            get_misses: self.counters.1.load(Ordering::Relaxed),
+            put_drops: self.counters.2.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Grow the pool if it has missed too often within the adaptation window: a filled bucket for
+    /// every bucket worth of values made on the fly, and an empty one for every bucket worth of
+    /// values dropped. Meant to be called periodically from a single maintenance thread, and it
+    /// returns if the pool has grown.
+    pub fn adapt(&mut self) -> bool {
+        let stats = self.miss_stats();
+        let misses = stats.get_misses - self.window.0.load(Ordering::Relaxed);
+        let drops = stats.put_drops - self.window.1.load(Ordering::Relaxed);
+
+        let grown = misses + drops >= MISS_THRESHOLD && {
+            let room = MAX_POOL_SIZE.saturating_sub(self.slots.len());
+            let filled = misses.div_ceil(SLOT_CAP).min(POOL_SIZE).min(room);
+            let empty = drops.div_ceil(SLOT_CAP).min(POOL_SIZE).min(room - filled);
+
+            // can't grow anymore, or the pool is too busy, the misses will count until the window
+            // is over
+            filled + empty > 0 && self.grow(filled, empty, false)
+        };
+
+        // a new window starts once the pool has grown, or the current one is over
+        if grown || self.window_opened.elapsed() >= MISS_WINDOW {
+            self.window.0.store(stats.get_misses, Ordering::Relaxed);
+            self.window.1.store(stats.put_drops, Ordering::Relaxed);
+            self.window_opened = Instant::now();
+        }
+
+        grown
+    }
+
     pub fn expand(&mut self, additional: usize, block: bool) -> bool {
+        self.grow(additional, 0, block)
+    }
+
+    fn grow(&mut self, filled: usize, empty: usize, block: bool) -> bool {
         // raise the write barrier now, if someone has already raised the flag to indicate the
         // intention to write, let me go away.
         if self
@@ -503,10 +592,14 @@ impl<T: Default> SyncPool<T> {
         };
 
         if safe {
-            // update the slots by pushing `additional` slots
-            (0..additional).for_each(|_| {
+            // update the slots by pushing the filled slots, then the empty ones
+            (0..filled).for_each(|_| {
                 self.slots.push(Bucket::new(true));
             });
+
+            (0..empty).for_each(|_| {
+                self.slots.push(Bucket::new(false));
+            });
         }
 
         // update the internal states
@@ -535,6 +628,13 @@ impl<T: Default> SyncPool<T> {
             slots: s,
             curr: (AtomicUsize::new(0), AtomicUsize::new(0)),
             visitor_counter: (AtomicUsize::new(1), AtomicBool::new(false)),
+            counters: (
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+            ),
+            window: (AtomicUsize::new(0), AtomicUsize::new(0)),
+            window_opened: Instant::now(),
         }
     }
 }
@@ -545,7 +645,7 @@ impl<T> Drop for SyncPool<T> {
     }
 }
 
-//TODO: support growth and auto-retraction
+//TODO: support auto-retraction
 
 pub(crate) trait Reusable {
     fn obtain() -> Box<Self>;
@@ -606,3 +706,112 @@ impl<T> Drop for StaticStore<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod syncstore_test {
+    use super::{MissStats, SyncPool, MAX_POOL_SIZE, MISS_THRESHOLD, MISS_WINDOW, SLOT_CAP};
+    use std::thread;
+
+    /// The pool shared by the hammering threads, the same way the statics are.
+    #[derive(Clone, Copy)]
+    struct Shared(*mut SyncPool<Vec<u8>>);
+
+    unsafe impl Send for Shared {}
+
+    impl Shared {
+        fn pool(self) -> &'static mut SyncPool<Vec<u8>> {
+            unsafe { &mut *self.0 }
+        }
+    }
+
+    /// Every thread holds a handful of values at a time, more than the pool can hold altogether.
+    fn hammer(pool: Shared) -> MissStats {
+        let before = pool.pool().miss_stats();
+
+        let workers: Vec<thread::JoinHandle<()>> = (0..8)
+            .map(|_| {
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        let held: Vec<Box<Vec<u8>>> = (0..16).map(|_| pool.pool().get()).collect();
+                        held.into_iter().for_each(|val| pool.pool().put(val));
+                    }
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .for_each(|worker| worker.join().unwrap());
+
+        let after = pool.pool().miss_stats();
+        MissStats {
+            gets: after.gets - before.gets,
+            get_misses: after.get_misses - before.get_misses,
+            put_drops: after.put_drops - before.put_drops,
+        }
+    }
+
+    fn hit_rate(stats: MissStats) -> f64 {
+        (stats.gets - stats.get_misses) as f64 / stats.gets as f64
+    }
+
+    #[test]
+    fn adapt_to_misses() {
+        let mut pool: SyncPool<Vec<u8>> = SyncPool::with_size(SLOT_CAP);
+        assert!(!pool.adapt());
+
+        let shared = Shared(&mut pool);
+
+        let cold = hammer(shared);
+        assert_eq!(cold.gets, 8 * 2000 * 16);
+        assert!(cold.get_misses > 0 && cold.put_drops > 0, "{:?}", cold);
+
+        // one adaptation per window, until the pool stops missing
+        let mut rounds = 0;
+        while pool.adapt() {
+            rounds += 1;
+            hammer(shared);
+        }
+
+        assert!(rounds > 0);
+        assert!(pool.capacity() > SLOT_CAP);
+        assert!(pool.capacity() <= MAX_POOL_SIZE * SLOT_CAP);
+
+        let warm = hammer(shared);
+        assert!(
+            hit_rate(warm) > hit_rate(cold) + 0.3,
+            "cold: {:?}, warm: {:?}",
+            cold,
+            warm
+        );
+    }
+
+    #[test]
+    fn miss_window_expires() {
+        let mut pool: SyncPool<Vec<u8>> = SyncPool::with_size(SLOT_CAP);
+        let mut held = Vec::new();
+
+        // keep the values out of the pool, such that the gets miss
+        let mut miss_until = |pool: &mut SyncPool<Vec<u8>>, misses: usize| {
+            while pool.miss_stats().get_misses < misses {
+                held.push(pool.get());
+            }
+        };
+
+        miss_until(&mut pool, MISS_THRESHOLD - 1);
+        assert!(!pool.adapt());
+
+        // the sparse misses are forgotten once the window is over
+        pool.window_opened -= MISS_WINDOW;
+        assert!(!pool.adapt());
+
+        miss_until(&mut pool, 2 * (MISS_THRESHOLD - 1));
+        assert!(!pool.adapt());
+
+        // while the ones adding up within the window make the pool grow
+        let capacity = pool.capacity();
+        miss_until(&mut pool, 2 * MISS_THRESHOLD - 1);
+        assert!(pool.adapt());
+        assert!(pool.capacity() > capacity);
+    }
+}
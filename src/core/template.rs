@@ -0,0 +1,351 @@
+//! The `template` module is a minimal view engine for the simple pages, which fills the
+//! `{{path}}` tags with the values from the rendering context, and repeats the block between
+//! `{{#each path}}` and `{{/each}}` for each item of the list at the path. Within the block, the
+//! paths are looked up in the item first, and `{{this}}` is the item itself. The values are HTML
+//! escaped, while the missing ones are rendered as empty.
+//!
+//! If the context names a layout, see `EngineContext::layout`, the rendered page is placed into
+//! the layout template at its `{{> body}}` tag, unescaped. The layout is looked up next to the
+//! page, with the page's extension if the name has none, and it's rendered with the same context.
+//!
+//! ```no_run
+//! use rusty_express::prelude::*;
+//!
+//! let mut server = HttpServer::new();
+//! ServerConfig::view_engine("tpl", template::render);
+//! server.listen(8080);
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use crate::core::config::{ContextValue, EngineContext};
+
+const EACH_OPEN: &str = "#each ";
+const EACH_CLOSE: &str = "/each";
+const BODY_SLOT: &str = "> body";
+
+/// Render the template in place, which returns 200 on success, or 500 if the template is malformed,
+/// e.g. a tag is not closed. The signature is the one of a `ViewEngine`.
+pub fn render(
+    content: &mut String,
+    path: &Path,
+    context: Box<dyn EngineContext + Send + Sync>,
+) -> u16 {
+    match render_page(content, path, context.as_ref()) {
+        Ok(page) => {
+            *content = page;
+            200
+        }
+        Err(e) => {
+            rex_warn!("Unable to render the template: {}", e);
+            500
+        }
+    }
+}
+
+fn render_page(content: &str, path: &Path, context: &dyn EngineContext) -> Result<String, String> {
+    let mut page = String::with_capacity(content.len());
+    render_block(content, &[], context, None, &mut page)?;
+
+    let name = match context.layout() {
+        Some(name) => name,
+        None => return Ok(page),
+    };
+
+    let mut layout_path = path.with_file_name(&name);
+    if layout_path.extension().is_none() {
+        if let Some(ext) = path.extension() {
+            layout_path.set_extension(ext);
+        }
+    }
+
+    let layout = fs::read_to_string(&layout_path)
+        .map_err(|e| format!("unable to read the layout `{}`: {}", name, e))?;
+
+    let mut out = String::with_capacity(layout.len() + page.len());
+    render_block(&layout, &[], context, Some(&page), &mut out)?;
+    Ok(out)
+}
+
+fn render_block(
+    src: &str,
+    scopes: &[&ContextValue],
+    root: &dyn EngineContext,
+    slot: Option<&str>,
+    out: &mut String,
+) -> Result<(), String> {
+    let mut rest = src;
+
+    while let Some(open) = rest.find("{{") {
+        out.push_str(&rest[..open]);
+
+        let close = rest[open..]
+            .find("}}")
+            .map(|pos| open + pos)
+            .ok_or_else(|| String::from("a tag is not closed"))?;
+
+        let tag = rest[open + 2..close].trim();
+        rest = &rest[close + 2..];
+
+        if let Some(name) = tag.strip_prefix(EACH_OPEN) {
+            let (body, after) = split_each(rest)?;
+
+            if let Some(ContextValue::List(items)) = resolve(name.trim(), scopes, root) {
+                for item in items.iter() {
+                    let mut inner = scopes.to_vec();
+                    inner.push(item);
+                    render_block(body, &inner, root, slot, out)?;
+                }
+            }
+
+            rest = after;
+        } else if tag == EACH_CLOSE {
+            return Err(String::from("`{{/each}}` without the `{{#each}}`"));
+        } else if tag == BODY_SLOT {
+            // the page is rendered already
+            out.push_str(slot.unwrap_or_default());
+        } else if let Some(text) = resolve(tag, scopes, root).and_then(|v| v.as_text()) {
+            escape_into(&text, out);
+        }
+    }
+
+    out.push_str(rest);
+    Ok(())
+}
+
+/// Split the source after the `{{#each}}` tag into the block and the source after its matching
+/// `{{/each}}`, with the nested blocks kept in the block.
+fn split_each(src: &str) -> Result<(&str, &str), String> {
+    let mut depth = 1;
+    let mut pos = 0;
+
+    while let Some(open) = src[pos..].find("{{") {
+        let open = pos + open;
+        let close = src[open..]
+            .find("}}")
+            .map(|p| open + p)
+            .ok_or_else(|| String::from("a tag is not closed"))?;
+
+        let tag = src[open + 2..close].trim();
+        if tag.starts_with(EACH_OPEN) {
+            depth += 1;
+        } else if tag == EACH_CLOSE {
+            depth -= 1;
+            if depth == 0 {
+                return Ok((&src[..open], &src[close + 2..]));
+            }
+        }
+
+        pos = close + 2;
+    }
+
+    Err(String::from("`{{#each}}` without the `{{/each}}`"))
+}
+
+/// Find the value of the path in the innermost item that has it, then in the rendering context.
+fn resolve(path: &str, scopes: &[&ContextValue], root: &dyn EngineContext) -> Option<ContextValue> {
+    if let Some(item) = scopes.last() {
+        if path == "this" {
+            return Some((*item).clone());
+        }
+
+        if let Some(field) = path.strip_prefix("this.") {
+            return item.lookup(field).cloned();
+        }
+    }
+
+    scopes
+        .iter()
+        .rev()
+        .find_map(|scope| scope.lookup(path))
+        .cloned()
+        .or_else(|| root.get_value(path))
+}
+
+fn escape_into(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod template_test {
+    use super::render;
+    use crate::core::config::{ContextValue, EngineContext};
+    use crate::hashbrown::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    fn map(pairs: Vec<(&str, ContextValue)>) -> ContextValue {
+        ContextValue::Map(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect::<HashMap<_, _>>(),
+        )
+    }
+
+    fn text(s: &str) -> ContextValue {
+        ContextValue::Str(s.to_owned())
+    }
+
+    fn run(template: &str, context: Box<dyn EngineContext + Send + Sync>) -> (u16, String) {
+        let mut content = template.to_owned();
+        let status = render(&mut content, Path::new("page.tpl"), context);
+        (status, content)
+    }
+
+    #[test]
+    fn nested_values_and_lists() {
+        let context = map(vec![
+            ("user", map(vec![("name", text("Ada & co"))])),
+            (
+                "items",
+                ContextValue::List(vec![
+                    map(vec![
+                        ("title", text("tea")),
+                        ("qty", ContextValue::Num(2.0)),
+                    ]),
+                    map(vec![
+                        ("title", text("<b>cake</b>")),
+                        ("qty", ContextValue::Num(1.5)),
+                    ]),
+                ]),
+            ),
+            (
+                "tags",
+                ContextValue::List(vec![text("a"), ContextValue::Bool(true)]),
+            ),
+        ]);
+
+        assert_eq!(
+            context.get_value("items.1.qty"),
+            Some(ContextValue::Num(1.5))
+        );
+        assert_eq!(context.get_value("items.2.qty"), None);
+        assert_eq!(context.display("user.name"), Ok(String::from("Ada & co")));
+
+        let (status, page) = run(
+            "<p>{{ user.name }}</p><ul>{{#each items}}<li>{{title}} x{{qty}} by {{user.name}}</li>{{/each}}</ul>\
+             {{#each tags}}[{{this}}]{{/each}}{{items.0.title}}{{missing}}",
+            Box::new(context),
+        );
+
+        assert_eq!(status, 200);
+        assert_eq!(
+            page,
+            "<p>Ada &amp; co</p><ul><li>tea x2 by Ada &amp; co</li>\
+             <li>&lt;b&gt;cake&lt;/b&gt; x1.5 by Ada &amp; co</li></ul>[a][true]tea"
+        );
+    }
+
+    #[test]
+    fn nested_each() {
+        let context = map(vec![(
+            "rows",
+            ContextValue::List(vec![
+                map(vec![(
+                    "cells",
+                    ContextValue::List(vec![text("1"), text("2")]),
+                )]),
+                map(vec![("cells", ContextValue::List(vec![text("3")]))]),
+            ]),
+        )]);
+
+        let (status, page) = run(
+            "{{#each rows}}<tr>{{#each cells}}<td>{{this}}</td>{{/each}}</tr>{{/each}}",
+            Box::new(context),
+        );
+
+        assert_eq!(status, 200);
+        assert_eq!(page, "<tr><td>1</td><td>2</td></tr><tr><td>3</td></tr>");
+    }
+
+    struct Flat;
+
+    impl EngineContext for Flat {
+        fn display(&self, field: &str) -> Result<String, String> {
+            match field {
+                "msg" => Ok(String::from("A Secret Message!")),
+                _ => Err(format!("Unknown field: {}", field)),
+            }
+        }
+    }
+
+    #[test]
+    fn flat_context_and_malformed() {
+        // the contexts only implementing `display` still work
+        assert_eq!(
+            run("<p>{{msg}}</p>{{#each msg}}x{{/each}}", Box::new(Flat)),
+            (200, String::from("<p>A Secret Message!</p>"))
+        );
+
+        assert_eq!(run("{{msg", Box::new(Flat)).0, 500);
+        assert_eq!(run("{{#each msg}}x", Box::new(Flat)).0, 500);
+        assert_eq!(run("x{{/each}}", Box::new(Flat)).0, 500);
+    }
+
+    struct Framed(Option<&'static str>);
+
+    impl EngineContext for Framed {
+        fn display(&self, field: &str) -> Result<String, String> {
+            match field {
+                "title" => Ok(String::from("<Home>")),
+                _ => Err(format!("Unknown field: {}", field)),
+            }
+        }
+
+        fn layout(&self) -> Option<String> {
+            self.0.map(String::from)
+        }
+    }
+
+    #[test]
+    fn page_in_layout() {
+        let dir = env::temp_dir().join(format!("rex-layout-{}", std::process::id()));
+        fs::create_dir_all(dir.join("layouts")).unwrap();
+        fs::write(
+            dir.join("layouts").join("main.tpl"),
+            "<h1>{{title}}</h1>{{#each none}}{{/each}}<main>{{> body}}</main>",
+        )
+        .unwrap();
+        fs::write(dir.join("plain.html"), "[{{> body}}]").unwrap();
+
+        let page = dir.join("page.tpl");
+        let render_in = |context: Framed| {
+            let mut content = String::from("<p>{{title}}</p>");
+            let status = render(&mut content, &page, Box::new(context));
+            (status, content)
+        };
+
+        let framed = render_in(Framed(Some("layouts/main")));
+        let explicit = render_in(Framed(Some("plain.html")));
+        let missing = render_in(Framed(Some("layouts/none")));
+        let bare = render_in(Framed(None));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the page is escaped once, when it's rendered, then placed into the layout as is
+        assert_eq!(
+            framed,
+            (
+                200,
+                String::from("<h1>&lt;Home&gt;</h1><main><p>&lt;Home&gt;</p></main>")
+            )
+        );
+        assert_eq!(explicit, (200, String::from("[<p>&lt;Home&gt;</p>]")));
+        assert_eq!(missing.0, 500);
+
+        // without the layout, the page stands on its own
+        assert_eq!(bare, (200, String::from("<p>&lt;Home&gt;</p>")));
+    }
+}
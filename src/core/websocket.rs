@@ -0,0 +1,706 @@
+//! The optional `websocket` module upgrades the http connection to a websocket (RFC 6455). Make
+//! sure to include the `websocket` feature in your Cargo's dependency build list to use it.
+//!
+//! The route handler accepts the upgrade with `Response::accept_websocket`, and once the handshake
+//! response is sent, the connection is handed to the given handler as a `WsConnection`, and is
+//! closed when the handler returns:
+//!
+//! ```
+//! use rusty_express::prelude::*;
+//!
+//! pub fn chat(req: &Box<Request>, resp: &mut Box<Response>) {
+//!     if req.is_websocket_upgrade() {
+//!         resp.accept_websocket(echo);
+//!     } else {
+//!         resp.status(400);
+//!     }
+//! }
+//!
+//! fn echo(mut conn: WsConnection) {
+//!     while let Ok(message) = conn.recv() {
+//!         match message {
+//!             WsMessage::Text(_) | WsMessage::Binary(_) => {
+//!                 if conn.send(message).is_err() {
+//!                     break;
+//!                 }
+//!             }
+//!             WsMessage::Close(_) => break,
+//!             _ => {}
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::io::{self, prelude::*, ErrorKind};
+use std::str;
+
+use crate::core::stream::Stream;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const DEFAULT_MAX_MESSAGE: usize = 1024 * 1024;
+const READ_SIZE: usize = 4096;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The source of the incoming bytes of the connection, which yields an empty read once the client
+/// has closed the connection.
+pub(crate) type WsReader = Box<dyn FnMut() -> io::Result<Vec<u8>> + Send>;
+
+/// The message received from, or sent to the client. The control messages, i.e. `Ping`, `Pong` and
+/// `Close`, carry at most 125 bytes of payload.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+/// The pending upgrade set by the route handler, which is carried by the response until the
+/// handshake response is sent.
+pub(crate) struct WsUpgrade {
+    pub(crate) handler: fn(WsConnection),
+    pub(crate) accept: String,
+    reader: Option<WsReader>,
+    buffered: Vec<u8>,
+}
+
+impl WsUpgrade {
+    pub(crate) fn new(handler: fn(WsConnection)) -> Self {
+        WsUpgrade {
+            handler,
+            accept: String::new(),
+            reader: None,
+            buffered: Vec::new(),
+        }
+    }
+
+    /// Feed the connection from the reader instead of the stream, and the bytes received after the
+    /// upgrade request, if any, come first.
+    pub(crate) fn set_reader(&mut self, reader: WsReader, buffered: Vec<u8>) {
+        self.reader = Some(reader);
+        self.buffered = buffered;
+    }
+
+    /// Run the handler with the connection, which will block until the handler returns.
+    pub(crate) fn serve(self, stream: Stream) {
+        let conn = WsConnection {
+            stream,
+            reader: self.reader,
+            buf: self.buffered,
+            partial: None,
+            max_message: DEFAULT_MAX_MESSAGE,
+            close_sent: false,
+            closed: false,
+        };
+
+        (self.handler)(conn);
+    }
+}
+
+/// The websocket connection handed to the handler after the upgrade. Messages are received with
+/// `recv` and sent with `send`; fragmented messages are reassembled, pings are answered with pongs,
+/// and the close handshake is answered automatically. Protocol violations and messages larger than
+/// the limit will close the connection with the corresponding status code.
+pub struct WsConnection {
+    stream: Stream,
+    reader: Option<WsReader>,
+    buf: Vec<u8>,
+    partial: Option<(u8, Vec<u8>)>,
+    max_message: usize,
+    close_sent: bool,
+    closed: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WsConnection {
+    /// Set the max size of a message, whether it's sent in a single frame or in fragments. The
+    /// default size is 1MB, and a size of 0 will be ignored.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        if size > 0 {
+            self.max_message = size;
+        }
+    }
+
+    /// If the connection is closed, i.e. the close handshake is done or the connection is lost.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Receive the next message from the client, which will block until a complete message has
+    /// arrived. The control messages are also returned to the caller, after they've been handled.
+    pub fn recv(&mut self) -> io::Result<WsMessage> {
+        if self.closed {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "The websocket is closed",
+            ));
+        }
+
+        loop {
+            let frame = self.read_frame()?;
+
+            match frame.opcode {
+                OP_CONTINUATION => {
+                    let (opcode, mut data) = match self.partial.take() {
+                        Some(partial) => partial,
+                        None => {
+                            return Err(
+                                self.fail(CLOSE_PROTOCOL_ERROR, "Unexpected continuation frame")
+                            )
+                        }
+                    };
+
+                    if data.len() + frame.payload.len() > self.max_message {
+                        return Err(self.fail(CLOSE_TOO_BIG, "The message is too big"));
+                    }
+
+                    data.extend_from_slice(&frame.payload);
+
+                    if frame.fin {
+                        return self.to_message(opcode, data);
+                    }
+
+                    self.partial = Some((opcode, data));
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.partial.is_some() {
+                        return Err(
+                            self.fail(CLOSE_PROTOCOL_ERROR, "Unfinished fragmented message")
+                        );
+                    }
+
+                    if frame.fin {
+                        return self.to_message(frame.opcode, frame.payload);
+                    }
+
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                OP_PING => {
+                    self.write_frame(OP_PONG, &frame.payload)?;
+                    return Ok(WsMessage::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(WsMessage::Pong(frame.payload)),
+                OP_CLOSE => return self.close_received(frame.payload),
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Unknown opcode")),
+            }
+        }
+    }
+
+    /// Send the message to the client. Sending a `Close` message starts the close handshake, and no
+    /// more messages can be sent afterwards, though the client's reply can still be received.
+    pub fn send(&mut self, message: WsMessage) -> io::Result<()> {
+        if self.close_sent || self.closed {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "The websocket is closing",
+            ));
+        }
+
+        match message {
+            WsMessage::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            WsMessage::Binary(data) => self.write_frame(OP_BINARY, &data),
+            WsMessage::Ping(data) => self.write_control(OP_PING, &data),
+            WsMessage::Pong(data) => self.write_control(OP_PONG, &data),
+            WsMessage::Close(status) => {
+                let payload = close_payload(status);
+                self.write_control(OP_CLOSE, &payload)?;
+                self.close_sent = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Start the close handshake with the status code and the reason.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.send(WsMessage::Close(Some((code, reason.to_owned()))))
+    }
+
+    fn to_message(&mut self, opcode: u8, data: Vec<u8>) -> io::Result<WsMessage> {
+        if opcode == OP_BINARY {
+            return Ok(WsMessage::Binary(data));
+        }
+
+        match String::from_utf8(data) {
+            Ok(text) => Ok(WsMessage::Text(text)),
+            Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "Invalid UTF-8 text")),
+        }
+    }
+
+    fn close_received(&mut self, payload: Vec<u8>) -> io::Result<WsMessage> {
+        let status = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Invalid close frame")),
+            _ => {
+                let code = u16::from(payload[0]) << 8 | u16::from(payload[1]);
+                if !valid_close_code(code) {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Invalid close code"));
+                }
+
+                match str::from_utf8(&payload[2..]) {
+                    Ok(reason) => Some((code, reason.to_owned())),
+                    Err(_) => return Err(self.fail(CLOSE_INVALID_DATA, "Invalid close reason")),
+                }
+            }
+        };
+
+        // reply the close frame with the same code, and we're done
+        if !self.close_sent {
+            let code = status.as_ref().map(|(code, _)| *code);
+            self.write_control(OP_CLOSE, &close_payload(code.map(|c| (c, String::new()))))
+                .unwrap_or_default();
+
+            self.close_sent = true;
+        }
+
+        self.closed = true;
+        Ok(WsMessage::Close(status))
+    }
+
+    /// Close the connection for the protocol error, then report it to the caller.
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        if !self.close_sent {
+            self.write_control(OP_CLOSE, &close_payload(Some((code, reason.to_owned()))))
+                .unwrap_or_default();
+
+            self.close_sent = true;
+        }
+
+        self.closed = true;
+        io::Error::new(ErrorKind::InvalidData, reason)
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        self.fill(2)?;
+
+        let fin = self.buf[0] & 0x80 != 0;
+        let rsv = self.buf[0] & 0x70;
+        let opcode = self.buf[0] & 0x0F;
+        let masked = self.buf[1] & 0x80 != 0;
+        let mut len = u64::from(self.buf[1] & 0x7F);
+        let mut offset = 2;
+
+        if rsv != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Reserved bits are set"));
+        }
+
+        // frames from the client must be masked
+        if !masked {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "The frame is not masked"));
+        }
+
+        if len == 126 {
+            self.fill(4)?;
+            len = u64::from(self.buf[2]) << 8 | u64::from(self.buf[3]);
+            offset = 4;
+        } else if len == 127 {
+            self.fill(10)?;
+            len = self.buf[2..10]
+                .iter()
+                .fold(0u64, |acc, b| acc << 8 | u64::from(*b));
+            offset = 10;
+        }
+
+        // control frames can't be fragmented, and carry 125 bytes at most
+        if opcode & 0x08 != 0 && (!fin || len > 125) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Invalid control frame"));
+        }
+
+        if len > self.max_message as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "The frame is too big"));
+        }
+
+        let len = len as usize;
+        self.fill(offset + 4 + len)?;
+
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&self.buf[offset..offset + 4]);
+
+        let start = offset + 4;
+        let mut payload: Vec<u8> = self.buf.drain(..start + len).skip(start).collect();
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Read until at least `size` bytes are buffered.
+    fn fill(&mut self, size: usize) -> io::Result<()> {
+        while self.buf.len() < size {
+            let read = match self.reader.as_mut() {
+                Some(reader) => reader()?,
+                None => {
+                    let mut chunk = vec![0u8; READ_SIZE];
+                    let len = self.stream.read(&mut chunk)?;
+                    chunk.truncate(len);
+                    chunk
+                }
+            };
+
+            if read.is_empty() {
+                self.closed = true;
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "The websocket connection is lost",
+                ));
+            }
+
+            if self.buf.is_empty() {
+                self.buf = read;
+            } else {
+                self.buf.extend_from_slice(&read);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_control(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > 125 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Control frames carry 125 bytes at most",
+            ));
+        }
+
+        self.write_frame(opcode, payload)
+    }
+
+    /// Write the message in a single frame; frames from the server are not masked.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut head = Vec::with_capacity(10);
+        head.push(0x80 | opcode);
+
+        match payload.len() {
+            len if len < 126 => head.push(len as u8),
+            len if len <= 0xFFFF => {
+                head.push(126);
+                head.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                head.push(127);
+                head.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        self.stream.write_all(&head)?;
+        self.stream.write_all(payload)?;
+        self.stream.flush()
+    }
+}
+
+impl Drop for WsConnection {
+    fn drop(&mut self) {
+        // the handler is done with the connection, say goodbye if it hasn't
+        if !self.close_sent && !self.closed {
+            let payload = close_payload(Some((CLOSE_NORMAL, String::new())));
+            self.write_control(OP_CLOSE, &payload).unwrap_or_default();
+        }
+    }
+}
+
+fn close_payload(status: Option<(u16, String)>) -> Vec<u8> {
+    match status {
+        Some((code, reason)) => {
+            let mut payload = Vec::with_capacity(2 + reason.len());
+            payload.extend_from_slice(&code.to_be_bytes());
+
+            // the close frame carries 125 bytes at most, and the reason may be cut
+            let mut end = reason.len().min(123);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+            payload
+        }
+        None => Vec::new(),
+    }
+}
+
+fn valid_close_code(code: u16) -> bool {
+    match code {
+        1000..=1003 | 1007..=1011 | 3000..=4999 => true,
+        _ => false,
+    }
+}
+
+/// Compute the `Sec-WebSocket-Accept` value from the `Sec-WebSocket-Key` of the request.
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut source = String::with_capacity(key.len() + ACCEPT_GUID.len());
+    source.push_str(key.trim());
+    source.push_str(ACCEPT_GUID);
+
+    base64(&sha1(source.as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+
+    // pad the message to a multiple of 64 bytes, with the bit length at the end
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                block[4 * i],
+                block[4 * i + 1],
+                block[4 * i + 2],
+                block[4 * i + 3],
+            ]);
+        }
+
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+
+        result.push(TABLE[(n >> 18) as usize & 0x3F] as char);
+        result.push(TABLE[(n >> 12) as usize & 0x3F] as char);
+        result.push(if chunk.len() > 1 {
+            TABLE[(n >> 6) as usize & 0x3F] as char
+        } else {
+            '='
+        });
+        result.push(if chunk.len() > 2 {
+            TABLE[n as usize & 0x3F] as char
+        } else {
+            '='
+        });
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod websocket_test {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Frame the payload as the client would, i.e. masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37u8, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Connect a client to the websocket connection, which receives the given bytes from the client.
+    fn connect(wire: Vec<u8>) -> (TcpStream, WsConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+
+        let mut fed = false;
+        let reader: WsReader = Box::new(move || {
+            // everything comes in the 1st read, then the client is gone
+            if fed {
+                return Ok(Vec::new());
+            }
+
+            fed = true;
+            Ok(wire.clone())
+        });
+
+        let conn = WsConnection {
+            stream: server,
+            reader: Some(reader),
+            buf: Vec::new(),
+            partial: None,
+            max_message: 64,
+            close_sent: false,
+            closed: false,
+        };
+
+        (client, conn)
+    }
+
+    fn read_server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+
+        let mut payload = vec![0u8; (head[1] & 0x7F) as usize];
+        client.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // the sample handshake from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_and_fragments() {
+        let mut wire = client_frame(true, OP_TEXT, b"hello");
+        wire.extend(client_frame(false, OP_BINARY, &[1, 2]));
+        wire.extend(client_frame(true, OP_PING, b"beat"));
+        wire.extend(client_frame(false, OP_CONTINUATION, &[3]));
+        wire.extend(client_frame(true, OP_CONTINUATION, &[4, 5]));
+        wire.extend(client_frame(
+            true,
+            OP_CLOSE,
+            &[0x03, 0xE8, b'b', b'y', b'e'],
+        ));
+
+        let (mut client, mut conn) = connect(wire);
+
+        assert_eq!(conn.recv().unwrap(), WsMessage::Text(String::from("hello")));
+        assert_eq!(conn.recv().unwrap(), WsMessage::Ping(b"beat".to_vec()));
+        assert_eq!(read_server_frame(&mut client), (OP_PONG, b"beat".to_vec()));
+        assert_eq!(conn.recv().unwrap(), WsMessage::Binary(vec![1, 2, 3, 4, 5]));
+
+        conn.send(WsMessage::Text(String::from("world"))).unwrap();
+        assert_eq!(read_server_frame(&mut client), (OP_TEXT, b"world".to_vec()));
+
+        // the close handshake is answered with the same code
+        assert_eq!(
+            conn.recv().unwrap(),
+            WsMessage::Close(Some((1000, String::from("bye"))))
+        );
+        assert_eq!(read_server_frame(&mut client), (OP_CLOSE, vec![0x03, 0xE8]));
+        assert!(conn.is_closed());
+        assert!(conn.recv().is_err());
+        assert!(conn.send(WsMessage::Text(String::from("late"))).is_err());
+    }
+
+    #[test]
+    fn protocol_violations() {
+        let cases: Vec<(Vec<u8>, u16)> = vec![
+            // not masked
+            (vec![0x81, 0x02, b'h', b'i'], CLOSE_PROTOCOL_ERROR),
+            // fragmented control frame
+            (client_frame(false, OP_PING, b"x"), CLOSE_PROTOCOL_ERROR),
+            // continuation without a start
+            (
+                client_frame(true, OP_CONTINUATION, b"x"),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            // invalid utf-8 text
+            (
+                client_frame(true, OP_TEXT, &[0xff, 0xfe]),
+                CLOSE_INVALID_DATA,
+            ),
+            // larger than the limit of 64 bytes
+            (client_frame(true, OP_BINARY, &[0u8; 200]), CLOSE_TOO_BIG),
+        ];
+
+        for (wire, code) in cases {
+            let (mut client, mut conn) = connect(wire);
+
+            assert!(conn.recv().is_err());
+            assert!(conn.is_closed());
+
+            let (opcode, payload) = read_server_frame(&mut client);
+            assert_eq!(opcode, OP_CLOSE);
+            assert_eq!(u16::from(payload[0]) << 8 | u16::from(payload[1]), code);
+        }
+
+        // the reassembled message can't exceed the limit either
+        let mut wire = client_frame(false, OP_TEXT, &[b'a'; 40]);
+        wire.extend(client_frame(true, OP_CONTINUATION, &[b'a'; 40]));
+
+        let (mut client, mut conn) = connect(wire);
+        assert!(conn.recv().is_err());
+        assert_eq!(read_server_frame(&mut client).1[..2], [0x03, 0xF1]);
+    }
+}
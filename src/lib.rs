@@ -15,6 +15,21 @@
 //!    resp.status(200);
 //! }
 //! ```
+//!
+//! Handlers taking the plain references to the request and the response are registered via the
+//! `*_ref` methods of the router:
+//! ```no_run
+//! use rusty_express::prelude::*;
+//!
+//! let mut server = HttpServer::new();
+//! server.get_ref(RequestPath::Explicit("/"), simple_response);
+//! server.listen(8080);
+//!
+//! pub fn simple_response(req: &Request, resp: &mut Response) {
+//!    resp.send(&format!("Hello world from rusty server from path: {}", req.uri));
+//!    resp.status(200);
+//! }
+//! ```
 
 #![allow(unused_variables)]
 #[macro_use]
@@ -24,32 +39,61 @@ extern crate hashbrown;
 extern crate native_tls;
 extern crate num_cpus;
 extern crate parking_lot;
+extern crate rand;
 extern crate regex;
 
-#[cfg(feature = "session")]
-extern crate rand;
+#[cfg(feature = "compression")]
+extern crate flate2;
 
-pub(crate) mod core;
+#[macro_use]
 pub(crate) mod support;
+pub(crate) mod core;
+
+#[doc(hidden)]
+pub use crate::support::debug as __rex_debug;
 
 pub mod prelude {
     pub use crate::core::config::{
-        EngineContext, PageGenerator, ServerConfig, ViewEngine, ViewEngineDefinition,
+        ConfigSnapshot, ContextValue, EngineContext, ErrorPageTemplate, LineEndings, PageGenerator,
+        RequestPageGenerator, ServerConfig, StatusPageGenerator, ViewEngine, ViewEngineDefinition,
     };
 
     pub use crate::core::context as ServerContext;
     pub use crate::core::context::ContextProvider;
     pub use crate::core::cookie::*;
-    pub use crate::core::http::{Request, RequestWriter, Response, ResponseStates, ResponseWriter};
-    pub use crate::core::router::{RequestPath, Route, Router, REST};
-    pub use crate::core::server::{HttpServer, ServerDef};
-    pub use crate::core::states::{AsyncController, ControlMessage};
+    pub use crate::core::cors::{AllowedOrigins, CorsPolicy};
+    pub use crate::core::http::{
+        LanguageTag, OverrideReason, Request, RequestWriter, Response, ResponseStates,
+        ResponseWriter, StreamOptions,
+    };
+    pub use crate::core::ipfilter::{CidrBlock, IpFilter};
+    pub use crate::core::multipart::{Multipart, MultipartError, Part};
+    pub use crate::core::router::{
+        DotfilePolicy, RequestPath, Route, RouteGroup, RouteNormalization, RouteOptions, Router,
+        TrailingSlash, ViewsScope, REST,
+    };
+    pub use crate::core::server::{HttpServer, ServerDef, ServerError};
+    pub use crate::core::states::{AsyncController, ControlMessage, QueryKind, QueryReply};
+    pub use crate::core::stats::{ConnError, ServerStats, StoreStats, TlsFailure};
+    pub use crate::core::template;
+    pub use crate::support::debug::InfoLevel as DebugLevel;
+    pub use crate::support::entropy;
+    pub use crate::support::{PoolEvent, PoolStats, Priority, TraceIds};
 
     #[cfg(feature = "session")]
     pub use crate::support::session::*;
 
     #[cfg(feature = "logger")]
-    pub use crate::support::logger::InfoLevel;
+    pub use crate::support::logger::{self, AccessLogFn, AccessLogFormat, InfoLevel};
+
+    #[cfg(feature = "metrics")]
+    pub use crate::support::metrics;
+
+    #[cfg(feature = "compression")]
+    pub use crate::core::config::CompressionPolicy;
+
+    #[cfg(feature = "websocket")]
+    pub use crate::core::websocket::{WsConnection, WsMessage};
 }
 
 use crossbeam_channel as channel;
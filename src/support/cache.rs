@@ -0,0 +1,204 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::core::router::{SeekResult, REST};
+use crate::hashbrown::HashMap;
+
+/// A cached lookup: the uri it's keyed on, the route handler found, the params captured from the
+/// uri, and the view of the router it's found in.
+struct Entry {
+    method: REST,
+    uri: String,
+    found: SeekResult,
+    referenced: AtomicBool,
+}
+
+/// The bounded cache of the route lookups, keyed on the method and the uri. The entries are
+/// evicted with the clock algorithm: a hit only marks the entry as referenced, such that the
+/// lookups can share the cache, and when the cache is full, the hand sweeps the entries and
+/// evicts the first one not referenced since the last sweep. An entry is only valid for the
+/// router generation it's found in, such that a change to the router outdates the entries without
+/// locking the cache.
+#[derive(Default)]
+pub(crate) struct RouteCache {
+    index: HashMap<REST, HashMap<String, usize>>,
+    entries: Vec<Entry>,
+    hand: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl RouteCache {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn get(&self, method: &REST, uri: &str, generation: usize) -> Option<SeekResult> {
+        let found = self.lookup(method, uri, generation);
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    fn lookup(&self, method: &REST, uri: &str, generation: usize) -> Option<SeekResult> {
+        let pos = *self.index.get(method)?.get(uri)?;
+        let entry = &self.entries[pos];
+        if entry.found.2.generation() != generation {
+            // outdated, the next insert will refresh the entry
+            return None;
+        }
+
+        entry.referenced.store(true, Ordering::Relaxed);
+        Some(entry.found.clone())
+    }
+
+    /// Cache the lookup, evicting an entry if the cache already holds `capacity` entries. Nothing
+    /// is cached if the capacity is 0.
+    pub(crate) fn insert(&mut self, method: &REST, uri: &str, found: SeekResult, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+
+        if let Some(pos) = self.index.get(method).and_then(|uris| uris.get(uri)) {
+            // another lookup of the same uri got here first, or the entry is outdated
+            self.entries[*pos].found = found;
+            return;
+        }
+
+        // the capacity may have been lowered since the entries were cached
+        while self.entries.len() > capacity {
+            let last = self.entries.len() - 1;
+            self.unindex(last);
+            self.entries.pop();
+        }
+
+        let entry = Entry {
+            method: method.clone(),
+            uri: uri.to_owned(),
+            found,
+            referenced: AtomicBool::new(false),
+        };
+
+        let pos = if self.entries.len() < capacity {
+            self.entries.push(entry);
+            self.entries.len() - 1
+        } else {
+            let pos = self.sweep();
+            self.unindex(pos);
+            self.entries[pos] = entry;
+            pos
+        };
+
+        self.index
+            .entry(method.clone())
+            .or_insert_with(HashMap::new)
+            .insert(uri.to_owned(), pos);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.index.clear();
+        self.entries.clear();
+        self.hand = 0;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The number of the lookups served from the cache, and the number of those that missed it.
+    #[cfg(test)]
+    pub(crate) fn stats(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Advance the hand to the first entry not referenced since the last sweep, and clear the
+    /// marks on the way.
+    fn sweep(&mut self) -> usize {
+        loop {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+
+            let pos = self.hand;
+            self.hand += 1;
+
+            if !self.entries[pos].referenced.swap(false, Ordering::Relaxed) {
+                return pos;
+            }
+        }
+    }
+
+    fn unindex(&mut self, pos: usize) {
+        let entry = &self.entries[pos];
+        if let Some(uris) = self.index.get_mut(&entry.method) {
+            uris.remove(&entry.uri);
+        }
+    }
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::RouteCache;
+    use crate::core::router::{RouteHandler, RouterView, REST};
+    use crate::hashbrown::HashMap;
+
+    fn cache_uri(cache: &mut RouteCache, uri: &str, capacity: usize) {
+        let mut params = HashMap::new();
+        params.insert(String::from("uri"), uri.to_owned());
+        let found = (RouteHandler::default(), params, RouterView::default());
+        cache.insert(&REST::GET, uri, found, capacity);
+    }
+
+    fn cached(cache: &RouteCache, uri: &str) -> bool {
+        match cache.get(&REST::GET, uri, 0) {
+            Some((_, params, _)) => {
+                assert_eq!(params["uri"], uri);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn clock_eviction() {
+        let mut cache = RouteCache::new();
+        for uri in ["/a", "/b", "/c"].iter() {
+            cache_uri(&mut cache, uri, 3);
+        }
+
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&REST::POST, "/a", 0).is_none());
+
+        // the entries found in another router generation are outdated
+        assert!(cache.get(&REST::GET, "/a", 1).is_none());
+
+        // the referenced entries get a second chance, so the unreferenced `/b` goes first
+        assert!(cached(&cache, "/a"));
+        assert!(cached(&cache, "/c"));
+        cache_uri(&mut cache, "/d", 3);
+
+        assert_eq!(cache.len(), 3);
+        assert!(!cached(&cache, "/b"));
+        assert!(["/a", "/c", "/d"].iter().all(|uri| cached(&cache, uri)));
+
+        // lowering the capacity shrinks the cache on the next insert
+        cache_uri(&mut cache, "/e", 2);
+        assert_eq!(cache.len(), 2);
+        assert!(cached(&cache, "/e"));
+
+        cache_uri(&mut cache, "/f", 0);
+        assert!(!cached(&cache, "/f"));
+
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert!(!cached(&cache, "/e"));
+    }
+}
@@ -1,10 +1,11 @@
-use std::io::{BufWriter, Write};
+use std::borrow::Cow;
+use std::hash::Hash;
+use std::io::{self, BufWriter, ErrorKind, Write};
 use std::ptr;
 use std::sync::atomic;
 
 use crate::core::stream::Stream;
 use crate::hashbrown::HashMap;
-use crate::support::debug::{self, InfoLevel};
 
 pub trait MapUpdates<T> {
     fn add(&mut self, field: &str, value: T, allow_replace: bool, allow_case: bool) -> Option<T>;
@@ -33,6 +34,69 @@ impl<T> MapUpdates<T> for HashMap<String, T> {
     }
 }
 
+/// The map of the request header fields. The names of the well-known fields are interned, such that
+/// only the unknown ones need to be allocated.
+pub(crate) type HeaderMap = HashMap<Cow<'static, str>, String>;
+
+impl<T> MapUpdates<T> for HashMap<Cow<'static, str>, T> {
+    fn add(&mut self, key: &str, value: T, enable_replace: bool, normalize_key: bool) -> Option<T> {
+        if key.is_empty() {
+            return None;
+        }
+
+        let f = if !normalize_key {
+            header_name(key)
+        } else {
+            Cow::Owned(key.to_owned())
+        };
+
+        if enable_replace {
+            self.insert(f, value)
+        } else {
+            self.entry(f).or_insert(value);
+            None
+        }
+    }
+}
+
+/// Get the lowercase name of the header field, which is borrowed from the static names of the
+/// well-known fields, and only allocated if the field is unknown.
+pub(crate) fn header_name(name: &str) -> Cow<'static, str> {
+    let known: &[&'static str] = match name.len() {
+        2 => &["te"],
+        3 => &["dnt"],
+        4 => &["host"],
+        5 => &["range"],
+        6 => &["accept", "cookie", "expect", "origin", "pragma"],
+        7 => &["referer", "upgrade"],
+        8 => &["if-range"],
+        10 => &["user-agent", "connection", "keep-alive"],
+        12 => &["content-type", "http_version"],
+        13 => &["cache-control", "if-none-match", "authorization"],
+        14 => &[
+            "content-length",
+            "accept-charset",
+            "sec-fetch-dest",
+            "sec-fetch-mode",
+            "sec-fetch-site",
+            "sec-fetch-user",
+        ],
+        15 => &["accept-encoding", "accept-language", "x-forwarded-for"],
+        16 => &["x-requested-with"],
+        17 => &["if-modified-since", "transfer-encoding"],
+        25 => &["upgrade-insecure-requests"],
+        _ => &[],
+    };
+
+    for field in known {
+        if field.eq_ignore_ascii_case(name) {
+            return Cow::Borrowed(field);
+        }
+    }
+
+    Cow::Owned(name.to_lowercase())
+}
+
 pub trait VecExt<T> {
     fn swap_reset(&mut self) -> Vec<T>;
     fn swap_reserve(&mut self, cap: usize) -> Vec<T>;
@@ -93,25 +157,40 @@ impl LineBreakUtil for Vec<u8> {
 
 pub(crate) fn write_to_buff(buffer: &mut BufWriter<&mut Stream>, content: &[u8]) {
     if buffer.write(content).is_err() {
-        debug::print(
-            "An error has taken place when writing the response header to the stream",
-            InfoLevel::Warning,
-        );
+        rex_warn!("An error has taken place when writing the response header to the stream");
     }
 }
 
-pub(crate) fn write_line_break(buffer: &mut BufWriter<&mut Stream>) {
-    let _ = buffer.write(&[13, 10]);
+/// How many times a flush is attempted when the client is slow to take the bytes, i.e. the write
+/// has timed out or would block. Each attempt is bounded by the stream's write timeout.
+const FLUSH_ATTEMPTS: usize = 3;
+
+/// Flush the buffered bytes to the stream, retrying a bounded number of times if the client isn't
+/// taking them fast enough. The `BufWriter` keeps whatever it has not written yet, so every retry
+/// picks up where the last one stopped.
+pub(crate) fn flush_with_retry<W: Write>(buffer: &mut W) -> io::Result<()> {
+    let mut attempts = 1;
+
+    loop {
+        match buffer.flush() {
+            Err(ref err)
+                if attempts < FLUSH_ATTEMPTS
+                    && (err.kind() == ErrorKind::Interrupted
+                        || err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::TimedOut) =>
+            {
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
 pub(crate) fn flush_buffer(buffer: &mut BufWriter<&mut Stream>) -> u8 {
-    if let Err(err) = buffer.flush() {
-        debug::print(
-            &format!(
-                "An error has taken place when flushing the response to the stream: {}",
-                err
-            )[..],
-            InfoLevel::Warning,
+    if let Err(err) = flush_with_retry(buffer) {
+        rex_warn!(
+            "An error has taken place when flushing the response to the stream: {}",
+            err
         );
 
         return 1;
@@ -127,12 +206,16 @@ pub(crate) fn cpu_relax(count: usize) {
     }
 }
 
-pub fn json_stringify(contents: &HashMap<String, String>) -> String {
+pub fn json_stringify<K>(contents: &HashMap<K, String>) -> String
+where
+    K: AsRef<str> + Eq + Hash,
+{
     let mut res: String = String::from("{");
     let mut is_first = true;
 
     if !contents.is_empty() {
         for (field, content) in contents.iter() {
+            let field = field.as_ref();
             if field.is_empty() {
                 continue;
             }
@@ -179,6 +262,85 @@ pub fn json_flat_stringify(contents: &HashMap<String, Vec<String>>) -> String {
     res
 }
 
+/// Escape the content to be placed within a quoted JSON string.
+pub(crate) fn json_escape(content: &str) -> String {
+    let mut res = String::with_capacity(content.len());
+
+    for c in content.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+
+    res
+}
+
+/// Decode the percent-encoded octets, e.g. `%20` or the UTF-8 sequence `%E4%B8%AD`, and the `+`
+/// signs into spaces if `plus_as_space` is set, as in the query strings. Malformed escapes, e.g.
+/// `%ZZ` or a truncated `%2`, are left as they are; if the decoded octets are not valid UTF-8,
+/// the raw content is returned untouched.
+pub(crate) fn percent_decode(raw: &str, plus_as_space: bool) -> String {
+    if !(raw.contains('%') || plus_as_space && raw.contains('+')) {
+        return raw.to_owned();
+    }
+
+    let bytes = raw.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' && idx + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[idx + 1]), hex_value(bytes[idx + 2])) {
+                res.push(hi << 4 | lo);
+                idx += 3;
+                continue;
+            }
+        }
+
+        if plus_as_space && bytes[idx] == b'+' {
+            res.push(b' ');
+        } else {
+            res.push(bytes[idx]);
+        }
+
+        idx += 1;
+    }
+
+    String::from_utf8(res).unwrap_or_else(|_| raw.to_owned())
+}
+
+/// Percent-encode the content as a query component, where only the unreserved characters, i.e. the
+/// alphanumerics and `-._~`, are kept as they are.
+pub(crate) fn percent_encode(raw: &str) -> String {
+    let mut res = String::with_capacity(raw.len());
+
+    for byte in raw.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                res.push(byte as char)
+            }
+            _ => res.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    res
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
 fn json_format_content(content: &[String]) -> String {
     let len = content.len();
     match len {
@@ -1,98 +1,279 @@
 use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
 
 use crate::chrono::prelude::{DateTime, Utc};
 use crate::parking_lot::Once;
 
+#[cfg(test)]
+use crate::parking_lot::Mutex;
+
+#[cfg(feature = "logger")]
+use crate::support::logger;
+use crate::support::span;
+
 static ONCE: Once = Once::new();
-static mut DEBUG_LEVEL: InfoLevel = InfoLevel::Silent;
+static DEBUG_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(test)]
+lazy_static! {
+    static ref CAPTURED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+    static ref CAPTURE_LOCK: Mutex<()> = Mutex::new(());
+}
 
-#[derive(PartialEq)]
+/// The levels of the debug messages, from the most verbose to the most severe. Messages below the
+/// current level, which can be set with `ServerConfig::set_debug_level` or the `DEBUG_LEVEL`
+/// environment variable, are dropped without being formatted.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub enum InfoLevel {
     Silent,
+    Trace,
+    Debug,
     Info,
     Warning,
     Error,
 }
 
+/// Print a trace message, accepting the same syntax as `format!`, optionally prefixed with the
+/// client's address as `client: addr,`. The format arguments are only evaluated if trace messages
+/// are enabled.
+#[macro_export]
+macro_rules! rex_trace {
+    (client: $client:expr, $($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Trace, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Trace, None, $($arg)+)
+    };
+}
+
+/// Print a debug message, see `rex_trace!` for the syntax.
+#[macro_export]
+macro_rules! rex_debug {
+    (client: $client:expr, $($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Debug, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Debug, None, $($arg)+)
+    };
+}
+
+/// Print an info message, see `rex_trace!` for the syntax.
+#[macro_export]
+macro_rules! rex_info {
+    (client: $client:expr, $($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Info, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Info, None, $($arg)+)
+    };
+}
+
+/// Print a warning message, see `rex_trace!` for the syntax.
+#[macro_export]
+macro_rules! rex_warn {
+    (client: $client:expr, $($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Warning, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Warning, None, $($arg)+)
+    };
+}
+
+/// Print an error message, see `rex_trace!` for the syntax.
+#[macro_export]
+macro_rules! rex_error {
+    (client: $client:expr, $($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Error, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::__rex_log!($crate::prelude::DebugLevel::Error, None, $($arg)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rex_log {
+    ($level:expr, $client:expr, $($arg:tt)+) => {
+        if $crate::__rex_debug::enabled($level) {
+            $crate::__rex_debug::print_args($level, $client, format_args!($($arg)+));
+        }
+    };
+}
+
 pub fn initialize() {
     ONCE.call_once(|| {
         if let Ok(debug_mode) = env::var("DEBUG_LEVEL") {
-            match &debug_mode[..] {
-                "1" => set_debug_level(InfoLevel::Info),
-                "2" => set_debug_level(InfoLevel::Warning),
-                "3" => set_debug_level(InfoLevel::Error),
+            match &debug_mode.to_lowercase()[..] {
+                "1" | "debug" => set_debug_level(InfoLevel::Debug),
+                "2" | "warn" => set_debug_level(InfoLevel::Warning),
+                "3" | "error" => set_debug_level(InfoLevel::Error),
+                "trace" => set_debug_level(InfoLevel::Trace),
+                "info" => set_debug_level(InfoLevel::Info),
                 _ => set_debug_level(InfoLevel::Silent),
             }
         }
     });
 }
 
-pub fn print(info: &str, level: InfoLevel) {
-    if !in_debug_mode() {
-        return;
-    }
+/// If the messages of the level shall be printed under the current debug level.
+#[inline]
+pub fn enabled(level: InfoLevel) -> bool {
+    let curr = DEBUG_LEVEL.load(Ordering::Relaxed);
+    curr != 0 && level != InfoLevel::Silent && level as u8 >= curr
+}
+
+pub fn print_args(level: InfoLevel, client: Option<SocketAddr>, args: fmt::Arguments) {
+    let info = fmt::format(args);
     if info.is_empty() {
         return;
     }
-    if !print_level_allowed(&level) {
-        return;
+
+    #[cfg(test)]
+    {
+        if let Some(captured) = CAPTURED.lock().as_mut() {
+            captured.push(info.clone());
+        }
+    }
+
+    #[cfg(feature = "logger")]
+    {
+        let log_level = match level {
+            InfoLevel::Trace => logger::InfoLevel::Trace,
+            InfoLevel::Debug => logger::InfoLevel::Debug,
+            InfoLevel::Info => logger::InfoLevel::Info,
+            InfoLevel::Warning => logger::InfoLevel::Warn,
+            InfoLevel::Error | InfoLevel::Silent => logger::InfoLevel::Error,
+        };
+
+        // if the logging service is running, hand the message over
+        if logger::log(&info, log_level, client).is_ok() {
+            return;
+        }
     }
 
     let now: DateTime<Utc> = Utc::now();
     let level_label = match level {
-        InfoLevel::Info => String::from("Info"),
-        InfoLevel::Warning => String::from("Warning"),
-        InfoLevel::Error => String::from("Error"),
+        InfoLevel::Trace => "Trace",
+        InfoLevel::Debug => "Debug",
+        InfoLevel::Info => "Info",
+        InfoLevel::Warning => "Warning",
+        InfoLevel::Error => "Error",
         InfoLevel::Silent => return,
     };
 
+    // the worker threads are named after their pools, e.g. `rex-parser-3`
+    let thread = thread::current();
+    let mut origin = match thread.name() {
+        Some(name) => format!(" in {}", name),
+        None => String::new(),
+    };
+
+    if let Some(addr) = client {
+        origin.push_str(&format!(" (from client {})", addr));
+    }
+
+    // the work done on behalf of a connection, or a request, is tagged with its ids
+    let info = match span::current() {
+        Some(ids) => format!("[{}] {}", ids, info),
+        None => info,
+    };
+
     eprintln!("\r\n======================");
     eprintln!(
-        "[{}] at {}:\r\n {}",
+        "[{}] at {}{}:\r\n {}",
         level_label,
         now.format("%Y-%m-%d %H:%M:%S GMT").to_string(),
+        origin,
         info
     );
 }
 
-#[inline]
-fn in_debug_mode() -> bool {
-    unsafe {
-        match DEBUG_LEVEL {
-            InfoLevel::Silent => false,
-            _ => true,
-        }
+pub(crate) fn set_debug_level(debug: InfoLevel) {
+    DEBUG_LEVEL.store(debug as u8, Ordering::Relaxed);
+
+    if debug != InfoLevel::Silent {
+        println!("\n\tNow in debug mode...\n");
     }
 }
 
-#[inline]
-fn print_level_allowed(level: &InfoLevel) -> bool {
-    unsafe {
-        let raw_level = cast_info_level(level);
-        match DEBUG_LEVEL {
-            InfoLevel::Silent => false,
-            InfoLevel::Error => raw_level > 2,
-            InfoLevel::Warning => raw_level > 1,
-            _ => true,
+/// Run the closure under the debug level, and return the messages printed meanwhile from any
+/// thread. The previous level is restored afterwards, and the captures are taken one at a time.
+#[cfg(test)]
+pub(crate) fn capture<F: FnOnce()>(level: InfoLevel, f: F) -> Vec<String> {
+    struct Restore(u8);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            DEBUG_LEVEL.store(self.0, Ordering::Relaxed);
         }
     }
-}
 
-fn cast_info_level(level: &InfoLevel) -> u8 {
-    match level {
-        InfoLevel::Silent => 0,
-        InfoLevel::Info => 1,
-        InfoLevel::Warning => 2,
-        InfoLevel::Error => 3,
-    }
+    let _guard = CAPTURE_LOCK.lock();
+    let _restore = Restore(DEBUG_LEVEL.swap(level as u8, Ordering::Relaxed));
+
+    *CAPTURED.lock() = Some(Vec::new());
+    f();
+
+    CAPTURED.lock().take().unwrap_or_default()
 }
 
-fn set_debug_level(debug: InfoLevel) {
-    unsafe {
-        DEBUG_LEVEL = debug;
+#[cfg(test)]
+mod debug_test {
+    use super::{capture, InfoLevel};
+    use std::fmt;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counted<'a>(&'a AtomicUsize);
 
-        if in_debug_mode() {
-            println!("\n\tNow in debug mode...\n");
+    impl<'a> fmt::Display for Counted<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            write!(f, "counted")
         }
     }
+
+    #[test]
+    fn lazy_format_arguments() {
+        let count = AtomicUsize::new(0);
+        let client: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        // other tests may be logging meanwhile, only look for the lines from here
+        let ours = |lines: Vec<String>| -> Vec<String> {
+            lines
+                .into_iter()
+                .filter(|line| line.starts_with("lazy "))
+                .collect()
+        };
+
+        let lines = capture(InfoLevel::Info, || {
+            rex_trace!("lazy trace {}", Counted(&count));
+            rex_debug!(client: client, "lazy debug {}", Counted(&count));
+            assert_eq!(count.load(Ordering::SeqCst), 0);
+
+            rex_info!("lazy info {}", Counted(&count));
+            rex_warn!("lazy warn {}", Counted(&count));
+            rex_error!(client: client, "lazy error {} from {}", Counted(&count), client);
+            assert_eq!(count.load(Ordering::SeqCst), 3);
+        });
+
+        assert_eq!(
+            ours(lines),
+            [
+                "lazy info counted",
+                "lazy warn counted",
+                "lazy error counted from 127.0.0.1:8080"
+            ]
+        );
+
+        let lines = capture(InfoLevel::Silent, || {
+            rex_error!("lazy error {}", Counted(&count));
+        });
+
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(ours(lines).is_empty());
+    }
 }
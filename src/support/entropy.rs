@@ -0,0 +1,137 @@
+//! The randomness and the unique ids for the handlers, e.g. the CSRF tokens or the names of the
+//! uploaded files, and for the framework itself, e.g. the session ids, such that they all come
+//! from a single source.
+//!
+//! The random bytes are drawn from the random source of the OS, e.g. `getrandom` on Linux, which
+//! is opened once per thread. If the OS source can't be opened, a warning is logged and the bytes
+//! are drawn from the thread local generator of `rand` instead, i.e. an ISAAC generator seeded
+//! from the timing jitter of the CPU, see `rand::JitterRng`, which is weaker than the OS source but
+//! still unpredictable.
+//!
+//! # Examples
+//! ```
+//! use rusty_express::prelude::*;
+//!
+//! let token = entropy::random_token(32);
+//! assert_eq!(token.len(), 32);
+//!
+//! let first = entropy::monotonic_id();
+//! assert!(entropy::monotonic_id() > first);
+//! ```
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::rand::{thread_rng, OsRng, Rng};
+
+/// The URL-safe alphabet of the tokens, i.e. the base64url alphabet, which also makes a valid
+/// cookie value.
+const TOKEN_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+thread_local! {
+    static OS_RNG: RefCell<Option<OsRng>> = RefCell::new(open_os_rng());
+}
+
+lazy_static! {
+    // seeded with the startup time, such that the ids won't repeat across restarts
+    static ref NEXT_ID: AtomicU64 = AtomicU64::new(startup_seed());
+}
+
+/// Fill the buffer with random bytes.
+pub fn fill_bytes(buf: &mut [u8]) {
+    OS_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(os) => os.fill_bytes(buf),
+        None => thread_rng().fill_bytes(buf),
+    });
+}
+
+/// Generate `n` random bytes.
+pub fn random_bytes(n: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; n];
+    fill_bytes(&mut buf);
+    buf
+}
+
+/// Generate a random token of `len` characters from the URL-safe alphabet, i.e. `A-Z`, `a-z`,
+/// `0-9`, `-` and `_`, such that it can be placed in a uri, a header or a cookie as is. Each
+/// character carries 6 random bits.
+pub fn random_token(len: usize) -> String {
+    random_bytes(len)
+        .into_iter()
+        .map(|b| TOKEN_ALPHABET[(b & 0x3f) as usize] as char)
+        .collect()
+}
+
+/// Generate an id unique within the process, which is strictly larger than any id generated
+/// before, from any thread. The ids are not random, so don't use them as the secrets.
+pub fn monotonic_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn open_os_rng() -> Option<OsRng> {
+    match OsRng::new() {
+        Ok(rng) => Some(rng),
+        Err(e) => {
+            rex_warn!(
+                "Unable to open the random source of the OS, the random bytes will be drawn \
+                 from the thread local generator instead: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+fn startup_seed() -> u64 {
+    // the microseconds since the epoch, which leave ~2^12 years before overflowing
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000 + u64::from(d.subsec_micros()))
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod entropy_test {
+    use super::{monotonic_id, random_bytes, random_token, TOKEN_ALPHABET};
+    use crate::hashbrown::HashSet;
+    use std::thread;
+
+    #[test]
+    fn tokens() {
+        assert!(random_token(0).is_empty());
+        assert_eq!(random_bytes(48).len(), 48);
+
+        let tokens: HashSet<String> = (0..10_000).map(|_| random_token(24)).collect();
+        assert_eq!(tokens.len(), 10_000);
+
+        for token in tokens.iter() {
+            assert_eq!(token.len(), 24);
+            assert!(token.bytes().all(|b| TOKEN_ALPHABET.contains(&b)));
+        }
+    }
+
+    #[test]
+    fn monotonic_across_threads() {
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(|| {
+                    let ids: Vec<u64> = (0..1000).map(|_| monotonic_id()).collect();
+                    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+                    ids
+                })
+            })
+            .collect();
+
+        let mut all = HashSet::new();
+        for worker in workers {
+            for id in worker.join().unwrap() {
+                assert!(all.insert(id));
+            }
+        }
+
+        // and the later ones come after all of them
+        assert!(all.iter().all(|id| *id < monotonic_id()));
+    }
+}
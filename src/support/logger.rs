@@ -17,19 +17,36 @@ use std::time::Duration;
 
 use crate::channel::{self, SendError};
 use crate::chrono::{DateTime, Utc};
+use crate::core::http::{Request, Response, ResponseStates};
 use crate::core::syncstore::StaticStore;
-use crate::parking_lot::Once;
-use crate::support::{common::cpu_relax, debug};
+use crate::hashbrown::HashSet;
+use crate::parking_lot::RwLock;
+use crate::support::{
+    common::cpu_relax,
+    debug,
+    span::{self, TraceIds},
+};
 
 const DEFAULT_LOCATION: &str = "./logs";
+const DEFAULT_ACCESS_FORMAT: &str = "%a \"%m %u\" %s %b %Dus";
 
-static ONCE: Once = Once::new();
 static DUMP_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
 
 static mut CHAN: StaticStore<(channel::Sender<LogMessage>, channel::Receiver<LogMessage>)> =
     StaticStore::init();
 static mut CONFIG: StaticStore<LoggerConfig> = StaticStore::init();
-static mut REFRESH_HANDLER: Option<thread::JoinHandle<()>> = None;
+static mut REFRESH_HANDLER: Option<(thread::JoinHandle<()>, Arc<AtomicBool>)> = None;
+
+lazy_static! {
+    static ref ACCESS_LOG: RwLock<AccessLog> = RwLock::new(AccessLog::default());
+}
+
+#[cfg(test)]
+lazy_static! {
+    /// The uri and the status of every response given to `log_access`, whether the logging service
+    /// is running or not, such that the tests can tell which responses reach the access log.
+    pub(crate) static ref ACCESS_TAP: RwLock<Vec<(String, u16)>> = RwLock::new(Vec::new());
+}
 
 #[derive(Debug)]
 pub enum InfoLevel {
@@ -58,26 +75,53 @@ pub struct LogInfo {
     client: Option<SocketAddr>,
     level: InfoLevel,
     time: DateTime<Utc>,
+    span: Option<TraceIds>,
 }
 
 impl fmt::Display for LogInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.client {
-            Some(addr) => write!(
-                f,
-                "[{}] @ {} (from client {}): {}",
-                self.level,
-                self.time.to_string(),
-                addr.to_string(),
-                self.message
-            ),
-            None => write!(
-                f,
-                "[{}] @ {}: {}",
-                self.level,
-                self.time.to_string(),
-                self.message
-            ),
+        write!(f, "[{}] @ {}", self.level, self.time.to_string())?;
+
+        if let Some(addr) = self.client {
+            write!(f, " (from client {})", addr)?;
+        }
+
+        match self.span {
+            Some(ids) => write!(f, ": [{}] {}", ids, self.message),
+            None => write!(f, ": {}", self.message),
+        }
+    }
+}
+
+/// Make the access log entry from the request, the response, and the time taken to generate the
+/// response, see `AccessLogFormat::Custom`.
+pub type AccessLogFn = dyn Fn(&Request, &Response, Duration) -> String + Send + Sync;
+
+/// The format of the access log entries, which are logged for each request served while the
+/// logging service is running, see `set_access_log_format`.
+pub enum AccessLogFormat {
+    /// The pattern with the tokens replaced by the values of the request: `%m` the method, `%u` the
+    /// uri, `%s` the status, `%b` the size of the response body in bytes, or `-` if unknown, `%D`
+    /// the time taken to generate the response in microseconds, `%a` the client address, or `-` if
+    /// unknown, and `%%` a literal `%`. The default is `%a "%m %u" %s %b %Dus`.
+    Pattern(String),
+    /// The function making the entry from the request, the response, and the time taken to generate
+    /// the response.
+    Custom(Box<AccessLogFn>),
+}
+
+struct AccessLog {
+    enabled: bool,
+    format: AccessLogFormat,
+    excluded: HashSet<String>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog {
+            enabled: true,
+            format: AccessLogFormat::Pattern(String::from(DEFAULT_ACCESS_FORMAT)),
+            excluded: HashSet::new(),
         }
     }
 }
@@ -160,7 +204,7 @@ pub trait LogWriter {
     fn dump(&self, log_store: &[LogInfo]) -> Result<(), usize>;
 }
 
-struct DefaultLogWriter;
+pub(crate) struct DefaultLogWriter;
 
 impl DefaultLogWriter {
     fn get_log_file(config: &LoggerConfig) -> Result<File, String> {
@@ -189,7 +233,7 @@ impl LogWriter for DefaultLogWriter {
             let mut content: String = String::new();
 
             for (count, info) in log_store.iter().enumerate() {
-                content.push_str(&format_content(&info.level, &info.message, info.time));
+                content.push_str(&format_content(info));
 
                 if count % 10 == 0 {
                     write_to_file(&mut file, &content);
@@ -215,11 +259,11 @@ pub fn log(message: &str, level: InfoLevel, client: Option<SocketAddr>) -> Resul
         client,
         level,
         time: Utc::now(),
+        span: span::current(),
     };
 
-    if let Ok(chan) = unsafe { CHAN.as_ref() } {
+    if let Some(chan) = sender() {
         return chan
-            .0
             .send(LogMessage::Info(info))
             .map_err(|err| format!("Failed to log the message: {:?}", err));
     }
@@ -227,6 +271,115 @@ pub fn log(message: &str, level: InfoLevel, client: Option<SocketAddr>) -> Resul
     Err(String::from("The logging service is not running..."))
 }
 
+/// Turn the access log on or off, which is on by default.
+pub fn set_access_log(enabled: bool) {
+    ACCESS_LOG.write().enabled = enabled;
+}
+
+/// Set the format of the access log entries.
+///
+/// # Examples
+///
+/// ```rust
+/// use rusty_express::prelude::*;
+///
+/// logger::set_access_log_format(AccessLogFormat::Pattern(String::from("%m %u -> %s in %Dus")));
+/// logger::exclude_path("/healthz");
+/// ```
+pub fn set_access_log_format(format: AccessLogFormat) {
+    ACCESS_LOG.write().format = format;
+}
+
+/// Don't log the requests to the path, e.g. the health checks to `/healthz`. The path is compared
+/// with the uri of the request as is, without the query.
+pub fn exclude_path(path: &str) {
+    ACCESS_LOG.write().excluded.insert(path.to_owned());
+}
+
+/// Log the request served, unless the logging service is not running, or the access log is off
+/// for the request. The entry is dropped rather than waiting if the logger is falling behind, such
+/// that the response is never held back by the logging.
+pub(crate) fn log_access(request: &Request, response: &Response, elapsed: Duration) {
+    #[cfg(test)]
+    ACCESS_TAP
+        .write()
+        .push((request.uri.clone(), response.get_status()));
+
+    let chan = match sender() {
+        Some(chan) => chan,
+        None => return,
+    };
+
+    let message = match access_entry(request, response, elapsed) {
+        Some(message) => message,
+        None => return,
+    };
+
+    let ids = request.trace_ids();
+    let info = LogInfo {
+        message,
+        client: request.client_info(),
+        level: InfoLevel::Info,
+        time: Utc::now(),
+        span: if ids.is_empty() { None } else { Some(ids) },
+    };
+
+    chan.try_send(LogMessage::Info(info)).unwrap_or_default();
+}
+
+/// The sending end of the logging service, if it's running.
+fn sender() -> Option<&'static channel::Sender<LogMessage>> {
+    unsafe { CHAN.as_ref() }.ok().map(|chan| &chan.0)
+}
+
+fn access_entry(request: &Request, response: &Response, elapsed: Duration) -> Option<String> {
+    let access = ACCESS_LOG.read();
+    if !access.enabled || access.excluded.contains(&request.uri) {
+        return None;
+    }
+
+    let entry = match access.format {
+        AccessLogFormat::Pattern(ref pattern) => {
+            let mut entry = String::with_capacity(pattern.len() + request.uri.len() + 32);
+            let mut tokens = pattern.chars();
+
+            while let Some(c) = tokens.next() {
+                if c != '%' {
+                    entry.push(c);
+                    continue;
+                }
+
+                match tokens.next() {
+                    Some('m') => entry.push_str(&request.method.to_string()),
+                    Some('u') => entry.push_str(&request.uri),
+                    Some('s') => entry.push_str(&response.get_status().to_string()),
+                    Some('b') => match response.get_body_size() {
+                        Some(size) => entry.push_str(&size.to_string()),
+                        None => entry.push('-'),
+                    },
+                    Some('D') => entry.push_str(&elapsed.as_micros().to_string()),
+                    Some('a') => match request.client_info() {
+                        Some(addr) => entry.push_str(&addr.to_string()),
+                        None => entry.push('-'),
+                    },
+                    Some('%') => entry.push('%'),
+                    Some(other) => {
+                        // not a token, keep it as is
+                        entry.push('%');
+                        entry.push(other);
+                    }
+                    None => entry.push('%'),
+                }
+            }
+
+            entry
+        }
+        AccessLogFormat::Custom(ref format) => format(request, response, elapsed),
+    };
+
+    Some(entry)
+}
+
 pub(crate) fn start<T>(
     writer: T,
     period: Option<u64>,
@@ -249,12 +402,16 @@ pub(crate) fn start<T>(
 
     config.meta_info_provider = provider;
 
-    ONCE.call_once(|| {
-        let (tx, rx) = channel::bounded(64);
-        unsafe {
-            CHAN.set((tx, rx));
+    let rx = unsafe {
+        if CHAN.as_ref().is_ok() {
+            // the logging service is already running
+            return;
         }
-    });
+
+        let (tx, rx) = channel::bounded(64);
+        CHAN.set((tx, rx.clone()));
+        rx
+    };
 
     if let Some(ref path) = config.log_folder_path {
         let refresh = config.refresh_period.as_secs();
@@ -264,69 +421,105 @@ pub(crate) fn start<T>(
             path.to_str().unwrap(),
             refresh
         );
-
-        start_refresh(config.refresh_period);
     }
 
-    config
-        .rx_handler
-        .replace(thread::spawn(move || run(Box::new(DefaultLogWriter))));
+    start_refresh(config.refresh_period);
+
+    // the writer reads the config when dumping, so it must be in place before the service starts
+    let config = unsafe {
+        CONFIG.set(config);
+        CONFIG.as_mut().unwrap()
+    };
+
+    match thread::Builder::new()
+        .name(String::from("rex-logger"))
+        .spawn(move || run(Box::new(DefaultLogWriter), rx))
+    {
+        Ok(handler) => {
+            config.rx_handler.replace(handler);
+        }
+        Err(e) => eprintln!("Failed to launch the logging service: {}", e),
+    }
 }
 
+/// Shut down the logging service: the messages still queued are dumped along with the final one,
+/// and this call blocks until all the dumps are done. New messages are rejected from now on.
 pub(crate) fn shutdown() {
     stop_refresh();
 
-    let config = match unsafe { CONFIG.as_mut() } {
-        Ok(c) => c,
-        Err(_) => return,
+    let chan = match unsafe { CHAN.take() } {
+        Some(chan) => chan,
+        None => return,
     };
 
-    if let Some(rx) = config.rx_handler.take() {
-        rx.join().unwrap_or_else(|err| {
-            eprintln!("Encountered error while closing the logger: {:?}", err);
-        });
-    }
-
     let final_msg = LogInfo {
         message: String::from("Shutting down the logging service..."),
         client: None,
         level: InfoLevel::Info,
         time: Utc::now(),
+        span: None,
     };
 
-    if let Some(chan) = unsafe { CHAN.take() } {
-        if let Err(SendError(msg)) = chan.0.send(LogMessage::Info(final_msg)) {
-            debug::print("Failed to log the final message", debug::InfoLevel::Warning);
-        }
+    if let Err(SendError(msg)) = chan.0.send(LogMessage::Info(final_msg)) {
+        eprintln!("Failed to log the final message");
     }
 
-    // Need to block because otherwise the lazy_static contents may go expired too soon
-    //    dump_log();
+    // flush what's been logged, then quit the service, which waits for the dumps to finish; the
+    // service must be told to quit before joining it, or the join would never return
+    if chan.0.send(LogMessage::Dump).is_err() || chan.0.send(LogMessage::Shutdown).is_err() {
+        return;
+    }
+
+    let config = match unsafe { CONFIG.as_mut() } {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+
+    if let Some(rx) = config.rx_handler.take() {
+        rx.join().unwrap_or_else(|err| {
+            eprintln!("Encountered error while closing the logger: {:?}", err);
+        });
+    }
 }
 
-fn run<T>(writer: Box<T>)
+fn run<T>(writer: Box<T>, rx: channel::Receiver<LogMessage>)
 where
     T: LogWriter + Send + Sync + 'static,
 {
     let mut store: Vec<LogInfo> = Vec::with_capacity(1024);
+    let mut dumps: Vec<thread::JoinHandle<()>> = Vec::new();
     let writer = Arc::new(writer);
-    let rx = unsafe { &CHAN.as_ref().unwrap().1 };
 
     for info in rx {
         match info {
             LogMessage::Info(i) => store.push(i),
             LogMessage::Dump => {
+                if store.is_empty() {
+                    continue;
+                }
+
                 let arc_writer = Arc::clone(&writer);
                 let mut dump_store = Vec::with_capacity(1024);
                 mem::swap(&mut store, &mut dump_store);
 
-                thread::spawn(move || {
+                dumps.retain(|handle| !handle.is_finished());
+                dumps.push(thread::spawn(move || {
                     dump_log(dump_store, arc_writer);
-                });
+                }));
             }
             LogMessage::Shutdown => break,
         }
     }
+
+    // the dumps in progress must be done before the service quits, or their logs could be lost
+    for handle in dumps {
+        handle.join().unwrap_or_else(|err| {
+            eprintln!("Encountered error while dumping the logs: {:?}", err);
+        });
+    }
+
+    // the final dump of whatever has been logged since the last one
+    dump_log(store, writer);
 }
 
 fn start_refresh(period: Duration) {
@@ -335,24 +528,38 @@ fn start_refresh(period: Duration) {
             stop_refresh();
         }
 
-        REFRESH_HANDLER = Some(thread::spawn(move || loop {
-            thread::sleep(period);
-            thread::spawn(|| {
-                //                dump_log();
-            });
-        }));
+        let chan = match sender() {
+            Some(chan) => chan.clone(),
+            None => return,
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+
+        REFRESH_HANDLER = thread::Builder::new()
+            .name(String::from("rex-logger-refresh"))
+            .spawn(move || refresh(period, chan, flag))
+            .ok()
+            .map(|handler| (handler, stop));
+    }
+}
+
+/// Ask the logging service to dump the logs every `period`, until stopped or the service is gone.
+fn refresh(period: Duration, chan: channel::Sender<LogMessage>, stop: Arc<AtomicBool>) {
+    loop {
+        thread::sleep(period);
+        if stop.load(Ordering::Acquire) || chan.send(LogMessage::Dump).is_err() {
+            return;
+        }
     }
 }
 
 fn stop_refresh() {
     unsafe {
-        if let Some(handler) = REFRESH_HANDLER.take() {
-            handler.join().unwrap_or_else(|err| {
-                eprintln!(
-                    "Failed to stop the log refresh service, error code: {:?}...",
-                    err
-                );
-            });
+        // the refresh thread is asleep for most of the period, don't wait for it to wake up and
+        // quit, otherwise the shutdown could be stalled for up to a whole period.
+        if let Some((_, stop)) = REFRESH_HANDLER.take() {
+            stop.store(true, Ordering::Release);
         }
     }
 }
@@ -412,14 +619,20 @@ fn write_to_file(file: &mut File, content: &str) {
     });
 }
 
-fn format_content(level: &InfoLevel, message: &str, timestamp: DateTime<Utc>) -> String {
+fn format_content(info: &LogInfo) -> String {
+    let span = match info.span {
+        Some(ids) => format!("[{}] ", ids),
+        None => String::new(),
+    };
+
     [
         "\r\n[",
-        &level.to_string(),
+        &info.level.to_string(),
         "] @ ",
-        &timestamp.to_rfc3339(),
+        &info.time.to_rfc3339(),
         ": ",
-        message,
+        &span,
+        &info.message,
     ]
     .join("")
 }
@@ -445,3 +658,114 @@ fn create_dump_file(id: String, loc: &PathBuf) -> Result<File, String> {
         _ => Err(String::from("Unable to create the dump file")),
     }
 }
+
+#[cfg(test)]
+mod logger_test {
+    use super::{
+        access_entry, exclude_path, refresh, run, set_access_log, set_access_log_format,
+        AccessLogFormat, InfoLevel, LogInfo, LogMessage, LogWriter,
+    };
+    use crate::channel;
+    use crate::chrono::Utc;
+    use crate::core::http::{Request, Response, ResponseWriter};
+    use crate::core::router::REST;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Records the messages of each dumped batch.
+    struct Recorder(Arc<Mutex<Vec<Vec<String>>>>);
+
+    impl LogWriter for Recorder {
+        fn dump(&self, log_store: &[LogInfo]) -> Result<(), usize> {
+            let batch = log_store.iter().map(|info| info.message.clone()).collect();
+            self.0.lock().unwrap().push(batch);
+            Ok(())
+        }
+    }
+
+    fn info(message: &str) -> LogMessage {
+        LogMessage::Info(LogInfo {
+            message: message.to_owned(),
+            client: None,
+            level: InfoLevel::Info,
+            time: Utc::now(),
+            span: None,
+        })
+    }
+
+    #[test]
+    fn periodic_and_shutdown_dumps() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = channel::bounded(64);
+
+        let writer = Recorder(Arc::clone(&batches));
+        let service = thread::spawn(move || run(Box::new(writer), rx));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (chan, flag) = (tx.clone(), Arc::clone(&stop));
+        let refresher = thread::spawn(move || refresh(Duration::from_millis(10), chan, flag));
+
+        // the refresh timer dumps the logs without being asked to
+        tx.send(info("periodic")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while batches.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        stop.store(true, Ordering::Release);
+        refresher.join().unwrap();
+
+        // and the shutdown dumps what's left before the service quits
+        tx.send(info("final")).unwrap();
+        tx.send(LogMessage::Shutdown).unwrap();
+        service.join().unwrap();
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(
+            *batches,
+            vec![vec![String::from("periodic")], vec![String::from("final")]]
+        );
+    }
+
+    #[test]
+    fn access_entries() {
+        let mut request = Box::new(Request::new());
+        request.method = REST::POST;
+        request.uri.push_str("/orders");
+
+        let mut response = Box::new(Response::new());
+        response.status(201);
+        response.send("created");
+
+        let elapsed = Duration::from_micros(1500);
+
+        set_access_log_format(AccessLogFormat::Pattern(String::from(
+            "%m %u %s %b %Dus %a 100%% %x",
+        )));
+        assert_eq!(
+            access_entry(&request, &response, elapsed).as_deref(),
+            Some("POST /orders 201 7 1500us - 100% %x")
+        );
+
+        set_access_log_format(AccessLogFormat::Custom(Box::new(|req, _, elapsed| {
+            format!("{} took {}ms", req.uri, elapsed.as_millis())
+        })));
+        assert_eq!(
+            access_entry(&request, &response, elapsed).as_deref(),
+            Some("/orders took 1ms")
+        );
+
+        exclude_path("/healthz");
+        request.uri = String::from("/healthz");
+        assert!(access_entry(&request, &response, elapsed).is_none());
+
+        request.uri = String::from("/orders");
+        set_access_log(false);
+        assert!(access_entry(&request, &response, elapsed).is_none());
+        set_access_log(true);
+    }
+}
@@ -0,0 +1,309 @@
+//! The `metrics` module collects the request counters and the handler latencies, and renders them
+//! along with the server stats in the Prometheus text exposition format. The endpoint is served by
+//! the server once it's enabled with `ServerConfig::enable_metrics`:
+//!
+//! ```no_run
+//! use rusty_express::prelude::*;
+//!
+//! let mut server = HttpServer::new();
+//! server.config().enable_metrics("/metrics");
+//! server.listen(8080);
+//! ```
+//!
+//! The collectors are plain atomics, one per method and status, and one per method and latency
+//! bucket, so neither serving a request nor scraping the endpoint takes a lock.
+
+#![allow(clippy::borrowed_box)]
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::core::http::{Request, Response, ResponseWriter};
+use crate::core::router::REST;
+use crate::core::stats::{self, ServerStats};
+use crate::support::PoolStats;
+
+#[cfg(feature = "session")]
+use crate::support::session::{ExchangeConfig, SessionExchangeConfig};
+
+/// Read the gauge off the stats of a pool.
+type PoolGauge = fn(&PoolStats) -> usize;
+
+/// The upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The labels of the methods, where the custom verbs are all counted as `OTHER`.
+const METHODS: [&str; 10] = [
+    "GET", "PATCH", "POST", "PUT", "DELETE", "OPTIONS", "HEAD", "TRACE", "CONNECT", "OTHER",
+];
+
+/// The statuses counted are `100` through `599`.
+const STATUS_BASE: u16 = 100;
+const STATUS_SPAN: usize = 500;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U64: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const STATUS_ROW: [AtomicUsize; STATUS_SPAN] = [ZERO; STATUS_SPAN];
+#[allow(clippy::declare_interior_mutable_const)]
+const BUCKET_ROW: [AtomicUsize; BUCKETS.len() + 1] = [ZERO; BUCKETS.len() + 1];
+
+static COLLECTOR: Collector = Collector::new();
+
+struct Collector {
+    /// The requests served, by method and status.
+    requests: [[AtomicUsize; STATUS_SPAN]; METHODS.len()],
+    /// The requests whose latency falls in each bucket, by method, where the last bucket is the
+    /// one beyond the largest bound. The counts are made cumulative when rendered.
+    buckets: [[AtomicUsize; BUCKETS.len() + 1]; METHODS.len()],
+    /// The sum of the latencies in microseconds, by method.
+    sums: [AtomicU64; METHODS.len()],
+}
+
+impl Collector {
+    const fn new() -> Self {
+        Collector {
+            requests: [STATUS_ROW; METHODS.len()],
+            buckets: [BUCKET_ROW; METHODS.len()],
+            sums: [ZERO_U64; METHODS.len()],
+        }
+    }
+
+    fn observe(&self, method: &REST, status: u16, elapsed: Duration) {
+        let m = method_slot(method);
+
+        if status >= STATUS_BASE && ((status - STATUS_BASE) as usize) < STATUS_SPAN {
+            self.requests[m][(status - STATUS_BASE) as usize].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.buckets[m][bucket_slot(elapsed)].fetch_add(1, Ordering::Relaxed);
+        self.sums[m].fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render_requests(&self, out: &mut String) {
+        out.push_str("# HELP http_requests_total The number of the requests served.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+
+        for (m, row) in self.requests.iter().enumerate() {
+            for (s, count) in row.iter().enumerate() {
+                let count = count.load(Ordering::Relaxed);
+                if count > 0 {
+                    let _ = writeln!(
+                        out,
+                        "http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                        METHODS[m],
+                        s + STATUS_BASE as usize,
+                        count
+                    );
+                }
+            }
+        }
+    }
+
+    fn render_latencies(&self, out: &mut String) {
+        out.push_str(
+            "# HELP http_request_duration_seconds The time taken by the handlers of the requests.\n",
+        );
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+
+        for (m, row) in self.buckets.iter().enumerate() {
+            let counts: Vec<usize> = row
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect();
+
+            let total: usize = counts.iter().sum();
+            if total == 0 {
+                continue;
+            }
+
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(counts.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    METHODS[m], bound, cumulative
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                METHODS[m], total
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{method=\"{}\"}} {}",
+                METHODS[m],
+                self.sums[m].load(Ordering::Relaxed) as f64 / 1_000_000f64
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{method=\"{}\"}} {}",
+                METHODS[m], total
+            );
+        }
+    }
+}
+
+/// Count the request served, along with the time its handler has taken.
+#[inline]
+pub(crate) fn observe(method: &REST, status: u16, elapsed: Duration) {
+    COLLECTOR.observe(method, status, elapsed);
+}
+
+/// Render the counters, the latencies, and the server stats in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::with_capacity(4096);
+
+    COLLECTOR.render_requests(&mut out);
+    COLLECTOR.render_latencies(&mut out);
+    render_stats(&stats::snapshot(None), &mut out);
+
+    #[cfg(feature = "session")]
+    {
+        if let Some(size) = ExchangeConfig::store_size() {
+            out.push_str("# HELP rex_session_store_size The number of the sessions stored.\n");
+            out.push_str("# TYPE rex_session_store_size gauge\n");
+            let _ = writeln!(out, "rex_session_store_size {}", size);
+        }
+    }
+
+    out
+}
+
+/// The handler of the endpoint set with `ServerConfig::enable_metrics`.
+pub(crate) fn serve(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.set_content_type("text/plain; version=0.0.4");
+    resp.send(&render());
+    resp.status(200);
+}
+
+fn render_stats(stats: &ServerStats, out: &mut String) {
+    let counters = [
+        ("rex_connections_accepted_total", stats.accepted),
+        ("rex_requests_parsed_total", stats.requests),
+    ];
+
+    for (name, value) in counters.iter() {
+        let _ = writeln!(out, "# TYPE {} counter\n{} {}", name, name, value);
+    }
+
+    out.push_str(
+        "# HELP rex_tls_handshake_failures_total The connections failed in the TLS handshake.\n",
+    );
+    out.push_str("# TYPE rex_tls_handshake_failures_total counter\n");
+    for (reason, count) in stats::tls_failures().iter() {
+        let _ = writeln!(
+            out,
+            "rex_tls_handshake_failures_total{{reason=\"{}\"}} {}",
+            reason.label(),
+            count
+        );
+    }
+
+    let pools: [(&str, Option<PoolStats>); 4] = [
+        ("request", stats.request_pool),
+        ("response", stats.response_pool),
+        ("parser", stats.parser_pool),
+        ("stream", stats.stream_pool),
+    ];
+
+    let gauges: [(&str, PoolGauge); 4] = [
+        ("rex_pool_workers", |p| p.size),
+        ("rex_pool_active_workers", |p| p.active),
+        ("rex_pool_pending_jobs", |p| p.pending),
+        ("rex_pool_under_pressure", |p| p.under_pressure as usize),
+    ];
+
+    for (name, gauge) in gauges.iter() {
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+
+        for (pool, pool_stats) in pools.iter() {
+            if let Some(p) = pool_stats {
+                let _ = writeln!(out, "{}{{pool=\"{}\"}} {}", name, pool, gauge(p));
+            }
+        }
+    }
+}
+
+fn method_slot(method: &REST) -> usize {
+    match method {
+        REST::GET => 0,
+        REST::PATCH => 1,
+        REST::POST => 2,
+        REST::PUT => 3,
+        REST::DELETE => 4,
+        REST::OPTIONS => 5,
+        REST::HEAD => 6,
+        REST::TRACE => 7,
+        REST::CONNECT => 8,
+        REST::OTHER(_) => 9,
+    }
+}
+
+fn bucket_slot(elapsed: Duration) -> usize {
+    let secs = elapsed.as_secs_f64();
+
+    BUCKETS
+        .iter()
+        .position(|bound| secs <= *bound)
+        .unwrap_or_else(|| BUCKETS.len())
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use super::{bucket_slot, Collector};
+    use crate::core::router::REST;
+    use std::time::Duration;
+
+    #[test]
+    fn latency_buckets() {
+        assert_eq!(bucket_slot(Duration::from_millis(1)), 0);
+        assert_eq!(bucket_slot(Duration::from_millis(5)), 0);
+        assert_eq!(bucket_slot(Duration::from_millis(6)), 1);
+        assert_eq!(bucket_slot(Duration::from_millis(300)), 6);
+        assert_eq!(bucket_slot(Duration::from_secs(11)), 11);
+    }
+
+    #[test]
+    fn exposition() {
+        let collector = Collector::new();
+        collector.observe(&REST::GET, 200, Duration::from_millis(2));
+        collector.observe(&REST::GET, 200, Duration::from_millis(40));
+        collector.observe(
+            &REST::OTHER(String::from("PURGE")),
+            404,
+            Duration::from_secs(20),
+        );
+
+        let mut out = String::new();
+        collector.render_requests(&mut out);
+        collector.render_latencies(&mut out);
+
+        assert!(out.contains("http_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(out.contains("http_requests_total{method=\"OTHER\",status=\"404\"} 1\n"));
+        assert!(!out.contains("method=\"POST\""));
+
+        assert!(
+            out.contains("http_request_duration_seconds_bucket{method=\"GET\",le=\"0.005\"} 1\n")
+        );
+        assert!(
+            out.contains("http_request_duration_seconds_bucket{method=\"GET\",le=\"0.05\"} 2\n")
+        );
+        assert!(
+            out.contains("http_request_duration_seconds_bucket{method=\"GET\",le=\"+Inf\"} 2\n")
+        );
+        assert!(out.contains("http_request_duration_seconds_sum{method=\"GET\"} 0.042\n"));
+        assert!(
+            out.contains("http_request_duration_seconds_bucket{method=\"OTHER\",le=\"10\"} 0\n")
+        );
+        assert!(out.contains("http_request_duration_seconds_count{method=\"OTHER\"} 1\n"));
+    }
+}
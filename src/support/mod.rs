@@ -1,18 +1,31 @@
+#[macro_use]
+pub mod debug;
+
+mod cache;
 mod scheduler;
 mod trie;
 
 #[cfg(feature = "logger")]
 pub mod logger;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 #[cfg(feature = "session")]
 pub mod session;
 
+pub mod entropy;
 pub mod locks;
 
 pub(crate) mod common;
-pub(crate) mod debug;
+pub(crate) mod span;
 pub(crate) mod shared_pool {
-    pub(crate) use crate::support::scheduler::{close, initialize_with, run};
+    pub(crate) use crate::support::scheduler::{
+        close, initialize_with, is_initialized, reserve, run, run_traced, set_event_hook,
+        set_expansion_policy, set_stack_size, stats, thread_builder, wait_idle,
+    };
 }
 
+pub(crate) use self::cache::RouteCache;
+pub use self::scheduler::{PoolEvent, PoolStats, Priority};
 pub(crate) use self::scheduler::{TaskType, ThreadPool, TimeoutPolicy};
+pub use self::span::TraceIds;
 pub(crate) use self::trie::{Field, RouteTrie};
@@ -1,24 +1,100 @@
 #![allow(dead_code)]
 
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{atomic::AtomicBool, atomic::AtomicUsize, atomic::Ordering, Arc};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::channel::{self, Receiver, RecvTimeoutError, SendTimeoutError, Sender};
+use crate::channel::{self, Receiver, RecvTimeoutError, SendTimeoutError, Sender, TrySendError};
 use crate::hashbrown::HashSet;
-use crate::parking_lot::{Mutex, Once, OnceState};
-use crate::support::debug::{self, InfoLevel};
+use crate::parking_lot::{Mutex, Once, OnceState, RwLock};
+use crate::support::span::{self, TraceIds};
 
 const CHAN_SIZE: usize = 512;
 const POOL_CAP: usize = 512;
 const POOL_INC_STEP: usize = 4;
+const POOL_IDLE_LIMIT: usize = 10;
 const RETRY_LIMIT: u8 = 64;
 const TIMEOUT: Duration = Duration::from_millis(200);
 const YIELD_DURATION: Duration = Duration::from_millis(128);
+const JOIN_POLL: Duration = Duration::from_millis(8);
+const STREAM_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
-static IS_CLOSING: AtomicBool = AtomicBool::new(false);
 static SOFT_POOL_CAP: AtomicUsize = AtomicUsize::new(POOL_CAP);
 
+/// The stack size in bytes of the threads spawned by the framework, where 0 leaves it to the
+/// platform default.
+static STACK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The priority of the job at hand on this thread, see `Priority`.
+    static PRIORITY: Cell<Priority> = Cell::new(Priority::Normal);
+}
+
+lazy_static! {
+    static ref EVENT_HOOK: RwLock<Option<fn(PoolEvent)>> = RwLock::new(None);
+}
+
+/// The events from the thread pools, which can be observed with the hook set via
+/// `ServerConfig::on_pool_event`. The `pool` field is the name of the pool where the event happens.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PoolEvent {
+    /// New workers have been added to the pool since all existing workers are busy.
+    Expanded { pool: &'static str, new_size: usize },
+    /// An expanded worker has been idle for too long and retired.
+    WorkerRetired { pool: &'static str, id: usize },
+    /// A job can't be dispatched in time since all workers are busy.
+    DispatchTimeout {
+        pool: &'static str,
+        queue_len: usize,
+    },
+}
+
+/// The counters of the events happened to a thread pool since it's created, along with the load of
+/// the pool at the moment the stats are taken.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PoolStats {
+    pub size: usize,
+    /// The number of workers reserved for the high priority jobs, not counted in the `size`.
+    pub reserved: usize,
+    pub expansions: usize,
+    pub retirements: usize,
+    pub dispatch_timeouts: usize,
+    /// The number of workers busy with a job.
+    pub active: usize,
+    /// The number of jobs either queued or being processed.
+    pub pending: usize,
+    /// If all workers have been busy for long enough that new jobs can't be dispatched in time.
+    pub under_pressure: bool,
+}
+
+#[derive(Default)]
+struct PoolCounters {
+    pending: AtomicUsize,
+    active: AtomicUsize,
+    expansions: AtomicUsize,
+    retirements: AtomicUsize,
+    dispatch_timeouts: AtomicUsize,
+}
+
+/// What an expandable worker needs to retire itself after being idle for too long.
+struct Retirement {
+    pool: &'static str,
+    idle_limit: usize,
+    grave: Arc<Mutex<HashSet<usize>>>,
+}
+
+pub(crate) fn set_event_hook(hook: Option<fn(PoolEvent)>) {
+    *EVENT_HOOK.write() = hook;
+}
+
+fn notify(event: PoolEvent) {
+    if let Some(hook) = *EVENT_HOOK.read() {
+        hook(event);
+    }
+}
+
 trait FnBox {
     fn call_box(self: Box<Self>);
 }
@@ -33,7 +109,7 @@ impl<F: FnOnce()> FnBox for F {
 type Job = Box<dyn FnBox + Send + 'static>;
 
 enum Message {
-    NewJob(Job),
+    NewJob(Job, TraceIds),
     Terminate,
 }
 
@@ -43,7 +119,40 @@ pub(crate) enum TimeoutPolicy {
     Run,
 }
 
+/// The priority of the jobs, e.g. of the connections from a listener. The high priority jobs are
+/// taken by the reserved workers of the pool if there are any, and they're queued rather than being
+/// subjected to the `TimeoutPolicy` while there's room in the queue. The work they hand over to the
+/// shared pools goes to the workers reserved there, such that it won't queue behind the others
+/// either.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Mark the jobs run on this thread with the priority until dropped.
+struct PriorityGuard(Priority);
+
+impl PriorityGuard {
+    fn enter(priority: Priority) -> Self {
+        PriorityGuard(PRIORITY.with(|p| p.replace(priority)))
+    }
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        PRIORITY.with(|p| p.set(self.0));
+    }
+}
+
 pub struct ThreadPool {
+    name: &'static str,
     workers: Vec<Worker>,
     sender: Sender<Message>,
     receiver: Receiver<Message>,
@@ -54,10 +163,31 @@ pub struct ThreadPool {
     ),
     grave: Arc<Mutex<HashSet<usize>>>,
     timeout_policy: TimeoutPolicy,
+    inc_step: usize,
+    idle_limit: usize,
+    counters: Arc<PoolCounters>,
+    next_id: AtomicUsize,
+    closing: Arc<AtomicBool>,
+    stack_size: usize,
+    /// The workers only taking the high priority jobs, see `reserve`.
+    reserved: Option<Box<ThreadPool>>,
 }
 
 impl ThreadPool {
-    pub(crate) fn new(size: usize) -> ThreadPool {
+    /// Create a pool with the `name` as its label, which is used in the pool events and to name
+    /// the worker threads, i.e. `rex-{name}-{id}`. The workers take the stack size set with
+    /// `set_stack_size`.
+    pub(crate) fn new(size: usize, name: &'static str) -> ThreadPool {
+        Self::with_stack_size(size, name, STACK_SIZE.load(Ordering::Acquire))
+    }
+
+    /// Create a pool whose workers, including the ones added on expansions, have the stack size in
+    /// bytes, or the platform default if it's 0.
+    pub(crate) fn with_stack_size(
+        size: usize,
+        name: &'static str,
+        stack_size: usize,
+    ) -> ThreadPool {
         let pool_size = match size {
             _ if size < 1 => 1,
             _ if size > POOL_CAP => POOL_CAP,
@@ -65,13 +195,24 @@ impl ThreadPool {
         };
 
         let (sender, receiver) = channel::bounded(CHAN_SIZE);
+        let counters = Arc::new(PoolCounters::default());
+        let closing = Arc::new(AtomicBool::new(false));
 
         let mut workers = Vec::with_capacity(pool_size);
         (0..pool_size).for_each(|id| {
-            workers.push(Worker::launch(id, receiver.clone(), None));
+            workers.push(Worker::launch(
+                id,
+                name,
+                receiver.clone(),
+                counters.clone(),
+                closing.clone(),
+                None,
+                stack_size,
+            ));
         });
 
         ThreadPool {
+            name,
             workers,
             sender,
             receiver,
@@ -79,6 +220,51 @@ impl ThreadPool {
             pressure_status: (None, None),
             grave: Arc::new(Mutex::new(HashSet::new())),
             timeout_policy: TimeoutPolicy::Drop,
+            inc_step: POOL_INC_STEP,
+            idle_limit: POOL_IDLE_LIMIT,
+            counters,
+            next_id: AtomicUsize::new(pool_size),
+            closing,
+            stack_size,
+            reserved: None,
+        }
+    }
+
+    /// Launch the workers reserved for the high priority jobs, named `rex-{name}-{id}`, which never
+    /// take the normal jobs, such that the high priority jobs are served even when the other
+    /// workers are all busy. The reserved workers don't expand.
+    pub(crate) fn reserve(&mut self, count: usize, name: &'static str) {
+        if count == 0 || self.reserved.is_some() {
+            return;
+        }
+
+        self.reserved = Some(Box::new(Self::with_stack_size(
+            count,
+            name,
+            self.stack_size,
+        )));
+    }
+
+    /// Set the number of workers to add on each expansion, and the number of idle ticks (each is
+    /// around 128ms) before an expanded worker retires.
+    pub(crate) fn set_expansion_policy(&mut self, step: usize, idle_limit: usize) {
+        if step > 0 {
+            self.inc_step = step;
+        }
+
+        self.idle_limit = idle_limit;
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.worker_count(),
+            reserved: self.reserved_count(),
+            expansions: self.counters.expansions.load(Ordering::Relaxed),
+            retirements: self.counters.retirements.load(Ordering::Relaxed),
+            dispatch_timeouts: self.counters.dispatch_timeouts.load(Ordering::Relaxed),
+            active: self.active_count(),
+            pending: self.pending_count(),
+            under_pressure: self.is_under_pressure(),
         }
     }
 
@@ -93,39 +279,222 @@ impl ThreadPool {
         self.timeout_policy = policy;
     }
 
+    /// If the jobs can't be dispatched in time since all workers are busy, for longer than the
+    /// threshold if one is set.
     pub(crate) fn is_under_pressure(&self) -> bool {
-        if let Some(threshold) = self.pressure_status.0 {
-            if let Some(since) = self.pressure_status.1 {
-                return since.elapsed().unwrap_or_default() > threshold;
+        match self.pressure_status {
+            (Some(threshold), Some(since)) => since.elapsed().unwrap_or_default() > threshold,
+            (None, since) => since.is_some(),
+            _ => false,
+        }
+    }
+
+    /// The number of the workers alive, not counting the retired ones which are yet to be cleaned
+    /// up from the worker list.
+    pub(crate) fn worker_count(&self) -> usize {
+        let grave = self.grave.lock();
+        self.workers
+            .iter()
+            .filter(|worker| worker.thread.is_some() && !grave.contains(&worker.id))
+            .count()
+    }
+
+    /// The number of the workers busy with a job at the moment, including the reserved ones.
+    pub(crate) fn active_count(&self) -> usize {
+        self.counters.active.load(Ordering::Acquire)
+            + self.reserved.as_ref().map_or(0, |lane| lane.active_count())
+    }
+
+    /// The number of jobs that are either queued or being processed by the workers, including the
+    /// reserved ones.
+    pub(crate) fn pending_count(&self) -> usize {
+        self.counters.pending.load(Ordering::Acquire)
+            + self
+                .reserved
+                .as_ref()
+                .map_or(0, |lane| lane.pending_count())
+    }
+
+    /// The number of the workers reserved for the high priority jobs.
+    pub(crate) fn reserved_count(&self) -> usize {
+        self.reserved.as_ref().map_or(0, |lane| lane.worker_count())
+    }
+
+    /// Block until all dispatched jobs are done, or until the timeout. Return `true` if the pool
+    /// has been drained.
+    pub(crate) fn wait_idle(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+
+        while self.pending_count() > 0 {
+            if start.elapsed() >= timeout {
+                return false;
             }
+
+            thread::sleep(Duration::from_millis(4));
         }
 
-        false
+        true
     }
 
     pub(crate) fn execute<F>(&mut self, f: F) -> u8
     where
         F: FnOnce() + Send + 'static,
     {
-        self.dispatch(Message::NewJob(Box::new(f)), 0)
+        self.execute_traced(f, TraceIds::default())
+    }
+
+    /// Same as `execute`, but the job is run on behalf of the connection or the request, and the
+    /// log lines from the job will be prefixed with the ids.
+    pub(crate) fn execute_traced<F>(&mut self, f: F, ids: TraceIds) -> u8
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.dispatch(Message::NewJob(Box::new(f), ids), 0)
+    }
+
+    /// Same as `execute`, but the high priority job goes to the reserved workers if there are any,
+    /// and it's queued without waiting if there's room, see `enqueue`.
+    pub(crate) fn execute_prioritized<F>(&mut self, f: F, priority: Priority) -> u8
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_prioritized_traced(f, priority, TraceIds::default())
+    }
+
+    /// Same as `execute_prioritized`, but the job is run on behalf of the connection or the request,
+    /// see `execute_traced`.
+    pub(crate) fn execute_prioritized_traced<F>(
+        &mut self,
+        f: F,
+        priority: Priority,
+        ids: TraceIds,
+    ) -> u8
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if priority == Priority::Normal {
+            return self.execute_traced(f, ids);
+        }
+
+        let job = move || {
+            let _priority = PriorityGuard::enter(Priority::High);
+            f()
+        };
+
+        let message = Message::NewJob(Box::new(job), ids);
+        match self.reserved.as_mut() {
+            Some(lane) => lane.enqueue(message),
+            None => self.enqueue(message),
+        }
+    }
+
+    /// Whether the queue taking the jobs of the priority is full, i.e. the high priority jobs would
+    /// have to wait for the reserved workers, or the others if there're none, to catch up.
+    pub(crate) fn is_backed_up(&self, priority: Priority) -> bool {
+        match (priority, self.reserved.as_ref()) {
+            (Priority::High, Some(lane)) => lane.sender.is_full(),
+            _ => self.sender.is_full(),
+        }
     }
 
+    /// Stop the workers once they're done with the jobs at hand, and drop the jobs still queued.
     pub(crate) fn close(&mut self) {
-        let sent = self.sender.send(Message::Terminate).is_ok();
+        self.shutdown(false);
+    }
+
+    /// Stop the workers and wait for them to quit. With `drain`, the jobs already queued are all
+    /// done first; otherwise, each worker quits after the job at hand, and the queued jobs are
+    /// dropped. Either way, every worker gets its own terminate message, and closing the pool
+    /// won't affect the other pools.
+    pub(crate) fn shutdown(&mut self, drain: bool) {
+        self.retire(drain, None);
+    }
+
+    /// Close the pool like `close`, but only wait for the workers until the timeout. The workers
+    /// still stuck in a job by then are detached, and will quit on their own once the job is done.
+    /// Return `true` if all workers have quit.
+    pub(crate) fn close_within(&mut self, timeout: Duration) -> bool {
+        self.retire(false, Some(Instant::now() + timeout))
+    }
+
+    fn retire(&mut self, drain: bool, deadline: Option<Instant>) -> bool {
+        let mut all_quit = match self.reserved.take() {
+            Some(mut lane) => lane.retire(drain, deadline),
+            None => true,
+        };
+
+        if self.workers.is_empty() {
+            return all_quit;
+        }
+
+        if !drain {
+            self.closing.store(true, Ordering::Release);
+        }
+
+        // the terminate messages are queued behind the jobs, so when draining, a worker only gets
+        // one after all the jobs have been picked up; the retired workers won't take theirs.
+        for _ in 0..self.worker_count() {
+            let sent = if drain {
+                self.sender.send(Message::Terminate).is_ok()
+            } else {
+                // the queue could be full, and the closing flag will stop the workers anyway
+                self.sender.try_send(Message::Terminate).is_ok()
+            };
+
+            if !sent {
+                break;
+            }
+        }
 
         for mut worker in self.workers.drain(..) {
-            if let Some(t) = worker.thread.take() {
-                if sent {
-                    // only sync join the threads if channel has not been closed; otherwise, it's
-                    // possible that the worker may never receive the shutdown message and quit the
-                    // infinite-loop.
-                    t.join().unwrap_or_else(|err| {
-                        debug::print(
-                            &format!("Failed to retire worker: {}, error: {:?}", worker.id, err),
-                            InfoLevel::Error,
-                        )
-                    });
+            let t = match worker.thread.take() {
+                Some(t) => t,
+                None => continue,
+            };
+
+            if let Some(deadline) = deadline {
+                while !t.is_finished() && Instant::now() < deadline {
+                    thread::sleep(JOIN_POLL);
                 }
+
+                if !t.is_finished() {
+                    // dropping the handle detaches the worker
+                    rex_warn!(
+                        "Worker {} of the {} pool is still busy, detaching it",
+                        worker.id,
+                        self.name
+                    );
+
+                    all_quit = false;
+                    continue;
+                }
+            }
+
+            t.join().unwrap_or_else(|err| {
+                rex_error!("Failed to retire worker: {}, error: {:?}", worker.id, err)
+            });
+        }
+
+        self.grave.lock().clear();
+        all_quit
+    }
+
+    /// Queue the job right away if there's room, or else dispatch it as usual, such that the caller
+    /// won't be blocked for longer than the `TimeoutPolicy` allows.
+    fn enqueue(&mut self, message: Message) -> u8 {
+        self.counters.pending.fetch_add(1, Ordering::AcqRel);
+
+        match self.sender.try_send(message) {
+            Ok(()) => 0,
+            Err(TrySendError::Full(message)) => {
+                self.counters.pending.fetch_sub(1, Ordering::AcqRel);
+                self.dispatch(message, 0)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                rex_error!("Unable to distribute the job: workers have been dropped");
+
+                self.counters.pending.fetch_sub(1, Ordering::AcqRel);
+                1
             }
         }
     }
@@ -133,27 +502,37 @@ impl ThreadPool {
     fn dispatch(&mut self, message: Message, mut retry: u8) -> u8 {
         let mut retry_message = message;
 
+        // count the job before it's sent, such that the worker can't finish it before we do
+        self.counters.pending.fetch_add(1, Ordering::AcqRel);
+
         while retry < RETRY_LIMIT {
             match self
                 .sender
                 .send_timeout(retry_message, Duration::from_millis(1))
             {
                 Ok(()) => {
-                    // if we care about under-pressure dropping, then reset the timer
-                    if self.pressure_status.0.is_some() {
-                        self.pressure_status.1 = None;
-                    }
+                    // the job is taken, so reset the busy timer
+                    self.pressure_status.1 = None;
 
                     return 0;
                 }
                 Err(SendTimeoutError::Timeout(msg)) => {
-                    debug::print(
-                            "Unable to distribute the job: execution timed out, all workers are busy for too long",
-                            InfoLevel::Warning
-                        );
+                    rex_warn!("Unable to distribute the job: execution timed out, all workers are busy for too long");
+
+                    // only report the first timeout of the job
+                    if retry == 0 {
+                        self.counters
+                            .dispatch_timeouts
+                            .fetch_add(1, Ordering::Relaxed);
+
+                        notify(PoolEvent::DispatchTimeout {
+                            pool: self.name,
+                            queue_len: self.sender.len(),
+                        });
+                    }
 
                     // set the busy_since timer
-                    if self.pressure_status.0.is_some() && self.pressure_status.1.is_none() {
+                    if self.pressure_status.1.is_none() {
                         self.pressure_status.1 = Some(SystemTime::now());
                     }
 
@@ -167,19 +546,21 @@ impl ThreadPool {
                     retry += 1;
                 }
                 Err(SendTimeoutError::Disconnected(_)) => {
-                    debug::print(
-                        "Unable to distribute the job: workers have been dropped: {}",
-                        InfoLevel::Error,
-                    );
+                    rex_error!("Unable to distribute the job: workers have been dropped");
 
+                    self.counters.pending.fetch_sub(1, Ordering::AcqRel);
                     return 1;
                 }
             };
         }
 
+        // the job won't be picked up by the workers
+        self.counters.pending.fetch_sub(1, Ordering::AcqRel);
+
         // timeout after waiting at least 64ms without being able to send the message
         if self.timeout_policy == TimeoutPolicy::Run {
-            if let Message::NewJob(job) = retry_message {
+            if let Message::NewJob(job, ids) = retry_message {
+                let _span = span::enter(ids);
                 job.call_box();
                 return 1;
             }
@@ -189,7 +570,7 @@ impl ThreadPool {
     }
 
     fn expand(&mut self) {
-        if self.auto_expansion && self.workers.len() + POOL_INC_STEP < POOL_CAP {
+        if self.auto_expansion && self.workers.len() + self.inc_step < POOL_CAP {
             // clean up died workers
             {
                 let mut g = self.grave.lock();
@@ -200,25 +581,39 @@ impl ThreadPool {
                 g.clear();
             }
 
-            // then expand with new workers
-            let start = self.workers[self.workers.len() - 1].id;
-            (0..POOL_INC_STEP).for_each(|id| {
+            // then expand with new workers, whose ids are never reused, or a retired worker's id in
+            // the grave could take a live worker down with it in the next clean up
+            let start = self.next_id.fetch_add(self.inc_step, Ordering::AcqRel);
+            (0..self.inc_step).for_each(|id| {
+                let retirement = Retirement {
+                    pool: self.name,
+                    idle_limit: self.idle_limit,
+                    grave: self.grave.clone(),
+                };
+
                 self.workers.push(Worker::launch(
                     start + id,
+                    self.name,
                     self.receiver.clone(),
-                    Some(self.grave.clone()),
+                    self.counters.clone(),
+                    self.closing.clone(),
+                    Some(retirement),
+                    self.stack_size,
                 ));
             });
+
+            self.counters.expansions.fetch_add(1, Ordering::Relaxed);
+            notify(PoolEvent::Expanded {
+                pool: self.name,
+                new_size: self.workers.len(),
+            });
         }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        debug::print(
-            "Job done, sending terminate message to all workers.",
-            InfoLevel::Info,
-        );
+        rex_debug!("Job done, sending terminate message to all workers.");
         self.close();
     }
 }
@@ -231,15 +626,24 @@ struct Worker {
 impl Worker {
     fn launch(
         id: usize,
+        pool: &'static str,
         work_queue: Receiver<Message>,
-        grave: Option<Arc<Mutex<HashSet<usize>>>>,
+        counters: Arc<PoolCounters>,
+        closing: Arc<AtomicBool>,
+        retirement: Option<Retirement>,
+        stack_size: usize,
     ) -> Worker {
-        let thread = thread::spawn(move || {
+        let mut builder = thread::Builder::new().name(format!("rex-{}-{}", pool, id));
+        if stack_size > 0 {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let thread = builder.spawn(move || {
             let mut idle_counter = 0;
             let mut message: Result<Message, RecvTimeoutError>;
 
             loop {
-                if IS_CLOSING.load(Ordering::Relaxed) {
+                if closing.load(Ordering::Acquire) {
                     return;
                 }
 
@@ -247,37 +651,57 @@ impl Worker {
 
                 if let Ok(message) = message {
                     match message {
-                        Message::NewJob(job) => {
-                            // process the work
-                            job.call_box();
+                        Message::NewJob(_, _) if closing.load(Ordering::Acquire) => {
+                            // the pool is closing, the queued jobs are dropped
+                            counters.pending.fetch_sub(1, Ordering::AcqRel);
+                            return;
+                        }
+                        Message::NewJob(job, ids) => {
+                            // process the work, and keep the worker alive if the job panics
+                            let _span = span::enter(ids);
+                            counters.active.fetch_add(1, Ordering::AcqRel);
+                            if panic::catch_unwind(AssertUnwindSafe(|| job.call_box())).is_err() {
+                                rex_error!(
+                                    "Job panicked in the worker thread {} of the {} pool",
+                                    thread::current().name().unwrap_or("unnamed"),
+                                    pool
+                                );
+                            }
+
+                            counters.active.fetch_sub(1, Ordering::AcqRel);
+                            counters.pending.fetch_sub(1, Ordering::AcqRel);
 
                             // give 2 more idle chances on every work processed
                             if idle_counter > 1 {
                                 idle_counter -= 2;
                             }
                         }
-                        Message::Terminate => {
-                            IS_CLOSING.store(true, Ordering::Release);
-                            return;
-                        }
+                        Message::Terminate => return,
                     }
-                } else if let Some(g) = grave.as_ref() {
-                    if idle_counter < 10 {
+                } else if let Some(r) = retirement.as_ref() {
+                    if idle_counter < r.idle_limit {
                         // addition of the idle counts, quit after being idle for around 1 sec.
                         idle_counter += 1;
                     } else {
                         // if an expandable worker, kill it.
-                        g.lock().insert(id);
+                        r.grave.lock().insert(id);
+                        counters.retirements.fetch_add(1, Ordering::Relaxed);
+                        notify(PoolEvent::WorkerRetired { pool: r.pool, id });
                         return;
                     }
                 }
             }
         });
 
-        Worker {
-            id,
-            thread: Some(thread),
-        }
+        let thread = match thread {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                rex_error!("Unable to launch worker {} of the {} pool: {}", id, pool, e);
+                None
+            }
+        };
+
+        Worker { id, thread }
     }
 }
 
@@ -286,10 +710,7 @@ impl Drop for Worker {
         if let Some(thread) = self.thread.take() {
             // make sure the work is done
             thread.join().unwrap_or_else(|err| {
-                debug::print(
-                    &format!("Unable to drop worker: {}, error: {:?}", self.id, err),
-                    InfoLevel::Error,
-                );
+                rex_error!("Unable to drop worker: {}, error: {:?}", self.id, err);
             });
         }
     }
@@ -312,7 +733,14 @@ pub enum TaskType {
 static ONCE: Once = Once::new();
 static mut POOL: Option<Pool> = None;
 
-pub(crate) fn initialize_with(sizes: Vec<usize>) {
+/// If the shared pools have been initialized in this process, which is only allowed once.
+pub(crate) fn is_initialized() -> bool {
+    ONCE.state() != OnceState::New
+}
+
+/// Initialize the shared pools with the sizes, and return the error if any of the pools has no
+/// worker launched, e.g. the threads can't be spawned with the stack size.
+pub(crate) fn initialize_with(sizes: Vec<usize>) -> Result<(), String> {
     assert_eq!(
         ONCE.state(),
         OnceState::New,
@@ -335,10 +763,10 @@ pub(crate) fn initialize_with(sizes: Vec<usize>) {
         };
 
         let mut pool = Pool {
-            req_workers: ThreadPool::new(worker_size),
-            resp_workers: ThreadPool::new(worker_size),
-            parser_workers: ThreadPool::new(parser_size),
-            stream_workers: ThreadPool::new(parser_size),
+            req_workers: ThreadPool::new(worker_size, "request"),
+            resp_workers: ThreadPool::new(worker_size, "response"),
+            parser_workers: ThreadPool::new(parser_size, "parser"),
+            stream_workers: ThreadPool::new(parser_size, "stream"),
         };
 
         pool.resp_workers
@@ -349,35 +777,517 @@ pub(crate) fn initialize_with(sizes: Vec<usize>) {
             POOL.replace(pool);
         }
     });
+
+    let launched = unsafe {
+        POOL.as_ref().map_or(false, |pool| {
+            [
+                &pool.req_workers,
+                &pool.resp_workers,
+                &pool.parser_workers,
+                &pool.stream_workers,
+            ]
+            .iter()
+            .all(|workers| workers.worker_count() > 0)
+        })
+    };
+
+    if launched {
+        Ok(())
+    } else {
+        close();
+        Err(String::from("no worker of the shared pools is launched"))
+    }
 }
 
 pub(crate) fn run<F>(f: F, task: TaskType)
 where
     F: FnOnce() + Send + 'static,
 {
+    run_traced(f, task, TraceIds::default());
+}
+
+/// Same as `run`, but the job is run on behalf of the connection or the request, see `TraceIds`.
+/// The work of a high priority job goes to the reserved workers of the pool, see `reserve`.
+pub(crate) fn run_traced<F>(f: F, task: TaskType, ids: TraceIds)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let priority = PRIORITY.with(|p| p.get());
+
     unsafe {
         if let Some(ref mut pool) = POOL {
             // if pool has been created
             match task {
-                TaskType::Request => pool.req_workers.execute(f),
-                TaskType::Response => pool.resp_workers.execute(f),
-                TaskType::Parser => pool.parser_workers.execute(f),
-                TaskType::StreamLoader => pool.stream_workers.execute(f),
+                TaskType::Request => pool
+                    .req_workers
+                    .execute_prioritized_traced(f, priority, ids),
+                TaskType::Response => pool
+                    .resp_workers
+                    .execute_prioritized_traced(f, priority, ids),
+                TaskType::Parser => pool
+                    .parser_workers
+                    .execute_prioritized_traced(f, priority, ids),
+                TaskType::StreamLoader => pool
+                    .stream_workers
+                    .execute_prioritized_traced(f, priority, ids),
             };
 
             return;
         }
 
         // otherwise, spawn to a new thread for the work;
-        thread::spawn(f);
+        let spawned = thread_builder(String::from("rex-job")).spawn(move || {
+            let _priority = PriorityGuard::enter(priority);
+            let _span = span::enter(ids);
+            f()
+        });
+
+        if let Err(e) = spawned {
+            rex_error!("Unable to launch the thread for the job: {}", e);
+        }
+    }
+}
+
+/// Set the stack size in bytes of the threads spawned by the framework from now on, i.e. the pools
+/// created afterwards and the threads built with `thread_builder`, where 0 leaves it to the platform.
+pub(crate) fn set_stack_size(bytes: usize) {
+    STACK_SIZE.store(bytes, Ordering::Release);
+}
+
+/// The builder of a long-lived framework thread, with the stack size set with `set_stack_size`.
+pub(crate) fn thread_builder(name: String) -> thread::Builder {
+    let builder = thread::Builder::new().name(name);
+
+    match STACK_SIZE.load(Ordering::Acquire) {
+        0 => builder,
+        size => builder.stack_size(size),
+    }
+}
+
+/// Reserve the workers in each of the shared pools for the work of the high priority jobs, see
+/// `ThreadPool::reserve`.
+pub(crate) fn reserve(count: usize) {
+    unsafe {
+        if let Some(ref mut pool) = POOL {
+            pool.req_workers.reserve(count, "request-priority");
+            pool.resp_workers.reserve(count, "response-priority");
+            pool.parser_workers.reserve(count, "parser-priority");
+            pool.stream_workers.reserve(count, "stream-priority");
+        }
+    }
+}
+
+pub(crate) fn set_expansion_policy(step: usize, idle_limit: usize) {
+    unsafe {
+        if let Some(ref mut pool) = POOL {
+            pool.req_workers.set_expansion_policy(step, idle_limit);
+            pool.resp_workers.set_expansion_policy(step, idle_limit);
+            pool.parser_workers.set_expansion_policy(step, idle_limit);
+            pool.stream_workers.set_expansion_policy(step, idle_limit);
+        }
+    }
+}
+
+pub(crate) fn stats(task: TaskType) -> Option<PoolStats> {
+    unsafe {
+        POOL.as_ref().map(|pool| match task {
+            TaskType::Request => pool.req_workers.stats(),
+            TaskType::Response => pool.resp_workers.stats(),
+            TaskType::Parser => pool.parser_workers.stats(),
+            TaskType::StreamLoader => pool.stream_workers.stats(),
+        })
+    }
+}
+
+/// Block until all shared pools are drained, or until the timeout. Return `true` if all pools have
+/// been drained.
+pub(crate) fn wait_idle(timeout: Duration) -> bool {
+    let start = Instant::now();
+
+    unsafe {
+        if let Some(ref pool) = POOL {
+            return [
+                &pool.stream_workers,
+                &pool.parser_workers,
+                &pool.req_workers,
+                &pool.resp_workers,
+            ]
+            .iter()
+            .all(|workers| {
+                workers.wait_idle(timeout.checked_sub(start.elapsed()).unwrap_or_default())
+            });
+        }
     }
+
+    true
 }
 
-pub(crate) fn close() {
+/// Close all shared pools, in the order of the pipeline stages, see `ThreadPool::close`. The stream
+/// loaders could be blocked on reading a socket, so they're only waited for `STREAM_CLOSE_TIMEOUT`
+/// before being detached. Return `false` if any worker is detached.
+pub(crate) fn close() -> bool {
     unsafe {
         if let Some(mut pool) = POOL.take() {
+            let all_quit = pool.stream_workers.close_within(STREAM_CLOSE_TIMEOUT);
+            pool.parser_workers.close();
             pool.req_workers.close();
             pool.resp_workers.close();
+
+            return all_quit;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod scheduler_test {
+    use super::{set_event_hook, PoolEvent, Priority, ThreadPool, CHAN_SIZE, YIELD_DURATION};
+    use crate::channel;
+    use crate::parking_lot::Mutex;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    lazy_static! {
+        static ref EVENTS: Mutex<Vec<PoolEvent>> = Mutex::new(Vec::new());
+    }
+
+    fn record(event: PoolEvent) {
+        EVENTS.lock().push(event);
+    }
+
+    #[test]
+    fn pool_wait_idle() {
+        let pool = {
+            let mut pool = ThreadPool::new(2, "idle");
+            pool.execute(|| thread::sleep(Duration::from_millis(64)));
+            pool
+        };
+
+        assert_eq!(pool.pending_count(), 1);
+        assert!(!pool.wait_idle(Duration::from_millis(1)));
+        assert!(pool.wait_idle(Duration::from_secs(2)));
+        assert_eq!(pool.pending_count(), 0);
+    }
+
+    #[test]
+    fn pool_close_within() {
+        let (tx, rx) = channel::bounded::<()>(1);
+        let (started_tx, started_rx) = channel::bounded(1);
+
+        let mut pool = ThreadPool::new(2, "stuck");
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+
+            // stuck, until the test releases it
+            rx.recv().unwrap_or_default();
+        });
+
+        started_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+
+        // the idle worker quits, the stuck one is detached
+        let start = Instant::now();
+        assert!(!pool.close_within(Duration::from_millis(64)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(pool.worker_count(), 0);
+
+        tx.send(()).unwrap();
+
+        let mut pool = ThreadPool::new(2, "free");
+        pool.execute(|| thread::sleep(Duration::from_millis(16)));
+        assert!(pool.close_within(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn pool_worker_names() {
+        let mut pool = ThreadPool::new(2, "named");
+        let (tx, rx) = channel::unbounded();
+
+        for _ in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                let name = thread::current().name().map(String::from);
+                tx.send(name).unwrap_or_default();
+            });
         }
+
+        drop(tx);
+        let names: Vec<Option<String>> = (0..4)
+            .filter_map(|_| rx.recv_timeout(Duration::from_secs(2)).ok())
+            .collect();
+
+        drop(pool);
+
+        assert_eq!(names.len(), 4);
+        assert!(names.iter().all(|name| match name {
+            Some(name) => name == "rex-named-0" || name == "rex-named-1",
+            None => false,
+        }));
+    }
+
+    #[test]
+    fn pool_expansion_events() {
+        set_event_hook(Some(record));
+
+        let mut pool = ThreadPool::new(1, "tiny");
+        pool.toggle_auto_expansion(true, None);
+        pool.set_expansion_policy(3, 10);
+
+        // saturate the only worker and the job queue, such that the next dispatch times out
+        let (tx, rx) = channel::unbounded::<()>();
+        for _ in 0..=CHAN_SIZE + 1 {
+            let rx = rx.clone();
+            pool.execute(move || {
+                rx.recv().unwrap_or_default();
+            });
+        }
+
+        let stats = pool.stats();
+        drop(tx);
+        set_event_hook(None);
+
+        drop(pool);
+
+        assert!(stats.expansions > 0);
+        assert!(stats.dispatch_timeouts > 0);
+        assert_eq!(stats.size, 1 + 3 * stats.expansions);
+
+        let events = EVENTS.lock();
+        let expanded: Vec<usize> = events
+            .iter()
+            .filter_map(|event| match event {
+                PoolEvent::Expanded {
+                    pool: "tiny",
+                    new_size,
+                } => Some(*new_size),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(expanded.first(), Some(&4));
+        assert!(expanded.iter().all(|size| (size - 1) % 3 == 0));
+        assert!(events.iter().any(|event| match event {
+            PoolEvent::DispatchTimeout { pool: "tiny", .. } => true,
+            _ => false,
+        }));
+    }
+
+    /// Recurse with 1KB on the stack for each level.
+    fn recurse(depth: usize) -> usize {
+        let frame = std::hint::black_box([depth as u8; 1024]);
+        if depth == 0 {
+            return 0;
+        }
+
+        recurse(depth - 1) + frame[1023] as usize % 2
+    }
+
+    #[test]
+    fn pool_stack_size() {
+        // ~6MB deep, beyond the 2MB default of the spawned threads
+        const DEPTH: usize = 6 * 1024;
+
+        let mut pool = ThreadPool::with_stack_size(1, "stack", 32 * 1024 * 1024);
+        pool.toggle_auto_expansion(true, None);
+
+        // saturate the worker and the queue, such that the expanded workers run some of the jobs
+        let (gate_tx, gate_rx) = channel::unbounded::<()>();
+        let (done_tx, done_rx) = channel::unbounded();
+        let jobs = CHAN_SIZE + 2;
+
+        for _ in 0..jobs {
+            let (gate_rx, done_tx) = (gate_rx.clone(), done_tx.clone());
+            pool.execute(move || {
+                gate_rx.recv().unwrap_or_default();
+                done_tx.send(recurse(DEPTH)).unwrap_or_default();
+            });
+        }
+
+        assert!(pool.worker_count() > 1);
+        drop(gate_tx);
+
+        let done = (0..jobs)
+            .filter_map(|_| done_rx.recv_timeout(Duration::from_secs(10)).ok())
+            .count();
+
+        assert_eq!(done, jobs);
+    }
+
+    #[test]
+    fn pool_expansion_cycles() {
+        let mut pool = ThreadPool::new(1, "cycles");
+        pool.toggle_auto_expansion(true, None);
+        pool.set_expansion_policy(2, 1);
+
+        for _ in 0..3 {
+            // saturate the worker and the queue to force the expansion
+            let (tx, rx) = channel::unbounded::<()>();
+            for _ in 0..=CHAN_SIZE + 1 {
+                let rx = rx.clone();
+                pool.execute(move || {
+                    rx.recv().unwrap_or_default();
+                });
+            }
+
+            assert!(pool.worker_count() > 1);
+            drop(tx);
+            assert!(pool.wait_idle(Duration::from_secs(5)));
+            assert_eq!(pool.active_count(), 0);
+
+            // then let the expanded workers idle out
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while pool.worker_count() > 1 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(16));
+            }
+
+            assert_eq!(pool.worker_count(), 1);
+        }
+
+        // the ids are never reused, and the original worker survived all the clean ups
+        let mut ids: Vec<usize> = pool.workers.iter().map(|worker| worker.id).collect();
+        let len = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), len);
+        assert_eq!(ids.first(), Some(&0));
+        assert!(pool.workers[0].thread.is_some());
+    }
+
+    #[test]
+    fn pool_close_drained() {
+        let mut pool = ThreadPool::new(2, "drained");
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..100 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // no job is lost on the graceful close
+        pool.shutdown(true);
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+        assert_eq!(pool.pending_count(), 0);
+        assert_eq!(pool.worker_count(), 0);
+    }
+
+    #[test]
+    fn pool_close_bounded() {
+        let mut pool = ThreadPool::new(2, "aborted");
+        let mut other = ThreadPool::new(1, "bystander");
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..200 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // the workers quit after the jobs at hand, regardless of the queue depth
+        let start = Instant::now();
+        pool.close();
+        assert!(start.elapsed() < 2 * YIELD_DURATION + Duration::from_millis(100));
+        assert!(done.load(Ordering::SeqCst) < 200);
+
+        // and the other pools carry on
+        let (tx, rx) = channel::bounded(1);
+        other.execute(move || tx.send(()).unwrap_or_default());
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn pool_reserved_workers() {
+        let mut pool = ThreadPool::new(1, "public");
+        pool.reserve(1, "admin");
+        assert_eq!(pool.stats().reserved, 1);
+
+        // the only normal worker is stuck, with more normal jobs queued behind
+        let (release_tx, release_rx) = channel::bounded::<()>(1);
+        pool.execute(move || release_rx.recv().unwrap_or_default());
+
+        let (tx, rx) = channel::unbounded();
+        for _ in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                let name = thread::current().name().map(String::from);
+                tx.send(name).unwrap_or_default();
+            });
+        }
+
+        // the high priority job is taken by the reserved worker right away
+        let high = tx.clone();
+        pool.execute_prioritized(
+            move || {
+                let name = thread::current().name().map(String::from);
+                high.send(name).unwrap_or_default();
+            },
+            Priority::High,
+        );
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(2)),
+            Ok(Some(String::from("rex-admin-0")))
+        );
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(pool.pending_count(), 5);
+
+        // the normal jobs are never taken by the reserved worker
+        release_tx.send(()).unwrap();
+        for _ in 0..4 {
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(2)),
+                Ok(Some(String::from("rex-public-0")))
+            );
+        }
+
+        assert!(pool.wait_idle(Duration::from_secs(2)));
+        pool.close();
+        assert_eq!(pool.stats().reserved, 0);
+    }
+
+    #[test]
+    fn pool_reserved_backed_up() {
+        let mut pool = ThreadPool::new(1, "open");
+        pool.reserve(1, "lane");
+        assert!(!pool.is_backed_up(Priority::High));
+
+        // the reserved worker is stuck, and its queue fills up
+        let (release_tx, release_rx) = channel::bounded::<()>(1);
+        pool.execute_prioritized(
+            move || release_rx.recv().unwrap_or_default(),
+            Priority::High,
+        );
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..CHAN_SIZE {
+            let done = done.clone();
+            pool.execute_prioritized(
+                move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                },
+                Priority::High,
+            );
+        }
+
+        assert!(pool.is_backed_up(Priority::High));
+        assert!(!pool.is_backed_up(Priority::Normal));
+
+        // one more job won't block the caller for long, it's dropped per the timeout policy
+        let start = Instant::now();
+        pool.execute_prioritized(|| {}, Priority::High);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        release_tx.send(()).unwrap();
+        assert!(pool.wait_idle(Duration::from_secs(2)));
+        assert_eq!(done.load(Ordering::SeqCst), CHAN_SIZE);
+        pool.close();
     }
 }
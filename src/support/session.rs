@@ -6,6 +6,22 @@
 //! must also implement the `SessionData` trait. This trait will give the framework the ability to
 //! store the session data to a local file, then retrieve them when rebooting or reloading the server.
 //!
+//! To carry the session with a cookie, find the session of the client with `Session::from_request`,
+//! and set the session cookie to the response with `Session::attach_to`:
+//! ```
+//! use rusty_express::prelude::*;
+//!
+//! pub fn login(req: &Box<Request>, resp: &mut Box<Response>) {
+//!     let session = Session::from_request(req).or_else(Session::create_new).unwrap();
+//!     session.attach_to(resp);
+//!     resp.send("Welcome!");
+//! }
+//! ```
+//!
+//! The sessions are kept in the memory by default. To keep them somewhere else, e.g. in Redis or a
+//! database, implement the `SessionBackend` trait and install it with `Session::set_backend` when
+//! the server starts.
+//!
 //! You will find more details in the `SessionData` trait, but here's a simple example of it:
 //! # Examples
 //! ```
@@ -56,43 +72,60 @@
 //! ```
 
 use std::cmp::Ordering;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::marker::Sized;
 use std::ops::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str;
 use std::sync::atomic;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::thread;
-use std::thread::*;
+use std::thread::JoinHandle;
 use std::time::{Duration, SystemTime};
 
-use crate::channel::{self, Receiver, Sender};
 use crate::chrono::{self, prelude::*};
+use crate::core::cookie::{Cookie, SameSite};
+use crate::core::http::{Request, Response, ResponseWriter};
 use crate::hashbrown::HashMap;
 use crate::parking_lot::RwLock;
-use crate::rand::{thread_rng, Rng};
-use crate::support::ThreadPool;
+use crate::support::entropy;
 
 const DELEM_LV_1: char = '\u{0005}';
 const DELEM_LV_2: char = '\u{0006}';
+const STORE_MAGIC: &[u8] = b"RUSTY-SESSION-STORE-V1\n";
+const STORE_MAGIC_BIN: &[u8] = b"RUSTY-SESSION-STORE-B1\n";
+const RECORD_HEADER_LEN: usize = 16;
+const BIN_RECORD_HEADER_LEN: usize = 8;
+const FLAG_AUTO_RENEWAL: u8 = 0b0000_0001;
+const SESSION_COOKIE_NAME: &str = "RUSTY_SESSION";
 
 lazy_static! {
-    static ref STORE: RwLock<HashMap<String, Session>> = RwLock::new(HashMap::new());
+    static ref BACKEND: RwLock<Box<dyn SessionBackend + Send + Sync>> =
+        RwLock::new(Box::new(MemoryBackend::new()));
     static ref DEFAULT_LIFETIME: RwLock<Duration> = RwLock::new(Duration::from_secs(172_800));
+    static ref EVICTION_HOOK: RwLock<Option<fn(&str)>> = RwLock::new(None);
+    static ref SESSION_COOKIE: RwLock<(String, CookieOptions)> =
+        RwLock::new((String::from(SESSION_COOKIE_NAME), CookieOptions::default()));
 }
 
 static AUTO_CLEAN: AtomicBool = AtomicBool::new(false);
+static MAX_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+static MAX_STORE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static EVICTED: AtomicUsize = AtomicUsize::new(0);
+static BINARY_STORE: AtomicBool = AtomicBool::new(false);
 
 /// SessionData is the trait that must be implemented for storing the session related information into
 /// the session store service provided by this module. The 'serialize' function is used to destruct the
-/// session object for persistent storage (currently only supporting plain text file); the 'deserialize'
+/// session object for persistent storage (in either of the `StoreFormat`s); the 'deserialize'
 /// serves as the constructor based on the saved info from the saved info.
 pub trait SessionData {
     /// 'serialize' should be implemented to convert all session data that shall be persistent between
     /// http requests or connections.
     /// Note: ASCII characters \u{0005} and \u{0006} are reserved delimiters, please avoid
-    /// using these characters in your output string.
+    /// using these characters in your output string, unless the store is saved in the
+    /// `StoreFormat::Binary` format.
     fn serialize(&self) -> String;
 
     /// 'deserialize' should be implemented to construct the session object based on the given string,
@@ -105,6 +138,169 @@ pub trait SessionData {
         Self: Sized;
 }
 
+/// The storage of the sessions. By default the sessions are kept in the memory with the
+/// `MemoryBackend`, and a different backend, e.g. one backed by a database, can be installed with
+/// `Session::set_backend` when the server starts.
+///
+/// The backend is shared by all the connections, so it shall take care of the synchronization.
+pub trait SessionBackend {
+    /// Get the session with the id, or `None` if the session doesn't exist.
+    fn load(&self, id: &str) -> Option<Session>;
+
+    /// Insert the session, or replace the existing one with the same id.
+    fn store(&self, session: Session);
+
+    /// Remove the session with the id from the storage.
+    fn remove(&self, id: &str);
+
+    /// Get the ids of the sessions which expire no later than the given time.
+    fn scan_expired(&self, before: DateTime<Utc>) -> Vec<String>;
+
+    /// The number of sessions in the storage, if the backend can tell it.
+    fn size(&self) -> Option<usize> {
+        None
+    }
+
+    /// The approximate bytes used by the sessions in the storage, i.e. the sum of the id and the
+    /// data lengths, if the backend can tell it.
+    fn bytes(&self) -> Option<usize> {
+        None
+    }
+
+    /// All the sessions in the storage, which are saved to the file by
+    /// `PersistHandler::save_to_file`. Backends which persist the sessions on their own can leave
+    /// this out.
+    fn snapshot(&self) -> Vec<Session> {
+        Vec::new()
+    }
+
+    /// Remove and return the session closest to expiry, other than the one with the `keep` id, to
+    /// make room when the storage goes over the caps. The default picks it from the `snapshot`,
+    /// and backends which keep the sessions ordered by the expiry can take it off the head.
+    fn evict_first(&self, keep: &str) -> Option<Session> {
+        let session = self
+            .snapshot()
+            .into_iter()
+            .filter(|session| session.id != keep)
+            .min_by(|a, b| a.expires_at.cmp(&b.expires_at))?;
+
+        self.remove(&session.id);
+        Some(session)
+    }
+}
+
+/// The default session backend, which keeps the sessions in the memory.
+#[derive(Default)]
+pub struct MemoryBackend {
+    store: RwLock<MemoryStore>,
+    bytes: AtomicUsize,
+}
+
+/// The sessions, and their ids ordered by the expiry, such that the expired sessions and the ones
+/// to evict are found from the head of the index, without going over the whole store.
+#[derive(Default)]
+struct MemoryStore {
+    sessions: HashMap<String, Session>,
+    expiry: BTreeSet<(DateTime<Utc>, String)>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl SessionBackend for MemoryBackend {
+    fn load(&self, id: &str) -> Option<Session> {
+        self.store.read().sessions.get(id).cloned()
+    }
+
+    fn store(&self, session: Session) {
+        let mut store = self.store.write();
+        self.bytes
+            .fetch_add(session.approx_size(), atomic::Ordering::Relaxed);
+
+        let key = (session.expires_at, session.id.to_owned());
+
+        //if key already exists, override to protect session scanning
+        if let Some(old) = store.sessions.insert(session.id.to_owned(), session) {
+            self.bytes
+                .fetch_sub(old.approx_size(), atomic::Ordering::Relaxed);
+            store.expiry.remove(&(old.expires_at, old.id.to_owned()));
+        }
+
+        store.expiry.insert(key);
+    }
+
+    fn remove(&self, id: &str) {
+        let mut store = self.store.write();
+        if let Some(old) = store.sessions.remove(id) {
+            self.bytes
+                .fetch_sub(old.approx_size(), atomic::Ordering::Relaxed);
+            store.expiry.remove(&(old.expires_at, old.id.to_owned()));
+        }
+    }
+
+    fn scan_expired(&self, before: DateTime<Utc>) -> Vec<String> {
+        self.store
+            .read()
+            .expiry
+            .iter()
+            .take_while(|(expires_at, _)| expires_at.cmp(&before) != Ordering::Greater)
+            .map(|(_, id)| id.to_owned())
+            .collect()
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.store.read().sessions.len())
+    }
+
+    fn bytes(&self) -> Option<usize> {
+        Some(self.bytes.load(atomic::Ordering::Relaxed))
+    }
+
+    fn snapshot(&self) -> Vec<Session> {
+        self.store.read().sessions.values().cloned().collect()
+    }
+
+    fn evict_first(&self, keep: &str) -> Option<Session> {
+        let mut store = self.store.write();
+
+        // the session just inserted is skipped, so the head is at most one entry further
+        let head = store.expiry.iter().find(|(_, id)| id != keep)?.clone();
+        store.expiry.remove(&head);
+
+        let old = store.sessions.remove(&head.1)?;
+        self.bytes
+            .fetch_sub(old.approx_size(), atomic::Ordering::Relaxed);
+
+        Some(old)
+    }
+}
+
+/// The attributes of the cookie carrying the session id, see `ExchangeConfig::set_session_cookie`.
+/// The cookie expires along with the session.
+#[derive(Clone, Debug)]
+pub struct CookieOptions {
+    pub path: String,
+    pub domain: String,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Default for CookieOptions {
+    fn default() -> Self {
+        CookieOptions {
+            path: String::from("/"),
+            domain: String::new(),
+            secure: false,
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+        }
+    }
+}
+
 pub struct Session {
     id: String,
     auto_renewal: bool,
@@ -161,6 +357,51 @@ impl Clone for Session {
     }
 }
 
+impl Session {
+    /// Install the session backend, which replaces the default `MemoryBackend`. This shall be done
+    /// when the server starts, since the sessions in the previous backend are not carried over.
+    pub fn set_backend(backend: Box<dyn SessionBackend + Send + Sync>) {
+        *BACKEND.write() = backend;
+    }
+
+    /// Find the session of the client with the id from the session cookie of the request, see
+    /// `ExchangeConfig::set_session_cookie`. Returns `None` if the request doesn't carry the cookie,
+    /// or if the session doesn't exist or has expired.
+    pub fn from_request(req: &Box<Request>) -> Option<Session> {
+        let id = req.cookie(&SESSION_COOKIE.read().0)?;
+        if id.is_empty() {
+            return None;
+        }
+
+        Session::from_id(id)
+    }
+
+    /// Set the session cookie with the session id to the response, such that the following
+    /// requests from the client can find the session with `Session::from_request`.
+    pub fn attach_to(&self, resp: &mut Box<Response>) {
+        let cookie = {
+            let config = SESSION_COOKIE.read();
+            let (name, options) = (&config.0, &config.1);
+
+            let mut cookie = Cookie::new(name, &self.id);
+            cookie.set_path(&options.path);
+            cookie.set_domain(&options.domain);
+            cookie.set_same_site(options.same_site);
+            cookie.set_secure_attr(options.secure);
+            cookie.set_http_only_attr(options.http_only);
+            cookie.set_expires(Some(SystemTime::from(self.expires_at)));
+            cookie
+        };
+
+        resp.set_cookie(cookie);
+    }
+
+    #[inline]
+    fn approx_size(&self) -> usize {
+        self.id.len() + self.store.len()
+    }
+}
+
 pub trait SessionExchange {
     fn create_new() -> Option<Session>;
     fn create_new_with_id(id: &str) -> Option<Session>;
@@ -181,12 +422,10 @@ impl SessionExchange for Session {
     }
 
     fn from_id(id: String) -> Option<Self> {
-        let store = STORE.read();
-
-        if let Some(val) = store.get(&id) {
+        if let Some(val) = BACKEND.read().load(&id) {
             if val.expires_at.cmp(&Utc::now()) != Ordering::Less {
                 //found the session, return now
-                return Some(val.to_owned());
+                return Some(val);
             } else {
                 //expired, remove it from the store
                 thread::spawn(move || {
@@ -217,11 +456,38 @@ impl SessionExchange for Session {
 
 pub struct ExchangeConfig;
 
+/// The format of the session store file written by `PersistHandler::save_to_file`. Either format
+/// can be restored by `PersistHandler::init_from_file`, which detects the format from the header
+/// of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreFormat {
+    /// The sessions are serialized as the delimited text, where the session data can't contain the
+    /// reserved delimiters `\u{0005}` and `\u{0006}`. This is the default format.
+    Text,
+
+    /// The sessions are serialized as the length-prefixed binary records, where the session data
+    /// can contain any characters.
+    Binary,
+}
+
+impl Default for StoreFormat {
+    fn default() -> Self {
+        StoreFormat::Text
+    }
+}
+
 pub trait SessionExchangeConfig {
     fn set_default_session_lifetime(lifetime: Duration);
     fn clean();
     fn clean_up_to(lifetime: DateTime<Utc>);
     fn store_size() -> Option<usize>;
+    fn set_max_sessions(count: usize);
+    fn set_max_store_bytes(bytes: usize);
+    fn evicted_count() -> usize;
+    fn on_session_evicted(hook: fn(&str));
+    fn set_session_cookie(name: &str, options: CookieOptions);
+    fn set_store_format(format: StoreFormat);
+    fn store_format() -> StoreFormat;
     fn auto_clean_start(period: Duration) -> Option<JoinHandle<()>>;
     fn auto_clean_stop();
     fn auto_clean_is_running() -> bool;
@@ -255,8 +521,57 @@ impl SessionExchangeConfig for ExchangeConfig {
     }
 
     fn store_size() -> Option<usize> {
-        let store = STORE.read();
-        Some(store.keys().len())
+        BACKEND.read().size()
+    }
+
+    /// Cap the number of sessions in the store, where the sessions closest to expiry are evicted
+    /// to make room for the new ones. Default to 0, i.e. no cap.
+    fn set_max_sessions(count: usize) {
+        MAX_SESSIONS.store(count, atomic::Ordering::Release);
+    }
+
+    /// Cap the approximate bytes used by the sessions in the store, i.e. the sum of the id and the
+    /// data lengths, where the sessions closest to expiry are evicted to make room for the new
+    /// ones. Default to 0, i.e. no cap.
+    fn set_max_store_bytes(bytes: usize) {
+        MAX_STORE_BYTES.store(bytes, atomic::Ordering::Release);
+    }
+
+    /// The number of sessions evicted from the store because of the caps.
+    fn evicted_count() -> usize {
+        EVICTED.load(atomic::Ordering::Acquire)
+    }
+
+    /// Set the hook to be called with the id of each session evicted from the store because of the
+    /// caps, e.g. to log the forced logouts.
+    fn on_session_evicted(hook: fn(&str)) {
+        *EVICTION_HOOK.write() = Some(hook);
+    }
+
+    /// Set the name and the attributes of the cookie carrying the session id, which is used by
+    /// `Session::from_request` and `Session::attach_to`. The name defaults to `RUSTY_SESSION`, and
+    /// an empty name keeps the current one.
+    fn set_session_cookie(name: &str, options: CookieOptions) {
+        let mut config = SESSION_COOKIE.write();
+
+        if !name.is_empty() {
+            config.0 = name.to_owned();
+        }
+
+        config.1 = options;
+    }
+
+    /// Set the format of the session store file written by `PersistHandler::save_to_file`.
+    fn set_store_format(format: StoreFormat) {
+        BINARY_STORE.store(format == StoreFormat::Binary, atomic::Ordering::Release);
+    }
+
+    fn store_format() -> StoreFormat {
+        if BINARY_STORE.load(atomic::Ordering::Acquire) {
+            StoreFormat::Binary
+        } else {
+            StoreFormat::Text
+        }
     }
 
     fn auto_clean_start(period: Duration) -> Option<JoinHandle<()>> {
@@ -270,14 +585,17 @@ impl SessionExchangeConfig for ExchangeConfig {
             period
         };
 
-        Some(thread::spawn(move || {
-            AUTO_CLEAN.store(true, atomic::Ordering::Release);
+        thread::Builder::new()
+            .name(String::from("rex-session-clean"))
+            .spawn(move || {
+                AUTO_CLEAN.store(true, atomic::Ordering::Release);
 
-            loop {
-                thread::sleep(sleep_period);
-                clean_up_to(Utc::now());
-            }
-        }))
+                loop {
+                    thread::sleep(sleep_period);
+                    clean_up_to(Utc::now());
+                }
+            })
+            .ok()
     }
 
     fn auto_clean_stop() {
@@ -354,108 +672,151 @@ impl Drop for Session {
     }
 }
 
+/// The summary of restoring the session store from a persistent file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadSummary {
+    /// The number of sessions restored into the session store.
+    pub loaded: usize,
+
+    /// The number of intact records skipped, because the session has expired or can't be rebuilt.
+    pub skipped: usize,
+
+    /// The number of records that are truncated, or fail the length or checksum validation.
+    pub corrupted: usize,
+}
+
 pub trait PersistHandler {
-    //TODO: support database
-    fn init_from_file(path: &Path) -> bool;
+    fn init_from_file(path: &Path) -> Result<LoadSummary, String>;
     fn save_to_file(path: &Path);
 }
 
 impl PersistHandler for Session {
+    /// Restore the session store from the file, in either of the `StoreFormat`s. Records that are
+    /// truncated or fail the checksum validation are skipped and counted in the summary, such that
+    /// the intact records can still be recovered from a partially written file.
     //TODO:allow decreptor
-    fn init_from_file(path: &Path) -> bool {
-        let mut buf_reader = if let Ok(dest_file) = File::open(&path) {
-            BufReader::new(dest_file)
-        } else {
-            // can't read the file, abort saving
-            eprintln!("Unable to open the session store file, please check if the file exists.");
-            return false;
+    fn init_from_file(path: &Path) -> Result<LoadSummary, String> {
+        let content = match fs::read(path) {
+            Ok(c) => c,
+            Err(e) => {
+                // can't read the file, abort loading
+                return Err(format!(
+                    "Unable to open the session store file, please check if the file exists: {}",
+                    e
+                ));
+            }
         };
 
-        let mut pool = ThreadPool::new(8);
-        let (tx, rx): (Sender<Option<Session>>, Receiver<Option<Session>>) = channel::bounded(16);
-
         let now = Utc::now();
         let default_expires = get_next_expiration(&now);
+        let mut summary = LoadSummary::default();
+        let mut sessions = Vec::new();
+
+        let mut rebuild = |raw: &[u8], summary: &mut LoadSummary| match str::from_utf8(raw) {
+            Ok(s) if s.is_empty() => {}
+            Ok(s) => match rebuild_session(s, default_expires, now) {
+                Some(session) => sessions.push(session),
+                None => summary.skipped += 1,
+            },
+            Err(_) => summary.corrupted += 1,
+        };
 
-        let mut failures: u8 = 0;
-        loop {
-            let mut buf: Vec<u8> = Vec::new();
-            if let Ok(size) = buf_reader.read_until(DELEM_LV_1 as u8, &mut buf) {
-                if size == 0 {
-                    break;
-                }
-
-                buf.pop();
-                if let Ok(session) = String::from_utf8(buf) {
-                    if session.is_empty() {
-                        continue;
+        if content.starts_with(STORE_MAGIC_BIN) {
+            let mut pos = STORE_MAGIC_BIN.len();
+
+            while pos < content.len() {
+                match read_bin_record(&content, pos) {
+                    Ok((payload, next)) => {
+                        match decode_session(payload, now) {
+                            Ok(Some(session)) => sessions.push(session),
+                            Ok(None) => summary.skipped += 1,
+                            Err(()) => summary.corrupted += 1,
+                        }
+                        pos = next;
+                    }
+                    Err(next) => {
+                        summary.corrupted += 1;
+                        pos = next;
                     }
-
-                    let tx_clone = tx.clone();
-                    pool.execute(move || {
-                        recreate_session_from_raw(session, &default_expires, &now, tx_clone);
-                    });
                 }
-            } else {
-                failures += 1;
-                if failures > 5 {
-                    break;
+            }
+        } else if content.starts_with(STORE_MAGIC) {
+            let mut pos = STORE_MAGIC.len();
+
+            while pos < content.len() {
+                match read_record(&content, pos) {
+                    Ok((payload, next)) => {
+                        rebuild(payload, &mut summary);
+                        pos = next;
+                    }
+                    Err(next) => {
+                        summary.corrupted += 1;
+                        pos = next;
+                    }
                 }
             }
+        } else {
+            // legacy store file without the record framing, only rely on the delimiter
+            for raw in content.split(|b| *b == DELEM_LV_1 as u8) {
+                rebuild(raw, &mut summary);
+            }
         }
 
-        drop(tx);
+        if summary.corrupted > 0 {
+            eprintln!(
+                "Failed to restore {} corrupted session records from the file store, the previous \
+                 copy can be found in the backup file",
+                summary.corrupted
+            );
+        }
 
-        let mut store = STORE.write();
-        for received in rx {
-            if let Some(session) = received {
-                let id: String = session.id.to_owned();
-                (*store).entry(id).or_insert(session); //if a key collision, always keep the early entry.
+        let backend = BACKEND.read();
+        for session in sessions {
+            if backend.load(&session.id).is_none() {
+                //if a key collision, always keep the early entry.
+                backend.store(session);
+                summary.loaded += 1;
             }
         }
 
-        true
+        Ok(summary)
     }
 
+    /// Save the session store to the file. The content is written to a temporary file in the same
+    /// folder first, and only renamed over the destination after being synced to the disk. The
+    /// previous copy of the store file is kept with the `.bak` extension, unless it fails the record
+    /// validation, in which case the existing backup is left alone. The file is written in the
+    /// format set by `ExchangeConfig::set_store_format`.
     //TODO:allow encryptor
     fn save_to_file(path: &Path) {
         let save_path = path.to_owned();
         let handler = thread::spawn(move || {
-            let mut file = if let Ok(dest_file) = File::create(&save_path) {
-                dest_file
-            } else {
-                // can't create file, abort saving
-                return;
-            };
-
-            let store = STORE.read();
-
-            let mut count: u8 = 0;
-            for (_, val) in store.iter() {
-                let mut s = val.serialize();
+            let temp_path = sibling_path(&save_path, ".tmp");
 
-                if s.is_empty() {
-                    continue;
-                } else {
-                    s.push(DELEM_LV_1);
-                }
+            if let Err(e) = write_store(&temp_path) {
+                eprintln!("Failed to save the session store to the file store: {}", e);
+                fs::remove_file(&temp_path).unwrap_or_default();
+                return;
+            }
 
-                if file.write(s.as_bytes()).is_err() {
-                    continue;
+            // rotate the previous copy of the store to the backup file, unless it's damaged and
+            // would overwrite the last good backup
+            if let Ok(previous) = fs::read(&save_path) {
+                if !is_intact(&previous) {
+                    eprintln!("The previous session file store is corrupted, keep the backup file");
+                } else if let Err(e) = fs::copy(&save_path, sibling_path(&save_path, ".bak")) {
+                    eprintln!("Unable to back up the previous session file store: {}", e);
                 }
+            }
 
-                count += 1;
-                if count % 32 == 0 {
-                    if let Err(e) = file.flush() {
-                        eprintln!("Failed to flush the session store to the file store: {}", e);
-                    }
-
-                    count = 0;
-                }
+            if let Err(e) = fs::rename(&temp_path, &save_path) {
+                eprintln!("Unable to replace the session file store: {}", e);
+                return;
             }
 
-            if let Err(e) = file.sync_all() {
-                eprintln!("Unable to sync all session data to the file store: {}", e);
+            // the rename is only durable once the directory entry is synced as well
+            if let Err(e) = sync_parent_dir(&save_path) {
+                eprintln!("Unable to sync the session file store folder: {}", e);
             }
         });
 
@@ -487,23 +848,21 @@ fn new_session(id: &str) -> Option<Session> {
         is_dirty: false,
     };
 
-    let mut store = STORE.write();
-    //if key already exists, override to protect session scanning
-    (*store).insert(session.id.to_owned(), session.to_owned());
+    store_session(session.to_owned());
 
     Some(session)
 }
 
 fn gen_session_id(id_size: usize) -> Option<String> {
     let size = if id_size < 16 { 16 } else { id_size };
-    let store = STORE.read();
+    let backend = BACKEND.read();
     let begin = SystemTime::now();
 
-    let mut next_id: String = thread_rng().gen_ascii_chars().take(size).collect();
+    let mut next_id = entropy::random_token(size);
     let mut count = 1;
 
     loop {
-        if !store.contains_key(&next_id) {
+        if backend.load(&next_id).is_none() {
             return Some(next_id);
         }
 
@@ -516,21 +875,77 @@ fn gen_session_id(id_size: usize) -> Option<String> {
         }
 
         // now take the next guess
-        next_id = thread_rng().gen_ascii_chars().take(32).collect();
+        next_id = entropy::random_token(size);
         count += 1;
     }
 }
 
 fn save(id: String, session: &mut Session) -> bool {
-    let mut store = STORE.write();
     if session.auto_renewal {
         session.expires_at = get_next_expiration(&Utc::now());
     }
 
-    let _ = (*store).insert(id, session.to_owned());
+    // the stored copy is clean, otherwise it would be saved again when dropped by the backend
+    let mut copy = session.to_owned();
+    copy.id = id;
+    copy.is_dirty = false;
+
+    store_session(copy);
     true
 }
 
+/// Insert the session into the backend, then evict the sessions closest to expiry if the store
+/// goes over the caps, other than the one just inserted.
+fn store_session(session: Session) {
+    let id = session.id.to_owned();
+
+    let evicted = {
+        let backend = BACKEND.read();
+        backend.store(session);
+        evict_over_caps(&**backend, &id)
+    };
+
+    if evicted.is_empty() {
+        return;
+    }
+
+    EVICTED.fetch_add(evicted.len(), atomic::Ordering::AcqRel);
+
+    if let Some(hook) = *EVICTION_HOOK.read() {
+        evicted.iter().for_each(|id| hook(id));
+    }
+}
+
+fn evict_over_caps(backend: &dyn SessionBackend, keep: &str) -> Vec<String> {
+    let max_count = MAX_SESSIONS.load(atomic::Ordering::Acquire);
+    let max_bytes = MAX_STORE_BYTES.load(atomic::Ordering::Acquire);
+
+    let over_caps = |count: usize, bytes: usize| {
+        (max_count > 0 && count > max_count) || (max_bytes > 0 && bytes > max_bytes)
+    };
+
+    let mut count = backend.size().unwrap_or(0);
+    let mut bytes = backend.bytes().unwrap_or(0);
+    let mut evicted = Vec::new();
+
+    if !over_caps(count, bytes) {
+        return evicted;
+    }
+
+    while over_caps(count, bytes) {
+        let session = match backend.evict_first(keep) {
+            Some(session) => session,
+            None => break,
+        };
+
+        count = count.saturating_sub(1);
+        bytes = bytes.saturating_sub(session.approx_size());
+        evicted.push(session.id.to_owned());
+    }
+
+    evicted
+}
+
 fn rebuild_session(
     raw: &str,
     default_expires: DateTime<Utc>,
@@ -593,42 +1008,696 @@ fn get_next_expiration(now: &DateTime<Utc>) -> chrono::DateTime<Utc> {
 }
 
 fn release(id: String) -> bool {
-    let mut store = STORE.write();
-    (*store).remove(&id);
-
+    BACKEND.read().remove(&id);
     true
 }
 
 fn clean_up_to(time: DateTime<Utc>) {
-    let mut stale_sessions: Vec<String> = Vec::new();
-    let mut store = STORE.write();
+    let backend = BACKEND.read();
+    let stale_sessions = backend.scan_expired(time);
 
-    if (*store).is_empty() {
+    if stale_sessions.is_empty() {
         return;
     }
 
-    for session in (*store).values() {
-        if session.expires_at.cmp(&time) != Ordering::Greater {
-            stale_sessions.push(session.id.to_owned());
+    println!("Cleaned: {}", stale_sessions.len());
+
+    for id in stale_sessions {
+        backend.remove(&id);
+    }
+}
+
+fn write_store(path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    if ExchangeConfig::store_format() == StoreFormat::Binary {
+        file.write_all(STORE_MAGIC_BIN)?;
+
+        for val in BACKEND.read().snapshot() {
+            if val.id.is_empty() {
+                continue;
+            }
+
+            let payload = encode_session(&val);
+            file.write_all(&(payload.len() as u32).to_be_bytes())?;
+            file.write_all(&checksum(&payload).to_be_bytes())?;
+            file.write_all(&payload)?;
+        }
+
+        file.flush()?;
+        return file.get_ref().sync_all();
+    }
+
+    file.write_all(STORE_MAGIC)?;
+
+    for val in BACKEND.read().snapshot() {
+        let s = val.serialize();
+        if s.is_empty() {
+            continue;
         }
+
+        file.write_all(format!("{:08x}{:08x}", s.len(), checksum(s.as_bytes())).as_bytes())?;
+        file.write_all(s.as_bytes())?;
+        file.write_all(&[DELEM_LV_1 as u8])?;
     }
 
-    println!("Cleaned: {}", stale_sessions.len());
+    file.flush()?;
+    file.get_ref().sync_all()
+}
 
-    for id in stale_sessions {
-        (*store).remove(&id);
+/// Read the record starting at `pos`, which is framed as the payload length and checksum in hex,
+/// then the payload and the delimiter. If the record is corrupted, return the position to resume the
+/// search from as the error.
+fn read_record(content: &[u8], pos: usize) -> Result<(&[u8], usize), usize> {
+    let body = pos + RECORD_HEADER_LEN;
+
+    if body <= content.len() {
+        let header = str::from_utf8(&content[pos..body]).unwrap_or_default();
+        let len = usize::from_str_radix(header.get(..8).unwrap_or_default(), 16);
+        let sum = u32::from_str_radix(header.get(8..).unwrap_or_default(), 16);
+
+        if let (Ok(len), Ok(sum)) = (len, sum) {
+            let end = body + len;
+
+            if end < content.len()
+                && content[end] == DELEM_LV_1 as u8
+                && checksum(&content[body..end]) == sum
+            {
+                return Ok((&content[body..end], end + 1));
+            }
+        }
+    }
+
+    // the frame is broken, skip to the next delimiter
+    match content[pos..].iter().position(|b| *b == DELEM_LV_1 as u8) {
+        Some(offset) => Err(pos + offset + 1),
+        None => Err(content.len()),
+    }
+}
+
+/// Read the binary record starting at `pos`, which is framed as the payload length and checksum in
+/// big-endian `u32`s, then the payload. Without a delimiter to search for, a corrupted record is
+/// skipped by its length if the length is within the file, otherwise the rest of the file is given
+/// up; either way the position to resume from is returned as the error.
+fn read_bin_record(content: &[u8], pos: usize) -> Result<(&[u8], usize), usize> {
+    let body = pos + BIN_RECORD_HEADER_LEN;
+    if body > content.len() {
+        return Err(content.len());
+    }
+
+    let len = read_u32(&content[pos..]) as usize;
+    let sum = read_u32(&content[pos + 4..]);
+
+    match body.checked_add(len) {
+        Some(end) if end <= content.len() => {
+            if checksum(&content[body..end]) == sum {
+                Ok((&content[body..end], end))
+            } else {
+                Err(end)
+            }
+        }
+        _ => Err(content.len()),
     }
 }
 
-fn recreate_session_from_raw(
-    raw: String,
-    expires_at: &DateTime<Utc>,
-    now: &DateTime<Utc>,
-    tx: Sender<Option<Session>>,
-) {
-    let result = rebuild_session(&raw[..], expires_at.to_owned(), now.to_owned());
+/// Reads the framed record at the position, as `read_record` and `read_bin_record` do.
+type ReadRecord = fn(&[u8], usize) -> Result<(&[u8], usize), usize>;
+
+/// Check that every record in the store file content passes the framing and checksum validation,
+/// i.e. the file can be restored without losing any records.
+fn is_intact(content: &[u8]) -> bool {
+    let (magic, read): (&[u8], ReadRecord) = if content.starts_with(STORE_MAGIC_BIN) {
+        (STORE_MAGIC_BIN, read_bin_record)
+    } else if content.starts_with(STORE_MAGIC) {
+        (STORE_MAGIC, read_record)
+    } else {
+        // legacy store file without the record framing, nothing to validate but the encoding
+        return str::from_utf8(content).is_ok();
+    };
+
+    let mut pos = magic.len();
+    while pos < content.len() {
+        match read(content, pos) {
+            Ok((_, next)) => pos = next,
+            Err(_) => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    // directories can't be opened as files on this platform, the rename is flushed with the volume
+    Ok(())
+}
+
+/// Encode the session as `id_len: u32 | id | expires_at: i64 | flags: u8 | store_len: u32 | store`,
+/// where the integers are big-endian and `expires_at` is in milliseconds since the epoch.
+fn encode_session(session: &Session) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(17 + session.id.len() + session.store.len());
+
+    buf.extend_from_slice(&(session.id.len() as u32).to_be_bytes());
+    buf.extend_from_slice(session.id.as_bytes());
+    buf.extend_from_slice(&session.expires_at.timestamp_millis().to_be_bytes());
+    buf.push(if session.auto_renewal {
+        FLAG_AUTO_RENEWAL
+    } else {
+        0
+    });
+    buf.extend_from_slice(&(session.store.len() as u32).to_be_bytes());
+    buf.extend_from_slice(session.store.as_bytes());
+
+    buf
+}
+
+/// Decode the session from the binary record payload. Returns `Ok(None)` if the session has
+/// expired, or `Err` if the payload is malformed.
+fn decode_session(payload: &[u8], now: DateTime<Utc>) -> Result<Option<Session>, ()> {
+    let mut pos = 0;
+
+    let id = read_str(payload, &mut pos)?;
+    if id.is_empty() {
+        return Err(());
+    }
+
+    let millis = payload.get(pos..pos + 8).ok_or(())?;
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(millis);
+    pos += 8;
+
+    let expires_at = match Utc.timestamp_millis_opt(i64::from_be_bytes(raw)) {
+        chrono::LocalResult::Single(time) => time,
+        _ => return Err(()),
+    };
+
+    let flags = *payload.get(pos).ok_or(())?;
+    pos += 1;
+
+    let store = read_str(payload, &mut pos)?;
+    if pos != payload.len() {
+        return Err(());
+    }
+
+    if expires_at.cmp(&now) == Ordering::Less {
+        //already expired
+        return Ok(None);
+    }
+
+    Ok(Some(Session {
+        id,
+        expires_at,
+        auto_renewal: flags & FLAG_AUTO_RENEWAL != 0,
+        store,
+        is_dirty: false,
+    }))
+}
+
+/// Read the length-prefixed UTF-8 string at `pos`, and advance `pos` past it.
+fn read_str(payload: &[u8], pos: &mut usize) -> Result<String, ()> {
+    let start = *pos + 4;
+    if start > payload.len() {
+        return Err(());
+    }
+
+    let end = start
+        .checked_add(read_u32(&payload[*pos..]) as usize)
+        .filter(|end| *end <= payload.len())
+        .ok_or(())?;
+
+    let s = str::from_utf8(&payload[start..end]).map_err(|_| ())?;
+    *pos = end;
+
+    Ok(s.to_owned())
+}
+
+#[inline]
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&bytes[..4]);
+    u32::from_be_bytes(raw)
+}
+
+/// FNV-1a hash of the record payload.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash: u32, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod session_test {
+    use super::*;
+    use crate::core::http::{RequestWriter, ResponseStates};
+    use crate::parking_lot::Mutex;
+    use std::env;
+    use std::sync::Arc;
+
+    lazy_static! {
+        // the tests share the global backend, so they must take turns
+        static ref BACKEND_LOCK: Mutex<()> = Mutex::new(());
+        static ref EVICTED_IDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    fn record_eviction(id: &str) {
+        EVICTED_IDS.lock().push(id.to_owned());
+    }
+
+    /// Create the session which expires in the given hours.
+    fn insert_expiring(id: &str, hours: i64) {
+        let mut session = Session::create_new_with_id(id).unwrap();
+        let expires = Utc::now() + chrono::Duration::hours(hours);
+        SessionHandler::<Session>::expires_at(&mut session, expires);
+    }
+
+    fn stored_ids() -> Vec<String> {
+        let mut ids: Vec<String> = BACKEND
+            .read()
+            .snapshot()
+            .iter()
+            .map(|s| s.id.to_owned())
+            .collect();
+
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn capped_store_eviction() {
+        let _guard = BACKEND_LOCK.lock();
+        Session::set_backend(Box::new(MemoryBackend::new()));
+        ExchangeConfig::on_session_evicted(record_eviction);
+        EVICTED_IDS.lock().clear();
+
+        let evicted_before = ExchangeConfig::evicted_count();
+        ExchangeConfig::set_max_sessions(3);
+
+        for i in 0..6 {
+            insert_expiring(&format!("cap-{}", i), 10 + i);
+            assert!(ExchangeConfig::store_size().unwrap() <= 3);
+        }
+
+        assert_eq!(stored_ids(), ["cap-3", "cap-4", "cap-5"]);
+        assert_eq!(*EVICTED_IDS.lock(), ["cap-0", "cap-1", "cap-2"]);
+        assert_eq!(ExchangeConfig::evicted_count() - evicted_before, 3);
+
+        // the session being inserted is never evicted, even if it expires the soonest
+        insert_expiring("cap-early", 1);
+        assert_eq!(stored_ids(), ["cap-4", "cap-5", "cap-early"]);
+
+        // the sessions carry no data, so the 4th one takes the store to 24 bytes, over the cap
+        EVICTED_IDS.lock().clear();
+        ExchangeConfig::set_max_sessions(0);
+        ExchangeConfig::set_max_store_bytes(20);
+
+        insert_expiring("cap-6", 20);
+        assert_eq!(stored_ids(), ["cap-4", "cap-5", "cap-6"]);
+        assert_eq!(*EVICTED_IDS.lock(), ["cap-early"]);
+        assert_eq!(BACKEND.read().bytes(), Some(15));
+
+        // a renewed session moves down the expiry index, so the next one up is evicted instead
+        ExchangeConfig::set_max_store_bytes(0);
+        ExchangeConfig::set_max_sessions(3);
+
+        insert_expiring("cap-4", 30);
+        insert_expiring("cap-7", 25);
+        assert_eq!(stored_ids(), ["cap-4", "cap-6", "cap-7"]);
+        assert_eq!(*EVICTED_IDS.lock(), ["cap-early", "cap-5"]);
+
+        let expiring = BACKEND
+            .read()
+            .scan_expired(Utc::now() + chrono::Duration::hours(21));
+        assert_eq!(expiring, ["cap-6"]);
+
+        ExchangeConfig::set_max_sessions(0);
+        Session::set_backend(Box::new(MemoryBackend::new()));
+    }
+
+    /// Keep the sessions in a memory backend, and record the calls made to it.
+    struct MockBackend {
+        inner: MemoryBackend,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SessionBackend for MockBackend {
+        fn load(&self, id: &str) -> Option<Session> {
+            self.calls.lock().push(format!("load:{}", id));
+            self.inner.load(id)
+        }
+
+        fn store(&self, session: Session) {
+            self.calls.lock().push(format!("store:{}", session.id));
+            self.inner.store(session);
+        }
+
+        fn remove(&self, id: &str) {
+            self.calls.lock().push(format!("remove:{}", id));
+            self.inner.remove(id);
+        }
+
+        fn scan_expired(&self, before: DateTime<Utc>) -> Vec<String> {
+            self.calls.lock().push(String::from("scan_expired"));
+            self.inner.scan_expired(before)
+        }
+    }
+
+    /// Send the request with the cookie set by the response, as the browser would.
+    fn follow_up(resp: &Response, name: &str) -> Box<Request> {
+        let header = resp.get_cookie(name).unwrap().to_string();
+        let cookie = Cookie::parse(&header).unwrap();
+
+        let mut req = Box::new(Request::new());
+        req.set_cookie(name, &cookie.get_cookie_value(), true);
+        req
+    }
+
+    #[test]
+    fn session_cookie_round_trip() {
+        let _guard = BACKEND_LOCK.lock();
+
+        // the first request comes without the session cookie
+        let first = Box::new(Request::new());
+        assert!(Session::from_request(&first).is_none());
+
+        let mut session = Session::create_new().unwrap();
+        SessionHandler::<Session>::set_data(
+            &mut session,
+            Session::create_new_with_id("payload").unwrap(),
+        );
+
+        let mut resp = Box::new(Response::new());
+        session.attach_to(&mut resp);
+
+        let header = resp.get_cookie("RUSTY_SESSION").unwrap().to_string();
+        assert!(header.starts_with(&format!("RUSTY_SESSION={};", session.id)));
+        assert!(header.contains(" Expires="));
+        assert!(header.contains(" Path=/;"));
+        assert!(header.contains(" HttpOnly;"));
+        assert!(header.contains(" SameSite=Lax;"));
+
+        let id = session.id.to_owned();
+        drop(session);
+
+        // the second request carries the session cookie back
+        let second = follow_up(&resp, "RUSTY_SESSION");
+        let found = Session::from_request(&second).unwrap();
+        assert_eq!(found.id, id);
+
+        let data: Session = SessionHandler::get_data(&found).unwrap();
+        assert_eq!(data.id, "payload");
+
+        // with a custom cookie name and attributes
+        ExchangeConfig::set_session_cookie(
+            "sid",
+            CookieOptions {
+                path: String::from("/app"),
+                secure: true,
+                same_site: Some(SameSite::Strict),
+                ..Default::default()
+            },
+        );
+
+        let mut resp = Box::new(Response::new());
+        found.attach_to(&mut resp);
+
+        let header = resp.get_cookie("sid").unwrap().to_string();
+        assert!(header.contains(" Path=/app;"));
+        assert!(header.contains(" Secure;"));
+        assert!(header.contains(" SameSite=Strict;"));
+        assert!(Session::from_request(&second).is_none());
+        assert_eq!(
+            Session::from_request(&follow_up(&resp, "sid")).unwrap().id,
+            id
+        );
+
+        ExchangeConfig::set_session_cookie("RUSTY_SESSION", CookieOptions::default());
+        release(id);
+        release(String::from("payload"));
+    }
+
+    #[test]
+    fn custom_backend() {
+        let _guard = BACKEND_LOCK.lock();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        Session::set_backend(Box::new(MockBackend {
+            inner: MemoryBackend::new(),
+            calls: Arc::clone(&calls),
+        }));
+
+        let mut session = Session::create_new_with_id("mock-session").unwrap();
+        assert_eq!(
+            calls.lock().drain(..).collect::<Vec<_>>(),
+            ["store:mock-session"]
+        );
+
+        SessionHandler::<Session>::save(&mut session);
+        assert_eq!(
+            calls.lock().drain(..).collect::<Vec<_>>(),
+            ["store:mock-session"]
+        );
+
+        let mut loaded = Session::from_id(String::from("mock-session")).unwrap();
+        assert_eq!(loaded.id, "mock-session");
+        assert_eq!(
+            calls.lock().drain(..).collect::<Vec<_>>(),
+            ["load:mock-session"]
+        );
+
+        // the session expires, and the clean up shall remove it
+        SessionHandler::<Session>::expires_at(
+            &mut loaded,
+            Utc::now() - chrono::Duration::seconds(1),
+        );
+        drop(loaded);
+        calls.lock().clear();
+
+        assert!(ExchangeConfig::store_size().is_none());
+        clean_up_to(Utc::now());
+        assert_eq!(
+            calls.lock().drain(..).collect::<Vec<_>>(),
+            ["scan_expired", "remove:mock-session"]
+        );
+
+        Session::create_new_with_id("mock-release").unwrap();
+        release(String::from("mock-release"));
+        assert!(Session::from_id(String::from("mock-release")).is_none());
+        assert_eq!(
+            calls.lock().drain(..).collect::<Vec<_>>(),
+            [
+                "store:mock-release",
+                "remove:mock-release",
+                "load:mock-release"
+            ]
+        );
+
+        Session::set_backend(Box::new(MemoryBackend::new()));
+    }
+
+    #[test]
+    fn persist_recover_truncated_store() {
+        let _guard = BACKEND_LOCK.lock();
+        let mut path = env::temp_dir();
+        path.push(format!("rusty-session-{}.store", std::process::id()));
+
+        let ids = ["persist-test-one", "persist-test-two", "persist-test-three"];
+        for id in ids.iter() {
+            assert!(Session::create_new_with_id(id).is_some());
+        }
+
+        Session::save_to_file(&path);
+
+        // chop off the tail of the file, as if the server crashed in the middle of the write
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 8]).unwrap();
+
+        ids.iter().for_each(|id| {
+            release(String::from(*id));
+        });
+
+        let summary = Session::init_from_file(&path).unwrap();
+        assert_eq!(summary.corrupted, 1);
+        assert_eq!(summary.loaded, ids.len() - 1);
+
+        let restored = ids
+            .iter()
+            .filter(|id| BACKEND.read().load(id).is_some())
+            .count();
+
+        assert_eq!(restored, ids.len() - 1);
+
+        // now save the recovered store again, and it shall be loaded cleanly; the truncated copy
+        // is not worth a backup
+        Session::save_to_file(&path);
+        assert!(!sibling_path(&path, ".bak").exists());
+
+        ids.iter().for_each(|id| {
+            release(String::from(*id));
+        });
+
+        let summary = Session::init_from_file(&path).unwrap();
+        assert_eq!(summary.corrupted, 0);
+        assert_eq!(summary.loaded, ids.len() - 1);
+
+        fs::remove_file(&path).unwrap_or_default();
+        fs::remove_file(sibling_path(&path, ".bak")).unwrap_or_default();
+    }
+
+    #[test]
+    fn persist_keep_backup_of_intact_store() {
+        let _guard = BACKEND_LOCK.lock();
+        let mut path = env::temp_dir();
+        path.push(format!("rusty-session-{}.rotate", std::process::id()));
+        let backup = sibling_path(&path, ".bak");
+
+        assert!(Session::create_new_with_id("rotate-test-one").is_some());
+        Session::save_to_file(&path);
+        assert!(!backup.exists());
+
+        // the intact store is rotated to the backup on the next save
+        let good = fs::read(&path).unwrap();
+        assert!(is_intact(&good));
+
+        assert!(Session::create_new_with_id("rotate-test-two").is_some());
+        Session::save_to_file(&path);
+        assert_eq!(fs::read(&backup).unwrap(), good);
+
+        // a truncated store must not replace the last good backup
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 8]).unwrap();
+        assert!(!is_intact(&content[..content.len() - 8]));
+
+        Session::save_to_file(&path);
+        assert_eq!(fs::read(&backup).unwrap(), good);
+        assert!(is_intact(&fs::read(&path).unwrap()));
+
+        release(String::from("rotate-test-one"));
+        release(String::from("rotate-test-two"));
+
+        fs::remove_file(&path).unwrap_or_default();
+        fs::remove_file(&backup).unwrap_or_default();
+    }
+
+    /// Switch the global store format, and switch it back once dropped, even if the test fails.
+    struct FormatGuard(StoreFormat);
+
+    impl FormatGuard {
+        fn set(format: StoreFormat) -> Self {
+            let previous = ExchangeConfig::store_format();
+            ExchangeConfig::set_store_format(format);
+            FormatGuard(previous)
+        }
+    }
+
+    impl Drop for FormatGuard {
+        fn drop(&mut self) {
+            ExchangeConfig::set_store_format(self.0);
+        }
+    }
+
+    #[test]
+    fn binary_store_format() {
+        let _guard = BACKEND_LOCK.lock();
+        let mut path = env::temp_dir();
+        path.push(format!("rusty-session-{}.bin", std::process::id()));
+
+        // the data carries the delimiters of the text format
+        let data = ["bin\u{0005}one", "bin\u{0006}two\nline", ""];
+        let ids = ["binary-test-one", "binary-test-two", "binary-test-three"];
+
+        for (id, data) in ids.iter().zip(data.iter()) {
+            let mut session = Session::create_new_with_id(id).unwrap();
+            session.store = String::from(*data);
+            session.auto_renewal = id.ends_with("two");
+            session.is_dirty = true;
+        }
+
+        let previous = ExchangeConfig::store_format();
+        {
+            let _format = FormatGuard::set(StoreFormat::Binary);
+            Session::save_to_file(&path);
+        }
+
+        assert_eq!(ExchangeConfig::store_format(), previous);
+
+        let content = fs::read(&path).unwrap();
+        assert!(content.starts_with(STORE_MAGIC_BIN));
+
+        ids.iter().for_each(|id| {
+            release(String::from(*id));
+        });
+
+        let summary = Session::init_from_file(&path).unwrap();
+        assert_eq!(
+            summary,
+            LoadSummary {
+                loaded: 3,
+                skipped: 0,
+                corrupted: 0
+            }
+        );
+
+        for (id, data) in ids.iter().zip(data.iter()) {
+            let session = BACKEND.read().load(id).unwrap();
+            assert_eq!(session.store, *data);
+            assert_eq!(session.auto_renewal, id.ends_with("two"));
+            release(String::from(*id));
+        }
+
+        // flip a byte in the first record's payload, then the other records are still restored
+        let mut broken = content.clone();
+        broken[STORE_MAGIC_BIN.len() + BIN_RECORD_HEADER_LEN + 6] ^= 0xff;
+        fs::write(&path, &broken).unwrap();
+
+        let summary = Session::init_from_file(&path).unwrap();
+        assert_eq!(
+            summary,
+            LoadSummary {
+                loaded: 2,
+                skipped: 0,
+                corrupted: 1
+            }
+        );
+
+        ids.iter().for_each(|id| {
+            release(String::from(*id));
+        });
+
+        // the legacy text store without the record framing still loads
+        let expires = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let legacy = format!(
+            "legacy-test{d}{e}{d}true{d}plain{d}{s}",
+            d = DELEM_LV_2,
+            e = expires,
+            s = DELEM_LV_1
+        );
+        fs::write(&path, legacy).unwrap();
+
+        let summary = Session::init_from_file(&path).unwrap();
+        assert_eq!(summary.loaded, 1);
+
+        let session = BACKEND.read().load("legacy-test").unwrap();
+        assert_eq!(session.store, "plain");
+        assert!(session.auto_renewal);
+        release(String::from("legacy-test"));
 
-    if let Err(e) = tx.send(result) {
-        println!("Unable to parse base request: {:?}", e);
+        fs::remove_file(&path).unwrap_or_default();
+        fs::remove_file(sibling_path(&path, ".bak")).unwrap_or_default();
     }
 }
@@ -0,0 +1,91 @@
+#[cfg(feature = "logger")]
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+#[cfg(feature = "logger")]
+thread_local! {
+    static CURRENT: Cell<TraceIds> = Cell::new(TraceIds::default());
+}
+
+/// The ids tying the log lines to where they come from: the connection, which is numbered when
+/// accepted, and the request within the connection, which is numbered in the order it's received,
+/// starting from 1. A request id of 0 stands for the connection-level work, and the default ids,
+/// i.e. `0.0`, are the ones not on behalf of any connection.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+pub struct TraceIds {
+    pub conn: u64,
+    pub req: usize,
+}
+
+impl TraceIds {
+    #[inline]
+    pub(crate) fn new(conn: u64, req: usize) -> Self {
+        TraceIds { conn, req }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.conn == 0
+    }
+}
+
+impl fmt::Display for TraceIds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.conn, self.req)
+    }
+}
+
+/// The span is the current one of the thread until the guard is dropped, and then the previous
+/// span is restored.
+pub(crate) struct SpanGuard {
+    #[cfg(feature = "logger")]
+    prev: TraceIds,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "logger")]
+        CURRENT.with(|curr| curr.set(self.prev));
+    }
+}
+
+/// Number the newly accepted connection.
+#[inline]
+pub(crate) fn next_conn_id() -> u64 {
+    CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Make the ids the current span of the thread, such that the log lines from the thread will be
+/// prefixed with the ids. This is a no-op without the `logger` feature.
+#[inline]
+pub(crate) fn enter(ids: TraceIds) -> SpanGuard {
+    #[cfg(feature = "logger")]
+    {
+        SpanGuard {
+            prev: CURRENT.with(|curr| curr.replace(ids)),
+        }
+    }
+
+    #[cfg(not(feature = "logger"))]
+    {
+        let _ = ids;
+        SpanGuard {}
+    }
+}
+
+/// The current span of the thread, if any.
+#[inline]
+pub(crate) fn current() -> Option<TraceIds> {
+    #[cfg(feature = "logger")]
+    {
+        let ids = CURRENT.with(Cell::get);
+        if !ids.is_empty() {
+            return Some(ids);
+        }
+    }
+
+    None
+}
@@ -1,6 +1,7 @@
 use crate::core::router::RouteHandler;
 use crate::hashbrown::HashMap;
 use crate::regex::Regex;
+use std::mem;
 
 #[derive(Debug)]
 pub(crate) struct Field {
@@ -72,9 +73,11 @@ impl Node {
         if !head.is_param {
             if let Some(child) = self.named_children.get_mut(&head.name) {
                 if segments.is_empty() {
-                    // done, update the node
+                    // done, update the node, and keep the early entry if the route, e.g. `/a/:b`
+                    // and `/a/:b/`, has been registered already
                     if child.handler.is_some() {
-                        panic!("Key collision!");
+                        rex_warn!("Route has been registered already, keeping the early entry");
+                        return;
                     }
 
                     child.handler = handler;
@@ -98,6 +101,90 @@ impl Node {
             .push(Node::build_new_child(head, segments, handler));
     }
 
+    /// Take the handler of the route out, and prune the branches left without any routes. The
+    /// segments are in the reversed order, same as the ones for `insert`.
+    fn remove(&mut self, segments: &[Field]) -> Option<RouteHandler> {
+        let (head, rest) = segments.split_last()?;
+
+        if !head.is_param {
+            let child = self.named_children.get_mut(&head.name)?;
+            let found = child.take_handler(rest)?;
+
+            if child.is_vacant() {
+                self.named_children.remove(&head.name);
+            }
+
+            return Some(found);
+        }
+
+        for index in 0..self.params_children.len() {
+            let child = &mut self.params_children[index];
+            if child.field != *head {
+                continue;
+            }
+
+            if let Some(found) = child.take_handler(rest) {
+                if child.is_vacant() {
+                    self.params_children.remove(index);
+                }
+
+                return Some(found);
+            }
+        }
+
+        None
+    }
+
+    /// Update the handler of the route in place, and return `false` if the route doesn't exist.
+    fn update(&mut self, segments: &[Field], f: &mut dyn FnMut(&mut RouteHandler)) -> bool {
+        let (head, rest) = match segments.split_last() {
+            Some(parts) => parts,
+            None => return false,
+        };
+
+        if !head.is_param {
+            return match self.named_children.get_mut(&head.name) {
+                Some(child) => child.update_handler(rest, f),
+                None => false,
+            };
+        }
+
+        self.params_children
+            .iter_mut()
+            .filter(|child| child.field == *head)
+            .any(|child| child.update_handler(rest, f))
+    }
+
+    fn take_handler(&mut self, rest: &[Field]) -> Option<RouteHandler> {
+        if !rest.is_empty() {
+            return self.remove(rest);
+        }
+
+        if self.handler.is_none() {
+            return None;
+        }
+
+        Some(mem::take(&mut self.handler))
+    }
+
+    fn update_handler(&mut self, rest: &[Field], f: &mut dyn FnMut(&mut RouteHandler)) -> bool {
+        if !rest.is_empty() {
+            return self.update(rest, f);
+        }
+
+        if self.handler.is_none() {
+            return false;
+        }
+
+        f(&mut self.handler);
+        true
+    }
+
+    #[inline]
+    fn is_vacant(&self) -> bool {
+        self.handler.is_none() && self.named_children.is_empty() && self.params_children.is_empty()
+    }
+
     fn build_new_child(field: Field, segments: Vec<Field>, handler: RouteHandler) -> Node {
         match segments.len() {
             0 => {
@@ -138,18 +225,36 @@ impl RouteTrie {
         self.root.insert(segments, handler);
     }
 
+    /// Remove the route with the segments, as the ones given to `add`, and return its handler.
+    #[inline]
+    pub(crate) fn remove(&mut self, segments: &[Field]) -> Option<RouteHandler> {
+        self.root.remove(segments)
+    }
+
+    /// Update the handler of the route with the segments in place, see `remove`.
+    #[inline]
+    pub(crate) fn update(
+        &mut self,
+        segments: &[Field],
+        mut f: impl FnMut(&mut RouteHandler),
+    ) -> bool {
+        self.root.update(segments, &mut f)
+    }
+
     pub(crate) fn find(
         route_head: &RouteTrie,
         segments: &[String],
         params: &mut HashMap<String, String>,
+        allow_case: bool,
     ) -> RouteHandler {
-        RouteTrie::recursive_find(&route_head.root, segments, params)
+        RouteTrie::recursive_find(&route_head.root, segments, params, allow_case)
     }
 
     fn recursive_find(
         root: &Node,
         segments: &[String],
         params: &mut HashMap<String, String>,
+        allow_case: bool,
     ) -> RouteHandler {
         if segments.is_empty() {
             return RouteHandler::default();
@@ -158,12 +263,20 @@ impl RouteTrie {
         let head = &segments[0];
         let is_tail = segments.len() <= 1;
 
-        if let Some(child) = root.named_children.get(head) {
+        // the named segments have been folded on registration if the routes are case-insensitive,
+        // while the parameters are always captured as they are
+        let named = if allow_case || !head.chars().any(char::is_uppercase) {
+            root.named_children.get(head)
+        } else {
+            root.named_children.get(&head.to_lowercase())
+        };
+
+        if let Some(child) = named {
             if is_tail {
                 return child.handler.clone();
             }
 
-            return RouteTrie::recursive_find(&child, &segments[1..], params);
+            return RouteTrie::recursive_find(&child, &segments[1..], params, allow_case);
         }
 
         for param_node in root.params_children.iter() {
@@ -181,7 +294,7 @@ impl RouteTrie {
                 //                RouteHandler::new(param_node.callback, param_node.location.clone())
                 param_node.handler.clone()
             } else {
-                RouteTrie::recursive_find(param_node, &segments[1..], params)
+                RouteTrie::recursive_find(param_node, &segments[1..], params, allow_case)
             };
 
             if result.is_some() {
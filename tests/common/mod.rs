@@ -0,0 +1,183 @@
+//! The scaffold shared by the integration tests: the server under test is launched on the ports
+//! picked by the OS, and the scenario is run against it from the control thread. The wires the
+//! scenario records are handed back to the test once the server is shut down.
+
+#![allow(dead_code)]
+
+use rusty_express::prelude::*;
+use std::any::Any;
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::time::Duration;
+
+type Scenario = fn(&Client);
+
+static SCENARIO: Mutex<Option<Scenario>> = Mutex::new(None);
+static ADDRS: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+static WIRES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static PANIC: Mutex<Option<Box<dyn Any + Send>>> = Mutex::new(None);
+
+/// What's left of the scenario once the server is shut down.
+pub struct Served {
+    /// Where the listeners were bound to, see `QueryKind::LocalAddrs`.
+    pub addrs: Vec<SocketAddr>,
+    /// The wires recorded by the scenario, in order.
+    pub wires: Vec<String>,
+}
+
+/// The client side of the scenario: the controller of the server under test, and the addresses to
+/// connect to.
+pub struct Client {
+    pub controller: AsyncController,
+    addrs: Vec<SocketAddr>,
+    shut_down: Cell<bool>,
+}
+
+impl Client {
+    /// The address of the first listener.
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// The addresses of all listeners, followed by the https redirect one if any.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Open a new connection to the first listener.
+    pub fn connect(&self) -> TcpStream {
+        connect(self.addr())
+    }
+
+    /// Send the raw request to the first listener, see `request`.
+    pub fn request(&self, raw: &[u8]) -> String {
+        request(self.addr(), raw)
+    }
+
+    /// Send the `GET` request of the path to the first listener, asking to close the connection
+    /// after the response.
+    pub fn get(&self, path: &str) -> String {
+        self.request(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        )
+    }
+
+    /// Keep the wire for the assertions of the test.
+    pub fn record(&self, wire: String) {
+        WIRES.lock().unwrap().push(wire);
+    }
+
+    /// Shut down the server with the message, instead of the `Terminate` sent once the scenario
+    /// returns.
+    pub fn shut_down(&self, message: ControlMessage) {
+        self.shut_down.set(true);
+        self.controller
+            .send(message)
+            .unwrap_or_else(|_| panic!("Failed to shut down the server"));
+    }
+}
+
+/// Open a new connection to the address, which gives up reading after 10 seconds.
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    client
+}
+
+/// Send the raw request on a new connection and read all of the wire until the server closes it.
+pub fn request(addr: SocketAddr, raw: &[u8]) -> String {
+    let mut client = connect(addr);
+
+    // the server may refuse the request before reading all of it, so the outcome is only told by
+    // the read
+    let _ = client.write_all(raw);
+
+    let mut wire = Vec::new();
+    client.read_to_end(&mut wire).unwrap_or_default();
+
+    String::from_utf8_lossy(&wire).into_owned()
+}
+
+/// Launch the server on a port picked by the OS, and run the scenario against it. This function
+/// blocks until the server is shut down, and fails the test if the scenario does.
+pub fn serve(server: &mut HttpServer, scenario: Scenario) -> Served {
+    launch(scenario, |callback| {
+        server.try_listen_and_serve(0, Some(callback))
+    })
+}
+
+/// Same as `serve`, but on the prioritized listeners, where the addresses usually come with port 0.
+pub fn serve_on(
+    server: &mut HttpServer,
+    addrs: Vec<(SocketAddr, Priority)>,
+    scenario: Scenario,
+) -> Served {
+    launch(scenario, |callback| {
+        server.try_listen_on_prioritized(addrs, Some(callback))
+    })
+}
+
+fn launch<F>(scenario: Scenario, listen: F) -> Served
+where
+    F: FnOnce(fn(AsyncController)) -> Result<(), ServerError>,
+{
+    *SCENARIO.lock().unwrap() = Some(scenario);
+    listen(run).unwrap_or_else(|err| panic!("Unable to launch the server: {}", err));
+
+    let failure = PANIC.lock().unwrap().take();
+    if let Some(payload) = failure {
+        panic::resume_unwind(payload);
+    }
+
+    Served {
+        addrs: mem::take(&mut *ADDRS.lock().unwrap()),
+        wires: mem::take(&mut *WIRES.lock().unwrap()),
+    }
+}
+
+/// The callback of the server: find out where the server is bound, and run the scenario. The
+/// server is shut down even if the scenario fails, such that the failure is reported instead of
+/// hanging the test.
+fn run(controller: AsyncController) {
+    let scenario = SCENARIO.lock().unwrap().take().expect("No scenario to run");
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        let addrs = match controller.query(QueryKind::LocalAddrs, Duration::from_secs(5)) {
+            Some(QueryReply::LocalAddrs(addrs)) => addrs,
+            other => panic!("Unexpected reply: {:?}", other),
+        };
+
+        *ADDRS.lock().unwrap() = addrs.clone();
+
+        let client = Client {
+            controller: controller.clone(),
+            addrs,
+            shut_down: Cell::new(false),
+        };
+
+        scenario(&client);
+        client.shut_down.get()
+    }));
+
+    let shut_down = outcome.unwrap_or_else(|payload| {
+        *PANIC.lock().unwrap() = Some(payload);
+        false
+    });
+
+    if !shut_down {
+        controller
+            .send(ControlMessage::Terminate)
+            .unwrap_or_else(|_| panic!("Failed to terminate the server"));
+    }
+}
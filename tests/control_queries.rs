@@ -0,0 +1,72 @@
+extern crate rusty_express;
+
+use rusty_express::prelude::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static REPLIES: Mutex<Vec<Option<QueryReply>>> = Mutex::new(Vec::new());
+
+fn admin(controller: AsyncController) {
+    // no connection ever arrives, and the server answers anyway
+    let mut kinds = vec![
+        QueryKind::RunningState,
+        QueryKind::EffectiveConfig,
+        QueryKind::Uptime,
+        QueryKind::LocalAddrs,
+    ];
+
+    #[cfg(feature = "session")]
+    kinds.push(QueryKind::SessionCount);
+
+    let mut replies = REPLIES.lock().unwrap();
+    for kind in kinds.iter() {
+        replies.push(controller.query(*kind, Duration::from_secs(5)));
+    }
+
+    controller
+        .send(ControlMessage::Terminate)
+        .unwrap_or_else(|_| panic!("Failed to terminate the server"));
+}
+
+#[test]
+fn idle_server_answers_queries() {
+    let mut server = HttpServer::new();
+    server.config().set_pool_size(3);
+
+    let start = Instant::now();
+    server.listen_and_serve(0, Some(admin));
+
+    // the terminate message is taken care of without waiting for a connection
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let replies = REPLIES.lock().unwrap();
+    assert_eq!(replies.len(), if cfg!(feature = "session") { 5 } else { 4 });
+    assert_eq!(replies[0], Some(QueryReply::RunningState(true)));
+
+    match &replies[1] {
+        Some(QueryReply::EffectiveConfig(config)) => {
+            assert_eq!(config.pool_size, 3);
+            assert!(!config.tls);
+            assert!(config.to_json().contains("\"pool_size\":3"));
+        }
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    match replies[2] {
+        Some(QueryReply::Uptime(uptime)) => assert!(uptime < start.elapsed()),
+        ref other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    // the port picked by the OS is told
+    match &replies[3] {
+        Some(QueryReply::LocalAddrs(addrs)) => {
+            assert_eq!(addrs.len(), 1);
+            assert!(addrs[0].ip().is_loopback());
+            assert_ne!(addrs[0].port(), 0);
+        }
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    #[cfg(feature = "session")]
+    assert_eq!(replies[4], Some(QueryReply::SessionCount(0)));
+}
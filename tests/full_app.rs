@@ -0,0 +1,172 @@
+extern crate rusty_express;
+
+#[path = "../examples/full_app.rs"]
+#[allow(dead_code)]
+mod full_app;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+/// One request of the scenario, sent over the same keep-alive connection as the others, and the
+/// status and the snippets expected from the response.
+struct Step {
+    request: &'static str,
+    body: &'static str,
+    status: u16,
+    expects: &'static [&'static str],
+}
+
+const SCENARIO: &[Step] = &[
+    Step {
+        request: "GET /index.html",
+        body: "",
+        status: 200,
+        expects: &["x-powered-by: Rusty Express", "Static Folder Test"],
+    },
+    Step {
+        request: "GET /secrets.json",
+        body: "",
+        status: 404,
+        expects: &["Nothing here"],
+    },
+    Step {
+        request: "GET /api/users/42",
+        body: "",
+        status: 200,
+        expects: &["application/json", r#"{"id":"42"}"#],
+    },
+    Step {
+        request: "POST /api/echo",
+        body: r#"{"name":"rex"}"#,
+        status: 200,
+        expects: &["application/json", "rex", "/api/echo"],
+    },
+    Step {
+        request: "GET /counter",
+        body: "",
+        status: 200,
+        expects: &["RUSTY_SESSION=", "visits: 1"],
+    },
+    Step {
+        request: "GET /counter",
+        body: "",
+        status: 200,
+        expects: &["x-powered-by: Rusty Express", "visits: 2"],
+    },
+    Step {
+        request: "GET /no/such/page",
+        body: "",
+        status: 404,
+        expects: &["Nothing here"],
+    },
+];
+
+static RESPONSES: Mutex<Vec<(u16, String)>> = Mutex::new(Vec::new());
+
+/// Read the response with the body sized by the `Content-Length` header.
+fn read_response(reader: &mut BufReader<TcpStream>) -> Option<(u16, String)> {
+    let mut head = String::new();
+    loop {
+        let start = head.len();
+        if reader.read_line(&mut head).ok()? == 0 {
+            return None;
+        }
+
+        if head[start..].trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let status = head.split_whitespace().nth(1)?.parse().ok()?;
+    let len = head
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(field, _)| field.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).ok()?;
+
+    Some((status, head + &String::from_utf8_lossy(&body)))
+}
+
+/// The value of the session cookie set by the response, to be sent back with the next requests.
+fn session_cookie(response: &str) -> Option<String> {
+    response
+        .lines()
+        .filter(|line| line.to_lowercase().starts_with("set-cookie:"))
+        .filter_map(|line| line.splitn(2, ':').nth(1))
+        .find(|cookie| cookie.trim_start().starts_with("RUSTY_SESSION="))
+        .and_then(|cookie| cookie.trim().split(';').next())
+        .map(String::from)
+}
+
+fn run_scenario(client: &Client) {
+    let stream = client.connect();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut cookie: Option<String> = None;
+
+    for step in SCENARIO {
+        let mut request = format!(
+            "{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n",
+            step.request,
+            step.body.len()
+        );
+
+        if let Some(cookie) = cookie.as_ref() {
+            request.push_str(&format!("Cookie: {}\r\n", cookie));
+        }
+
+        request.push_str("\r\n");
+        request.push_str(step.body);
+
+        if writer.write_all(request.as_bytes()).is_err() {
+            break;
+        }
+
+        match read_response(&mut reader) {
+            Some(response) => {
+                if let Some(found) = session_cookie(&response.1) {
+                    cookie.replace(found);
+                }
+
+                RESPONSES.lock().unwrap().push(response);
+            }
+            None => break,
+        }
+    }
+}
+
+#[test]
+fn full_app_scenario() {
+    let mut server = HttpServer::new();
+    full_app::setup(&mut server);
+    common::serve(&mut server, run_scenario);
+
+    let responses = RESPONSES.lock().unwrap();
+    assert_eq!(responses.len(), SCENARIO.len(), "{:?}", responses);
+
+    for (step, (status, response)) in SCENARIO.iter().zip(responses.iter()) {
+        assert_eq!(*status, step.status, "{}: {}", step.request, response);
+
+        for expected in step.expects {
+            assert!(
+                response.contains(expected),
+                "{}: missing '{}' in {}",
+                step.request,
+                expected,
+                response
+            );
+        }
+    }
+}
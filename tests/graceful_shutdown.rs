@@ -0,0 +1,77 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+static CLIENT: Mutex<Option<JoinHandle<String>>> = Mutex::new(None);
+
+/// Take a while to respond, such that the server is told to shut down mid-request.
+fn slow(_req: &Box<Request>, resp: &mut Box<Response>) {
+    STARTED.store(true, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(500));
+    resp.send("finished");
+}
+
+fn request(client: &Client) -> JoinHandle<String> {
+    let mut stream = client.connect();
+    stream
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    thread::spawn(move || {
+        let mut wire = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut wire) {
+            return format!("not closed: {}", e);
+        }
+
+        String::from_utf8_lossy(&wire).into_owned()
+    })
+}
+
+fn scenario(client: &Client) {
+    let reader = request(client);
+
+    // wait for the handler to pick up the request
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !STARTED.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    *CLIENT.lock().unwrap() = Some(reader);
+    client.shut_down(ControlMessage::TerminateGracefully(Duration::from_secs(5)));
+}
+
+#[test]
+fn in_flight_request_drained() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/slow"), slow);
+    let served = common::serve(&mut server, scenario);
+
+    assert!(
+        STARTED.load(Ordering::SeqCst),
+        "the handler is never invoked"
+    );
+
+    // the server only returns after the request in flight is served in full
+    let client = CLIENT
+        .lock()
+        .unwrap()
+        .take()
+        .expect("the terminate message is never sent");
+    let wire = client.join().unwrap();
+
+    assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+    assert!(wire.ends_with("\r\n\r\nfinished"), "{}", wire);
+
+    // and no new streams are accepted afterwards
+    assert!(TcpStream::connect(served.addrs[0]).is_err());
+}
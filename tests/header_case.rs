@@ -0,0 +1,49 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+
+/// Read the fields back in the casing other than the one they're sent in.
+fn echo(req: &Box<Request>, resp: &mut Box<Response>) {
+    let fields: Vec<String> = [
+        "X-Requested-With",
+        "authorization",
+        "CONTENT-LENGTH",
+        "X-Trace",
+    ]
+    .iter()
+    .map(|field| req.header(field).unwrap_or_else(|| String::from("-")))
+    .collect();
+
+    let mut names: Vec<&str> = req.header_iter().map(|(field, _)| field).collect();
+    names.sort();
+
+    resp.send(&format!("[{}|{}]", fields.join(","), names.join(",")));
+}
+
+fn scenario(client: &Client) {
+    client.record(client.request(
+        b"POST /echo HTTP/1.1\r\nhost: localhost\r\nx-requested-with: XMLHttpRequest\r\n\
+          AUTHORIZATION: Bearer abc\r\nContent-length: 2\r\nCONNECTION: close\r\n\r\nok",
+    ));
+}
+
+#[test]
+fn mixed_case_headers() {
+    let mut server = HttpServer::new();
+    server.post(RequestPath::Explicit("/echo"), echo);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    let wire = &wires[0];
+    assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+    assert!(
+        wire.ends_with(
+            "[XMLHttpRequest,Bearer abc,2,-|\
+             authorization,connection,content-length,host,x-requested-with]"
+        ),
+        "{}",
+        wire
+    );
+}
@@ -0,0 +1,56 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+
+const UPLOAD: &[u8] =
+    b"POST /upload HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 8\r\n\r\n12345678";
+
+const CROWDED: &[u8] =
+    b"POST /upload HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-One: 1\r\nX-Two: 2\r\n\r\n";
+
+fn upload(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("received");
+}
+
+fn hot_load(controller: &AsyncController, body_bytes: usize, header_count: usize) {
+    let mut config = ServerConfig::new();
+    config.set_max_body_bytes(body_bytes);
+    config.set_max_header_count(header_count);
+
+    // the limits take effect from the next connection accepted
+    controller
+        .send(ControlMessage::HotLoadConfig(config))
+        .unwrap_or_else(|_| panic!("Failed to hot load the config"));
+}
+
+fn scenario(client: &Client) {
+    // refused by the limits the server is launched with
+    client.record(client.request(UPLOAD));
+
+    // the body limit is lifted, while the header fields are now limited
+    hot_load(&client.controller, 0, 3);
+    client.record(client.request(UPLOAD));
+    client.record(client.request(CROWDED));
+
+    // and back to no limits at all
+    hot_load(&client.controller, 0, 0);
+    client.record(client.request(CROWDED));
+}
+
+#[test]
+fn limits_hot_load() {
+    let mut server = HttpServer::new();
+    server.config().set_max_body_bytes(4);
+    server.post(RequestPath::Explicit("/upload"), upload);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    assert_eq!(wires.len(), 4, "{:?}", wires);
+    assert!(wires[0].starts_with("HTTP/1.1 413"), "{}", wires[0]);
+    assert!(wires[1].starts_with("HTTP/1.1 200"), "{}", wires[1]);
+    assert!(wires[1].ends_with("received"), "{}", wires[1]);
+    assert!(wires[2].starts_with("HTTP/1.1 431"), "{}", wires[2]);
+    assert!(wires[3].starts_with("HTTP/1.1 200"), "{}", wires[3]);
+}
@@ -0,0 +1,69 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+fn request(addr: SocketAddr, target: &str) -> String {
+    let raw = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+        target,
+        addr.port()
+    );
+
+    common::request(addr, raw.as_bytes())
+}
+
+fn scenario(client: &Client) {
+    // the redirect listener comes after the https one
+    let redirect = client.addrs()[1];
+    client.record(request(redirect, "/account/orders?page=2"));
+    client.record(request(redirect, "/"));
+
+    // the redirect listener screens the peers with the reloaded ip filter as well
+    let mut config = ServerConfig::new();
+    config.set_ip_filter(IpFilter {
+        deny: vec!["127.0.0.0/8".parse().unwrap()],
+        ..Default::default()
+    });
+
+    client
+        .controller
+        .send(ControlMessage::HotLoadConfig(config))
+        .unwrap_or_else(|_| panic!("Failed to hot load the config"));
+
+    // the control messages are handled in order, so the config is loaded once the stats are back
+    assert!(client
+        .controller
+        .query_stats(Duration::from_secs(5))
+        .is_some());
+    client.record(request(redirect, "/"));
+}
+
+#[test]
+fn redirect_listener_stops_with_server() {
+    let mut server = HttpServer::new();
+    server.config().set_https_redirect(0, "example.com");
+    let served = common::serve(&mut server, scenario);
+
+    let wires = served.wires;
+    assert_eq!(wires.len(), 3, "{:?}", wires);
+    assert!(wires[0].starts_with("HTTP/1.1 301"), "{}", wires[0]);
+    assert!(
+        wires[0].contains("Location: https://example.com/account/orders?page=2\r\n"),
+        "{}",
+        wires[0]
+    );
+    assert!(
+        wires[1].contains("Location: https://example.com/\r\n"),
+        "{}",
+        wires[1]
+    );
+    assert!(wires[2].is_empty(), "{}", wires[2]);
+
+    // terminating the server has closed the redirect listener as well
+    assert!(TcpStream::connect(served.addrs[1]).is_err());
+}
@@ -0,0 +1,29 @@
+extern crate rusty_express;
+
+use rusty_express::prelude::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static SENT: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn terminate(controller: AsyncController) {
+    // no connection ever arrives
+    *SENT.lock().unwrap() = Some(Instant::now());
+    controller
+        .send(ControlMessage::Terminate)
+        .unwrap_or_else(|_| panic!("Failed to terminate the server"));
+}
+
+#[test]
+fn idle_server_terminates_promptly() {
+    let mut server = HttpServer::new();
+    server.listen_and_serve(0, Some(terminate));
+
+    let sent = SENT
+        .lock()
+        .unwrap()
+        .expect("the terminate message is never sent");
+    // the server has shut down, the pools and the statics included, soon after the message
+    let latency = sent.elapsed();
+    assert!(latency < Duration::from_millis(100), "{:?}", latency);
+}
@@ -0,0 +1,66 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+
+fn hello(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("hello");
+}
+
+fn filter(allow: &str, deny: &str, send_forbidden: bool) -> IpFilter {
+    let blocks = |source: &str| {
+        source
+            .split(',')
+            .filter(|block| !block.is_empty())
+            .map(|block| block.parse().unwrap())
+            .collect()
+    };
+
+    IpFilter {
+        allow: blocks(allow),
+        deny: blocks(deny),
+        send_forbidden,
+        ..Default::default()
+    }
+}
+
+const HELLO: &[u8] = b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+fn hot_load(controller: &AsyncController, filter: IpFilter) {
+    let mut config = ServerConfig::new();
+    config.set_ip_filter(filter);
+
+    // the config takes effect when the next connection is accepted, i.e. the one to be screened
+    controller
+        .send(ControlMessage::HotLoadConfig(config))
+        .unwrap_or_else(|_| panic!("Failed to hot load the config"));
+}
+
+fn scenario(client: &Client) {
+    // denied by the filter the server is launched with
+    client.record(client.request(HELLO));
+
+    hot_load(&client.controller, filter("127.0.0.1/32", "", false));
+    client.record(client.request(HELLO));
+
+    hot_load(&client.controller, filter("10.0.0.0/8", "", true));
+    client.record(client.request(HELLO));
+}
+
+#[test]
+fn ip_filter_hot_load() {
+    let mut server = HttpServer::new();
+    server
+        .config()
+        .set_ip_filter(filter("", "127.0.0.0/8", false));
+    server.get(RequestPath::Explicit("/hello"), hello);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    assert_eq!(wires.len(), 3, "{:?}", wires);
+    assert!(wires[0].is_empty(), "{}", wires[0]);
+    assert!(wires[1].starts_with("HTTP/1.1 200"), "{}", wires[1]);
+    assert!(wires[1].ends_with("hello"), "{}", wires[1]);
+    assert!(wires[2].starts_with("HTTP/1.1 403"), "{}", wires[2]);
+}
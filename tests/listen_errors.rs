@@ -0,0 +1,46 @@
+extern crate rusty_express;
+
+use rusty_express::prelude::*;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CALLED: AtomicBool = AtomicBool::new(false);
+
+fn terminate(controller: AsyncController) {
+    CALLED.store(true, Ordering::SeqCst);
+
+    controller
+        .send(ControlMessage::Terminate)
+        .unwrap_or_else(|_| panic!("Failed to terminate the server"));
+}
+
+#[test]
+fn listen_errors() {
+    let mut server = HttpServer::new();
+
+    // the port is taken before the server tries to listen on it
+    let taken = TcpListener::bind(SocketAddr::new(server.config().get_bind_address(), 0)).unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    match server.try_listen_and_serve(port, Some(terminate)) {
+        Err(ServerError::BindFailed(_)) => {}
+        other => panic!("Expecting the bind failure, got: {:?}", other),
+    }
+
+    assert!(!CALLED.load(Ordering::SeqCst));
+
+    // the failure doesn't count as a launch, and the server can be launched on another port
+    drop(taken);
+    server.try_listen_and_serve(0, Some(terminate)).unwrap();
+    assert!(CALLED.load(Ordering::SeqCst));
+
+    // but only once per process
+    let mut another = HttpServer::new();
+    match another.try_listen(0) {
+        Err(ServerError::AlreadyRunning) => {}
+        other => panic!(
+            "Expecting the server to be running already, got: {:?}",
+            other
+        ),
+    }
+}
@@ -0,0 +1,73 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+static SENT: Mutex<Option<Instant>> = Mutex::new(None);
+static CLIENTS: Mutex<Vec<JoinHandle<String>>> = Mutex::new(Vec::new());
+
+/// Keep the connection open, without ever sending the message to end the body.
+fn events(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.status(200);
+    resp.keep_alive(true);
+    resp.send("subscribed");
+    resp.get_channels().unwrap();
+}
+
+/// Open a long connection, and read from it until the server closes it.
+fn subscribe(client: &Client) -> JoinHandle<String> {
+    let mut stream = client.connect();
+    stream
+        .write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+
+    thread::spawn(move || {
+        let mut wire = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut wire) {
+            return format!("not closed: {}", e);
+        }
+
+        String::from_utf8_lossy(&wire).into_owned()
+    })
+}
+
+fn scenario(client: &Client) {
+    let readers = vec![subscribe(client), subscribe(client)];
+
+    // both are in service by now
+    thread::sleep(Duration::from_millis(300));
+
+    CLIENTS.lock().unwrap().extend(readers);
+    *SENT.lock().unwrap() = Some(Instant::now());
+    client.shut_down(ControlMessage::Terminate);
+}
+
+#[test]
+fn long_conns_closed_on_shutdown() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/events"), events);
+    common::serve(&mut server, scenario);
+
+    // the long connections would otherwise hold the shutdown for seconds
+    let latency = SENT
+        .lock()
+        .unwrap()
+        .expect("the terminate message is never sent")
+        .elapsed();
+    assert!(latency < Duration::from_secs(2), "{:?}", latency);
+
+    let clients: Vec<JoinHandle<String>> = CLIENTS.lock().unwrap().drain(..).collect();
+    assert_eq!(clients.len(), 2);
+
+    for client in clients {
+        let wire = client.join().unwrap();
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"), "{}", wire);
+        assert!(wire.contains("subscribed"), "{}", wire);
+    }
+}
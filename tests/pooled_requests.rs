@@ -0,0 +1,35 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+
+fn echo(req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send(&format!("[{}|{}]", req.host_info(), req.uri_fragment()));
+}
+
+fn scenario(client: &Client) {
+    // the second request is served with the recycled objects of the first one
+    client.record(client.request(
+        b"GET /echo#top HTTP/1.1\r\nHost: a.example\r\n\r\n\
+          GET /echo HTTP/1.1\r\nConnection: close\r\n\r\n",
+    ));
+}
+
+#[test]
+fn recycled_request_is_blank() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/echo"), echo);
+    server.config().set_pool_size(1);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    let wire = &wires[0];
+    let bodies: Vec<&str> = wire
+        .split("HTTP/1.1 200 OK\r\n")
+        .skip(1)
+        .filter_map(|resp| resp.split_once("\r\n\r\n").map(|(_, body)| body))
+        .collect();
+
+    assert_eq!(bodies, vec!["[a.example|#top]", "[|]"], "{}", wire);
+}
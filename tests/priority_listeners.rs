@@ -0,0 +1,78 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static RESULTS: Mutex<Vec<(Option<String>, Duration)>> = Mutex::new(Vec::new());
+
+fn pong(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("pong");
+}
+
+/// Ping the listener, and return the response if any arrives before the timeout, along with how
+/// long it takes.
+fn ping(addr: SocketAddr, timeout: Duration) -> (Option<String>, Duration) {
+    let start = Instant::now();
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(timeout)).unwrap();
+    client
+        .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    let mut wire = Vec::new();
+    client.read_to_end(&mut wire).unwrap_or_default();
+
+    let response = Some(String::from_utf8_lossy(&wire).into_owned()).filter(|r| !r.is_empty());
+    (response, start.elapsed())
+}
+
+fn flood(client: &Client) {
+    let (public, admin) = (client.addrs()[0], client.addrs()[1]);
+
+    // idle connections holding all the public workers, and more queued behind them
+    let held: Vec<TcpStream> = (0..8).map(|_| client.connect()).collect();
+
+    thread::sleep(Duration::from_millis(200));
+
+    let public = ping(public, Duration::from_millis(500));
+    let admin = ping(admin, Duration::from_secs(5));
+    RESULTS.lock().unwrap().extend(vec![public, admin]);
+
+    drop(held);
+}
+
+#[test]
+fn admin_listener_under_flood() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/ping"), pong);
+    server.config().set_pool_size(2);
+    server.config().set_reserved_workers(1);
+    server.config().set_read_timeout(20_000);
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    common::serve_on(
+        &mut server,
+        vec![(addr, Priority::Normal), (addr, Priority::High)],
+        flood,
+    );
+
+    let results = RESULTS.lock().unwrap();
+    assert_eq!(results.len(), 2);
+
+    // the public listener is saturated
+    assert_eq!(results[0].0, None);
+
+    // while the admin one responds right away
+    let (admin, latency) = &results[1];
+    let admin = admin.as_ref().expect("no response from the admin listener");
+    assert!(admin.starts_with("HTTP/1.1 200 OK\r\n"), "{}", admin);
+    assert!(admin.ends_with("pong"), "{}", admin);
+    assert!(*latency < Duration::from_secs(1), "{:?}", latency);
+}
@@ -0,0 +1,63 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+
+fn pong(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("pong");
+}
+
+/// The ping with the `Host` field, and the extra fields, query and cookies.
+fn ping(client: &Client, fields: usize, params: usize, cookies: usize) -> String {
+    let query: Vec<String> = (0..params).map(|i| format!("p{}=x", i)).collect();
+    let mut raw = format!(
+        "GET /ping?{} HTTP/1.1\r\nHost: localhost\r\n",
+        query.join("&")
+    );
+
+    (0..fields).for_each(|i| raw.push_str(&format!("X-A-{}: x\r\n", i)));
+
+    if cookies > 0 {
+        let jar: Vec<String> = (0..cookies).map(|i| format!("c{}=x", i)).collect();
+        raw.push_str(&format!("Cookie: {}\r\n", jar.join("; ")));
+    }
+
+    raw.push_str("Connection: close\r\n\r\n");
+    client.request(raw.as_bytes())
+}
+
+fn scenario(client: &Client) {
+    // right at the limits: the fields, along with `Host` and `Connection`
+    client.record(ping(client, 97, 256, 100));
+    client.record(ping(client, 99, 0, 0));
+    client.record(ping(client, 0, 0, 300));
+    client.record(ping(client, 0, 500, 0));
+    client.record(ping(client, 0, 0, 0));
+}
+
+#[test]
+fn count_limits() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/ping"), pong);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    let status_lines: Vec<&str> = wires
+        .iter()
+        .map(|wire| wire.split("\r\n").next().unwrap_or_default())
+        .collect();
+
+    assert_eq!(
+        status_lines,
+        vec![
+            "HTTP/1.1 200 OK",
+            "HTTP/1.1 431 Request Header Fields Too Large",
+            "HTTP/1.1 413 Payload Too Large",
+            "HTTP/1.1 400 Bad Request",
+            // and the server is still serving
+            "HTTP/1.1 200 OK",
+        ]
+    );
+    assert!(wires[4].ends_with("pong"), "{}", wires[4]);
+}
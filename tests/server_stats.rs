@@ -0,0 +1,75 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static QUERIED: Mutex<Option<ServerStats>> = Mutex::new(None);
+
+fn hello(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("hello");
+}
+
+/// Report the counters as seen from a route handler, where the connection pool is not reported.
+fn counters(_req: &Box<Request>, resp: &mut Box<Response>) {
+    let stats = HttpServer::stats();
+
+    resp.send(&format!(
+        "requests {} 2xx {} 4xx {} connection_pool {}",
+        stats.requests,
+        stats.responses_2xx,
+        stats.responses_4xx,
+        stats.connection_pool.is_some()
+    ));
+}
+
+fn scenario(client: &Client) {
+    for path in ["/hello", "/hello", "/missing", "/counters"].iter() {
+        client.record(client.get(path));
+    }
+
+    *QUERIED.lock().unwrap() = client.controller.query_stats(Duration::from_secs(5));
+}
+
+#[test]
+fn stats_counted() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/hello"), hello);
+    server.get(RequestPath::Explicit("/counters"), counters);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    assert_eq!(wires.len(), 4, "{:?}", wires);
+    assert!(wires[2].starts_with("HTTP/1.1 404"), "{}", wires[2]);
+
+    // the handler sees its own request parsed, but not yet its response
+    assert!(
+        wires[3].ends_with("requests 4 2xx 2 4xx 1 connection_pool false"),
+        "{}",
+        wires[3]
+    );
+
+    let stats = QUERIED
+        .lock()
+        .unwrap()
+        .take()
+        .expect("the stats are never reported");
+
+    assert_eq!(stats.accepted, 4);
+    assert_eq!(stats.requests, 4);
+    assert_eq!(stats.responses_2xx, 3);
+    assert_eq!(stats.responses_3xx, 0);
+    assert_eq!(stats.responses_4xx, 1);
+    assert_eq!(stats.responses_5xx, 0);
+    assert_eq!(stats.tls_handshake_failures, 0);
+
+    // the controller is also told about the connection pool, and all pools are running
+    assert!(stats.connection_pool.is_some());
+    assert!(stats.request_pool.is_some());
+    assert!(stats.response_pool.is_some());
+    assert!(stats.parser_pool.is_some());
+    assert!(stats.stream_pool.is_some());
+    assert!(!stats.is_under_pressure());
+}
@@ -0,0 +1,71 @@
+extern crate rusty_express;
+
+use rusty_express::prelude::*;
+use rusty_express::rex_warn;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+struct Token(String);
+
+impl SessionData for Token {
+    fn serialize(&self) -> String {
+        self.0.to_owned()
+    }
+
+    fn deserialize(raw: &str) -> Option<Self> {
+        Some(Token(raw.to_owned()))
+    }
+}
+
+fn artifacts() -> (PathBuf, PathBuf) {
+    let base = env::temp_dir().join(format!("rex-shutdown-{}", process::id()));
+    (base.join("logs"), base.join("sessions.store"))
+}
+
+fn terminate(controller: AsyncController) {
+    // dirty sessions which only live in the memory store
+    for token in &["first-token", "second-token"] {
+        let mut session = Session::create_new().unwrap();
+        session.set_data(Token(token.to_string()));
+    }
+
+    // pending log messages which are yet to be dumped
+    rex_warn!("pending message before shutdown");
+
+    controller
+        .send(ControlMessage::Terminate)
+        .unwrap_or_else(|_| panic!("Failed to terminate the server"));
+}
+
+#[test]
+fn shutdown_flushes_logs_and_sessions() {
+    let (log_folder, session_store) = artifacts();
+
+    ServerConfig::set_debug_level(DebugLevel::Warning);
+
+    let mut server = HttpServer::new();
+    server
+        .config()
+        .set_log_folder_path(Some(log_folder.clone()));
+    server
+        .config()
+        .set_session_store_path(Some(session_store.clone()));
+
+    server.listen_and_serve(0, Some(terminate));
+
+    let logs: Vec<String> = fs::read_dir(&log_folder)
+        .unwrap()
+        .filter_map(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+        .collect();
+
+    assert!(logs
+        .iter()
+        .any(|log| log.contains("pending message before shutdown")));
+
+    let store = fs::read_to_string(&session_store).unwrap();
+    assert!(store.contains("first-token") && store.contains("second-token"));
+
+    fs::remove_dir_all(log_folder.parent().unwrap()).unwrap();
+}
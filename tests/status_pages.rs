@@ -0,0 +1,107 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+
+fn maintenance(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.status(503);
+}
+
+fn forbidden(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.status(403);
+}
+
+fn throttled(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.status(429);
+}
+
+fn gone(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.status(410);
+}
+
+fn accepted(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.status(202);
+}
+
+fn maintenance_page() -> (String, Option<String>) {
+    (String::from("back soon"), Some(String::from("text/plain")))
+}
+
+fn forbidden_page() -> (String, Option<String>) {
+    (String::from("<h1>keep out</h1>"), None)
+}
+
+fn error_page(status: u16) -> (String, Option<String>) {
+    (format!("<h1>error {}</h1>", status), None)
+}
+
+fn request_page(status: u16, req: &Box<Request>) -> (String, Option<String>) {
+    (format!("<h1>{} {}</h1>", req.uri, status), None)
+}
+
+fn scenario(client: &Client) {
+    for path in ["/maintenance", "/forbidden", "/throttled", "/missing"].iter() {
+        client.record(client.get(path));
+    }
+
+    client.record(client.request(b"GET /\xff HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+    client.record(client.get("/gone"));
+    client.record(client.get("/accepted"));
+}
+
+#[test]
+fn custom_status_pages() {
+    let mut server = HttpServer::new();
+    server.get(RequestPath::Explicit("/maintenance"), maintenance);
+    server.get(RequestPath::Explicit("/forbidden"), forbidden);
+    server.get(RequestPath::Explicit("/throttled"), throttled);
+    server.get(RequestPath::Explicit("/gone"), gone);
+    server.get(RequestPath::Explicit("/accepted"), accepted);
+
+    // the server config resets the pages, so they're set after the server is created
+    ServerConfig::set_status_page(503, maintenance_page);
+    ServerConfig::set_status_page(403, forbidden_page);
+    ServerConfig::set_error_page_template(error_page);
+
+    // the request that can't be parsed gets the template instead
+    ServerConfig::set_request_status_page(410, request_page);
+    ServerConfig::set_request_status_page(400, request_page);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    assert_eq!(wires.len(), 7, "{:?}", wires);
+
+    let expected = [
+        ("503", "text/plain", "back soon"),
+        ("403", "text/html", "<h1>keep out</h1>"),
+        ("429", "text/html", "<h1>error 429</h1>"),
+        ("404", "text/html", "<h1>error 404</h1>"),
+        ("400", "text/html", "<h1>error 400</h1>"),
+        ("410", "text/html", "<h1>/gone 410</h1>"),
+    ];
+
+    // the other responses left without a body never get the error pages
+    let accepted = &wires[6];
+    assert!(accepted.starts_with("HTTP/1.1 202 "), "{}", accepted);
+    assert!(!accepted.contains("<h1>error 202</h1>"), "{}", accepted);
+
+    for (wire, (status, content_type, page)) in wires.iter().zip(expected.iter()) {
+        assert!(
+            wire.starts_with(&format!("HTTP/1.1 {} ", status)),
+            "{}",
+            wire
+        );
+        assert!(
+            wire.contains(&format!("Content-Type: {}\r\n", content_type)),
+            "{}",
+            wire
+        );
+        assert!(
+            wire.contains(&format!("Content-Length: {}\r\n", page.len())),
+            "{}",
+            wire
+        );
+        assert!(wire.ends_with(page), "{}", wire);
+    }
+}
@@ -0,0 +1,93 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ERRORS: Mutex<Vec<ConnError>> = Mutex::new(Vec::new());
+static FAILURES: Mutex<usize> = Mutex::new(0);
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+fn record(err: ConnError) {
+    ERRORS.lock().unwrap().push(err);
+}
+
+fn garbage(client: &Client) {
+    // a plain http request to the https port
+    client.request(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+    // the handshake is failed on a worker, after the connection is closed
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while HttpServer::stats().tls_handshake_failures == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    *FAILURES.lock().unwrap() = HttpServer::stats().tls_handshake_failures;
+}
+
+#[test]
+fn garbage_fails_handshake_quietly() {
+    let log_folder = env::temp_dir().join(format!("rex-tls-handshake-{}", process::id()));
+
+    ServerConfig::set_debug_level(DebugLevel::Info);
+    ServerConfig::on_conn_error(record);
+
+    let mut server = HttpServer::new();
+    server
+        .config()
+        .set_tls_from_pem(fixture("cert.pem"), fixture("key.pem"));
+    server
+        .config()
+        .set_log_folder_path(Some(log_folder.clone()));
+    common::serve(&mut server, garbage);
+
+    assert_eq!(*FAILURES.lock().unwrap(), 1);
+
+    let errors = ERRORS.lock().unwrap();
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    match errors[0] {
+        ConnError::TlsHandshake { peer, reason } => {
+            assert_eq!(peer.map(|addr| addr.ip()), Some([127, 0, 0, 1].into()));
+            assert_eq!(reason, TlsFailure::NotTls);
+        }
+        ref other => panic!("Unexpected connection error: {:?}", other),
+    }
+
+    let logs: Vec<String> = fs::read_dir(&log_folder)
+        .unwrap()
+        .filter_map(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+        .collect();
+
+    // the scanner noise is logged as info
+    assert!(
+        logs.iter()
+            .flat_map(|log| log.lines())
+            .any(|line| line.contains("[Info]") && line.contains("TLS handshake with")),
+        "{:?}",
+        logs
+    );
+    assert!(
+        !logs
+            .iter()
+            .flat_map(|log| log.lines())
+            .any(|line| line.contains("[Error]")),
+        "{:?}",
+        logs
+    );
+
+    fs::remove_dir_all(&log_folder).unwrap();
+}
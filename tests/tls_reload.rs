@@ -0,0 +1,95 @@
+extern crate native_tls;
+extern crate rusty_express;
+
+use native_tls::TlsConnector;
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+fn identity_dir() -> PathBuf {
+    env::temp_dir().join(format!("rex-tls-reload-{}", process::id()))
+}
+
+fn hello(_req: &Box<Request>, resp: &mut Box<Response>) {
+    resp.send("hello");
+}
+
+/// Send a request over TLS, and read all of the wire until the server closes the connection.
+fn request(client: &Client) -> String {
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .unwrap();
+
+    let mut stream = match connector.connect("localhost", client.connect()) {
+        Ok(stream) => stream,
+        Err(e) => return format!("handshake failed: {}", e),
+    };
+
+    stream
+        .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    let mut wire = Vec::new();
+    stream.read_to_end(&mut wire).unwrap_or_default();
+
+    String::from_utf8_lossy(&wire).into_owned()
+}
+
+fn scenario(client: &Client) {
+    client.record(request(client));
+
+    // the renewed certificate is broken, so the reload fails
+    fs::write(identity_dir().join("cert.pem"), "not a certificate").unwrap();
+    client
+        .controller
+        .send(ControlMessage::ReloadTls)
+        .unwrap_or_else(|_| panic!("Failed to reload TLS"));
+
+    // the control messages are handled in order, so the reload is done once the stats are back
+    assert!(client
+        .controller
+        .query_stats(Duration::from_secs(5))
+        .is_some());
+    client.record(request(client));
+}
+
+#[test]
+fn failed_reload_keeps_identity() {
+    let dir = identity_dir();
+    fs::create_dir_all(&dir).unwrap();
+    for name in &["cert.pem", "key.pem"] {
+        fs::copy(fixture(name), dir.join(name)).unwrap();
+    }
+
+    let mut server = HttpServer::new();
+    server
+        .config()
+        .set_tls_from_pem(dir.join("cert.pem"), dir.join("key.pem"));
+    server.get(RequestPath::Explicit("/hello"), hello);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    // the connections after the failed reload are still served with the previous identity
+    assert_eq!(wires.len(), 2, "{:?}", wires);
+    for wire in wires.iter() {
+        assert!(wire.starts_with("HTTP/1.1 200"), "{}", wire);
+        assert!(wire.ends_with("hello"), "{}", wire);
+    }
+}
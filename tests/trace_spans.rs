@@ -0,0 +1,73 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use rusty_express::rex_warn;
+use std::env;
+use std::fs;
+use std::process;
+
+fn traced(req: &Box<Request>, resp: &mut Box<Response>) {
+    rex_warn!("handled {}", req.uri);
+    resp.send(&req.trace_ids().to_string());
+}
+
+fn pipelined(client: &Client) {
+    client.record(client.request(
+        b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    ));
+}
+
+/// The ids prefixed to the log line of the message, e.g. `3.1` in `[Warn] @ ...: [3.1] message`.
+fn span_of<'a>(logs: &'a [String], message: &str) -> (&'a str, &'a str) {
+    let line = logs
+        .iter()
+        .flat_map(|log| log.lines())
+        .find(|line| line.ends_with(message))
+        .unwrap_or_else(|| panic!("No log line for: {}", message));
+
+    let ids = line.rsplitn(2, ": [").next().unwrap();
+    let ids = &ids[..ids.find(']').unwrap()];
+
+    let mut parts = ids.splitn(2, '.');
+    (parts.next().unwrap(), parts.next().unwrap())
+}
+
+#[test]
+fn pipelined_requests_share_connection_span() {
+    let log_folder = env::temp_dir().join(format!("rex-spans-{}", process::id()));
+
+    ServerConfig::set_debug_level(DebugLevel::Warning);
+
+    let mut server = HttpServer::new();
+    server
+        .config()
+        .set_log_folder_path(Some(log_folder.clone()));
+    server
+        .get(RequestPath::Explicit("/first"), traced)
+        .get(RequestPath::Explicit("/second"), traced);
+
+    let wires = common::serve(&mut server, pipelined).wires;
+
+    let logs: Vec<String> = fs::read_dir(&log_folder)
+        .unwrap()
+        .filter_map(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+        .collect();
+
+    let first = span_of(&logs, "handled /first");
+    let second = span_of(&logs, "handled /second");
+
+    assert_ne!(first.0, "0");
+    assert_eq!(first.0, second.0);
+    assert_eq!((first.1, second.1), ("1", "2"));
+
+    // the handlers see the same ids
+    let wire = &wires[0];
+    assert!(wire.contains(&format!("{}.1", first.0)), "{}", wire);
+    assert!(wire.contains(&format!("{}.2", second.0)), "{}", wire);
+
+    fs::remove_dir_all(&log_folder).unwrap();
+}
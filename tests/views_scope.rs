@@ -0,0 +1,136 @@
+extern crate rusty_express;
+
+mod common;
+
+use common::Client;
+use rusty_express::prelude::*;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+struct Title;
+
+impl EngineContext for Title {
+    fn display(&self, field: &str) -> Result<String, String> {
+        match field {
+            "title" => Ok(String::from("Hello")),
+            _ => Err(format!("Unknown field: {}", field)),
+        }
+    }
+}
+
+/// The new engine: `{{title}}`.
+fn hbs(content: &mut String, _path: &Path, context: Box<dyn EngineContext + Send + Sync>) -> u16 {
+    *content = content.replace("{{title}}", &context.display("title").unwrap());
+    200
+}
+
+/// The old engine: `$title`.
+fn tpl(content: &mut String, _path: &Path, context: Box<dyn EngineContext + Send + Sync>) -> u16 {
+    *content = content.replace("$title", &context.display("title").unwrap());
+    200
+}
+
+fn render(resp: &mut Box<Response>, template: &str) {
+    let status = resp.send_template(template, Box::new(Title));
+    resp.status(status);
+}
+
+fn page(_req: &Box<Request>, resp: &mut Box<Response>) {
+    render(resp, "page.html");
+}
+
+fn card(_req: &Box<Request>, resp: &mut Box<Response>) {
+    render(resp, "card.tpl");
+}
+
+fn legacy(_req: &Box<Request>, resp: &mut Box<Response>) {
+    render(resp, "legacy.tpl");
+}
+
+fn scenario(client: &Client) {
+    let paths = [
+        "/admin/page",
+        "/shop/page",
+        "/admin/card",
+        "/home",
+        "/legacy",
+    ];
+    for path in paths.iter() {
+        client.record(client.get(path));
+    }
+}
+
+#[test]
+fn scoped_views() {
+    let base = env::temp_dir().join(format!("rex-views-scope-{}", std::process::id()));
+    let (admin, shop, views) = (base.join("admin"), base.join("shop"), base.join("views"));
+    fs::create_dir_all(&admin).unwrap();
+    fs::create_dir_all(&shop).unwrap();
+    fs::create_dir_all(&views).unwrap();
+
+    // the same template names under each root, in the dialect of the scope
+    fs::write(admin.join("page.html"), "<h1>{{title}}</h1>").unwrap();
+    fs::write(admin.join("card.tpl"), "[$title]").unwrap();
+    fs::write(shop.join("page.html"), "<p>$title</p>").unwrap();
+    fs::write(views.join("page.html"), "<b>{{title}}</b>").unwrap();
+    fs::write(views.join("legacy.tpl"), "<i>$title</i>").unwrap();
+
+    let mut server = HttpServer::new();
+    ServerConfig::view_engine("hbs", hbs);
+    ServerConfig::view_engine("tpl", tpl);
+    ServerConfig::set_views_root(views);
+
+    server.scope("/admin", |scope| {
+        scope.get(RequestPath::Explicit("/page"), page);
+        scope.get(RequestPath::Explicit("/card"), card);
+        scope.with_views(
+            "/",
+            ViewsScope {
+                root: admin,
+                default_engine_ext: Some(String::from("hbs")),
+            },
+        );
+    });
+
+    server.get(RequestPath::Explicit("/shop/page"), page);
+    server.with_views(
+        "/shop",
+        ViewsScope {
+            root: shop,
+            default_engine_ext: Some(String::from(".tpl")),
+        },
+    );
+
+    server.get(RequestPath::Explicit("/home"), page);
+    server.get(RequestPath::Explicit("/legacy"), legacy);
+    let wires = common::serve(&mut server, scenario).wires;
+
+    ServerConfig::clear_views_root();
+    fs::remove_dir_all(&base).unwrap();
+
+    assert_eq!(wires.len(), 5, "{:?}", wires);
+
+    let expected = [
+        // the scope's root, and its default engine for the extension without one
+        ("200", Some("<h1>Hello</h1>")),
+        ("200", Some("<p>Hello</p>")),
+        // the engine of the file's own extension wins over the scope's default one
+        ("200", Some("[Hello]")),
+        // out of any scope: the server's views root, where no engine renders the `.html` files
+        ("404", None),
+        ("200", Some("<i>Hello</i>")),
+    ];
+
+    for (wire, (status, body)) in wires.iter().zip(expected.iter()) {
+        assert!(
+            wire.starts_with(&format!("HTTP/1.1 {} ", status)),
+            "{}",
+            wire
+        );
+
+        if let Some(body) = body {
+            assert!(wire.ends_with(body), "{}", wire);
+        }
+    }
+}